@@ -26,15 +26,15 @@ use AARCH64_RTC_SIZE;
 use AARCH64_RTC_IRQ;
 use devices::pl030::PL030_AMBA_ID;
 
+use psci::PsciFunction;
+
 // These are serial device related constants.
 use AARCH64_SERIAL_ADDR;
 use AARCH64_SERIAL_SIZE;
 use AARCH64_SERIAL_SPEED;
 
 // These are related to guest virtio devices.
-use AARCH64_MMIO_BASE;
 use AARCH64_MMIO_LEN;
-use AARCH64_IRQ_BASE;
 
 // This is an arbitrary number to specify the node for the GIC.
 // If we had a more complex interrupt architecture, then we'd need an enum for
@@ -79,6 +79,7 @@ pub enum Error {
     FdtFinishError(c_int),
     FdtPackError(c_int),
     FdtGuestMemoryWriteError,
+    FdtRegionOutsideMemory,
 }
 
 impl error::Error for Error {
@@ -94,6 +95,8 @@ impl error::Error for Error {
             &Error::FdtPackError(_) => "Error packing FDT",
             &Error::FdtGuestMemoryWriteError =>
                 "Error writing FDT to Guest Memory",
+            &Error::FdtRegionOutsideMemory =>
+                "FDT region does not fit in Guest Memory",
         }
     }
 }
@@ -113,7 +116,8 @@ impl fmt::Display for Error {
                 write!(f, "{} {} code: {}", prefix,
                        Error::description(self), fdt_ret)
             },
-            &Error::FdtGuestMemoryWriteError =>
+            &Error::FdtGuestMemoryWriteError |
+            &Error::FdtRegionOutsideMemory =>
                 write!(f, "{} {}", prefix, Error::description(self)),
         }
     }
@@ -329,38 +333,40 @@ fn create_psci_node(fdt: &mut Vec<u8>) -> Result<(), Box<Error>> {
     property_string(fdt, "compatible", compatible)?;
     // Only support aarch64 guest
     property_string(fdt, "method", "hvc")?;
-    // These constants are from PSCI
-    property_u32(fdt, "cpu_suspend", 0xc4000001)?;
-    property_u32(fdt, "cpu_off", 0x84000002)?;
-    property_u32(fdt, "cpu_on", 0xc4000003)?;
-    property_u32(fdt, "migrate", 0xc4000005)?;
+    property_u32(fdt, "cpu_suspend", PsciFunction::CpuSuspend.id())?;
+    property_u32(fdt, "cpu_off", PsciFunction::CpuOff.id())?;
+    property_u32(fdt, "cpu_on", PsciFunction::CpuOn.id())?;
+    property_u32(fdt, "migrate", PsciFunction::Migrate.id())?;
     end_node(fdt)?;
 
     Ok(())
 }
 
-fn create_chosen_node(fdt: &mut Vec<u8>, cmdline: &CStr)
+fn create_chosen_node(fdt: &mut Vec<u8>, cmdline: &CStr,
+                      initrd: Option<(GuestAddress, usize)>)
                       -> Result<(), Box<Error>> {
     begin_node(fdt, "chosen")?;
     property_u32(fdt, "linux,pci-probe-only", 1)?;
     property_cstring(fdt, "bootargs", cmdline)?;
     property_u64(fdt, "kaslr", 0)?;
+    if let Some((initrd_addr, initrd_size)) = initrd {
+        let initrd_start = initrd_addr.offset() as u64;
+        property_u64(fdt, "linux,initrd-start", initrd_start)?;
+        property_u64(fdt, "linux,initrd-end", initrd_start + initrd_size as u64)?;
+    }
     end_node(fdt)?;
 
     Ok(())
 }
 
-fn create_io_nodes(fdt: &mut Vec<u8>) -> Result<(), Box<Error>> {
-    // TODO(sonnyrao) Pass in bus to get number of devices
-    // HACK -- this is creating a static number of device nodes
-    // the unused nodes just throw a warning when the guest boots
-    for i in 0..8 {
-        let addr = AARCH64_MMIO_BASE + i * AARCH64_MMIO_LEN;
+fn create_io_nodes(fdt: &mut Vec<u8>, mmio_devices: &[(u64, u32)])
+                   -> Result<(), Box<Error>> {
+    for &(addr, irq) in mmio_devices {
         let node = format!("virtio@{:x}", addr);
         let reg = generate_prop64(&[addr, AARCH64_MMIO_LEN]);
         let irq = generate_prop32(&[
             GIC_FDT_IRQ_TYPE_SPI,
-            AARCH64_IRQ_BASE + i as u32,
+            irq,
             IRQ_TYPE_EDGE_RISING]);
 
         begin_node(fdt, &node)?;
@@ -412,11 +418,21 @@ fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<(), Box<Error>> {
 /// * `num_cpus` - Number of virtual CPUs the guest will have
 /// * `fdt_load_offset` - The offset into physical memory for the device tree
 /// * `cmdline` - The kernel commandline
+/// * `initrd` - The address and size of the initrd, if one was loaded
+/// * `mmio_devices` - The base address and interrupt line of each virtio-mmio device
 pub fn create_fdt(fdt_max_size: usize,
                   guest_mem: &GuestMemory,
                   num_cpus: u32,
                   fdt_load_offset: u64,
-                  cmdline: &CStr) -> Result<(), Box<Error>> {
+                  cmdline: &CStr,
+                  initrd: Option<(GuestAddress, usize)>,
+                  mmio_devices: &[(u64, u32)]) -> Result<(), Box<Error>> {
+    let fdt_address = GuestAddress(AARCH64_PHYS_MEM_START + fdt_load_offset);
+    let fdt_end = fdt_address.checked_add(fdt_max_size as u64);
+    if fdt_end.map_or(true, |end| end > guest_mem.end_addr()) {
+        return Err(Box::new(Error::FdtRegionOutsideMemory));
+    }
+
     let mut fdt = vec![0; fdt_max_size];
 
     // Safe since we allocated this array with fdt_max_size
@@ -439,14 +455,14 @@ pub fn create_fdt(fdt_max_size: usize,
     property_u32(&mut fdt, "#address-cells", 0x2)?;
     property_u32(&mut fdt, "#size-cells", 0x2)?;
 
-    create_chosen_node(&mut fdt, cmdline)?;
+    create_chosen_node(&mut fdt, cmdline, initrd)?;
     create_memory_node(&mut fdt, guest_mem)?;
     create_cpu_nodes(&mut fdt, num_cpus)?;
     create_gic_node(&mut fdt)?;
     create_timer_node(&mut fdt, num_cpus)?;
     create_serial_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
-    create_io_nodes(&mut fdt)?;
+    create_io_nodes(&mut fdt, mmio_devices)?;
     create_rtc_node(&mut fdt)?;
     // End giant node
     end_node(&mut fdt)?;
@@ -473,7 +489,6 @@ pub fn create_fdt(fdt_max_size: usize,
     if fdt_ret != 0 {
         return Err(Box::new(Error::FdtPackError(fdt_ret)));
     }
-    let fdt_address = GuestAddress(AARCH64_PHYS_MEM_START + fdt_load_offset);
     let written = guest_mem.write_slice_at_addr(fdt_final.as_slice(),
                                                 fdt_address).
         map_err(|_| Error::FdtGuestMemoryWriteError)?;
@@ -482,3 +497,89 @@ pub fn create_fdt(fdt_max_size: usize,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    #[link(name = "fdt")]
+    extern {
+        fn fdt_path_offset(fdt: *const c_void, path: *const c_char) -> c_int;
+        fn fdt_getprop(fdt: *const c_void, nodeoffset: c_int, name: *const c_char,
+                       lenp: *mut c_int) -> *const c_void;
+    }
+
+    const FDT_MAGIC: u32 = 0xd00dfeed;
+
+    // Returns the value of property `name` of the node at `path`, if both exist.
+    fn get_prop(fdt: &[u8], path: &str, name: &str) -> Option<Vec<u8>> {
+        let cstr_path = CString::new(path).unwrap();
+        let cstr_name = CString::new(name).unwrap();
+        let mut len: c_int = 0;
+        // Safe because fdt holds a complete device tree and both names are CStrings. The returned
+        // value points into fdt and is copied out before fdt goes away.
+        unsafe {
+            let node = fdt_path_offset(fdt.as_ptr() as *const c_void, cstr_path.as_ptr());
+            if node < 0 {
+                return None;
+            }
+            let val = fdt_getprop(fdt.as_ptr() as *const c_void, node, cstr_name.as_ptr(),
+                                  &mut len);
+            if val.is_null() {
+                return None;
+            }
+            Some(slice::from_raw_parts(val as *const u8, len as usize).to_vec())
+        }
+    }
+
+    #[test]
+    fn fdt_contents() {
+        let mem_size = 0x1000000;
+        let mem = GuestMemory::new(&[(GuestAddress(AARCH64_PHYS_MEM_START), mem_size)]).unwrap();
+        let fdt_max_size = 0x10000;
+        let fdt_offset = mem_size - fdt_max_size as u64;
+        let initrd_start = AARCH64_PHYS_MEM_START + 0x800000;
+        let cmdline = CString::new("console=ttyS0").unwrap();
+        create_fdt(fdt_max_size,
+                   &mem,
+                   2,
+                   fdt_offset,
+                   &cmdline,
+                   Some((GuestAddress(initrd_start), 0x1000)),
+                   &[(0x10000, 1), (0x11000, 2)])
+            .unwrap();
+
+        let mut fdt = vec![0u8; fdt_max_size];
+        mem.read_slice_at_addr(&mut fdt, GuestAddress(AARCH64_PHYS_MEM_START + fdt_offset))
+            .unwrap();
+        assert_eq!(BigEndian::read_u32(&fdt[0..4]), FDT_MAGIC);
+        assert!(BigEndian::read_u32(&fdt[4..8]) as usize <= fdt_max_size);
+
+        assert_eq!(get_prop(&fdt, "/chosen", "bootargs").unwrap(), b"console=ttyS0\0".to_vec());
+        assert_eq!(get_prop(&fdt, "/chosen", "linux,initrd-start").unwrap(),
+                   cpu_to_fdt64(initrd_start).to_vec());
+        assert_eq!(get_prop(&fdt, "/chosen", "linux,initrd-end").unwrap(),
+                   cpu_to_fdt64(initrd_start + 0x1000).to_vec());
+        assert_eq!(get_prop(&fdt, "/virtio@11000", "reg").unwrap(),
+                   generate_prop64(&[0x11000, AARCH64_MMIO_LEN]));
+        assert_eq!(get_prop(&fdt, "/virtio@11000", "interrupts").unwrap(),
+                   generate_prop32(&[GIC_FDT_IRQ_TYPE_SPI, 2, IRQ_TYPE_EDGE_RISING]));
+        // Only registered devices get a node.
+        assert!(get_prop(&fdt, "/virtio@12000", "reg").is_none());
+    }
+
+    #[test]
+    fn fdt_outside_memory() {
+        let mem_size = 0x100000;
+        let mem = GuestMemory::new(&[(GuestAddress(AARCH64_PHYS_MEM_START), mem_size)]).unwrap();
+        let cmdline = CString::new("console=ttyS0").unwrap();
+        match create_fdt(0x10000, &mem, 1, mem_size - 0x8000, &cmdline, None, &[]) {
+            Err(e) => match *e {
+                Error::FdtRegionOutsideMemory => {}
+                ref e => panic!("unexpected error {}", e),
+            },
+            Ok(()) => panic!("FDT created past the end of memory"),
+        }
+    }
+}
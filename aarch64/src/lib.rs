@@ -16,7 +16,8 @@ extern crate sys_util;
 use std::error::{self, Error as Aarch64Error};
 use std::fmt::{self, Display};
 use std::fs::File;
-use std::io::stdout;
+use std::io::{self, stdout};
+use std::result;
 use std::sync::{Arc, Mutex};
 use std::ffi::CStr;
 
@@ -26,8 +27,9 @@ use std::os::unix::io::FromRawFd;
 use kvm::*;
 use kvm_sys::kvm_device_attr;
 
-use arch::Result;
+use arch::{CpuidOverride, LoadedKernel, Result};
 mod fdt;
+mod psci;
 
 // We place the kernel at offset 8MB
 const AARCH64_KERNEL_OFFSET: u64 = 0x80000;
@@ -113,6 +115,26 @@ pub enum Error {
     VCPUInitFailure,
     /// VCPU Set one reg failed
     VCPUSetRegFailure,
+    /// The initrd doesn't fit between the kernel and the FDT
+    InitrdTooLarge,
+    /// The initrd could not be loaded
+    InitrdLoadFailure,
+    /// Guest memory is too small to hold the FDT
+    MemoryTooSmallForFdt,
+    /// KVM can't provide PSCI 0.2, which guests use to start secondary cpus
+    PsciUnsupported,
+    /// Splitting the interrupt controller between the kernel and userspace is not supported
+    SplitIrqChipUnsupported,
+    /// x2APIC is an x86 feature
+    X2ApicUnsupported,
+    /// The TSC is an x86 feature
+    TscKhzUnsupported,
+    /// CPUID is an x86 feature
+    CpuidUnsupported,
+    /// Only ttyS0 is emulated
+    SerialPortsUnsupported,
+    /// The watchdog is an x86 I/O port device
+    WatchdogUnsupported,
 }
 
 impl error::Error for Error {
@@ -128,6 +150,26 @@ impl error::Error for Error {
                 "Failed to initialize VCPU",
             &Error::VCPUSetRegFailure =>
                 "Failed to set register",
+            &Error::InitrdTooLarge =>
+                "The initrd does not fit between the kernel and the FDT",
+            &Error::InitrdLoadFailure =>
+                "Initrd could not be loaded",
+            &Error::MemoryTooSmallForFdt =>
+                "Guest memory is too small to hold the FDT",
+            &Error::PsciUnsupported =>
+                "KVM does not support PSCI 0.2",
+            &Error::SplitIrqChipUnsupported =>
+                "Splitting the interrupt controller is not supported",
+            &Error::X2ApicUnsupported =>
+                "x2APIC is only supported on x86",
+            &Error::TscKhzUnsupported =>
+                "Setting the TSC frequency is only supported on x86",
+            &Error::CpuidUnsupported =>
+                "Overriding CPUID leaves is only supported on x86",
+            &Error::SerialPortsUnsupported =>
+                "Serial ports other than ttyS0 are only supported on x86",
+            &Error::WatchdogUnsupported =>
+                "The watchdog is only supported on x86",
         }
     }
 }
@@ -144,11 +186,13 @@ pub fn arch_memory_regions(size: u64) -> Vec<(GuestAddress, u64)> {
     vec![(GuestAddress(AARCH64_PHYS_MEM_START), size)]
 }
 
-fn fdt_offset(mem_size: u64) -> u64 {
+fn fdt_offset(mem_size: u64) -> result::Result<u64, Error> {
     // Put fdt up near the top of memory
     // TODO(sonnyrao): will have to handle this differently if there's
     // > 4GB memory
-    mem_size - AARCH64_FDT_MAX_SIZE - 0x10000
+    mem_size
+        .checked_sub(AARCH64_FDT_MAX_SIZE + 0x10000)
+        .ok_or(Error::MemoryTooSmallForFdt)
 }
 
 pub struct AArch64;
@@ -160,26 +204,57 @@ impl arch::LinuxArch for AArch64 {
     ///
     /// * `mem` - The memory to be used by the guest.
     /// * `kernel_image` - the File object for the specified kernel.
-    fn load_kernel(guest_mem: &GuestMemory, mut kernel_image: &mut File) -> Result<()> {
+    fn load_kernel(guest_mem: &GuestMemory, mut kernel_image: &mut File)
+                   -> Result<LoadedKernel> {
         let kernel_addr = get_kernel_addr();
         let kernel_meta = kernel_image.metadata()?;
         let kernel_size = kernel_meta.len();
         guest_mem.read_to_memory(kernel_addr, &mut kernel_image, kernel_size as usize).
             map_err(|_| Error::KernelLoadFailure)?;
-        Ok(())
+        let kernel_end = kernel_addr.checked_add(kernel_size).ok_or(Error::KernelLoadFailure)?;
+        Ok(LoadedKernel {
+               entry: kernel_addr,
+               end: kernel_end,
+               setup_header: None,
+           })
+    }
+
+    /// Loads the initrd right below the FDT, where the kernel is told to find it through the FDT's
+    /// chosen node.
+    fn load_initrd(guest_mem: &GuestMemory, kernel_end: GuestAddress,
+                   initrd_image: &mut File) -> Result<(GuestAddress, usize)> {
+        let initrd_size = initrd_image.metadata()?.len();
+        let fdt_start = AARCH64_PHYS_MEM_START + fdt_offset(guest_mem.memory_size())?;
+        let page_mask = !(sys_util::pagesize() as u64 - 1);
+        let addr = fdt_start.checked_sub(initrd_size).ok_or(Error::InitrdTooLarge)? & page_mask;
+        if addr < kernel_end.offset() {
+            return Err(Box::new(Error::InitrdTooLarge));
+        }
+        guest_mem.read_to_memory(GuestAddress(addr), initrd_image, initrd_size as usize).
+            map_err(|_| Error::InitrdLoadFailure)?;
+        Ok((GuestAddress(addr), initrd_size as usize))
     }
 
     fn setup_system_memory(mem: &GuestMemory, mem_size: u64, vcpu_count: u32,
-                           cmdline: &CStr) -> Result<()> {
+                           cmdline: &CStr, _setup_header: Option<&[u8]>,
+                           initrd: Option<(GuestAddress, usize)>,
+                           mmio_devices: &[(u64, u32)],
+                           _high_mmio: Option<(GuestAddress, u64)>)
+                           -> Result<()> {
         fdt::create_fdt(AARCH64_FDT_MAX_SIZE as usize,
                         mem,
                         vcpu_count,
-                        fdt_offset(mem_size),
-                        cmdline)?;
+                        fdt_offset(mem_size)?,
+                        cmdline,
+                        initrd,
+                        mmio_devices)?;
         Ok(())
     }
 
-    fn create_vm(kvm: &Kvm, mem: GuestMemory) -> Result<Vm> {
+    fn create_vm(kvm: &Kvm, mem: GuestMemory, split_irqchip: bool, _pit: bool) -> Result<Vm> {
+        if split_irqchip {
+            return Err(Box::new(Error::SplitIrqChipUnsupported));
+        }
         let vm = Vm::new(&kvm, mem)?;
         Ok(vm)
     }
@@ -191,38 +266,48 @@ impl arch::LinuxArch for AArch64 {
     }
 
     fn get_base_dev_pfn(mem_size: u64) -> u64 {
-        (AARCH64_PHYS_MEM_START + mem_size) >> 12
+        (AARCH64_PHYS_MEM_START + mem_size) / sys_util::pagesize() as u64
+    }
+
+    fn get_high_mmio_region(_mem_end: u64, _size: u64) -> Result<Option<(GuestAddress, u64)>> {
+        Ok(None)
     }
 
     /// This returns a base part of the kernel command for this architecture
     fn get_base_linux_cmdline() -> kernel_cmdline::Cmdline {
         let mut cmdline = kernel_cmdline::Cmdline::new(sys_util::pagesize());
-        cmdline.insert_str("console=ttyS0 reboot=k panic=1").
+        cmdline.insert_str("reboot=k panic=1").
             unwrap();
         cmdline
     }
 
+    fn serial_earlycon(port: u8) -> Option<String> {
+        if port == 0 {
+            Some(format!("uart8250,mmio,{:#x}", AARCH64_SERIAL_ADDR))
+        } else {
+            None
+        }
+    }
+
     /// This creates and returns a device_manager object for this vm.
     ///
     /// # Arguments
     ///
     /// * `vm` - the vm object
     /// * `mem` - A copy of the GuestMemory object for this VM.
-    fn get_device_manager(vm: &mut Vm, mem: GuestMemory) ->
-        Result<device_manager::DeviceManager> {
-        let rtc_evt = EventFd::new()?;
-        vm.register_irqfd(&rtc_evt, AARCH64_RTC_IRQ)?;
+    /// * `serial` - ttyS0, which is mapped at `AARCH64_SERIAL_ADDR`.
+    fn get_device_manager(vm: &mut Vm, mem: GuestMemory, serial: Arc<Mutex<devices::Serial>>)
+                          -> Result<device_manager::DeviceManager> {
+        let mut irq_routes = arch::IrqRoutes::new();
+        let rtc_evt = irq_routes.get_evt(AARCH64_RTC_IRQ)?;
+        irq_routes.register(vm)?;
 
         let mut dm = device_manager::DeviceManager::new(vm,
                                                         mem,
                                                         AARCH64_MMIO_LEN,
                                                         AARCH64_MMIO_BASE,
                                                         AARCH64_IRQ_BASE);
-        let com_evt_1_3 = EventFd::new()?;
-        let serial = Arc::new(Mutex::new(devices::Serial::new_out(
-            com_evt_1_3.try_clone()?,
-            Box::new(stdout()))));
-        dm.bus.insert(serial.clone(), AARCH64_SERIAL_ADDR,
+        dm.bus.insert(serial, AARCH64_SERIAL_ADDR,
                       AARCH64_SERIAL_SIZE).expect("failed to add serial device");
 
         let rtc = Arc::new(Mutex::new(devices::pl030::Pl030::new(rtc_evt)));
@@ -325,26 +410,55 @@ impl arch::LinuxArch for AArch64 {
         Ok(Some(vgic_fd))
     }
 
-    fn setup_io_bus(_vm: &mut Vm, _exit_evt: EventFd)
+    fn setup_io_bus(_vm: &mut Vm,
+                    _exit_evt: EventFd,
+                    _rtc_base_time: i64,
+                    serial_outputs: Vec<(u8, Box<io::Write + Send>)>,
+                    watchdog: bool)
                     -> Result<(devices::Bus, Arc<Mutex<devices::Serial>>)> {
+        if watchdog {
+            return Err(Box::new(Error::WatchdogUnsupported));
+        }
+        let mut out: Box<io::Write + Send> = Box::new(stdout());
+        for (num, port_out) in serial_outputs {
+            if num != 0 {
+                return Err(Box::new(Error::SerialPortsUnsupported));
+            }
+            out = port_out;
+        }
         // ARM doesn't really use the io bus like x86, instead we have a
-        // separate serial device that is returned as a separate object.
+        // separate serial device that is returned as a separate object and then mapped in MMIO
+        // space by get_device_manager.
         let io_bus = devices::Bus::new();
         let com_evt_1_3 = EventFd::new()?;
 
         let serial = Arc::new(Mutex::new(devices::Serial::new_out(
             com_evt_1_3.try_clone()?,
-            Box::new(stdout()))));
+            out)));
         Ok((io_bus, serial))
     }
 
     fn configure_vcpu(guest_mem: &GuestMemory,
-                      _kvm: &Kvm,
+                      kernel_entry: GuestAddress,
+                      kvm: &Kvm,
                       vm: &Vm,
                       vcpu: &Vcpu,
                       cpu_id: u64,
-                      _num_cpus: u64)
+                      _num_cpus: u64,
+                      x2apic: bool,
+                      _kvmclock: bool,
+                      tsc_khz: Option<u32>,
+                      cpuid_overrides: &[CpuidOverride])
                       -> Result<()> {
+        if x2apic {
+            return Err(Box::new(Error::X2ApicUnsupported));
+        }
+        if tsc_khz.is_some() {
+            return Err(Box::new(Error::TscKhzUnsupported));
+        }
+        if !cpuid_overrides.is_empty() {
+            return Err(Box::new(Error::CpuidUnsupported));
+        }
         let mut kvi = kvm_sys::kvm_vcpu_init {
             target: kvm_sys::KVM_ARM_TARGET_GENERIC_V8,
             features: [0; 7],
@@ -353,7 +467,11 @@ impl arch::LinuxArch for AArch64 {
         // This reads back the kernel's preferred target type.
         vm.arm_preferred_target(&mut kvi)?;
 
-        // TODO(sonnyrao): need to verify this feature is supported by host kernel
+        // KVM handles the guest's PSCI calls, including CPU_ON for the secondary cpus powered off
+        // below. SYSTEM_OFF and SYSTEM_RESET exit to the vcpu loop as system events.
+        if !kvm.check_extension(Cap::ArmPsci02) {
+            return Err(Box::new(Error::PsciUnsupported));
+        }
         kvi.features[0] |= 1 << kvm_sys::KVM_ARM_VCPU_PSCI_0_2;
 
         // Non-boot cpus are powered off initially
@@ -373,13 +491,13 @@ impl arch::LinuxArch for AArch64 {
 
         // Other cpus are powered off initially
         if cpu_id == 0 {
-            data    = AARCH64_PHYS_MEM_START + AARCH64_KERNEL_OFFSET;
+            data    = kernel_entry.offset();
             reg_id  = arm64_core_reg!(pc);
             vcpu.set_one_reg(reg_id, data)?;
 
             /* X0 -- fdt address */
             let mem_size = guest_mem.memory_size();
-            data    = (AARCH64_PHYS_MEM_START + fdt_offset(mem_size)) as u64;
+            data    = (AARCH64_PHYS_MEM_START + fdt_offset(mem_size)?) as u64;
             // hack -- can't get this to do offsetof(regs[0]) but luckily it's at offset 0
             reg_id  = arm64_core_reg!(regs);
             vcpu.set_one_reg(reg_id, data)?;
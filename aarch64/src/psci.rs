@@ -0,0 +1,75 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Function IDs of the Power State Coordination Interface (PSCI) 0.2, which guests call to power
+//! cpus on and off and to shut down or reset the system.
+//!
+//! KVM implements PSCI itself once a vcpu is initialized with `KVM_ARM_VCPU_PSCI_0_2`, so the IDs
+//! are only needed to describe the interface to the guest. `SYSTEM_OFF` and `SYSTEM_RESET` come
+//! back out of `KVM_RUN` as system events.
+
+use kvm_sys::{PSCI_0_2_64BIT, PSCI_0_2_FN_BASE};
+
+/// A PSCI 0.2 function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PsciFunction {
+    Version,
+    CpuSuspend,
+    CpuOff,
+    CpuOn,
+    AffinityInfo,
+    Migrate,
+    MigrateInfoType,
+    MigrateInfoUpCpu,
+    SystemOff,
+    SystemReset,
+}
+
+// Every function, in the order of their IDs.
+const FUNCTIONS: [PsciFunction; 10] = [PsciFunction::Version,
+                                       PsciFunction::CpuSuspend,
+                                       PsciFunction::CpuOff,
+                                       PsciFunction::CpuOn,
+                                       PsciFunction::AffinityInfo,
+                                       PsciFunction::Migrate,
+                                       PsciFunction::MigrateInfoType,
+                                       PsciFunction::MigrateInfoUpCpu,
+                                       PsciFunction::SystemOff,
+                                       PsciFunction::SystemReset];
+
+impl PsciFunction {
+    /// Returns the ID an aarch64 guest calls this function with, which is the SMC64 form where
+    /// there is one.
+    pub fn id(&self) -> u32 {
+        let num = FUNCTIONS.iter().position(|f| f == self).unwrap() as u32;
+        let width = if self.has_smc64() { PSCI_0_2_64BIT } else { 0 };
+        (PSCI_0_2_FN_BASE + num) | width
+    }
+
+    fn has_smc64(&self) -> bool {
+        match self {
+            &PsciFunction::CpuSuspend |
+            &PsciFunction::CpuOn |
+            &PsciFunction::AffinityInfo |
+            &PsciFunction::Migrate |
+            &PsciFunction::MigrateInfoUpCpu => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_ids() {
+        assert_eq!(PsciFunction::CpuSuspend.id(), 0xc4000001);
+        assert_eq!(PsciFunction::CpuOff.id(), 0x84000002);
+        assert_eq!(PsciFunction::CpuOn.id(), 0xc4000003);
+        assert_eq!(PsciFunction::Migrate.id(), 0xc4000005);
+        assert_eq!(PsciFunction::SystemOff.id(), 0x84000008);
+        assert_eq!(PsciFunction::SystemReset.id(), 0x84000009);
+    }
+}
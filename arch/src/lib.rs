@@ -4,21 +4,40 @@
 
 extern crate sys_util;
 extern crate kernel_cmdline;
+extern crate kernel_loader;
 extern crate kvm;
 extern crate libc;
 extern crate device_manager;
 extern crate devices;
 
+use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::fs::File;
+use std::io;
 use std::result;
 use std::sync::{Arc, Mutex};
 
 use kvm::{Kvm, Vm, Vcpu};
-use sys_util::{EventFd, GuestMemory};
+use sys_util::{EventFd, GuestAddress, GuestMemory};
+
+pub use kernel_loader::LoadedKernel;
 
 pub type Result<T> = result::Result<T, Box<std::error::Error>>;
 
+/// Exact register values for a CPUID leaf, which take the place of whatever the host and crosvm
+/// would otherwise report for it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuidOverride {
+    /// The leaf, the value of eax when the guest runs CPUID.
+    pub function: u32,
+    /// The subleaf, the value of ecx when the guest runs CPUID.
+    pub index: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
 /// Trait which is implemented for each Linux Architecture in order to
 /// set up the memory, cpus, and system devices and to boot the kernel.
 pub trait LinuxArch {
@@ -28,7 +47,21 @@ pub trait LinuxArch {
     ///
     /// * `mem` - The memory to be used by the guest.
     /// * `kernel_image` - the File object for the specified kernel.
-    fn load_kernel(mem: &GuestMemory, kernel_image: &mut File) -> Result<()>;
+    ///
+    /// Returns the kernel's entry point and the first address past the loaded kernel.
+    fn load_kernel(mem: &GuestMemory, kernel_image: &mut File) -> Result<LoadedKernel>;
+
+    /// Loads an initial ramdisk from an open file, placing it after the kernel.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory to be used by the guest.
+    /// * `kernel_end` - The end of the kernel returned by `load_kernel`.
+    /// * `initrd_image` - the File object for the specified initrd.
+    ///
+    /// Returns the address and size of the loaded initrd.
+    fn load_initrd(mem: &GuestMemory, kernel_end: GuestAddress, initrd_image: &mut File)
+                   -> Result<(GuestAddress, usize)>;
 
     /// Configures the system memory space should be called once per vm before
     /// starting vcpu threads.
@@ -39,10 +72,18 @@ pub trait LinuxArch {
     /// * `mem_size` - The size in bytes of system memory
     /// * `vcpu_count` - Number of virtual CPUs the guest will have
     /// * `cmdline` - the kernel commandline
+    /// * `setup_header` - the `setup_header` of the `LoadedKernel`, if it has one
+    /// * `initrd` - the address and size of the initrd returned by `load_initrd`, if any
+    /// * `mmio_devices` - the MMIO base address and interrupt line of each virtio-mmio device
+    /// * `high_mmio` - the window returned by `get_high_mmio_region`, if any
     fn setup_system_memory(mem: &GuestMemory,
                            mem_size: u64,
                            vcpu_count: u32,
-                           cmdline: &CStr) -> Result<()>;
+                           cmdline: &CStr,
+                           setup_header: Option<&[u8]>,
+                           initrd: Option<(GuestAddress, usize)>,
+                           mmio_devices: &[(u64, u32)],
+                           high_mmio: Option<(GuestAddress, u64)>) -> Result<()>;
 
     /// Creates a new VM object and initializes architecture specific devices
     ///
@@ -50,7 +91,10 @@ pub trait LinuxArch {
     ///
     /// * `kvm` - The opened /dev/kvm object.
     /// * `mem` - The memory to be used by the guest.
-    fn create_vm(kvm: &Kvm, mem: GuestMemory) -> Result<Vm>;
+    /// * `split_irqchip` - Create only the local interrupt controllers in the kernel, leaving the
+    ///                     rest of the interrupt controllers to userspace.
+    /// * `pit` - Create the in-kernel legacy timer, where the architecture has one.
+    fn create_vm(kvm: &Kvm, mem: GuestMemory, split_irqchip: bool, pit: bool) -> Result<Vm>;
 
     /// This creates a GuestMemory object for this VM
     ///
@@ -73,16 +117,31 @@ pub trait LinuxArch {
     /// * `mem_size` - the size in bytes of physical ram for the guest
     fn get_base_dev_pfn(mem_size: u64) -> u64;
 
-    /// This returns a minimal kernel command for this architecture.
+    /// Returns the guest physical address and size of the window that 64-bit PCI BARs are placed
+    /// in, or None if this architecture has no such window.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_end` - the end of guest RAM and of the device memory after it
+    /// * `size` - the size in bytes of the window
+    fn get_high_mmio_region(mem_end: u64, size: u64) -> Result<Option<(GuestAddress, u64)>>;
+
+    /// This returns a minimal kernel command for this architecture. It names no console.
     fn get_base_linux_cmdline() -> kernel_cmdline::Cmdline;
 
+    /// Returns the `earlycon=` value that points the kernel at serial port ttyS`port`, or None if
+    /// this architecture doesn't have that port.
+    fn serial_earlycon(port: u8) -> Option<String>;
+
     /// This creates and returns a device_manager object for this vm.
     ///
     /// # Arguments
     ///
     /// * `vm` - the vm object
     /// * `mem` - A copy of the GuestMemory object for this VM.
-    fn get_device_manager(vm: &mut Vm, mem: GuestMemory)
+    /// * `serial` - ttyS0 as returned by `setup_io_bus`, for architectures that map it in MMIO
+    ///     space.
+    fn get_device_manager(vm: &mut Vm, mem: GuestMemory, serial: Arc<Mutex<devices::Serial>>)
                           -> Result<device_manager::DeviceManager>;
 
     /// Sets up the IO bus for this platform
@@ -91,7 +150,17 @@ pub trait LinuxArch {
     ///
     /// * - `vm` the vm object
     /// * - `exit_evt` - the event fd object which should receive exit events
-    fn setup_io_bus(vm: &mut Vm, exit_evt: EventFd)
+    /// * - `rtc_base_time` - the time, in seconds since the Unix epoch, the guest's real-time clock
+    ///     starts at
+    /// * - `serial_outputs` - where the output of the serial ports goes, keyed by port number.
+    ///     ttyS0 writes to stdout unless it is given here. Only ttyS0, which is returned, gets
+    ///     input.
+    /// * - `watchdog` - Add a watchdog timer that signals `exit_evt` when it expires.
+    fn setup_io_bus(vm: &mut Vm,
+                    exit_evt: EventFd,
+                    rtc_base_time: i64,
+                    serial_outputs: Vec<(u8, Box<io::Write + Send>)>,
+                    watchdog: bool)
                     -> Result<(devices::Bus, Arc<Mutex<devices::Serial>>)>;
 
     /// Configures the vcpu and should be called once per vcpu from the vcpu's thread.
@@ -99,18 +168,154 @@ pub trait LinuxArch {
     /// # Arguments
     ///
     /// * `guest_mem` - The memory to be used by the guest.
-    /// * `kernel_load_offset` - Offset in bytes from `guest_mem` at which the
-    ///                          kernel starts.
+    /// * `kernel_entry` - The entry point returned by `load_kernel`.
     /// * `kvm` - The /dev/kvm object that created vcpu.
     /// * `vm` - The VM object associated with this VCPU.
     /// * `vcpu` - The VCPU object to configure.
     /// * `cpu_id` - The id of the given `vcpu`.
     /// * `num_cpus` - Number of virtual CPUs the guest will have.
+    /// * `x2apic` - Start the vcpu's local interrupt controller in x2APIC mode.
+    /// * `kvmclock` - Offer the guest the kvmclock paravirtual clock, which follows the host's time.
+    /// * `tsc_khz` - The frequency to run the vcpu's timestamp counter at instead of the host's.
+    /// * `cpuid_overrides` - CPUID leaves to report exactly these values for.
     fn configure_vcpu(guest_mem: &GuestMemory,
+                      kernel_entry: GuestAddress,
                       kvm: &Kvm,
                       vm: &Vm,
                       vcpu: &Vcpu,
                       cpu_id: u64,
-                      num_cpus: u64)
+                      num_cpus: u64,
+                      x2apic: bool,
+                      kvmclock: bool,
+                      tsc_khz: Option<u32>,
+                      cpuid_overrides: &[CpuidOverride])
                       -> Result<()>;
 }
+
+// Guest memory is split between NUMA nodes at multiples of this, so huge pages aren't split.
+const NUMA_NODE_ALIGNMENT: u64 = 2 << 20;
+
+/// Splits the guest memory `regions` evenly between the host NUMA `nodes`, in order. Returns the
+/// address, size and node of each part. A region that crosses the boundary between two nodes is
+/// split in two, and a node's share may be spread over several regions.
+pub fn numa_node_ranges(regions: &[(GuestAddress, u64)],
+                        nodes: &[u32])
+                        -> Vec<(GuestAddress, u64, u32)> {
+    let mut ranges = Vec::new();
+    if nodes.is_empty() {
+        return ranges;
+    }
+    let total: u64 = regions.iter().map(|&(_, size)| size).sum();
+    let per_node = total / nodes.len() as u64 / NUMA_NODE_ALIGNMENT * NUMA_NODE_ALIGNMENT;
+
+    let mut node_idx = 0;
+    let mut node_left = per_node;
+    for &(base, size) in regions {
+        let mut offset = 0;
+        while offset < size {
+            // The last node gets whatever is left after rounding.
+            let last = node_idx == nodes.len() - 1;
+            let len = if last { size - offset } else { (size - offset).min(node_left) };
+            if len > 0 {
+                ranges.push((base.unchecked_add(offset), len, nodes[node_idx]));
+            }
+            offset += len;
+            node_left -= len.min(node_left);
+            if node_left == 0 && !last {
+                node_idx += 1;
+                node_left = per_node;
+            }
+        }
+    }
+    ranges
+}
+
+/// Hands out the eventfds that raise interrupt lines of the in-kernel irq chip. Devices sharing
+/// an interrupt line get clones of the same eventfd.
+pub struct IrqRoutes {
+    evts: BTreeMap<u32, EventFd>,
+}
+
+impl IrqRoutes {
+    pub fn new() -> IrqRoutes {
+        IrqRoutes { evts: BTreeMap::new() }
+    }
+
+    /// Returns an eventfd that raises interrupt line `irq` once `register` is called.
+    pub fn get_evt(&mut self, irq: u32) -> sys_util::Result<EventFd> {
+        if let Some(evt) = self.evts.get(&irq) {
+            return evt.try_clone();
+        }
+        let evt = EventFd::new()?;
+        let ret = evt.try_clone()?;
+        self.evts.insert(irq, evt);
+        Ok(ret)
+    }
+
+    /// The interrupt lines an eventfd was requested for, in increasing order.
+    pub fn irqs(&self) -> Vec<u32> {
+        self.evts.keys().cloned().collect()
+    }
+
+    /// Registers every eventfd handed out with `vm` as an irqfd for its interrupt line.
+    pub fn register(&self, vm: &Vm) -> sys_util::Result<()> {
+        for (&irq, evt) in &self.evts {
+            vm.register_irqfd(evt, irq)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1 << 20;
+
+    #[test]
+    fn numa_node_ranges_split() {
+        let regions = [(GuestAddress(0), 3072 * MB), (GuestAddress(4096 * MB), 1024 * MB)];
+        assert_eq!(numa_node_ranges(&regions, &[]), vec![]);
+        assert_eq!(numa_node_ranges(&regions, &[1]),
+                   vec![(GuestAddress(0), 3072 * MB, 1), (GuestAddress(4096 * MB), 1024 * MB, 1)]);
+        assert_eq!(numa_node_ranges(&regions, &[0, 1]),
+                   vec![(GuestAddress(0), 2048 * MB, 0),
+                        (GuestAddress(2048 * MB), 1024 * MB, 1),
+                        (GuestAddress(4096 * MB), 1024 * MB, 1)]);
+        // Node 2's share is exactly the first region.
+        assert_eq!(numa_node_ranges(&regions, &[2, 0, 3, 1])[..2],
+                   [(GuestAddress(0), 1024 * MB, 2), (GuestAddress(1024 * MB), 1024 * MB, 0)]);
+        assert_eq!(numa_node_ranges(&regions, &[2, 0, 3, 1])[2..],
+                   [(GuestAddress(2048 * MB), 1024 * MB, 3), (GuestAddress(4096 * MB), 1024 * MB, 1)]);
+    }
+
+    #[test]
+    fn numa_node_ranges_alignment() {
+        // 10MB split three ways leaves the remainder to the last node.
+        let ranges = numa_node_ranges(&[(GuestAddress(0), 10 * MB)], &[0, 1, 2]);
+        assert_eq!(ranges,
+                   vec![(GuestAddress(0), 2 * MB, 0),
+                        (GuestAddress(2 * MB), 2 * MB, 1),
+                        (GuestAddress(4 * MB), 6 * MB, 2)]);
+        // Too little memory to give every node a share.
+        let ranges = numa_node_ranges(&[(GuestAddress(0), 3 * MB)], &[0, 1]);
+        assert_eq!(ranges, vec![(GuestAddress(0), 3 * MB, 1)]);
+    }
+
+    #[test]
+    fn irq_routes() {
+        let mut routes = IrqRoutes::new();
+        let com1 = routes.get_evt(4).unwrap();
+        let com2 = routes.get_evt(3).unwrap();
+        let com3 = routes.get_evt(4).unwrap();
+        assert_eq!(routes.irqs(), vec![3, 4]);
+
+        // Devices on the same line share the eventfd.
+        com1.write(1).unwrap();
+        com3.write(2).unwrap();
+        assert_eq!(routes.evts[&4].read().unwrap(), 3);
+
+        com2.write(1).unwrap();
+        assert_eq!(routes.evts[&3].read().unwrap(), 1);
+    }
+}
@@ -187,7 +187,7 @@ fn get_fields_impl(fields: &[(String, Tokens)]) -> Vec<Tokens> {
         let getter_ident = Ident::new(format!("get_{}", name).as_str(), Span::call_site());
         let setter_ident = Ident::new(format!("set_{}", name).as_str(), Span::call_site());
         impls.push(quote!(
-                pub fn #getter_ident(&mut self) -> <#ty as BitFieldSpecifier>::DefaultFieldType {
+                pub fn #getter_ident(&self) -> <#ty as BitFieldSpecifier>::DefaultFieldType {
                     let offset = #(#ct0::FIELD_WIDTH as usize)+*;
                     return self.get(offset, #ty::FIELD_WIDTH) as <#ty as BitFieldSpecifier>::DefaultFieldType;
                 }
@@ -9,6 +9,7 @@ extern crate io_jail;
 extern crate kvm;
 extern crate sys_util;
 extern crate kernel_cmdline;
+extern crate libc;
 
 use std::fmt;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -16,6 +17,7 @@ use std::sync::{Arc, Mutex};
 
 use io_jail::Minijail;
 use kvm::{Vm, IoeventAddress};
+use libc::pid_t;
 use sys_util::{GuestMemory, syslog};
 
 /// Errors for device manager.
@@ -61,6 +63,12 @@ const MAX_IRQ: u32 = 15;
 /// Manages the complexities of adding a device.
 pub struct DeviceManager<'a> {
     pub bus: devices::Bus,
+    /// The feature bits acknowledged by the guest for each registered virtio device.
+    pub acked_features: Vec<devices::virtio::AckedFeatures>,
+    /// The MMIO base address and interrupt line of each registered device, in registration order.
+    pub mmio_devices: Vec<(u64, u32)>,
+    /// The process id and virtio device type of each device running in a jailed child process.
+    pub device_pids: Vec<(pid_t, u32)>,
     vm: &'a mut Vm,
     guest_mem: GuestMemory,
     mmio_len: u64,
@@ -78,6 +86,9 @@ impl<'a> DeviceManager<'a> {
                -> DeviceManager {
         DeviceManager {
             bus: devices::Bus::new(),
+            acked_features: Vec::new(),
+            mmio_devices: Vec::new(),
+            device_pids: Vec::new(),
             vm,
             guest_mem,
             mmio_len,
@@ -99,9 +110,11 @@ impl<'a> DeviceManager<'a> {
         // List of FDs to keep open in the child after it forks.
         let mut keep_fds: Vec<RawFd> = device.keep_fds();
         syslog::push_fds(&mut keep_fds);
+        let device_type = device.device_type();
 
         let mmio_device = devices::virtio::MmioDevice::new(self.guest_mem.clone(), device)
             .map_err(Error::CreateMmioDevice)?;
+        self.acked_features.push(mmio_device.acked_features());
         for (i, queue_evt) in mmio_device.queue_evts().iter().enumerate() {
             let io_addr = IoeventAddress::Mmio(self.mmio_base +
                                                devices::virtio::NOTIFY_REG_OFFSET as u64);
@@ -121,6 +134,7 @@ impl<'a> DeviceManager<'a> {
         if let Some(jail) = jail {
             let proxy_dev = devices::ProxyDevice::new(mmio_device, &jail, keep_fds)
                 .map_err(Error::ProxyDeviceCreation)?;
+            self.device_pids.push((proxy_dev.pid(), device_type));
 
             self.bus
                 .insert(Arc::new(Mutex::new(proxy_dev)),
@@ -139,6 +153,7 @@ impl<'a> DeviceManager<'a> {
             .insert("virtio_mmio.device",
                     &format!("4K@0x{:08x}:{}", self.mmio_base, self.irq))
             .map_err(Error::Cmdline)?;
+        self.mmio_devices.push((self.mmio_base, self.irq));
         self.mmio_base += self.mmio_len;
         self.irq += 1;
 
@@ -184,7 +199,9 @@ mod tests {
                     interrupt_evt: EventFd,
                     status: Arc<AtomicUsize>,
                     queues: Vec<devices::virtio::Queue>,
-                    mut queue_evts: Vec<EventFd>) {
+                    mut queue_evts: Vec<EventFd>,
+                    queue_interrupt_evts: Option<Vec<EventFd>>,
+                    _max_chains_per_pass: usize) {
         }
     }
 
@@ -6,6 +6,8 @@
 
 use std::cmp::{Ord, PartialOrd, PartialEq, Ordering};
 use std::collections::btree_map::BTreeMap;
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, Write};
 use std::result;
 use std::sync::{Arc, Mutex};
 
@@ -19,6 +21,9 @@ pub trait BusDevice: Send {
     fn read(&mut self, offset: u64, data: &mut [u8]) {}
     /// Writes at `offset` into this device
     fn write(&mut self, offset: u64, data: &[u8]) {}
+    /// Returns the device to its power-on state, such as when the guest reboots. Only state the
+    /// guest can observe needs to be reset.
+    fn reset(&mut self) {}
 }
 
 #[derive(Debug)]
@@ -52,6 +57,121 @@ impl PartialOrd for BusRange {
     }
 }
 
+/// A read or write on a `Bus`, as given to its trace callback.
+#[derive(Debug, PartialEq)]
+pub struct BusAccess<'a> {
+    /// The address accessed.
+    pub addr: u64,
+    /// The base address of the device that handled the access, or `None` if no device is there.
+    pub device_base: Option<u64>,
+    /// The data written, or the data read back from the device.
+    pub data: &'a [u8],
+    /// True for writes, false for reads.
+    pub write: bool,
+}
+
+/// A callback invoked on every access to a `Bus` that has tracing enabled.
+pub type BusTraceFn = Fn(&BusAccess) + Send + Sync;
+
+/// Returns a trace callback that records every access to `out`, one per line, in the format read
+/// back by `replay`.
+pub fn bus_recorder<W: Write + Send + 'static>(out: W) -> Arc<BusTraceFn> {
+    let out = Mutex::new(out);
+    Arc::new(move |access: &BusAccess| {
+        let mut line = format!("{} {:#x} ", if access.write { 'W' } else { 'R' }, access.addr);
+        for b in access.data {
+            line.push_str(&format!("{:02x}", b));
+        }
+        if let Err(e) = writeln!(out.lock().unwrap(), "{}", line) {
+            error!("failed to record bus access: {}", e);
+        }
+    })
+}
+
+/// Errors from replaying a recording made by `bus_recorder`.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The recording couldn't be read.
+    Io(io::Error),
+    /// Line `line` of the recording is not an access.
+    Parse { line: usize },
+    /// The read on line `line` returned `actual` instead of the recorded data.
+    ReadMismatch {
+        line: usize,
+        addr: u64,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ReplayError::Io(ref e) => write!(f, "failed to read bus recording: {}", e),
+            &ReplayError::Parse { line } => write!(f, "invalid bus access on line {}", line),
+            &ReplayError::ReadMismatch { line, addr, ref expected, ref actual } => {
+                write!(f,
+                       "read of {:#x} on line {} returned {:?} instead of {:?}",
+                       addr,
+                       line,
+                       actual,
+                       expected)
+            }
+        }
+    }
+}
+
+fn parse_access(line: &str) -> Option<(bool, u64, Vec<u8>)> {
+    let mut fields = line.split_whitespace();
+    let write = match fields.next() {
+        Some("W") => true,
+        Some("R") => false,
+        _ => return None,
+    };
+    let addr = fields.next()?;
+    if !addr.starts_with("0x") {
+        return None;
+    }
+    let addr = u64::from_str_radix(&addr[2..], 16).ok()?;
+    // Zero length accesses leave the data field out.
+    let hex = fields.next().unwrap_or("");
+    if fields.next().is_some() || hex.len() % 2 != 0 {
+        return None;
+    }
+    let mut data = Vec::with_capacity(hex.len() / 2);
+    for i in 0..hex.len() / 2 {
+        data.push(u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?);
+    }
+    Some((write, addr, data))
+}
+
+/// Performs the accesses recorded by `bus_recorder` on `bus`, in order, and checks that every read
+/// returns the recorded data. Blank lines are skipped.
+pub fn replay<R: BufRead>(bus: &Bus, recording: R) -> result::Result<(), ReplayError> {
+    for (i, line) in recording.lines().enumerate() {
+        let line = line.map_err(ReplayError::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (write, addr, data) = parse_access(&line).ok_or(ReplayError::Parse { line: i + 1 })?;
+        if write {
+            bus.write(addr, &data);
+        } else {
+            let mut actual = vec![0; data.len()];
+            bus.read(addr, &mut actual);
+            if actual != data {
+                return Err(ReplayError::ReadMismatch {
+                               line: i + 1,
+                               addr: addr,
+                               expected: data,
+                               actual: actual,
+                           });
+            }
+        }
+    }
+    Ok(())
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
@@ -59,12 +179,30 @@ impl PartialOrd for BusRange {
 #[derive(Clone)]
 pub struct Bus {
     devices: BTreeMap<BusRange, Arc<Mutex<BusDevice>>>,
+    trace: Option<Arc<BusTraceFn>>,
+    unmapped_read_value: Option<u8>,
 }
 
 impl Bus {
     /// Constructs an a bus with an empty address space.
     pub fn new() -> Bus {
-        Bus { devices: BTreeMap::new() }
+        Bus {
+            devices: BTreeMap::new(),
+            trace: None,
+            unmapped_read_value: None,
+        }
+    }
+
+    /// Fills the data of reads that hit no device with `value`, such as 0xff for x86 IO ports,
+    /// instead of leaving it untouched.
+    pub fn set_unmapped_read_value(&mut self, value: Option<u8>) {
+        self.unmapped_read_value = value;
+    }
+
+    /// Calls `trace` on every read and write from now on, or stops tracing if it is `None`. Clones
+    /// of the bus made before this call are not affected.
+    pub fn set_trace(&mut self, trace: Option<Arc<BusTraceFn>>) {
+        self.trace = trace;
     }
 
     fn first_before(&self, addr: u64) -> Option<(BusRange, &Mutex<BusDevice>)> {
@@ -87,6 +225,17 @@ impl Bus {
         None
     }
 
+    fn trace_access(&self, addr: u64, offset: Option<u64>, data: &[u8], write: bool) {
+        if let Some(ref trace) = self.trace {
+            trace(&BusAccess {
+                       addr: addr,
+                       device_base: offset.map(|o| addr - o),
+                       data: data,
+                       write: write,
+                   });
+        }
+    }
+
     /// Puts the given device at the given address space.
     pub fn insert(&mut self, device: Arc<Mutex<BusDevice>>, base: u64, len: u64) -> Result<()> {
         if len == 0 {
@@ -121,12 +270,20 @@ impl Bus {
 
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
-    /// Returns true on success, otherwise `data` is untouched.
+    /// Returns true on success, otherwise `data` is filled with the unmapped read value, or left
+    /// untouched if there is none.
     pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
         if let Some((offset, dev)) = self.get_device(addr) {
             dev.lock().unwrap().read(offset, data);
+            self.trace_access(addr, Some(offset), data, false);
             true
         } else {
+            if let Some(value) = self.unmapped_read_value {
+                for b in data.iter_mut() {
+                    *b = value;
+                }
+            }
+            self.trace_access(addr, None, data, false);
             false
         }
     }
@@ -137,16 +294,31 @@ impl Bus {
     pub fn write(&self, addr: u64, data: &[u8]) -> bool {
         if let Some((offset, dev)) = self.get_device(addr) {
             dev.lock().unwrap().write(offset, data);
+            self.trace_access(addr, Some(offset), data, true);
             true
         } else {
+            self.trace_access(addr, None, data, true);
             false
         }
     }
+
+    /// Resets every device on the bus once, even if it was inserted at more than one range.
+    pub fn reset_all(&self) {
+        let mut reset: Vec<&Arc<Mutex<BusDevice>>> = Vec::new();
+        for dev in self.devices.values() {
+            if reset.iter().any(|d| Arc::ptr_eq(d, dev)) {
+                continue;
+            }
+            dev.lock().unwrap().reset();
+            reset.push(dev);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fakes::SharedBuffer;
 
     struct DummyDevice;
     impl BusDevice for DummyDevice {}
@@ -166,6 +338,29 @@ mod tests {
         }
     }
 
+    struct ResetCountingDevice {
+        resets: usize,
+    }
+
+    impl BusDevice for ResetCountingDevice {
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    #[test]
+    fn bus_reset_all() {
+        let mut bus = Bus::new();
+        let first = Arc::new(Mutex::new(ResetCountingDevice { resets: 0 }));
+        let second = Arc::new(Mutex::new(ResetCountingDevice { resets: 0 }));
+        assert!(bus.insert(first.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(first.clone(), 0x40, 0x10).is_ok());
+        assert!(bus.insert(second.clone(), 0x20, 0x10).is_ok());
+        bus.reset_all();
+        assert_eq!(first.lock().unwrap().resets, 1);
+        assert_eq!(second.lock().unwrap().resets, 1);
+    }
+
     #[test]
     fn bus_insert() {
         let mut bus = Bus::new();
@@ -214,4 +409,105 @@ mod tests {
         assert_eq!(values, [5, 6, 7, 8]);
         assert!(bus.write(0x15, &values));
     }
+
+    #[test]
+    fn bus_unmapped_read_value() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(ConstantDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+
+        let mut values = [1, 2, 3, 4];
+        assert!(!bus.read(0x20, &mut values));
+        assert_eq!(values, [1, 2, 3, 4]);
+
+        bus.set_unmapped_read_value(Some(0xff));
+        assert!(!bus.read(0x20, &mut values));
+        assert_eq!(values, [0xff; 4]);
+        // Mapped reads are up to the device.
+        assert!(bus.read(0x10, &mut values));
+        assert_eq!(values, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn bus_trace() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(ConstantDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy.clone(), 0x40, 0x10).is_ok());
+
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let trace_accesses = accesses.clone();
+        let trace: Arc<BusTraceFn> = Arc::new(move |access: &BusAccess| {
+            trace_accesses.lock().unwrap().push((access.addr,
+                                                 access.device_base,
+                                                 access.data.to_vec(),
+                                                 access.write));
+        });
+        bus.set_trace(Some(trace));
+
+        assert!(bus.write(0x12, &[2, 3]));
+        let mut values = [0, 0];
+        assert!(bus.read(0x43, &mut values));
+        assert!(!bus.write(0x30, &[9]));
+        assert_eq!(*accesses.lock().unwrap(),
+                   vec![(0x12, Some(0x10), vec![2, 3], true),
+                        (0x43, Some(0x40), vec![3, 4], false),
+                        (0x30, None, vec![9], true)]);
+
+        bus.set_trace(None);
+        assert!(bus.write(0x12, &[2, 3]));
+        assert_eq!(accesses.lock().unwrap().len(), 3);
+    }
+
+    // Keeps the last value written at each offset, so reads depend on earlier writes.
+    struct MemoryDevice {
+        bytes: [u8; 0x10],
+    }
+
+    impl BusDevice for MemoryDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            let offset = offset as usize;
+            data.copy_from_slice(&self.bytes[offset..offset + data.len()]);
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            let offset = offset as usize;
+            self.bytes[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    fn memory_bus() -> Bus {
+        let mut bus = Bus::new();
+        let dev = Arc::new(Mutex::new(MemoryDevice { bytes: [0; 0x10] }));
+        assert!(bus.insert(dev, 0x10, 0x10).is_ok());
+        bus
+    }
+
+    #[test]
+    fn bus_record_replay() {
+        let recording = SharedBuffer::new();
+        let mut bus = memory_bus();
+        bus.set_trace(Some(bus_recorder(recording.clone())));
+        assert!(bus.write(0x12, &[0xab, 0xcd]));
+        let mut values = [0; 4];
+        assert!(bus.read(0x11, &mut values));
+        assert!(!bus.read(0x40, &mut values[..1]));
+
+        let recording = recording.contents();
+        assert_eq!(String::from_utf8(recording.clone()).unwrap(),
+                   "W 0x12 abcd\nR 0x11 00abcd00\nR 0x40 00\n");
+        assert!(replay(&memory_bus(), &recording[..]).is_ok());
+
+        // Without the write, the read comes back different.
+        match replay(&memory_bus(), "R 0x11 00abcd00\n".as_bytes()) {
+            Err(ReplayError::ReadMismatch { line: 1, addr: 0x11, actual, .. }) => {
+                assert_eq!(actual, vec![0; 4])
+            }
+            r => panic!("unexpected replay result: {:?}", r),
+        }
+        match replay(&memory_bus(), "W 0x12 abcd\nW 12 ab\n".as_bytes()) {
+            Err(ReplayError::Parse { line: 2 }) => {}
+            r => panic!("unexpected replay result: {:?}", r),
+        }
+    }
 }
@@ -3,7 +3,8 @@
 // found in the LICENSE file.
 
 use std::mem;
-use libc::{tm, time_t, time, gmtime_r};
+use std::time::Instant;
+use libc::{tm, time_t, gmtime_r};
 
 use BusDevice;
 
@@ -16,14 +17,19 @@ const DATA_LEN: usize = 128;
 pub struct Cmos {
     index: u8,
     data: [u8; DATA_LEN],
+    base_time: time_t,
+    start: Instant,
 }
 
 impl Cmos {
-    /// Constructs a CMOS/RTC device with zero data.
-    pub fn new() -> Cmos {
+    /// Constructs a CMOS/RTC device with zero data whose clock reads `base_time`, in seconds since
+    /// the Unix epoch, right now and advances in real time from then on.
+    pub fn new(base_time: i64) -> Cmos {
         Cmos {
             index: 0,
             data: [0; DATA_LEN],
+            base_time: base_time as time_t,
+            start: Instant::now(),
         }
     }
 }
@@ -61,13 +67,12 @@ impl BusDevice for Cmos {
                 let day;
                 let month;
                 let year;
-                // The time and gmtime_r calls are safe as long as the structs they are given are
-                // large enough, and neither of them fail. It is safe to zero initialize the tm
-                // struct because it contains only plain data.
+                let now = self.base_time + self.start.elapsed().as_secs() as time_t;
+                // The gmtime_r call is safe as long as the struct it is given is large enough,
+                // and it doesn't fail. It is safe to zero initialize the tm struct because it
+                // contains only plain data.
                 unsafe {
                     let mut tm: tm = mem::zeroed();
-                    let mut now: time_t = 0;
-                    time(&mut now as *mut _);
                     gmtime_r(&now, &mut tm as *mut _);
                     // The following lines of code are safe but depend on tm being in scope.
                     seconds = tm.tm_sec;
@@ -96,4 +101,50 @@ impl BusDevice for Cmos {
             o => panic!("bad read offset on CMOS device: {}", o),
         }
     }
+
+    // The CMOS memory is battery backed and survives a reboot, only the index register resets.
+    fn reset(&mut self) {
+        self.index = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_keeps_data() {
+        let mut cmos = Cmos::new(0);
+        cmos.write(INDEX_OFFSET, &[0x40]);
+        cmos.write(DATA_OFFSET, &[0xaa]);
+        cmos.reset();
+
+        let mut data = [0u8; 1];
+        cmos.read(INDEX_OFFSET, &mut data);
+        assert_eq!(data[0], 0);
+        cmos.write(INDEX_OFFSET, &[0x40]);
+        cmos.read(DATA_OFFSET, &mut data);
+        assert_eq!(data[0], 0xaa);
+    }
+
+    #[test]
+    fn seeded_time() {
+        // 2018-07-04 12:34:56 UTC.
+        let mut cmos = Cmos::new(1530707696);
+        let mut read_reg = |index: u8| {
+            let mut data = [0u8; 1];
+            cmos.write(INDEX_OFFSET, &[index]);
+            cmos.read(DATA_OFFSET, &mut data);
+            data[0]
+        };
+        assert_eq!(read_reg(0x02), 0x34);
+        assert_eq!(read_reg(0x04), 0x12);
+        assert_eq!(read_reg(0x07), 0x04);
+        assert_eq!(read_reg(0x08), 0x07);
+        assert_eq!(read_reg(0x09), 0x18);
+        assert_eq!(read_reg(0x32), 0x20);
+        // Only allow for the test itself taking a few seconds.
+        let seconds = read_reg(0x00);
+        assert!(seconds >= 0x56 && seconds <= 0x59, "seconds were {:#x}", seconds);
+    }
 }
@@ -6,15 +6,25 @@ use sys_util::EventFd;
 
 use BusDevice;
 
+// The bits of I8042_PORT_B_REG the guest can set: the timer 2 gate, speaker data and the parity
+// and channel check enables.
+const PORT_B_WRITABLE: u8 = 0x0f;
+// Bit 5 of I8042_PORT_B_REG is the output of timer 2.
+const PORT_B_TIMER2_OUT: u8 = 0x20;
+
 /// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
 pub struct I8042Device {
     reset_evt: EventFd,
+    port_b: u8,
 }
 
 impl I8042Device {
     /// Constructs a i8042 device that will signal the given event when the guest requests it.
     pub fn new(reset_evt: EventFd) -> I8042Device {
-        I8042Device { reset_evt: reset_evt }
+        I8042Device {
+            reset_evt: reset_evt,
+            port_b: 0,
+        }
     }
 }
 
@@ -28,7 +38,7 @@ impl BusDevice for I8042Device {
         } else if data.len() == 1 && offset == 0 {
             // Like kvmtool, we return bit 5 set in I8042_PORT_B_REG to
             // avoid hang in pit_calibrate_tsc() in Linux kernel.
-            data[0] = 0x20;
+            data[0] = self.port_b | PORT_B_TIMER2_OUT;
         }
     }
 
@@ -37,6 +47,29 @@ impl BusDevice for I8042Device {
             if let Err(e) = self.reset_evt.write(1) {
                 error!("failed to trigger i8042 reset event: {:?}", e);
             }
+        } else if data.len() == 1 && offset == 0 {
+            self.port_b = data[0] & PORT_B_WRITABLE;
         }
     }
+
+    fn reset(&mut self) {
+        self.port_b = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_b_reset() {
+        let mut i8042 = I8042Device::new(EventFd::new().unwrap());
+        let mut data = [0u8];
+        i8042.write(0, &[0xff]);
+        i8042.read(0, &mut data);
+        assert_eq!(data[0], 0x2f);
+        i8042.reset();
+        i8042.read(0, &mut data);
+        assert_eq!(data[0], 0x20);
+    }
 }
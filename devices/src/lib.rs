@@ -10,6 +10,7 @@ extern crate io_jail;
 extern crate libc;
 extern crate net_sys;
 extern crate net_util;
+extern crate qcow;
 #[macro_use]
 extern crate sys_util;
 extern crate vhost;
@@ -21,14 +22,18 @@ mod cmos;
 mod i8042;
 mod proxy;
 mod serial;
+mod watchdog;
+pub mod fakes;
+pub mod pci;
 pub mod pl030;
 pub mod virtio;
 pub mod usb;
 
-pub use self::bus::{Bus, BusDevice};
+pub use self::bus::{Bus, BusAccess, BusDevice, BusTraceFn, ReplayError, bus_recorder, replay};
 pub use self::cmos::Cmos;
 pub use self::pl030::Pl030;
 pub use self::i8042::I8042Device;
 pub use self::proxy::ProxyDevice;
 pub use self::proxy::Error as ProxyError;
-pub use self::serial::Serial;
+pub use self::serial::{Serial, SerialInputTranslation, TimestampWriter};
+pub use self::watchdog::{Watchdog, WATCHDOG_SIZE};
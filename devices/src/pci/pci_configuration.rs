@@ -0,0 +1,577 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt::{self, Display};
+
+// The number of 32bit registers in the config space, 256 bytes.
+const NUM_CONFIGURATION_REGISTERS: usize = 64;
+
+const COMMAND_REG: usize = 1;
+// Bits of the command register, the low half of COMMAND_REG.
+pub const COMMAND_IO_SPACE: u16 = 0x1;
+pub const COMMAND_MEMORY_SPACE: u16 = 0x2;
+pub const COMMAND_BUS_MASTER: u16 = 0x4;
+const BAR0_REG: usize = 4;
+const NUM_BAR_REGS: usize = 6;
+const SUBSYSTEM_ID_REG: usize = 11;
+const INTERRUPT_LINE_REG: usize = 15;
+// The expansion ROM BAR is at 0x30 in a device header and 0x38 in a bridge header.
+const DEVICE_ROM_BAR_REG: usize = 12;
+const BRIDGE_ROM_BAR_REG: usize = 14;
+const HEADER_TYPE_REG: usize = 3;
+
+// Bits 1:0 of an I/O BAR and bits 3:0 of a memory BAR describe the BAR and are read only.
+const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
+const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
+const BAR_IO_SPACE: u32 = 0x1;
+const BAR_MEM_TYPE_MASK: u32 = 0x6;
+const BAR_MEM_TYPE_64BIT: u32 = 0x4;
+const BAR_MEM_PREFETCHABLE: u32 = 0x8;
+
+// Bits 31:11 of the expansion ROM BAR hold the address and bit 0 enables decoding.
+const ROM_BAR_ADDR_MASK: u32 = 0xffff_f800;
+const ROM_BAR_ENABLE: u32 = 0x1;
+
+// Smallest regions a BAR can decode. See section 6.2.5.1 of the PCI local bus specification.
+const MIN_IO_REGION_SIZE: u64 = 0x4;
+const MIN_MMIO_REGION_SIZE: u64 = 0x10;
+const MIN_ROM_SIZE: u64 = 0x800;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // There's no BAR with this index, or no room for the upper half of a 64-bit BAR.
+    BarInvalid(usize),
+    BarInUse(usize),
+    // BARs decode naturally aligned power of two sized regions.
+    BarSizeInvalid(u64),
+    // The device already has an expansion ROM.
+    RomInUse,
+    // The contents of the expansion ROM are larger than the region it decodes.
+    RomTooLarge(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::BarInvalid(index) => write!(f, "BAR {} is invalid", index),
+            &Error::BarInUse(index) => write!(f, "BAR {} is already in use", index),
+            &Error::BarSizeInvalid(size) => write!(f, "BAR size {:#x} is invalid", size),
+            &Error::RomInUse => write!(f, "expansion ROM is already in use"),
+            &Error::RomTooLarge(len) => {
+                write!(f, "expansion ROM of {} bytes doesn't fit its region", len)
+            }
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Represents the types of PCI headers allowed in the configuration registers.
+#[derive(Copy, Clone)]
+pub enum PciHeaderType {
+    Device,
+    Bridge,
+}
+
+/// Classes of PCI nodes.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciClassCode {
+    TooOld,
+    MassStorage,
+    NetworkController,
+    DisplayController,
+    MultimediaController,
+    MemoryController,
+    BridgeDevice,
+    SimpleCommunicationController,
+    BaseSystemPeripheral,
+    InputDevice,
+    DockingStation,
+    Processor,
+    SerialBusController,
+    WirelessController,
+    IntelligentIoController,
+    EncryptionController,
+    DataAcquisitionSignalProcessing,
+    Other = 0xff,
+}
+
+impl PciClassCode {
+    pub fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// A PCI subclass. Each class in `PciClassCode` can specify a unique set of subclasses. This trait
+/// is implemented by each subclass so that a configuration can be built from any of them.
+pub trait PciSubclass {
+    /// Convert this subclass to the value used in the PCI specification.
+    fn get_register_value(&self) -> u8;
+}
+
+/// Subclasses of the MultimediaController class.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciMultimediaSubclass {
+    VideoController = 0x00,
+    AudioController = 0x01,
+    TelephonyDevice = 0x02,
+    AudioDevice = 0x03,
+    Other = 0x80,
+}
+
+impl PciSubclass for PciMultimediaSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// Subclasses of the SerialBusController class.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciSerialBusSubclass {
+    Firewire = 0x00,
+    ACCESSbus = 0x01,
+    SSA = 0x02,
+    USB = 0x03,
+}
+
+impl PciSubclass for PciSerialBusSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PciBarRegionType {
+    IoRegion,
+    Memory64BitRegion,
+}
+
+impl PciBarRegionType {
+    // Number of BAR registers used by a region of this type.
+    fn num_regs(&self) -> usize {
+        match self {
+            &PciBarRegionType::IoRegion => 1,
+            &PciBarRegionType::Memory64BitRegion => 2,
+        }
+    }
+}
+
+// A region decoded by one or two BAR registers starting at `index`.
+struct PciBar {
+    index: usize,
+    size: u64,
+    region_type: PciBarRegionType,
+    // Address of the region the device decodes, once the guest programmed one. None while the
+    // guest is sizing the BAR.
+    base: Option<u64>,
+}
+
+/// Called with the new value of the command register whenever the guest changes it, such as when
+/// it enables decoding of the device's BARs or bus mastering.
+pub type CommandObserver = FnMut(u16) + Send;
+
+/// Contains the configuration space of a PCI node.
+/// See the [specification](https://en.wikipedia.org/wiki/PCI_configuration_space).
+/// The configuration space is accessed with DWORD reads and writes from the guest.
+pub struct PciConfiguration {
+    registers: [u32; NUM_CONFIGURATION_REGISTERS],
+    writable_bits: [u32; NUM_CONFIGURATION_REGISTERS], // writable bits for each register.
+    bars: Vec<PciBar>,
+    command_observer: Option<Box<CommandObserver>>,
+    // Size of the region decoded by the expansion ROM BAR and the contents at its start.
+    rom: Option<(u64, Vec<u8>)>,
+}
+
+impl PciConfiguration {
+    pub fn new(vendor_id: u16,
+               device_id: u16,
+               class_code: PciClassCode,
+               subclass: &PciSubclass,
+               header_type: PciHeaderType)
+               -> Self {
+        let mut registers = [0u32; NUM_CONFIGURATION_REGISTERS];
+        let mut writable_bits = [0u32; NUM_CONFIGURATION_REGISTERS];
+        registers[0] = u32::from(device_id) << 16 | u32::from(vendor_id);
+        writable_bits[COMMAND_REG] = 0x0000_ffff;
+        registers[2] = u32::from(class_code.get_register_value()) << 24 |
+                       u32::from(subclass.get_register_value()) << 16;
+        match header_type {
+            PciHeaderType::Device => (),
+            PciHeaderType::Bridge => registers[3] = 0x0001_0000,
+        };
+        writable_bits[INTERRUPT_LINE_REG] = 0x0000_00ff;
+
+        PciConfiguration {
+            registers: registers,
+            writable_bits: writable_bits,
+            bars: Vec::new(),
+            command_observer: None,
+            rom: None,
+        }
+    }
+
+    /// Sets the subsystem vendor and device IDs, which some drivers match on to tell apart boards
+    /// built around the same chip. They only exist in a device header.
+    pub fn set_subsystem_ids(&mut self, subsystem_vendor_id: u16, subsystem_id: u16) {
+        self.registers[SUBSYSTEM_ID_REG] = u32::from(subsystem_id) << 16 |
+                                           u32::from(subsystem_vendor_id);
+    }
+
+    /// Calls `observer` on every change to the command register from now on, or stops if it is
+    /// `None`.
+    pub fn set_command_observer(&mut self, observer: Option<Box<CommandObserver>>) {
+        self.command_observer = observer;
+    }
+
+    /// Returns the command register.
+    pub fn command(&self) -> u16 {
+        self.registers[COMMAND_REG] as u16
+    }
+
+    /// Returns true if the guest enabled decoding of the I/O BARs.
+    pub fn io_space_enabled(&self) -> bool {
+        self.command() & COMMAND_IO_SPACE != 0
+    }
+
+    /// Returns true if the guest enabled decoding of the memory BARs.
+    pub fn memory_space_enabled(&self) -> bool {
+        self.command() & COMMAND_MEMORY_SPACE != 0
+    }
+
+    /// Reads a 32bit register from `reg_idx` in the register map.
+    pub fn read_reg(&self, reg_idx: usize) -> u32 {
+        *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
+    }
+
+    /// Writes a 32bit register to `reg_idx` in the register map. Read only bits keep their value.
+    ///
+    /// Guests size a BAR by writing all ones to it and reading back which address bits stuck, so
+    /// the BAR stops decoding until the guest writes an address again.
+    pub fn write_reg(&mut self, reg_idx: usize, value: u32) {
+        let old_command = self.command();
+        if let Some(r) = self.registers.get_mut(reg_idx) {
+            let mask = self.writable_bits[reg_idx];
+            *r = (*r & !mask) | (value & mask);
+        } else {
+            warn!("bad PCI register write {}", reg_idx);
+            return;
+        }
+
+        let command = self.command();
+        if command != old_command {
+            if let Some(ref mut observer) = self.command_observer {
+                observer(command);
+            }
+        }
+
+        if reg_idx < BAR0_REG || reg_idx >= BAR0_REG + NUM_BAR_REGS {
+            return;
+        }
+        let bar_reg = reg_idx - BAR0_REG;
+        let pos = match self.bars
+            .iter()
+            .position(|b| bar_reg >= b.index && bar_reg < b.index + b.region_type.num_regs()) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let sizing = value == 0xffff_ffff;
+        let addr = self.get_bar_addr(self.bars[pos].index);
+        let bar = &mut self.bars[pos];
+        bar.base = if sizing || addr == 0 || addr.checked_add(bar.size).is_none() {
+            None
+        } else {
+            Some(addr)
+        };
+    }
+
+    /// Adds an I/O region of `size` bytes decoded by BAR `index`.
+    pub fn add_io_region(&mut self, index: usize, size: u64) -> Result<()> {
+        if size < MIN_IO_REGION_SIZE || size > u64::from(u32::max_value()) ||
+           !size.is_power_of_two() {
+            return Err(Error::BarSizeInvalid(size));
+        }
+        self.add_bar(index, size, PciBarRegionType::IoRegion)?;
+        let reg_idx = BAR0_REG + index;
+        self.registers[reg_idx] = BAR_IO_SPACE;
+        self.writable_bits[reg_idx] = !(size - 1) as u32 & BAR_IO_ADDR_MASK;
+        Ok(())
+    }
+
+    /// Adds a prefetchable memory region of `size` bytes that can be placed anywhere in the 64-bit
+    /// address space. The region is decoded by BARs `index` and `index + 1`, which hold the low and
+    /// high halves of its address.
+    pub fn add_mmio_region_64(&mut self, index: usize, size: u64) -> Result<()> {
+        if size < MIN_MMIO_REGION_SIZE || !size.is_power_of_two() {
+            return Err(Error::BarSizeInvalid(size));
+        }
+        self.add_bar(index, size, PciBarRegionType::Memory64BitRegion)?;
+        let reg_idx = BAR0_REG + index;
+        let addr_mask = !(size - 1);
+        self.registers[reg_idx] = BAR_MEM_TYPE_64BIT | BAR_MEM_PREFETCHABLE;
+        self.writable_bits[reg_idx] = addr_mask as u32 & BAR_MEM_ADDR_MASK;
+        self.registers[reg_idx + 1] = 0;
+        self.writable_bits[reg_idx + 1] = (addr_mask >> 32) as u32;
+        Ok(())
+    }
+
+    /// Adds an expansion ROM holding `data`, decoded by the expansion ROM BAR in a region of `size`
+    /// bytes. The rest of the region reads as zeros.
+    pub fn add_expansion_rom(&mut self, size: u64, data: Vec<u8>) -> Result<()> {
+        if self.rom.is_some() {
+            return Err(Error::RomInUse);
+        }
+        if size < MIN_ROM_SIZE || size > u64::from(u32::max_value()) || !size.is_power_of_two() {
+            return Err(Error::BarSizeInvalid(size));
+        }
+        if data.len() as u64 > size {
+            return Err(Error::RomTooLarge(data.len()));
+        }
+        let reg_idx = self.rom_bar_reg();
+        self.registers[reg_idx] = 0;
+        self.writable_bits[reg_idx] = !(size - 1) as u32 & ROM_BAR_ADDR_MASK | ROM_BAR_ENABLE;
+        self.rom = Some((size, data));
+        Ok(())
+    }
+
+    /// Returns the address and size of the expansion ROM region, or None unless the guest
+    /// programmed an address and enabled both the ROM and memory space decoding.
+    pub fn get_expansion_rom_region(&self) -> Option<(u64, u64)> {
+        let size = match self.rom {
+            Some((size, _)) => size,
+            None => return None,
+        };
+        let bar = self.registers[self.rom_bar_reg()];
+        let addr = u64::from(bar & ROM_BAR_ADDR_MASK);
+        if bar & ROM_BAR_ENABLE == 0 || addr == 0 || !self.memory_space_enabled() {
+            return None;
+        }
+        Some((addr, size))
+    }
+
+    /// Reads the expansion ROM at `addr` into `data`. Returns false, leaving `data` untouched, if
+    /// `addr` isn't in the decoded ROM region.
+    pub fn read_expansion_rom(&self, addr: u64, data: &mut [u8]) -> bool {
+        let (base, size) = match self.get_expansion_rom_region() {
+            Some(region) => region,
+            None => return false,
+        };
+        if addr < base || addr - base >= size {
+            return false;
+        }
+        let contents = match self.rom {
+            Some((_, ref contents)) => contents,
+            None => return false,
+        };
+        let offset = addr - base;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = contents.get((offset + i as u64) as usize).cloned().unwrap_or(0);
+        }
+        true
+    }
+
+    /// Returns the address the guest programmed into BAR `index`. For a 64-bit BAR this combines
+    /// both BAR registers.
+    pub fn get_bar_addr(&self, index: usize) -> u64 {
+        if index >= NUM_BAR_REGS {
+            return 0;
+        }
+        let bar = self.registers[BAR0_REG + index];
+        if bar & BAR_IO_SPACE != 0 {
+            return u64::from(bar & BAR_IO_ADDR_MASK);
+        }
+        let mut addr = u64::from(bar & BAR_MEM_ADDR_MASK);
+        if bar & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64BIT && index + 1 < NUM_BAR_REGS {
+            addr |= u64::from(self.registers[BAR0_REG + index + 1]) << 32;
+        }
+        addr
+    }
+
+    /// Returns the address and size of the region decoded by BAR `index`, or None if the guest
+    /// hasn't programmed an address into it or is sizing it.
+    pub fn get_bar_region(&self, index: usize) -> Option<(u64, u64)> {
+        self.bars
+            .iter()
+            .find(|b| b.index == index)
+            .and_then(|b| b.base.map(|base| (base, b.size)))
+    }
+
+    /// Finds the BAR decoding `addr`, of the given region type. Returns the index of the BAR and
+    /// the offset of `addr` in its region.
+    pub fn find_bar(&self, region_type: PciBarRegionType, addr: u64) -> Option<(usize, u64)> {
+        self.bars
+            .iter()
+            .filter(|b| b.region_type == region_type)
+            .filter_map(|b| b.base.map(|base| (b.index, base, b.size)))
+            .find(|&(_, base, size)| addr >= base && addr - base < size)
+            .map(|(index, base, _)| (index, addr - base))
+    }
+
+    fn rom_bar_reg(&self) -> usize {
+        if (self.registers[HEADER_TYPE_REG] >> 16) & 0x7f == 1 {
+            BRIDGE_ROM_BAR_REG
+        } else {
+            DEVICE_ROM_BAR_REG
+        }
+    }
+
+    // Claims the BAR registers starting at `index` for a region, if they are all available.
+    fn add_bar(&mut self, index: usize, size: u64, region_type: PciBarRegionType) -> Result<()> {
+        let count = region_type.num_regs();
+        if index + count > NUM_BAR_REGS {
+            return Err(Error::BarInvalid(index));
+        }
+        let used = |i: usize| {
+            self.bars
+                .iter()
+                .any(|b| i >= b.index && i < b.index + b.region_type.num_regs())
+        };
+        if let Some(i) = (index..index + count).find(|&i| used(i)) {
+            return Err(Error::BarInUse(i));
+        }
+        self.bars.push(PciBar {
+            index: index,
+            size: size,
+            region_type: region_type,
+            base: None,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn audio_config() -> PciConfiguration {
+        PciConfiguration::new(0x8086,
+                              0x2415,
+                              PciClassCode::MultimediaController,
+                              &PciMultimediaSubclass::AudioDevice,
+                              PciHeaderType::Device)
+    }
+
+    #[test]
+    fn mmio_region_64() {
+        let mut cfg = audio_config();
+        cfg.add_mmio_region_64(2, 0x1_0000).unwrap();
+        assert_eq!(cfg.read_reg(BAR0_REG + 2), 0xc);
+        assert_eq!(cfg.read_reg(BAR0_REG + 3), 0);
+
+        cfg.write_reg(BAR0_REG + 2, 0x1234_5678);
+        cfg.write_reg(BAR0_REG + 3, 0x0000_0001);
+        // The type bits stay set and the address is aligned to the size of the region.
+        assert_eq!(cfg.read_reg(BAR0_REG + 2), 0x1234_000c);
+        assert_eq!(cfg.read_reg(BAR0_REG + 3), 0x0000_0001);
+        assert_eq!(cfg.get_bar_addr(2), 0x1_1234_0000);
+    }
+
+    #[test]
+    fn bar_slots() {
+        let mut cfg = audio_config();
+        cfg.add_io_region(1, 0x100).unwrap();
+        assert_eq!(cfg.add_mmio_region_64(0, 0x1000), Err(Error::BarInUse(1)));
+        assert_eq!(cfg.add_mmio_region_64(1, 0x1000), Err(Error::BarInUse(1)));
+        assert_eq!(cfg.add_mmio_region_64(5, 0x1000), Err(Error::BarInvalid(5)));
+        assert_eq!(cfg.add_mmio_region_64(2, 0x1800), Err(Error::BarSizeInvalid(0x1800)));
+        assert_eq!(cfg.add_mmio_region_64(2, 0x8), Err(Error::BarSizeInvalid(0x8)));
+        cfg.add_mmio_region_64(2, 0x1000).unwrap();
+        assert_eq!(cfg.add_io_region(3, 0x100), Err(Error::BarInUse(3)));
+    }
+
+    #[test]
+    fn size_and_program_bar() {
+        let mut cfg = audio_config();
+        cfg.add_io_region(0, 0x100).unwrap();
+        cfg.add_mmio_region_64(1, 0x4000).unwrap();
+
+        cfg.write_reg(BAR0_REG, 0xffff_ffff);
+        assert_eq!(cfg.read_reg(BAR0_REG), 0xffff_ff01);
+        cfg.write_reg(BAR0_REG + 1, 0xffff_ffff);
+        cfg.write_reg(BAR0_REG + 2, 0xffff_ffff);
+        assert_eq!(cfg.read_reg(BAR0_REG + 1), 0xffff_c00c);
+        assert_eq!(cfg.read_reg(BAR0_REG + 2), 0xffff_ffff);
+        assert_eq!(cfg.get_bar_region(0), None);
+        assert_eq!(cfg.get_bar_region(1), None);
+
+        cfg.write_reg(BAR0_REG, 0xc000);
+        cfg.write_reg(BAR0_REG + 1, 0xe000_0000);
+        cfg.write_reg(BAR0_REG + 2, 0x2);
+        assert_eq!(cfg.read_reg(BAR0_REG), 0xc001);
+        assert_eq!(cfg.get_bar_region(0), Some((0xc000, 0x100)));
+        assert_eq!(cfg.get_bar_region(1), Some((0x2_e000_0000, 0x4000)));
+        assert_eq!(cfg.find_bar(PciBarRegionType::IoRegion, 0xc010), Some((0, 0x10)));
+        assert_eq!(cfg.find_bar(PciBarRegionType::Memory64BitRegion, 0x2_e000_3fff),
+                   Some((1, 0x3fff)));
+        assert_eq!(cfg.find_bar(PciBarRegionType::Memory64BitRegion, 0x2_e000_4000), None);
+        assert_eq!(cfg.find_bar(PciBarRegionType::Memory64BitRegion, 0xc010), None);
+
+        // Sizing again stops decoding until the address is restored.
+        cfg.write_reg(BAR0_REG, 0xffff_ffff);
+        assert_eq!(cfg.find_bar(PciBarRegionType::IoRegion, 0xc010), None);
+        cfg.write_reg(BAR0_REG, 0xc000);
+        assert_eq!(cfg.find_bar(PciBarRegionType::IoRegion, 0xc010), Some((0, 0x10)));
+    }
+
+    #[test]
+    fn command_observer() {
+        let mut cfg = audio_config();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let observed = commands.clone();
+        cfg.set_command_observer(Some(Box::new(move |command| {
+            observed.lock().unwrap().push(command)
+        })));
+
+        assert!(!cfg.memory_space_enabled());
+        cfg.write_reg(COMMAND_REG, u32::from(COMMAND_MEMORY_SPACE));
+        assert!(cfg.memory_space_enabled());
+        // Writing the same value again, or another register, is no change.
+        cfg.write_reg(COMMAND_REG, u32::from(COMMAND_MEMORY_SPACE));
+        cfg.write_reg(INTERRUPT_LINE_REG, 5);
+        cfg.write_reg(COMMAND_REG, 0);
+        assert!(!cfg.memory_space_enabled());
+        assert_eq!(*commands.lock().unwrap(), vec![COMMAND_MEMORY_SPACE, 0]);
+    }
+
+    #[test]
+    fn expansion_rom() {
+        let mut cfg = audio_config();
+        assert_eq!(cfg.add_expansion_rom(0x400, vec![0x55, 0xaa]),
+                   Err(Error::BarSizeInvalid(0x400)));
+        assert_eq!(cfg.add_expansion_rom(0x800, vec![0; 0x801]), Err(Error::RomTooLarge(0x801)));
+        cfg.add_expansion_rom(0x1000, vec![0x55, 0xaa, 0x08]).unwrap();
+        assert_eq!(cfg.add_expansion_rom(0x1000, Vec::new()), Err(Error::RomInUse));
+
+        // Sizing, leaving the enable bit clear.
+        cfg.write_reg(DEVICE_ROM_BAR_REG, 0xffff_fffe);
+        assert_eq!(cfg.read_reg(DEVICE_ROM_BAR_REG), 0xffff_f000);
+
+        cfg.write_reg(DEVICE_ROM_BAR_REG, 0xfebf_0000 | ROM_BAR_ENABLE);
+        cfg.write_reg(COMMAND_REG, u32::from(COMMAND_MEMORY_SPACE));
+        assert_eq!(cfg.get_expansion_rom_region(), Some((0xfebf_0000, 0x1000)));
+        let mut data = [0xff; 4];
+        assert!(cfg.read_expansion_rom(0xfebf_0001, &mut data));
+        assert_eq!(data, [0xaa, 0x08, 0, 0]);
+        assert!(!cfg.read_expansion_rom(0xfebf_1000, &mut data));
+
+        // Clearing the enable bit stops decoding.
+        cfg.write_reg(DEVICE_ROM_BAR_REG, 0xfebf_0000);
+        assert_eq!(cfg.get_expansion_rom_region(), None);
+        assert!(!cfg.read_expansion_rom(0xfebf_0000, &mut data));
+    }
+
+    #[test]
+    fn subsystem_ids() {
+        let mut cfg = audio_config();
+        assert_eq!(cfg.read_reg(SUBSYSTEM_ID_REG), 0);
+        cfg.set_subsystem_ids(0x1af4, 0x1100);
+        assert_eq!(cfg.read_reg(SUBSYSTEM_ID_REG), 0x1100_1af4);
+        // Read only to the guest.
+        cfg.write_reg(SUBSYSTEM_ID_REG, 0);
+        assert_eq!(cfg.read_reg(SUBSYSTEM_ID_REG), 0x1100_1af4);
+    }
+}
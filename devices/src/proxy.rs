@@ -41,6 +41,7 @@ enum Command {
     Read = 0,
     Write = 1,
     Shutdown = 2,
+    Reset = 3,
 }
 
 fn child_proc(sock: UnixDatagram, device: &mut BusDevice) {
@@ -72,6 +73,9 @@ fn child_proc(sock: UnixDatagram, device: &mut BusDevice) {
         } else if cmd == Command::Write as u32 {
             device.write(offset, &buf[16..16 + len]);
             handle_eintr!(sock.send(&buf))
+        } else if cmd == Command::Reset as u32 {
+            device.reset();
+            handle_eintr!(sock.send(&buf))
         } else if cmd == Command::Shutdown as u32 {
             running = false;
             handle_eintr!(sock.send(&buf))
@@ -178,6 +182,13 @@ impl BusDevice for ProxyDevice {
             error!("failed write to child device process: {}", e);
         }
     }
+
+    fn reset(&mut self) {
+        let res = self.send_cmd(Command::Reset, 0, 0, &[]).and_then(|_| self.wait());
+        if let Err(e) = res {
+            error!("failed to reset child device process: {}", e);
+        }
+    }
 }
 
 impl Drop for ProxyDevice {
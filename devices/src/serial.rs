@@ -4,12 +4,17 @@
 
 use std::io;
 use std::collections::VecDeque;
+use std::time::Instant;
 
-use sys_util::{EventFd, Result};
+use libc::EAGAIN;
+use sys_util::{Error, EventFd, Result};
 
 use BusDevice;
 
 const LOOP_SIZE: usize = 0x40;
+// Most bytes of host input held for the guest to read. Input beyond this is refused instead of
+// being buffered without bound.
+const INPUT_BUFFER_SIZE: usize = 0x1000;
 
 const DATA: u8 = 0;
 const IER: u8 = 1;
@@ -44,10 +49,68 @@ const DEFAULT_MODEM_CONTROL: u8 = 0x8; // Auxiliary output 2
 const DEFAULT_MODEM_STATUS: u8 = 0x20 | 0x10 | 0x80; // data ready, clear to send, carrier detect
 const DEFAULT_BAUD_DIVISOR: u16 = 12; // 9600 bps
 
+/// How bytes queued with `Serial::queue_input_bytes` are translated before the guest reads them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialInputTranslation {
+    /// Bytes are passed through unchanged.
+    None,
+    /// Carriage returns become line feeds, like the ICRNL terminal flag.
+    CrToLf,
+    /// Line feeds become a carriage return followed by a line feed.
+    LfToCrLf,
+}
+
+/// Prefixes each line written through it with the time since it was created, like `dmesg` does.
+/// Lines may be split across any number of writes and still get exactly one timestamp.
+pub struct TimestampWriter<W: io::Write> {
+    out: W,
+    start: Instant,
+    line_start: bool,
+}
+
+impl<W: io::Write> TimestampWriter<W> {
+    /// Constructs a writer that passes timestamped lines on to `out`.
+    pub fn new(out: W) -> TimestampWriter<W> {
+        TimestampWriter {
+            out: out,
+            start: Instant::now(),
+            line_start: true,
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for TimestampWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if self.line_start {
+                let t = self.start.elapsed();
+                write!(self.out, "[{:5}.{:06}] ", t.as_secs(), t.subsec_nanos() / 1000)?;
+                self.line_start = false;
+            }
+            let len = match rest.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    self.line_start = true;
+                    i + 1
+                }
+                None => rest.len(),
+            };
+            self.out.write_all(&rest[..len])?;
+            rest = &rest[len..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
 /// Emulates serial COM ports commonly seen on x86 I/O ports 0x3f8/0x2f8/0x3e8/0x2e8.
 ///
 /// This can optionally write the guest's output to a Write trait object. To send input to the
-/// guest, use `queue_input_bytes`.
+/// guest, use `queue_input_bytes`. Input is held in a bounded buffer until the guest reads it, so
+/// callers should stop feeding input while `input_space` is zero.
 pub struct Serial {
     interrupt_enable: u8,
     interrupt_identification: u8,
@@ -59,6 +122,7 @@ pub struct Serial {
     scratch: u8,
     baud_divisor: u16,
     in_buffer: VecDeque<u8>,
+    input_translation: SerialInputTranslation,
     out: Option<Box<io::Write + Send>>,
 }
 
@@ -75,6 +139,7 @@ impl Serial {
             scratch: 0,
             baud_divisor: DEFAULT_BAUD_DIVISOR,
             in_buffer: VecDeque::new(),
+            input_translation: SerialInputTranslation::None,
             out: out,
         }
     }
@@ -84,19 +149,59 @@ impl Serial {
         Self::new(interrupt_evt, Some(out))
     }
 
-    /// Constructs a Serial port with no connected output.
+    /// Constructs a Serial port with no connected output. Everything the guest writes is discarded,
+    /// but the port otherwise behaves as if it was sent.
     pub fn new_sink(interrupt_evt: EventFd) -> Serial {
         Self::new(interrupt_evt, None)
     }
 
-    /// Queues raw bytes for the guest to read and signals the interrupt if the line status would
-    /// change.
-    pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<()> {
-        if !self.is_loop() {
-            self.in_buffer.extend(c);
+    /// Sets how bytes passed to `queue_input_bytes` are translated. Defaults to
+    /// `SerialInputTranslation::None`.
+    pub fn set_input_translation(&mut self, translation: SerialInputTranslation) {
+        self.input_translation = translation;
+    }
+
+    /// Returns how many bytes `queue_input_bytes` is sure to accept, whatever they are.
+    pub fn input_space(&self) -> usize {
+        let free = INPUT_BUFFER_SIZE - self.in_buffer.len();
+        match self.input_translation {
+            // Every byte could be a line feed that turns into two.
+            SerialInputTranslation::LfToCrLf => free / 2,
+            _ => free,
+        }
+    }
+
+    /// Queues bytes for the guest to read, translated according to the input translation mode, and
+    /// signals the interrupt if the line status would change.
+    ///
+    /// Returns how many bytes of `c` were queued, which is fewer than `c.len()` if the input buffer
+    /// filled up. Fails with `EAGAIN` if the buffer had no room for any of them.
+    pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<usize> {
+        if self.is_loop() {
+            // The receiver is connected to the transmitter, so host input is lost like it would be
+            // on real hardware.
+            return Ok(c.len());
+        }
+        let mut queued = 0;
+        for &b in c {
+            let translated: &[u8] = match (self.input_translation, b) {
+                (SerialInputTranslation::CrToLf, b'\r') => b"\n",
+                (SerialInputTranslation::LfToCrLf, b'\n') => b"\r\n",
+                _ => &[b],
+            };
+            if self.in_buffer.len() + translated.len() > INPUT_BUFFER_SIZE {
+                break;
+            }
+            self.in_buffer.extend(translated);
+            queued += 1;
+        }
+        if queued == 0 && !c.is_empty() {
+            return Err(Error::new(EAGAIN));
+        }
+        if queued > 0 {
             self.recv_data()?;
         }
-        Ok(())
+        Ok(queued)
     }
 
     fn is_dlab_set(&self) -> bool {
@@ -224,33 +329,24 @@ impl BusDevice for Serial {
             _ => 0,
         };
     }
+
+    fn reset(&mut self) {
+        self.interrupt_enable = 0;
+        self.interrupt_identification = DEFAULT_INTERRUPT_IDENTIFICATION;
+        self.line_control = DEFAULT_LINE_CONTROL;
+        self.line_status = DEFAULT_LINE_STATUS;
+        self.modem_control = DEFAULT_MODEM_CONTROL;
+        self.modem_status = DEFAULT_MODEM_STATUS;
+        self.scratch = 0;
+        self.baud_divisor = DEFAULT_BAUD_DIVISOR;
+        self.in_buffer.clear();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::{Arc, Mutex};
-    use std::io;
-
-    #[derive(Clone)]
-    struct SharedBuffer {
-        buf: Arc<Mutex<Vec<u8>>>,
-    }
-
-    impl SharedBuffer {
-        fn new() -> SharedBuffer {
-            SharedBuffer { buf: Arc::new(Mutex::new(Vec::new())) }
-        }
-    }
-
-    impl io::Write for SharedBuffer {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            self.buf.lock().unwrap().write(buf)
-        }
-        fn flush(&mut self) -> io::Result<()> {
-            self.buf.lock().unwrap().flush()
-        }
-    }
+    use fakes::SharedBuffer;
 
     #[test]
     fn serial_output() {
@@ -262,7 +358,7 @@ mod tests {
         serial.write(DATA as u64, &['a' as u8]);
         serial.write(DATA as u64, &['b' as u8]);
         serial.write(DATA as u64, &['c' as u8]);
-        assert_eq!(serial_out.buf.lock().unwrap().as_slice(),
+        assert_eq!(serial_out.contents().as_slice(),
                    &['a' as u8, 'b' as u8, 'c' as u8]);
     }
 
@@ -286,4 +382,139 @@ mod tests {
         serial.read(DATA as u64, &mut data[..]);
         assert_eq!(data[0], 'c' as u8);
     }
+
+    #[test]
+    fn serial_sink() {
+        let intr_evt = EventFd::new().unwrap();
+        let mut serial = Serial::new_sink(intr_evt.try_clone().unwrap());
+        let mut data = [0u8; 1];
+
+        serial.write(IER as u64, &[IER_THR_BIT | IER_RECV_BIT]);
+        serial.write(DATA as u64, &['a' as u8]);
+        // The write is swallowed, but the guest still sees the transmitter empty out.
+        assert_eq!(intr_evt.read(), Ok(1));
+        serial.read(IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_THR_BIT | IIR_FIFO_BITS);
+        serial.read(LSR as u64, &mut data[..]);
+        assert_eq!(data[0], LSR_EMPTY_BIT | LSR_IDLE_BIT);
+
+        // Input is unaffected.
+        serial.queue_input_bytes(&['b' as u8]).unwrap();
+        assert_eq!(intr_evt.read(), Ok(1));
+        serial.read(DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'b' as u8);
+
+        serial.write(SCR as u64, &[0x5a]);
+        serial.read(SCR as u64, &mut data[..]);
+        assert_eq!(data[0], 0x5a);
+    }
+
+    #[test]
+    fn timestamped_lines() {
+        let out = SharedBuffer::new();
+        let mut writer = TimestampWriter::new(out.clone());
+        for chunk in &["boo", "ting\nfirst", " line\n", "\n", "a\nb\nc"] {
+            io::Write::write_all(&mut writer, chunk.as_bytes()).unwrap();
+        }
+
+        let text = String::from_utf8(out.contents()).unwrap();
+        let lines: Vec<&str> = text.split('\n').collect();
+        assert_eq!(lines.len(), 6);
+        let untimed: Vec<&str> = lines
+            .iter()
+            .map(|line| {
+                // Each line starts with a single "[SSSSS.UUUUUU] " timestamp.
+                assert_eq!(&line[..1], "[");
+                assert_eq!(&line[13..15], "] ");
+                assert!(line[1..13].trim_left().parse::<f64>().is_ok(), "bad line {:?}", line);
+                &line[15..]
+            })
+            .collect();
+        assert_eq!(untimed, vec!["booting", "first line", "", "a", "b", "c"]);
+    }
+
+    fn translated_input(translation: SerialInputTranslation, input: &[u8]) -> Vec<u8> {
+        let intr_evt = EventFd::new().unwrap();
+        let mut serial = Serial::new_sink(intr_evt);
+        serial.set_input_translation(translation);
+        serial.queue_input_bytes(input).unwrap();
+
+        let mut out = Vec::new();
+        let mut data = [0u8; 1];
+        loop {
+            serial.read(LSR as u64, &mut data[..]);
+            if data[0] & LSR_DATA_BIT == 0 {
+                break;
+            }
+            serial.read(DATA as u64, &mut data[..]);
+            out.push(data[0]);
+        }
+        out
+    }
+
+    #[test]
+    fn serial_input_translation() {
+        let input = b"ls\rcat a\r\nb\n";
+        assert_eq!(translated_input(SerialInputTranslation::None, input),
+                   b"ls\rcat a\r\nb\n".to_vec());
+        assert_eq!(translated_input(SerialInputTranslation::CrToLf, input),
+                   b"ls\ncat a\n\nb\n".to_vec());
+        assert_eq!(translated_input(SerialInputTranslation::LfToCrLf, input),
+                   b"ls\rcat a\r\r\nb\r\n".to_vec());
+    }
+
+    #[test]
+    fn serial_input_buffer_full() {
+        let intr_evt = EventFd::new().unwrap();
+        let mut serial = Serial::new_sink(intr_evt);
+        let input: Vec<u8> = (0..INPUT_BUFFER_SIZE + 10).map(|i| i as u8).collect();
+
+        assert_eq!(serial.input_space(), INPUT_BUFFER_SIZE);
+        assert_eq!(serial.queue_input_bytes(&input[..100]).unwrap(), 100);
+        // Only what fits is taken.
+        assert_eq!(serial.queue_input_bytes(&input[100..]).unwrap(), INPUT_BUFFER_SIZE - 100);
+        assert_eq!(serial.input_space(), 0);
+        assert_eq!(serial.queue_input_bytes(&input[INPUT_BUFFER_SIZE..]),
+                   Err(Error::new(EAGAIN)));
+
+        // Reading makes room for the rest.
+        let mut data = [0u8; 1];
+        for i in 0..10 {
+            serial.read(DATA as u64, &mut data[..]);
+            assert_eq!(data[0], input[i]);
+        }
+        assert_eq!(serial.input_space(), 10);
+        assert_eq!(serial.queue_input_bytes(&input[INPUT_BUFFER_SIZE..]).unwrap(), 10);
+
+        // Nothing was lost or reordered.
+        for i in 10..input.len() {
+            serial.read(DATA as u64, &mut data[..]);
+            assert_eq!(data[0], input[i]);
+        }
+        serial.read(LSR as u64, &mut data[..]);
+        assert_eq!(data[0] & LSR_DATA_BIT, 0);
+    }
+
+    #[test]
+    fn serial_reset() {
+        let intr_evt = EventFd::new().unwrap();
+        let mut serial = Serial::new_sink(intr_evt.try_clone().unwrap());
+
+        serial.write(IER as u64, &[IER_RECV_BIT]);
+        serial.write(SCR as u64, &[0x5a]);
+        serial.queue_input_bytes(&['a' as u8, 'b' as u8]).unwrap();
+        serial.reset();
+
+        let mut data = [0u8; 1];
+        serial.read(IER as u64, &mut data[..]);
+        assert_eq!(data[0], 0);
+        serial.read(SCR as u64, &mut data[..]);
+        assert_eq!(data[0], 0);
+        serial.read(LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS);
+        serial.read(IIR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_INTERRUPT_IDENTIFICATION | IIR_FIFO_BITS);
+        serial.read(DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 0);
+    }
 }
@@ -2,12 +2,705 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-// Wraps libusb interface. Represents a physical device attached to host usb
-// controller.
+use std::cell::RefCell;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use sys_util;
+
+use super::super::xhci::{BackendTransfer, BackendTransferType, BackendType, TransferStatus,
+                         UsbRequestSetup, XhciBackendDevice};
+
+// The parts of a device descriptor that the xhci controller asks backend devices for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HostDeviceDescriptor {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+// How long a control request may take before it is cancelled, unless configured otherwise.
+const DEFAULT_CONTROL_TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a reset waits for the cancelled transfers to call back.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A transfer to run on the host device. Control transfers carry their setup packet and go to
+// endpoint 0. A transfer without a timeout waits for the device for as long as it takes.
+pub struct HostTransfer {
+    // Names the transfer to `HostDeviceIo::cancel_transfer`.
+    pub id: u64,
+    pub endpoint: u8,
+    pub transfer_type: BackendTransferType,
+    pub buffer: Vec<u8>,
+    pub timeout: Option<Duration>,
+}
+
+// Called once a host transfer is over with the transfer, how it ended and how many bytes of its
+// buffer were transferred.
+pub type HostTransferCallback = Box<FnMut(HostTransfer, TransferStatus, usize) + Send>;
+
+// Access to a physical device attached to the host usb controller. Outside of tests this goes
+// through libusb.
+pub trait HostDeviceIo: Send {
+    // Returns the protocol the device is attached to the host with.
+    fn backend_type(&self) -> BackendType;
+
+    // Reads the device descriptor, which is a round trip to the device.
+    fn read_device_descriptor(&self) -> sys_util::Result<HostDeviceDescriptor>;
+
+    // Returns the addresses of the endpoints of the active configuration's interfaces, at their
+    // current alternate settings.
+    fn read_endpoints(&self) -> sys_util::Result<Vec<u8>>;
+
+    // Starts `transfer` on the device. `done` is called exactly once, usually from another thread,
+    // and before this function returns if the transfer couldn't be started. A transfer that runs
+    // past its timeout is cancelled and ends with `TransferStatus::Error`.
+    fn submit_transfer(&mut self, transfer: HostTransfer, done: HostTransferCallback);
+
+    // Asks the device to stop the transfer `id`. Its callback is still called, with
+    // `TransferStatus::Cancelled` unless it ended some other way first.
+    fn cancel_transfer(&mut self, id: u64);
+}
+
+// Which stage of a control transfer the default control endpoint expects next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlEndpointState {
+    SetupStage,
+    DataStage,
+    StatusStage,
+}
+
+// A stage of a control transfer. The guest queues each one as its own transfer descriptor on the
+// default control endpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlStage {
+    Setup(UsbRequestSetup),
+    Data,
+    Status,
+}
+
+// The host transfers that haven't called back yet. A reset cancels them and waits for them, and
+// starts a new generation so that the ones calling back late leave the device alone.
+#[derive(Default)]
+struct InflightTransfers {
+    ids: Vec<u64>,
+    generation: u64,
+}
+
+// The request in progress on the default control endpoint. Shared with the callbacks of the host
+// transfers carrying it out.
+struct ControlEndpoint {
+    state: ControlEndpointState,
+    setup: UsbRequestSetup,
+}
+
+// Represents a physical device attached to host usb controller.
 pub struct UsbHostDevice {
-    device: LibUsbDevice,
-    device_handle: DeviceHandle,
+    io: Box<HostDeviceIo>,
+    // Read when the device is opened and again when it is reconfigured, instead of asking the
+    // device on every query. None until it could be read, and read again when it's next needed.
+    descriptor: RefCell<Option<HostDeviceDescriptor>>,
+    backend_type: BackendType,
+    control: Arc<Mutex<ControlEndpoint>>,
+    // Keeps a wedged device from holding the control endpoint forever.
+    control_timeout: Duration,
+    // The endpoints other than the default control endpoint that transfers can go to.
+    endpoints: Vec<u8>,
+    // Set once a request that changes the endpoints completes, to create them again before the
+    // next transfer.
+    endpoints_changed: Arc<AtomicBool>,
+    inflight: Arc<(Mutex<InflightTransfers>, Condvar)>,
+    next_transfer_id: u64,
+    reset_timeout: Duration,
 }
 
 impl UsbHostDevice {
+    pub fn new(io: Box<HostDeviceIo>) -> UsbHostDevice {
+        let descriptor = read_device_descriptor(io.as_ref());
+        let backend_type = io.backend_type();
+        let mut device = UsbHostDevice {
+            io: io,
+            descriptor: RefCell::new(descriptor),
+            backend_type: backend_type,
+            control: Arc::new(Mutex::new(ControlEndpoint {
+                                             state: ControlEndpointState::SetupStage,
+                                             setup: UsbRequestSetup::default(),
+                                         })),
+            control_timeout: DEFAULT_CONTROL_TRANSFER_TIMEOUT,
+            endpoints: Vec::new(),
+            endpoints_changed: Arc::new(AtomicBool::new(false)),
+            inflight: Arc::new((Mutex::new(InflightTransfers::default()), Condvar::new())),
+            next_transfer_id: 0,
+            reset_timeout: RESET_TIMEOUT,
+        };
+        device.create_endpoints();
+        device
+    }
+
+    // Sets how long a control request may take before it fails.
+    pub fn set_control_transfer_timeout(&mut self, timeout: Duration) {
+        self.control_timeout = timeout;
+    }
+
+    // Reads the device descriptor again. Called after the guest changes the configuration.
+    pub fn refresh_device_descriptor(&mut self) {
+        *self.descriptor.borrow_mut() = read_device_descriptor(self.io.as_ref());
+    }
+
+    // Reads which endpoints the device has. If that fails, it's tried again before the next
+    // transfer.
+    fn create_endpoints(&mut self) {
+        match self.io.read_endpoints() {
+            Ok(endpoints) => self.endpoints = endpoints,
+            Err(e) => {
+                warn!("usb: failed to read the endpoints: {:?}", e);
+                self.endpoints.clear();
+                self.endpoints_changed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Catches up with a finished request that changed the configuration or an interface's
+    // alternate setting, so that the host and guest agree on the endpoints.
+    fn update_endpoints(&mut self) {
+        if self.endpoints_changed.swap(false, Ordering::SeqCst) {
+            self.refresh_device_descriptor();
+            self.create_endpoints();
+        }
+    }
+
+    // Returns the cached device descriptor, reading it first if it couldn't be read before.
+    fn device_descriptor(&self) -> Option<HostDeviceDescriptor> {
+        let mut descriptor = self.descriptor.borrow_mut();
+        if descriptor.is_none() {
+            *descriptor = read_device_descriptor(self.io.as_ref());
+        }
+        *descriptor
+    }
+
+    // Carries out one stage of a control transfer on the default control endpoint. A stage the
+    // endpoint isn't expecting completes with an error and puts the endpoint back in the setup
+    // stage, so that the guest can retry the request instead of waiting on it forever.
+    pub fn handle_control_transfer(&mut self, stage: ControlStage, transfer: BackendTransfer) {
+        self.update_endpoints();
+        let mut control = self.control.lock().unwrap();
+        match (stage, control.state) {
+            (ControlStage::Setup(setup), _) => {
+                // A setup stage starts a new request even if the last one wasn't finished.
+                control.setup = setup;
+                control.state = if setup.length > 0 {
+                    ControlEndpointState::DataStage
+                } else {
+                    ControlEndpointState::StatusStage
+                };
+                drop(control);
+                transfer.complete(TransferStatus::Completed, 0);
+            }
+            (ControlStage::Data, ControlEndpointState::DataStage) => {
+                // The host carries out the data and status stages in one transfer.
+                let setup = control.setup;
+                drop(control);
+                self.submit_control_request(setup, transfer, ControlEndpointState::StatusStage);
+            }
+            (ControlStage::Status, ControlEndpointState::StatusStage) => {
+                let setup = control.setup;
+                if setup.length > 0 {
+                    // Already done along with the data stage.
+                    control.state = ControlEndpointState::SetupStage;
+                    drop(control);
+                    transfer.complete(TransferStatus::Completed, 0);
+                } else {
+                    drop(control);
+                    self.submit_control_request(setup, transfer, ControlEndpointState::SetupStage);
+                }
+            }
+            (stage, state) => {
+                error!("usb: control endpoint expected the {:?} but got {:?}", state, stage);
+                control.state = ControlEndpointState::SetupStage;
+                drop(control);
+                transfer.complete(TransferStatus::Error, 0);
+            }
+        }
+    }
+
+    // Sends the request described by `setup` to the device as the data or status stage carried by
+    // `transfer`. The control endpoint expects `next` once the request succeeded, and a new setup
+    // stage if it failed or timed out.
+    fn submit_control_request(&mut self,
+                              setup: UsbRequestSetup,
+                              transfer: BackendTransfer,
+                              next: ControlEndpointState) {
+        let control = self.control.clone();
+        let timeout = self.control_timeout;
+        self.submit_host_transfer(transfer,
+                                  BackendTransferType::Control(setup),
+                                  Some(timeout),
+                                  move |status| {
+            control.lock().unwrap().state = if status == TransferStatus::Completed {
+                next
+            } else {
+                ControlEndpointState::SetupStage
+            };
+        });
+    }
+
+    // Runs `transfer` on the host device and completes it with the outcome, after calling `finish`
+    // with the transfer's status. A successful request that changes the endpoints has them
+    // created again before the next transfer. A transfer that calls back after the device was
+    // reset completes as cancelled and doesn't call `finish`.
+    fn submit_host_transfer<F>(&mut self,
+                               mut transfer: BackendTransfer,
+                               transfer_type: BackendTransferType,
+                               timeout: Option<Duration>,
+                               finish: F)
+        where F: FnOnce(TransferStatus) + Send + 'static
+    {
+        let id = self.next_transfer_id;
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        let generation = {
+            let mut inflight = (self.inflight.0).lock().unwrap();
+            inflight.ids.push(id);
+            inflight.generation
+        };
+        let host_transfer = HostTransfer {
+            id: id,
+            endpoint: transfer.endpoint,
+            transfer_type: transfer_type,
+            buffer: mem::replace(&mut transfer.buffer, Vec::new()),
+            timeout: timeout,
+        };
+        let endpoints_changed = self.endpoints_changed.clone();
+        let inflight = self.inflight.clone();
+        let mut pending = Some((transfer, finish));
+        let done = Box::new(move |host_transfer: HostTransfer,
+                                  status: TransferStatus,
+                                  actual_length: usize| {
+            if let Some((mut transfer, finish)) = pending.take() {
+                let reset = {
+                    let &(ref lock, ref cvar) = &*inflight;
+                    let mut inflight = lock.lock().unwrap();
+                    inflight.ids.retain(|&i| i != id);
+                    cvar.notify_all();
+                    inflight.generation != generation
+                };
+                transfer.buffer = host_transfer.buffer;
+                if reset {
+                    transfer.complete(TransferStatus::Cancelled, 0);
+                    return;
+                }
+                if let BackendTransferType::Control(setup) = transfer_type {
+                    if status == TransferStatus::Completed && setup.changes_endpoints() {
+                        endpoints_changed.store(true, Ordering::SeqCst);
+                    }
+                }
+                finish(status);
+                transfer.complete(status, actual_length);
+            }
+        });
+        self.io.submit_transfer(host_transfer, done);
+    }
+}
+
+impl XhciBackendDevice for UsbHostDevice {
+    fn get_backend_type(&self) -> BackendType {
+        self.backend_type
+    }
+
+    // Returns the vendor id, or 0 if the device descriptor is unavailable.
+    fn get_vid(&self) -> u16 {
+        self.device_descriptor().map_or(0, |d| d.vendor_id)
+    }
+
+    // Returns the product id, or 0 if the device descriptor is unavailable.
+    fn get_pid(&self) -> u16 {
+        self.device_descriptor().map_or(0, |d| d.product_id)
+    }
+
+    fn submit_transfer(&mut self, transfer: BackendTransfer) {
+        self.update_endpoints();
+        let transfer_type = transfer.transfer_type;
+        let timeout = match transfer_type {
+            BackendTransferType::Control(_) => Some(self.control_timeout),
+            _ => {
+                if !self.endpoints.contains(&transfer.endpoint) {
+                    transfer.complete(TransferStatus::Stalled, 0);
+                    return;
+                }
+                None
+            }
+        };
+        self.submit_host_transfer(transfer, transfer_type, timeout, |_status| {});
+    }
+
+    // The host assigned the device its address when it was attached.
+    fn set_address(&mut self, _address: u32) {}
+
+    // Cancels the transfers still on the device and waits for them to call back, so that none
+    // finishes into the state the device starts over with.
+    fn reset(&mut self) {
+        let ids = {
+            let mut inflight = (self.inflight.0).lock().unwrap();
+            inflight.generation = inflight.generation.wrapping_add(1);
+            inflight.ids.clone()
+        };
+        for id in ids {
+            self.io.cancel_transfer(id);
+        }
+
+        let deadline = Instant::now() + self.reset_timeout;
+        let &(ref lock, ref cvar) = &*self.inflight;
+        let mut inflight = lock.lock().unwrap();
+        while !inflight.ids.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("usb: {} transfers didn't finish after being cancelled by a reset",
+                      inflight.ids.len());
+                break;
+            }
+            inflight = cvar.wait_timeout(inflight, deadline - now).unwrap().0;
+        }
+        drop(inflight);
+
+        self.control.lock().unwrap().state = ControlEndpointState::SetupStage;
+    }
+}
+
+fn read_device_descriptor(io: &HostDeviceIo) -> Option<HostDeviceDescriptor> {
+    match io.read_device_descriptor() {
+        Ok(d) => Some(d),
+        Err(e) => {
+            warn!("usb: failed to read device descriptor: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    const VENDOR_ID: u16 = 0x18d1;
+    const PRODUCT_ID: u16 = 0x4ee7;
+
+    type Pending = Arc<Mutex<Vec<(HostTransfer, HostTransferCallback)>>>;
+    type Completed = Arc<Mutex<Vec<(TransferStatus, Vec<u8>)>>>;
+
+    // Counts the descriptor reads, the first `failed_reads` of which fail as if the device was
+    // busy. Transfers stay pending until the test completes them, or until they are cancelled
+    // unless the device is `wedged`.
+    #[derive(Default)]
+    struct FakeHostIo {
+        descriptor_reads: Arc<AtomicUsize>,
+        failed_reads: usize,
+        endpoint_reads: Arc<AtomicUsize>,
+        pending: Pending,
+        wedged: bool,
+    }
+
+    // The bulk IN and OUT endpoints of the fake device.
+    const BULK_IN: u8 = 0x81;
+    const BULK_OUT: u8 = 0x02;
+
+    impl HostDeviceIo for FakeHostIo {
+        fn backend_type(&self) -> BackendType {
+            BackendType::Usb2
+        }
+
+        fn read_device_descriptor(&self) -> sys_util::Result<HostDeviceDescriptor> {
+            let reads = self.descriptor_reads.fetch_add(1, Ordering::SeqCst) + 1;
+            if reads <= self.failed_reads {
+                return Err(sys_util::Error::new(libc::EBUSY));
+            }
+            Ok(HostDeviceDescriptor {
+                vendor_id: VENDOR_ID,
+                product_id: PRODUCT_ID,
+            })
+        }
+
+        fn read_endpoints(&self) -> sys_util::Result<Vec<u8>> {
+            self.endpoint_reads.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![BULK_IN, BULK_OUT])
+        }
+
+        fn submit_transfer(&mut self, transfer: HostTransfer, done: HostTransferCallback) {
+            self.pending.lock().unwrap().push((transfer, done));
+        }
+
+        fn cancel_transfer(&mut self, id: u64) {
+            if self.wedged {
+                return;
+            }
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(i) = pending.iter().position(|p| p.0.id == id) {
+                let (transfer, mut done) = pending.remove(i);
+                drop(pending);
+                // libusb calls back from its event thread.
+                thread::spawn(move || done(transfer, TransferStatus::Cancelled, 0));
+            }
+        }
+    }
+
+    // Completes the oldest pending host transfer, filling in `data` for an IN transfer.
+    fn complete_host_transfer(pending: &Pending, status: TransferStatus, data: &[u8]) {
+        let (mut transfer, mut done) = pending.lock().unwrap().remove(0);
+        transfer.buffer[..data.len()].copy_from_slice(data);
+        done(transfer, status, data.len());
+    }
+
+    fn make_transfer(len: usize, completed: &Completed) -> BackendTransfer {
+        make_bulk_transfer(0, len, completed)
+    }
+
+    fn make_bulk_transfer(endpoint: u8, len: usize, completed: &Completed) -> BackendTransfer {
+        let mut transfer = BackendTransfer::new(endpoint, BackendTransferType::Bulk, vec![0; len]);
+        let completed = completed.clone();
+        transfer.set_callback(Box::new(move |t: BackendTransfer| {
+            let data = t.buffer[..t.actual_length].to_vec();
+            completed.lock().unwrap().push((t.status, data));
+        }));
+        transfer
+    }
+
+    fn control_state(device: &UsbHostDevice) -> ControlEndpointState {
+        device.control.lock().unwrap().state
+    }
+
+    // GET_DESCRIPTOR for the first `length` bytes of the device descriptor.
+    fn get_descriptor(length: u16) -> UsbRequestSetup {
+        UsbRequestSetup {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0100,
+            index: 0,
+            length: length,
+        }
+    }
+
+    #[test]
+    fn descriptor_cached() {
+        let io = FakeHostIo::default();
+        let reads = io.descriptor_reads.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        for _ in 0..10 {
+            assert_eq!(device.get_vid(), VENDOR_ID);
+            assert_eq!(device.get_pid(), PRODUCT_ID);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        device.refresh_device_descriptor();
+        assert_eq!(device.get_vid(), VENDOR_ID);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn descriptor_read_retried() {
+        let io = FakeHostIo {
+            failed_reads: 2,
+            ..Default::default()
+        };
+        let reads = io.descriptor_reads.clone();
+        let device = UsbHostDevice::new(Box::new(io));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        // Still busy.
+        assert_eq!(device.get_vid(), 0);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert_eq!(device.get_vid(), VENDOR_ID);
+        assert_eq!(device.get_pid(), PRODUCT_ID);
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn control_transfer_stages() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+
+        device.handle_control_transfer(ControlStage::Setup(get_descriptor(4)),
+                                       make_transfer(0, &completed));
+        assert_eq!(control_state(&device), ControlEndpointState::DataStage);
+        device.handle_control_transfer(ControlStage::Data, make_transfer(4, &completed));
+        match pending.lock().unwrap()[0].0.transfer_type {
+            BackendTransferType::Control(setup) => assert_eq!(setup, get_descriptor(4)),
+            t => panic!("unexpected host transfer type {:?}", t),
+        }
+        complete_host_transfer(&pending, TransferStatus::Completed, &[0x12, 0x01, 0x00, 0x02]);
+        device.handle_control_transfer(ControlStage::Status, make_transfer(0, &completed));
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+
+        let completed = completed.lock().unwrap();
+        assert_eq!(completed.len(), 3);
+        assert_eq!(completed[1], (TransferStatus::Completed, vec![0x12, 0x01, 0x00, 0x02]));
+        assert!(completed.iter().all(|c| c.0 == TransferStatus::Completed));
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn control_stage_out_of_order() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+
+        // Data and status stages without a setup stage.
+        device.handle_control_transfer(ControlStage::Data, make_transfer(4, &completed));
+        device.handle_control_transfer(ControlStage::Status, make_transfer(0, &completed));
+        // A data stage for a request that has none.
+        let set_configuration = UsbRequestSetup {
+            request_type: 0x00,
+            request: 0x09,
+            value: 1,
+            index: 0,
+            length: 0,
+        };
+        device.handle_control_transfer(ControlStage::Setup(set_configuration),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Data, make_transfer(4, &completed));
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+        assert!(pending.lock().unwrap().is_empty());
+        assert_eq!(*completed.lock().unwrap(),
+                   vec![(TransferStatus::Error, vec![]),
+                        (TransferStatus::Error, vec![]),
+                        (TransferStatus::Completed, vec![]),
+                        (TransferStatus::Error, vec![])]);
+
+        // The guest can retry the request.
+        device.handle_control_transfer(ControlStage::Setup(set_configuration),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Status, make_transfer(0, &completed));
+        complete_host_transfer(&pending, TransferStatus::Completed, &[]);
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+        assert_eq!(completed.lock().unwrap().last(), Some(&(TransferStatus::Completed, vec![])));
+    }
+
+    #[test]
+    fn control_data_stage_failed() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+
+        device.handle_control_transfer(ControlStage::Setup(get_descriptor(18)),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Data, make_transfer(18, &completed));
+        complete_host_transfer(&pending, TransferStatus::Stalled, &[]);
+        assert_eq!(completed.lock().unwrap()[1], (TransferStatus::Stalled, vec![]));
+        // The status stage the guest queued after the failed one is out of order now.
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+    }
+
+    #[test]
+    fn control_transfer_timeout() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        device.set_control_transfer_timeout(Duration::from_millis(100));
+        let completed = Completed::default();
+
+        device.handle_control_transfer(ControlStage::Setup(get_descriptor(18)),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Data, make_transfer(18, &completed));
+        assert_eq!(pending.lock().unwrap()[0].0.timeout, Some(Duration::from_millis(100)));
+        // The device never answers and the host gives up on the transfer.
+        complete_host_transfer(&pending, TransferStatus::Error, &[]);
+        assert_eq!(completed.lock().unwrap()[1], (TransferStatus::Error, vec![]));
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+
+        // Only control requests time out.
+        device.submit_transfer(make_bulk_transfer(BULK_IN, 512, &completed));
+        assert_eq!(pending.lock().unwrap()[0].0.timeout, None);
+    }
+
+    #[test]
+    fn set_interface_recreates_endpoints() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let reads = io.endpoint_reads.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // A request that doesn't touch the endpoints.
+        device.handle_control_transfer(ControlStage::Setup(get_descriptor(18)),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Data, make_transfer(18, &completed));
+        complete_host_transfer(&pending, TransferStatus::Completed, &[0; 18]);
+        device.handle_control_transfer(ControlStage::Status, make_transfer(0, &completed));
+        device.submit_transfer(make_bulk_transfer(BULK_OUT, 4, &completed));
+        complete_host_transfer(&pending, TransferStatus::Completed, &[]);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // SET_INTERFACE to alternate setting 1 of interface 0.
+        let set_interface = UsbRequestSetup {
+            request_type: 0x01,
+            request: 0x0b,
+            value: 1,
+            index: 0,
+            length: 0,
+        };
+        device.handle_control_transfer(ControlStage::Setup(set_interface),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Status, make_transfer(0, &completed));
+        complete_host_transfer(&pending, TransferStatus::Completed, &[]);
+        device.submit_transfer(make_bulk_transfer(BULK_IN, 512, &completed));
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert_eq!(pending.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_endpoint_stalls() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+
+        device.submit_transfer(make_bulk_transfer(0x83, 512, &completed));
+        assert!(pending.lock().unwrap().is_empty());
+        assert_eq!(*completed.lock().unwrap(), vec![(TransferStatus::Stalled, vec![])]);
+    }
+
+    #[test]
+    fn reset_cancels_transfers() {
+        let io = FakeHostIo::default();
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        let completed = Completed::default();
+
+        device.submit_transfer(make_bulk_transfer(BULK_IN, 512, &completed));
+        device.submit_transfer(make_bulk_transfer(BULK_OUT, 512, &completed));
+        device.reset();
+        // Both callbacks ran before the reset returned.
+        assert!(pending.lock().unwrap().is_empty());
+        assert_eq!(*completed.lock().unwrap(),
+                   vec![(TransferStatus::Cancelled, vec![]), (TransferStatus::Cancelled, vec![])]);
+    }
+
+    #[test]
+    fn reset_deadline() {
+        let io = FakeHostIo {
+            wedged: true,
+            ..Default::default()
+        };
+        let pending = io.pending.clone();
+        let mut device = UsbHostDevice::new(Box::new(io));
+        device.reset_timeout = Duration::from_millis(20);
+        let completed = Completed::default();
+
+        device.handle_control_transfer(ControlStage::Setup(get_descriptor(18)),
+                                       make_transfer(0, &completed));
+        device.handle_control_transfer(ControlStage::Data, make_transfer(18, &completed));
+        let start = Instant::now();
+        device.reset();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+
+        // The device finally answers, after the reset gave up on it.
+        complete_host_transfer(&pending, TransferStatus::Completed, &[0; 18]);
+        assert_eq!(completed.lock().unwrap()[1], (TransferStatus::Cancelled, vec![]));
+        assert_eq!(control_state(&device), ControlEndpointState::SetupStage);
+    }
 }
@@ -14,5 +14,13 @@ impl DeviceDescriptor {
             descriptor: descriptor,
         }
     }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.descriptor.idVendor
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.descriptor.idProduct
+    }
 }
 
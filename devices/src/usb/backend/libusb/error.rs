@@ -4,6 +4,8 @@
 
 use std;
 use std::fmt;
+use std::thread;
+use std::time::Duration;
 
 use usb::libusb::bindings::*;
 
@@ -66,10 +68,39 @@ impl Error {
             _ => Error::SUCCESS(e),
         }
     }
+
+    // True for errors that may go away by themselves, so the same call is worth making again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            &Error::BUSY | &Error::INTERRUPTED => true,
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Calls `f` until it succeeds, fails with an error that isn't transient, or has been called `tries`
+// times. Waits `delay` before the first retry and twice as long before each one after that.
+// Errors like NO_DEVICE are returned right away, for the caller to handle the disconnect.
+pub fn retry_transient<T, F>(tries: u32, delay: Duration, mut f: F) -> Result<T>
+    where F: FnMut() -> Result<T>
+{
+    let mut delay = delay;
+    let mut tries_left = tries;
+    loop {
+        match f() {
+            Err(ref e) if e.is_transient() && tries_left > 1 => {
+                warn!("usb: retrying after transient libusb error: {}", e);
+                tries_left -= 1;
+                thread::sleep(delay);
+                delay = delay * 2;
+            }
+            result => return result,
+        }
+    }
+}
+
 macro_rules! call_libusb_fn {
     ($x:expr) => {
         match unsafe { Error::new($x as i32) } {
@@ -78,3 +109,38 @@ macro_rules! call_libusb_fn {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls retry_transient with a function that fails with each of `errors` in turn, then
+    // returns how often it was called.
+    fn retry_errors(tries: u32, errors: Vec<Error>) -> Result<u32> {
+        let mut errors = errors.into_iter();
+        let mut calls = 0;
+        retry_transient(tries, Duration::from_millis(1), || {
+            calls += 1;
+            match errors.next() {
+                Some(e) => Err(e),
+                None => Ok(calls),
+            }
+        })
+    }
+
+    #[test]
+    fn retry_busy() {
+        match retry_errors(3, vec![Error::BUSY, Error::BUSY]) {
+            Ok(3) => {}
+            _ => panic!("busy errors weren't retried"),
+        }
+        match retry_errors(2, vec![Error::BUSY, Error::BUSY]) {
+            Err(Error::BUSY) => {}
+            _ => panic!("retried more than twice"),
+        }
+        match retry_errors(3, vec![Error::NO_DEVICE]) {
+            Err(Error::NO_DEVICE) => {}
+            _ => panic!("a missing device was retried"),
+        }
+    }
+}
@@ -4,11 +4,16 @@
 
 use std::boxed::Box;
 use std::sync::Arc;
+use std::time::Duration;
 
 use usb::libusb::bindings::*;
 use usb::libusb::error::*;
 use usb::libusb::device_handle::*;
 
+// A busy device is given a few more chances to take a transfer before it fails.
+const SUBMIT_TRIES: u32 = 4;
+const SUBMIT_RETRY_DELAY_MS: u64 = 1;
+
 pub trait TransferBuffer {
     fn raw_buffer(&self) -> *mut u8;
     fn buffer_len(&self) -> i32;
@@ -71,7 +76,10 @@ impl LibUsbTransfer {
                                          userdata, timeout);
         }
 
-        call_libusb_fn!(libusb_submit_transfer(self.transfer));
+        retry_transient(SUBMIT_TRIES, Duration::from_millis(SUBMIT_RETRY_DELAY_MS), || {
+            call_libusb_fn!(libusb_submit_transfer(transfer.0.transfer));
+            Ok(())
+        })?;
         Ok(new_transfer)
     }
 
@@ -94,7 +102,10 @@ impl LibUsbTransfer {
                                          userdata, timeout);
         }
 
-        call_libusb_fn!(libusb_submit_transfer(self.transfer));
+        retry_transient(SUBMIT_TRIES, Duration::from_millis(SUBMIT_RETRY_DELAY_MS), || {
+            call_libusb_fn!(libusb_submit_transfer(transfer.0.transfer));
+            Ok(())
+        })?;
         Ok(new_transfer)
     }
 
@@ -4,7 +4,7 @@
 
 use usb::libusb::bindings::*;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Speed {
     // The OS doesn't report or know the device speed.
     Unknown,
@@ -19,6 +19,9 @@ pub trait UsbBackendInterface {
     // Assigns address to the backend.
     fn set_address(&self, address: UsbDeviceAddress);
 
+    // Resets the device, which also forgets its address and configuration.
+    fn reset(&self);
+
     // Returns the address of the backend.
     fn address(&self) -> UsbDeviceAddress;
 
@@ -0,0 +1,440 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+use super::xhci::{BackendTransfer, BackendTransferType, BackendType, TransferStatus,
+                  UsbRequestSetup, XhciBackendDevice, ENDPOINT_DIRECTION_IN};
+
+// Google vendor id and a product id reserved for the emulated keyboard.
+const KEYBOARD_VENDOR_ID: u16 = 0x18d1;
+const KEYBOARD_PRODUCT_ID: u16 = 0x5f01;
+
+// The only non-control endpoint: interrupt IN, endpoint 1.
+const KEYBOARD_ENDPOINT: u8 = ENDPOINT_DIRECTION_IN | 1;
+
+// Size of a boot protocol keyboard input report: modifiers, reserved, and six key usages.
+const REPORT_SIZE: usize = 8;
+const MAX_PRESSED_KEYS: usize = 6;
+
+// Usage ids of the modifier keys (left control through right gui) in the keyboard usage page.
+const MODIFIER_USAGE_FIRST: u8 = 0xe0;
+const MODIFIER_USAGE_LAST: u8 = 0xe7;
+
+// Size of a key event message on the key socket: usage id followed by 1 for press, 0 for release.
+const KEY_EVENT_SIZE: usize = 2;
+
+// Standard requests. See usb spec table 9-4.
+const REQUEST_GET_STATUS: u8 = 0x00;
+const REQUEST_SET_ADDRESS: u8 = 0x05;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_GET_INTERFACE: u8 = 0x0a;
+const REQUEST_SET_INTERFACE: u8 = 0x0b;
+
+// Hid class requests. See hid spec 7.2.
+const HID_REQUEST_GET_REPORT: u8 = 0x01;
+const HID_REQUEST_SET_REPORT: u8 = 0x09;
+const HID_REQUEST_SET_IDLE: u8 = 0x0a;
+const HID_REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+// Request type bits selecting a class request.
+const REQUEST_TYPE_CLASS: u8 = 0x20;
+const REQUEST_TYPE_TYPE_MASK: u8 = 0x60;
+
+// Descriptor types, carried in the high byte of wValue of GET_DESCRIPTOR.
+const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_TYPE_HID_REPORT: u8 = 0x22;
+
+const DEVICE_DESCRIPTOR: [u8; 18] = [
+    0x12, // bLength
+    0x01, // bDescriptorType: device
+    0x00, 0x02, // bcdUSB: 2.0
+    0x00, // bDeviceClass: defined by interface
+    0x00, // bDeviceSubClass
+    0x00, // bDeviceProtocol
+    0x40, // bMaxPacketSize0
+    (KEYBOARD_VENDOR_ID & 0xff) as u8, (KEYBOARD_VENDOR_ID >> 8) as u8, // idVendor
+    (KEYBOARD_PRODUCT_ID & 0xff) as u8, (KEYBOARD_PRODUCT_ID >> 8) as u8, // idProduct
+    0x00, 0x01, // bcdDevice
+    0x00, // iManufacturer
+    0x00, // iProduct
+    0x00, // iSerialNumber
+    0x01, // bNumConfigurations
+];
+
+// Boot protocol keyboard report descriptor. See hid spec appendix B.1.
+pub const KEYBOARD_REPORT_DESCRIPTOR: [u8; 63] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): led report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): led report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array): key array
+    0xc0,       // End Collection
+];
+
+const CONFIGURATION_DESCRIPTOR: [u8; 34] = [
+    // Configuration descriptor.
+    0x09, // bLength
+    0x02, // bDescriptorType: configuration
+    0x22, 0x00, // wTotalLength
+    0x01, // bNumInterfaces
+    0x01, // bConfigurationValue
+    0x00, // iConfiguration
+    0xa0, // bmAttributes: bus powered, remote wakeup
+    0x32, // bMaxPower: 100mA
+    // Interface descriptor.
+    0x09, // bLength
+    0x04, // bDescriptorType: interface
+    0x00, // bInterfaceNumber
+    0x00, // bAlternateSetting
+    0x01, // bNumEndpoints
+    0x03, // bInterfaceClass: hid
+    0x01, // bInterfaceSubClass: boot interface
+    0x01, // bInterfaceProtocol: keyboard
+    0x00, // iInterface
+    // Hid descriptor.
+    0x09, // bLength
+    0x21, // bDescriptorType: hid
+    0x11, 0x01, // bcdHID: 1.11
+    0x00, // bCountryCode
+    0x01, // bNumDescriptors
+    DESCRIPTOR_TYPE_HID_REPORT, // bDescriptorType
+    KEYBOARD_REPORT_DESCRIPTOR.len() as u8, 0x00, // wDescriptorLength
+    // Endpoint descriptor.
+    0x07, // bLength
+    0x05, // bDescriptorType: endpoint
+    KEYBOARD_ENDPOINT, // bEndpointAddress
+    0x03, // bmAttributes: interrupt
+    REPORT_SIZE as u8, 0x00, // wMaxPacketSize
+    0x0a, // bInterval: 10ms
+];
+
+// A usb hid keyboard emulated entirely in crosvm. Key events are read from a socket as a usage id
+// byte followed by a pressed byte, and delivered to the guest as boot protocol input reports on the
+// interrupt IN endpoint.
+pub struct HidKeyboard {
+    address: u32,
+    configuration: u8,
+    key_socket: Option<UnixDatagram>,
+    modifiers: u8,
+    pressed_keys: Vec<u8>,
+    pending_reports: VecDeque<[u8; REPORT_SIZE]>,
+    pending_in_transfer: Option<BackendTransfer>,
+}
+
+impl HidKeyboard {
+    // Creates a keyboard that reads key events from `key_socket`, if given.
+    pub fn new(key_socket: Option<UnixDatagram>) -> io::Result<HidKeyboard> {
+        if let Some(ref socket) = key_socket {
+            socket.set_nonblocking(true)?;
+        }
+        Ok(HidKeyboard {
+            address: 0,
+            configuration: 0,
+            key_socket: key_socket,
+            modifiers: 0,
+            pressed_keys: Vec::new(),
+            pending_reports: VecDeque::new(),
+            pending_in_transfer: None,
+        })
+    }
+
+    // File descriptors that must be kept open when the keyboard is jailed.
+    pub fn keep_fds(&self) -> Vec<RawFd> {
+        self.key_socket.iter().map(|s| s.as_raw_fd()).collect()
+    }
+
+    // Reads all key events currently available on the key socket and queues the resulting reports.
+    pub fn process_key_events(&mut self) {
+        loop {
+            let mut buf = [0u8; KEY_EVENT_SIZE];
+            let res = match self.key_socket {
+                Some(ref socket) => socket.recv(&mut buf),
+                None => return,
+            };
+            match res {
+                Ok(KEY_EVENT_SIZE) => self.queue_key_event(buf[0], buf[1] != 0),
+                Ok(len) => warn!("hid keyboard: key event with bad size: {}", len),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("hid keyboard: failed to read key socket: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Presses or releases the key with the given usage id and queues an input report if the state
+    // of the keyboard changed.
+    pub fn queue_key_event(&mut self, usage: u8, pressed: bool) {
+        let changed = if usage >= MODIFIER_USAGE_FIRST && usage <= MODIFIER_USAGE_LAST {
+            let bit = 1u8 << (usage - MODIFIER_USAGE_FIRST);
+            let old = self.modifiers;
+            if pressed {
+                self.modifiers |= bit;
+            } else {
+                self.modifiers &= !bit;
+            }
+            old != self.modifiers
+        } else if pressed {
+            if self.pressed_keys.contains(&usage) || self.pressed_keys.len() >= MAX_PRESSED_KEYS {
+                false
+            } else {
+                self.pressed_keys.push(usage);
+                true
+            }
+        } else {
+            let len = self.pressed_keys.len();
+            self.pressed_keys.retain(|&k| k != usage);
+            len != self.pressed_keys.len()
+        };
+
+        if changed {
+            let report = self.current_report();
+            self.pending_reports.push_back(report);
+            self.complete_pending_in_transfer();
+        }
+    }
+
+    fn current_report(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = self.modifiers;
+        for (i, key) in self.pressed_keys.iter().enumerate() {
+            report[2 + i] = *key;
+        }
+        report
+    }
+
+    fn complete_pending_in_transfer(&mut self) {
+        if self.pending_reports.is_empty() {
+            return;
+        }
+        if let Some(mut transfer) = self.pending_in_transfer.take() {
+            // The unwrap can't fail since pending_reports was checked above.
+            let report = self.pending_reports.pop_front().unwrap();
+            let len = min(transfer.buffer.len(), report.len());
+            transfer.buffer[..len].copy_from_slice(&report[..len]);
+            transfer.complete(TransferStatus::Completed, len);
+        }
+    }
+
+    fn handle_interrupt_transfer(&mut self, transfer: BackendTransfer) {
+        if transfer.endpoint != KEYBOARD_ENDPOINT {
+            transfer.complete(TransferStatus::Stalled, 0);
+            return;
+        }
+        if let Some(old) = self.pending_in_transfer.take() {
+            old.complete(TransferStatus::Cancelled, 0);
+        }
+        self.pending_in_transfer = Some(transfer);
+        self.complete_pending_in_transfer();
+    }
+
+    fn get_descriptor(&self, setup: &UsbRequestSetup) -> Option<&'static [u8]> {
+        match (setup.value >> 8) as u8 {
+            DESCRIPTOR_TYPE_DEVICE => Some(&DEVICE_DESCRIPTOR),
+            DESCRIPTOR_TYPE_CONFIGURATION => Some(&CONFIGURATION_DESCRIPTOR),
+            DESCRIPTOR_TYPE_HID_REPORT => Some(&KEYBOARD_REPORT_DESCRIPTOR),
+            _ => None,
+        }
+    }
+
+    // Handles a control request, returning the number of bytes of the data stage written to `data`
+    // or None if the request should be stalled.
+    fn handle_control_request(&mut self, setup: &UsbRequestSetup, data: &mut [u8])
+                              -> Option<usize> {
+        let copy = |src: &[u8], dst: &mut [u8]| {
+            let len = min(src.len(), dst.len());
+            dst[..len].copy_from_slice(&src[..len]);
+            len
+        };
+
+        if setup.request_type & REQUEST_TYPE_TYPE_MASK == REQUEST_TYPE_CLASS {
+            return match setup.request {
+                HID_REQUEST_GET_REPORT => Some(copy(&self.current_report(), data)),
+                // Leds are not emulated, the idle rate is ignored and only the boot protocol is
+                // supported, so these can be acknowledged without doing anything.
+                HID_REQUEST_SET_REPORT | HID_REQUEST_SET_IDLE | HID_REQUEST_SET_PROTOCOL => Some(0),
+                _ => None,
+            };
+        }
+
+        match setup.request {
+            REQUEST_GET_STATUS => Some(copy(&[0, 0], data)),
+            REQUEST_SET_ADDRESS => {
+                self.address = setup.value as u32;
+                Some(0)
+            }
+            REQUEST_GET_DESCRIPTOR => self.get_descriptor(setup).map(|d| copy(d, data)),
+            REQUEST_GET_CONFIGURATION => Some(copy(&[self.configuration], data)),
+            REQUEST_SET_CONFIGURATION => {
+                self.configuration = setup.value as u8;
+                Some(0)
+            }
+            REQUEST_GET_INTERFACE => Some(copy(&[0], data)),
+            REQUEST_SET_INTERFACE => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl XhciBackendDevice for HidKeyboard {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        KEYBOARD_VENDOR_ID
+    }
+
+    fn get_pid(&self) -> u16 {
+        KEYBOARD_PRODUCT_ID
+    }
+
+    fn submit_transfer(&mut self, mut transfer: BackendTransfer) {
+        match transfer.transfer_type {
+            BackendTransferType::Control(setup) => {
+                match self.handle_control_request(&setup, &mut transfer.buffer) {
+                    Some(len) => transfer.complete(TransferStatus::Completed, len),
+                    None => transfer.complete(TransferStatus::Stalled, 0),
+                }
+            }
+            BackendTransferType::Interrupt => self.handle_interrupt_transfer(transfer),
+            BackendTransferType::Bulk => transfer.complete(TransferStatus::Stalled, 0),
+        }
+    }
+
+    fn set_address(&mut self, address: u32) {
+        self.address = address;
+    }
+
+    fn reset(&mut self) {
+        self.address = 0;
+        self.configuration = 0;
+        self.modifiers = 0;
+        self.pressed_keys.clear();
+        self.pending_reports.clear();
+        if let Some(transfer) = self.pending_in_transfer.take() {
+            transfer.complete(TransferStatus::Cancelled, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Completed = Arc<Mutex<Vec<(TransferStatus, Vec<u8>)>>>;
+
+    fn make_transfer(endpoint: u8, transfer_type: BackendTransferType, len: usize,
+                     completed: &Completed) -> BackendTransfer {
+        let mut transfer = BackendTransfer::new(endpoint, transfer_type, vec![0; len]);
+        let completed = completed.clone();
+        transfer.set_callback(Box::new(move |t: BackendTransfer| {
+            let data = t.buffer[..t.actual_length].to_vec();
+            completed.lock().unwrap().push((t.status, data));
+        }));
+        transfer
+    }
+
+    #[test]
+    fn report_descriptor() {
+        let mut keyboard = HidKeyboard::new(None).unwrap();
+        let completed = Completed::default();
+        let setup = UsbRequestSetup {
+            request_type: 0x81,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (DESCRIPTOR_TYPE_HID_REPORT as u16) << 8,
+            index: 0,
+            length: 128,
+        };
+        keyboard.submit_transfer(make_transfer(0, BackendTransferType::Control(setup), 128,
+                                               &completed));
+        let completed = completed.lock().unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].0, TransferStatus::Completed);
+        assert_eq!(&completed[0].1[..], &KEYBOARD_REPORT_DESCRIPTOR[..]);
+    }
+
+    #[test]
+    fn truncated_device_descriptor() {
+        let mut keyboard = HidKeyboard::new(None).unwrap();
+        let completed = Completed::default();
+        let setup = UsbRequestSetup {
+            request_type: 0x80,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (DESCRIPTOR_TYPE_DEVICE as u16) << 8,
+            index: 0,
+            length: 8,
+        };
+        keyboard.submit_transfer(make_transfer(0, BackendTransferType::Control(setup), 8,
+                                               &completed));
+        let completed = completed.lock().unwrap();
+        assert_eq!(&completed[0].1[..], &DEVICE_DESCRIPTOR[..8]);
+    }
+
+    #[test]
+    fn key_event_completes_in_transfer() {
+        let (host_socket, device_socket) = UnixDatagram::pair().unwrap();
+        let mut keyboard = HidKeyboard::new(Some(device_socket)).unwrap();
+        let completed = Completed::default();
+
+        keyboard.submit_transfer(make_transfer(KEYBOARD_ENDPOINT, BackendTransferType::Interrupt,
+                                               REPORT_SIZE, &completed));
+        assert!(completed.lock().unwrap().is_empty());
+
+        // Press left shift and 'a'.
+        host_socket.send(&[0xe1, 1]).unwrap();
+        host_socket.send(&[0x04, 1]).unwrap();
+        keyboard.process_key_events();
+
+        {
+            let completed = completed.lock().unwrap();
+            assert_eq!(completed.len(), 1);
+            assert_eq!(completed[0].0, TransferStatus::Completed);
+            assert_eq!(&completed[0].1[..], &[0x02, 0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        // The second report was queued and is returned by the next IN transfer.
+        keyboard.submit_transfer(make_transfer(KEYBOARD_ENDPOINT, BackendTransferType::Interrupt,
+                                               REPORT_SIZE, &completed));
+        let completed = completed.lock().unwrap();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(&completed[1].1[..], &[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+    }
+}
@@ -2,6 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod backend;
+mod hid_keyboard;
 mod xhci;
 
+pub use self::backend::*;
+pub use self::hid_keyboard::*;
 pub use self::xhci::*;
@@ -78,10 +78,10 @@ impl DeviceSlot {
             return TrbCompletionCode::SlotNotEnabledError;
         }
 
-        if ( self.state() != DeviceSlotState::DisabledOrEnabled ) &&
-            ( self.state() != DeviceSlotState::Default ||  trb.get_block_set_address_request()) {
-                return TrbCompletionCode::ContextStateError;
-            }
+        let new_state = match self.state().address_device(trb.get_block_set_address_request()) {
+            Ok(state) => state,
+            Err(code) => return code,
+        };
 
         // Copy all fields of the slot context and endpoint 0 context from the input context
         // to the output context.
@@ -92,18 +92,17 @@ impl DeviceSlot {
         // TODO refactor this
         self.backend = get_backend_from_some_where();
 
-        // Assign slot ID as device address if block_set_address_request is not set.
-        if !trb.get_block_set_address_request() {
+        // Assign slot ID as device address, unless this is a Block Set Address Request that only
+        // moves the slot to Default. The address is then assigned by the next Address Device.
+        if new_state == DeviceSlotState::Addressed {
             if there_is_backend {
                 backend.set_address(self.slot_id);
             } else {
                 return TrbCompletionCode::TransactionError;
             }
             device_context.slot_context.set_usb_device_address(self.slot_id);
-            device_context.slot_context.set_state(DeviceSlotState::Addressed);
-        } else {
-            device_context.slot_context.set_state(DeviceSlotState::Default);
         }
+        device_context.slot_context.set_state(new_state);
 
         self.transfer_ring_controllers[0].unwrap().set_dequeue_pointer(
             GuestAddress(
@@ -115,6 +114,7 @@ impl DeviceSlot {
 
         device_context.endpoint_context[0].unwrap().set_state(EndpointState::Running);
         self.set_device_context(device_context);
+        TrbCompletionCode::Success
     }
 
     // Adds or dropbs multiple endpoints in the device slot.
@@ -134,6 +134,24 @@ impl DeviceSlot {
                 _>> mem.read_obj_from_addr(trb.get_input_context_pointer()).unwrap();
             }
 
+       // Check every endpoint that is added before changing any of them. The endpoint contexts
+       // follow the input control context and the slot context.
+       let speed = match self.get_device_context().slot_context.speed() {
+           Ok(speed) => speed,
+           Err(_) => return TrbCompletionCode::ParameterError,
+       };
+       for device_context_index in 1..32 {
+           if input_control_context.add_context_flag(device_context_index) {
+               let endpoint_context: EndpointContext =
+                   self.mem.read_obj_from_addr(trb.get_input_context_pointer() +
+                                               (device_context_index + 1) *
+                                               DEVICE_CONTEXT_ENTRY_SIZE).unwrap();
+               if let Err(code) = endpoint_context.check_parameters(speed) {
+                   return code;
+               }
+           }
+       }
+
        for device_context_index in 1..32 {
            if input_control_context.drop_context_flag(device_context_index) {
                self.drop_one_endpoint(device_context_index);
@@ -159,11 +177,8 @@ impl DeviceSlot {
             return TrbCompletionCode::SlotNotEnabledError;
         }
 
-        let device_context = self.get_device_context();
-        match device_context.slot_context.get_state() {
-            DeviceSlotState::Default | DeviceSlotState::Addressed | DeviceSlotState::Configured
-                => return TrbCompletionCode::ContextStateError,
-            _ => (),
+        if !self.state().can_evaluate_context() {
+            return TrbCompletionCode::ContextStateError;
         }
 
         // TODO(jkwang) verify this
@@ -197,24 +212,32 @@ impl DeviceSlot {
                 );
         }
         self.set_device_context(device_context);
+        TrbCompletionCode::Success
     }
 
-    // Reset the device slot to default state and deconfigures all but the
-    // control endpoint.
-    pub fn reset_device(&self) {
-        let state = self.state();
-        if state != DeviceSlotState::Addressed &&
-            state != DeviceSlotState::Configured {
-                return;
-            }
+    // Handles a Reset Device command. The device goes back to the Default state with only its
+    // control endpoint, while the slot stays enabled. See xhci spec section 4.6.11.
+    pub fn reset_device(&mut self) -> TrbCompletionCode {
+        let new_state = match self.state().reset_device() {
+            Ok(state) => state,
+            Err(code) => return code,
+        };
         for i in 2..32 {
             self.drop_one_endpoint(i);
         }
-        let ctx = self.get_device_context();
-        // TODO(jkwang) caution here
-        ctx.slot_context.set_state(DeviceSlotState::Default);
-        ctx.slot_context.context_entries(1);
-        ctx.slot_context.set_root_hub_port_number(0);
+        let mut ctx = self.get_device_context();
+        ctx.slot_context.set_state(new_state);
+        ctx.slot_context.set_context_entries(1);
+        ctx.slot_context.set_usb_device_address(0);
+        // The control endpoint starts over from its endpoint context.
+        if let Some(ref mut trc) = self.transfer_ring_controllers[0] {
+            trc.set_dequeue_pointer(
+                GuestAddress(ctx.endpoint_context[0].get_tr_dequeue_pointer() << 4));
+            trc.set_consumer_cycle_state(ctx.endpoint_context[0].get_dequeue_cycle_state());
+        }
+        self.set_device_context(ctx);
+        self.backend.reset();
+        TrbCompletionCode::Success
     }
 
     // Returns th ecuurent state of the device slot.
@@ -2,11 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use sys_util::{GuestAddress, GuestMemory, GuestMemoryError};
+
+use super::xhci_abi::*;
+
 #[derive(Debug)]
-enum Error {
+pub enum Error {
     Uninitialized,  // The event ring is uninitialized.
-    InvalidMemoryAccess, // Event ring want to do invalid memory access.
-    InconstantState, // Event ring is in a bad state.
+    InvalidMemoryAccess(GuestMemoryError), // Event ring want to do invalid memory access.
+    BadSegTableAddress, // The segment table or a segment in it is outside guest memory.
+    EventRingFull, // The guest hasn't consumed enough events to make room for another.
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -15,24 +20,30 @@ type Result<T> = std::result::Result<T, Error>;
 // xHCI device back to the guest.  Each event ring is associated with a single
 // interrupter.  See section 4.9.4 of the xHCI specification for more details.
 pub struct EventRing {
-    guest_memory: &GuestMemory,
-    segment_table_size: u64,
+    mem: GuestMemory,
+    segment_table_size: u16,
     segment_table_base_address: GuestAddress,
-    current_segment_index: u64,
+    current_segment_index: u16,
+    // Set when the guest changed the segment table while it still had events to consume. The new
+    // table is loaded once it has caught up.
+    reload_pending: bool,
 
     enqueue_pointer: GuestAddress,
     dequeue_pointer: GuestAddress,
+    // Number of TRBs left in the current segment.
     trb_count: u16,
     producer_cycle_state: bool,
 }
 
 // Public interfaces.
 impl EventRing {
-    pub fn new(mem: &GuestMemory) -> Self {
+    pub fn new(mem: GuestMemory) -> Self {
         EventRing {
+            mem: mem,
             segment_table_size: 0,
             segment_table_base_address: GuestAddress(0),
             current_segment_index: 0,
+            reload_pending: false,
             enqueue_pointer: GuestAddress(0),
             dequeue_pointer: GuestAddress(0),
             trb_count: 0,
@@ -42,14 +53,19 @@ impl EventRing {
     }
 
     // This function implements left side of xHCI spec, Figure 4-12.
-    pub fn add_event(&mut self, trb: Trb) -> Result<()> {
+    pub fn add_event(&mut self, mut trb: Trb) -> Result<()> {
         self.check_inited()?;
-        trb.set_cycle_bit(producer_cycle_state);
-        self.guest_memory.write_obj_at_addr(self,enqueue_pointer, trb).expect("Fail to write Guest Memory");
-        self.enqueue_pointer = match self.enqueue_pointer.checked_add(TRB_SIZE) {
-            Some(addr) => addr,
-            None => return Err(InvalidAddress);
-        };
+        // TODO(jkwang) Write an Event Ring Full Error event when there is room for one last TRB.
+        if self.is_full()? {
+            return Err(Error::EventRingFull);
+        }
+        trb.set_cycle_bit(self.producer_cycle_state);
+        self.mem
+            .write_obj_at_addr(trb, self.enqueue_pointer)
+            .map_err(Error::InvalidMemoryAccess)?;
+        self.enqueue_pointer = self.enqueue_pointer
+            .checked_add(TRB_SIZE as u64)
+            .ok_or(Error::BadSegTableAddress)?;
         self.trb_count -= 1;
         if self.trb_count == 0 {
             self.current_segment_index += 1;
@@ -57,85 +73,111 @@ impl EventRing {
                 self.producer_cycle_state = !self.producer_cycle_state;
                 self.current_segment_index = 0;
             }
+            self.load_current_seg_table_entry()?;
         }
-        self.load_current_seg_table_entry();
+        Ok(())
     }
 
+    // Called when the guest writes ERSTSZ.
     pub fn set_seg_table_size(&mut self, size: u16) {
-        self.segment_table_size = u16;
+        self.segment_table_size = size;
+        self.reload_seg_table();
     }
 
+    // Called when the guest writes ERSTBA.
     pub fn set_seg_table_base_addr(&mut self, addr: GuestAddress) {
         self.segment_table_base_address = addr;
-        self.try_init();
+        self.reload_seg_table();
     }
 
+    // Called when the guest writes ERDP.
     pub fn set_dequeue_pointer(&mut self, addr: GuestAddress) {
         self.dequeue_pointer = addr;
+        if self.reload_pending && self.is_empty() {
+            self.reload_seg_table();
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        return self.enqueue_pointer = self.dequeue_pointer;
+        self.enqueue_pointer == self.dequeue_pointer
     }
 
     // Event ring is considered full when there is only space for one last TRB.
     // In this case, xHC should write an error Trb and do a bunch of handlings.
     // See spec, figure 4-12 for more details.
-    // For now, we just check event ring full and panic (as it's unlikely).
-    // TODO(jkwang) Handle event ring full.
-    pub fn isFull(&self) -> bool {
-        if (self.trb_count == 1) {
+    pub fn is_full(&self) -> Result<bool> {
+        if self.trb_count == 1 {
             let next_erst_idx = (self.current_segment_index + 1) % self.segment_table_size;
-            let erst_entry = self.read_seg_table_entry(next_erst_idx).unwrap();
-            return self.dequeue_pointer.0 == erst.get_ring_segment_base_address;
+            let erst_entry = self.read_seg_table_entry(next_erst_idx)?;
+            Ok(self.dequeue_pointer.0 == erst_entry.get_ring_segment_base_address())
         } else {
-            return self.dequeue_pointer.0 == self.enqueue_pointer.0 + TRB_SIZE
+            Ok(self.dequeue_pointer.0 == self.enqueue_pointer.0 + TRB_SIZE as u64)
         }
     }
 }
 
 // Private implementations.
 impl EventRing {
-    fn try_init(&mut self) {
-        if (self.segment_table_size == 0 || segment_table_address.0 == 0) {
+    // Starts over at the first segment of the current segment table, as if the ring was new. The
+    // guest may reprogram the table at any time, but the events it hasn't consumed yet would be
+    // lost, so that waits until the ring is empty.
+    fn reload_seg_table(&mut self) {
+        if self.check_inited().is_ok() && !self.is_empty() {
+            if !self.reload_pending {
+                warn!("xhci: event ring segment table changed before the ring was empty");
+            }
+            self.reload_pending = true;
+            return;
+        }
+        self.reload_pending = false;
+        self.current_segment_index = 0;
+        self.producer_cycle_state = true;
+        self.enqueue_pointer = GuestAddress(0);
+        self.trb_count = 0;
+        if self.segment_table_size == 0 || self.segment_table_base_address == GuestAddress(0) {
             return;
         }
-        self.load_current_seg_table_entry().expect("Unable to init event ring");
+        match self.load_current_seg_table_entry() {
+            // The new ring starts out empty. The guest is expected to point ERDP at the start of
+            // the first segment too.
+            Ok(()) => self.dequeue_pointer = self.enqueue_pointer,
+            Err(e) => warn!("xhci: failed to load event ring segment table: {:?}", e),
+        }
     }
 
     fn check_inited(&self) -> Result<()> {
-        if (self.segment_table_size == 0 ||
-            self.segment_table_address == GuestAddress(0) ||
+        if self.segment_table_size == 0 ||
+            self.segment_table_base_address == GuestAddress(0) ||
             self.enqueue_pointer == GuestAddress(0) ||
-            self.dequeue_pointer == GuestAddress(0) ||
-            ) {
+            self.dequeue_pointer == GuestAddress(0) {
             return Err(Error::Uninitialized);
         }
-        Ok(());
+        Ok(())
     }
 
     fn load_current_seg_table_entry(&mut self) -> Result<()> {
-        let entry = read_seg_table_entry(self.current_segment_index)?;
-        self.enqueue_pointer = entry.get_ring_segment_base_address();
-        self.trb_count = entry.get_ring_segment_size();
+        let entry = self.read_seg_table_entry(self.current_segment_index)?;
+        let size = entry.get_ring_segment_size();
+        if size == 0 {
+            self.enqueue_pointer = GuestAddress(0);
+            return Err(Error::BadSegTableAddress);
+        }
+        self.enqueue_pointer = GuestAddress(entry.get_ring_segment_base_address());
+        self.trb_count = size;
+        Ok(())
     }
 
-    fn read_seg_table_entry(&mut self, index: u64) -> Result<EventRingSegmentTableEntry> {
+    fn read_seg_table_entry(&self, index: u16) -> Result<EventRingSegmentTableEntry> {
         let seg_table_addr = self.get_seg_table_addr(index)?;
-        let entry: EventRingSegmentTableEntry =
-            self.guest_memory.read_obj_from_addr(seg_table_addr).map_err(Error::InvalidMemoryAccess)?;
-        Ok(())
+        self.mem
+            .read_obj_from_addr(seg_table_addr)
+            .map_err(Error::InvalidMemoryAccess)
     }
 
-    fn get_seg_table_addr(&self, index: u64) -> Result<GuestAddress> {
-       let seg_table_addr =
-            self.segment_table_base_address.checked_add(
-            (SEGMENT_TABLE_SIZE as u64) * index
-            );
-        match seg_table_addr {
-            Some(addr) => addr,
-            None => return Err(Error::InvalidMemoryAccess),
-        }
+    fn get_seg_table_addr(&self, index: u16) -> Result<GuestAddress> {
+        self.segment_table_base_address
+            .checked_add((SEGMENT_TABLE_SIZE as u64) * index as u64)
+            .ok_or(Error::BadSegTableAddress)
     }
 }
 
@@ -144,7 +186,91 @@ impl EventRing {
 mod test {
     use super::*;
 
+    fn write_seg_table(mem: &GuestMemory, table: u64, segments: &[(u64, u16)]) {
+        for (i, &(base, size)) in segments.iter().enumerate() {
+            let mut entry = EventRingSegmentTableEntry::new();
+            entry.set_ring_segment_base_address(base);
+            entry.set_ring_segment_size(size);
+            mem.write_obj_at_addr(entry, GuestAddress(table + (i * SEGMENT_TABLE_SIZE) as u64))
+                .unwrap();
+        }
+    }
+
+    fn event(mem: &GuestMemory, addr: u64) -> Trb {
+        mem.read_obj_from_addr(GuestAddress(addr)).unwrap()
+    }
+
+    fn noop_event() -> Trb {
+        let mut trb = Trb::new();
+        trb.set_trb_type(TrbType::PortStatusChangeEvent.to());
+        trb
+    }
+
+    fn setup_ring(ring: &mut EventRing, table: u64, table_size: u16, first_segment: u64) {
+        ring.set_seg_table_size(table_size);
+        ring.set_seg_table_base_addr(GuestAddress(table));
+        ring.set_dequeue_pointer(GuestAddress(first_segment));
+    }
+
     #[test]
-    fn test() {
+    fn wrap_around() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        write_seg_table(&mem, 0x100, &[(0x1000, 2), (0x2000, 2)]);
+        let mut ring = EventRing::new(mem.clone());
+        setup_ring(&mut ring, 0x100, 2, 0x1000);
+
+        for &addr in &[0x1000, 0x1010, 0x2000, 0x2010] {
+            ring.add_event(noop_event()).unwrap();
+            assert_eq!(event(&mem, addr).get_cycle_bit(), true);
+            ring.set_dequeue_pointer(GuestAddress(addr + TRB_SIZE as u64));
+        }
+        // Back at the first segment with the cycle bit flipped.
+        ring.set_dequeue_pointer(GuestAddress(0x1000));
+        ring.add_event(noop_event()).unwrap();
+        assert_eq!(event(&mem, 0x1000).get_cycle_bit(), false);
+    }
+
+    #[test]
+    fn full() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        write_seg_table(&mem, 0x100, &[(0x1000, 2)]);
+        let mut ring = EventRing::new(mem.clone());
+        setup_ring(&mut ring, 0x100, 1, 0x1000);
+
+        ring.add_event(noop_event()).unwrap();
+        match ring.add_event(noop_event()) {
+            Err(Error::EventRingFull) => {}
+            _ => panic!("event added to a full ring"),
+        }
+    }
+
+    #[test]
+    fn reprogram_seg_table() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        write_seg_table(&mem, 0x100, &[(0x1000, 16)]);
+        write_seg_table(&mem, 0x200, &[(0x2000, 4), (0x3000, 4)]);
+        let mut ring = EventRing::new(mem.clone());
+        setup_ring(&mut ring, 0x100, 1, 0x1000);
+        ring.add_event(noop_event()).unwrap();
+        ring.add_event(noop_event()).unwrap();
+        ring.set_dequeue_pointer(GuestAddress(0x1020));
+
+        // The ring is empty, so the new table takes effect right away and starts over.
+        setup_ring(&mut ring, 0x200, 2, 0x2000);
+        ring.add_event(noop_event()).unwrap();
+        assert_eq!(event(&mem, 0x2000).get_trb_type(), TrbType::PortStatusChangeEvent.to());
+        assert_eq!(event(&mem, 0x2000).get_cycle_bit(), true);
+        assert_eq!(event(&mem, 0x1020).get_trb_type(), 0);
+
+        // The guest hasn't consumed the event at 0x2000 yet, so switching back to the first
+        // table waits until it does.
+        ring.set_seg_table_size(1);
+        ring.set_seg_table_base_addr(GuestAddress(0x100));
+        ring.add_event(noop_event()).unwrap();
+        assert_eq!(event(&mem, 0x2010).get_trb_type(), TrbType::PortStatusChangeEvent.to());
+        ring.set_dequeue_pointer(GuestAddress(0x2020));
+        ring.add_event(noop_event()).unwrap();
+        assert_eq!(event(&mem, 0x1000).get_cycle_bit(), true);
+        assert_eq!(event(&mem, 0x2020).get_trb_type(), 0);
     }
 }
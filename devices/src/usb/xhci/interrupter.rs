@@ -2,6 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use sys_util::{GuestAddress, GuestMemory};
+
+use super::event_ring::{Error, EventRing};
+use super::xhci_abi::*;
+use super::xhci_backend_device::{BackendTransfer, TransferStatus};
+
+type Result<T> = std::result::Result<T, Error>;
+
 // An interrupter manages events on an event ring and signals an interrupt in
 // the guest when necessary. Each interrupter is mapped to a unique MSI-X
 // interrupt vector.
@@ -17,45 +25,140 @@ pub struct Interrupter {
 }
 
 impl Interrupter {
-  pub fn new() -> Interrupter {
-  }
-
-  pub fn add_event(&self, trb: Trb) {
-      self.event_ring.add_event(trb);
-      self.pending = true;
-      self.maybe_signal_interrupt();
-  }
-
-  pub fn set_enabled(&self, enabled: bool) {
-      self.enabled = enabled;
-      self.maybe_signal_interrupt();
-  }
-
-  pub fn set_moderation(_interval: u16, _counter: u16) {
-  }
-
-  pub fn set_event_ring_seg_table_size(&self, size: u16) {
-      self.event_ring.set_seg_table_size(size);
-  }
-
-  pub fn set_event_ring_seg_table_base_addr(&self, addr: GuestAddress) {
-      self.event_ring.set_seg_table_base_addr(addr);
-  }
-
-  pub fn set_event_ring_dequeue_pointer(&self, addr: GuestAddress) {
-      self.event_ring.set_dequeue_pointer(addr);
-      // Refer to table 5-39.
-      self.set_event_handler_busy(false);
-      if self.event_ring.is_empty() {
-          self.pending = false;
-      }
-  }
-
-  pub fn set_event_handler_busy(&self, bool busy) {
-      self.event_handler_busy = busy;
-      self.maybe_signal_interrupt();
-  }
-
-  fn maybe_signal_interrupt(&self) {
-  }
+    pub fn new(mem: GuestMemory, idx: u8) -> Interrupter {
+        Interrupter {
+            idx: idx,
+            event_ring: EventRing::new(mem),
+            enabled: false,
+            pending: false,
+            event_handler_busy: false,
+        }
+    }
+
+    pub fn add_event(&mut self, trb: Trb) -> Result<()> {
+        self.event_ring.add_event(trb)?;
+        self.pending = true;
+        self.maybe_signal_interrupt();
+        Ok(())
+    }
+
+    // Sends the event for the transfer trb at `trb_addr` on endpoint `endpoint_id` of slot
+    // `slot_id`, once the backend completed `transfer`. The event reports how many of the bytes
+    // asked for weren't transferred.
+    pub fn send_transfer_event(&mut self,
+                               trb_addr: u64,
+                               slot_id: u8,
+                               endpoint_id: u8,
+                               transfer: &BackendTransfer)
+                               -> Result<()> {
+        let mut trb = TransferEventTrb::for_completed_transfer(trb_addr,
+                                                               transfer.buffer.len() as u32,
+                                                               transfer.actual_length as u32,
+                                                               slot_id,
+                                                               endpoint_id);
+        let code = match transfer.status {
+            TransferStatus::Completed => None,
+            TransferStatus::Stalled => Some(TrbCompletionCode::StallError),
+            TransferStatus::Cancelled => Some(TrbCompletionCode::Stopped),
+            TransferStatus::Error => Some(TrbCompletionCode::TransactionError),
+        };
+        if let Some(code) = code {
+            trb.set_completion_code(code.to());
+        }
+        self.add_event(*trb.cast::<Trb>())
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.maybe_signal_interrupt();
+    }
+
+    pub fn set_moderation(&mut self, _interval: u16, _counter: u16) {
+    }
+
+    // The event ring reloads its segment table on these two writes. See `EventRing`.
+    pub fn set_event_ring_seg_table_size(&mut self, size: u16) {
+        self.event_ring.set_seg_table_size(size);
+    }
+
+    pub fn set_event_ring_seg_table_base_addr(&mut self, addr: GuestAddress) {
+        self.event_ring.set_seg_table_base_addr(addr);
+    }
+
+    pub fn set_event_ring_dequeue_pointer(&mut self, addr: GuestAddress) {
+        self.event_ring.set_dequeue_pointer(addr);
+        // Refer to table 5-39.
+        self.set_event_handler_busy(false);
+        if self.event_ring.is_empty() {
+            self.pending = false;
+        }
+    }
+
+    pub fn set_event_handler_busy(&mut self, busy: bool) {
+        self.event_handler_busy = busy;
+        self.maybe_signal_interrupt();
+    }
+
+    fn maybe_signal_interrupt(&self) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::xhci_backend_device::BackendTransferType;
+
+    const SEGMENT_TABLE: u64 = 0x100;
+    const SEGMENT: u64 = 0x1000;
+
+    fn setup_interrupter(mem: &GuestMemory) -> Interrupter {
+        let mut entry = EventRingSegmentTableEntry::new();
+        entry.set_ring_segment_base_address(SEGMENT);
+        entry.set_ring_segment_size(16);
+        mem.write_obj_at_addr(entry, GuestAddress(SEGMENT_TABLE)).unwrap();
+        let mut interrupter = Interrupter::new(mem.clone(), 0);
+        interrupter.set_event_ring_seg_table_size(1);
+        interrupter.set_event_ring_seg_table_base_addr(GuestAddress(SEGMENT_TABLE));
+        interrupter.set_event_ring_dequeue_pointer(GuestAddress(SEGMENT));
+        interrupter
+    }
+
+    fn completed_transfer(len: usize, status: TransferStatus, actual_length: usize)
+                          -> BackendTransfer {
+        let mut transfer = BackendTransfer::new(0x81, BackendTransferType::Bulk, vec![0; len]);
+        transfer.status = status;
+        transfer.actual_length = actual_length;
+        transfer
+    }
+
+    fn event(mem: &GuestMemory, index: u64) -> TransferEventTrb {
+        mem.read_obj_from_addr(GuestAddress(SEGMENT + index * 16)).unwrap()
+    }
+
+    #[test]
+    fn transfer_events() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut interrupter = setup_interrupter(&mem);
+
+        // A 13 byte mass storage CSW read into a 512 byte buffer.
+        interrupter
+            .send_transfer_event(0x2000,
+                                 1,
+                                 3,
+                                 &completed_transfer(512, TransferStatus::Completed, 13))
+            .unwrap();
+        let trb = event(&mem, 0);
+        assert_eq!(trb.get_trb_pointer(), 0x2000);
+        assert_eq!(trb.get_trb_transfer_length(), 499);
+        assert_eq!(trb.get_completion_code(), TrbCompletionCode::ShortPacket.to());
+        assert_eq!(trb.get_slot_id(), 1);
+        assert_eq!(trb.get_endpoint_id(), 3);
+
+        interrupter
+            .send_transfer_event(0x2010, 1, 3, &completed_transfer(512, TransferStatus::Stalled, 0))
+            .unwrap();
+        let trb = event(&mem, 1);
+        assert_eq!(trb.get_trb_transfer_length(), 512);
+        assert_eq!(trb.get_completion_code(), TrbCompletionCode::StallError.to());
+    }
 }
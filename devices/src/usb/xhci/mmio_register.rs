@@ -9,7 +9,7 @@ use std::sync::{Arc, Mutex};
 use std::mem::size_of;
 use std::slice::{from_raw_parts_mut};
 
-type BarOffset = u64;
+pub type BarOffset = u64;
 
 // This represents a range of memory in the MMIO space starting from Bar.
 // Both from and to and inclusive.
@@ -108,6 +108,7 @@ pub trait RegisterInterface {
 
 // Spec for Hardware init Read Only Registers.
 // The value of this register won't change.
+#[derive(Clone, Copy)]
 pub struct StaticRegisterSpec<T> {
     offset: BarOffset,
     value: T,
@@ -115,7 +116,15 @@ pub struct StaticRegisterSpec<T> {
 
 // All functions implemented on this one is thread safe.
 pub struct StaticRegister<T: 'static> where T: std::convert::Into<u64> {
-    spec: &'static StaticRegisterSpec<T>,
+    spec: StaticRegisterSpec<T>,
+}
+
+impl<T> StaticRegister<T> where T: std::convert::Into<u64> {
+    pub fn new(offset: BarOffset, value: T) -> StaticRegister<T> {
+        StaticRegister {
+            spec: StaticRegisterSpec { offset: offset, value: value },
+        }
+    }
 }
 
 impl<T> RegisterInterface for StaticRegister<T> where T: std::convert::Into<u64> + Clone {
@@ -139,16 +148,11 @@ macro_rules! static_register {
         offset: $offset:expr,
         value: $value:expr,
     ) => {{
-        static REG_SPEC: StaticRegisterSpec<$ty> = StaticRegisterSpec::<$ty> {
-            offset: $offset,
-            value: $value,
-        };
-        StaticRegister::<$ty> {
-            spec: &REG_SPEC
-        }
+        StaticRegister::<$ty>::new($offset, $value)
     }}
 }
 
+#[derive(Clone, Copy)]
 pub struct RegisterSpec<T> {
     offset: BarOffset,
     reset_value: T,
@@ -164,11 +168,22 @@ struct RegisterInner<T: 'static> {
     write_cb: Option<Box<Fn(u64)>>
 }
 
+// Clones share the value of the register, so one can be added to an `MMIOSpace` while the device
+// keeps another.
 pub struct Register<T: 'static> {
-    spec: &'static RegisterSpec<T>,
+    spec: RegisterSpec<T>,
     inner: Arc<Mutex<RegisterInner<T>>>,
 }
 
+impl<T> Clone for Register<T> where T: Copy {
+    fn clone(&self) -> Register<T> {
+        Register {
+            spec: self.spec,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
 // All functions implemented on this one is thread safe.
 impl <T> RegisterInterface for Register<T>  where T: std::convert::Into<u64> + Clone {
     fn bar_range(&self) -> BarRange {
@@ -216,6 +231,25 @@ impl <T> RegisterInterface for Register<T>  where T: std::convert::Into<u64> + C
 }
 
 impl <T> Register<T> where T: std::convert::Into<u64> + Clone {
+    pub fn new(offset: BarOffset,
+               reset_value: T,
+               guest_writeable_mask: T,
+               guest_write_1_to_clear_mask: T)
+               -> Register<T> {
+        Register {
+            spec: RegisterSpec {
+                offset: offset,
+                reset_value: reset_value.clone(),
+                guest_writeable_mask: guest_writeable_mask,
+                guest_write_1_to_clear_mask: guest_write_1_to_clear_mask,
+            },
+            inner: Arc::new(Mutex::new(RegisterInner {
+                value: reset_value,
+                write_cb: None,
+            })),
+        }
+    }
+
     pub fn get_value(&self) -> T{
         self.inner.lock().unwrap().value.clone()
     }
@@ -256,19 +290,7 @@ macro_rules! register {
         guest_writeable_mask: $mask:expr,
         guest_write_1_to_clear_mask: $w1tcm:expr,
     ) => {{
-        static REG_SPEC: RegisterSpec<$ty> = RegisterSpec::<$ty> {
-            offset: $offset,
-            reset_value: $rv,
-            guest_writeable_mask: $mask,
-            guest_write_1_to_clear_mask: $w1tcm,
-        };
-        Register::<$ty> {
-            spec: &REG_SPEC,
-            inner: Arc::new(Mutex::new(RegisterInner::<$ty> {
-                value: $rv,
-                write_cb: None,
-            }))
-        }
+        Register::<$ty>::new($offset, $rv, $mask, $w1tcm)
     }}
 }
 
@@ -283,19 +305,13 @@ macro_rules! register_array {
         guest_writeable_mask: $gwm:expr,
         guest_write_1_to_clear_mask: $gw1tcm:expr,
     ) => {{
-        static mut REGS: [Register; $cnt] = [Register {
-            offset: $base_offset,
-            size: $size,
-            reset_value: $rv,
-            guest_writeable_mask: $gwm,
-            guest_write_1_to_clear_mask: $gw1tcm,
-        }; $cnt];
+        // The count doesn't have to be a constant, so arrays can be sized by device parameters.
         let mut v: Vec<Register<$ty>> = Vec::new();
-        for i in 0..$cnt {
-            unsafe {
-                REGS[i].offset += ($stride * i) as BarOffset;
-                v.push(&REGS[i]);
-            }
+        for i in 0..($cnt as usize) {
+            v.push(Register::<$ty>::new($base_offset + ($stride * i) as BarOffset,
+                                        $rv,
+                                        $gwm,
+                                        $gw1tcm));
         }
         v
     }};
@@ -400,7 +416,7 @@ mod tests {
 
     #[test]
     fn static_register_basic_test_u8() {
-        let r = StaticRegister::<u8> { spec: &REG_SPEC0 };
+        let r = StaticRegister::<u8> { spec: REG_SPEC0 };
         let mut data: [u8; 4] = [0, 0, 0, 0];
         assert_eq!(r.bar_range().from, 3);
         assert_eq!(r.bar_range().to, 3);
@@ -412,7 +428,7 @@ mod tests {
 
     #[test]
     fn static_register_basic_test_u16() {
-        let r = StaticRegister::<u16> { spec: &REG_SPEC1 };
+        let r = StaticRegister::<u16> { spec: REG_SPEC1 };
         let mut data: [u8; 4] = [0, 0, 0, 0];
         assert_eq!(r.bar_range().from, 3);
         assert_eq!(r.bar_range().to, 4);
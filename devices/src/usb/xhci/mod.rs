@@ -7,7 +7,17 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
+#[macro_use]
+mod mmio_register;
+mod event_ring;
+mod interrupter;
+mod transfer_ring;
 mod xhci_abi;
+mod xhci_abi_schema;
 mod mmio_space;
+mod scatter_gather_buffer;
+mod xhci_backend_device;
+mod xhci_regs;
 
 pub use self::xhci_abi::*;
+pub use self::xhci_backend_device::*;
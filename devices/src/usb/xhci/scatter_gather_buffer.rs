@@ -0,0 +1,152 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+
+use sys_util::{GuestAddress, GuestMemory, GuestMemoryError};
+
+#[derive(Debug)]
+pub enum Error {
+    // A segment isn't entirely inside one region of guest memory.
+    InvalidSegment(GuestAddress, usize),
+    ReadGuestMemory(GuestMemoryError),
+    WriteGuestMemory(GuestMemoryError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::InvalidSegment(addr, len) => {
+                write!(f, "buffer segment of {} bytes at {:#x} is outside guest memory",
+                       len, addr.offset())
+            }
+            &Error::ReadGuestMemory(ref e) => write!(f, "failed to read guest memory: {:?}", e),
+            &Error::WriteGuestMemory(ref e) => write!(f, "failed to write guest memory: {:?}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// True if the `len` bytes at `addr` are all in the same region of `mem`.
+fn segment_in_memory(mem: &GuestMemory, addr: GuestAddress, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut found = false;
+    let _ = mem.with_regions_mut::<_, ()>(|_, base, size, _| {
+        if addr >= base && end.offset() - base.offset() <= size as u64 {
+            found = true;
+        }
+        Ok(())
+    });
+    found
+}
+
+// A transfer buffer made of segments of guest memory, such as the data buffers of the TRBs in a
+// transfer descriptor. The segments come from the guest, so each one is checked against guest
+// memory before it is used and a bad one fails the transfer instead of the device.
+pub struct ScatterGatherBuffer {
+    mem: GuestMemory,
+    segments: Vec<(GuestAddress, usize)>,
+}
+
+impl ScatterGatherBuffer {
+    pub fn new(mem: GuestMemory,
+               segments: Vec<(GuestAddress, usize)>)
+               -> Result<ScatterGatherBuffer> {
+        for &(addr, len) in &segments {
+            if !segment_in_memory(&mem, addr, len) {
+                return Err(Error::InvalidSegment(addr, len));
+            }
+        }
+        Ok(ScatterGatherBuffer { mem: mem, segments: segments })
+    }
+
+    // Total length of all segments in bytes.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|&(_, len)| len).sum()
+    }
+
+    // Copies the buffer into `buffer`, stopping when either one runs out. Returns the number of
+    // bytes copied.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize> {
+        let mut total = 0;
+        for &(addr, len) in &self.segments {
+            let count = min(len, buffer.len() - total);
+            if count == 0 {
+                break;
+            }
+            let read = self.mem
+                .read_slice_at_addr(&mut buffer[total..total + count], addr)
+                .map_err(Error::ReadGuestMemory)?;
+            if read != count {
+                return Err(Error::InvalidSegment(addr, len));
+            }
+            total += count;
+        }
+        Ok(total)
+    }
+
+    // Copies `buffer` into the buffer, stopping when either one runs out. Returns the number of
+    // bytes copied.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize> {
+        let mut total = 0;
+        for &(addr, len) in &self.segments {
+            let count = min(len, buffer.len() - total);
+            if count == 0 {
+                break;
+            }
+            let written = self.mem
+                .write_slice_at_addr(&buffer[total..total + count], addr)
+                .map_err(Error::WriteGuestMemory)?;
+            if written != count {
+                return Err(Error::InvalidSegment(addr, len));
+            }
+            total += count;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guest_memory() -> GuestMemory {
+        GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x2000), 0x1000)]).unwrap()
+    }
+
+    #[test]
+    fn read_write() {
+        let segments = vec![(GuestAddress(0x100), 4), (GuestAddress(0x2000), 4)];
+        let buffer = ScatterGatherBuffer::new(guest_memory(), segments).unwrap();
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(buffer.write(&[1, 2, 3, 4, 5, 6]).unwrap(), 6);
+        let mut data = [0u8; 10];
+        assert_eq!(buffer.read(&mut data).unwrap(), 8);
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn segment_outside_memory() {
+        let mem = guest_memory();
+        // Past the end of guest memory.
+        match ScatterGatherBuffer::new(mem.clone(), vec![(GuestAddress(0x3000), 16)]) {
+            Err(Error::InvalidSegment(GuestAddress(0x3000), 16)) => {}
+            _ => panic!("segment past the end of memory accepted"),
+        }
+        // Runs off the end of the last region.
+        assert!(ScatterGatherBuffer::new(mem.clone(), vec![(GuestAddress(0x2ff0), 32)]).is_err());
+        // Runs into the hole between regions.
+        assert!(ScatterGatherBuffer::new(mem.clone(), vec![(GuestAddress(0xff0), 32)]).is_err());
+        // Wraps around the address space.
+        assert!(ScatterGatherBuffer::new(mem, vec![(GuestAddress(u64::max_value()), 2)]).is_err());
+    }
+}
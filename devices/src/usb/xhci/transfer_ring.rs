@@ -2,11 +2,22 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use sys_util::{GuestAddress, GuestMemory};
+
+use super::xhci_abi::*;
+
+// Bits 3:0 of the ring segment pointer of a link trb are reserved. See spec 6.4.4.1.
+const LINK_TRB_RING_SEGMENT_POINTER_MASK: u64 = !0xf;
+
+// A guest could build a loop of link trbs that never reaches a transfer trb. Give up on the ring
+// after following this many links in a row.
+const MAX_CONSECUTIVE_LINK_TRBS: usize = 32;
+
 // Transfer Ring is segmented circular buffer in guest memory containing work items
 // called transfer descriptors, each of which consists of one or more TRBs.
 // Transfer Ring management is defined in 4.9.2.
 pub struct TransferRing {
-    mem: &GuestMemory,
+    mem: GuestMemory,
     dequeue_pointer: GuestAddress,
     // Used to check if the ring is empty. Toggled when looping back to the begining
     // of the buffer.
@@ -15,7 +26,7 @@ pub struct TransferRing {
 
 // Public interfaces for Transfer Ring.
 impl TransferRing {
-    pub fn new(mem: &GuestMemory) -> Self {
+    pub fn new(mem: GuestMemory) -> Self {
         TransferRing {
             mem: mem,
             dequeue_pointer: GuestAddress(0),
@@ -23,58 +34,163 @@ impl TransferRing {
         }
     }
 
-    pub fn dequeue_transfer_descriptor(&mut self) -> Option<Vec<Trb>> {
-        let td: Vec<Trb> = Vec::new();
+    // Dequeues the next complete transfer descriptor. Link trbs are followed and not returned.
+    // Returns None if the ring doesn't hold a complete descriptor yet, in which case the trbs of
+    // the partial descriptor are left on the ring to be dequeued again once it is complete.
+    pub fn dequeue_transfer_descriptor(&mut self) -> Option<Vec<AddressedTrb>> {
+        let mut td: Vec<AddressedTrb> = Vec::new();
+        // Where the descriptor starts, so a partial one can be put back.
+        let mut td_start = (self.dequeue_pointer, self.consumer_cycle_state);
+        let mut links = 0;
         loop {
             let addressed_trb = match self.get_next_trb() {
                 Some(t) => t,
                 None => break,
-            }
+            };
 
-            if addressed_trb.trb.get_trb_type() == TrbType::Link {
+            if let Ok(TrbType::Link) = addressed_trb.trb.trb_type() {
+                links += 1;
+                if links > MAX_CONSECUTIVE_LINK_TRBS {
+                    warn!("xhci: too many consecutive link trbs in transfer ring");
+                    break;
+                }
                 let link_trb = addressed_trb.trb.cast::<LinkTrb>();
-                self.dequeue_pointer = GuestAddress(link_trb.ring_segment_pointer);
-                self.consumer_cycle_state = (self.consumer_cycle_state != link_trb.get_toggle_cycle());
+                self.dequeue_pointer = GuestAddress(link_trb.get_ring_segment_pointer() &
+                                                    LINK_TRB_RING_SEGMENT_POINTER_MASK);
+                if link_trb.get_toggle_cycle() != 0 {
+                    self.consumer_cycle_state = !self.consumer_cycle_state;
+                }
+                if td.is_empty() {
+                    td_start = (self.dequeue_pointer, self.consumer_cycle_state);
+                }
                 continue;
             }
-            self.dequeue_pointer = match self.dequeue_pointer.checked_add(TRB_SIZE) {
+            links = 0;
+
+            self.dequeue_pointer = match self.dequeue_pointer.checked_add(TRB_SIZE as u64) {
                 Some(addr) => addr,
-                None => panic!("Crash due to unknown bug"),
-            }
+                None => break,
+            };
+            let chain = addressed_trb.trb.get_chain_bit();
             td.push(addressed_trb);
-            if !addressed_trb.get_chain_bit() {
-                break;
+            if !chain {
+                return Some(td);
             }
         }
-        if td.len() == 0 || td.last().trb.get_chain_bit() {
-            None
-        }
-        td
+
+        self.dequeue_pointer = td_start.0;
+        self.consumer_cycle_state = td_start.1;
+        None
     }
 
     pub fn set_dequeue_pointer(&mut self, addr: GuestAddress) {
         self.dequeue_pointer = addr;
     }
 
-    pub fn set_consumer_cycle_state(&mut self, bool state) {
+    pub fn set_consumer_cycle_state(&mut self, state: bool) {
         self.consumer_cycle_state = state;
     }
+
+    pub fn get_dequeue_pointer(&self) -> GuestAddress {
+        self.dequeue_pointer
+    }
+
+    pub fn get_consumer_cycle_state(&self) -> bool {
+        self.consumer_cycle_state
+    }
 }
 
 impl TransferRing {
     // Read next trb pointed by dequeue pointer. Does not proceed dequeue pointer.
     fn get_next_trb(&self) -> Option<AddressedTrb> {
-        let trb = self.mem.read_obj_from_addr().unwrap();
+        let trb: Trb = match self.mem.read_obj_from_addr(self.dequeue_pointer) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("xhci: failed to read transfer ring: {:?}", e);
+                return None;
+            }
+        };
         // If cycle bit of trb does not equal consumer cycle state, the ring is empty.
         // This trb is invalid.
-        if trb.get_cycle() != self.consumer_cycle_state {
+        if trb.get_cycle_bit() != self.consumer_cycle_state {
             None
         } else {
             Some(AddressedTrb {
                 trb: trb,
-                gpa: dequeue_pointer.0,
+                gpa: self.dequeue_pointer.0,
             })
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_normal_trb(mem: &GuestMemory, addr: u64, cycle: bool, chain: bool) {
+        let mut trb = NormalTrb::new();
+        trb.set_trb_type(TrbType::Normal.to());
+        trb.set_cycle(cycle as u8);
+        trb.set_chain(chain as u8);
+        mem.write_obj_at_addr(trb, GuestAddress(addr)).unwrap();
+    }
+
+    fn write_link_trb(mem: &GuestMemory, addr: u64, cycle: bool, target: u64) {
+        let mut trb = LinkTrb::new();
+        trb.set_trb_type(TrbType::Link.to());
+        trb.set_cycle(cycle as u8);
+        trb.set_toggle_cycle(1);
+        trb.set_ring_segment_pointer(target);
+        mem.write_obj_at_addr(trb, GuestAddress(addr)).unwrap();
+    }
+
+    fn gpas(td: &[AddressedTrb]) -> Vec<u64> {
+        td.iter().map(|t| t.gpa).collect()
+    }
+
+    #[test]
+    fn wrap_with_link_trb() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        // A ring of three transfer trbs and a link back to the start that toggles the cycle.
+        write_normal_trb(&mem, 0x100, true, false);
+        write_normal_trb(&mem, 0x110, true, false);
+        write_normal_trb(&mem, 0x120, true, true);
+        write_link_trb(&mem, 0x130, true, 0x100);
+        let mut ring = TransferRing::new(mem.clone());
+        ring.set_dequeue_pointer(GuestAddress(0x100));
+
+        assert_eq!(gpas(&ring.dequeue_transfer_descriptor().unwrap()), vec![0x100]);
+        assert_eq!(gpas(&ring.dequeue_transfer_descriptor().unwrap()), vec![0x110]);
+        assert_eq!(ring.get_dequeue_pointer(), GuestAddress(0x120));
+
+        // The trb at 0x120 chains across the link to a trb the guest hasn't written yet.
+        assert!(ring.dequeue_transfer_descriptor().is_none());
+        assert_eq!(ring.get_dequeue_pointer(), GuestAddress(0x120));
+        assert!(ring.get_consumer_cycle_state());
+
+        // After the wrap the guest writes trbs with the cycle bit cleared.
+        write_normal_trb(&mem, 0x100, false, false);
+        assert_eq!(gpas(&ring.dequeue_transfer_descriptor().unwrap()), vec![0x120, 0x100]);
+        assert_eq!(ring.get_dequeue_pointer(), GuestAddress(0x110));
+        assert!(!ring.get_consumer_cycle_state());
+
+        // The old trb at 0x110 still has the cycle bit set, so the ring is empty.
+        assert!(ring.dequeue_transfer_descriptor().is_none());
+        assert_eq!(ring.get_dequeue_pointer(), GuestAddress(0x110));
+    }
+
+    #[test]
+    fn link_without_transfer_trb() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        write_link_trb(&mem, 0x100, true, 0x200);
+        // Left over from the previous pass over the ring.
+        write_normal_trb(&mem, 0x200, true, false);
+        let mut ring = TransferRing::new(mem.clone());
+        ring.set_dequeue_pointer(GuestAddress(0x100));
+
+        // The link is followed even though nothing is behind it yet.
+        assert!(ring.dequeue_transfer_descriptor().is_none());
+        assert_eq!(ring.get_dequeue_pointer(), GuestAddress(0x200));
+        assert!(!ring.get_consumer_cycle_state());
+    }
+}
@@ -2,6 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std;
+use std::fmt;
+
+use data_model::DataInit;
+
+pub use super::xhci_abi_schema::*;
+
 unsafe impl data_model::DataInit for Trb {}
 unsafe impl data_model::DataInit for NormalTrb {}
 unsafe impl data_model::DataInit for SetupStageTrb {}
@@ -49,16 +56,19 @@ unsafe impl TrbCast for InputControlContext {}
 unsafe impl TrbCast for SlotContext {}
 unsafe impl TrbCast for EndpointContext {}
 
-// One trb could be safely casted to another.
-pub trait TrbCast: DataInit {
+// One trb could be safely casted to another. Implementing it asserts that every bit pattern of
+// the implementor is a valid value of every other implementor of the same size.
+pub unsafe trait TrbCast: DataInit {
     fn cast<T: DataInit + TrbCast>(&self) -> &T {
-        match T::from_slice(self.as_slice()) {
-            Some(&t) => t,
-            _ => panic!("Unable to cast"),
-        }
+        T::from_slice(self.as_slice()).expect("Unable to cast")
+    }
+
+    fn cast_mut<T: DataInit + TrbCast>(&mut self) -> &mut T {
+        T::from_mut_slice(self.as_mut_slice()).expect("Unable to cast")
     }
 }
 
+#[derive(Debug)]
 pub enum Error {
     InvalidValue(u8),
 }
@@ -75,63 +85,64 @@ type Result<T> = std::result::Result<T, Error>;
 
 impl Trb {
     pub fn trb_type(&self) -> Result<TrbType> {
-        TrbType::from(self.get_trb_type());
+        <TrbType as PrimitiveEnum>::from(self.get_trb_type())
     }
 
     pub fn set_cycle_bit(&mut self, b: bool) {
-        match b {
-            true => self.set_cycle(1u8),
-            false => self.set_cyle(0u8),
-        }
+        self.set_cycle(b as u8);
+    }
+
+    pub fn get_cycle_bit(&self) -> bool {
+        self.get_cycle() != 0
     }
 
     pub fn get_chain_bit(&self) -> bool {
-        match self.trb_type().unwrap() {
-            TrbType::Normal => self.cast<NormalTrb>().get_chain() != 0,
-            TrbType::DataStage => self.cast<DataStageTrb>().get_chain() != 0,
-            TrbType::StatusStage => self.cast<StatusStageTrb>().get_chain() != 0,
-            TrbType::Isoch => self.cast<IsochTrb>().get_chain() != 0,
-            TrbType::Noop => self.cast<NoopTrb>().get_chain() != 0,
-            TrbType::Link => self.cast<LinkTrb>().get_chain() != 0,
-            TrbType::EventData => self.cast<EventDataTrb>().get_chain() != 0,
+        match self.trb_type() {
+            Ok(TrbType::Normal) => self.cast::<NormalTrb>().get_chain() != 0,
+            Ok(TrbType::DataStage) => self.cast::<DataStageTrb>().get_chain() != 0,
+            Ok(TrbType::StatusStage) => self.cast::<StatusStageTrb>().get_chain() != 0,
+            Ok(TrbType::Isoch) => self.cast::<IsochTrb>().get_chain() != 0,
+            Ok(TrbType::Noop) => self.cast::<NoopTrb>().get_chain() != 0,
+            Ok(TrbType::Link) => self.cast::<LinkTrb>().get_chain() != 0,
+            Ok(TrbType::EventData) => self.cast::<EventDataTrb>().get_chain() != 0,
             // TODO(jkwang) add log here.
             _ => false,
         }
     }
 
-    pub fn interrupter_target(&self) -> u8 {
-        const STATUS_INTERRUPTER_TARGET_OFFSET: u8 = 22;
-        self.get_status() >> STATUS_INTERRUPTER_TARGET_OFFSET
+    pub fn interrupter_target(&self) -> u16 {
+        const STATUS_INTERRUPTER_TARGET_OFFSET: u32 = 22;
+        (self.get_status() >> STATUS_INTERRUPTER_TARGET_OFFSET) as u16
     }
 
     pub fn can_in_transfer_ring(&self) -> bool {
-        match self.trb_type().unwrap() {
-            TrbType::Normal | TrbType::SetupStage | TrbType::DataStage |
-                TrbType::StatusStage | TrbType::Isoch | TrbType::Link |
-                TrbType:: EventData | TrbType::Noop => true,
+        match self.trb_type() {
+            Ok(TrbType::Normal) | Ok(TrbType::SetupStage) | Ok(TrbType::DataStage) |
+            Ok(TrbType::StatusStage) | Ok(TrbType::Isoch) | Ok(TrbType::Link) |
+            Ok(TrbType::EventData) | Ok(TrbType::Noop) => true,
             _ => false,
         }
     }
 
     pub fn transfer_length(&self) -> u32 {
         const STATUS_TRANSFER_LENGTH_MASK: u32 = 0x1ffff;
-        match self.trb_type().unwrap() {
-            TrbType::Normal | TrbType::SetupStage | TrbType::DataStage | TrbType::Isoch
-                => trb.get_status() & STATUS_TRANSFER_LENGTH_MASK,
-                _ => 0,
+        match self.trb_type() {
+            Ok(TrbType::Normal) | Ok(TrbType::SetupStage) | Ok(TrbType::DataStage) |
+            Ok(TrbType::Isoch) => self.get_status() & STATUS_TRANSFER_LENGTH_MASK,
+            _ => 0,
         }
     }
 
     pub fn interrupt_on_completion(&self) -> bool {
-        const FLAGS_INTERRUPT_ON_COMPLETION_MASK: u32= 0x10;
+        const FLAGS_INTERRUPT_ON_COMPLETION_MASK: u16 = 0x10;
         (self.get_flags() & FLAGS_INTERRUPT_ON_COMPLETION_MASK) > 0
     }
 
     pub fn immediate_data(&self) -> bool {
-        const FLAGS_IMMEDIATE_DATA_MASK: u32 = 0x20;
-        match self.trb_type().unwrap() {
-            TrbType::Normal | TrbType::SetupStage | TrbType::DataStage | TrbType::Isoch
-                -> self.get_flags() & FLAGS_IMMEDIATE_DATA_MASK,
+        const FLAGS_IMMEDIATE_DATA_MASK: u16 = 0x20;
+        match self.trb_type() {
+            Ok(TrbType::Normal) | Ok(TrbType::SetupStage) | Ok(TrbType::DataStage) |
+            Ok(TrbType::Isoch) => (self.get_flags() & FLAGS_IMMEDIATE_DATA_MASK) != 0,
             _ => false,
         }
     }
@@ -159,8 +170,18 @@ pub enum TrbType {
     AddressDeviceCommand = 11,
     ConfigureEndpointCommand = 12,
     EvaluateContextCommand = 13,
+    ResetEndpointCommand = 14,
+    StopEndpointCommand = 15,
+    SetTRDequeuePointerCommand = 16,
     ResetDeviceCommand = 17,
+    ForceEventCommand = 18,
+    NegotiateBandwidthCommand = 19,
+    SetLatencyToleranceValueCommand = 20,
+    GetPortBandwidthCommand = 21,
+    ForceHeaderCommand = 22,
     NoopCommand = 23,
+    GetExtendedPropertyCommand = 24,
+    SetExtendedPropertyCommand = 25,
     TransferEvent = 32,
     CommandCompletionEvent = 33,
     PortStatusChangeEvent = 34,
@@ -183,8 +204,18 @@ impl PrimitiveEnum for TrbType {
             11 => Ok(TrbType::AddressDeviceCommand),
             12 => Ok(TrbType::ConfigureEndpointCommand),
             13 => Ok(TrbType::EvaluateContextCommand),
+            14 => Ok(TrbType::ResetEndpointCommand),
+            15 => Ok(TrbType::StopEndpointCommand),
+            16 => Ok(TrbType::SetTRDequeuePointerCommand),
             17 => Ok(TrbType::ResetDeviceCommand),
+            18 => Ok(TrbType::ForceEventCommand),
+            19 => Ok(TrbType::NegotiateBandwidthCommand),
+            20 => Ok(TrbType::SetLatencyToleranceValueCommand),
+            21 => Ok(TrbType::GetPortBandwidthCommand),
+            22 => Ok(TrbType::ForceHeaderCommand),
             23 => Ok(TrbType::NoopCommand),
+            24 => Ok(TrbType::GetExtendedPropertyCommand),
+            25 => Ok(TrbType::SetExtendedPropertyCommand),
             32 => Ok(TrbType::TransferEvent),
             33 => Ok(TrbType::CommandCompletionEvent),
             34 => Ok(TrbType::PortStatusChangeEvent),
@@ -207,8 +238,18 @@ impl PrimitiveEnum for TrbType {
             &TrbType::AddressDeviceCommand => 11,
             &TrbType::ConfigureEndpointCommand => 12,
             &TrbType::EvaluateContextCommand => 13,
+            &TrbType::ResetEndpointCommand => 14,
+            &TrbType::StopEndpointCommand => 15,
+            &TrbType::SetTRDequeuePointerCommand => 16,
             &TrbType::ResetDeviceCommand => 17,
+            &TrbType::ForceEventCommand => 18,
+            &TrbType::NegotiateBandwidthCommand => 19,
+            &TrbType::SetLatencyToleranceValueCommand => 20,
+            &TrbType::GetPortBandwidthCommand => 21,
+            &TrbType::ForceHeaderCommand => 22,
             &TrbType::NoopCommand => 23,
+            &TrbType::GetExtendedPropertyCommand => 24,
+            &TrbType::SetExtendedPropertyCommand => 25,
             &TrbType::TransferEvent => 32,
             &TrbType::CommandCompletionEvent => 33,
             &TrbType::PortStatusChangeEvent => 34,
@@ -216,14 +257,28 @@ impl PrimitiveEnum for TrbType {
     }
 }
 
+impl TrbType {
+    // Command TRBs are the only ones allowed on the command ring. See xhci spec table 6-91.
+    pub fn is_command(&self) -> bool {
+        let val = self.to();
+        val >= TrbType::EnableSlotCommand.to() && val <= TrbType::SetExtendedPropertyCommand.to()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TrbCompletionCode {
     Success = 1,
     TransactionError = 4,
     TrbError = 5,
+    StallError = 6,
     NoSlotsAvailableError = 9,
     SlotNotEnabledError = 11,
     ShortPacket = 13,
+    ParameterError = 17,
     ContextStateError = 19,
+    CommandRingStopped = 24,
+    CommandAborted = 25,
+    Stopped = 26,
 }
 
 impl PrimitiveEnum for TrbCompletionCode {
@@ -232,10 +287,15 @@ impl PrimitiveEnum for TrbCompletionCode {
             1 => Ok(TrbCompletionCode::Success),
             4 => Ok(TrbCompletionCode::TransactionError),
             5 => Ok(TrbCompletionCode::TrbError),
+            6 => Ok(TrbCompletionCode::StallError),
             9 => Ok(TrbCompletionCode::NoSlotsAvailableError),
             11 => Ok(TrbCompletionCode::SlotNotEnabledError),
             13 => Ok(TrbCompletionCode::ShortPacket),
+            17 => Ok(TrbCompletionCode::ParameterError),
             19 => Ok(TrbCompletionCode::ContextStateError),
+            24 => Ok(TrbCompletionCode::CommandRingStopped),
+            25 => Ok(TrbCompletionCode::CommandAborted),
+            26 => Ok(TrbCompletionCode::Stopped),
             _ => Err(Error::InvalidValue(val)),
         }
     }
@@ -245,14 +305,20 @@ impl PrimitiveEnum for TrbCompletionCode {
             &TrbCompletionCode::Success => 1,
             &TrbCompletionCode::TransactionError => 4,
             &TrbCompletionCode::TrbError => 5,
+            &TrbCompletionCode::StallError => 6,
             &TrbCompletionCode::NoSlotsAvailableError => 9,
             &TrbCompletionCode::SlotNotEnabledError => 11,
             &TrbCompletionCode::ShortPacket => 13,
+            &TrbCompletionCode::ParameterError => 17,
             &TrbCompletionCode::ContextStateError => 19,
+            &TrbCompletionCode::CommandRingStopped => 24,
+            &TrbCompletionCode::CommandAborted => 25,
+            &TrbCompletionCode::Stopped => 26,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DeviceSlotState {
     // The same value (0) is used for both the enabled and disabled states. See
     // xhci spec table 60.
@@ -283,13 +349,56 @@ impl PrimitiveEnum for DeviceSlotState {
     }
 }
 
+impl DeviceSlotState {
+    // Returns the state an Address Device command moves a slot in this state to, or the completion
+    // code it fails with. See xhci spec section 4.6.5. With the Block Set Address Request flag
+    // set, an enabled slot only goes to Default, without a USB address. The guest then sends
+    // another Address Device, without the flag, to actually assign the address.
+    pub fn address_device(&self,
+                          block_set_address_request: bool)
+                          -> std::result::Result<DeviceSlotState, TrbCompletionCode> {
+        match (self, block_set_address_request) {
+            (&DeviceSlotState::DisabledOrEnabled, true) => Ok(DeviceSlotState::Default),
+            (&DeviceSlotState::DisabledOrEnabled, false) |
+            (&DeviceSlotState::Default, false) => Ok(DeviceSlotState::Addressed),
+            _ => Err(TrbCompletionCode::ContextStateError),
+        }
+    }
+
+    // Returns the state a Reset Device command moves a slot in this state to, see xhci spec
+    // section 4.6.11. Only a slot with an addressed device can be reset.
+    pub fn reset_device(&self) -> std::result::Result<DeviceSlotState, TrbCompletionCode> {
+        match self {
+            &DeviceSlotState::Addressed | &DeviceSlotState::Configured => {
+                Ok(DeviceSlotState::Default)
+            }
+            _ => Err(TrbCompletionCode::ContextStateError),
+        }
+    }
+
+    // Evaluate Context only updates the contexts of a slot that has been through Address Device,
+    // see xhci spec section 4.6.7.
+    pub fn can_evaluate_context(&self) -> bool {
+        match self {
+            &DeviceSlotState::Default |
+            &DeviceSlotState::Addressed |
+            &DeviceSlotState::Configured => true,
+            &DeviceSlotState::DisabledOrEnabled => false,
+        }
+    }
+}
+
 impl SlotContext {
-    pub fn state(&self) -> DeviceSlotState {
-        DeviceSlotState::from(self.get_slot_state())
+    pub fn state(&self) -> Result<DeviceSlotState> {
+        <DeviceSlotState as PrimitiveEnum>::from(self.get_slot_state())
     }
 
     pub fn set_state(&mut self, new_state: DeviceSlotState) {
-        self.set_slot_state(state.to());
+        self.set_slot_state(new_state.to());
+    }
+
+    pub fn speed(&self) -> Result<UsbSpeed> {
+        <UsbSpeed as PrimitiveEnum>::from(self.get_speed())
     }
 }
 
@@ -315,17 +424,156 @@ impl PrimitiveEnum for EndpointState {
     }
 }
 
+// Speed of a device, as in the slot context. These are the default Protocol Speed ID values, see
+// xhci spec section 7.2.2.1.1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsbSpeed {
+    Full = 1,
+    Low = 2,
+    High = 3,
+    Super = 4,
+}
+
+impl PrimitiveEnum for UsbSpeed {
+    fn from(val: u8) -> Result<Self> {
+        match val {
+            1 => Ok(UsbSpeed::Full),
+            2 => Ok(UsbSpeed::Low),
+            3 => Ok(UsbSpeed::High),
+            4 => Ok(UsbSpeed::Super),
+            _ => Err(Error::InvalidValue(val)),
+        }
+    }
+
+    fn to(&self) -> u8 {
+        match self {
+            &UsbSpeed::Full => 1,
+            &UsbSpeed::Low => 2,
+            &UsbSpeed::High => 3,
+            &UsbSpeed::Super => 4,
+        }
+    }
+}
+
+// See xhci spec table 57. Value 0 is "Not Valid".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointType {
+    IsochOut = 1,
+    BulkOut = 2,
+    InterruptOut = 3,
+    Control = 4,
+    IsochIn = 5,
+    BulkIn = 6,
+    InterruptIn = 7,
+}
+
+impl PrimitiveEnum for EndpointType {
+    fn from(val: u8) -> Result<Self> {
+        match val {
+            1 => Ok(EndpointType::IsochOut),
+            2 => Ok(EndpointType::BulkOut),
+            3 => Ok(EndpointType::InterruptOut),
+            4 => Ok(EndpointType::Control),
+            5 => Ok(EndpointType::IsochIn),
+            6 => Ok(EndpointType::BulkIn),
+            7 => Ok(EndpointType::InterruptIn),
+            _ => Err(Error::InvalidValue(val)),
+        }
+    }
+
+    fn to(&self) -> u8 {
+        match self {
+            &EndpointType::IsochOut => 1,
+            &EndpointType::BulkOut => 2,
+            &EndpointType::InterruptOut => 3,
+            &EndpointType::Control => 4,
+            &EndpointType::IsochIn => 5,
+            &EndpointType::BulkIn => 6,
+            &EndpointType::InterruptIn => 7,
+        }
+    }
+}
+
 impl EndpointContext {
-    pub fn state(&self) -> EndpointState {
-        EndpointState::from(self.get_endpoint_state())
+    pub fn state(&self) -> Result<EndpointState> {
+        <EndpointState as PrimitiveEnum>::from(self.get_endpoint_state())
     }
 
     pub fn set_state(&mut self, state: EndpointState) {
-        self.set_endpoint_sate(state.to());
+        self.set_endpoint_state(state.to());
+    }
+
+    pub fn endpoint_type(&self) -> Result<EndpointType> {
+        <EndpointType as PrimitiveEnum>::from(self.get_endpoint_type())
+    }
+
+    // Checks the endpoint type, Max Packet Size and Interval against what USB allows for a device
+    // of `speed`, before the endpoint is added by a Configure Endpoint command. Returns the
+    // completion code for the command if they don't fit. The packet size limits are from the USB
+    // 2.0 spec chapter 5 and the USB 3.0 spec section 9.6.6, the interval ranges from xhci spec
+    // section 6.2.3.6.
+    pub fn check_parameters(&self, speed: UsbSpeed) -> std::result::Result<(), TrbCompletionCode> {
+        use self::EndpointType::*;
+        use self::UsbSpeed::*;
+        let ep_type = self.endpoint_type().map_err(|_| TrbCompletionCode::ParameterError)?;
+        let max_packet_size = self.get_max_packet_size();
+        let interval = self.get_interval();
+        let max_size_ok = match (ep_type, speed) {
+            (Control, Low) => max_packet_size == 8,
+            (Control, Full) => [8, 16, 32, 64].contains(&max_packet_size),
+            (Control, High) => max_packet_size == 64,
+            (Control, Super) => max_packet_size == 512,
+            (BulkOut, Low) | (BulkIn, Low) | (IsochOut, Low) | (IsochIn, Low) => false,
+            (BulkOut, Full) | (BulkIn, Full) => [8, 16, 32, 64].contains(&max_packet_size),
+            (BulkOut, High) | (BulkIn, High) => max_packet_size <= 512,
+            (InterruptOut, Low) | (InterruptIn, Low) => max_packet_size <= 8,
+            (InterruptOut, Full) | (InterruptIn, Full) => max_packet_size <= 64,
+            (IsochOut, Full) | (IsochIn, Full) => max_packet_size <= 1023,
+            _ => max_packet_size <= 1024,
+        };
+        let interval_ok = match (ep_type, speed) {
+            (Control, _) | (BulkOut, _) | (BulkIn, _) => interval == 0,
+            (InterruptOut, Low) | (InterruptIn, Low) |
+            (InterruptOut, Full) | (InterruptIn, Full) => interval >= 3 && interval <= 10,
+            (IsochOut, Full) | (IsochIn, Full) => interval >= 3 && interval <= 18,
+            _ => interval <= 15,
+        };
+        if max_packet_size == 0 || !max_size_ok || !interval_ok {
+            return Err(TrbCompletionCode::ParameterError);
+        }
+        Ok(())
     }
 }
 
 
+impl TransferEventTrb {
+    // Builds the event for the transfer trb at `trb_addr`, which asked for `requested` bytes of
+    // which `actual` were transferred. The event's transfer length is the residual, the bytes not
+    // transferred, and a transfer that fell short, like a bulk IN transfer ending in a short
+    // packet, completes with Short Packet. See xhci spec section 4.10.1.1.
+    pub fn for_completed_transfer(trb_addr: u64,
+                                  requested: u32,
+                                  actual: u32,
+                                  slot_id: u8,
+                                  endpoint_id: u8)
+                                  -> TransferEventTrb {
+        let mut trb = TransferEventTrb::new();
+        trb.set_trb_pointer(trb_addr);
+        let residual = requested.saturating_sub(actual);
+        trb.set_trb_transfer_length(residual);
+        let code = if residual == 0 {
+            TrbCompletionCode::Success
+        } else {
+            TrbCompletionCode::ShortPacket
+        };
+        trb.set_completion_code(code.to());
+        trb.set_trb_type(TrbType::TransferEvent.to());
+        trb.set_slot_id(slot_id);
+        trb.set_endpoint_id(endpoint_id);
+        trb
+    }
+}
+
 impl InputControlContext {
     pub fn drop_context_flag(&self, idx: u8) -> bool {
         (self.get_drop_context_flags() &  (1 << idx)) > 0
@@ -336,4 +584,97 @@ impl InputControlContext {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn command_trb_types() {
+        assert!(TrbType::ForceHeaderCommand.is_command());
+        assert!(TrbType::GetExtendedPropertyCommand.is_command());
+        assert!(!TrbType::Normal.is_command());
+        assert!(!TrbType::CommandCompletionEvent.is_command());
+    }
+
+    #[test]
+    fn short_packet_residual() {
+        // A 13 byte mass storage CSW read into a 512 byte buffer.
+        let trb = TransferEventTrb::for_completed_transfer(0x2000, 512, 13, 1, 3);
+        assert_eq!(trb.get_trb_transfer_length(), 499);
+        assert_eq!(trb.get_completion_code(), TrbCompletionCode::ShortPacket.to());
+        assert_eq!(trb.get_trb_type(), TrbType::TransferEvent.to());
+        assert_eq!(trb.get_endpoint_id(), 3);
+
+        let trb = TransferEventTrb::for_completed_transfer(0x2000, 512, 512, 1, 3);
+        assert_eq!(trb.get_trb_transfer_length(), 0);
+        assert_eq!(trb.get_completion_code(), TrbCompletionCode::Success.to());
+    }
+
+    #[test]
+    fn address_device_states() {
+        // Block Set Address Request first, then the real address.
+        let state = DeviceSlotState::DisabledOrEnabled.address_device(true).unwrap();
+        assert_eq!(state, DeviceSlotState::Default);
+        assert_eq!(state.address_device(false), Ok(DeviceSlotState::Addressed));
+        assert_eq!(state.address_device(true), Err(TrbCompletionCode::ContextStateError));
+
+        assert_eq!(DeviceSlotState::DisabledOrEnabled.address_device(false),
+                   Ok(DeviceSlotState::Addressed));
+        assert_eq!(DeviceSlotState::Addressed.address_device(false),
+                   Err(TrbCompletionCode::ContextStateError));
+        assert_eq!(DeviceSlotState::Configured.address_device(true),
+                   Err(TrbCompletionCode::ContextStateError));
+    }
+
+    #[test]
+    fn reset_device_states() {
+        assert_eq!(DeviceSlotState::Configured.reset_device(), Ok(DeviceSlotState::Default));
+        assert_eq!(DeviceSlotState::Addressed.reset_device(), Ok(DeviceSlotState::Default));
+        assert_eq!(DeviceSlotState::Default.reset_device(),
+                   Err(TrbCompletionCode::ContextStateError));
+        assert_eq!(DeviceSlotState::DisabledOrEnabled.reset_device(),
+                   Err(TrbCompletionCode::ContextStateError));
+    }
+
+    #[test]
+    fn evaluate_context_states() {
+        assert!(!DeviceSlotState::DisabledOrEnabled.can_evaluate_context());
+        assert!(DeviceSlotState::Default.can_evaluate_context());
+        assert!(DeviceSlotState::Addressed.can_evaluate_context());
+        assert!(DeviceSlotState::Configured.can_evaluate_context());
+    }
+
+    fn endpoint(ep_type: EndpointType, max_packet_size: u16, interval: u8) -> EndpointContext {
+        let mut ctx = EndpointContext::new();
+        ctx.set_endpoint_type(ep_type.to());
+        ctx.set_max_packet_size(max_packet_size);
+        ctx.set_interval(interval);
+        ctx
+    }
+
+    #[test]
+    fn endpoint_parameters() {
+        assert_eq!(endpoint(EndpointType::Control, 64, 0).check_parameters(UsbSpeed::High), Ok(()));
+        assert_eq!(endpoint(EndpointType::BulkIn, 512, 0).check_parameters(UsbSpeed::High), Ok(()));
+        assert_eq!(endpoint(EndpointType::InterruptIn, 8, 10).check_parameters(UsbSpeed::Low),
+                   Ok(()));
+        assert_eq!(endpoint(EndpointType::IsochOut, 1024, 4).check_parameters(UsbSpeed::Super),
+                   Ok(()));
+
+        // Packets too big for the speed.
+        assert_eq!(endpoint(EndpointType::BulkOut, 1024, 0).check_parameters(UsbSpeed::High),
+                   Err(TrbCompletionCode::ParameterError));
+        assert_eq!(endpoint(EndpointType::InterruptIn, 64, 10).check_parameters(UsbSpeed::Low),
+                   Err(TrbCompletionCode::ParameterError));
+        assert_eq!(endpoint(EndpointType::BulkIn, 0, 0).check_parameters(UsbSpeed::Super),
+                   Err(TrbCompletionCode::ParameterError));
+        // Control endpoints aren't polled.
+        assert_eq!(endpoint(EndpointType::Control, 64, 3).check_parameters(UsbSpeed::High),
+                   Err(TrbCompletionCode::ParameterError));
+        // Low speed devices can't have bulk endpoints.
+        assert_eq!(endpoint(EndpointType::BulkIn, 8, 0).check_parameters(UsbSpeed::Low),
+                   Err(TrbCompletionCode::ParameterError));
+        assert_eq!(EndpointContext::new().check_parameters(UsbSpeed::Full),
+                   Err(TrbCompletionCode::ParameterError));
+    }
+}
@@ -6,7 +6,6 @@ extern crate bit_field;
 
 use self::bit_field::*;
 use std;
-use std::fmt;
 
 type B0 = BitField0;
 type B1 = BitField1;
@@ -75,10 +74,10 @@ type B63 = BitField63;
 type B64 = BitField64;
 
 // Fixed size for all TRB types.
-const TRB_SIZE: usize = 16;
+pub const TRB_SIZE: usize = 16;
 
 // Size for segment table.
-const SEGMENT_TABLE_SIZE: usize = 16;
+pub const SEGMENT_TABLE_SIZE: usize = 16;
 
 // Generic TRB struct containing only fields common to all types.
 // TODO(jkwang) add stringify.
@@ -378,7 +377,7 @@ pub struct InputControlContextSchema {
 }
 
 // Size for device context entries (SlotContext and EndpointContext).
-const DEVICE_CONTEXT_ENTRY_SIZE: usize = 32usize;
+pub const DEVICE_CONTEXT_ENTRY_SIZE: usize = 32usize;
 
 #[derive(BitField)]
 #[passthrough(derive(Clone, Copy))]
@@ -433,6 +432,7 @@ pub struct EndpointContextSchema {
     reserved7: B32,
 }
 
+#[derive(Clone, Copy)]
 pub struct DeviceContext {
     slot_context: SlotContext,
     endpoint_context: [EndpointContext; 31],
@@ -441,9 +441,10 @@ pub struct DeviceContext {
 // POD struct for associating a TRB with its address in guest memory.  This is
 // useful because transfer and command completion event TRBs must contain
 // pointers to the original TRB that generated the event.
+#[derive(Clone, Copy)]
 pub struct AddressedTrb {
-    trb: Trb,
-    gpa: u64,
+    pub trb: Trb,
+    pub gpa: u64,
 }
 
 type TransferDescriptor = Vec<AddressedTrb>;
@@ -0,0 +1,166 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Interface between the xhci controller and the usb devices attached to its root hub ports. A
+// backend could be a host device accessed through libusb or a device emulated entirely in crosvm.
+
+// Bit in the endpoint address that indicates the IN (device to host) direction.
+pub const ENDPOINT_DIRECTION_IN: u8 = 0x80;
+
+// Standard requests that can change which endpoints a device has. See usb spec table 9-4.
+const REQUEST_SET_DESCRIPTOR: u8 = 0x07;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_SET_INTERFACE: u8 = 0x0b;
+
+// Request type bits selecting the kind of request; 0 is a standard request.
+const REQUEST_TYPE_TYPE_MASK: u8 = 0x60;
+
+// The usb protocol a backend device speaks. Determines which root hub port it is attached to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendType {
+    Usb2,
+    Usb3,
+}
+
+// Setup packet of a control transfer. See usb spec 9.3.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UsbRequestSetup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl UsbRequestSetup {
+    // True if the data stage of this request moves data from device to host.
+    pub fn is_device_to_host(&self) -> bool {
+        self.request_type & ENDPOINT_DIRECTION_IN != 0
+    }
+
+    // True if the device's endpoints may be different once this request completes, so a backend
+    // passing requests through to a real device has to enumerate them again.
+    pub fn changes_endpoints(&self) -> bool {
+        if self.request_type & REQUEST_TYPE_TYPE_MASK != 0 {
+            return false;
+        }
+        match self.request {
+            REQUEST_SET_DESCRIPTOR | REQUEST_SET_CONFIGURATION | REQUEST_SET_INTERFACE => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendTransferType {
+    Control(UsbRequestSetup),
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferStatus {
+    Completed,
+    Stalled,
+    Cancelled,
+    Error,
+}
+
+pub type BackendTransferCallback = Box<FnMut(BackendTransfer) + Send>;
+
+// A transfer submitted by the xhci controller to a backend device. For IN transfers, `buffer` is
+// sized to the requested length and `actual_length` is set by the backend on completion. For OUT
+// transfers, `buffer` holds the data to be sent.
+pub struct BackendTransfer {
+    pub endpoint: u8,
+    pub transfer_type: BackendTransferType,
+    pub buffer: Vec<u8>,
+    pub actual_length: usize,
+    pub status: TransferStatus,
+    callback: Option<BackendTransferCallback>,
+}
+
+impl BackendTransfer {
+    pub fn new(endpoint: u8, transfer_type: BackendTransferType, buffer: Vec<u8>)
+               -> BackendTransfer {
+        BackendTransfer {
+            endpoint: endpoint,
+            transfer_type: transfer_type,
+            buffer: buffer,
+            actual_length: 0,
+            status: TransferStatus::Completed,
+            callback: None,
+        }
+    }
+
+    // Sets the callback that will be invoked exactly once when the transfer completes.
+    pub fn set_callback(&mut self, callback: BackendTransferCallback) {
+        self.callback = Some(callback);
+    }
+
+    pub fn is_in(&self) -> bool {
+        match self.transfer_type {
+            BackendTransferType::Control(setup) => setup.is_device_to_host(),
+            _ => self.endpoint & ENDPOINT_DIRECTION_IN != 0,
+        }
+    }
+
+    // Completes this transfer with `status`, reporting `actual_length` bytes transferred.
+    pub fn complete(mut self, status: TransferStatus, actual_length: usize) {
+        self.status = status;
+        self.actual_length = actual_length;
+        if let Some(mut callback) = self.callback.take() {
+            callback(self);
+        }
+    }
+}
+
+pub trait XhciBackendDevice: Send {
+    // Returns the protocol this device speaks.
+    fn get_backend_type(&self) -> BackendType;
+
+    // Returns the vendor id of this device.
+    fn get_vid(&self) -> u16;
+
+    // Returns the product id of this device.
+    fn get_pid(&self) -> u16;
+
+    // Submits a transfer to this device. The backend owns the transfer until it calls
+    // `BackendTransfer::complete`, which may happen before this function returns.
+    fn submit_transfer(&mut self, transfer: BackendTransfer);
+
+    // Assigns the usb address to this device.
+    fn set_address(&mut self, address: u32);
+
+    // Resets the device to its default state. Pending transfers are cancelled.
+    fn reset(&mut self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(request_type: u8, request: u8) -> UsbRequestSetup {
+        UsbRequestSetup {
+            request_type: request_type,
+            request: request,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requests_changing_endpoints() {
+        // SET_INTERFACE to an interface.
+        assert!(setup(0x01, REQUEST_SET_INTERFACE).changes_endpoints());
+        assert!(setup(0x00, REQUEST_SET_CONFIGURATION).changes_endpoints());
+        assert!(setup(0x00, REQUEST_SET_DESCRIPTOR).changes_endpoints());
+        // GET_DESCRIPTOR and SYNCH_FRAME.
+        assert!(!setup(0x80, 0x06).changes_endpoints());
+        assert!(!setup(0x82, 0x0c).changes_endpoints());
+        // The hid SET_PROTOCOL and SET_REPORT class requests share their codes with
+        // SET_INTERFACE and SET_CONFIGURATION.
+        assert!(!setup(0x21, REQUEST_SET_INTERFACE).changes_endpoints());
+        assert!(!setup(0x21, REQUEST_SET_CONFIGURATION).changes_endpoints());
+    }
+}
@@ -2,6 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fmt::{self, Display};
+
+use super::mmio_register::*;
+
 const XHCI_CAPLENGTH: u64 = 0x20;
 const XHCI_DBOFF: u64 = 0x00002000;
 const XHCI_RTSOFF: u64 = 0x00003000;
@@ -70,115 +74,237 @@ const HCSPARAMS1_MAX_SLOTS_MASK: u32 = 0xFF;
 const SPCAP_PORT_COUNT_MASK: u32 = 0xFF00;
 const SPCAP_PORT_COUNT_OFFSET: u32 = 8;
 
-struct XHCIRegs {
-    usbcmd: &'static Register,
-    usbsts: &'static Register,
-    dnctrl: &'static Register,
-    crcr: &'static Register,
-    dcbaap: &'static Register,
-    config: &'static Register,
-    portsc: Vec<&'static Register>,
-    doorbells: Vec<&'static Register>,
-    iman: Vec<&'static Register>,
-    imod: Vec<&'static Register>,
-    erstsz: Vec<&'static Register>,
-    erstba: Vec<&'static Register>,
-    erdp: Vec<&'static Register>,
+/// Number of USB 2.0 root hub ports a controller has unless told otherwise.
+pub const DEFAULT_USB2_PORTS: u8 = 4;
+/// Number of USB 3.0 root hub ports a controller has unless told otherwise.
+pub const DEFAULT_USB3_PORTS: u8 = 4;
+/// HCSPARAMS1 has an 8 bit field for the number of ports.
+pub const MAX_PORTS: u32 = 0xff;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NoUsb2Ports,
+    NoUsb3Ports,
+    TooManyPorts(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::NoUsb2Ports => write!(f, "xhci needs at least one USB 2.0 port"),
+            &Error::NoUsb3Ports => write!(f, "xhci needs at least one USB 3.0 port"),
+            &Error::TooManyPorts(n) => {
+                write!(f, "xhci can't have {} ports, the limit is {}", n, MAX_PORTS)
+            }
+        }
+    }
+}
+
+/// The root hub ports of an xhci controller. The USB 2.0 ports come first, followed by the USB 3.0
+/// ports, each group described by its own "Supported Protocol" capability.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct XhciPorts {
+    usb2: u8,
+    usb3: u8,
+}
+
+impl XhciPorts {
+    /// Checks that there is at least one port of each kind and that the total fits in HCSPARAMS1.
+    pub fn new(usb2: u8, usb3: u8) -> Result<XhciPorts, Error> {
+        if usb2 == 0 {
+            return Err(Error::NoUsb2Ports);
+        }
+        if usb3 == 0 {
+            return Err(Error::NoUsb3Ports);
+        }
+        let total = usb2 as u32 + usb3 as u32;
+        if total > MAX_PORTS {
+            return Err(Error::TooManyPorts(total));
+        }
+        Ok(XhciPorts { usb2: usb2, usb3: usb3 })
+    }
+
+    pub fn num_ports(&self) -> u8 {
+        self.usb2 + self.usb3
+    }
+
+    /// One device slot per port, so every port can have a device addressed at the same time.
+    pub fn max_slots(&self) -> u8 {
+        self.num_ports()
+    }
+
+    // max_slots = num ports, max_interrupters = 1, max_ports = num ports.
+    fn hcsparams1(&self) -> u32 {
+        (self.num_ports() as u32) << 24 | 1 << HCSPARAMS1_MAX_INTERRUPTERS_OFFSET |
+        self.max_slots() as u32
+    }
+
+    // Third dword of the USB 2.0 "Supported Protocol" capability: port count and first port.
+    fn usb2_port_range(&self) -> u32 {
+        (self.usb2 as u32) << SPCAP_PORT_COUNT_OFFSET | 1
+    }
+
+    // Third dword of the USB 3.0 "Supported Protocol" capability. Its ports follow the USB 2.0
+    // ones.
+    fn usb3_port_range(&self) -> u32 {
+        (self.usb3 as u32) << SPCAP_PORT_COUNT_OFFSET | (self.usb2 as u32 + 1)
+    }
+}
+
+impl Default for XhciPorts {
+    fn default() -> XhciPorts {
+        XhciPorts {
+            usb2: DEFAULT_USB2_PORTS,
+            usb3: DEFAULT_USB3_PORTS,
+        }
+    }
+}
+
+/// A guest write to one of the doorbell registers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Doorbell {
+    /// Doorbell 0, rung when there are new commands on the command ring.
+    Command,
+    /// The doorbell of device slot `slot_id`, rung for the transfer ring of endpoint `target`
+    /// (see `DeviceSlot::ring_doorbell`) and stream `stream_id`.
+    Slot {
+        slot_id: u8,
+        target: u8,
+        stream_id: u16,
+    },
+}
+
+pub struct XHCIRegs {
+    pub usbcmd: Register<u32>,
+    pub usbsts: Register<u32>,
+    pub dnctrl: Register<u32>,
+    pub crcr: Register<u64>,
+    pub dcbaap: Register<u64>,
+    pub config: Register<u64>,
+    pub portsc: Vec<Register<u32>>,
+    pub doorbells: Vec<Register<u32>>,
+    pub iman: Vec<Register<u32>>,
+    pub imod: Vec<Register<u32>>,
+    pub erstsz: Vec<Register<u32>>,
+    pub erstba: Vec<Register<u64>>,
+    pub erdp: Vec<Register<u64>>,
+}
+
+impl XHCIRegs {
+    /// Decodes `value` written to the doorbell register at `index` in `doorbells`. Doorbell
+    /// `index` belongs to device slot `index`, except for doorbell 0. An index past the last slot
+    /// is logged and ignored.
+    pub fn doorbell(&self, index: usize, value: u32) -> Option<Doorbell> {
+        if index >= self.doorbells.len() {
+            error!("xhci: doorbell {} rung but there are only {} device slots",
+                   index,
+                   self.doorbells.len() - 1);
+            return None;
+        }
+        if index == 0 {
+            return Some(Doorbell::Command);
+        }
+        Some(Doorbell::Slot {
+                 slot_id: index as u8,
+                 target: (value & DOORBELL_TARGET) as u8,
+                 stream_id: (value >> DOORBELL_STREAM_ID_OFFSET) as u16,
+             })
+    }
 }
 
 // This function returns mmio space definition for xhci. See Xhci spec chapter 5
 // for details.
-pub fn get_xhci_mmio_space_and_regs() -> (MMIOSpace, XHCIRegs) {
-    let mut mmio = MMIO::new();
+pub fn get_xhci_mmio_space_and_regs(ports: XhciPorts) -> (MMIOSpace, XHCIRegs) {
+    let mut mmio = MMIOSpace::new();
     /**************************************************************************/
 
     /***************** Host Controller Capability Registers *******************/
-    mmio.add_reg(
+    mmio.add_register(
         // CAPLENGTH
-        register!(
+        static_register!(
+            ty: u8,
             offset: 0x00,
-            size: 1,
-            reset_value: XHCI_CAPLENGTH, // Operation register start at offset 0x20
+            value: XHCI_CAPLENGTH as u8, // Operation register start at offset 0x20
             ),
     );
-    mmio.add_reg(
+    mmio.add_register(
         // HCIVERSION
-        register!(
+        static_register!(
+            ty: u16,
             offset: 0x02,
-            size: 2,
-            reset_value: 0x0110,// Revision 1.1
+            value: 0x0110,// Revision 1.1
             ),
     );
-    mmio.add_reg(
+    mmio.add_register(
         // HCSPARAMS1
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x04,
-            size: 4,
-            reset_value: 0x08000108, // max_slots = 8, max_interrupters = 1, max_ports = 8
+            value: ports.hcsparams1(),
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // HCSPARAMS2
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x08,
-            size: 4,
             // Maximum number of event ring segment table entries = 32k
             // No scratchpad buffers.
-            reset_value: 0xf0,
+            value: 0xf0,
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // HCSPARAM3
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x0c,
-            size: 4,
 
             // Exit latencies for U1 (standby with fast exit) and U2 (standby with
             // slower exit) power states. We use the max values:
             // - U1 to U0: < 10 us
             // - U2 to U1: < 2047 us
-            reset_value: 0x07FF000A,
+            value: 0x07FF000A,
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // HCCPARAMS1
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x10,
-            size: 4,
 
             // Supports 64 bit addressing
             // Max primary stream array size = 0 (streams not supported).
             // Extended capabilities pointer = 0xC000 offset from base.
-            reset_value: 0x30000501
+            value: 0x30000501,
             ),
     );
-    mmio.add_reg(
+    mmio.add_register(
         // DBOFF
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x14,
-            size: 4,
-            reset_value: XHCI_DBOFF, // Doorbell array offset 0x2000 from base.
+            value: XHCI_DBOFF as u32, // Doorbell array offset 0x2000 from base.
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // RTSOFF
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x18,
-            size: 4,
-            reset_value: XHCI_RTSOFF, // Runtime registers offset 0x3000 from base.
+            value: XHCI_RTSOFF as u32, // Runtime registers offset 0x3000 from base.
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // HCCPARAMS2
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x1c,
-            size: 4,
-            reset_value: 0,
+            value: 0,
             ),
     );
     /************** End of Host Controller Capability Registers ***************/
@@ -187,164 +313,184 @@ pub fn get_xhci_mmio_space_and_regs() -> (MMIOSpace, XHCIRegs) {
     /**************************************************************************/
     /***************** Host Controller Operational Registers ******************/
     let usbcmd = register!(
+            ty: u32,
             offset: 0x20,
-            size: 4,
             reset_value: 0,
             guest_writeable_mask: 0x00002F0F,
             guest_write_1_to_clear_mask: 0,
         );
+    mmio.add_register(usbcmd.clone());
 
     let usbsts = register!(
+            ty: u32,
             offset: 0x24,
-            size: 4,
             reset_value: 0x00000001,
             guest_writeable_mask: 0x0000041C,
             guest_write_1_to_clear_mask: 0x0000041C,
         );
+    mmio.add_register(usbsts.clone());
 
-    mmio.add_reg(
+    mmio.add_register(
         //  Pagesize
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x28,
-            size: 4,
-            reset_value: 0x00000001,
+            value: 0x00000001,
             ),
     );
 
     let dnctrl = register!(
+            ty: u32,
             offset: 0x34,
-            size: 4,
             reset_value: 0,
             guest_writeable_mask: 0x0000FFFF,
             guest_write_1_to_clear_mask: 0,
         );
+    mmio.add_register(dnctrl.clone());
 
     let crcr = register!(
+            ty: u64,
             offset: 0x38,
-            size: 8,
             reset_value: 9,
             guest_writeable_mask: 0xFFFFFFFFFFFFFFC7,
             guest_write_1_to_clear_mask: 0,
         );
+    mmio.add_register(crcr.clone());
 
     let dcbaap = register!(
+            ty: u64,
             offset: 0x50,
-            size: 8,
             reset_value: 0x0,
             guest_writeable_mask: 0xFFFFFFFFFFFFFFC0,
             guest_write_1_to_clear_mask: 0,
         );
+    mmio.add_register(dcbaap.clone());
 
     let config = register!(
+            ty: u64,
             offset: 0x58,
-            size: 8,
             reset_value: 0,
             guest_writeable_mask: 0x0000003F,
             guest_write_1_to_clear_mask: 0,
         );
+    mmio.add_register(config.clone());
 
-    let portsc = register_array!(cnt: 8, //  Must be equal to max_ports
+    let portsc = register_array!(ty: u32,
+                                 cnt: ports.num_ports(),
                                  base_offset: 0x420,
                                  stride: 16,
-                                 size: 4,
                                  reset_value: 0x000002A0,
                                  guest_writeable_mask: 0x8EFFC3F2,
                                  guest_write_1_to_clear_mask: 0x00FE0002,);
+    for r in &portsc {
+        mmio.add_register(r.clone());
+    }
 
     // Portpmsc.
-    mmio.add_reg_array(register_array!(cnt: 8,
-                                       base_offset: 0x424,
-                                       stride: 16,
-                                       size: 4,
-                                       reset_value: 0,
-                                       guest_writeable_mask: 0x0001FFFF,
-                                       guest_write_1_to_clear_mask: 0,));
+    for r in register_array!(ty: u32,
+                             cnt: ports.num_ports(),
+                             base_offset: 0x424,
+                             stride: 16,
+                             reset_value: 0,
+                             guest_writeable_mask: 0x0001FFFF,
+                             guest_write_1_to_clear_mask: 0,) {
+        mmio.add_register(r);
+    }
 
     // Portli
-    mmio.add_reg_array(register_array!(cnt: 8,
-                                       base_offset: 0x428,
-                                       stride: 16,
-                                       size: 4,
-                                       reset_value: 0,
-                                       guest_writeable_mask: 0,
-                                       guest_write_1_to_clear_mask: 0,));
+    for r in register_array!(ty: u32,
+                             cnt: ports.num_ports(),
+                             base_offset: 0x428,
+                             stride: 16,
+                             reset_value: 0,
+                             guest_writeable_mask: 0,
+                             guest_write_1_to_clear_mask: 0,) {
+        mmio.add_register(r);
+    }
 
     // Porthlpmc
-    mmio.add_reg_array(register_array!(cnt 8,
-                                       base_offset: 0x42c,
-                                       stride: 16,
-                                       size: 4,
-                                       reset_value: 0
-                                       guest_writeable_mask: 0x00003FFF,
-                                       guest_write_1_to_clear_mask: 0,));
-
-    let doorbells = register_array!(cnt: 9, //  Must be equal to max_ports
-                                    base_offset: 0x2000,
-                                    size: 4,
+    for r in register_array!(ty: u32,
+                             cnt: ports.num_ports(),
+                             base_offset: 0x42c,
+                             stride: 16,
+                             reset_value: 0,
+                             guest_writeable_mask: 0x00003FFF,
+                             guest_write_1_to_clear_mask: 0,) {
+        mmio.add_register(r);
+    }
+
+    // Doorbell 0 is for the host controller, the rest are one per device slot.
+    let doorbells = register_array!(ty: u32,
+                                    cnt: ports.max_slots() as usize + 1,
+                                    base_offset: XHCI_DBOFF,
+                                    stride: 4,
                                     reset_value: 0,
                                     guest_writeable_mask: 0xFFFF00FF,
                                     guest_write_1_to_clear_mask: 0,);
+    for r in &doorbells {
+        mmio.add_register(r.clone());
+    }
 
     /**************************************************************************/
     /***************************** Runtime Registers **************************/
 
-    mmio.add_reg(
+    mmio.add_register(
         // mfindex
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0x3000,
-            size: 4,
-            reset_value: 0, // 4 ports starting at port 5
+            value: 0,
             ),
     );
 
 
     /*************************** Reg Array for interrupters *******************/
-    let iman = register_array!(cnt: 1, //  Must be equal to max_ports
+    let iman = register_array!(ty: u32,
+                               cnt: 1, //  Must be equal to max_interrupters
                                base_offset: 0x3020,
                                stride: 32,
-                               size: 4,
                                reset_value: 0,
                                guest_writeable_mask: 0x00000003,
                                guest_write_1_to_clear_mask: 0x00000001,);
 
-    let imod = register_array!(cnt: 1, //  Must be equal to max_ports
+    let imod = register_array!(ty: u32,
+                               cnt: 1, //  Must be equal to max_interrupters
                                base_offset: 0x3024,
                                stride: 32,
-                               size: 4,
                                reset_value: 0x00000FA0,
                                guest_writeable_mask: 0xFFFFFFFF,
                                guest_write_1_to_clear_mask: 0,);
 
-
-
-    let erstsz = register_array!(cnt: 1, //  Must be equal to max_ports
+    let erstsz = register_array!(ty: u32,
+                                 cnt: 1, //  Must be equal to max_interrupters
                                  base_offset: 0x3028,
                                  stride: 32,
-                                 size: 4,
                                  reset_value: 0,
                                  guest_writeable_mask: 0x0000FFFF,
                                  guest_write_1_to_clear_mask: 0,);
 
-
-
-    let erstba = register_array!(cnt: 1, //  Must be equal to max_ports
+    let erstba = register_array!(ty: u64,
+                                 cnt: 1, //  Must be equal to max_interrupters
                                  base_offset: 0x3030,
                                  stride: 32,
-                                 size: 8,
                                  reset_value: 0,
                                  guest_writeable_mask: 0xFFFFFFFFFFFFFFC0,
                                  guest_write_1_to_clear_mask: 0,);
 
-
-
-    let erdp = register_array!(cnt: 1, //  Must be equal to max_ports
+    let erdp = register_array!(ty: u64,
+                               cnt: 1, //  Must be equal to max_interrupters
                                base_offset: 0x3038,
                                stride: 32,
-                               size: 8,
                                reset_value: 0,
                                guest_writeable_mask: 0xFFFFFFFFFFFFFFFF,
-                               guest_write_1_to_clear_mask: 0x0000000000000008);
+                               guest_write_1_to_clear_mask: 0x0000000000000008,);
 
+    for r in iman.iter().chain(&imod).chain(&erstsz) {
+        mmio.add_register(r.clone());
+    }
+    for r in erstba.iter().chain(&erdp) {
+        mmio.add_register(r.clone());
+    }
 
     /************************* End of Runtime Registers ***********************/
     /**************************************************************************/
@@ -375,85 +521,85 @@ pub fn get_xhci_mmio_space_and_regs() -> (MMIOSpace, XHCIRegs) {
 
     // Extended capability registers. Base offset defined by hccparams1.
     // Each set of 4 registers represents a "Supported Protocol" extended
-    // capability.  The first capability indicates that ports 1-4 are USB 2.0 and
-    // the second capability indicates that ports 5-8 are USB 3.0.
-    mmio.add_reg(
+    // capability.  The first capability lists the USB 2.0 ports and the second
+    // capability lists the USB 3.0 ports that follow them.
+    mmio.add_register(
         // spcap 1.1
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc000,
-            size: 4,
             // "Supported Protocol" capability.
             // Next capability at 0x40 dwords offset.
             // USB 2.0.
-            reset_value: 0x20,
+            value: 0x20,
             ),
     );
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 1.2
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc004,
-            size: 4,
-            reset_value: 0x20425355, // Name string = "USB "
+            value: 0x20425355, // Name string = "USB "
             ),
     );
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 1.3
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc008,
-            size: 4,
-            reset_value: 0x00000401, // 4 ports starting at port 1.
+            value: ports.usb2_port_range(),
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 1.4
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc00c,
-            size: 4,
             // The specification says that this shall be set to 0 with no explanation.
             // Section 7.2.2.1.4.
-            reset_value: 0,
+            value: 0,
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 2.1
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc100,
-            size: 4,
             // "Supported Protocol" capability.
             // No pointer to next capability.
             // USB 3.0.
-            reset_value: 0x03000002,
+            value: 0x03000002,
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 2.2
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc104,
-            size: 4,
-            reset_value: 0x20425355, // Name string = "USB "
+            value: 0x20425355, // Name string = "USB "
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 2.3
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc108,
-            size: 4,
-            reset_value: 0x00000405, // 4 ports starting at port 5
+            value: ports.usb3_port_range(),
             ),
     );
 
-    mmio.add_reg(
+    mmio.add_register(
         // spcap 2.4
-        register!(
+        static_register!(
+            ty: u32,
             offset: 0xc10c,
-            size: 4,
             // The specification says that this shall be set to 0 with no explanation.
             // Section 7.2.2.1.4.
-            reset_value: 0,
+            value: 0,
             ),
     );
     /************** End of Host Controller Operational Registers **************/
@@ -461,3 +607,63 @@ pub fn get_xhci_mmio_space_and_regs() -> (MMIOSpace, XHCIRegs) {
 
     (mmio, xhci_regs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn read_u32(mmio: &MMIOSpace, addr: u64) -> u32 {
+        let mut data = [0u8; 4];
+        mmio.read_bar(addr, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    #[test]
+    fn default_ports() {
+        let (mmio, regs) = get_xhci_mmio_space_and_regs(XhciPorts::default());
+        assert_eq!(read_u32(&mmio, 0x04), 0x08000108);
+        assert_eq!(read_u32(&mmio, 0xc008), 0x00000401);
+        assert_eq!(read_u32(&mmio, 0xc108), 0x00000405);
+        assert_eq!(regs.portsc.len(), 8);
+        assert_eq!(regs.doorbells.len(), 9);
+    }
+
+    #[test]
+    fn sixteen_ports() {
+        let ports = XhciPorts::new(8, 8).unwrap();
+        assert_eq!(ports.max_slots(), 16);
+        let (mmio, regs) = get_xhci_mmio_space_and_regs(ports);
+        assert_eq!(read_u32(&mmio, 0x04), 0x10000110);
+        // 8 USB 2.0 ports starting at port 1, then 8 USB 3.0 ports starting at port 9.
+        assert_eq!(read_u32(&mmio, 0xc008), 0x00000801);
+        assert_eq!(read_u32(&mmio, 0xc108), 0x00000809);
+        assert_eq!(regs.portsc.len(), 16);
+        assert_eq!(regs.doorbells.len(), 17);
+        // The last port's status register is live.
+        assert_eq!(read_u32(&mmio, 0x420 + 15 * 16), 0x000002A0);
+    }
+
+    #[test]
+    fn doorbell_slots() {
+        let (_, regs) = get_xhci_mmio_space_and_regs(XhciPorts::default());
+        assert_eq!(regs.doorbell(0, 0), Some(Doorbell::Command));
+        assert_eq!(regs.doorbell(8, 0x00050003),
+                   Some(Doorbell::Slot {
+                            slot_id: 8,
+                            target: 3,
+                            stream_id: 5,
+                        }));
+        // There are only 8 device slots.
+        assert_eq!(regs.doorbell(9, 0x00050003), None);
+        assert_eq!(regs.doorbell(256, 2), None);
+    }
+
+    #[test]
+    fn port_split() {
+        assert_eq!(XhciPorts::new(0, 4), Err(Error::NoUsb2Ports));
+        assert_eq!(XhciPorts::new(4, 0), Err(Error::NoUsb3Ports));
+        assert_eq!(XhciPorts::new(200, 100), Err(Error::TooManyPorts(300)));
+        assert!(XhciPorts::new(127, 128).is_ok());
+    }
+}
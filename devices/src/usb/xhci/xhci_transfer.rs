@@ -2,6 +2,57 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fmt::{self, Display};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // The transfer descriptor has no trbs.
+    EmptyTransferDescriptor,
+    // The first trb of the transfer descriptor has this type, which doesn't start a transfer.
+    UnexpectedTrbType(u8),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::EmptyTransferDescriptor => write!(f, "empty transfer descriptor"),
+            &Error::UnexpectedTrbType(t) => {
+                write!(f, "trb type {} can't start a transfer descriptor", t)
+            }
+        }
+    }
+}
+
+// The kind of transfer a transfer descriptor holds, going by its first trb.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XhciTransferType {
+    Normal,
+    SetupStage,
+    DataStage,
+    StatusStage,
+    Isoch,
+    Noop,
+}
+
+impl XhciTransferType {
+    // The trbs come from the guest, so any type can show up here. One that doesn't belong on a
+    // transfer ring is an error to complete the transfer with, not a reason to stop the device.
+    pub fn new(td: &[AddressedTrb]) -> std::result::Result<XhciTransferType, Error> {
+        let first = match td.first() {
+            Some(t) => t.trb,
+            None => return Err(Error::EmptyTransferDescriptor),
+        };
+        match first.trb_type() {
+            Ok(TrbType::Normal) => Ok(XhciTransferType::Normal),
+            Ok(TrbType::SetupStage) => Ok(XhciTransferType::SetupStage),
+            Ok(TrbType::DataStage) => Ok(XhciTransferType::DataStage),
+            Ok(TrbType::StatusStage) => Ok(XhciTransferType::StatusStage),
+            Ok(TrbType::Isoch) => Ok(XhciTransferType::Isoch),
+            Ok(TrbType::Noop) => Ok(XhciTransferType::Noop),
+            _ => Err(Error::UnexpectedTrbType(first.get_trb_type())),
+        }
+    }
+}
 
 type TransferTrb = AddressedTrb;
 impl AddressedTrb {
@@ -55,3 +106,26 @@ impl XhciTransfer {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn td(trb_type: u8) -> Vec<AddressedTrb> {
+        let mut trb = Trb::new();
+        trb.set_trb_type(trb_type);
+        vec![AddressedTrb { trb: trb, gpa: 0x1000 }]
+    }
+
+    #[test]
+    fn transfer_type() {
+        assert_eq!(XhciTransferType::new(&td(TrbType::Normal.to())),
+                   Ok(XhciTransferType::Normal));
+        assert_eq!(XhciTransferType::new(&td(TrbType::SetupStage.to())),
+                   Ok(XhciTransferType::SetupStage));
+        assert_eq!(XhciTransferType::new(&td(TrbType::EnableSlotCommand.to())),
+                   Err(Error::UnexpectedTrbType(9)));
+        // Not a trb type at all.
+        assert_eq!(XhciTransferType::new(&td(63)), Err(Error::UnexpectedTrbType(63)));
+        assert_eq!(XhciTransferType::new(&[]), Err(Error::EmptyTransferDescriptor));
+    }
+}
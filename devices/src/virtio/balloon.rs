@@ -19,6 +19,8 @@ use super::{VirtioDevice, Queue, DescriptorChain, INTERRUPT_STATUS_CONFIG_CHANGE
 
 #[derive(Debug)]
 pub enum BalloonError {
+    /// The size of the pages backing guest memory isn't a power of two of at least 4K.
+    InvalidBackingPageSize(u64),
     /// Request to adjust memory size can't provide the number of pages requested.
     NotEnoughPages,
     /// Failure wriitng the config notification event.
@@ -32,6 +34,11 @@ const QUEUE_SIZE: u16 = 128;
 const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE, QUEUE_SIZE];
 
 const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+// The most page numbers the Linux driver puts in one inflate descriptor. Longer descriptors are
+// ignored rather than trusted to size the allocations below.
+const MAX_PFNS_PER_DESC: u32 = 256;
+// Page numbers are released to the host in batches of at most this many.
+const PFN_BATCH_SIZE: usize = 4096;
 
 // The feature bitmap for virtio balloon
 const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0x01; // Tell before reclaiming pages
@@ -52,10 +59,63 @@ struct Worker {
     interrupt_evt: EventFd,
     config: Arc<BalloonConfig>,
     command_socket: UnixDatagram,
+    backing_page_size: u64,
 }
 
 fn valid_inflate_desc(desc: &DescriptorChain) -> bool {
-    !desc.is_write_only() && desc.len % 4 == 0
+    !desc.is_write_only() && desc.len % 4 == 0 && desc.len / 4 <= MAX_PFNS_PER_DESC
+}
+
+// Tells the host it may reclaim the backing pages of the balloon pages in `pfns` and empties it.
+fn release_pfns(mem: &GuestMemory, backing_page_size: u64, pfns: &mut Vec<u64>) {
+    if pfns.is_empty() {
+        return;
+    }
+    let (ranges, skipped) = backing_page_ranges(pfns, backing_page_size);
+    if skipped > 0 {
+        warn!("balloon: skipped {} pages that only partly cover a {} byte backing page",
+              skipped, backing_page_size);
+    }
+    for (guest_address, len) in ranges {
+        if mem.dont_need_range(guest_address, len).is_err() {
+            warn!("Marking pages unused failed {:?}", guest_address);
+        }
+    }
+    pfns.clear();
+}
+
+// Merges the balloon pages in `pfns` into contiguous runs and trims each run to whole pages of
+// `page_size` bytes, the only granularity at which the host can release memory. Returns the
+// address and length of each trimmed run along with the number of balloon pages that were left
+// out because they only partly cover a backing page.
+fn backing_page_ranges(pfns: &mut Vec<u64>, page_size: u64) -> (Vec<(GuestAddress, u64)>, u64) {
+    pfns.sort();
+    pfns.dedup();
+
+    let mut ranges = Vec::new();
+    let mut skipped = 0;
+    let mut i = 0;
+    while i < pfns.len() {
+        let first = pfns[i];
+        let mut next = first + 1;
+        i += 1;
+        while i < pfns.len() && pfns[i] == next {
+            next += 1;
+            i += 1;
+        }
+
+        let run_start = first << VIRTIO_BALLOON_PFN_SHIFT;
+        let run_end = next << VIRTIO_BALLOON_PFN_SHIFT;
+        let start = (run_start + page_size - 1) & !(page_size - 1);
+        let end = run_end & !(page_size - 1);
+        if end > start {
+            ranges.push((GuestAddress(start), end - start));
+            skipped += (start - run_start + run_end - end) >> VIRTIO_BALLOON_PFN_SHIFT;
+        } else {
+            skipped += next - first;
+        }
+    }
+    (ranges, skipped)
 }
 
 impl Worker {
@@ -68,11 +128,15 @@ impl Worker {
 
         let mut used_desc_heads = [0; QUEUE_SIZE as usize];
         let mut used_count = 0;
+        let mut pfns = Vec::with_capacity(PFN_BATCH_SIZE);
         for avail_desc in queue.iter(&self.mem) {
             if inflate {
                 if valid_inflate_desc(&avail_desc) {
                     let num_addrs = avail_desc.len / 4;
-                    'addr_loop: for i in 0..num_addrs as usize {
+                    if pfns.len() + num_addrs as usize > PFN_BATCH_SIZE {
+                        release_pfns(&self.mem, self.backing_page_size, &mut pfns);
+                    }
+                    for i in 0..num_addrs as usize {
                         let addr = match avail_desc.addr.checked_add((i * 4) as u64) {
                             Some(a) => a,
                             None => break,
@@ -81,16 +145,7 @@ impl Worker {
                             Ok(a) => a,
                             Err(_) => continue,
                         };
-                        let guest_address =
-                            GuestAddress((guest_input as u64) << VIRTIO_BALLOON_PFN_SHIFT);
-
-                        if self.mem
-                            .dont_need_range(guest_address, 1 << VIRTIO_BALLOON_PFN_SHIFT)
-                            .is_err()
-                        {
-                            warn!("Marking pages unused failed {:?}", guest_address);
-                            continue;
-                        }
+                        pfns.push(guest_input as u64);
                     }
                 }
             }
@@ -99,6 +154,8 @@ impl Worker {
             used_count += 1;
         }
 
+        release_pfns(&self.mem, self.backing_page_size, &mut pfns);
+
         for &desc_index in &used_desc_heads[..used_count] {
             queue.add_used(&self.mem, desc_index, 0);
         }
@@ -215,11 +272,19 @@ pub struct Balloon {
     config: Arc<BalloonConfig>,
     features: u32,
     kill_evt: Option<EventFd>,
+    backing_page_size: u64,
 }
 
 impl Balloon {
     /// Create a new virtio balloon device.
-    pub fn new(command_socket: UnixDatagram) -> Result<Balloon> {
+    ///
+    /// `backing_page_size` is the size of the host pages backing guest memory. Inflated pages are
+    /// only released in whole backing pages, so it must be a power of two no smaller than 4K.
+    pub fn new(command_socket: UnixDatagram, backing_page_size: u64) -> Result<Balloon> {
+        if !backing_page_size.is_power_of_two() ||
+            backing_page_size < 1 << VIRTIO_BALLOON_PFN_SHIFT {
+            return Err(BalloonError::InvalidBackingPageSize(backing_page_size));
+        }
         Ok(Balloon {
             command_socket: Some(command_socket),
             config: Arc::new(BalloonConfig {
@@ -227,6 +292,7 @@ impl Balloon {
                 actual_pages: AtomicUsize::new(0),
             }),
             kill_evt: None,
+            backing_page_size: backing_page_size,
             // TODO(dgreid) - Add stats queue feature.
             features: VIRTIO_BALLOON_F_MUST_TELL_HOST | VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
         })
@@ -279,6 +345,9 @@ impl VirtioDevice for Balloon {
     fn write_config(&mut self, offset: u64, mut data: &[u8]) {
         // Only allow writing to `actual` pages from the guest.
         if offset != 4 || data.len() != 4 {
+            warn!("balloon: dropped write to read-only config: 0x{:x}:0x{:x}",
+                  offset,
+                  data.len());
             return;
         }
         // This read can't fail as it fits in the declared array so unwrap is fine.
@@ -310,6 +379,8 @@ impl VirtioDevice for Balloon {
         status: Arc<AtomicUsize>,
         mut queues: Vec<Queue>,
         queue_evts: Vec<EventFd>,
+        _queue_interrupt_evts: Option<Vec<EventFd>>,
+        _max_chains_per_pass: usize,
     ) {
         if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
             return;
@@ -327,6 +398,7 @@ impl VirtioDevice for Balloon {
 
         let config = self.config.clone();
         let command_socket = self.command_socket.take().unwrap();
+        let backing_page_size = self.backing_page_size;
         let worker_result = thread::Builder::new()
             .name("virtio_balloon".to_string())
             .spawn(move || {
@@ -338,6 +410,7 @@ impl VirtioDevice for Balloon {
                     interrupt_evt: interrupt_evt,
                     command_socket: command_socket,
                     config: config,
+                    backing_page_size: backing_page_size,
                 };
                 worker.run(queue_evts, kill_evt);
             });
@@ -347,3 +420,45 @@ impl VirtioDevice for Balloon {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HUGE_PAGE_SIZE: u64 = 2 << 20;
+    const HUGE_PAGE_PFNS: u64 = HUGE_PAGE_SIZE >> VIRTIO_BALLOON_PFN_SHIFT;
+
+    #[test]
+    fn coalesce_huge_page() {
+        // The guest hands pages over in no particular order.
+        let mut pfns: Vec<u64> = (HUGE_PAGE_PFNS..2 * HUGE_PAGE_PFNS).rev().collect();
+        let (ranges, skipped) = backing_page_ranges(&mut pfns, HUGE_PAGE_SIZE);
+        assert_eq!(ranges, vec![(GuestAddress(HUGE_PAGE_SIZE), HUGE_PAGE_SIZE)]);
+        assert_eq!(skipped, 0);
+    }
+
+    #[test]
+    fn skip_partial_huge_pages() {
+        // One page short of a huge page on both ends of the second huge page.
+        let mut pfns: Vec<u64> = (1..3 * HUGE_PAGE_PFNS - 1).collect();
+        let (ranges, skipped) = backing_page_ranges(&mut pfns, HUGE_PAGE_SIZE);
+        assert_eq!(ranges, vec![(GuestAddress(HUGE_PAGE_SIZE), HUGE_PAGE_SIZE)]);
+        assert_eq!(skipped, 2 * (HUGE_PAGE_PFNS - 1));
+
+        let mut pfns: Vec<u64> = (0..HUGE_PAGE_PFNS - 1).collect();
+        let (ranges, skipped) = backing_page_ranges(&mut pfns, HUGE_PAGE_SIZE);
+        assert!(ranges.is_empty());
+        assert_eq!(skipped, HUGE_PAGE_PFNS - 1);
+    }
+
+    #[test]
+    fn coalesce_small_pages() {
+        let mut pfns = vec![7, 3, 4, 5, 5, 9];
+        let (ranges, skipped) = backing_page_ranges(&mut pfns, 4096);
+        assert_eq!(ranges,
+                   vec![(GuestAddress(0x3000), 0x3000),
+                        (GuestAddress(0x7000), 0x1000),
+                        (GuestAddress(0x9000), 0x1000)]);
+        assert_eq!(skipped, 0);
+    }
+}
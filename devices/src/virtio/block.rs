@@ -3,22 +3,39 @@
 // found in the LICENSE file.
 
 use std::cmp;
+use std::mem::size_of;
+use std::fs::File;
 use std::io::{self, Seek, SeekFrom, Read, Write};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use data_model::{DataInit, Le32, Le64};
+use libc::{self, EOPNOTSUPP, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+
+use qcow::QcowFile;
 use sys_util::Result as SysResult;
-use sys_util::{EventFd, GuestAddress, GuestMemory, GuestMemoryError, PollContext, PollToken};
+use sys_util::{EventFd, GuestAddress, GuestMemory, GuestMemoryError, MemfdSeals, PollContext,
+               PollToken, SharedMemory};
 
-use super::{VirtioDevice, Queue, DescriptorChain, INTERRUPT_STATUS_USED_RING, TYPE_BLOCK};
+use super::{VirtioDevice, Queue, DescriptorChain, DescriptorError, DeviceCounters, InlineChain,
+            Reader,
+            signal_used_queue, MAX_CHAINS_PER_PASS, TYPE_BLOCK};
+use super::worker_thread::{WorkerThread, WORKER_EXIT_TIMEOUT_MS};
 
 const QUEUE_SIZE: u16 = 256;
 const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
 const SECTOR_SHIFT: u8 = 9;
 const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
+// Largest disk access that merged requests are combined into.
+const MAX_MERGED_BYTES: u64 = 1 << 20;
+/// Largest request a block device accepts unless it is configured otherwise.
+pub const DEFAULT_MAX_TRANSFER: u32 = 1 << 20;
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
@@ -28,8 +45,287 @@ const VIRTIO_BLK_S_OK: u8 = 0;
 const VIRTIO_BLK_S_IOERR: u8 = 1;
 const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
-pub trait DiskFile: Read + Seek + Write {}
-impl<D: Read + Seek + Write> DiskFile for D {}
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
+const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
+
+// Offset of the writeback field in the config space.
+const CONFIG_WRITEBACK_OFFSET: usize = 32;
+
+/// How writes to a block device are cached on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Writes complete once they are in the host page cache. Guest flushes sync the disk.
+    WriteBack,
+    /// Every write is synced to the disk before it completes.
+    WriteThrough,
+    /// Like `WriteBack`, but the disk is opened with `O_DIRECT` to bypass the host page cache.
+    /// Guest buffers must be aligned to the logical block size of the disk.
+    None,
+}
+
+/// The backing store of a block device.
+pub trait DiskFile: Read + Seek + Write {
+    /// Returns the file descriptor the disk is accessed through, if there is one. It is kept open
+    /// when the device is jailed.
+    fn optional_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Returns a handle to the disk that many threads can use at once, if the disk supports that.
+    /// It is needed to run requests asynchronously.
+    fn positioned_disk(&self) -> Option<Arc<PositionedDisk>> {
+        None
+    }
+
+    /// Makes all completed writes durable.
+    fn fsync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Bypasses the host page cache for all further accesses.
+    fn set_direct_io(&mut self) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(EOPNOTSUPP))
+    }
+}
+
+// Sets `O_DIRECT` on `file`.
+fn set_direct_io(file: &File) -> io::Result<()> {
+    // Safe because the file descriptor is valid and the return values are checked.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_DIRECT) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl DiskFile for File {
+    fn optional_raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn positioned_disk(&self) -> Option<Arc<PositionedDisk>> {
+        match self.try_clone() {
+            Ok(file) => Some(Arc::new(file)),
+            Err(e) => {
+                warn!("failed to clone disk image: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn set_direct_io(&mut self) -> io::Result<()> {
+        set_direct_io(self)
+    }
+}
+
+/// A disk that can be read and written at any offset by several threads at the same time.
+pub trait PositionedDisk: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+    /// Makes all completed writes durable.
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl PositionedDisk for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        FileExt::write_at(self, buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+// Reads and writes a `PositionedDisk` sequentially from `offset`.
+struct DiskCursor<'a> {
+    disk: &'a PositionedDisk,
+    offset: u64,
+}
+
+impl<'a> Read for DiskCursor<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.disk.read_at(buf, self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a> Write for DiskCursor<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.disk.write_at(buf, self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn optional_raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// A raw disk image that stays sparse. Writes of nothing but zeros punch a hole in the file
+/// instead of allocating blocks for them. If the filesystem can't punch holes, all writes go to
+/// the file as usual.
+pub struct SparseFile {
+    file: File,
+    punch_hole: bool,
+}
+
+impl SparseFile {
+    pub fn new(file: File) -> SparseFile {
+        SparseFile {
+            file: file,
+            punch_hole: true,
+        }
+    }
+
+    // Tries to deallocate `len` bytes at the current position. Returns false if that isn't
+    // possible and the data needs to be written instead.
+    fn punch_hole(&mut self, len: u64) -> io::Result<bool> {
+        let offset = self.file.seek(SeekFrom::Current(0))?;
+        // A hole can't grow the file, which a write past the end would have done.
+        if offset + len > self.file.metadata()?.len() {
+            return Ok(false);
+        }
+        // Safe because the file descriptor is valid and the return value is checked.
+        let ret = unsafe {
+            libc::fallocate(self.file.as_raw_fd(),
+                            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                            offset as libc::off_t,
+                            len as libc::off_t)
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(EOPNOTSUPP) {
+                warn!("disk image doesn't support punching holes, writing zeros instead");
+                self.punch_hole = false;
+                return Ok(false);
+            }
+            return Err(e);
+        }
+        self.file.seek(SeekFrom::Current(len as i64))?;
+        Ok(true)
+    }
+}
+
+impl Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.punch_hole && !buf.is_empty() && buf.iter().all(|&b| b == 0) {
+            if self.punch_hole(buf.len() as u64)? {
+                return Ok(buf.len());
+            }
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for SparseFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AsRawFd for SparseFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl DiskFile for SparseFile {
+    fn optional_raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_direct_io(&mut self) -> io::Result<()> {
+        set_direct_io(&self.file)
+    }
+}
+
+/// A disk that only exists in memory, for tests and guests that don't need their disk to outlive
+/// them. The contents are kept in shared memory that starts out zeroed and is only allocated as
+/// the guest writes to it. The size is fixed, so writes past the end fail.
+pub struct MemDisk {
+    file: File,
+}
+
+impl MemDisk {
+    pub fn new(size: u64) -> SysResult<MemDisk> {
+        let mut shm = SharedMemory::new(None)?;
+        shm.set_size(size)?;
+        let mut seals = MemfdSeals::new();
+        seals.set_grow_seal();
+        seals.set_shrink_seal();
+        shm.add_seals(seals)?;
+        Ok(MemDisk { file: shm.into() })
+    }
+}
+
+impl Read for MemDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for MemDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AsRawFd for MemDisk {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl DiskFile for MemDisk {
+    fn optional_raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
 
 #[derive(PartialEq)]
 enum RequestType {
@@ -41,10 +337,8 @@ enum RequestType {
 
 #[derive(Debug)]
 enum ParseError {
-    /// Guest gave us bad memory addresses
-    GuestMemory(GuestMemoryError),
-    /// Guest gave us offsets that would have overflowed a usize.
-    CheckedOffset(GuestAddress, u64),
+    /// Guest gave us a request header we couldn't read.
+    Header(DescriptorError),
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -55,28 +349,24 @@ enum ParseError {
     DescriptorLengthTooSmall,
 }
 
-fn request_type(mem: &GuestMemory,
-                desc_addr: GuestAddress)
-                -> result::Result<RequestType, ParseError> {
-    let type_ = mem.read_obj_from_addr(desc_addr)
-        .map_err(ParseError::GuestMemory)?;
-    match type_ {
-        VIRTIO_BLK_T_IN => Ok(RequestType::In),
-        VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
-        VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
-        t => Ok(RequestType::Unsupported(t)),
-    }
+// The header at the start of every request, struct virtio_blk_outhdr in the spec.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RequestHeader {
+    request_type: Le32,
+    reserved: Le32,
+    sector: Le64,
 }
 
-fn sector(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<u64, ParseError> {
-    const SECTOR_OFFSET: u64 = 8;
-    let addr = match mem.checked_offset(desc_addr, SECTOR_OFFSET) {
-        Some(v) => v,
-        None => return Err(ParseError::CheckedOffset(desc_addr, SECTOR_OFFSET)),
-    };
+unsafe impl DataInit for RequestHeader {}
 
-    mem.read_obj_from_addr(addr)
-        .map_err(ParseError::GuestMemory)
+fn request_type(type_: u32) -> RequestType {
+    match type_ {
+        VIRTIO_BLK_T_IN => RequestType::In,
+        VIRTIO_BLK_T_OUT => RequestType::Out,
+        VIRTIO_BLK_T_FLUSH => RequestType::Flush,
+        t => RequestType::Unsupported(t),
+    }
 }
 
 #[derive(Debug)]
@@ -98,6 +388,15 @@ enum ExecuteError {
         sector: u64,
         guestmemerr: GuestMemoryError
     },
+    // Part of a merged access, which failed before it got to this request.
+    Transfer {
+        kind: io::ErrorKind,
+        sector: u64
+    },
+    TooLarge {
+        length: u32,
+        sector: u64
+    },
     Unsupported(u32),
 }
 
@@ -108,6 +407,8 @@ impl ExecuteError {
             &ExecuteError::Read{ .. } => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Seek{ .. } => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Write{ .. } => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::Transfer{ .. } => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::TooLarge{ .. } => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
@@ -130,38 +431,64 @@ impl Request {
             return Err(ParseError::UnexpectedWriteOnlyDescriptor);
         }
 
-        let req_type = request_type(&mem, avail_desc.addr)?;
-        let sector = sector(&mem, avail_desc.addr)?;
-        let data_desc = avail_desc
-            .next_descriptor()
+        // Most requests are a header, a single data buffer and the status, which can be read in
+        // one go.
+        if let Some(chain) = InlineChain::new(avail_desc) {
+            let (header_addr, header_len, _) = chain.get(0).unwrap();
+            if chain.len() == 3 && header_len as usize >= size_of::<RequestHeader>() {
+                let header: RequestHeader =
+                    mem.read_obj_from_addr(header_addr)
+                        .map_err(|e| ParseError::Header(DescriptorError::GuestMemory(e)))?;
+                return Request::new(&header, chain.get(1).unwrap(), chain.get(2).unwrap());
+            }
+        }
+
+        let mut reader = Reader::new(mem, avail_desc.clone());
+        let header: RequestHeader = reader.read_obj().map_err(ParseError::Header)?;
+        let data_desc = reader
+            .into_next_descriptor()
             .ok_or(ParseError::DescriptorChainTooShort)?;
         let status_desc = data_desc
             .next_descriptor()
             .ok_or(ParseError::DescriptorChainTooShort)?;
+        Request::new(&header,
+                     (data_desc.addr, data_desc.len, data_desc.is_write_only()),
+                     (status_desc.addr, status_desc.len, status_desc.is_write_only()))
+    }
 
-        if data_desc.is_write_only() && req_type == RequestType::Out {
+    // Checks the data and status buffers, given as address, length and if they are write only,
+    // against the request in `header`.
+    fn new(header: &RequestHeader,
+           data: (GuestAddress, u32, bool),
+           status: (GuestAddress, u32, bool))
+           -> result::Result<Request, ParseError> {
+        let req_type = request_type(header.request_type.to_native());
+        let (data_addr, data_len, data_write_only) = data;
+        let (status_addr, status_len, status_write_only) = status;
+
+        if data_write_only && req_type == RequestType::Out {
             return Err(ParseError::UnexpectedWriteOnlyDescriptor);
         }
 
-        if !data_desc.is_write_only() && req_type == RequestType::In {
+        if !data_write_only && req_type == RequestType::In {
             return Err(ParseError::UnexpectedReadOnlyDescriptor);
         }
 
         // The status MUST always be writable
-        if !status_desc.is_write_only() {
+        if !status_write_only {
             return Err(ParseError::UnexpectedReadOnlyDescriptor);
         }
 
-        if status_desc.len < 1 {
+        if status_len < 1 {
             return Err(ParseError::DescriptorLengthTooSmall);
         }
 
         Ok(Request {
                request_type: req_type,
-               sector: sector,
-               data_addr: data_desc.addr,
-               data_len: data_desc.len,
-               status_addr: status_desc.addr,
+               sector: header.sector.to_native(),
+               data_addr: data_addr,
+               data_len: data_len,
+               status_addr: status_addr,
            })
     }
 
@@ -187,19 +514,315 @@ impl Request {
                                                       sector: self.sector,
                                                       guestmemerr: e })?;
             }
-            RequestType::Flush => disk.flush().map_err(ExecuteError::Flush)?,
+            RequestType::Flush => disk.fsync().map_err(ExecuteError::Flush)?,
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        };
+        Ok(0)
+    }
+
+    // With a write-through cache, a write isn't complete until `sync` has made it durable.
+    fn write_through<F>(&self,
+                        result: result::Result<u32, ExecuteError>,
+                        writeback: &AtomicBool,
+                        sync: F)
+                        -> result::Result<u32, ExecuteError>
+        where F: FnOnce() -> io::Result<()>
+    {
+        if self.request_type == RequestType::Out && result.is_ok() &&
+           !writeback.load(Ordering::Relaxed) {
+            sync().map_err(ExecuteError::Flush)?;
+        }
+        result
+    }
+
+    // Whether `next` is the same kind of access as this request, to the sectors right after it.
+    fn continued_by(&self, next: &Request) -> bool {
+        match self.request_type {
+            RequestType::In | RequestType::Out => {}
+            _ => return false,
+        }
+        next.request_type == self.request_type && self.data_len as u64 % SECTOR_SIZE == 0 &&
+            self.sector.checked_add(self.data_len as u64 >> SECTOR_SHIFT) == Some(next.sector)
+    }
+
+    // Like `execute`, but doesn't need exclusive access to the disk.
+    fn execute_at(&self,
+                  disk: &PositionedDisk,
+                  mem: &GuestMemory)
+                  -> result::Result<u32, ExecuteError> {
+        let mut cursor = DiskCursor {
+            disk: disk,
+            offset: self.sector << SECTOR_SHIFT,
+        };
+        match self.request_type {
+            RequestType::In => {
+                mem.read_to_memory(self.data_addr, &mut cursor, self.data_len as usize)
+                    .map_err(|e| ExecuteError::Read{ addr: self.data_addr,
+                                                     length: self.data_len,
+                                                     sector: self.sector,
+                                                     guestmemerr: e })?;
+                return Ok(self.data_len);
+            }
+            RequestType::Out => {
+                mem.write_from_memory(self.data_addr, &mut cursor, self.data_len as usize)
+                    .map_err(|e| ExecuteError::Write{ addr: self.data_addr,
+                                                      length: self.data_len,
+                                                      sector: self.sector,
+                                                      guestmemerr: e })?;
+            }
+            RequestType::Flush => disk.sync().map_err(ExecuteError::Flush)?,
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
 }
 
+// Runs `requests` in order. Reads or writes of consecutive sectors that come one after the other
+// are merged into a single disk access.
+fn execute_merged<T: DiskFile>(disk: &mut T,
+                               mem: &GuestMemory,
+                               requests: &[Request])
+                               -> Vec<result::Result<u32, ExecuteError>> {
+    let mut results = Vec::with_capacity(requests.len());
+    let mut start = 0;
+    while start < requests.len() {
+        let mut end = start + 1;
+        let mut bytes = requests[start].data_len as u64;
+        while end < requests.len() && requests[end - 1].continued_by(&requests[end]) &&
+              bytes + requests[end].data_len as u64 <= MAX_MERGED_BYTES {
+            bytes += requests[end].data_len as u64;
+            end += 1;
+        }
+        if end - start == 1 {
+            results.push(requests[start].execute(disk, mem));
+        } else {
+            results.extend(execute_run(disk, mem, &requests[start..end]));
+        }
+        start = end;
+    }
+    results
+}
+
+// Reads or writes the consecutive sectors of all the requests in `run` with one buffer. If the
+// disk only transfers part of it, the requests that were transferred in full still succeed.
+fn execute_run<T: DiskFile>(disk: &mut T,
+                            mem: &GuestMemory,
+                            run: &[Request])
+                            -> Vec<result::Result<u32, ExecuteError>> {
+    let first = &run[0];
+    let write = first.request_type == RequestType::Out;
+    let total = run.iter().map(|r| r.data_len as usize).sum();
+    // Page aligned, in case the disk is opened with O_DIRECT.
+    let mut storage = vec![0u8; total + 4095];
+    let align = (4096 - (storage.as_ptr() as usize & 4095)) & 4095;
+    let buf = &mut storage[align..align + total];
+
+    if write {
+        let mut offset = 0;
+        for r in run {
+            let len = r.data_len as usize;
+            if mem.write_from_memory(r.data_addr, &mut &mut buf[offset..offset + len], len)
+                   .is_err() {
+                // Let each request report its own error.
+                return run.iter().map(|r| r.execute(disk, mem)).collect();
+            }
+            offset += len;
+        }
+    }
+
+    if let Err(e) = disk.seek(SeekFrom::Start(first.sector << SECTOR_SHIFT)) {
+        return run.iter()
+                   .map(|r| {
+                            Err(ExecuteError::Seek {
+                                    ioerr: io::Error::from(e.kind()),
+                                    sector: r.sector,
+                                })
+                        })
+                   .collect();
+    }
+
+    let mut done = 0;
+    let mut error = if write {
+        io::ErrorKind::WriteZero
+    } else {
+        io::ErrorKind::UnexpectedEof
+    };
+    while done < total {
+        let ret = if write {
+            disk.write(&buf[done..])
+        } else {
+            disk.read(&mut buf[done..])
+        };
+        match ret {
+            Ok(0) => break,
+            Ok(count) => done += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error = e.kind();
+                break;
+            }
+        }
+    }
+
+    let mut offset = 0;
+    run.iter()
+        .map(|r| {
+            let len = r.data_len as usize;
+            let start = offset;
+            offset += len;
+            if start + len > done {
+                return Err(ExecuteError::Transfer {
+                               kind: error,
+                               sector: r.sector,
+                           });
+            }
+            if write {
+                return Ok(0);
+            }
+            mem.read_to_memory(r.data_addr, &mut &buf[start..start + len], len)
+                .map_err(|e| ExecuteError::Read{ addr: r.data_addr,
+                                                 length: r.data_len,
+                                                 sector: r.sector,
+                                                 guestmemerr: e })?;
+            Ok(r.data_len)
+        })
+        .collect()
+}
+
+type IoJob = (u16, Request);
+type IoCompletion = (u16, Request, result::Result<u32, ExecuteError>);
+
+// Runs requests on a pool of threads, so a slow request doesn't hold up the ones behind it. Each
+// request is reported on `completion_evt` as soon as it finishes, in whatever order that happens.
+struct IoPool {
+    job_tx: Option<Sender<IoJob>>,
+    completion_rx: Receiver<IoCompletion>,
+    completion_evt: EventFd,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl IoPool {
+    fn new(disk: Arc<PositionedDisk>,
+           mem: GuestMemory,
+           writeback: Arc<AtomicBool>,
+           num_threads: usize)
+           -> SysResult<IoPool> {
+        let (job_tx, job_rx) = channel::<IoJob>();
+        let (completion_tx, completion_rx) = channel();
+        let completion_evt = EventFd::new()?;
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let mut threads = Vec::with_capacity(num_threads);
+        for _ in 0..num_threads {
+            let disk = disk.clone();
+            let mem = mem.clone();
+            let job_rx = job_rx.clone();
+            let writeback = writeback.clone();
+            let completion_tx = completion_tx.clone();
+            let completion_evt = completion_evt.try_clone()?;
+            let thread = thread::Builder::new()
+                .name("virtio_blk_io".to_string())
+                .spawn(move || loop {
+                    // The sender is only dropped when the pool is, so there is no more work.
+                    let (index, request) = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let result = request.execute_at(&*disk, &mem);
+                    let result = request.write_through(result, &writeback, || disk.sync());
+                    if completion_tx.send((index, request, result)).is_err() {
+                        break;
+                    }
+                    if let Err(e) = completion_evt.write(1) {
+                        error!("failed writing completion EventFd: {:?}", e);
+                    }
+                });
+            match thread {
+                Ok(t) => threads.push(t),
+                Err(e) => {
+                    error!("failed to spawn virtio_blk io thread: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(IoPool {
+               job_tx: Some(job_tx),
+               completion_rx: completion_rx,
+               completion_evt: completion_evt,
+               threads: threads,
+           })
+    }
+
+    fn submit(&self, index: u16, request: Request) {
+        if let Some(ref job_tx) = self.job_tx {
+            // The threads only go away with the pool, so this can't fail.
+            let _ = job_tx.send((index, request));
+        }
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        // Let the threads finish what they are working on and exit.
+        self.job_tx.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Counts a finished request and writes its status for the guest. Returns the length to put in
+// the used ring.
+fn finish_request(mem: &GuestMemory,
+                  counters: &Option<DeviceCounters>,
+                  request: &Request,
+                  result: result::Result<u32, ExecuteError>)
+                  -> u32 {
+    let (status, len) = match result {
+        Ok(l) => {
+            if let Some(ref counters) = *counters {
+                match request.request_type {
+                    RequestType::In => counters.add_in(request.data_len as usize),
+                    RequestType::Out => counters.add_out(request.data_len as usize),
+                    _ => counters.add_out(0),
+                }
+            }
+            (VIRTIO_BLK_S_OK, l)
+        }
+        Err(e) => {
+            error!("failed executing disk request: {:?}", e);
+            if let Some(ref counters) = *counters {
+                counters.add_error();
+            }
+            // 1 byte for the status
+            (e.status(), 1)
+        }
+    };
+    // We use unwrap because the request parsing process already checked that the status_addr was
+    // valid.
+    mem.write_obj_at_addr(status, request.status_addr).unwrap();
+    len
+}
+
 struct Worker<T: DiskFile> {
     queues: Vec<Queue>,
     mem: GuestMemory,
     disk_image: T,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
+    queue_interrupt_evts: Option<Vec<EventFd>>,
+    counters: Option<DeviceCounters>,
+    // Requests that access the disk go here instead of being run on the worker thread.
+    io_pool: Option<IoPool>,
+    merge_requests: bool,
+    max_transfer: u32,
+    // Cleared when the cache is write-through.
+    writeback: Arc<AtomicBool>,
+    // The most chains a pass takes from the queue.
+    max_chains: usize,
+    // Set when the last pass stopped at `max_chains`, so chains may be left in the queue.
+    queue_backlog: bool,
 }
 
 impl<T: DiskFile> Worker<T> {
@@ -208,29 +831,47 @@ impl<T: DiskFile> Worker<T> {
 
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
-        for avail_desc in queue.iter(&self.mem) {
+        // Requests held back to be merged with the ones after them.
+        let mut merge_indices = Vec::new();
+        let mut merge_requests = Vec::new();
+        let mut taken = 0;
+        for avail_desc in queue.iter(&self.mem).take(self.max_chains) {
+            taken += 1;
             let len;
             match Request::parse(&avail_desc, &self.mem) {
                 Ok(request) => {
-                    let status = match request.execute(&mut self.disk_image, &self.mem) {
-                        Ok(l) => {
-                            len = l;
-                            VIRTIO_BLK_S_OK
+                    if request.data_len > self.max_transfer {
+                        let result = Err(ExecuteError::TooLarge {
+                                             length: request.data_len,
+                                             sector: request.sector,
+                                         });
+                        len = finish_request(&self.mem, &self.counters, &request, result);
+                    } else {
+                        if let Some(ref io_pool) = self.io_pool {
+                            match request.request_type {
+                                RequestType::Unsupported(_) => {}
+                                _ => {
+                                    io_pool.submit(avail_desc.index, request);
+                                    continue;
+                                }
+                            }
                         }
-                        Err(e) => {
-                            error!("failed executing disk request: {:?}", e);
-                            len = 1; // 1 byte for the status
-                            e.status()
+                        if self.merge_requests {
+                            merge_indices.push(avail_desc.index);
+                            merge_requests.push(request);
+                            continue;
                         }
-                    };
-                    // We use unwrap because the request parsing process already checked that the
-                    // status_addr was valid.
-                    self.mem
-                        .write_obj_at_addr(status, request.status_addr)
-                        .unwrap();
+                        let disk = &mut self.disk_image;
+                        let result = request.execute(disk, &self.mem);
+                        let result = request.write_through(result, &self.writeback, || disk.fsync());
+                        len = finish_request(&self.mem, &self.counters, &request, result);
+                    }
                 }
                 Err(e) => {
                     error!("failed processing available descriptor chain: {:?}", e);
+                    if let Some(ref counters) = self.counters {
+                        counters.add_error();
+                    }
                     len = 0;
                 }
             }
@@ -238,22 +879,55 @@ impl<T: DiskFile> Worker<T> {
             used_count += 1;
         }
 
+        let results = execute_merged(&mut self.disk_image, &self.mem, &merge_requests);
+        for ((&index, request), result) in
+            merge_indices.iter().zip(merge_requests.iter()).zip(results) {
+            let disk = &mut self.disk_image;
+            let result = request.write_through(result, &self.writeback, || disk.fsync());
+            let len = finish_request(&self.mem, &self.counters, request, result);
+            used_desc_heads[used_count] = (index, len);
+            used_count += 1;
+        }
+
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             queue.add_used(&self.mem, desc_index, len);
         }
+        self.queue_backlog = taken == self.max_chains;
         used_count > 0
     }
 
+    // Returns the requests the io pool has finished to the guest.
+    fn process_completions(&mut self) -> bool {
+        let io_pool = match self.io_pool {
+            Some(ref p) => p,
+            None => return false,
+        };
+        let mut completed = false;
+        loop {
+            let (index, request, result) = match io_pool.completion_rx.try_recv() {
+                Ok(c) => c,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break,
+            };
+            let len = finish_request(&self.mem, &self.counters, &request, result);
+            self.queues[0].add_used(&self.mem, index, len);
+            completed = true;
+        }
+        completed
+    }
+
     fn signal_used_queue(&self) {
-        self.interrupt_status
-            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).unwrap();
+        signal_used_queue(&self.interrupt_status,
+                          &self.interrupt_evt,
+                          &self.queue_interrupt_evts,
+                          0);
     }
 
     fn run(&mut self, queue_evt: EventFd, kill_evt: EventFd) {
         #[derive(PollToken)]
         enum Token {
             QueueAvailable,
+            IoComplete,
             Kill,
         }
 
@@ -267,6 +941,12 @@ impl<T: DiskFile> Worker<T> {
                     return;
                 }
             };
+        if let Some(ref io_pool) = self.io_pool {
+            if let Err(e) = poll_ctx.add(&io_pool.completion_evt, Token::IoComplete) {
+                error!("failed adding completion EventFd to PollContext: {:?}", e);
+                return;
+            }
+        }
 
         'poll: loop {
             let events = match poll_ctx.wait() {
@@ -281,11 +961,37 @@ impl<T: DiskFile> Worker<T> {
             for event in events.iter_readable() {
                 match event.token() {
                     Token::QueueAvailable => {
-                        if let Err(e) = queue_evt.read() {
-                            error!("failed reading queue EventFd: {:?}", e);
-                            break 'poll;
+                        // Each pass takes every notification so far, so requests the guest adds
+                        // while a pass runs are handled by another pass without a trip through
+                        // poll.
+                        loop {
+                            match queue_evt.drain() {
+                                Ok(0) => break,
+                                Ok(_) => needs_interrupt |= self.process_queue(0),
+                                Err(e) => {
+                                    error!("failed reading queue EventFd: {:?}", e);
+                                    break 'poll;
+                                }
+                            }
+                            // Leave the rest of a full queue for the next wake, after the other
+                            // events have had their turn.
+                            if self.queue_backlog {
+                                if let Err(e) = queue_evt.write(1) {
+                                    error!("failed writing queue EventFd: {:?}", e);
+                                    break 'poll;
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    Token::IoComplete => {
+                        if let Some(ref io_pool) = self.io_pool {
+                            if let Err(e) = io_pool.completion_evt.read() {
+                                error!("failed reading completion EventFd: {:?}", e);
+                                break 'poll;
+                            }
                         }
-                        needs_interrupt |= self.process_queue(0);
+                        needs_interrupt |= self.process_completions();
                     }
                     Token::Kill => break 'poll,
                 }
@@ -300,27 +1006,51 @@ impl<T: DiskFile> Worker<T> {
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block<T: DiskFile> {
     kill_evt: Option<EventFd>,
+    worker_thread: Option<WorkerThread>,
     disk_image: Option<T>,
+    disk_size: u64,
     config_space: Vec<u8>,
+    counters: Option<DeviceCounters>,
+    io_threads: usize,
+    merge_requests: bool,
+    max_transfer: u32,
+    writeback: Arc<AtomicBool>,
 }
 
-fn build_config_space(disk_size: u64) -> Vec<u8> {
-    // We only support disk size, which uses the first two words of the configuration space.
+fn build_config_space(disk_size: u64, max_transfer: u32, writeback: bool) -> Vec<u8> {
+    // We support disk size, which uses the first two words of the configuration space, followed
+    // by the largest segment and the number of segments in a request, and the writeback flag.
     // If the image is not a multiple of the sector size, the tail bits are not exposed.
     // The config space is little endian.
-    let mut config = Vec::with_capacity(8);
+    let mut config = Vec::with_capacity(CONFIG_WRITEBACK_OFFSET + 1);
     let num_sectors = disk_size >> SECTOR_SHIFT;
     for i in 0..8 {
         config.push((num_sectors >> (8 * i)) as u8);
     }
+    // Requests always have a single data descriptor.
+    let seg_max = 1u32;
+    for i in 0..4 {
+        config.push((max_transfer >> (8 * i)) as u8);
+    }
+    for i in 0..4 {
+        config.push((seg_max >> (8 * i)) as u8);
+    }
+    // Geometry, block size and topology aren't supported.
+    config.resize(CONFIG_WRITEBACK_OFFSET, 0);
+    config.push(writeback as u8);
     config
 }
 
 impl<T: DiskFile> Block<T> {
-    /// Create a new virtio block device that operates on the given file.
+    /// Create a new virtio block device that operates on the given file, caching writes according
+    /// to `cache_mode`.
     ///
     /// The given file must be seekable and sizable.
-    pub fn new(mut disk_image: T) -> SysResult<Block<T>> {
+    pub fn new(mut disk_image: T, cache_mode: CacheMode) -> SysResult<Block<T>> {
+        if cache_mode == CacheMode::None {
+            disk_image.set_direct_io()?;
+        }
+        let writeback = cache_mode != CacheMode::WriteThrough;
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
         if disk_size % SECTOR_SIZE != 0 {
             warn!("Disk size {} is not a multiple of sector size {}; \
@@ -330,10 +1060,43 @@ impl<T: DiskFile> Block<T> {
         }
         Ok(Block {
                kill_evt: None,
+               worker_thread: None,
                disk_image: Some(disk_image),
-               config_space: build_config_space(disk_size),
+               disk_size: disk_size,
+               config_space: build_config_space(disk_size, DEFAULT_MAX_TRANSFER, writeback),
+               counters: None,
+               io_threads: 0,
+               merge_requests: false,
+               max_transfer: DEFAULT_MAX_TRANSFER,
+               writeback: Arc::new(AtomicBool::new(writeback)),
            })
     }
+
+    /// Counts the requests this device completes in `counters`.
+    pub fn set_counters(&mut self, counters: DeviceCounters) {
+        self.counters = Some(counters);
+    }
+
+    /// Runs disk requests on `num_threads` threads, so that many of them can be in flight at once.
+    /// Disks that can't be shared between threads keep running requests one at a time.
+    pub fn set_async_io(&mut self, num_threads: usize) {
+        self.io_threads = num_threads;
+    }
+
+    /// Merges requests the guest makes for consecutive sectors into a single read or write of the
+    /// disk. Disks with async io run every request on its own.
+    pub fn set_merge_requests(&mut self, merge: bool) {
+        self.merge_requests = merge;
+    }
+
+    /// Sets the largest request in bytes the device accepts. It is advertised to the guest, and
+    /// larger requests fail.
+    pub fn set_max_transfer(&mut self, max_transfer: u32) {
+        self.max_transfer = max_transfer;
+        self.config_space = build_config_space(self.disk_size,
+                                               max_transfer,
+                                               self.writeback.load(Ordering::Relaxed));
+    }
 }
 
 impl<T: DiskFile> Drop for Block<T> {
@@ -342,15 +1105,21 @@ impl<T: DiskFile> Drop for Block<T> {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        // The worker owns the disk image, so wait for it to let go before returning.
+        if let Some(worker_thread) = self.worker_thread.take() {
+            if !worker_thread.join_timeout(Duration::from_millis(WORKER_EXIT_TIMEOUT_MS)) {
+                warn!("virtio_blk worker didn't exit in {}ms", WORKER_EXIT_TIMEOUT_MS);
+            }
+        }
     }
 }
 
-impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
+impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
     fn keep_fds(&self) -> Vec<RawFd> {
         let mut keep_fds = Vec::new();
 
-        if let Some(ref disk_image) = self.disk_image {
-            keep_fds.push(disk_image.as_raw_fd());
+        if let Some(fd) = self.disk_image.as_ref().and_then(|d| d.optional_raw_fd()) {
+            keep_fds.push(fd);
         }
 
         keep_fds
@@ -364,6 +1133,16 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
         QUEUE_SIZES
     }
 
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            0 => {
+                VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH |
+                VIRTIO_BLK_F_CONFIG_WCE
+            }
+            _ => 0,
+        }
+    }
+
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
         let config_len = self.config_space.len() as u64;
         if offset >= config_len {
@@ -376,12 +1155,25 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
         }
     }
 
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only the writeback flag can be changed by the guest.
+        if offset != CONFIG_WRITEBACK_OFFSET as u64 || data.len() != 1 {
+            warn!("block: dropped write to read-only config: 0x{:x}:0x{:x}", offset, data.len());
+            return;
+        }
+        let writeback = data[0] != 0;
+        self.writeback.store(writeback, Ordering::Relaxed);
+        self.config_space[CONFIG_WRITEBACK_OFFSET] = writeback as u8;
+    }
+
     fn activate(&mut self,
                 mem: GuestMemory,
                 interrupt_evt: EventFd,
                 status: Arc<AtomicUsize>,
                 queues: Vec<Queue>,
-                mut queue_evts: Vec<EventFd>) {
+                mut queue_evts: Vec<EventFd>,
+                queue_interrupt_evts: Option<Vec<EventFd>>,
+                max_chains_per_pass: usize) {
         if queues.len() != 1 || queue_evts.len() != 1 {
             return;
         }
@@ -397,22 +1189,54 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
         self.kill_evt = Some(self_kill_evt);
 
         if let Some(disk_image) = self.disk_image.take() {
-            let worker_result = thread::Builder::new()
-                .name("virtio_blk".to_string())
-                .spawn(move || {
-                    let mut worker = Worker {
-                        queues: queues,
-                        mem: mem,
-                        disk_image: disk_image,
-                        interrupt_status: status,
-                        interrupt_evt: interrupt_evt,
-                    };
-                    worker.run(queue_evts.remove(0), kill_evt);
-                });
+            let io_pool = if self.io_threads > 0 {
+                match disk_image.positioned_disk() {
+                    Some(disk) => {
+                        match IoPool::new(disk,
+                                          mem.clone(),
+                                          self.writeback.clone(),
+                                          self.io_threads) {
+                            Ok(p) => Some(p),
+                            Err(e) => {
+                                error!("failed creating virtio_blk io pool: {:?}", e);
+                                return;
+                            }
+                        }
+                    }
+                    None => {
+                        warn!("disk doesn't support asynchronous io");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let counters = self.counters.take();
+            let merge_requests = self.merge_requests;
+            let max_transfer = self.max_transfer;
+            let writeback = self.writeback.clone();
+            let worker_result = WorkerThread::spawn("virtio_blk", move || {
+                let mut worker = Worker {
+                    queues: queues,
+                    mem: mem,
+                    disk_image: disk_image,
+                    interrupt_status: status,
+                    interrupt_evt: interrupt_evt,
+                    queue_interrupt_evts: queue_interrupt_evts,
+                    counters: counters,
+                    io_pool: io_pool,
+                    merge_requests: merge_requests,
+                    max_transfer: max_transfer,
+                    writeback: writeback,
+                    max_chains: max_chains_per_pass,
+                    queue_backlog: false,
+                };
+                worker.run(queue_evts.remove(0), kill_evt);
+            });
 
-            if let Err(e) = worker_result {
-                error!("failed to spawn virtio_blk worker: {}", e);
-                return;
+            match worker_result {
+                Ok(worker_thread) => self.worker_thread = Some(worker_thread),
+                Err(e) => error!("failed to spawn virtio_blk worker: {}", e),
             }
         }
     }
@@ -420,7 +1244,7 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
 
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
+    use std::fs::{File, OpenOptions};
     use std::path::PathBuf;
     use sys_util::TempDir;
 
@@ -434,7 +1258,7 @@ mod tests {
         let f = File::create(&path).unwrap();
         f.set_len(0x1000).unwrap();
 
-        let b = Block::new(f).unwrap();
+        let b = Block::new(f, CacheMode::WriteBack).unwrap();
         let mut num_sectors = [0u8; 4];
         b.read_config(0, &mut num_sectors);
         // size is 0x1000, so num_sectors is 8 (4096/512).
@@ -444,4 +1268,607 @@ mod tests {
         // size is 0x1000, so msw_sectors is 0.
         assert_eq!([0x00, 0x00, 0x00, 0x00], msw_sectors);
     }
+
+    // A disk image that lets the test see whether anything still holds it.
+    struct TrackedDisk {
+        file: File,
+        _holder: Arc<()>,
+    }
+
+    impl Read for TrackedDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for TrackedDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for TrackedDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl DiskFile for TrackedDisk {
+        fn optional_raw_fd(&self) -> Option<RawFd> {
+            Some(self.file.as_raw_fd())
+        }
+    }
+
+    // A disk that has no file descriptor at all.
+    impl DiskFile for io::Cursor<Vec<u8>> {}
+
+    #[test]
+    fn keep_fds() {
+        let tempdir = TempDir::new("/tmp/block_keep_fds_test").unwrap();
+        let mut path = PathBuf::from(tempdir.as_path().unwrap());
+        path.push("disk_image");
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+        let fd = f.as_raw_fd();
+        assert_eq!(Block::new(f, CacheMode::WriteBack).unwrap().keep_fds(), vec![fd]);
+
+        let b = Block::new(io::Cursor::new(vec![0u8; 0x1000]), CacheMode::WriteBack).unwrap();
+        assert!(b.keep_fds().is_empty());
+        let mut num_sectors = [0u8; 4];
+        b.read_config(0, &mut num_sectors);
+        assert_eq!([0x08, 0x00, 0x00, 0x00], num_sectors);
+    }
+
+    #[test]
+    fn drop_joins_worker() {
+        let tempdir = TempDir::new("/tmp/block_drop_test").unwrap();
+        let mut path = PathBuf::from(tempdir.as_path().unwrap());
+        path.push("disk_image");
+        let file = File::create(&path).unwrap();
+        file.set_len(0x1000).unwrap();
+        let holder = Arc::new(());
+        let disk = TrackedDisk {
+            file: file,
+            _holder: holder.clone(),
+        };
+
+        let mut b = Block::new(disk, CacheMode::WriteBack).unwrap();
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        b.activate(mem,
+                   EventFd::new().unwrap(),
+                   Arc::new(AtomicUsize::new(0)),
+                   vec![Queue::new(QUEUE_SIZE)],
+                   vec![EventFd::new().unwrap()],
+                   None,
+                   MAX_CHAINS_PER_PASS);
+        assert!(b.worker_thread.is_some());
+        assert_eq!(Arc::strong_count(&holder), 2);
+
+        drop(b);
+        // The worker has exited and dropped the disk image by the time drop returns.
+        assert_eq!(Arc::strong_count(&holder), 1);
+    }
+
+    const TEST_QUEUE_SIZE: u16 = 16;
+
+    // Puts a request made of a header, a data buffer and a status byte in the available ring of a
+    // queue at guest address 0. Request `n` uses descriptors 3n to 3n+2.
+    fn post_request(mem: &GuestMemory, n: u16, request_type: u32, data_len: u32) {
+        let header_addr = GuestAddress(0x10000 + n as u64 * 0x100);
+        mem.write_obj_at_addr(request_type, header_addr).unwrap();
+        mem.write_obj_at_addr(0u64, header_addr.unchecked_add(8)).unwrap();
+        let data_addr = GuestAddress(0x20000 + n as u64 * 0x1000);
+        let status_addr = header_addr.unchecked_add(0x20);
+        let data_flags = if request_type == VIRTIO_BLK_T_IN { 3u16 } else { 1u16 };
+        let descs = [(header_addr, 16, 1u16), (data_addr, data_len, data_flags), (status_addr, 1, 2)];
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let index = n * 3 + i as u16;
+            let desc = GuestAddress(index as u64 * 16);
+            mem.write_obj_at_addr(addr.offset() as u64, desc).unwrap();
+            mem.write_obj_at_addr(len, desc.unchecked_add(8)).unwrap();
+            mem.write_obj_at_addr(flags, desc.unchecked_add(12)).unwrap();
+            mem.write_obj_at_addr(index + 1, desc.unchecked_add(14)).unwrap();
+        }
+        mem.write_obj_at_addr(n * 3, GuestAddress(0x1004 + n as u64 * 2)).unwrap();
+        mem.write_obj_at_addr(n + 1, GuestAddress(0x1002)).unwrap();
+    }
+
+    #[test]
+    fn process_queue_updates_counters() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = TEST_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        let counters = DeviceCounters::new("block0").unwrap();
+        let mut worker = Worker {
+            queues: vec![queue],
+            mem: mem.clone(),
+            disk_image: io::Cursor::new(vec![0u8; 0x1000]),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            counters: Some(counters.clone()),
+            io_pool: None,
+            merge_requests: false,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            writeback: Arc::new(AtomicBool::new(true)),
+            max_chains: MAX_CHAINS_PER_PASS,
+            queue_backlog: false,
+        };
+
+        post_request(&mem, 0, VIRTIO_BLK_T_IN, 512);
+        post_request(&mem, 1, VIRTIO_BLK_T_OUT, 1024);
+        post_request(&mem, 2, VIRTIO_BLK_T_FLUSH, 0);
+        post_request(&mem, 3, 0xff, 0);
+        assert!(worker.process_queue(0));
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.requests_in, 1);
+        assert_eq!(stats.bytes_in, 512);
+        assert_eq!(stats.requests_out, 2);
+        assert_eq!(stats.bytes_out, 1024);
+        assert_eq!(stats.errors, 1);
+        let used_idx: u16 = mem.read_obj_from_addr(GuestAddress(0x2002)).unwrap();
+        assert_eq!(used_idx, 4);
+    }
+
+    #[test]
+    fn parse_split_header() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        post_request(&mem, 0, VIRTIO_BLK_T_IN, 512);
+        mem.write_obj_at_addr(7u64, GuestAddress(0x10008)).unwrap();
+        let parse = || {
+            let mut queue = Queue::new(QUEUE_SIZE);
+            queue.size = 0x20;
+            queue.ready = true;
+            queue.desc_table = GuestAddress(0);
+            queue.avail_ring = GuestAddress(0x1000);
+            queue.used_ring = GuestAddress(0x2000);
+            let head = queue.iter(&mem).next().unwrap();
+            Request::parse(&head, &mem).unwrap()
+        };
+        let req = parse();
+        assert_eq!(req.sector, 7);
+        assert_eq!(req.data_len, 512);
+
+        // Split the header over two descriptors, which takes the walk through the `Reader`.
+        mem.write_obj_at_addr(8u32, GuestAddress(8)).unwrap();
+        let descs = [(0x10008u64, 8u32, 1u16, 1u16), (0x20000, 512, 3, 2), (0x10020, 1, 2, 3)];
+        for (i, &(addr, len, flags, next)) in descs.iter().enumerate() {
+            let desc = GuestAddress(0x100 + i as u64 * 16);
+            mem.write_obj_at_addr(addr, desc).unwrap();
+            mem.write_obj_at_addr(len, desc.unchecked_add(8)).unwrap();
+            mem.write_obj_at_addr(flags, desc.unchecked_add(12)).unwrap();
+            mem.write_obj_at_addr(0x10 + next, desc.unchecked_add(14)).unwrap();
+        }
+        mem.write_obj_at_addr(0x10u16, GuestAddress(14)).unwrap();
+        let req = parse();
+        assert_eq!(req.sector, 7);
+        assert_eq!(req.data_addr, GuestAddress(0x20000));
+        assert_eq!(req.data_len, 512);
+        assert_eq!(req.status_addr, GuestAddress(0x10020));
+    }
+
+    #[test]
+    fn process_queue_stops_at_max_chains() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = TEST_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        let mut worker = Worker {
+            queues: vec![queue],
+            mem: mem.clone(),
+            disk_image: io::Cursor::new(vec![0u8; 0x1000]),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            counters: None,
+            io_pool: None,
+            merge_requests: false,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            writeback: Arc::new(AtomicBool::new(true)),
+            max_chains: 3,
+            queue_backlog: false,
+        };
+        let used_idx = || mem.read_obj_from_addr::<u16>(GuestAddress(0x2002)).unwrap();
+
+        for n in 0..5 {
+            post_request(&mem, n, VIRTIO_BLK_T_IN, 512);
+        }
+        assert!(worker.process_queue(0));
+        assert_eq!(used_idx(), 3);
+        assert!(worker.queue_backlog);
+
+        assert!(worker.process_queue(0));
+        assert_eq!(used_idx(), 5);
+        assert!(!worker.queue_backlog);
+    }
+
+    #[test]
+    fn sparse_file_zero_write() {
+        // Not defined by this version of libc.
+        const SEEK_DATA: libc::c_int = 3;
+        const SEEK_HOLE: libc::c_int = 4;
+
+        let tempdir = TempDir::new("/tmp/block_sparse_test").unwrap();
+        let mut path = PathBuf::from(tempdir.as_path().unwrap());
+        path.push("disk_image");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        let mut disk = SparseFile::new(file);
+        disk.write_all(&[0x55u8; 0x3000]).unwrap();
+
+        disk.seek(SeekFrom::Start(0x1000)).unwrap();
+        disk.write_all(&[0u8; 0x1000]).unwrap();
+        assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), 0x2000);
+
+        // Safe because the file descriptor is valid and lseek doesn't touch memory.
+        let hole = unsafe { libc::lseek(disk.as_raw_fd(), 0, SEEK_HOLE) };
+        let data = unsafe { libc::lseek(disk.as_raw_fd(), 0x1000, SEEK_DATA) };
+        assert_eq!(hole, 0x1000);
+        assert_eq!(data, 0x2000);
+
+        let mut buf = [0xffu8; 0x1000];
+        disk.seek(SeekFrom::Start(0x1000)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), 0x3000);
+    }
+
+    #[test]
+    fn mem_disk_requests() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = TEST_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        let mut worker = Worker {
+            queues: vec![queue],
+            mem: mem.clone(),
+            disk_image: MemDisk::new(0x1000).unwrap(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            counters: None,
+            io_pool: None,
+            merge_requests: false,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            writeback: Arc::new(AtomicBool::new(true)),
+            max_chains: MAX_CHAINS_PER_PASS,
+            queue_backlog: false,
+        };
+        let status = |n: u64| -> u8 {
+            mem.read_obj_from_addr(GuestAddress(0x10020 + n * 0x100)).unwrap()
+        };
+        let set_sector = |n: u64, sector: u64| {
+            mem.write_obj_at_addr(sector, GuestAddress(0x10008 + n * 0x100)).unwrap()
+        };
+
+        // Write a sector in the middle of the disk, then read it back along with the next one.
+        let pattern = [0xa5u8; 512];
+        mem.write_slice_at_addr(&pattern, GuestAddress(0x20000)).unwrap();
+        post_request(&mem, 0, VIRTIO_BLK_T_OUT, 512);
+        set_sector(0, 3);
+        post_request(&mem, 1, VIRTIO_BLK_T_IN, 1024);
+        set_sector(1, 3);
+        assert!(worker.process_queue(0));
+        assert_eq!(status(0), VIRTIO_BLK_S_OK);
+        assert_eq!(status(1), VIRTIO_BLK_S_OK);
+        let mut data = [0u8; 1024];
+        mem.read_slice_at_addr(&mut data, GuestAddress(0x21000)).unwrap();
+        assert!(data[..512].iter().all(|&b| b == 0xa5));
+        assert!(data[512..].iter().all(|&b| b == 0));
+
+        // The disk is eight sectors long and can't grow.
+        post_request(&mem, 2, VIRTIO_BLK_T_OUT, 512);
+        set_sector(2, 8);
+        post_request(&mem, 3, VIRTIO_BLK_T_IN, 512);
+        set_sector(3, 8);
+        assert!(worker.process_queue(0));
+        assert_eq!(status(2), VIRTIO_BLK_S_IOERR);
+        assert_eq!(status(3), VIRTIO_BLK_S_IOERR);
+    }
+
+    // Holds every read until the test lets it through. Writes complete right away.
+    struct GatedDisk {
+        gate: Mutex<Receiver<()>>,
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl PositionedDisk for GatedDisk {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.gate.lock().unwrap().recv().unwrap();
+            let data = self.data.lock().unwrap();
+            let offset = offset as usize;
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+            Ok(buf.len())
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            let mut data = self.data.lock().unwrap();
+            let offset = offset as usize;
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn async_requests_complete_out_of_order() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = TEST_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        let (gate_tx, gate_rx) = channel();
+        let disk = Arc::new(GatedDisk {
+                                gate: Mutex::new(gate_rx),
+                                data: Mutex::new(vec![0u8; 0x1000]),
+                            });
+        let mut worker = Worker {
+            queues: vec![queue],
+            mem: mem.clone(),
+            disk_image: io::Cursor::new(Vec::new()),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            counters: None,
+            io_pool: Some(IoPool::new(disk,
+                                      mem.clone(),
+                                      Arc::new(AtomicBool::new(true)),
+                                      2)
+                                  .unwrap()),
+            merge_requests: false,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            writeback: Arc::new(AtomicBool::new(true)),
+            max_chains: MAX_CHAINS_PER_PASS,
+            queue_backlog: false,
+        };
+        let used_id = |n: u64| -> u32 {
+            mem.read_obj_from_addr(GuestAddress(0x2004 + n * 8)).unwrap()
+        };
+
+        // The read is stuck on the gate, so the write that came after it finishes first.
+        post_request(&mem, 0, VIRTIO_BLK_T_IN, 512);
+        post_request(&mem, 1, VIRTIO_BLK_T_OUT, 512);
+        assert!(!worker.process_queue(0));
+        worker.io_pool.as_ref().unwrap().completion_evt.read().unwrap();
+        assert!(worker.process_completions());
+        let used_idx: u16 = mem.read_obj_from_addr(GuestAddress(0x2002)).unwrap();
+        assert_eq!(used_idx, 1);
+        assert_eq!(used_id(0), 3);
+
+        gate_tx.send(()).unwrap();
+        worker.io_pool.as_ref().unwrap().completion_evt.read().unwrap();
+        assert!(worker.process_completions());
+        let used_idx: u16 = mem.read_obj_from_addr(GuestAddress(0x2002)).unwrap();
+        assert_eq!(used_idx, 2);
+        assert_eq!(used_id(1), 0);
+        let status: u8 = mem.read_obj_from_addr(GuestAddress(0x10020)).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+    }
+
+    // Counts the reads and syncs of a disk and fails writes after `write_limit` bytes.
+    struct CountingDisk {
+        data: io::Cursor<Vec<u8>>,
+        reads: usize,
+        syncs: usize,
+        write_limit: usize,
+    }
+
+    impl Read for CountingDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for CountingDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.write_limit == 0 {
+                return Err(io::Error::from(io::ErrorKind::Other));
+            }
+            let count = cmp::min(buf.len(), self.write_limit);
+            self.write_limit -= count;
+            self.data.write(&buf[..count])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for CountingDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    impl DiskFile for CountingDisk {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    fn merging_worker(mem: &GuestMemory, write_limit: usize) -> Worker<CountingDisk> {
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = TEST_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        // Each sector is filled with its own number.
+        let data = (0..8).flat_map(|i| vec![i as u8; 512]).collect();
+        Worker {
+            queues: vec![queue],
+            mem: mem.clone(),
+            disk_image: CountingDisk {
+                data: io::Cursor::new(data),
+                reads: 0,
+                syncs: 0,
+                write_limit: write_limit,
+            },
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            counters: None,
+            io_pool: None,
+            merge_requests: true,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            writeback: Arc::new(AtomicBool::new(true)),
+            max_chains: MAX_CHAINS_PER_PASS,
+            queue_backlog: false,
+        }
+    }
+
+    fn set_sector(mem: &GuestMemory, n: u64, sector: u64) {
+        mem.write_obj_at_addr(sector, GuestAddress(0x10008 + n * 0x100)).unwrap();
+    }
+
+    fn request_status(mem: &GuestMemory, n: u64) -> u8 {
+        mem.read_obj_from_addr(GuestAddress(0x10020 + n * 0x100)).unwrap()
+    }
+
+    #[test]
+    fn merge_adjacent_reads() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut worker = merging_worker(&mem, 0);
+        for n in 0..3 {
+            post_request(&mem, n, VIRTIO_BLK_T_IN, 512);
+            set_sector(&mem, n as u64, 2 + n as u64);
+        }
+        assert!(worker.process_queue(0));
+
+        assert_eq!(worker.disk_image.reads, 1);
+        for n in 0..3 {
+            assert_eq!(request_status(&mem, n), VIRTIO_BLK_S_OK);
+            let mut data = [0u8; 512];
+            mem.read_slice_at_addr(&mut data, GuestAddress(0x20000 + n * 0x1000)).unwrap();
+            assert!(data.iter().all(|&b| b == 2 + n as u8));
+        }
+    }
+
+    #[test]
+    fn merged_write_error() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        // The disk takes the first request and part of the second before failing.
+        let mut worker = merging_worker(&mem, 700);
+        for n in 0..3 {
+            post_request(&mem, n, VIRTIO_BLK_T_OUT, 512);
+            set_sector(&mem, n as u64, n as u64);
+        }
+        assert!(worker.process_queue(0));
+
+        assert_eq!(request_status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(request_status(&mem, 1), VIRTIO_BLK_S_IOERR);
+        assert_eq!(request_status(&mem, 2), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn max_transfer() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut worker = merging_worker(&mem, 0x1000);
+        worker.merge_requests = false;
+        worker.max_transfer = 1024;
+        post_request(&mem, 0, VIRTIO_BLK_T_IN, 1024);
+        post_request(&mem, 1, VIRTIO_BLK_T_IN, 1536);
+        post_request(&mem, 2, VIRTIO_BLK_T_OUT, 1536);
+        assert!(worker.process_queue(0));
+
+        assert_eq!(request_status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(request_status(&mem, 1), VIRTIO_BLK_S_IOERR);
+        assert_eq!(request_status(&mem, 2), VIRTIO_BLK_S_IOERR);
+        // Only the request within the limit got to the disk.
+        assert_eq!(worker.disk_image.reads, 1);
+        assert_eq!(worker.disk_image.write_limit, 0x1000);
+    }
+
+    #[test]
+    fn max_transfer_config() {
+        let mut b = Block::new(io::Cursor::new(vec![0u8; 0x1000]), CacheMode::WriteBack).unwrap();
+        b.set_max_transfer(0x10000);
+        assert_eq!(b.features(0) & (VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX),
+                   VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX);
+        let mut config = [0u8; 8];
+        b.read_config(8, &mut config);
+        assert_eq!(config, [0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn write_through() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut worker = merging_worker(&mem, 0x1000);
+        worker.merge_requests = false;
+        worker.writeback.store(false, Ordering::Relaxed);
+        post_request(&mem, 0, VIRTIO_BLK_T_OUT, 512);
+        post_request(&mem, 1, VIRTIO_BLK_T_OUT, 512);
+        post_request(&mem, 2, VIRTIO_BLK_T_IN, 512);
+        assert!(worker.process_queue(0));
+        assert_eq!(worker.disk_image.syncs, 2);
+
+        // Merged writes are each synced too.
+        worker.merge_requests = true;
+        for n in 3..5 {
+            post_request(&mem, n, VIRTIO_BLK_T_OUT, 512);
+            set_sector(&mem, n as u64, n as u64);
+        }
+        assert!(worker.process_queue(0));
+        assert_eq!(worker.disk_image.syncs, 4);
+    }
+
+    #[test]
+    fn write_back() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let mut worker = merging_worker(&mem, 0x1000);
+        worker.merge_requests = false;
+        post_request(&mem, 0, VIRTIO_BLK_T_OUT, 512);
+        post_request(&mem, 1, VIRTIO_BLK_T_OUT, 512);
+        assert!(worker.process_queue(0));
+        assert_eq!(worker.disk_image.syncs, 0);
+        post_request(&mem, 2, VIRTIO_BLK_T_FLUSH, 0);
+        assert!(worker.process_queue(0));
+        assert_eq!(worker.disk_image.syncs, 1);
+    }
+
+    #[test]
+    fn cache_mode_config() {
+        let mut b = Block::new(io::Cursor::new(vec![0u8; 0x1000]), CacheMode::WriteThrough)
+            .unwrap();
+        let wce = VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_CONFIG_WCE;
+        assert_eq!(b.features(0) & wce, wce);
+        let mut writeback = [0xffu8];
+        b.read_config(CONFIG_WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [0]);
+
+        // The guest switches the cache to writeback.
+        b.write_config(CONFIG_WRITEBACK_OFFSET as u64, &[1]);
+        b.read_config(CONFIG_WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [1]);
+        assert!(b.writeback.load(Ordering::Relaxed));
+
+        // Direct io isn't possible without a file.
+        assert!(Block::new(io::Cursor::new(vec![0u8; 0x1000]), CacheMode::None).is_err());
+    }
 }
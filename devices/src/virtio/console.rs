@@ -0,0 +1,897 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Virtio console device, bridging the guest's hvc console and any further ports to host readers
+//! and writers.
+//!
+//! A device with a single port is a plain console. With more ports, `VIRTIO_CONSOLE_F_MULTIPORT` is
+//! offered and the driver must accept it, since the transport only activates the device once every
+//! queue, including the control queues and those of the extra ports, is set up. Port 0 is always
+//! the console; the others show up in the guest as `/dev/vportNpM`, or as
+//! `/dev/virtio-ports/NAME` if they are named.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sys_util::{EventFd, GuestMemory, PollContext, PollToken};
+
+use super::{VirtioDevice, Queue, DescriptorChain, Reader, INTERRUPT_STATUS_USED_RING,
+            TYPE_CONSOLE};
+
+const QUEUE_SIZE: u16 = 256;
+
+const VIRTIO_CONSOLE_F_SIZE: u32 = 1 << 0;
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+
+// Size of struct virtio_console_config from linux/virtio_console.h.
+const CONFIG_SIZE: usize = 12;
+
+// Size of struct virtio_console_control, the header of every message on the control queues.
+const CONTROL_MSG_SIZE: usize = 8;
+// The most output copied from guest memory with a single write.
+const TRANSMIT_CHUNK_SIZE: usize = 4096;
+
+// Control events from linux/virtio_console.h.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The width assumed for the console when the host endpoint has no size.
+pub const DEFAULT_CONSOLE_COLS: u16 = 80;
+/// The height assumed for the console when the host endpoint has no size.
+pub const DEFAULT_CONSOLE_ROWS: u16 = 24;
+
+/// The host endpoint of one port of a `Console`.
+pub struct ConsolePort {
+    /// Read for input to the guest, on a thread of its own. Reads may block.
+    pub input: Option<Box<io::Read + Send>>,
+    /// Written with the output of the guest.
+    pub output: Option<Box<io::Write + Send>>,
+    /// The name the guest sees the port by. The name of port 0 is ignored.
+    pub name: Option<String>,
+}
+
+// Bytes read from the host input, waiting for the guest to post receive buffers.
+type InputBuffer = Arc<Mutex<VecDeque<u8>>>;
+
+// Reads `input` until it ends, queueing the bytes for the guest and signaling `in_avail_evt` each
+// time more are available.
+fn read_input(mut input: Box<io::Read + Send>, buffer: InputBuffer, in_avail_evt: EventFd) {
+    let mut buf = [0u8; 256];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => {
+                buffer.lock().unwrap().extend(&buf[..count]);
+                if let Err(e) = in_avail_evt.write(1) {
+                    error!("failed to signal console input: {:?}", e);
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error!("failed to read console input: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn control_msg(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = vec![0u8; CONTROL_MSG_SIZE];
+    LittleEndian::write_u32(&mut msg[0..4], id);
+    LittleEndian::write_u16(&mut msg[4..6], event);
+    LittleEndian::write_u16(&mut msg[6..8], value);
+    msg
+}
+
+// Writes as much of `input` as fits into the writable descriptors of the chain starting at
+// `avail_desc`, returning the number of bytes written.
+fn write_to_chain(mem: &GuestMemory,
+                  avail_desc: DescriptorChain,
+                  input: &mut VecDeque<u8>)
+                  -> u32 {
+    let mut written = 0;
+    let mut desc = Some(avail_desc);
+    while let Some(d) = desc {
+        if !d.is_write_only() || input.is_empty() {
+            break;
+        }
+        let count = cmp::min(d.len as usize, input.len());
+        let bytes: Vec<u8> = input.drain(..count).collect();
+        if let Err(e) = mem.write_slice_at_addr(&bytes, d.addr) {
+            error!("failed to write console input to guest: {:?}", e);
+            break;
+        }
+        written += count as u32;
+        desc = d.next_descriptor();
+    }
+    written
+}
+
+// Moves bytes from `input` into the buffers the guest posted on `queue`. Returns true if any buffer
+// was used.
+fn fill_receive_queue(mem: &GuestMemory, queue: &mut Queue, input: &mut VecDeque<u8>) -> bool {
+    let mut used = false;
+    while !input.is_empty() {
+        let avail_desc = match queue.iter(mem).next() {
+            Some(d) => d,
+            None => break,
+        };
+        let index = avail_desc.index;
+        let written = write_to_chain(mem, avail_desc, input);
+        queue.add_used(mem, index, written);
+        used = true;
+    }
+    used
+}
+
+// Passes a `Reader` over each buffer the guest sent on `queue` to `f`. Returns true if any buffer
+// was used.
+fn drain_transmit_queue<F>(mem: &GuestMemory, queue: &mut Queue, mut f: F) -> bool
+    where F: FnMut(&mut Reader)
+{
+    let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+    let mut used_count = 0;
+    for avail_desc in queue.iter(mem) {
+        used_desc_heads[used_count] = avail_desc.index;
+        used_count += 1;
+        f(&mut Reader::new(mem, avail_desc));
+    }
+
+    for &index in &used_desc_heads[..used_count] {
+        queue.add_used(mem, index, 0);
+    }
+    used_count > 0
+}
+
+struct Port {
+    receive_queue: Queue,
+    transmit_queue: Queue,
+    input: InputBuffer,
+    output: Option<Box<io::Write + Send>>,
+    name: Option<String>,
+    // Whether the port has a host endpoint.
+    host_connected: bool,
+    // Whether a program in the guest has the port open. Input is held back while it doesn't.
+    guest_open: bool,
+    // Whether the guest driver has set up the port. Later PORT_READY messages are ignored.
+    guest_ready: bool,
+}
+
+struct Control {
+    receive_queue: Queue,
+    transmit_queue: Queue,
+    // Messages waiting for the guest to post control receive buffers.
+    pending: VecDeque<Vec<u8>>,
+    // Whether the guest driver has announced itself. Later DEVICE_READY messages are ignored.
+    device_ready: bool,
+}
+
+struct Worker {
+    mem: GuestMemory,
+    ports: Vec<Port>,
+    control: Option<Control>,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+}
+
+impl Worker {
+    // Fills receive buffers posted by the guest for `port` with pending input.
+    fn process_receive_queue(&mut self, port: usize) -> bool {
+        let port = match self.ports.get_mut(port) {
+            Some(p) => p,
+            None => return false,
+        };
+        if !port.guest_open {
+            return false;
+        }
+        let mut input = port.input.lock().unwrap();
+        fill_receive_queue(&self.mem, &mut port.receive_queue, &mut input)
+    }
+
+    // Writes the buffers sent by the guest on `port` to its host output.
+    fn process_transmit_queue(&mut self, port: usize) -> bool {
+        let port = match self.ports.get_mut(port) {
+            Some(p) => p,
+            None => return false,
+        };
+        let output = &mut port.output;
+        let used = drain_transmit_queue(&self.mem, &mut port.transmit_queue, |reader| {
+            if let Some(ref mut output) = *output {
+                // Guest memory goes straight to the output a chunk at a time, however long the
+                // guest made the buffer.
+                loop {
+                    match reader.read_to(output, TRANSMIT_CHUNK_SIZE) {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("failed to write console output: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        if used {
+            if let Some(ref mut output) = *output {
+                let _ = output.flush();
+            }
+        }
+        used
+    }
+
+    // Sends pending control messages to the guest.
+    fn process_control_receive_queue(&mut self) -> bool {
+        let control = match self.control {
+            Some(ref mut c) => c,
+            None => return false,
+        };
+        let mut used = false;
+        // Each message goes in a buffer of its own.
+        while let Some(msg) = control.pending.pop_front() {
+            let avail_desc = match control.receive_queue.iter(&self.mem).next() {
+                Some(d) => d,
+                None => {
+                    control.pending.push_front(msg);
+                    break;
+                }
+            };
+            let index = avail_desc.index;
+            let len = msg.len();
+            let written = write_to_chain(&self.mem, avail_desc, &mut VecDeque::from(msg));
+            if written as usize != len {
+                error!("virtio console control buffer too small for message");
+            }
+            control.receive_queue.add_used(&self.mem, index, written);
+            used = true;
+        }
+        used
+    }
+
+    fn queue_control_msg(&mut self, msg: Vec<u8>) {
+        if let Some(ref mut control) = self.control {
+            control.pending.push_back(msg);
+        }
+    }
+
+    fn handle_control_msg(&mut self, msg: &[u8]) {
+        if msg.len() < CONTROL_MSG_SIZE {
+            error!("virtio console control message too short: {}", msg.len());
+            return;
+        }
+        let id = LittleEndian::read_u32(&msg[0..4]);
+        let event = LittleEndian::read_u16(&msg[4..6]);
+        let value = LittleEndian::read_u16(&msg[6..8]);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("guest failed to set up the virtio console");
+                    return;
+                }
+                match self.control {
+                    Some(ref mut control) if !control.device_ready => control.device_ready = true,
+                    _ => return,
+                }
+                for port in 0..self.ports.len() {
+                    self.queue_control_msg(control_msg(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                let (has_host, name) = match self.ports.get(id as usize) {
+                    Some(p) if p.guest_ready => return,
+                    Some(p) => (p.host_connected, p.name.clone()),
+                    None => {
+                        error!("guest readied nonexistent virtio console port {}", id);
+                        return;
+                    }
+                };
+                if value != 1 {
+                    error!("guest failed to set up virtio console port {}", id);
+                    return;
+                }
+                self.ports[id as usize].guest_ready = true;
+                if id == 0 {
+                    self.queue_control_msg(control_msg(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                    // The guest treats the console as open from now on.
+                    self.ports[0].guest_open = true;
+                } else if let Some(name) = name {
+                    let mut msg = control_msg(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    msg.extend_from_slice(name.as_bytes());
+                    self.queue_control_msg(msg);
+                }
+                if has_host {
+                    self.queue_control_msg(control_msg(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                match self.ports.get_mut(id as usize) {
+                    Some(p) => p.guest_open = value != 0,
+                    None => error!("guest opened nonexistent virtio console port {}", id),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Handles the control messages sent by the guest.
+    fn process_control_transmit_queue(&mut self) -> bool {
+        let mut msgs = Vec::new();
+        let used = match self.control {
+            Some(ref mut c) => {
+                drain_transmit_queue(&self.mem, &mut c.transmit_queue, |reader| {
+                    // Anything past the fixed size message is ignored.
+                    let mut msg = [0u8; CONTROL_MSG_SIZE];
+                    match reader.read_to(&mut &mut msg[..], CONTROL_MSG_SIZE) {
+                        Ok(len) => msgs.push(msg[..len].to_vec()),
+                        Err(e) => error!("failed to read console control message: {:?}", e),
+                    }
+                })
+            }
+            None => false,
+        };
+        for msg in msgs {
+            self.handle_control_msg(&msg);
+        }
+        // Replies, and input for ports that were just opened, can go out right away.
+        let mut needs_interrupt = self.process_control_receive_queue();
+        for port in 0..self.ports.len() {
+            needs_interrupt |= self.process_receive_queue(port);
+        }
+        used || needs_interrupt
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).unwrap();
+    }
+
+    fn run(&mut self,
+           port_evts: Vec<(EventFd, EventFd, EventFd)>,
+           control_evts: Option<(EventFd, EventFd)>,
+           kill_evt: EventFd) {
+        #[derive(PollToken)]
+        enum Token {
+            ReceiveQueueAvailable { port: usize },
+            TransmitQueueAvailable { port: usize },
+            InputAvailable { port: usize },
+            ControlReceiveQueueAvailable,
+            ControlTransmitQueueAvailable,
+            Kill,
+        }
+
+        let poll_ctx: PollContext<Token> =
+            match PollContext::new().and_then(|pc| pc.add(&kill_evt, Token::Kill).and(Ok(pc))) {
+                Ok(pc) => pc,
+                Err(e) => {
+                    error!("failed creating PollContext: {:?}", e);
+                    return;
+                }
+            };
+        for (port, &(ref receive_evt, ref transmit_evt, ref in_avail_evt)) in
+            port_evts.iter().enumerate() {
+            let res = poll_ctx
+                .add(receive_evt, Token::ReceiveQueueAvailable { port: port })
+                .and_then(|_| {
+                    poll_ctx.add(transmit_evt, Token::TransmitQueueAvailable { port: port })
+                })
+                .and_then(|_| poll_ctx.add(in_avail_evt, Token::InputAvailable { port: port }));
+            if let Err(e) = res {
+                error!("failed adding console port events to PollContext: {:?}", e);
+                return;
+            }
+        }
+        if let Some((ref receive_evt, ref transmit_evt)) = control_evts {
+            let res = poll_ctx
+                .add(receive_evt, Token::ControlReceiveQueueAvailable)
+                .and_then(|_| poll_ctx.add(transmit_evt, Token::ControlTransmitQueueAvailable));
+            if let Err(e) = res {
+                error!("failed adding console control events to PollContext: {:?}", e);
+                return;
+            }
+        }
+
+        'poll: loop {
+            let events = match poll_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {:?}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter_readable() {
+                let (evt, token) = match event.token() {
+                    Token::ReceiveQueueAvailable { port } => (&port_evts[port].0, event.token()),
+                    Token::TransmitQueueAvailable { port } => (&port_evts[port].1, event.token()),
+                    Token::InputAvailable { port } => (&port_evts[port].2, event.token()),
+                    Token::ControlReceiveQueueAvailable => {
+                        // The token is only registered when there are control events.
+                        (&control_evts.as_ref().unwrap().0, event.token())
+                    }
+                    Token::ControlTransmitQueueAvailable => {
+                        (&control_evts.as_ref().unwrap().1, event.token())
+                    }
+                    Token::Kill => break 'poll,
+                };
+                if let Err(e) = evt.read() {
+                    error!("failed reading console EventFd: {:?}", e);
+                    break 'poll;
+                }
+                needs_interrupt |= match token {
+                    Token::ReceiveQueueAvailable { port } |
+                    Token::InputAvailable { port } => self.process_receive_queue(port),
+                    Token::TransmitQueueAvailable { port } => self.process_transmit_queue(port),
+                    Token::ControlReceiveQueueAvailable => self.process_control_receive_queue(),
+                    Token::ControlTransmitQueueAvailable => {
+                        self.process_control_transmit_queue()
+                    }
+                    Token::Kill => false,
+                };
+            }
+            if needs_interrupt {
+                self.signal_used_queue();
+            }
+        }
+    }
+}
+
+/// Virtio console device that sends what the guest writes to each port to the port's output and
+/// gives the guest what is read from the port's input.
+pub struct Console {
+    kill_evt: Option<EventFd>,
+    ports: Vec<ConsolePort>,
+    keep_fds: Vec<RawFd>,
+    queue_sizes: Vec<u16>,
+    cols: u16,
+    rows: u16,
+    avail_features: u32,
+    acked_features: u32,
+}
+
+impl Console {
+    /// Create a new virtio console device.
+    ///
+    /// # Arguments
+    /// * `ports` - The host endpoints of each port. Port 0 is the console and there must be at least
+    ///             one port.
+    /// * `keep_fds` - The file descriptors the ports need to be kept open when jailed.
+    /// * `cols` - The width of the console in characters.
+    /// * `rows` - The height of the console in characters.
+    pub fn new(mut ports: Vec<ConsolePort>, keep_fds: Vec<RawFd>, cols: u16, rows: u16) -> Console {
+        if ports.is_empty() {
+            ports.push(ConsolePort {
+                           input: None,
+                           output: None,
+                           name: None,
+                       });
+        }
+        let mut avail_features = VIRTIO_CONSOLE_F_SIZE;
+        // Port 0's queues, then the control queues, then the queues of every other port.
+        let mut num_queues = 2;
+        if ports.len() > 1 {
+            avail_features |= VIRTIO_CONSOLE_F_MULTIPORT;
+            num_queues += 2 * ports.len();
+        }
+        Console {
+            kill_evt: None,
+            ports: ports,
+            keep_fds: keep_fds,
+            queue_sizes: vec![QUEUE_SIZE; num_queues],
+            cols: cols,
+            rows: rows,
+            avail_features: avail_features,
+            acked_features: 0,
+        }
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn keep_fds(&self) -> Vec<RawFd> {
+        self.keep_fds.clone()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_CONSOLE
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            0 => self.avail_features,
+            _ => 0,
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        if page == 0 {
+            self.acked_features |= value & self.avail_features;
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // cols, rows, max_nr_ports and emerg_wr. Emergency writes aren't supported.
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u16(&mut config[0..2], self.cols);
+        LittleEndian::write_u16(&mut config[2..4], self.rows);
+        LittleEndian::write_u32(&mut config[4..8], self.ports.len() as u32);
+        let start = offset as usize;
+        if let Some(end) = start.checked_add(data.len()) {
+            if end <= CONFIG_SIZE {
+                data.copy_from_slice(&config[start..end]);
+            }
+        }
+    }
+
+    fn activate(&mut self,
+                mem: GuestMemory,
+                interrupt_evt: EventFd,
+                status: Arc<AtomicUsize>,
+                mut queues: Vec<Queue>,
+                mut queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            return;
+        }
+        let multiport = self.ports.len() > 1;
+        if multiport && self.acked_features & VIRTIO_CONSOLE_F_MULTIPORT == 0 {
+            error!("virtio console driver did not accept multiple ports");
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to create kill EventFd pair: {:?}", e);
+                    return;
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        // Without multiport there is no control queue and the console is open from the start.
+        let mut control = None;
+        let mut control_evts = None;
+        if multiport {
+            let receive_queue = queues.remove(2);
+            let transmit_queue = queues.remove(2);
+            let receive_evt = queue_evts.remove(2);
+            let transmit_evt = queue_evts.remove(2);
+            control = Some(Control {
+                               receive_queue: receive_queue,
+                               transmit_queue: transmit_queue,
+                               pending: VecDeque::new(),
+                               device_ready: false,
+                           });
+            control_evts = Some((receive_evt, transmit_evt));
+        }
+
+        let mut ports = Vec::with_capacity(self.ports.len());
+        let mut port_evts = Vec::with_capacity(self.ports.len());
+        for (index, host_port) in self.ports.drain(..).enumerate() {
+            let (in_avail_evt, reader_evt) =
+                match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("failed to create input EventFd pair: {:?}", e);
+                        return;
+                    }
+                };
+            let input_buffer = Arc::new(Mutex::new(VecDeque::new()));
+            let host_connected = host_port.input.is_some() || host_port.output.is_some();
+            if let Some(input) = host_port.input {
+                let reader_buffer = input_buffer.clone();
+                // The reader is left blocked in `read` when the device goes away, so it isn't
+                // joined.
+                let reader_result = thread::Builder::new()
+                    .name(format!("virtio_console_input{}", index))
+                    .spawn(move || read_input(input, reader_buffer, reader_evt));
+                if let Err(e) = reader_result {
+                    error!("failed to spawn virtio_console input reader: {}", e);
+                    return;
+                }
+            }
+            ports.push(Port {
+                           receive_queue: queues.remove(0),
+                           transmit_queue: queues.remove(0),
+                           input: input_buffer,
+                           output: host_port.output,
+                           name: host_port.name,
+                           host_connected: host_connected,
+                           guest_open: !multiport,
+                           guest_ready: false,
+                       });
+            port_evts.push((queue_evts.remove(0), queue_evts.remove(0), in_avail_evt));
+        }
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_console".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    mem: mem,
+                    ports: ports,
+                    control: control,
+                    interrupt_status: status,
+                    interrupt_evt: interrupt_evt,
+                };
+                worker.run(port_evts, control_evts, kill_evt);
+            });
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_console worker: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_queue::TestQueue;
+    use fakes::SharedBuffer;
+    use sys_util::GuestAddress;
+
+    fn test_port(mem: &GuestMemory,
+                 base: u64,
+                 output: SharedBuffer,
+                 name: Option<&str>)
+                 -> (Port, TestQueue, TestQueue) {
+        let (rx, receive_queue) = TestQueue::new(mem, base, QUEUE_SIZE);
+        let (tx, transmit_queue) = TestQueue::new(mem, base + 0x100000, QUEUE_SIZE);
+        let port = Port {
+            receive_queue: receive_queue,
+            transmit_queue: transmit_queue,
+            input: Arc::new(Mutex::new(VecDeque::new())),
+            output: Some(Box::new(output)),
+            name: name.map(|n| n.to_string()),
+            host_connected: true,
+            guest_open: false,
+            guest_ready: false,
+        };
+        (port, rx, tx)
+    }
+
+    fn test_worker(mem: &GuestMemory, ports: Vec<Port>, control: Option<Control>) -> Worker {
+        Worker {
+            mem: mem.clone(),
+            ports: ports,
+            control: control,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+        }
+    }
+
+    fn test_control(mem: &GuestMemory, base: u64) -> (Control, TestQueue, TestQueue) {
+        let (c_rx, receive_queue) = TestQueue::new(mem, base, QUEUE_SIZE);
+        let (c_tx, transmit_queue) = TestQueue::new(mem, base + 0x100000, QUEUE_SIZE);
+        let control = Control {
+            receive_queue: receive_queue,
+            transmit_queue: transmit_queue,
+            pending: VecDeque::new(),
+            device_ready: false,
+        };
+        (control, c_rx, c_tx)
+    }
+
+    // Reads every control message the device has sent, posting receive buffers for them first.
+    fn read_control_msgs(worker: &mut Worker,
+                         mem: &GuestMemory,
+                         c_rx: &mut TestQueue,
+                         first_index: u16)
+                         -> Vec<(u32, u16, u16, Vec<u8>)> {
+        for i in 0..8 {
+            c_rx.post(mem, first_index + i, None, 64);
+        }
+        worker.process_control_receive_queue();
+        let mut msgs = Vec::new();
+        while let Some(m) = c_rx.pop_used(mem) {
+            msgs.push((LittleEndian::read_u32(&m[0..4]),
+                       LittleEndian::read_u16(&m[4..6]),
+                       LittleEndian::read_u16(&m[6..8]),
+                       m[8..].to_vec()));
+        }
+        msgs
+    }
+
+    #[test]
+    fn transmit_to_host() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let output = SharedBuffer::new();
+        let (port, _rx, mut tx) = test_port(&mem, 0, output.clone(), None);
+        let mut worker = test_worker(&mem, vec![port], None);
+
+        assert!(!worker.process_transmit_queue(0));
+        tx.post(&mem, 0, Some(b"hello "), 6);
+        tx.post(&mem, 1, Some(b"world"), 5);
+        assert!(worker.process_transmit_queue(0));
+        assert_eq!(&output.contents()[..], b"hello world");
+        assert_eq!(tx.pop_used(&mem), Some(Vec::new()));
+        assert_eq!(tx.pop_used(&mem), Some(Vec::new()));
+        assert_eq!(tx.pop_used(&mem), None);
+    }
+
+    #[test]
+    fn transmit_long_buffer() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let output = SharedBuffer::new();
+        let (port, _rx, mut tx) = test_port(&mem, 0, output.clone(), None);
+        let mut worker = test_worker(&mem, vec![port], None);
+
+        let data: Vec<u8> = (0..3 * TRANSMIT_CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        tx.post(&mem, 0, Some(&data), data.len() as u32);
+        assert!(worker.process_transmit_queue(0));
+        assert_eq!(output.contents(), data);
+    }
+
+    #[test]
+    fn receive_from_host() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut port, mut rx, _tx) = test_port(&mem, 0, SharedBuffer::new(), None);
+        port.guest_open = true;
+        let mut worker = test_worker(&mem, vec![port], None);
+
+        // Buffers stay with the device until there is input for them.
+        rx.post(&mem, 0, None, 4);
+        rx.post(&mem, 1, None, 4);
+        assert!(!worker.process_receive_queue(0));
+        assert_eq!(rx.pop_used(&mem), None);
+
+        worker.ports[0].input.lock().unwrap().extend(b"abcdef");
+        assert!(worker.process_receive_queue(0));
+        assert_eq!(rx.pop_used(&mem), Some(b"abcd".to_vec()));
+        assert_eq!(rx.pop_used(&mem), Some(b"ef".to_vec()));
+
+        // Input waits for the guest to post more buffers.
+        worker.ports[0].input.lock().unwrap().extend(b"gh");
+        assert!(!worker.process_receive_queue(0));
+        rx.post(&mem, 2, None, 4);
+        assert!(worker.process_receive_queue(0));
+        assert_eq!(rx.pop_used(&mem), Some(b"gh".to_vec()));
+    }
+
+    #[test]
+    fn ports_route_to_distinct_sinks() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let console_output = SharedBuffer::new();
+        let agent_output = SharedBuffer::new();
+        let (console, _rx0, mut tx0) = test_port(&mem, 0, console_output.clone(), None);
+        let (agent, _rx1, mut tx1) = test_port(&mem, 0x200000, agent_output.clone(), Some("agent"));
+        let mut worker = test_worker(&mem, vec![console, agent], None);
+
+        tx0.post(&mem, 0, Some(b"boot log"), 8);
+        tx1.post(&mem, 0, Some(b"agent msg"), 9);
+        assert!(worker.process_transmit_queue(1));
+        assert!(worker.process_transmit_queue(0));
+        assert_eq!(&console_output.contents()[..], b"boot log");
+        assert_eq!(&agent_output.contents()[..], b"agent msg");
+        assert!(!worker.process_transmit_queue(2));
+    }
+
+    #[test]
+    fn control_port_ready() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (console, _rx0, _tx0) = test_port(&mem, 0, SharedBuffer::new(), None);
+        let (agent, _rx1, _tx1) = test_port(&mem, 0x200000, SharedBuffer::new(), Some("agent"));
+        let (control, mut c_rx, mut c_tx) = test_control(&mem, 0x400000);
+        let mut worker = test_worker(&mem, vec![console, agent], Some(control));
+
+        // The driver announcing itself gets every port added.
+        c_tx.post(&mem, 0, Some(&control_msg(0, VIRTIO_CONSOLE_DEVICE_READY, 1)), 8);
+        assert!(worker.process_control_transmit_queue());
+        assert_eq!(read_control_msgs(&mut worker, &mem, &mut c_rx, 0),
+                   vec![(0, VIRTIO_CONSOLE_DEVICE_ADD, 1, Vec::new()),
+                        (1, VIRTIO_CONSOLE_DEVICE_ADD, 1, Vec::new())]);
+
+        // Port 0 is the console, and both ports are connected on the host side.
+        c_tx.post(&mem, 1, Some(&control_msg(0, VIRTIO_CONSOLE_PORT_READY, 1)), 8);
+        c_tx.post(&mem, 2, Some(&control_msg(1, VIRTIO_CONSOLE_PORT_READY, 1)), 8);
+        assert!(worker.process_control_transmit_queue());
+        assert_eq!(read_control_msgs(&mut worker, &mem, &mut c_rx, 8),
+                   vec![(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, Vec::new()),
+                        (0, VIRTIO_CONSOLE_PORT_OPEN, 1, Vec::new()),
+                        (1, VIRTIO_CONSOLE_PORT_NAME, 1, b"agent".to_vec()),
+                        (1, VIRTIO_CONSOLE_PORT_OPEN, 1, Vec::new())]);
+
+        // Repeated ready messages don't queue the replies again.
+        c_tx.post(&mem, 3, Some(&control_msg(0, VIRTIO_CONSOLE_DEVICE_READY, 1)), 8);
+        c_tx.post(&mem, 4, Some(&control_msg(1, VIRTIO_CONSOLE_PORT_READY, 1)), 8);
+        worker.process_control_transmit_queue();
+        assert!(read_control_msgs(&mut worker, &mem, &mut c_rx, 16).is_empty());
+    }
+
+    #[test]
+    fn guest_open_gates_input() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (console, _rx0, _tx0) = test_port(&mem, 0, SharedBuffer::new(), None);
+        let (agent, mut rx1, _tx1) = test_port(&mem, 0x200000, SharedBuffer::new(), None);
+        let (control, _c_rx, mut c_tx) = test_control(&mem, 0x400000);
+        let mut worker = test_worker(&mem, vec![console, agent], Some(control));
+
+        // Input is held until a program in the guest opens the port.
+        rx1.post(&mem, 0, None, 16);
+        worker.ports[1].input.lock().unwrap().extend(b"early");
+        assert!(!worker.process_receive_queue(1));
+        assert_eq!(rx1.pop_used(&mem), None);
+
+        c_tx.post(&mem, 0, Some(&control_msg(1, VIRTIO_CONSOLE_PORT_OPEN, 1)), 8);
+        assert!(worker.process_control_transmit_queue());
+        assert_eq!(rx1.pop_used(&mem), Some(b"early".to_vec()));
+
+        c_tx.post(&mem, 1, Some(&control_msg(1, VIRTIO_CONSOLE_PORT_OPEN, 0)), 8);
+        assert!(worker.process_control_transmit_queue());
+        rx1.post(&mem, 1, None, 16);
+        worker.ports[1].input.lock().unwrap().extend(b"late");
+        assert!(!worker.process_receive_queue(1));
+        assert_eq!(rx1.pop_used(&mem), None);
+    }
+
+    #[test]
+    fn input_reader() {
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let evt = EventFd::new().unwrap();
+        read_input(Box::new(&b"typed"[..]), buffer.clone(), evt.try_clone().unwrap());
+        assert!(evt.read().unwrap() > 0);
+        assert_eq!(buffer.lock().unwrap().iter().cloned().collect::<Vec<u8>>(), b"typed");
+    }
+
+    #[test]
+    fn config_size() {
+        let console = Console::new(Vec::new(), Vec::new(), 132, 43);
+        assert_eq!(console.features(0) & VIRTIO_CONSOLE_F_SIZE, VIRTIO_CONSOLE_F_SIZE);
+        assert_eq!(console.features(0) & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+        assert_eq!(console.queue_max_sizes().len(), 2);
+        let mut data = [0u8; 4];
+        console.read_config(0, &mut data);
+        assert_eq!(LittleEndian::read_u16(&data[0..2]), 132);
+        assert_eq!(LittleEndian::read_u16(&data[2..4]), 43);
+        let mut rows = [0u8; 2];
+        console.read_config(2, &mut rows);
+        assert_eq!(LittleEndian::read_u16(&rows), 43);
+    }
+
+    #[test]
+    fn multiport_config() {
+        let ports = (0..3)
+            .map(|_| {
+                     ConsolePort {
+                         input: None,
+                         output: None,
+                         name: None,
+                     }
+                 })
+            .collect();
+        let console = Console::new(ports, Vec::new(), 80, 24);
+        assert_eq!(console.features(0) & VIRTIO_CONSOLE_F_MULTIPORT,
+                   VIRTIO_CONSOLE_F_MULTIPORT);
+        assert_eq!(console.queue_max_sizes().len(), 8);
+        let mut max_nr_ports = [0u8; 4];
+        console.read_config(4, &mut max_nr_ports);
+        assert_eq!(LittleEndian::read_u32(&max_nr_ports), 3);
+    }
+}
@@ -0,0 +1,611 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Virtio input device, delivering keyboard and pointer events injected by the host to the guest.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sys_util::{EventFd, GuestMemory, PollContext, PollToken};
+use vm_control::{InputEvent, INPUT_EVENT_SIZE, MAX_INPUT_EVENTS};
+
+use super::{VirtioDevice, Queue, INTERRUPT_STATUS_USED_RING, TYPE_INPUT};
+
+const QUEUE_SIZE: u16 = 64;
+// The event queue and the status queue.
+const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+// Values of the select field of struct virtio_input_config.
+const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// Size of struct virtio_input_config: select, subsel, size, 5 reserved bytes and a 128 byte union.
+const CONFIG_HEADER_SIZE: usize = 8;
+const CONFIG_DATA_SIZE: usize = 128;
+const CONFIG_SIZE: usize = CONFIG_HEADER_SIZE + CONFIG_DATA_SIZE;
+
+// Events injected by the host but not yet taken by the guest are dropped past this many.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Event types, codes and properties from linux/input-event-codes.h.
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const KEY_ESC: u16 = 1;
+const KEY_MICMUTE: u16 = 248;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const INPUT_PROP_POINTER: u16 = 0x00;
+
+/// The range of an absolute axis, as in struct virtio_input_absinfo.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AbsInfo {
+    pub min: u32,
+    pub max: u32,
+    pub fuzz: u32,
+    pub flat: u32,
+    pub res: u32,
+}
+
+/// Describes what a virtio input device reports to the guest: its name, the input properties and
+/// the event codes it supports for each event type.
+#[derive(Clone, Debug)]
+pub struct InputConfig {
+    name: String,
+    serial: String,
+    properties: Vec<u16>,
+    events: BTreeMap<u16, Vec<u16>>,
+    abs_info: BTreeMap<u16, AbsInfo>,
+}
+
+impl InputConfig {
+    /// Creates a configuration for a device with the given name that supports no events.
+    pub fn new(name: &str) -> InputConfig {
+        InputConfig {
+            name: name.to_string(),
+            serial: String::new(),
+            properties: Vec::new(),
+            events: BTreeMap::new(),
+            abs_info: BTreeMap::new(),
+        }
+    }
+
+    /// A keyboard that reports every key up to `KEY_MICMUTE`.
+    pub fn keyboard() -> InputConfig {
+        let mut config = InputConfig::new("crosvm virtio keyboard");
+        let keys: Vec<u16> = (KEY_ESC..KEY_MICMUTE + 1).collect();
+        config.add_events(EV_KEY, &keys);
+        config
+    }
+
+    /// A mouse with three buttons and a wheel that reports relative motion.
+    pub fn mouse() -> InputConfig {
+        let mut config = InputConfig::new("crosvm virtio mouse");
+        config.add_property(INPUT_PROP_POINTER);
+        config.add_events(EV_KEY, &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]);
+        config.add_events(EV_REL, &[REL_X, REL_Y, REL_WHEEL]);
+        config
+    }
+
+    /// A pointer with three buttons that reports absolute positions within a `width` by `height`
+    /// area, so the host can place the guest's cursor exactly.
+    pub fn tablet(width: u32, height: u32) -> InputConfig {
+        let mut config = InputConfig::new("crosvm virtio tablet");
+        config.add_events(EV_KEY, &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]);
+        config.add_abs_axis(ABS_X,
+                            AbsInfo {
+                                max: width.saturating_sub(1),
+                                ..Default::default()
+                            });
+        config.add_abs_axis(ABS_Y,
+                            AbsInfo {
+                                max: height.saturating_sub(1),
+                                ..Default::default()
+                            });
+        config
+    }
+
+    /// Sets the serial number reported to the guest.
+    pub fn set_serial(&mut self, serial: &str) {
+        self.serial = serial.to_string();
+    }
+
+    /// Adds an input property, such as `INPUT_PROP_POINTER`.
+    pub fn add_property(&mut self, property: u16) {
+        self.properties.push(property);
+    }
+
+    /// Adds `codes` to the event codes supported for event type `ev_type`.
+    pub fn add_events(&mut self, ev_type: u16, codes: &[u16]) {
+        self.events
+            .entry(ev_type)
+            .or_insert_with(Vec::new)
+            .extend_from_slice(codes);
+    }
+
+    /// Adds the absolute axis `axis` with the range given by `info`.
+    pub fn add_abs_axis(&mut self, axis: u16, info: AbsInfo) {
+        self.add_events(EV_ABS, &[axis]);
+        self.abs_info.insert(axis, info);
+    }
+
+    // Fills `data` with the union of struct virtio_input_config for `select` and `subsel`,
+    // returning the number of bytes that are valid.
+    fn fill(&self, select: u8, subsel: u8, data: &mut [u8; CONFIG_DATA_SIZE]) -> usize {
+        match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => fill_string(&self.name, data),
+            VIRTIO_INPUT_CFG_ID_SERIAL if subsel == 0 => fill_string(&self.serial, data),
+            VIRTIO_INPUT_CFG_PROP_BITS if subsel == 0 => fill_bitmap(&self.properties, data),
+            VIRTIO_INPUT_CFG_EV_BITS => {
+                match self.events.get(&(subsel as u16)) {
+                    Some(codes) => fill_bitmap(codes, data),
+                    None => 0,
+                }
+            }
+            VIRTIO_INPUT_CFG_ABS_INFO => {
+                match self.abs_info.get(&(subsel as u16)) {
+                    Some(info) => {
+                        let fields = [info.min, info.max, info.fuzz, info.flat, info.res];
+                        for (dst, &field) in data.chunks_mut(4).zip(fields.iter()) {
+                            LittleEndian::write_u32(dst, field);
+                        }
+                        fields.len() * 4
+                    }
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+}
+
+fn fill_string(s: &str, data: &mut [u8; CONFIG_DATA_SIZE]) -> usize {
+    let len = cmp::min(s.len(), CONFIG_DATA_SIZE);
+    data[..len].copy_from_slice(&s.as_bytes()[..len]);
+    len
+}
+
+// Sets the bits of `data` for each of `bits` that fits, returning the number of bytes up to the
+// last one with a bit set.
+fn fill_bitmap(bits: &[u16], data: &mut [u8; CONFIG_DATA_SIZE]) -> usize {
+    let mut len = 0;
+    for &bit in bits {
+        let byte = bit as usize / 8;
+        if byte < CONFIG_DATA_SIZE {
+            data[byte] |= 1 << (bit % 8);
+            len = cmp::max(len, byte + 1);
+        }
+    }
+    len
+}
+
+struct Worker {
+    mem: GuestMemory,
+    event_queue: Queue,
+    status_queue: Queue,
+    event_socket: UnixDatagram,
+    pending: VecDeque<InputEvent>,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+}
+
+impl Worker {
+    // Moves the events sent by the host on the event socket to the pending events.
+    fn receive_events(&mut self) {
+        let mut buf = [0u8; MAX_INPUT_EVENTS * INPUT_EVENT_SIZE];
+        let count = match self.event_socket.recv(&mut buf) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("failed to receive input events: {}", e);
+                return;
+            }
+        };
+        // Events are dropped a whole batch at a time so the guest never sees part of a report.
+        if self.pending.len() + count / INPUT_EVENT_SIZE > MAX_PENDING_EVENTS {
+            warn!("dropping input events the guest isn't taking");
+            return;
+        }
+        self.pending
+            .extend(buf[..count]
+                        .chunks(INPUT_EVENT_SIZE)
+                        .filter(|e| e.len() == INPUT_EVENT_SIZE)
+                        .map(InputEvent::from_bytes));
+    }
+
+    // Writes pending events into the buffers posted by the guest, one event per buffer.
+    fn process_event_queue(&mut self) -> bool {
+        let mut used = false;
+        while let Some(event) = self.pending.pop_front() {
+            let avail_desc = match self.event_queue.iter(&self.mem).next() {
+                Some(d) => d,
+                None => {
+                    self.pending.push_front(event);
+                    break;
+                }
+            };
+            let index = avail_desc.index;
+            let mut len = 0;
+            if !avail_desc.is_write_only() || (avail_desc.len as usize) < INPUT_EVENT_SIZE {
+                error!("virtio input event buffer is too small or read only");
+            } else {
+                match self.mem.write_slice_at_addr(&event.to_bytes(), avail_desc.addr) {
+                    Ok(_) => len = INPUT_EVENT_SIZE as u32,
+                    Err(e) => error!("failed to write input event to guest: {:?}", e),
+                }
+            }
+            self.event_queue.add_used(&self.mem, index, len);
+            used = true;
+        }
+        used
+    }
+
+    // Takes the status events, such as keyboard LED changes, sent by the guest. They are ignored.
+    fn process_status_queue(&mut self) -> bool {
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in self.status_queue.iter(&self.mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+        }
+
+        for &index in &used_desc_heads[..used_count] {
+            self.status_queue.add_used(&self.mem, index, 0);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).unwrap();
+    }
+
+    fn run(&mut self, event_queue_evt: EventFd, status_queue_evt: EventFd, kill_evt: EventFd) {
+        #[derive(PollToken)]
+        enum Token {
+            EventQueueAvailable,
+            StatusQueueAvailable,
+            EventSocket,
+            Kill,
+        }
+
+        let poll_ctx: PollContext<Token> =
+            match PollContext::new()
+                      .and_then(|pc| {
+                                    pc.add(&event_queue_evt, Token::EventQueueAvailable)
+                                        .and(Ok(pc))
+                                })
+                      .and_then(|pc| {
+                                    pc.add(&status_queue_evt, Token::StatusQueueAvailable)
+                                        .and(Ok(pc))
+                                })
+                      .and_then(|pc| pc.add(&self.event_socket, Token::EventSocket).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&kill_evt, Token::Kill).and(Ok(pc))) {
+                Ok(pc) => pc,
+                Err(e) => {
+                    error!("failed creating PollContext: {:?}", e);
+                    return;
+                }
+            };
+
+        'poll: loop {
+            let events = match poll_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {:?}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter_readable() {
+                match event.token() {
+                    Token::EventQueueAvailable => {
+                        if let Err(e) = event_queue_evt.read() {
+                            error!("failed reading event queue EventFd: {:?}", e);
+                            break 'poll;
+                        }
+                        needs_interrupt |= self.process_event_queue();
+                    }
+                    Token::StatusQueueAvailable => {
+                        if let Err(e) = status_queue_evt.read() {
+                            error!("failed reading status queue EventFd: {:?}", e);
+                            break 'poll;
+                        }
+                        needs_interrupt |= self.process_status_queue();
+                    }
+                    Token::EventSocket => {
+                        self.receive_events();
+                        needs_interrupt |= self.process_event_queue();
+                    }
+                    Token::Kill => break 'poll,
+                }
+            }
+            for event in events.iter_hungup() {
+                if !event.readable() {
+                    if let Token::EventSocket = event.token() {
+                        // If this call fails, the event socket was already removed from the
+                        // PollContext.
+                        let _ = poll_ctx.delete(&self.event_socket);
+                    }
+                }
+            }
+            if needs_interrupt {
+                self.signal_used_queue();
+            }
+        }
+    }
+}
+
+/// Virtio input device that delivers the events sent by the host over a socket to the guest.
+pub struct Input {
+    kill_evt: Option<EventFd>,
+    config: InputConfig,
+    select: u8,
+    subsel: u8,
+    event_socket: Option<UnixDatagram>,
+}
+
+impl Input {
+    /// Create a new virtio input device.
+    ///
+    /// # Arguments
+    /// * `config` - What the device reports supporting to the guest.
+    /// * `event_socket` - Receives datagrams of `InputEvent`s, each in the layout of
+    ///                    `InputEvent::to_bytes`, to deliver to the guest.
+    pub fn new(config: InputConfig, event_socket: UnixDatagram) -> Input {
+        Input {
+            kill_evt: None,
+            config: config,
+            select: VIRTIO_INPUT_CFG_UNSET,
+            subsel: 0,
+            event_socket: Some(event_socket),
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Input {
+    fn keep_fds(&self) -> Vec<RawFd> {
+        match self.event_socket {
+            Some(ref s) => vec![s.as_raw_fd()],
+            None => Vec::new(),
+        }
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_INPUT
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut union = [0u8; CONFIG_DATA_SIZE];
+        let size = self.config.fill(self.select, self.subsel, &mut union);
+        let mut config = [0u8; CONFIG_SIZE];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = size as u8;
+        config[CONFIG_HEADER_SIZE..].copy_from_slice(&union);
+        let start = offset as usize;
+        if let Some(end) = start.checked_add(data.len()) {
+            if end <= CONFIG_SIZE {
+                data.copy_from_slice(&config[start..end]);
+            }
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only select and subsel are writable.
+        for (i, &b) in data.iter().enumerate() {
+            match offset.checked_add(i as u64) {
+                Some(0) => self.select = b,
+                Some(1) => self.subsel = b,
+                _ => {
+                    warn!("input: dropped write to read-only config: 0x{:x}", offset + i as u64);
+                }
+            }
+        }
+    }
+
+    fn activate(&mut self,
+                mem: GuestMemory,
+                interrupt_evt: EventFd,
+                status: Arc<AtomicUsize>,
+                mut queues: Vec<Queue>,
+                mut queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to create kill EventFd pair: {:?}", e);
+                    return;
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        let event_socket = match self.event_socket.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let worker_result = thread::Builder::new()
+            .name("virtio_input".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    mem: mem,
+                    event_queue: queues.remove(0),
+                    status_queue: queues.remove(0),
+                    event_socket: event_socket,
+                    pending: VecDeque::new(),
+                    interrupt_status: status,
+                    interrupt_evt: interrupt_evt,
+                };
+                let event_queue_evt = queue_evts.remove(0);
+                let status_queue_evt = queue_evts.remove(0);
+                worker.run(event_queue_evt, status_queue_evt, kill_evt);
+            });
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_input worker: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_queue::TestQueue;
+    use sys_util::GuestAddress;
+
+    fn test_worker(mem: &GuestMemory) -> (Worker, TestQueue, UnixDatagram) {
+        let (events, event_queue) = TestQueue::new(mem, 0, QUEUE_SIZE);
+        let (_status, status_queue) = TestQueue::new(mem, 0x100000, QUEUE_SIZE);
+        let (host_socket, event_socket) = UnixDatagram::pair().unwrap();
+        let worker = Worker {
+            mem: mem.clone(),
+            event_queue: event_queue,
+            status_queue: status_queue,
+            event_socket: event_socket,
+            pending: VecDeque::new(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+        };
+        (worker, events, host_socket)
+    }
+
+    fn send_events(socket: &UnixDatagram, events: &[InputEvent]) {
+        let mut buf = Vec::new();
+        for event in events {
+            buf.extend_from_slice(&event.to_bytes());
+        }
+        socket.send(&buf).unwrap();
+    }
+
+    #[test]
+    fn key_event() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut worker, mut events, host_socket) = test_worker(&mem);
+
+        // KEY_A pressed, then a SYN_REPORT.
+        send_events(&host_socket,
+                    &[InputEvent {
+                          type_: EV_KEY,
+                          code: 30,
+                          value: 1,
+                      },
+                      InputEvent::default()]);
+        worker.receive_events();
+        // The events wait for the guest to post buffers.
+        assert!(!worker.process_event_queue());
+        events.post(&mem, 0, None, 8);
+        events.post(&mem, 1, None, 8);
+        assert!(worker.process_event_queue());
+        assert_eq!(events.pop_used(&mem), Some(vec![1, 0, 30, 0, 1, 0, 0, 0]));
+        assert_eq!(events.pop_used(&mem), Some(vec![0; 8]));
+        assert_eq!(events.pop_used(&mem), None);
+    }
+
+    #[test]
+    fn motion_event() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut worker, mut events, host_socket) = test_worker(&mem);
+
+        events.post(&mem, 0, None, 8);
+        events.post(&mem, 1, None, 8);
+        send_events(&host_socket,
+                    &[InputEvent {
+                          type_: EV_REL,
+                          code: REL_X,
+                          value: -3,
+                      },
+                      InputEvent {
+                          type_: EV_ABS,
+                          code: ABS_Y,
+                          value: 0x1234,
+                      }]);
+        worker.receive_events();
+        assert!(worker.process_event_queue());
+        assert_eq!(events.pop_used(&mem),
+                   Some(vec![2, 0, 0, 0, 0xfd, 0xff, 0xff, 0xff]));
+        assert_eq!(events.pop_used(&mem), Some(vec![3, 0, 1, 0, 0x34, 0x12, 0, 0]));
+    }
+
+    fn select(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        input.write_config(0, &[select, subsel]);
+        let mut header = [0u8; CONFIG_HEADER_SIZE];
+        input.read_config(0, &mut header);
+        assert_eq!(header[0], select);
+        assert_eq!(header[1], subsel);
+        let mut data = vec![0u8; header[2] as usize];
+        input.read_config(CONFIG_HEADER_SIZE as u64, &mut data);
+        data
+    }
+
+    #[test]
+    fn config_keyboard() {
+        let (_host_socket, event_socket) = UnixDatagram::pair().unwrap();
+        let mut input = Input::new(InputConfig::keyboard(), event_socket);
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_UNSET, 0), Vec::<u8>::new());
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_ID_NAME, 0),
+                   b"crosvm virtio keyboard".to_vec());
+        let keys = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(keys.len(), KEY_MICMUTE as usize / 8 + 1);
+        // KEY_RESERVED isn't reported, KEY_ESC and KEY_A are.
+        assert_eq!(keys[0] & 0x03, 0x02);
+        assert_ne!(keys[30 / 8] & (1 << (30 % 8)), 0);
+        assert_eq!(select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+                   Vec::<u8>::new());
+    }
+
+    #[test]
+    fn config_tablet() {
+        let (_host_socket, event_socket) = UnixDatagram::pair().unwrap();
+        let mut input = Input::new(InputConfig::tablet(1920, 1080), event_socket);
+        let abs = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8);
+        assert_eq!(abs, vec![0x03]);
+        let x = select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_X as u8);
+        assert_eq!(x.len(), 20);
+        assert_eq!(LittleEndian::read_u32(&x[0..4]), 0);
+        assert_eq!(LittleEndian::read_u32(&x[4..8]), 1919);
+        let y = select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(LittleEndian::read_u32(&y[4..8]), 1079);
+        let buttons = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(buttons.len(), BTN_MIDDLE as usize / 8 + 1);
+        assert_eq!(buttons[BTN_LEFT as usize / 8], 0x07);
+    }
+}
@@ -7,15 +7,23 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use byteorder::{ByteOrder, LittleEndian};
+use libc::EINVAL;
 
 use super::*;
 use BusDevice;
-use sys_util::{Result, EventFd, GuestAddress, GuestMemory};
+use sys_util::{self, Result, EventFd, GuestAddress, GuestMemory, MmapError};
 
 const VENDOR_ID: u32 = 0;
 
 const MMIO_MAGIC_VALUE: u32 = 0x74726976;
 const MMIO_VERSION: u32 = 2;
+// What the length and base registers read as when the selected shared memory region doesn't exist.
+const NO_SHM_REGION: u64 = !0;
+
+/// The default for the most descriptor chains a device worker takes from a queue before it goes
+/// back to waiting for events, so that a guest that keeps a queue full can't keep the worker from
+/// its other queues or from being stopped. The rest of the chains are taken on the next wake.
+pub const MAX_CHAINS_PER_PASS: usize = 64;
 
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
@@ -53,25 +61,100 @@ pub trait VirtioDevice: Send {
         let _ = data;
     }
 
-    /// Writes to this device configuration space at `offset`.
+    /// Writes to this device configuration space at `offset`. Devices without writable fields
+    /// don't need to implement this, the write is dropped.
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        let _ = offset;
-        let _ = data;
+        debug!("virtio device type {} dropped config write: 0x{:x}:0x{:x}",
+               self.device_type(),
+               offset,
+               data.len());
     }
 
     /// Activates this device for real usage.
+    ///
+    /// A transport that gives each queue its own interrupt vector, such as with MSI-X, passes one
+    /// eventfd per queue in `queue_interrupt_evts`. The device signals the eventfd of the queue it
+    /// used instead of `interrupt_evt`, and doesn't touch `status`. Without them, every queue
+    /// shares `interrupt_evt`.
+    ///
+    /// A device worker takes at most `max_chains_per_pass` descriptor chains from a queue each
+    /// time it wakes.
     fn activate(&mut self,
                 mem: GuestMemory,
                 interrupt_evt: EventFd,
                 status: Arc<AtomicUsize>,
                 queues: Vec<Queue>,
-                queue_evts: Vec<EventFd>);
+                queue_evts: Vec<EventFd>,
+                queue_interrupt_evts: Option<Vec<EventFd>>,
+                max_chains_per_pass: usize);
 
     /// Optionally deactivates this device and returns ownership of the guest memory map, interrupt
     /// event, and queue events.
     fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
         None
     }
+
+    /// Returns the guest physical address and length of the shared memory region with `id`, where
+    /// the device maps host memory for the guest to use, or None if there is no such region.
+    fn get_shared_memory_region(&self, id: u32) -> Option<(GuestAddress, u64)> {
+        let _ = id;
+        None
+    }
+}
+
+// The transport only knows about the first two pages of feature bits.
+const NUM_FEATURE_PAGES: usize = 2;
+
+/// The feature bits a guest driver acknowledged for a virtio device.
+///
+/// Each page of bits is one of a set of `SharedCounters`, so clones still see the driver's
+/// acknowledgements after the `MmioDevice` that records them has been moved into a jailed process.
+#[derive(Clone)]
+pub struct AckedFeatures {
+    device_type: u32,
+    pages: SharedCounters,
+}
+
+impl AckedFeatures {
+    fn new(device_type: u32) -> Result<AckedFeatures> {
+        let pages = SharedCounters::new(NUM_FEATURE_PAGES)
+            .map_err(|e| match e {
+                         MmapError::SystemCallFailed(e) => e,
+                         _ => sys_util::Error::new(EINVAL),
+                     })?;
+        Ok(AckedFeatures {
+               device_type: device_type,
+               pages: pages,
+           })
+    }
+
+    fn page(&self, page: usize) -> &AtomicUsize {
+        self.pages.get(page)
+    }
+
+    fn set_page(&self, page: u32, value: u32) {
+        if (page as usize) < NUM_FEATURE_PAGES {
+            self.page(page as usize).store(value as usize, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&self) {
+        for page in 0..NUM_FEATURE_PAGES {
+            self.page(page).store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// The virtio device type of the device the features belong to.
+    pub fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    /// Returns the feature bits acknowledged so far.
+    pub fn get(&self) -> u64 {
+        let low = self.page(0).load(Ordering::Relaxed) as u64 & 0xffffffff;
+        let high = self.page(1).load(Ordering::Relaxed) as u64 & 0xffffffff;
+        low | high << 32
+    }
 }
 
 /// Implements the
@@ -94,7 +177,9 @@ pub struct MmioDevice {
 
     features_select: u32,
     acked_features_select: u32,
+    acked_features: AckedFeatures,
     queue_select: u32,
+    shm_select: u32,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<EventFd>,
     driver_status: u32,
@@ -102,6 +187,7 @@ pub struct MmioDevice {
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<GuestMemory>,
+    max_chains_per_pass: usize,
 }
 
 impl MmioDevice {
@@ -116,12 +202,15 @@ impl MmioDevice {
             .iter()
             .map(|&s| Queue::new(s))
             .collect();
+        let acked_features = AckedFeatures::new(device.device_type())?;
         Ok(MmioDevice {
                device: device,
                device_activated: false,
                features_select: 0,
                acked_features_select: 0,
+               acked_features: acked_features,
                queue_select: 0,
+               shm_select: 0,
                interrupt_status: Arc::new(AtomicUsize::new(0)),
                interrupt_evt: Some(EventFd::new()?),
                driver_status: 0,
@@ -129,9 +218,16 @@ impl MmioDevice {
                queues: queues,
                queue_evts: queue_evts,
                mem: Some(mem),
+               max_chains_per_pass: MAX_CHAINS_PER_PASS,
            })
     }
 
+    /// Sets the most descriptor chains the device worker takes from a queue each time it wakes.
+    /// Must be called before the guest activates the device to take effect.
+    pub fn set_max_chains_per_pass(&mut self, max_chains_per_pass: usize) {
+        self.max_chains_per_pass = max_chains_per_pass;
+    }
+
     /// Gets the list of queue events that must be triggered whenever the VM writes to
     /// `virtio::NOTIFY_REG_OFFSET` past the MMIO base. Each event must be triggered when the
     /// value being written equals the index of the event in this list.
@@ -144,6 +240,12 @@ impl MmioDevice {
         self.interrupt_evt.as_ref()
     }
 
+    /// Gets a handle on the feature bits the guest driver acknowledges for this device, which keeps
+    /// working after this transport is moved into the bus.
+    pub fn acked_features(&self) -> AckedFeatures {
+        self.acked_features.clone()
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
         self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED == 0
@@ -166,6 +268,14 @@ impl MmioDevice {
         }
     }
 
+    // The length and base address of the selected shared memory region.
+    fn shm_region(&self) -> (u64, u64) {
+        match self.device.get_shared_memory_region(self.shm_select) {
+            Some((base, len)) => (len, base.offset()),
+            None => (NO_SHM_REGION, NO_SHM_REGION),
+        }
+    }
+
     fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) -> bool {
         if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
             f(queue);
@@ -193,6 +303,10 @@ impl BusDevice for MmioDevice {
                     0x44 => self.with_queue(0, |q| q.ready as u32),
                     0x60 => self.interrupt_status.load(Ordering::SeqCst) as u32,
                     0x70 => self.driver_status,
+                    0xb0 => self.shm_region().0 as u32,
+                    0xb4 => (self.shm_region().0 >> 32) as u32,
+                    0xb8 => self.shm_region().1 as u32,
+                    0xbc => (self.shm_region().1 >> 32) as u32,
                     0xfc => self.config_generation,
                     _ => {
                         warn!("unknown virtio mmio register read: 0x{:x}", offset);
@@ -224,7 +338,12 @@ impl BusDevice for MmioDevice {
                 let v = LittleEndian::read_u32(data);
                 match offset {
                     0x14 => self.features_select = v,
-                    0x20 => self.device.ack_features(self.acked_features_select, v),
+                    0x20 => {
+                        // Only features the device offered can be acknowledged.
+                        let offered = self.device.features(self.acked_features_select);
+                        self.acked_features.set_page(self.acked_features_select, v & offered);
+                        self.device.ack_features(self.acked_features_select, v)
+                    }
                     0x24 => self.acked_features_select = v,
                     0x30 => self.queue_select = v,
                     0x38 => mut_q = self.with_queue_mut(|q| q.size = v as u16),
@@ -233,13 +352,20 @@ impl BusDevice for MmioDevice {
                         self.interrupt_status
                             .fetch_and(!(v as usize), Ordering::SeqCst);
                     }
-                    0x70 => self.driver_status = v,
+                    0x70 => {
+                        // Writing zero resets the device, including the negotiated features.
+                        if v == 0 {
+                            self.acked_features.clear();
+                        }
+                        self.driver_status = v
+                    }
                     0x80 => mut_q = self.with_queue_mut(|q| lo(&mut q.desc_table, v)),
                     0x84 => mut_q = self.with_queue_mut(|q| hi(&mut q.desc_table, v)),
                     0x90 => mut_q = self.with_queue_mut(|q| lo(&mut q.avail_ring, v)),
                     0x94 => mut_q = self.with_queue_mut(|q| hi(&mut q.avail_ring, v)),
                     0xa0 => mut_q = self.with_queue_mut(|q| lo(&mut q.used_ring, v)),
                     0xa4 => mut_q = self.with_queue_mut(|q| hi(&mut q.used_ring, v)),
+                    0xac => self.shm_select = v,
                     _ => {
                         warn!("unknown virtio mmio register write: 0x{:x}", offset);
                         return;
@@ -265,10 +391,243 @@ impl BusDevice for MmioDevice {
                                   interrupt_evt,
                                   self.interrupt_status.clone(),
                                   self.queues.clone(),
-                                  self.queue_evts.split_off(0));
+                                  self.queue_evts.split_off(0),
+                                  None,
+                                  self.max_chains_per_pass);
                     self.device_activated = true;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE_SIZES: &'static [u16] = &[16];
+
+    struct DummyDevice;
+
+    impl VirtioDevice for DummyDevice {
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+
+        fn device_type(&self) -> u32 {
+            TYPE_NET
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn features(&self, page: u32) -> u32 {
+            if page == 0 { 0x3 } else { 0 }
+        }
+
+        fn activate(&mut self,
+                    _mem: GuestMemory,
+                    _interrupt_evt: EventFd,
+                    _status: Arc<AtomicUsize>,
+                    _queues: Vec<Queue>,
+                    _queue_evts: Vec<EventFd>,
+                    _queue_interrupt_evts: Option<Vec<EventFd>>,
+                    _max_chains_per_pass: usize) {
+        }
+    }
+
+    // Has a one byte writable config field at offset 2.
+    struct ConfigDevice {
+        field: Arc<AtomicUsize>,
+    }
+
+    impl VirtioDevice for ConfigDevice {
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+
+        fn device_type(&self) -> u32 {
+            TYPE_INPUT
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            if offset == 2 && data.len() == 1 {
+                data[0] = self.field.load(Ordering::SeqCst) as u8;
+            }
+        }
+
+        fn write_config(&mut self, offset: u64, data: &[u8]) {
+            if offset == 2 && data.len() == 1 {
+                self.field.store(data[0] as usize, Ordering::SeqCst);
+            }
+        }
+
+        fn activate(&mut self,
+                    _mem: GuestMemory,
+                    _interrupt_evt: EventFd,
+                    _status: Arc<AtomicUsize>,
+                    _queues: Vec<Queue>,
+                    _queue_evts: Vec<EventFd>,
+                    _queue_interrupt_evts: Option<Vec<EventFd>>,
+                    _max_chains_per_pass: usize) {
+        }
+    }
+
+    // Has a shared memory region with id 1.
+    struct ShmDevice;
+
+    impl VirtioDevice for ShmDevice {
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+
+        fn device_type(&self) -> u32 {
+            TYPE_WL
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn activate(&mut self,
+                    _mem: GuestMemory,
+                    _interrupt_evt: EventFd,
+                    _status: Arc<AtomicUsize>,
+                    _queues: Vec<Queue>,
+                    _queue_evts: Vec<EventFd>,
+                    _queue_interrupt_evts: Option<Vec<EventFd>>,
+                    _max_chains_per_pass: usize) {
+        }
+
+        fn get_shared_memory_region(&self, id: u32) -> Option<(GuestAddress, u64)> {
+            if id == 1 {
+                Some((GuestAddress(0x1_2345_0000), 0x2_0000_1000))
+            } else {
+                None
+            }
+        }
+    }
+
+    // Records the chain cap it is activated with.
+    struct CapDevice {
+        max_chains: Arc<AtomicUsize>,
+    }
+
+    impl VirtioDevice for CapDevice {
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+
+        fn device_type(&self) -> u32 {
+            TYPE_NET
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn activate(&mut self,
+                    _mem: GuestMemory,
+                    _interrupt_evt: EventFd,
+                    _status: Arc<AtomicUsize>,
+                    _queues: Vec<Queue>,
+                    _queue_evts: Vec<EventFd>,
+                    _queue_interrupt_evts: Option<Vec<EventFd>>,
+                    max_chains_per_pass: usize) {
+            self.max_chains.store(max_chains_per_pass, Ordering::SeqCst);
+        }
+    }
+
+    fn read_reg(device: &mut MmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_reg(device: &mut MmioDevice, offset: u64, v: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, v);
+        device.write(offset, &data);
+    }
+
+    #[test]
+    fn acked_features() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let mut device = MmioDevice::new(mem, Box::new(DummyDevice)).unwrap();
+        let features = device.acked_features();
+        assert_eq!(features.device_type(), TYPE_NET);
+        assert_eq!(features.get(), 0);
+
+        write_reg(&mut device, 0x70, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        write_reg(&mut device, 0x24, 0);
+        write_reg(&mut device, 0x20, 0x2);
+        write_reg(&mut device, 0x70, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK);
+        assert_eq!(features.get(), 0x2);
+
+        // Features the device didn't offer are dropped.
+        write_reg(&mut device, 0x20, 0x6);
+        write_reg(&mut device, 0x24, 1);
+        write_reg(&mut device, 0x20, 0x1);
+        assert_eq!(features.get(), 0x2);
+
+        // A reset forgets the negotiated features.
+        write_reg(&mut device, 0x70, 0);
+        assert_eq!(features.get(), 0);
+    }
+
+    #[test]
+    fn config_write() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let field = Arc::new(AtomicUsize::new(0));
+        let config_device = ConfigDevice { field: field.clone() };
+        let mut device = MmioDevice::new(mem, Box::new(config_device)).unwrap();
+        device.write(0x102, &[7]);
+        assert_eq!(field.load(Ordering::SeqCst), 7);
+        let mut data = [0];
+        device.read(0x102, &mut data);
+        assert_eq!(data, [7]);
+    }
+
+    #[test]
+    fn max_chains_per_pass() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let max_chains = Arc::new(AtomicUsize::new(0));
+        let cap_device = CapDevice { max_chains: max_chains.clone() };
+        let mut device = MmioDevice::new(mem, Box::new(cap_device)).unwrap();
+        device.set_max_chains_per_pass(8);
+
+        write_reg(&mut device, 0x30, 0);
+        write_reg(&mut device, 0x38, 16);
+        write_reg(&mut device, 0x80, 0x1000);
+        write_reg(&mut device, 0x90, 0x2000);
+        write_reg(&mut device, 0xa0, 0x3000);
+        write_reg(&mut device, 0x44, 1);
+        write_reg(&mut device, 0x70, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK);
+        write_reg(&mut device,
+                  0x70,
+                  DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK);
+        assert_eq!(max_chains.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let mut device = MmioDevice::new(mem, Box::new(ShmDevice)).unwrap();
+        write_reg(&mut device, 0xac, 1);
+        assert_eq!(read_reg(&mut device, 0xb0), 0x1000);
+        assert_eq!(read_reg(&mut device, 0xb4), 0x2);
+        assert_eq!(read_reg(&mut device, 0xb8), 0x2345_0000);
+        assert_eq!(read_reg(&mut device, 0xbc), 0x1);
+
+        // A region that doesn't exist has a length and base of all ones.
+        write_reg(&mut device, 0xac, 0);
+        for &offset in [0xb0, 0xb4, 0xb8, 0xbc].iter() {
+            assert_eq!(read_reg(&mut device, offset), 0xffff_ffff);
+        }
+    }
+}
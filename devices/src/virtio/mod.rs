@@ -8,19 +8,36 @@ mod balloon;
 mod queue;
 mod mmio;
 mod block;
+mod console;
+mod input;
 mod rng;
 mod net;
+mod p9;
+mod stats;
+mod worker_thread;
 mod wl;
+mod vsock;
+#[cfg(test)]
+mod test_queue;
 
 pub mod vhost;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sys_util::EventFd;
+
 pub use self::balloon::*;
 pub use self::queue::*;
 pub use self::mmio::*;
 pub use self::block::*;
+pub use self::console::*;
+pub use self::input::*;
 pub use self::rng::*;
 pub use self::net::*;
+pub use self::p9::*;
+pub use self::stats::*;
 pub use self::wl::*;
+pub use self::vsock::*;
 
 const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
@@ -29,16 +46,34 @@ const DEVICE_FEATURES_OK: u32 = 0x08;
 const DEVICE_FAILED: u32 = 0x80;
 
 // Types taken from linux/virtio_ids.h
-const TYPE_NET: u32 = 1;
-const TYPE_BLOCK: u32 = 2;
-const TYPE_RNG: u32 = 4;
-const TYPE_BALLOON: u32 = 5;
-const TYPE_VSOCK: u32 = 19;
-const TYPE_WL: u32 = 30;
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+pub const TYPE_RNG: u32 = 4;
+pub const TYPE_BALLOON: u32 = 5;
+pub const TYPE_9P: u32 = 9;
+pub const TYPE_INPUT: u32 = 18;
+pub const TYPE_VSOCK: u32 = 19;
+pub const TYPE_WL: u32 = 30;
 
 const INTERRUPT_STATUS_USED_RING: u32 = 0x1;
 const INTERRUPT_STATUS_CONFIG_CHANGED: u32 = 0x2;
 
+// Interrupts the guest for new used buffers in queue `queue_index`. If the transport gave each queue
+// its own eventfd, only that queue's is signaled. Otherwise the shared `interrupt_evt` is, with the
+// used ring bit set in the interrupt status.
+fn signal_used_queue(interrupt_status: &AtomicUsize,
+                     interrupt_evt: &EventFd,
+                     queue_interrupt_evts: &Option<Vec<EventFd>>,
+                     queue_index: usize) {
+    if let Some(evt) = queue_interrupt_evts.as_ref().and_then(|evts| evts.get(queue_index)) {
+        evt.write(1).unwrap();
+        return;
+    }
+    interrupt_status.fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+    interrupt_evt.write(1).unwrap();
+}
+
 /// Offset from the base MMIO address of a virtio device used by the guest to notify the device of
 /// queue events.
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
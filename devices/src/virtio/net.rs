@@ -7,9 +7,10 @@ use std::mem;
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 
+use byteorder::{ByteOrder, LittleEndian};
 use libc::EAGAIN;
 use net_sys;
 use net_util::{Error as TapError, MacAddress, TapT};
@@ -18,7 +19,9 @@ use sys_util::{EventFd, GuestMemory, PollContext, PollToken};
 use virtio_sys::{vhost, virtio_net};
 use virtio_sys::virtio_net::virtio_net_hdr_v1;
 
-use super::{VirtioDevice, Queue, INTERRUPT_STATUS_USED_RING, TYPE_NET};
+use super::{VirtioDevice, Queue, DescriptorChain, DeviceCounters, signal_used_queue,
+            MAX_CHAINS_PER_PASS, TYPE_NET};
+use super::worker_thread::{WorkerThread, WORKER_EXIT_TIMEOUT_MS};
 
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
@@ -26,6 +29,11 @@ use super::{VirtioDevice, Queue, INTERRUPT_STATUS_USED_RING, TYPE_NET};
 const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
 const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+// Indices of the receive and transmit queues.
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+// Offset of `num_buffers` in `virtio_net_hdr_v1`.
+const NUM_BUFFERS_OFFSET: usize = 10;
 
 #[derive(Debug)]
 pub enum NetError {
@@ -60,82 +68,162 @@ struct Worker<T: TapT> {
     tap: T,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
+    // One for each of the rx and tx queues, if they have their own interrupt.
+    queue_interrupt_evts: Option<Vec<EventFd>>,
     rx_buf: [u8; MAX_BUFFER_SIZE],
     rx_count: usize,
     deferred_rx: bool,
-    // TODO(smbarber): http://crbug.com/753630
-    // Remove once MRG_RXBUF is supported and this variable is actually used.
-    #[allow(dead_code)]
     acked_features: u64,
+    counters: Option<DeviceCounters>,
+    // The most chains a pass takes from the tx queue.
+    max_chains: usize,
+}
+
+// Returns the number of bytes the device can write to the chain starting at `head`.
+fn writable_len(head: &DescriptorChain) -> usize {
+    if !head.is_write_only() {
+        return 0;
+    }
+    let mut len = head.len as usize;
+    let mut next_desc = head.next_descriptor();
+    while let Some(desc) = next_desc {
+        if !desc.is_write_only() {
+            break;
+        }
+        len += desc.len as usize;
+        next_desc = desc.next_descriptor();
+    }
+    len
+}
+
+// Copies as much of `data` as fits into the writable descriptors of the chain starting at `head`.
+// Returns the number of bytes copied.
+fn write_to_chain(mem: &GuestMemory, head: DescriptorChain, data: &[u8]) -> usize {
+    let mut write_count = 0;
+    let mut next_desc = Some(head);
+    while let Some(desc) = next_desc {
+        if !desc.is_write_only() || write_count >= data.len() {
+            break;
+        }
+        let limit = cmp::min(write_count + desc.len as usize, data.len());
+        match mem.write_slice_at_addr(&data[write_count..limit], desc.addr) {
+            Ok(sz) => write_count += sz,
+            Err(e) => {
+                warn!("net: rx: failed to write slice: {:?}", e);
+                break;
+            }
+        }
+        next_desc = desc.next_descriptor();
+    }
+    write_count
 }
 
 impl<T> Worker<T>
 where
     T: TapT,
 {
-    fn signal_used_queue(&self) {
-        self.interrupt_status
-            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).unwrap();
+    fn signal_used_queue(&self, queue_index: usize) {
+        signal_used_queue(&self.interrupt_status,
+                          &self.interrupt_evt,
+                          &self.queue_interrupt_evts,
+                          queue_index);
     }
 
     // Copies a single frame from `self.rx_buf` into the guest. Returns true
-    // if a buffer was used, and false if the frame must be deferred until a buffer
+    // if the frame was handled, and false if the frame must be deferred until a buffer
     // is made available by the driver.
     fn rx_single_frame(&mut self) -> bool {
-        let mut next_desc = self.rx_queue.iter(&self.mem).next();
+        if self.acked_features & (1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF) != 0 {
+            return self.rx_mergeable_frame();
+        }
 
-        if next_desc.is_none() {
-            return false;
+        // Without mergeable buffers every frame takes exactly one buffer.
+        self.set_num_buffers(1);
+        let head = match self.rx_queue.iter(&self.mem).next() {
+            Some(desc) => desc,
+            None => return false,
+        };
+        let head_index = head.index;
+        let write_count = write_to_chain(&self.mem, head, &self.rx_buf[..self.rx_count]);
+        if write_count < self.rx_count {
+            warn!("net: rx: buffer is too small to hold frame of size {}",
+                  self.rx_count);
+        }
+        if let Some(ref counters) = self.counters {
+            if write_count < self.rx_count {
+                counters.add_error();
+            }
+            counters.add_in(write_count);
         }
 
-        // We just checked that the head descriptor exists.
-        let head_index = next_desc.as_ref().unwrap().index;
-        let mut write_count = 0;
+        self.rx_queue.add_used(&self.mem, head_index, write_count as u32);
 
-        // Copy from frame into buffer, which may span multiple descriptors.
-        loop {
-            match next_desc {
-                Some(desc) => {
-                    if !desc.is_write_only() {
-                        break;
-                    }
-                    let limit = cmp::min(write_count + desc.len as usize, self.rx_count);
-                    let source_slice = &self.rx_buf[write_count..limit];
-                    let write_result = self.mem.write_slice_at_addr(source_slice, desc.addr);
+        // Interrupt the guest immediately for received frames to
+        // reduce latency.
+        self.signal_used_queue(RX_QUEUE);
 
-                    match write_result {
-                        Ok(sz) => {
-                            write_count += sz;
-                        }
-                        Err(e) => {
-                            warn!("net: rx: failed to write slice: {:?}", e);
-                            break;
-                        }
-                    };
+        true
+    }
 
-                    if write_count >= self.rx_count {
-                        break;
-                    }
-                    next_desc = desc.next_descriptor();
-                }
-                None => {
-                    warn!("net: rx: buffer is too small to hold frame of size {}",
-                          self.rx_count);
-                    break;
+    // Copies a single frame from `self.rx_buf` into as many of the guest's buffers as it takes,
+    // recording how many in the frame's header. Returns false if the driver hasn't made enough
+    // buffers available yet. A frame that doesn't fit even in a full queue of buffers is dropped.
+    fn rx_mergeable_frame(&mut self) -> bool {
+        // Find out how many buffers the frame needs before taking any of them.
+        let mut needed = 0;
+        let mut capacity = 0;
+        let mut available = 0;
+        {
+            let mut peek_queue = self.rx_queue.clone();
+            for head in peek_queue.iter(&self.mem) {
+                available += 1;
+                if capacity < self.rx_count {
+                    needed += 1;
+                    capacity += writable_len(&head);
                 }
             }
         }
+        if capacity < self.rx_count {
+            if available < self.rx_queue.actual_size() {
+                return false;
+            }
+            warn!("net: rx: dropping frame of size {} that doesn't fit in the rx queue",
+                  self.rx_count);
+            if let Some(ref counters) = self.counters {
+                counters.add_error();
+            }
+            return true;
+        }
 
-        self.rx_queue.add_used(&self.mem, head_index, write_count as u32);
+        self.set_num_buffers(needed as u16);
+        let mut used = Vec::with_capacity(needed);
+        let mut offset = 0;
+        for head in self.rx_queue.iter(&self.mem).take(needed) {
+            let head_index = head.index;
+            let count = write_to_chain(&self.mem, head, &self.rx_buf[offset..self.rx_count]);
+            used.push((head_index, count as u32));
+            offset += count;
+        }
+        // The driver must see all of the frame's buffers at once.
+        self.rx_queue.add_used_batch(&self.mem, &used);
+        if let Some(ref counters) = self.counters {
+            counters.add_in(self.rx_count);
+        }
 
         // Interrupt the guest immediately for received frames to
         // reduce latency.
-        self.signal_used_queue();
+        self.signal_used_queue(RX_QUEUE);
 
         true
     }
 
+    // Sets `num_buffers` in the header at the start of `self.rx_buf`.
+    fn set_num_buffers(&mut self, num_buffers: u16) {
+        if self.rx_count >= mem::size_of::<virtio_net_hdr_v1>() {
+            LittleEndian::write_u16(&mut self.rx_buf[NUM_BUFFERS_OFFSET..], num_buffers);
+        }
+    }
+
     fn process_rx(&mut self) {
         // Read as many frames as possible.
         loop {
@@ -160,12 +248,14 @@ where
         }
     }
 
-    fn process_tx(&mut self) {
+    // Sends the frames in the tx queue. Returns true if the pass stopped at `max_chains`, so
+    // frames may be left in the queue.
+    fn process_tx(&mut self) -> bool {
         let mut frame = [0u8; MAX_BUFFER_SIZE];
         let mut used_desc_heads = [0u16; QUEUE_SIZE as usize];
         let mut used_count = 0;
 
-        for avail_desc in self.tx_queue.iter(&self.mem) {
+        for avail_desc in self.tx_queue.iter(&self.mem).take(self.max_chains) {
             let head_index = avail_desc.index;
             let mut next_desc = Some(avail_desc);
             let mut read_count = 0;
@@ -199,9 +289,16 @@ where
 
             let write_result = self.tap.write(&frame[..read_count as usize]);
             match write_result {
-                Ok(_) => {},
+                Ok(_) => {
+                    if let Some(ref counters) = self.counters {
+                        counters.add_out(read_count);
+                    }
+                }
                 Err(e) => {
                     warn!("net: tx: error failed to write to tap: {:?}", e);
+                    if let Some(ref counters) = self.counters {
+                        counters.add_error();
+                    }
                 }
             };
 
@@ -213,7 +310,8 @@ where
             self.tx_queue.add_used(&self.mem, desc_index, 0);
         }
 
-        self.signal_used_queue();
+        self.signal_used_queue(TX_QUEUE);
+        used_count == self.max_chains
     }
 
     fn run(&mut self,
@@ -271,7 +369,14 @@ where
                             error!("net: error reading tx queue EventFd: {:?}", e);
                             break 'poll;
                         }
-                        self.process_tx();
+                        // Leave the rest of a full queue for the next wake, after the other
+                        // events have had their turn.
+                        if self.process_tx() {
+                            if let Err(e) = tx_queue_evt.write(1) {
+                                error!("net: error writing tx queue EventFd: {:?}", e);
+                                break 'poll;
+                            }
+                        }
                     }
                     Token::Kill => break 'poll,
                 }
@@ -284,9 +389,11 @@ where
 pub struct Net<T: TapT> {
     workers_kill_evt: Option<EventFd>,
     kill_evt: EventFd,
+    worker_thread: Option<WorkerThread>,
     tap: Option<T>,
     avail_features: u64,
     acked_features: u64,
+    counters: Option<DeviceCounters>,
 }
 
 impl<T> Net<T>
@@ -298,12 +405,26 @@ where
     pub fn new(ip_addr: Ipv4Addr,
                netmask: Ipv4Addr,
                mac_addr: MacAddress) -> Result<Net<T>, NetError> {
-        let kill_evt = EventFd::new().map_err(NetError::CreateKillEventFd)?;
-
         let tap: T = T::new(true).map_err(NetError::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(NetError::TapSetIp)?;
         tap.set_netmask(netmask)
             .map_err(NetError::TapSetNetmask)?;
+        Self::with_tap(tap, mac_addr)
+    }
+
+    /// Create a new virtio network device on an existing tap interface, such as one created and
+    /// configured by a network manager. The tap's addresses are left untouched.
+    ///
+    /// This is unsafe because the device takes ownership of `fd`, so it must be valid and not
+    /// owned by anything else.
+    pub unsafe fn from_tap_fd(fd: RawFd, mac_addr: MacAddress) -> Result<Net<T>, NetError> {
+        let tap: T = T::from_raw_fd(fd).map_err(NetError::TapOpen)?;
+        Self::with_tap(tap, mac_addr)
+    }
+
+    fn with_tap(tap: T, mac_addr: MacAddress) -> Result<Net<T>, NetError> {
+        let kill_evt = EventFd::new().map_err(NetError::CreateKillEventFd)?;
+
         tap.set_mac_address(mac_addr)
             .map_err(NetError::TapSetMacAddress)?;
 
@@ -323,16 +444,24 @@ where
                 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4 |
                 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO |
                 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4 |
-                1 << virtio_net::VIRTIO_NET_F_HOST_UFO | 1 << vhost::VIRTIO_F_VERSION_1;
+                1 << virtio_net::VIRTIO_NET_F_HOST_UFO |
+                1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF | 1 << vhost::VIRTIO_F_VERSION_1;
 
         Ok(Net {
             workers_kill_evt: Some(kill_evt.try_clone().map_err(NetError::CloneKillEventFd)?),
             kill_evt: kill_evt,
+            worker_thread: None,
             tap: Some(tap),
             avail_features: avail_features,
             acked_features: 0u64,
+            counters: None,
         })
     }
+
+    /// Counts the frames this device receives and transmits in `counters`.
+    pub fn set_counters(&mut self, counters: DeviceCounters) {
+        self.counters = Some(counters);
+    }
 }
 
 impl<T> Drop for Net<T>
@@ -345,6 +474,12 @@ where
             // Ignore the result because there is nothing we can do about it.
             let _ = self.kill_evt.write(1);
         }
+        // The worker owns the tap, so wait for it to let go before returning.
+        if let Some(worker_thread) = self.worker_thread.take() {
+            if !worker_thread.join_timeout(Duration::from_millis(WORKER_EXIT_TIMEOUT_MS)) {
+                warn!("net: worker didn't exit in {}ms", WORKER_EXIT_TIMEOUT_MS);
+            }
+        }
     }
 }
 
@@ -412,7 +547,9 @@ where
                 interrupt_evt: EventFd,
                 status: Arc<AtomicUsize>,
                 mut queues: Vec<Queue>,
-                mut queue_evts: Vec<EventFd>) {
+                mut queue_evts: Vec<EventFd>,
+                queue_interrupt_evts: Option<Vec<EventFd>>,
+                max_chains_per_pass: usize) {
         if queues.len() != 2 || queue_evts.len() != 2 {
             error!("net: expected 2 queues, got {}", queues.len());
             return;
@@ -421,37 +558,214 @@ where
         if let Some(tap) = self.tap.take() {
             if let Some(kill_evt) = self.workers_kill_evt.take() {
                 let acked_features = self.acked_features;
-                let worker_result = thread::Builder::new()
-                    .name("virtio_net".to_string())
-                    .spawn(move || {
-                        // First queue is rx, second is tx.
-                        let rx_queue = queues.remove(0);
-                        let tx_queue = queues.remove(0);
-                        let mut worker = Worker {
-                            mem: mem,
-                            rx_queue: rx_queue,
-                            tx_queue: tx_queue,
-                            tap: tap,
-                            interrupt_status: status,
-                            interrupt_evt: interrupt_evt,
-                            rx_buf: [0u8; MAX_BUFFER_SIZE],
-                            rx_count: 0,
-                            deferred_rx: false,
-                            acked_features: acked_features,
-                        };
-                        let rx_queue_evt = queue_evts.remove(0);
-                        let tx_queue_evt = queue_evts.remove(0);
-                        let result = worker.run(rx_queue_evt, tx_queue_evt, kill_evt);
-                        if let Err(e) = result {
-                            error!("net worker thread exited with error: {:?}", e);
-                        }
-                    });
+                let counters = self.counters.take();
+                let worker_result = WorkerThread::spawn("virtio_net", move || {
+                    // First queue is rx, second is tx.
+                    let rx_queue = queues.remove(0);
+                    let tx_queue = queues.remove(0);
+                    let mut worker = Worker {
+                        mem: mem,
+                        rx_queue: rx_queue,
+                        tx_queue: tx_queue,
+                        tap: tap,
+                        interrupt_status: status,
+                        interrupt_evt: interrupt_evt,
+                        queue_interrupt_evts: queue_interrupt_evts,
+                        rx_buf: [0u8; MAX_BUFFER_SIZE],
+                        rx_count: 0,
+                        deferred_rx: false,
+                        acked_features: acked_features,
+                        counters: counters,
+                        max_chains: max_chains_per_pass,
+                    };
+                    let rx_queue_evt = queue_evts.remove(0);
+                    let tx_queue_evt = queue_evts.remove(0);
+                    let result = worker.run(rx_queue_evt, tx_queue_evt, kill_evt);
+                    if let Err(e) = result {
+                        error!("net worker thread exited with error: {:?}", e);
+                    }
+                });
 
-                if let Err(e) = worker_result {
-                    error!("failed to spawn virtio_net worker: {}", e);
-                    return;
+                match worker_result {
+                    Ok(worker_thread) => self.worker_thread = Some(worker_thread),
+                    Err(e) => error!("failed to spawn virtio_net worker: {}", e),
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use super::super::INTERRUPT_STATUS_USED_RING;
+    use super::super::test_queue::TestQueue;
+    use net_util::fakes::FakeTap;
+    use sys_util::GuestAddress;
+
+    const TEST_QUEUE_SIZE: u16 = 4;
+    const HDR_SIZE: usize = 12;
+
+    fn test_worker(mem: &GuestMemory, acked_features: u64) -> (Worker<FakeTap>, TestQueue) {
+        let (rx, rx_queue) = TestQueue::new(mem, 0, TEST_QUEUE_SIZE);
+        let worker = Worker {
+            mem: mem.clone(),
+            rx_queue: rx_queue,
+            tx_queue: Queue::new(QUEUE_SIZE),
+            tap: FakeTap::new(true).unwrap(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_interrupt_evts: None,
+            rx_buf: [0u8; MAX_BUFFER_SIZE],
+            rx_count: 0,
+            deferred_rx: false,
+            acked_features: acked_features,
+            counters: None,
+            max_chains: MAX_CHAINS_PER_PASS,
+        };
+        (worker, rx)
+    }
+
+    // Puts a frame of `len` bytes, including the header, in the worker's receive buffer.
+    fn set_frame(worker: &mut Worker<FakeTap>, len: usize) -> Vec<u8> {
+        let frame: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        worker.rx_buf[..len].copy_from_slice(&frame);
+        worker.rx_count = len;
+        frame
+    }
+
+    #[test]
+    fn per_queue_interrupts() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 0);
+        let rx_evt = EventFd::new().unwrap();
+        let tx_evt = EventFd::new().unwrap();
+        worker.queue_interrupt_evts = Some(vec![rx_evt.try_clone().unwrap(),
+                                                tx_evt.try_clone().unwrap()]);
+        set_frame(&mut worker, 100);
+        rx.post(&mem, 0, None, 1000);
+
+        assert!(worker.rx_single_frame());
+        assert_eq!(rx_evt.drain().unwrap(), 1);
+        assert_eq!(tx_evt.drain().unwrap(), 0);
+        worker.process_tx();
+        assert_eq!(rx_evt.drain().unwrap(), 0);
+        assert_eq!(tx_evt.drain().unwrap(), 1);
+        // The shared interrupt isn't used at all.
+        assert_eq!(worker.interrupt_evt.drain().unwrap(), 0);
+        assert_eq!(worker.interrupt_status.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn shared_interrupt() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 0);
+        set_frame(&mut worker, 100);
+        rx.post(&mem, 0, None, 1000);
+
+        assert!(worker.rx_single_frame());
+        worker.process_tx();
+        assert_eq!(worker.interrupt_evt.drain().unwrap(), 2);
+        assert_eq!(worker.interrupt_status.load(Ordering::SeqCst),
+                   INTERRUPT_STATUS_USED_RING as usize);
+    }
+
+    #[test]
+    fn mergeable_rx_splits_frame() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF);
+        let mut frame = set_frame(&mut worker, 2500);
+        for i in 0..TEST_QUEUE_SIZE {
+            rx.post(&mem, i, None, 1000);
+        }
+
+        assert!(worker.rx_single_frame());
+        let mut received = Vec::new();
+        for &(index, len) in &[(0, 1000), (1, 1000), (2, 500)] {
+            let (used_index, data) = rx.pop_used_desc(&mem).unwrap();
+            assert_eq!(used_index, index);
+            assert_eq!(data.len(), len);
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(rx.pop_used_desc(&mem), None);
+        assert_eq!(LittleEndian::read_u16(&received[NUM_BUFFERS_OFFSET..HDR_SIZE]), 3);
+        LittleEndian::write_u16(&mut frame[NUM_BUFFERS_OFFSET..HDR_SIZE], 3);
+        assert_eq!(received, frame);
+    }
+
+    #[test]
+    fn mergeable_rx_waits_for_buffers() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF);
+        set_frame(&mut worker, 1500);
+
+        // Not enough room yet, so no buffer is taken.
+        rx.post(&mem, 0, None, 1000);
+        assert!(!worker.rx_single_frame());
+        assert_eq!(rx.pop_used_desc(&mem), None);
+
+        rx.post(&mem, 1, None, 1000);
+        assert!(worker.rx_single_frame());
+        assert_eq!(rx.pop_used_desc(&mem).map(|(i, d)| (i, d.len())), Some((0, 1000)));
+        assert_eq!(rx.pop_used_desc(&mem).map(|(i, d)| (i, d.len())), Some((1, 500)));
+    }
+
+    #[test]
+    fn mergeable_rx_drops_oversized_frame() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF);
+        set_frame(&mut worker, 5000);
+        for i in 0..TEST_QUEUE_SIZE {
+            rx.post(&mem, i, None, 1000);
+        }
+
+        // Even a full queue of buffers can't hold the frame, so it is dropped.
+        assert!(worker.rx_single_frame());
+        assert_eq!(rx.pop_used_desc(&mem), None);
+
+        // The buffers are still there for the next frame.
+        set_frame(&mut worker, 100);
+        assert!(worker.rx_single_frame());
+        assert_eq!(rx.pop_used_desc(&mem).map(|(i, d)| (i, d.len())), Some((0, 100)));
+    }
+
+    #[test]
+    fn rx_without_mergeable_buffers() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 0);
+        set_frame(&mut worker, 100);
+
+        assert!(!worker.rx_single_frame());
+        rx.post(&mem, 0, None, 1000);
+        assert!(worker.rx_single_frame());
+        let (index, data) = rx.pop_used_desc(&mem).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(data.len(), 100);
+        assert_eq!(LittleEndian::read_u16(&data[NUM_BUFFERS_OFFSET..HDR_SIZE]), 1);
+    }
+
+    #[test]
+    fn rx_updates_counters() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x100000)]).unwrap();
+        let (mut worker, mut rx) = test_worker(&mem, 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF);
+        let counters = DeviceCounters::new("net0").unwrap();
+        worker.counters = Some(counters.clone());
+        for i in 0..TEST_QUEUE_SIZE {
+            rx.post(&mem, i, None, 1000);
+        }
+
+        // Too big for every buffer in the queue, so it gets dropped.
+        set_frame(&mut worker, 5000);
+        assert!(worker.rx_single_frame());
+        set_frame(&mut worker, 1500);
+        assert!(worker.rx_single_frame());
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.requests_in, 1);
+        assert_eq!(stats.bytes_in, 1500);
+        assert_eq!(stats.requests_out, 0);
+        assert_eq!(stats.errors, 1);
+    }
+}
@@ -0,0 +1,900 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use libc;
+
+use sys_util::{EventFd, GuestMemory, PollContext, PollToken};
+
+use super::{VirtioDevice, Queue, Reader, Writer, INTERRUPT_STATUS_USED_RING, TYPE_9P};
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
+
+// The device has a mount tag in its config space.
+const VIRTIO_9P_MOUNT_TAG: u32 = 1 << 0;
+
+/// The largest message size offered to the guest in `Tversion`.
+pub const MAX_MESSAGE_SIZE: u32 = 65536;
+/// The smallest message size the guest may pick in `Tversion`, which is also what Linux requires.
+/// Every reply other than the data of an `Rread` or `Rreaddir` fits in it.
+pub const MIN_MESSAGE_SIZE: u32 = 4096;
+
+const PROTOCOL_VERSION: &'static str = "9P2000.L";
+
+// Message types of 9P2000.L. Each reply is the request type plus one.
+const RLERROR: u8 = 7;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TLOPEN: u8 = 12;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+// type[1] version[4] path[8]
+const QID_SIZE: usize = 13;
+// The header of an Rread or Rreaddir, which is followed by count[4].
+const IO_HEADER_SIZE: usize = HEADER_SIZE + 4;
+
+const QID_TYPE_DIR: u8 = 0x80;
+const QID_TYPE_SYMLINK: u8 = 0x02;
+const QID_TYPE_FILE: u8 = 0x00;
+
+// All of the basic fields of Rgetattr are filled in.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// Per the 9P spec, a walk may have at most this many path elements.
+const MAX_WALK_ELEMENTS: u16 = 16;
+
+// The most fids the guest can hold at once, each of which may keep a host file open.
+const MAX_FIDS: usize = 1024;
+
+fn errno_error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+/// Checks that `name` is a single path element that can't name anything outside of its
+/// directory. `..` is handled by the walk itself, which never goes above the root.
+pub fn sanitize_name(name: &str) -> io::Result<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') ||
+       name.contains('\0') {
+        return Err(errno_error(libc::EINVAL));
+    }
+    Ok(name)
+}
+
+// Resolves symlinks in `path` and fails with EACCES unless the result is inside `root`, which must
+// already be canonical.
+fn resolve_in_root(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let resolved = fs::canonicalize(path)?;
+    if !resolved.starts_with(root) {
+        return Err(errno_error(libc::EACCES));
+    }
+    Ok(resolved)
+}
+
+fn read_string(msg: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let len = msg.read_u16::<LittleEndian>()? as usize;
+    let mut buf = vec![0u8; len];
+    msg.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| errno_error(libc::EINVAL))
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.write_u16::<LittleEndian>(s.len() as u16).unwrap();
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Qid {
+    ty: u8,
+    version: u32,
+    path: u64,
+}
+
+impl Qid {
+    fn from_metadata(metadata: &Metadata) -> Qid {
+        let file_type = metadata.file_type();
+        let ty = if file_type.is_dir() {
+            QID_TYPE_DIR
+        } else if file_type.is_symlink() {
+            QID_TYPE_SYMLINK
+        } else {
+            QID_TYPE_FILE
+        };
+        Qid {
+            ty: ty,
+            version: metadata.mtime() as u32,
+            path: metadata.ino(),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.ty);
+        out.write_u32::<LittleEndian>(self.version).unwrap();
+        out.write_u64::<LittleEndian>(self.path).unwrap();
+    }
+}
+
+// The Linux dirent type of a file, as sent in Rreaddir.
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        libc::DT_DIR
+    } else if file_type.is_symlink() {
+        libc::DT_LNK
+    } else if file_type.is_fifo() {
+        libc::DT_FIFO
+    } else if file_type.is_socket() {
+        libc::DT_SOCK
+    } else if file_type.is_char_device() {
+        libc::DT_CHR
+    } else if file_type.is_block_device() {
+        libc::DT_BLK
+    } else {
+        libc::DT_REG
+    }
+}
+
+// A file the guest has a handle to. The path is always canonical and inside the root, and the file
+// is set once the guest opens it with Tlopen.
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// Serves the 9P2000.L protocol for the files under a host directory.
+pub struct Server {
+    root: PathBuf,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    /// Constructs a server for the files under `root`. The root is only resolved when the guest
+    /// attaches, so the path may be one that only exists in the device's jail.
+    pub fn new<P: AsRef<Path>>(root: P) -> Server {
+        Server {
+            root: root.as_ref().to_path_buf(),
+            msize: MAX_MESSAGE_SIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// The largest message the guest agreed to send or receive.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Handles the request message in `request` and returns the reply message. Failed requests
+    /// are answered with `Rlerror`.
+    pub fn handle_message(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut msg = Cursor::new(request);
+        let (ty, tag) = match (msg.read_u32::<LittleEndian>(),
+                               msg.read_u8(),
+                               msg.read_u16::<LittleEndian>()) {
+            (Ok(size), Ok(ty), Ok(tag)) if size as usize <= request.len() => (ty, tag),
+            _ => return Self::error_reply(!0, libc::EINVAL),
+        };
+
+        let mut body = Vec::new();
+        let result = match ty {
+            TVERSION => self.version(&mut msg, &mut body),
+            TATTACH => self.attach(&mut msg, &mut body),
+            TFLUSH => Ok(()),
+            TWALK => self.walk(&mut msg, &mut body),
+            TLOPEN => self.lopen(&mut msg, &mut body),
+            TREAD => self.read(&mut msg, &mut body),
+            TWRITE => self.write(&mut msg, &mut body),
+            TREADDIR => self.readdir(&mut msg, &mut body),
+            TGETATTR => self.getattr(&mut msg, &mut body),
+            TCLUNK => self.clunk(&mut msg),
+            _ => Err(errno_error(libc::EOPNOTSUPP)),
+        };
+        match result {
+            Ok(()) => Self::reply(ty + 1, tag, &body),
+            // Errors without an errno come from parsing a message that is too short.
+            Err(e) => Self::error_reply(tag, e.raw_os_error().unwrap_or(libc::EINVAL)),
+        }
+    }
+
+    fn reply(ty: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+        out.write_u32::<LittleEndian>((HEADER_SIZE + body.len()) as u32)
+            .unwrap();
+        out.push(ty);
+        out.write_u16::<LittleEndian>(tag).unwrap();
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn error_reply(tag: u16, errno: i32) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(errno as u32).unwrap();
+        Self::reply(RLERROR, tag, &body)
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or(errno_error(libc::EBADF))
+    }
+
+    fn version(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let msize = msg.read_u32::<LittleEndian>()?;
+        let version = read_string(msg)?;
+        if msize < MIN_MESSAGE_SIZE {
+            return Err(errno_error(libc::EINVAL));
+        }
+        // A new session starts, so all of the old fids go away.
+        self.fids.clear();
+        self.msize = min(msize, MAX_MESSAGE_SIZE);
+        out.write_u32::<LittleEndian>(self.msize).unwrap();
+        write_string(out,
+                     if version == PROTOCOL_VERSION {
+                         PROTOCOL_VERSION
+                     } else {
+                         "unknown"
+                     });
+        Ok(())
+    }
+
+    fn attach(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let _afid = msg.read_u32::<LittleEndian>()?;
+        let _uname = read_string(msg)?;
+        let _aname = read_string(msg)?;
+        if self.fids.contains_key(&fid) {
+            return Err(errno_error(libc::EBADF));
+        }
+        if self.fids.len() >= MAX_FIDS {
+            return Err(errno_error(libc::EMFILE));
+        }
+        let root = fs::canonicalize(&self.root)?;
+        let metadata = fs::metadata(&root)?;
+        if !metadata.is_dir() {
+            return Err(errno_error(libc::ENOTDIR));
+        }
+        self.root = root.clone();
+        self.fids
+            .insert(fid,
+                    Fid {
+                        path: root,
+                        file: None,
+                    });
+        Qid::from_metadata(&metadata).encode(out);
+        Ok(())
+    }
+
+    fn walk(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let newfid = msg.read_u32::<LittleEndian>()?;
+        let nwname = msg.read_u16::<LittleEndian>()?;
+        if nwname > MAX_WALK_ELEMENTS {
+            return Err(errno_error(libc::EINVAL));
+        }
+        let mut names = Vec::with_capacity(nwname as usize);
+        for _ in 0..nwname {
+            names.push(read_string(msg)?);
+        }
+        if newfid != fid {
+            if self.fids.contains_key(&newfid) {
+                return Err(errno_error(libc::EBADF));
+            }
+            if self.fids.len() >= MAX_FIDS {
+                return Err(errno_error(libc::EMFILE));
+            }
+        }
+
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::with_capacity(names.len());
+        for name in &names {
+            let next = if name == ".." {
+                // Walking up from the root stays at the root.
+                if path == self.root {
+                    path.clone()
+                } else {
+                    path.parent().unwrap_or(&self.root).to_path_buf()
+                }
+            } else {
+                path.join(sanitize_name(name)?)
+            };
+            let next = match resolve_in_root(&self.root, &next)
+                      .and_then(|p| fs::metadata(&p).map(|m| (p, m))) {
+                Ok((p, metadata)) => {
+                    qids.push(Qid::from_metadata(&metadata));
+                    p
+                }
+                // Only a failure of the first element is an error. Otherwise the guest learns how
+                // far the walk got from the number of qids.
+                Err(e) => {
+                    if qids.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                }
+            };
+            path = next;
+        }
+
+        if qids.len() == names.len() {
+            self.fids
+                .insert(newfid,
+                        Fid {
+                            path: path,
+                            file: None,
+                        });
+        }
+        out.write_u16::<LittleEndian>(qids.len() as u16).unwrap();
+        for qid in &qids {
+            qid.encode(out);
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let flags = msg.read_u32::<LittleEndian>()? as i32;
+        let root = self.root.clone();
+        let entry = self.fids.get_mut(&fid).ok_or(errno_error(libc::EBADF))?;
+        if entry.file.is_some() {
+            return Err(errno_error(libc::EBADF));
+        }
+        // The file may have been replaced by a symlink since it was walked to.
+        let path = resolve_in_root(&root, &entry.path)?;
+        let metadata = fs::metadata(&path)?;
+        let file = if metadata.is_dir() {
+            // Directories are only opened for Treaddir.
+            File::open(&path)?
+        } else {
+            let access = flags & libc::O_ACCMODE;
+            let writable = access == libc::O_WRONLY || access == libc::O_RDWR;
+            OpenOptions::new()
+                .read(access == libc::O_RDONLY || access == libc::O_RDWR)
+                .write(writable)
+                .append(flags & libc::O_APPEND != 0)
+                .truncate(writable && flags & libc::O_TRUNC != 0)
+                .open(&path)?
+        };
+        entry.file = Some(file);
+        Qid::from_metadata(&metadata).encode(out);
+        // Let the guest pick the I/O size from msize.
+        out.write_u32::<LittleEndian>(0).unwrap();
+        Ok(())
+    }
+
+    fn read(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let offset = msg.read_u64::<LittleEndian>()?;
+        let count = msg.read_u32::<LittleEndian>()?;
+        let count = min(count as usize, self.msize as usize - IO_HEADER_SIZE);
+        let file = self.fid(fid)?
+            .file
+            .as_ref()
+            .ok_or(errno_error(libc::EBADF))?;
+        let mut data = vec![0u8; count];
+        let len = file.read_at(&mut data, offset)?;
+        out.write_u32::<LittleEndian>(len as u32).unwrap();
+        out.extend_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let offset = msg.read_u64::<LittleEndian>()?;
+        let count = msg.read_u32::<LittleEndian>()? as usize;
+        let start = msg.position() as usize;
+        let data = msg.get_ref()
+            .get(start..start + count)
+            .ok_or(errno_error(libc::EINVAL))?;
+        let file = self.fid(fid)?
+            .file
+            .as_ref()
+            .ok_or(errno_error(libc::EBADF))?;
+        let len = file.write_at(data, offset)?;
+        out.write_u32::<LittleEndian>(len as u32).unwrap();
+        Ok(())
+    }
+
+    fn readdir(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let offset = msg.read_u64::<LittleEndian>()?;
+        let count = msg.read_u32::<LittleEndian>()?;
+        let count = min(count as usize, self.msize as usize - IO_HEADER_SIZE);
+        let entry = self.fid(fid)?;
+        if entry.file.is_none() {
+            return Err(errno_error(libc::EBADF));
+        }
+        // The directory may have been replaced by a symlink since it was opened.
+        let path = &resolve_in_root(&self.root, &entry.path)?;
+
+        // The offset of an entry is its position in the sorted listing, plus one so that the
+        // offset of the last entry sent is where the next Treaddir starts.
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            if let Ok(name) = entry?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut entries = Vec::new();
+        for (index, name) in names.iter().enumerate().skip(offset as usize) {
+            let metadata = match fs::symlink_metadata(path.join(name)) {
+                Ok(m) => m,
+                // The file went away since it was listed.
+                Err(_) => continue,
+            };
+            if entries.len() + QID_SIZE + 8 + 1 + 2 + name.len() > count {
+                break;
+            }
+            Qid::from_metadata(&metadata).encode(&mut entries);
+            entries.write_u64::<LittleEndian>(index as u64 + 1).unwrap();
+            entries.push(dirent_type(&metadata));
+            write_string(&mut entries, name);
+        }
+        out.write_u32::<LittleEndian>(entries.len() as u32)
+            .unwrap();
+        out.extend_from_slice(&entries);
+        Ok(())
+    }
+
+    fn getattr(&mut self, msg: &mut Cursor<&[u8]>, out: &mut Vec<u8>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        let _request_mask = msg.read_u64::<LittleEndian>()?;
+        let entry = self.fid(fid)?;
+        // Opened files are looked at through their fd. Otherwise the file may have been replaced
+        // by a symlink since it was walked to.
+        let metadata = match entry.file {
+            Some(ref file) => file.metadata()?,
+            None => fs::metadata(resolve_in_root(&self.root, &entry.path)?)?,
+        };
+        out.write_u64::<LittleEndian>(GETATTR_BASIC).unwrap();
+        Qid::from_metadata(&metadata).encode(out);
+        out.write_u32::<LittleEndian>(metadata.mode()).unwrap();
+        out.write_u32::<LittleEndian>(metadata.uid()).unwrap();
+        out.write_u32::<LittleEndian>(metadata.gid()).unwrap();
+        for &v in &[metadata.nlink(),
+                    metadata.rdev(),
+                    metadata.size(),
+                    metadata.blksize(),
+                    metadata.blocks(),
+                    metadata.atime() as u64,
+                    metadata.atime_nsec() as u64,
+                    metadata.mtime() as u64,
+                    metadata.mtime_nsec() as u64,
+                    metadata.ctime() as u64,
+                    metadata.ctime_nsec() as u64,
+                    // btime, gen and data_version aren't supported.
+                    0,
+                    0,
+                    0,
+                    0,
+                    0] {
+            out.write_u64::<LittleEndian>(v).unwrap();
+        }
+        Ok(())
+    }
+
+    fn clunk(&mut self, msg: &mut Cursor<&[u8]>) -> io::Result<()> {
+        let fid = msg.read_u32::<LittleEndian>()?;
+        self.fids
+            .remove(&fid)
+            .map(|_| ())
+            .ok_or(errno_error(libc::EBADF))
+    }
+}
+
+struct Worker {
+    queue: Queue,
+    mem: GuestMemory,
+    server: Server,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+}
+
+impl Worker {
+    fn process_queue(&mut self) -> bool {
+        let queue = &mut self.queue;
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(&self.mem) {
+            let mut request = Vec::new();
+            let mut reader = Reader::new(&self.mem, avail_desc.clone());
+            let mut len = 0;
+            match reader.read_to(&mut request, self.server.msize() as usize) {
+                Ok(_) => {
+                    let reply = self.server.handle_message(&request);
+                    let mut writer = Writer::new(&self.mem, avail_desc.clone());
+                    match writer.write_all(&reply) {
+                        Ok(()) => len = writer.bytes_written() as u32,
+                        Err(e) => error!("failed to write 9P reply: {:?}", e),
+                    }
+                }
+                Err(e) => error!("failed to read 9P request: {:?}", e),
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&self.mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).unwrap();
+    }
+
+    fn run(&mut self, queue_evt: EventFd, kill_evt: EventFd) {
+        #[derive(PollToken)]
+        enum Token {
+            QueueAvailable,
+            Kill,
+        }
+
+        let poll_ctx: PollContext<Token> =
+            match PollContext::new()
+                      .and_then(|pc| pc.add(&queue_evt, Token::QueueAvailable).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&kill_evt, Token::Kill).and(Ok(pc))) {
+                Ok(pc) => pc,
+                Err(e) => {
+                    error!("failed creating PollContext: {:?}", e);
+                    return;
+                }
+            };
+
+        'poll: loop {
+            let events = match poll_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {:?}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter_readable() {
+                match event.token() {
+                    Token::QueueAvailable => {
+                        if let Err(e) = queue_evt.read() {
+                            error!("failed reading queue EventFd: {:?}", e);
+                            break 'poll;
+                        }
+                        needs_interrupt |= self.process_queue();
+                    }
+                    Token::Kill => break 'poll,
+                }
+            }
+            if needs_interrupt {
+                self.signal_used_queue();
+            }
+        }
+    }
+}
+
+/// Virtio device for sharing a host directory with the guest over the 9P2000.L protocol.
+pub struct P9 {
+    kill_evt: Option<EventFd>,
+    root: PathBuf,
+    // The length of the tag followed by the tag, which the guest mounts the share by.
+    config: Vec<u8>,
+}
+
+impl P9 {
+    /// Creates a device that shares the files under `root` with the guest, which mounts them by
+    /// `tag`.
+    pub fn new<P: AsRef<Path>>(root: P, tag: &str) -> P9 {
+        let mut config = Vec::with_capacity(2 + tag.len());
+        write_string(&mut config, tag);
+        P9 {
+            kill_evt: None,
+            root: root.as_ref().to_path_buf(),
+            config: config,
+        }
+    }
+}
+
+impl Drop for P9 {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn keep_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_9P
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            0 => VIRTIO_9P_MOUNT_TAG,
+            _ => 0u32,
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if offset >= self.config.len() as u64 {
+            return;
+        }
+        let config = &self.config[offset as usize..];
+        let len = min(config.len(), data.len());
+        data[..len].copy_from_slice(&config[..len]);
+    }
+
+    fn activate(&mut self,
+                mem: GuestMemory,
+                interrupt_evt: EventFd,
+                status: Arc<AtomicUsize>,
+                mut queues: Vec<Queue>,
+                mut queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
+        if queues.len() != 1 || queue_evts.len() != 1 {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to create kill EventFd pair: {:?}", e);
+                    return;
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        let queue = queues.remove(0);
+        let server = Server::new(&self.root);
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_9p".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    queue: queue,
+                    mem: mem,
+                    server: server,
+                    interrupt_status: status,
+                    interrupt_evt: interrupt_evt,
+                };
+                worker.run(queue_evts.remove(0), kill_evt);
+            });
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_9p worker: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use sys_util::TempDir;
+
+    fn message(ty: u8, body: &[u8]) -> Vec<u8> {
+        Server::reply(ty, 1, body)
+    }
+
+    fn fid_body(fid: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(fid).unwrap();
+        body
+    }
+
+    fn attach(server: &mut Server, fid: u32) -> Vec<u8> {
+        let mut body = fid_body(fid);
+        body.write_u32::<LittleEndian>(!0).unwrap();
+        write_string(&mut body, "user");
+        write_string(&mut body, "");
+        body.write_u32::<LittleEndian>(0).unwrap();
+        server.handle_message(&message(TATTACH, &body))
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+        let mut body = fid_body(fid);
+        body.write_u32::<LittleEndian>(newfid).unwrap();
+        body.write_u16::<LittleEndian>(names.len() as u16)
+            .unwrap();
+        for name in names {
+            write_string(&mut body, name);
+        }
+        server.handle_message(&message(TWALK, &body))
+    }
+
+    // Returns the errno of an Rlerror reply.
+    fn lerror(reply: &[u8]) -> Option<u32> {
+        if reply[4] != RLERROR {
+            return None;
+        }
+        Some((&reply[HEADER_SIZE..]).read_u32::<LittleEndian>().unwrap())
+    }
+
+    #[test]
+    fn sanitize() {
+        assert!(sanitize_name("file").is_ok());
+        assert!(sanitize_name("..hidden").is_ok());
+        assert!(sanitize_name("").is_err());
+        assert!(sanitize_name(".").is_err());
+        assert!(sanitize_name("..").is_err());
+        assert!(sanitize_name("a/b").is_err());
+        assert!(sanitize_name("/etc").is_err());
+        assert!(sanitize_name("a\0b").is_err());
+    }
+
+    #[test]
+    fn walk_stays_in_root() {
+        let outer = TempDir::new("/tmp/p9_walk_test").unwrap();
+        let outer_path = outer.as_path().unwrap();
+        let root = outer_path.join("root");
+        fs::create_dir(&root).unwrap();
+        File::create(outer_path.join("secret")).unwrap();
+        symlink(outer_path.join("secret"), root.join("link")).unwrap();
+
+        let mut server = Server::new(&root);
+        assert_eq!(lerror(&attach(&mut server, 0)), None);
+
+        // Walking up from the root stays at the root, so the secret isn't found.
+        let reply = walk(&mut server, 0, 1, &["..", "secret"]);
+        assert_eq!(lerror(&reply), None);
+        assert_eq!((&reply[HEADER_SIZE..]).read_u16::<LittleEndian>().unwrap(), 1);
+        assert!(!server.fids.contains_key(&1));
+
+        assert_eq!(lerror(&walk(&mut server, 0, 1, &["../secret"])),
+                   Some(libc::EINVAL as u32));
+        assert_eq!(lerror(&walk(&mut server, 0, 1, &["link"])),
+                   Some(libc::EACCES as u32));
+    }
+
+    #[test]
+    fn too_many_fids() {
+        let root = TempDir::new("/tmp/p9_fids_test").unwrap();
+        let mut server = Server::new(root.as_path().unwrap());
+        assert_eq!(lerror(&attach(&mut server, 0)), None);
+        for fid in 1..MAX_FIDS as u32 {
+            assert_eq!(lerror(&walk(&mut server, 0, fid, &[])), None);
+        }
+        assert_eq!(lerror(&walk(&mut server, 0, MAX_FIDS as u32, &[])),
+                   Some(libc::EMFILE as u32));
+        assert_eq!(lerror(&attach(&mut server, MAX_FIDS as u32)),
+                   Some(libc::EMFILE as u32));
+        // Walking a fid onto itself doesn't take a new one.
+        assert_eq!(lerror(&walk(&mut server, 0, 0, &[])), None);
+    }
+
+    #[test]
+    fn read_shared_file() {
+        let dir = TempDir::new("/tmp/p9_read_test").unwrap();
+        let root = dir.as_path().unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        File::create(root.join("dir").join("hello"))
+            .unwrap()
+            .write_all(b"hello, guest")
+            .unwrap();
+
+        let mut server = Server::new(root);
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(8192).unwrap();
+        write_string(&mut body, PROTOCOL_VERSION);
+        let reply = server.handle_message(&message(TVERSION, &body));
+        assert_eq!(reply[4], TVERSION + 1);
+        assert_eq!(server.msize(), 8192);
+
+        assert_eq!(lerror(&attach(&mut server, 0)), None);
+        let reply = walk(&mut server, 0, 1, &["dir", "hello"]);
+        assert_eq!((&reply[HEADER_SIZE..]).read_u16::<LittleEndian>().unwrap(), 2);
+
+        let mut body = fid_body(1);
+        body.write_u32::<LittleEndian>(libc::O_RDONLY as u32)
+            .unwrap();
+        assert_eq!(lerror(&server.handle_message(&message(TLOPEN, &body))), None);
+
+        let mut body = fid_body(1);
+        body.write_u64::<LittleEndian>(7).unwrap();
+        body.write_u32::<LittleEndian>(100).unwrap();
+        let reply = server.handle_message(&message(TREAD, &body));
+        assert_eq!(reply[4], TREAD + 1);
+        assert_eq!((&reply[HEADER_SIZE..]).read_u32::<LittleEndian>().unwrap(), 5);
+        assert_eq!(&reply[IO_HEADER_SIZE..], b"guest");
+
+        let reply = server.handle_message(&message(TCLUNK, &fid_body(1)));
+        assert_eq!(reply[4], TCLUNK + 1);
+        assert_eq!(lerror(&server.handle_message(&message(TREAD, &body))),
+                   Some(libc::EBADF as u32));
+    }
+
+    #[test]
+    fn version_min_msize() {
+        let dir = TempDir::new("/tmp/p9_version_test").unwrap();
+        let mut server = Server::new(dir.as_path().unwrap());
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(MIN_MESSAGE_SIZE - 1).unwrap();
+        write_string(&mut body, PROTOCOL_VERSION);
+        assert_eq!(lerror(&server.handle_message(&message(TVERSION, &body))),
+                   Some(libc::EINVAL as u32));
+        assert_eq!(server.msize(), MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn readdir_stays_in_root() {
+        let outer = TempDir::new("/tmp/p9_readdir_test").unwrap();
+        let outer_path = outer.as_path().unwrap();
+        let root = outer_path.join("root");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        File::create(root.join("dir").join("file")).unwrap();
+        File::create(outer_path.join("secret")).unwrap();
+
+        let mut server = Server::new(&root);
+        assert_eq!(lerror(&attach(&mut server, 0)), None);
+        walk(&mut server, 0, 1, &["dir"]);
+        let mut readdir_body = fid_body(1);
+        readdir_body.write_u64::<LittleEndian>(0).unwrap();
+        readdir_body.write_u32::<LittleEndian>(4096).unwrap();
+        let readdir =
+            |server: &mut Server| server.handle_message(&message(TREADDIR, &readdir_body));
+        let getattr = |server: &mut Server| {
+            let mut body = fid_body(1);
+            body.write_u64::<LittleEndian>(GETATTR_BASIC).unwrap();
+            server.handle_message(&message(TGETATTR, &body))
+        };
+
+        // The directory has to be opened first.
+        assert_eq!(lerror(&readdir(&mut server)), Some(libc::EBADF as u32));
+        let mut body = fid_body(1);
+        body.write_u32::<LittleEndian>(libc::O_RDONLY as u32).unwrap();
+        assert_eq!(lerror(&server.handle_message(&message(TLOPEN, &body))), None);
+        let reply = readdir(&mut server);
+        assert_eq!(lerror(&reply), None);
+        assert!(reply.windows(4).any(|w| w == b"file"));
+
+        // Swapping the directory for a symlink to outside of the root doesn't list the outside.
+        fs::rename(root.join("dir"), root.join("old")).unwrap();
+        symlink(outer_path, root.join("dir")).unwrap();
+        assert_eq!(lerror(&readdir(&mut server)), Some(libc::EACCES as u32));
+        // The opened fid still describes the directory it opened.
+        assert_eq!(lerror(&getattr(&mut server)), None);
+
+        // An unopened fid is resolved again.
+        walk(&mut server, 0, 2, &["old"]);
+        fs::rename(root.join("old"), root.join("older")).unwrap();
+        symlink(outer_path, root.join("old")).unwrap();
+        let mut body = fid_body(2);
+        body.write_u64::<LittleEndian>(GETATTR_BASIC).unwrap();
+        assert_eq!(lerror(&server.handle_message(&message(TGETATTR, &body))),
+                   Some(libc::EACCES as u32));
+    }
+}
@@ -3,17 +3,72 @@
 // found in the LICENSE file.
 
 use std::cmp::min;
+use std::io::{Read, Write};
+use std::mem;
 use std::num::Wrapping;
+use std::result;
 use std::sync::atomic::{fence, Ordering};
 
-use sys_util::{GuestAddress, GuestMemory};
+use data_model::DataInit;
+use sys_util::{GuestAddress, GuestMemory, GuestMemoryError};
 
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 #[allow(dead_code)]
 const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
 
+/// The most descriptors a chain may have to be read ahead into an `InlineChain`.
+pub const INLINE_CHAIN_LEN: usize = 4;
+
+// A descriptor as laid out in the descriptor table.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for Descriptor {}
+
+// Reads descriptor `index` of the table at `desc_table`. Returns None if the descriptor isn't in
+// guest memory, its buffer isn't either, or it links to a descriptor past the end of the table.
+fn read_descriptor(mem: &GuestMemory,
+                   desc_table: GuestAddress,
+                   queue_size: u16,
+                   index: u16)
+                   -> Option<Descriptor> {
+    if index >= queue_size {
+        return None;
+    }
+
+    let desc_head = match mem.checked_offset(desc_table, (index as u64) * 16) {
+        Some(a) => a,
+        None => return None,
+    };
+    if mem.checked_offset(desc_head, 16).is_none() {
+        return None;
+    }
+    let desc: Descriptor = match mem.read_obj_from_addr(desc_head) {
+        Ok(d) => d,
+        Err(_) => return None,
+    };
+
+    let has_next = desc.flags & VIRTQ_DESC_F_NEXT != 0 && queue_size > 1;
+    if mem.checked_offset(GuestAddress(desc.addr), desc.len as u64)
+           .is_none() {
+        None
+    } else if has_next && desc.next >= queue_size {
+        None
+    } else {
+        Some(desc)
+    }
+}
+
 /// A virtio descriptor chain.
+#[derive(Clone)]
 pub struct DescriptorChain<'a> {
     mem: &'a GuestMemory,
     desc_table: GuestAddress,
@@ -43,50 +98,19 @@ impl<'a> DescriptorChain<'a> {
                    queue_size: u16,
                    index: u16)
                    -> Option<DescriptorChain> {
-        if index >= queue_size {
-            return None;
-        }
-
-        let desc_head = match mem.checked_offset(desc_table, (index as u64) * 16) {
-            Some(a) => a,
-            None => return None,
-        };
-        // These reads can't fail unless Guest memory is hopelessly broken.
-        let addr = GuestAddress(mem.read_obj_from_addr::<u64>(desc_head).unwrap() as u64);
-        if mem.checked_offset(desc_head, 16).is_none() {
-            return None;
-        }
-        let len: u32 = mem.read_obj_from_addr(desc_head.unchecked_add(8))
-            .unwrap();
-        let flags: u16 = mem.read_obj_from_addr(desc_head.unchecked_add(12))
-            .unwrap();
-        let next: u16 = mem.read_obj_from_addr(desc_head.unchecked_add(14))
-            .unwrap();
-        let chain = DescriptorChain {
-            mem: mem,
-            desc_table: desc_table,
-            queue_size: queue_size,
-            ttl: queue_size,
-            index: index,
-            addr: addr,
-            len: len,
-            flags: flags,
-            next: next,
-        };
-
-        if chain.is_valid() { Some(chain) } else { None }
-    }
-
-    fn is_valid(&self) -> bool {
-        if self.mem
-               .checked_offset(self.addr, self.len as u64)
-               .is_none() {
-            false
-        } else if self.has_next() && self.next >= self.queue_size {
-            false
-        } else {
-            true
-        }
+        read_descriptor(mem, desc_table, queue_size, index).map(|desc| {
+            DescriptorChain {
+                mem: mem,
+                desc_table: desc_table,
+                queue_size: queue_size,
+                ttl: queue_size,
+                index: index,
+                addr: GuestAddress(desc.addr),
+                len: desc.len,
+                flags: desc.flags,
+                next: desc.next,
+            }
+        })
     }
 
     /// Gets if this descriptor chain has another descriptor chain linked after it.
@@ -119,6 +143,255 @@ impl<'a> DescriptorChain<'a> {
     }
 }
 
+/// The buffers of a short descriptor chain, read from the descriptor table all at once and kept by
+/// value. Devices that look at every descriptor of their requests, possibly more than once, can
+/// use this instead of going back to guest memory with `next_descriptor` each time.
+#[derive(Clone, Copy)]
+pub struct InlineChain {
+    // The address and length of each buffer, and if it is write only.
+    descs: [(GuestAddress, u32, bool); INLINE_CHAIN_LEN],
+    count: usize,
+}
+
+impl InlineChain {
+    /// Reads the chain starting at `head`. Returns None if it has more than `INLINE_CHAIN_LEN`
+    /// descriptors, which have to be walked with `next_descriptor` instead.
+    pub fn new(head: &DescriptorChain) -> Option<InlineChain> {
+        let mut chain = InlineChain {
+            descs: [(GuestAddress(0), 0, false); INLINE_CHAIN_LEN],
+            count: 0,
+        };
+        let mut desc = Descriptor {
+            addr: head.addr.offset(),
+            len: head.len,
+            flags: head.flags,
+            next: head.next,
+        };
+        let mut ttl = head.ttl;
+        loop {
+            if chain.count == INLINE_CHAIN_LEN {
+                return None;
+            }
+            chain.descs[chain.count] =
+                (GuestAddress(desc.addr), desc.len, desc.flags & VIRTQ_DESC_F_WRITE != 0);
+            chain.count += 1;
+            // The chain ends where `next_descriptor` would end it.
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 || ttl <= 1 {
+                return Some(chain);
+            }
+            desc = match read_descriptor(head.mem, head.desc_table, head.queue_size, desc.next) {
+                Some(d) => d,
+                None => return Some(chain),
+            };
+            ttl -= 1;
+        }
+    }
+
+    /// The number of descriptors in the chain.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// The address and length of the buffer of the descriptor at `index` in the chain, and if it
+    /// is write only.
+    pub fn get(&self, index: usize) -> Option<(GuestAddress, u32, bool)> {
+        if index < self.count {
+            Some(self.descs[index])
+        } else {
+            None
+        }
+    }
+}
+
+/// Errors from accessing the buffers of a descriptor chain through a `Reader` or `Writer`.
+#[derive(Debug)]
+pub enum DescriptorError {
+    /// The chain ran out of descriptors of the right kind before the access was complete.
+    ChainTooShort,
+    /// The buffer of a descriptor couldn't be accessed.
+    GuestMemory(GuestMemoryError),
+}
+
+pub type DescriptorResult<T> = result::Result<T, DescriptorError>;
+
+// A position in the buffers of either the readable or the writable descriptors of a chain.
+struct DescriptorCursor<'a> {
+    mem: &'a GuestMemory,
+    desc: Option<DescriptorChain<'a>>,
+    // Bytes of `desc` already used.
+    offset: u32,
+    write_only: bool,
+    bytes_done: usize,
+}
+
+impl<'a> DescriptorCursor<'a> {
+    fn new(mem: &'a GuestMemory, head: DescriptorChain<'a>, write_only: bool) -> Self {
+        DescriptorCursor {
+            mem: mem,
+            desc: Some(head),
+            offset: 0,
+            write_only: write_only,
+            bytes_done: 0,
+        }
+    }
+
+    // Returns the address and length of what is left of the current descriptor, moving on to the
+    // next one of the right kind once it is used up.
+    fn current(&mut self) -> Option<(GuestAddress, u32)> {
+        loop {
+            let (addr, len, write_only) = match self.desc {
+                Some(ref d) => (d.addr, d.len, d.is_write_only()),
+                None => return None,
+            };
+            if write_only == self.write_only && self.offset < len {
+                // The whole buffer of a valid descriptor is in guest memory.
+                return Some((addr.unchecked_add(self.offset as u64), len - self.offset));
+            }
+            // All readable descriptors come before the writable ones.
+            if write_only && !self.write_only {
+                return None;
+            }
+            self.desc = self.desc.take().and_then(|d| d.next_descriptor());
+            self.offset = 0;
+        }
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.offset += count as u32;
+        self.bytes_done += count;
+    }
+}
+
+/// Reads the buffers of the readable descriptors at the start of a chain in order, as if they
+/// were one buffer.
+pub struct Reader<'a> {
+    cursor: DescriptorCursor<'a>,
+}
+
+impl<'a> Reader<'a> {
+    /// Constructs a reader starting at the beginning of the buffer of `head`.
+    pub fn new(mem: &'a GuestMemory, head: DescriptorChain<'a>) -> Reader<'a> {
+        Reader { cursor: DescriptorCursor::new(mem, head, false) }
+    }
+
+    /// Reads an object, which may be split across descriptors.
+    pub fn read_obj<T: DataInit>(&mut self) -> DescriptorResult<T> {
+        // Safe because any bytes are valid for a DataInit type.
+        let mut obj: T = unsafe { mem::zeroed() };
+        self.read_exact(obj.as_mut_slice())?;
+        Ok(obj)
+    }
+
+    /// Fills `buf` from the chain. Fails with `ChainTooShort` if there isn't enough left.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> DescriptorResult<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, len) = self.cursor.current().ok_or(DescriptorError::ChainTooShort)?;
+            let count = min(len as usize, buf.len() - done);
+            let count = self.cursor
+                .mem
+                .read_slice_at_addr(&mut buf[done..done + count], addr)
+                .map_err(DescriptorError::GuestMemory)?;
+            self.cursor.consume(count);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `count` bytes from the chain into `dst`, returning how many there were.
+    pub fn read_to<F: Write>(&mut self, dst: &mut F, count: usize) -> DescriptorResult<usize> {
+        let mut done = 0;
+        while done < count {
+            let (addr, len) = match self.cursor.current() {
+                Some(c) => c,
+                None => break,
+            };
+            let len = min(len as usize, count - done);
+            self.cursor
+                .mem
+                .write_from_memory(addr, dst, len)
+                .map_err(DescriptorError::GuestMemory)?;
+            self.cursor.consume(len);
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// The number of bytes read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.cursor.bytes_done
+    }
+
+    /// Returns the descriptor after the last one read from, skipping whatever is left of that one.
+    /// This is where a device continues if it addresses the rest of the chain by descriptor.
+    pub fn into_next_descriptor(self) -> Option<DescriptorChain<'a>> {
+        let cursor = self.cursor;
+        match cursor.desc {
+            Some(ref d) if cursor.offset > 0 => d.next_descriptor(),
+            desc => desc,
+        }
+    }
+}
+
+/// Writes to the buffers of the writable descriptors of a chain in order, as if they were one
+/// buffer.
+pub struct Writer<'a> {
+    cursor: DescriptorCursor<'a>,
+}
+
+impl<'a> Writer<'a> {
+    /// Constructs a writer starting at the first writable descriptor of the chain at `head`.
+    pub fn new(mem: &'a GuestMemory, head: DescriptorChain<'a>) -> Writer<'a> {
+        Writer { cursor: DescriptorCursor::new(mem, head, true) }
+    }
+
+    /// Writes an object, which may be split across descriptors.
+    pub fn write_obj<T: DataInit>(&mut self, val: T) -> DescriptorResult<()> {
+        self.write_all(val.as_slice())
+    }
+
+    /// Writes all of `buf` to the chain. Fails with `ChainTooShort` if there isn't enough room.
+    pub fn write_all(&mut self, buf: &[u8]) -> DescriptorResult<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, len) = self.cursor.current().ok_or(DescriptorError::ChainTooShort)?;
+            let count = min(len as usize, buf.len() - done);
+            let count = self.cursor
+                .mem
+                .write_slice_at_addr(&buf[done..done + count], addr)
+                .map_err(DescriptorError::GuestMemory)?;
+            self.cursor.consume(count);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes up to `count` bytes read from `src` to the chain, returning how many there was room
+    /// for.
+    pub fn write_from<F: Read>(&mut self, src: &mut F, count: usize) -> DescriptorResult<usize> {
+        let mut done = 0;
+        while done < count {
+            let (addr, len) = match self.cursor.current() {
+                Some(c) => c,
+                None => break,
+            };
+            let len = min(len as usize, count - done);
+            self.cursor
+                .mem
+                .read_to_memory(addr, src, len)
+                .map_err(DescriptorError::GuestMemory)?;
+            self.cursor.consume(len);
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// The number of bytes written so far.
+    pub fn bytes_written(&self) -> usize {
+        self.cursor.bytes_done
+    }
+}
+
 /// Consuming iterator over all available descriptor chain heads in the queue.
 pub struct AvailIter<'a, 'b> {
     mem: &'a GuestMemory,
@@ -157,6 +430,30 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
     }
 }
 
+/// The state of a queue that is saved in a snapshot: what the driver set up and how far the device
+/// got in the rings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: GuestAddress,
+    pub avail_ring: GuestAddress,
+    pub used_ring: GuestAddress,
+    /// The index in the available ring of the next descriptor chain to take.
+    pub next_avail: u16,
+    /// The index in the used ring of the next descriptor chain to give back.
+    pub next_used: u16,
+}
+
+/// Errors from restoring a queue from a `QueueState`.
+#[derive(Debug, PartialEq)]
+pub enum QueueStateError {
+    /// The saved size is bigger than the device allows.
+    SizeTooLarge { size: u16, max_size: u16 },
+    /// The saved queue is ready but isn't valid in guest memory.
+    InvalidQueue,
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -289,25 +586,78 @@ impl Queue {
         }
     }
 
-    /// Puts an available descriptor head into the used ring for use by the guest.
-    pub fn add_used(&mut self, mem: &GuestMemory, desc_index: u16, len: u32) {
-        if desc_index >= self.actual_size() {
-            error!("attempted to add out of bounds descriptor to used ring: {}",
-                   desc_index);
-            return;
+    /// Saves what is needed to pick up the queue where it is now.
+    pub fn snapshot(&self) -> QueueState {
+        QueueState {
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
         }
+    }
 
-        let used_ring = self.used_ring;
-        let next_used = (self.next_used.0 % self.actual_size()) as usize;
-        let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
+    /// Puts the queue back in the saved `state`. A ready queue has to be valid in `mem`. On error
+    /// the queue is left as it was.
+    pub fn restore(&mut self,
+                   mem: &GuestMemory,
+                   state: &QueueState)
+                   -> result::Result<(), QueueStateError> {
+        if state.size > self.max_size {
+            return Err(QueueStateError::SizeTooLarge {
+                           size: state.size,
+                           max_size: self.max_size,
+                       });
+        }
+        let mut queue = self.clone();
+        queue.size = state.size;
+        queue.ready = state.ready;
+        queue.desc_table = state.desc_table;
+        queue.avail_ring = state.avail_ring;
+        queue.used_ring = state.used_ring;
+        queue.next_avail = Wrapping(state.next_avail);
+        queue.next_used = Wrapping(state.next_used);
+        if queue.ready && !queue.is_valid(mem) {
+            return Err(QueueStateError::InvalidQueue);
+        }
+        *self = queue;
+        Ok(())
+    }
 
-        // These writes can't fail as we are guaranteed to be within the descriptor ring.
-        mem.write_obj_at_addr(desc_index as u32, used_elem)
-            .unwrap();
-        mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
-            .unwrap();
+    /// Puts an available descriptor head into the used ring for use by the guest.
+    pub fn add_used(&mut self, mem: &GuestMemory, desc_index: u16, len: u32) {
+        self.add_used_batch(mem, &[(desc_index, len)]);
+    }
 
-        self.next_used += Wrapping(1);
+    /// Puts several available descriptor heads, each with the length written to it, into the used
+    /// ring, making them visible to the guest all at once.
+    pub fn add_used_batch(&mut self, mem: &GuestMemory, used: &[(u16, u32)]) {
+        let used_ring = self.used_ring;
+        let mut added = false;
+        for &(desc_index, len) in used {
+            if desc_index >= self.actual_size() {
+                error!("attempted to add out of bounds descriptor to used ring: {}",
+                       desc_index);
+                continue;
+            }
+
+            let next_used = (self.next_used.0 % self.actual_size()) as usize;
+            let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
+
+            // These writes can't fail as we are guaranteed to be within the descriptor ring.
+            mem.write_obj_at_addr(desc_index as u32, used_elem)
+                .unwrap();
+            mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
+                .unwrap();
+
+            self.next_used += Wrapping(1);
+            added = true;
+        }
+        if !added {
+            return;
+        }
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
@@ -316,3 +666,232 @@ impl Queue {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use data_model::{Le16, Le32, Le64};
+
+    const QUEUE_SIZE: u16 = 16;
+
+    // Writes a chain of descriptors with the given buffers and flags to a descriptor table at 0,
+    // and returns its head.
+    fn chain<'a>(mem: &'a GuestMemory, descs: &[(u64, u32, u16)]) -> DescriptorChain<'a> {
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let desc = GuestAddress(i as u64 * 16);
+            let next = if i + 1 < descs.len() { VIRTQ_DESC_F_NEXT } else { 0 };
+            mem.write_obj_at_addr(addr, desc).unwrap();
+            mem.write_obj_at_addr(len, desc.unchecked_add(8)).unwrap();
+            mem.write_obj_at_addr(flags | next, desc.unchecked_add(12)).unwrap();
+            mem.write_obj_at_addr(i as u16 + 1, desc.unchecked_add(14)).unwrap();
+        }
+        DescriptorChain::checked_new(mem, GuestAddress(0), QUEUE_SIZE, 0).unwrap()
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct Header {
+        a: Le16,
+        b: Le16,
+        c: Le32,
+        d: Le64,
+    }
+
+    unsafe impl DataInit for Header {}
+
+    #[test]
+    fn read_obj_across_descriptors() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let head = chain(&mem,
+                         &[(0x1000, 3, 0),
+                           (0x2000, 0, 0),
+                           (0x3000, 9, 0),
+                           (0x4000, 8, 0),
+                           (0x5000, 4, VIRTQ_DESC_F_WRITE)]);
+        let bytes: Vec<u8> = (1..21).collect();
+        mem.write_slice_at_addr(&bytes[0..3], GuestAddress(0x1000)).unwrap();
+        mem.write_slice_at_addr(&bytes[3..12], GuestAddress(0x3000)).unwrap();
+        mem.write_slice_at_addr(&bytes[12..20], GuestAddress(0x4000)).unwrap();
+
+        let mut reader = Reader::new(&mem, head);
+        let header: Header = reader.read_obj().unwrap();
+        assert_eq!(header.a.to_native(), 0x0201);
+        assert_eq!(header.b.to_native(), 0x0403);
+        assert_eq!(header.c.to_native(), 0x08070605);
+        assert_eq!(header.d.to_native(), 0x100f0e0d0c0b0a09);
+        assert_eq!(reader.bytes_read(), 16);
+
+        // The reader stops at the writable descriptor.
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to(&mut rest, 100).unwrap(), 4);
+        assert_eq!(rest, &bytes[16..20]);
+        match reader.read_obj::<u8>() {
+            Err(DescriptorError::ChainTooShort) => {}
+            _ => panic!("read past the readable descriptors"),
+        }
+        assert_eq!(reader.into_next_descriptor().unwrap().addr, GuestAddress(0x5000));
+    }
+
+    #[test]
+    fn next_descriptor_after_read() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let head = chain(&mem,
+                         &[(0x1000, 16, 0), (0x2000, 512, 0), (0x3000, 1, VIRTQ_DESC_F_WRITE)]);
+        let mut reader = Reader::new(&mem, head.clone());
+        reader.read_obj::<u64>().unwrap();
+        // The rest of the first descriptor is skipped.
+        assert_eq!(reader.into_next_descriptor().unwrap().addr, GuestAddress(0x2000));
+        assert_eq!(Reader::new(&mem, head).into_next_descriptor().unwrap().addr,
+                   GuestAddress(0x1000));
+    }
+
+    #[test]
+    fn write_across_descriptors() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let head = chain(&mem,
+                         &[(0x1000, 4, 0),
+                           (0x2000, 2, VIRTQ_DESC_F_WRITE),
+                           (0x3000, 6, VIRTQ_DESC_F_WRITE)]);
+        let mut writer = Writer::new(&mem, head);
+        writer.write_obj(0x0807060504030201u64).unwrap();
+        assert_eq!(writer.write_from(&mut &[9u8, 10, 11][..], 3).unwrap(), 0);
+        assert_eq!(writer.bytes_written(), 8);
+        match writer.write_obj(0u32) {
+            Err(DescriptorError::ChainTooShort) => {}
+            _ => panic!("wrote past the end of the chain"),
+        }
+
+        // Nothing is written to the readable descriptor.
+        let mut data = [0u8; 4];
+        mem.read_slice_at_addr(&mut data, GuestAddress(0x1000)).unwrap();
+        assert_eq!(data, [0; 4]);
+        let mut data = [0u8; 2];
+        mem.read_slice_at_addr(&mut data, GuestAddress(0x2000)).unwrap();
+        assert_eq!(data, [1, 2]);
+        let mut data = [0u8; 6];
+        mem.read_slice_at_addr(&mut data, GuestAddress(0x3000)).unwrap();
+        assert_eq!(data, [3, 4, 5, 6, 7, 8]);
+    }
+
+    // The buffers of the chain at `head`, found with `next_descriptor`.
+    fn walk_chain(head: &DescriptorChain) -> Vec<(GuestAddress, u32, bool)> {
+        let mut buffers = Vec::new();
+        let mut desc = Some(head.clone());
+        while let Some(d) = desc {
+            buffers.push((d.addr, d.len, d.is_write_only()));
+            desc = d.next_descriptor();
+        }
+        buffers
+    }
+
+    #[test]
+    fn inline_chain_matches_descriptors() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let head = chain(&mem,
+                         &[(0x1000, 3, 0), (0x2000, 0, 0), (0x3000, 9, VIRTQ_DESC_F_WRITE)]);
+        let inline = InlineChain::new(&head).unwrap();
+        let buffers = walk_chain(&head);
+        assert_eq!(inline.len(), buffers.len());
+        for (i, &buffer) in buffers.iter().enumerate() {
+            assert_eq!(inline.get(i), Some(buffer));
+        }
+        assert_eq!(inline.get(buffers.len()), None);
+
+        // A chain that runs out of guest memory ends in the same place both ways.
+        mem.write_obj_at_addr(0xfff0u64, GuestAddress(16)).unwrap();
+        mem.write_obj_at_addr(0x100u32, GuestAddress(16 + 8)).unwrap();
+        assert_eq!(walk_chain(&head).len(), 1);
+        assert_eq!(InlineChain::new(&head).unwrap().len(), 1);
+
+        // Longer chains are left to `next_descriptor`.
+        let head = chain(&mem, &[(0x1000, 1, 0); INLINE_CHAIN_LEN + 1]);
+        assert!(InlineChain::new(&head).is_none());
+        assert_eq!(walk_chain(&head).len(), INLINE_CHAIN_LEN + 1);
+    }
+
+    #[test]
+    #[ignore] // benchmark, run with --ignored --nocapture
+    fn inline_chain_speed() {
+        const ITERATIONS: u32 = 100000;
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let head = chain(&mem,
+                         &[(0x1000, 16, 0), (0x2000, 512, 0), (0x3000, 1, VIRTQ_DESC_F_WRITE)]);
+
+        // Goes over all the buffers twice, the way a device checks a request before carrying it
+        // out.
+        let start = Instant::now();
+        let mut total = 0;
+        for _ in 0..ITERATIONS {
+            let chain = InlineChain::new(&head).unwrap();
+            for _ in 0..2 {
+                for i in 0..chain.len() {
+                    total += chain.get(i).unwrap().1 as usize;
+                }
+            }
+        }
+        let inline_time = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            for _ in 0..2 {
+                let mut desc = Some(head.clone());
+                while let Some(d) = desc {
+                    total += d.len as usize;
+                    desc = d.next_descriptor();
+                }
+            }
+        }
+        let chain_time = start.elapsed();
+
+        assert_eq!(total, 2 * 2 * 529 * ITERATIONS as usize);
+        println!("{} chains: inline {:?}, descriptor by descriptor {:?}",
+                 ITERATIONS,
+                 inline_time,
+                 chain_time);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = 4;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        chain(&mem, &[(0x3000, 1, 0), (0x3000, 1, 0), (0x3000, 1, 0)]);
+        // The driver offers descriptors 0 and 1, and the device takes the first one.
+        for (i, &desc) in [0u16, 1].iter().enumerate() {
+            mem.write_obj_at_addr(desc, GuestAddress(0x1004 + i as u64 * 2)).unwrap();
+        }
+        mem.write_obj_at_addr(2u16, GuestAddress(0x1002)).unwrap();
+        let head = queue.iter(&mem).next().unwrap();
+        queue.add_used(&mem, head.index, 0);
+
+        let state = queue.snapshot();
+        assert_eq!(state.next_avail, 1);
+        assert_eq!(state.next_used, 1);
+        let mut restored = Queue::new(QUEUE_SIZE);
+        restored.restore(&mem, &state).unwrap();
+        assert_eq!(restored.snapshot(), state);
+        // The restored queue carries on with the second descriptor.
+        let heads: Vec<u16> = restored.iter(&mem).map(|d| d.index).collect();
+        assert_eq!(heads, vec![1]);
+        restored.add_used(&mem, 1, 0);
+        assert_eq!(mem.read_obj_from_addr::<u16>(GuestAddress(0x2002)).unwrap(), 2);
+
+        let mut bad = state;
+        bad.size = QUEUE_SIZE * 2;
+        assert_eq!(restored.restore(&mem, &bad),
+                   Err(QueueStateError::SizeTooLarge {
+                           size: QUEUE_SIZE * 2,
+                           max_size: QUEUE_SIZE,
+                       }));
+        let mut bad = state;
+        bad.used_ring = GuestAddress(0xfff0);
+        assert_eq!(restored.restore(&mem, &bad), Err(QueueStateError::InvalidQueue));
+        // A failed restore leaves the queue alone.
+        assert_eq!(restored.snapshot().next_avail, 2);
+    }
+}
@@ -166,7 +166,9 @@ impl VirtioDevice for Rng {
                 interrupt_evt: EventFd,
                 status: Arc<AtomicUsize>,
                 mut queues: Vec<Queue>,
-                mut queue_evts: Vec<EventFd>) {
+                mut queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
         if queues.len() != 1 || queue_evts.len() != 1 {
             return;
         }
@@ -0,0 +1,129 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sys_util::{MemoryMapping, MmapError};
+use vm_control::DeviceStats;
+
+const REQUESTS_IN: usize = 0;
+const BYTES_IN: usize = 1;
+const REQUESTS_OUT: usize = 2;
+const BYTES_OUT: usize = 3;
+const ERRORS: usize = 4;
+const NUM_COUNTERS: usize = 5;
+
+/// A fixed number of counters in a shared anonymous mapping.
+///
+/// Updates made by a device that was moved into a forked and jailed process are still seen by
+/// clones held by the main process.
+#[derive(Clone)]
+pub struct SharedCounters {
+    mem: Arc<MemoryMapping>,
+    len: usize,
+}
+
+impl SharedCounters {
+    /// Creates `len` counters, all zero.
+    pub fn new(len: usize) -> Result<SharedCounters, MmapError> {
+        let mem = MemoryMapping::new(len * size_of::<AtomicUsize>())?;
+        Ok(SharedCounters {
+               mem: Arc::new(mem),
+               len: len,
+           })
+    }
+
+    /// Returns the counter at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than the number of counters.
+    pub fn get(&self, index: usize) -> &AtomicUsize {
+        assert!(index < self.len);
+        // Safe because the mapping is page aligned, large enough for every counter, zeroed when it
+        // was created, and lives as long as `self`.
+        unsafe { &*(self.mem.as_ptr() as *const AtomicUsize).offset(index as isize) }
+    }
+}
+
+/// Throughput and error counters of one device, cheap enough to update on every request.
+///
+/// The counters are `SharedCounters`, so they can be read from outside the device's process. "In"
+/// counts work toward the guest and "out" counts work from the guest, matching
+/// `vm_control::DeviceStats`.
+#[derive(Clone)]
+pub struct DeviceCounters {
+    name: String,
+    counters: SharedCounters,
+}
+
+impl DeviceCounters {
+    /// Creates zeroed counters for the device with the given name.
+    pub fn new(name: &str) -> Result<DeviceCounters, MmapError> {
+        Ok(DeviceCounters {
+               name: name.to_owned(),
+               counters: SharedCounters::new(NUM_COUNTERS)?,
+           })
+    }
+
+    fn counter(&self, index: usize) -> &AtomicUsize {
+        self.counters.get(index)
+    }
+
+    /// Counts a request that moved `bytes` of data toward the guest.
+    pub fn add_in(&self, bytes: usize) {
+        self.counter(REQUESTS_IN).fetch_add(1, Ordering::Relaxed);
+        self.counter(BYTES_IN).fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a request that moved `bytes` of data from the guest.
+    pub fn add_out(&self, bytes: usize) {
+        self.counter(REQUESTS_OUT).fetch_add(1, Ordering::Relaxed);
+        self.counter(BYTES_OUT).fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a request that failed.
+    pub fn add_error(&self) {
+        self.counter(ERRORS).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads the current value of every counter.
+    pub fn snapshot(&self) -> DeviceStats {
+        let load = |index| self.counter(index).load(Ordering::Relaxed) as u64;
+        DeviceStats {
+            name: self.name.clone(),
+            requests_in: load(REQUESTS_IN),
+            bytes_in: load(BYTES_IN),
+            requests_out: load(REQUESTS_OUT),
+            bytes_out: load(BYTES_OUT),
+            errors: load(ERRORS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let counters = DeviceCounters::new("test0").unwrap();
+        let clone = counters.clone();
+        counters.add_in(512);
+        counters.add_in(1024);
+        clone.add_out(4096);
+        clone.add_error();
+        assert_eq!(counters.snapshot(),
+                   DeviceStats {
+                       name: "test0".to_owned(),
+                       requests_in: 2,
+                       bytes_in: 1536,
+                       requests_out: 1,
+                       bytes_out: 4096,
+                       errors: 1,
+                   });
+    }
+}
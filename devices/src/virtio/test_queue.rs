@@ -0,0 +1,94 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The driver side of a virtqueue, for testing devices.
+
+use sys_util::{GuestAddress, GuestMemory};
+
+use super::Queue;
+
+// VIRTQ_DESC_F_WRITE
+const DESC_F_WRITE: u16 = 2;
+
+/// A minimal driver side of a split virtqueue laid out in guest memory at `base`. The descriptor
+/// table is at `base`, the available ring at `base + 0x1000`, the used ring at `base + 0x2000` and
+/// the buffer of descriptor `n` at `base + 0x10000 + n * 0x1000`.
+pub struct TestQueue {
+    base: u64,
+    size: u16,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl TestQueue {
+    /// Returns the driver side of a queue of `size` entries at `base`, and the device side to hand
+    /// to the device under test.
+    pub fn new(mem: &GuestMemory, base: u64, size: u16) -> (TestQueue, Queue) {
+        let mut queue = Queue::new(size);
+        queue.size = size;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(base);
+        queue.avail_ring = GuestAddress(base + 0x1000);
+        queue.used_ring = GuestAddress(base + 0x2000);
+        assert!(queue.is_valid(mem));
+        (TestQueue {
+             base: base,
+             size: size,
+             next_avail: 0,
+             next_used: 0,
+         },
+         queue)
+    }
+
+    /// The address of the buffer of descriptor `index`.
+    pub fn buf_addr(&self, index: u16) -> GuestAddress {
+        GuestAddress(self.base + 0x10000 + index as u64 * 0x1000)
+    }
+
+    /// Posts a single descriptor at index `index` with a buffer of `len` bytes. The buffer is
+    /// filled with `data` for the device to read, or is writable by the device if `data` is None.
+    pub fn post(&mut self, mem: &GuestMemory, index: u16, data: Option<&[u8]>, len: u32) {
+        let buf = self.buf_addr(index);
+        let flags = match data {
+            Some(d) => {
+                mem.write_slice_at_addr(d, buf).unwrap();
+                0
+            }
+            None => DESC_F_WRITE,
+        };
+        let desc = GuestAddress(self.base + index as u64 * 16);
+        mem.write_obj_at_addr(buf.offset() as u64, desc).unwrap();
+        mem.write_obj_at_addr(len, desc.unchecked_add(8)).unwrap();
+        mem.write_obj_at_addr(flags, desc.unchecked_add(12)).unwrap();
+        mem.write_obj_at_addr(0u16, desc.unchecked_add(14)).unwrap();
+
+        let avail = GuestAddress(self.base + 0x1000);
+        let slot = 4 + (self.next_avail % self.size) as u64 * 2;
+        mem.write_obj_at_addr(index, avail.unchecked_add(slot)).unwrap();
+        self.next_avail = self.next_avail.wrapping_add(1);
+        mem.write_obj_at_addr(self.next_avail, avail.unchecked_add(2)).unwrap();
+    }
+
+    /// Returns the descriptor index and contents of the next used buffer, if any.
+    pub fn pop_used_desc(&mut self, mem: &GuestMemory) -> Option<(u16, Vec<u8>)> {
+        let used = GuestAddress(self.base + 0x2000);
+        let used_idx: u16 = mem.read_obj_from_addr(used.unchecked_add(2)).unwrap();
+        if used_idx == self.next_used {
+            return None;
+        }
+        let elem = used.unchecked_add(4 + (self.next_used % self.size) as u64 * 8);
+        self.next_used = self.next_used.wrapping_add(1);
+        let index: u32 = mem.read_obj_from_addr(elem).unwrap();
+        let len: u32 = mem.read_obj_from_addr(elem.unchecked_add(4)).unwrap();
+        let mut data = vec![0u8; len as usize];
+        mem.read_slice_at_addr(&mut data, self.buf_addr(index as u16))
+            .unwrap();
+        Some((index as u16, data))
+    }
+
+    /// Returns the contents of the next used buffer, if any.
+    pub fn pop_used(&mut self, mem: &GuestMemory) -> Option<Vec<u8>> {
+        self.pop_used_desc(mem).map(|(_, data)| data)
+    }
+}
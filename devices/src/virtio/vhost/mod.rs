@@ -15,7 +15,7 @@ mod vsock;
 mod worker;
 
 pub use self::net::Net;
-pub use self::vsock::Vsock;
+pub use self::vsock::{Vsock, DEFAULT_QUEUE_SIZE as VSOCK_DEFAULT_QUEUE_SIZE};
 
 #[derive(Debug)]
 pub enum Error {
@@ -67,6 +67,8 @@ pub enum Error {
     VhostVsockSetCid(VhostError),
     /// Failed to start vhost-vsock driver.
     VhostVsockStart(VhostError),
+    /// Requested vring size is zero, not a power of two, or too large.
+    InvalidQueueSize(u16),
     /// Failed to create vhost eventfd.
     VhostIrqCreate(SysError),
     /// Failed to read vhost eventfd.
@@ -45,11 +45,27 @@ where
                netmask: Ipv4Addr,
                mac_addr: MacAddress,
                mem: &GuestMemory) -> Result<Net<T, U>> {
-        let kill_evt = EventFd::new().map_err(Error::CreateKillEventFd)?;
-
         let tap: T = T::new(true).map_err(Error::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
         tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
+        Self::with_tap(tap, mac_addr, mem)
+    }
+
+    /// Create a new virtio network device on an existing tap interface, such as one created and
+    /// configured by a network manager. The tap's addresses are left untouched.
+    ///
+    /// This is unsafe because the device takes ownership of `fd`, so it must be valid and not
+    /// owned by anything else.
+    pub unsafe fn from_tap_fd(fd: RawFd,
+                              mac_addr: MacAddress,
+                              mem: &GuestMemory) -> Result<Net<T, U>> {
+        let tap: T = T::from_raw_fd(fd).map_err(Error::TapOpen)?;
+        Self::with_tap(tap, mac_addr, mem)
+    }
+
+    fn with_tap(tap: T, mac_addr: MacAddress, mem: &GuestMemory) -> Result<Net<T, U>> {
+        let kill_evt = EventFd::new().map_err(Error::CreateKillEventFd)?;
+
         tap.set_mac_address(mac_addr).map_err(Error::TapSetMacAddress)?;
 
         // Set offload flags to match the virtio features below.
@@ -178,6 +194,8 @@ where
         status: Arc<AtomicUsize>,
         queues: Vec<Queue>,
         queue_evts: Vec<EventFd>,
+        _queue_interrupt_evts: Option<Vec<EventFd>>,
+        _max_chains_per_pass: usize,
     ) {
         if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
             error!("net: expected {} queues, got {}", NUM_QUEUES, queues.len());
@@ -227,10 +245,13 @@ where
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
     use std::result;
     use net_util::fakes::FakeTap;
     use sys_util::{GuestAddress, GuestMemory, GuestMemoryError};
     use vhost::net::fakes::FakeNet;
+    use virtio::MAX_CHAINS_PER_PASS;
 
     fn create_guest_memory() -> result::Result<GuestMemory, GuestMemoryError> {
         let start_addr1 = GuestAddress(0x0);
@@ -250,7 +271,25 @@ pub mod tests {
 
     #[test]
     fn create_net() {
-        create_net_common();
+        let net = create_net_common();
+        assert!(net.tap.as_ref().unwrap().addr_configured());
+    }
+
+    #[test]
+    fn create_net_from_tap_fd() {
+        let guest_memory = create_guest_memory().unwrap();
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let fd = sock.into_raw_fd();
+        // The fd was just created and ownership is passed to the device.
+        let net = unsafe {
+            Net::<FakeTap, FakeNet<FakeTap>>::from_tap_fd(
+                fd,
+                "de:21:e8:47:6b:6a".parse().unwrap(),
+                &guest_memory,
+            ).unwrap()
+        };
+        assert!(net.keep_fds().contains(&fd));
+        assert!(!net.tap.as_ref().unwrap().addr_configured());
     }
 
     #[test]
@@ -287,6 +326,8 @@ pub mod tests {
             Arc::new(AtomicUsize::new(0)),
             vec![Queue::new(1)],
             vec![EventFd::new().unwrap()],
+            None,
+            MAX_CHAINS_PER_PASS,
         );
     }
 }
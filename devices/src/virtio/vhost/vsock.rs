@@ -17,23 +17,53 @@ use super::{Error, Result};
 use super::super::{Queue, VirtioDevice, TYPE_VSOCK};
 use super::worker::Worker;
 
-const QUEUE_SIZE: u16 = 256;
+/// Default number of descriptors in each of the rx, tx and event queues.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+/// Largest vring the vhost driver accepts for VHOST_SET_VRING_NUM.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
 const NUM_QUEUES: usize = 3;
-const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE; NUM_QUEUES];
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
 
 pub struct Vsock {
     worker_kill_evt: Option<EventFd>,
     kill_evt: Option<EventFd>,
     vhost_handle: Option<VhostVsockHandle>,
     cid: u64,
+    queue_sizes: [u16; NUM_QUEUES],
     interrupt: Option<EventFd>,
     avail_features: u64,
     acked_features: u64,
 }
 
+/// Checks that `size` can be programmed into a vhost vring: the kernel requires a non-zero power of
+/// two no larger than `MAX_QUEUE_SIZE`.
+fn validate_queue_size(size: u16) -> Result<u16> {
+    if size == 0 || !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+        return Err(Error::InvalidQueueSize(size));
+    }
+    Ok(size)
+}
+
+fn queue_sizes(rx_queue_size: u16, tx_queue_size: u16) -> Result<[u16; NUM_QUEUES]> {
+    let mut sizes = [DEFAULT_QUEUE_SIZE; NUM_QUEUES];
+    sizes[RX_QUEUE] = validate_queue_size(rx_queue_size)?;
+    sizes[TX_QUEUE] = validate_queue_size(tx_queue_size)?;
+    Ok(sizes)
+}
+
 impl Vsock {
     /// Create a new virtio-vsock device with the given VM cid.
-    pub fn new(cid: u64, mem: &GuestMemory) -> Result<Vsock> {
+    ///
+    /// # Arguments
+    /// * `cid` - CID to assign to the guest.
+    /// * `mem` - Guest memory mapping.
+    /// * `rx_queue_size` - Number of descriptors in the rx vring, bounding how many receive buffers
+    ///                     the guest can have posted at once.
+    /// * `tx_queue_size` - Number of descriptors in the tx vring.
+    pub fn new(cid: u64, mem: &GuestMemory, rx_queue_size: u16, tx_queue_size: u16)
+               -> Result<Vsock> {
+        let queue_sizes = queue_sizes(rx_queue_size, tx_queue_size)?;
         let kill_evt = EventFd::new().map_err(Error::CreateKillEventFd)?;
         let handle = VhostVsockHandle::new(mem).map_err(Error::VhostOpen)?;
 
@@ -47,6 +77,7 @@ impl Vsock {
             kill_evt: Some(kill_evt),
             vhost_handle: Some(handle),
             cid: cid,
+            queue_sizes: queue_sizes,
             interrupt: Some(EventFd::new().map_err(Error::VhostIrqCreate)?),
             avail_features: avail_features,
             acked_features: 0,
@@ -59,6 +90,7 @@ impl Vsock {
             kill_evt: None,
             vhost_handle: None,
             cid: cid,
+            queue_sizes: [DEFAULT_QUEUE_SIZE; NUM_QUEUES],
             interrupt: None,
             avail_features: features,
             acked_features: 0,
@@ -106,7 +138,7 @@ impl VirtioDevice for Vsock {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -171,6 +203,8 @@ impl VirtioDevice for Vsock {
         status: Arc<AtomicUsize>,
         queues: Vec<Queue>,
         queue_evts: Vec<EventFd>,
+        _queue_interrupt_evts: Option<Vec<EventFd>>,
+        _max_chains_per_pass: usize,
     ) {
         if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
             error!("net: expected {} queues, got {}", NUM_QUEUES, queues.len());
@@ -182,6 +216,7 @@ impl VirtioDevice for Vsock {
                 if let Some(kill_evt) = self.worker_kill_evt.take() {
                     let acked_features = self.acked_features;
                     let cid = self.cid;
+                    let queue_sizes = self.queue_sizes;
                     let worker_result = thread::Builder::new()
                         .name("vhost_vsock".to_string())
                         .spawn(move || {
@@ -200,7 +235,7 @@ impl VirtioDevice for Vsock {
                                 Ok(())
                             };
                             let result =
-                                worker.run(queue_evts, QUEUE_SIZES, kill_evt, activate_vqs);
+                                worker.run(queue_evts, &queue_sizes, kill_evt, activate_vqs);
                             if let Err(e) = result {
                                 error!("vsock worker thread exited with error: {:?}", e);
                             }
@@ -283,4 +318,28 @@ mod tests {
         assert_eq!(0, vsock.features(559));
         assert_eq!(0, vsock.features(3));
     }
+
+    #[test]
+    fn queue_size_validation() {
+        assert_eq!(validate_queue_size(1).unwrap(), 1);
+        assert_eq!(validate_queue_size(1024).unwrap(), 1024);
+        assert_eq!(validate_queue_size(MAX_QUEUE_SIZE).unwrap(), MAX_QUEUE_SIZE);
+
+        for &size in &[0, 3, 1000, 0xffff] {
+            match validate_queue_size(size) {
+                Err(Error::InvalidQueueSize(s)) => assert_eq!(s, size),
+                _ => panic!("queue size {} should have been rejected", size),
+            }
+        }
+    }
+
+    #[test]
+    fn queue_sizes_order() {
+        // The worker programs VHOST_SET_VRING_NUM with these sizes by queue index, so rx and tx
+        // must land in the vrings the vhost driver uses for them.
+        let sizes = queue_sizes(1024, 64).unwrap();
+        assert_eq!(sizes, [1024, 64, DEFAULT_QUEUE_SIZE]);
+        assert!(queue_sizes(1024, 100).is_err());
+        assert!(queue_sizes(0, 64).is_err());
+    }
 }
@@ -0,0 +1,1087 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Userspace virtio-vsock device, used when the host's vhost-vsock driver is unavailable.
+//!
+//! Stream connections made by the guest to the host (CID 2) on port `P` are bridged to the Unix
+//! stream socket named `P` in the device's socket directory. Connections initiated from the host
+//! side are not supported.
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sys_util::{self, EventFd, GuestAddress, GuestMemory, PollContext, PollToken, WatchingEvents};
+
+use super::{VirtioDevice, Queue, INTERRUPT_STATUS_USED_RING, TYPE_VSOCK};
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 3;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// CID that always refers to the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+// Size of struct virtio_vsock_hdr from linux/virtio_vsock.h.
+const HDR_SIZE: usize = 44;
+
+const VSOCK_TYPE_STREAM: u16 = 1;
+
+const VSOCK_OP_REQUEST: u16 = 1;
+const VSOCK_OP_RESPONSE: u16 = 2;
+const VSOCK_OP_RST: u16 = 3;
+const VSOCK_OP_SHUTDOWN: u16 = 4;
+const VSOCK_OP_RW: u16 = 5;
+const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+const VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+// Receive buffer space advertised to the guest for each connection. Data from the guest that the
+// host socket doesn't take right away is queued, up to this much per connection.
+const CONN_BUF_ALLOC: u32 = 256 * 1024;
+// Largest payload of a packet in either direction.
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+// Longer tx chains are rejected without reading more than their header.
+const MAX_TX_PACKET: usize = HDR_SIZE + MAX_PKT_PAYLOAD;
+// Most connections the guest can have open at once. Further requests are reset.
+const MAX_CONNECTIONS: usize = 1024;
+// Most control packets that can wait for an rx buffer. Past this they are dropped, which only
+// happens if the guest stops posting rx buffers.
+const MAX_PENDING: usize = 4 * MAX_CONNECTIONS;
+
+/// The header that starts every packet on the rx and tx queues.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn from_bytes(buf: &[u8]) -> PacketHeader {
+        PacketHeader {
+            src_cid: LittleEndian::read_u64(&buf[0..8]),
+            dst_cid: LittleEndian::read_u64(&buf[8..16]),
+            src_port: LittleEndian::read_u32(&buf[16..20]),
+            dst_port: LittleEndian::read_u32(&buf[20..24]),
+            len: LittleEndian::read_u32(&buf[24..28]),
+            type_: LittleEndian::read_u16(&buf[28..30]),
+            op: LittleEndian::read_u16(&buf[30..32]),
+            flags: LittleEndian::read_u32(&buf[32..36]),
+            buf_alloc: LittleEndian::read_u32(&buf[36..40]),
+            fwd_cnt: LittleEndian::read_u32(&buf[40..44]),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; HDR_SIZE] {
+        let mut buf = [0u8; HDR_SIZE];
+        LittleEndian::write_u64(&mut buf[0..8], self.src_cid);
+        LittleEndian::write_u64(&mut buf[8..16], self.dst_cid);
+        LittleEndian::write_u32(&mut buf[16..20], self.src_port);
+        LittleEndian::write_u32(&mut buf[20..24], self.dst_port);
+        LittleEndian::write_u32(&mut buf[24..28], self.len);
+        LittleEndian::write_u16(&mut buf[28..30], self.type_);
+        LittleEndian::write_u16(&mut buf[30..32], self.op);
+        LittleEndian::write_u32(&mut buf[32..36], self.flags);
+        LittleEndian::write_u32(&mut buf[36..40], self.buf_alloc);
+        LittleEndian::write_u32(&mut buf[40..44], self.fwd_cnt);
+        buf
+    }
+}
+
+// Connections are identified by (guest port, host port).
+type ConnKey = (u32, u32);
+
+fn conn_token(key: ConnKey) -> u64 {
+    (key.0 as u64) << 32 | key.1 as u64
+}
+
+fn conn_key(token: u64) -> ConnKey {
+    ((token >> 32) as u32, token as u32)
+}
+
+struct Connection {
+    stream: UnixStream,
+    // Credit information last reported by the guest.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // Bytes sent to the guest.
+    tx_cnt: u32,
+    // Bytes received from the guest and written to the host socket.
+    fwd_cnt: u32,
+    // Value of `fwd_cnt` last reported to the guest.
+    last_fwd_cnt_sent: u32,
+    // Data from the guest that the host socket hasn't taken yet.
+    to_host: VecDeque<u8>,
+    // Set once the host socket reached end of file or the guest will not receive any more data.
+    read_done: bool,
+    // What the host socket is watched for in `conn_poll_ctx`.
+    polled: WatchingEvents,
+}
+
+impl Connection {
+    // Number of bytes that can be sent to the guest without overflowing its receive buffer.
+    fn peer_free(&self) -> u32 {
+        self.peer_buf_alloc.wrapping_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+// A writable descriptor chain posted by the guest on the rx queue.
+struct RxChain {
+    index: u16,
+    descs: Vec<(GuestAddress, usize)>,
+}
+
+impl RxChain {
+    fn capacity(&self) -> usize {
+        self.descs.iter().map(|&(_, len)| len).sum()
+    }
+}
+
+struct Worker {
+    mem: GuestMemory,
+    guest_cid: u64,
+    socket_dir: PathBuf,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    connections: HashMap<ConnKey, Connection>,
+    conn_poll_ctx: PollContext<u64>,
+    rx_chains: VecDeque<RxChain>,
+    // Control packets waiting for an rx buffer: connection, op, and flags.
+    pending: VecDeque<(ConnKey, u16, u32)>,
+}
+
+impl Worker {
+    fn new(mem: GuestMemory,
+           guest_cid: u64,
+           socket_dir: PathBuf,
+           rx_queue: Queue,
+           tx_queue: Queue,
+           interrupt_status: Arc<AtomicUsize>,
+           interrupt_evt: EventFd)
+           -> sys_util::Result<Worker> {
+        Ok(Worker {
+               mem: mem,
+               guest_cid: guest_cid,
+               socket_dir: socket_dir,
+               rx_queue: rx_queue,
+               tx_queue: tx_queue,
+               interrupt_status: interrupt_status,
+               interrupt_evt: interrupt_evt,
+               connections: HashMap::new(),
+               conn_poll_ctx: PollContext::new()?,
+               rx_chains: VecDeque::new(),
+               pending: VecDeque::new(),
+           })
+    }
+
+    fn header(&self, key: ConnKey, op: u16, flags: u32, len: u32) -> PacketHeader {
+        let (buf_alloc, fwd_cnt) = match self.connections.get(&key) {
+            Some(conn) => (CONN_BUF_ALLOC, conn.fwd_cnt),
+            None => (0, 0),
+        };
+        PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.1,
+            dst_port: key.0,
+            len: len,
+            type_: VSOCK_TYPE_STREAM,
+            op: op,
+            flags: flags,
+            buf_alloc: buf_alloc,
+            fwd_cnt: fwd_cnt,
+        }
+    }
+
+    fn remove_connection(&mut self, key: ConnKey) {
+        if let Some(conn) = self.connections.remove(&key) {
+            if conn.polled != WatchingEvents::empty() {
+                let _ = self.conn_poll_ctx.delete(&conn.stream);
+            }
+        }
+    }
+
+    fn reset_connection(&mut self, key: ConnKey) {
+        self.remove_connection(key);
+        self.queue_control(key, VSOCK_OP_RST, 0);
+    }
+
+    // Queues a control packet for the guest. A packet already waiting with the same contents is
+    // enough, as the header is only filled in once it is sent.
+    fn queue_control(&mut self, key: ConnKey, op: u16, flags: u32) {
+        if self.pending.contains(&(key, op, flags)) {
+            return;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            warn!("vsock: dropped control packet for port {}, no rx buffers", key.0);
+            return;
+        }
+        self.pending.push_back((key, op, flags));
+    }
+
+    // Consumes all packets the guest has posted on the tx queue. Returns true if any descriptors
+    // were returned to the guest.
+    fn process_tx_queue(&mut self) -> bool {
+        let mut packets = Vec::new();
+        for avail_desc in self.tx_queue.iter(&self.mem) {
+            let index = avail_desc.index;
+            let mut data = Vec::new();
+            let mut too_long = false;
+            let mut desc = Some(avail_desc);
+            while let Some(d) = desc {
+                if d.is_write_only() {
+                    break;
+                }
+                let start = data.len();
+                let mut len = d.len as usize;
+                if start + len > MAX_TX_PACKET {
+                    // Only the header is read, to know which connection to reset.
+                    too_long = true;
+                    len = min(len, HDR_SIZE.saturating_sub(start));
+                }
+                data.resize(start + len, 0);
+                if self.mem.read_slice_at_addr(&mut data[start..], d.addr).is_err() {
+                    data.truncate(start);
+                    break;
+                }
+                if too_long {
+                    break;
+                }
+                desc = d.next_descriptor();
+            }
+            packets.push((index, data, too_long));
+        }
+
+        for &(index, _, _) in packets.iter() {
+            self.tx_queue.add_used(&self.mem, index, 0);
+        }
+
+        for &(_, ref data, too_long) in packets.iter() {
+            if data.len() < HDR_SIZE {
+                error!("vsock: tx packet too short: {}", data.len());
+                continue;
+            }
+            let hdr = PacketHeader::from_bytes(&data[..HDR_SIZE]);
+            if too_long || hdr.len as usize > MAX_PKT_PAYLOAD {
+                error!("vsock: tx packet longer than {} bytes", MAX_TX_PACKET);
+                if hdr.op != VSOCK_OP_RST {
+                    self.reset_connection((hdr.src_port, hdr.dst_port));
+                }
+                continue;
+            }
+            let payload_end = min(data.len(), HDR_SIZE + hdr.len as usize);
+            self.handle_tx_packet(hdr, &data[HDR_SIZE..payload_end]);
+        }
+
+        !packets.is_empty()
+    }
+
+    fn handle_tx_packet(&mut self, hdr: PacketHeader, payload: &[u8]) {
+        let key = (hdr.src_port, hdr.dst_port);
+
+        if hdr.src_cid != self.guest_cid || hdr.dst_cid != VSOCK_HOST_CID ||
+           hdr.type_ != VSOCK_TYPE_STREAM {
+            if hdr.op != VSOCK_OP_RST {
+                self.reset_connection(key);
+            }
+            return;
+        }
+
+        if hdr.op == VSOCK_OP_REQUEST {
+            self.connect(key, &hdr);
+            return;
+        }
+
+        match self.connections.get_mut(&key) {
+            Some(conn) => {
+                conn.peer_buf_alloc = hdr.buf_alloc;
+                conn.peer_fwd_cnt = hdr.fwd_cnt;
+            }
+            None => {
+                if hdr.op != VSOCK_OP_RST {
+                    self.reset_connection(key);
+                }
+                return;
+            }
+        }
+
+        match hdr.op {
+            VSOCK_OP_RW => {
+                let overrun = {
+                    // The unwrap is safe because the connection was found above.
+                    let conn = self.connections.get_mut(&key).unwrap();
+                    // The guest may only have as much data in flight as it was given credit for.
+                    if conn.to_host.len() + payload.len() > CONN_BUF_ALLOC as usize {
+                        true
+                    } else {
+                        conn.to_host.extend(payload.iter());
+                        false
+                    }
+                };
+                if overrun {
+                    warn!("vsock: guest sent more data than its credit allows");
+                    self.reset_connection(key);
+                    return;
+                }
+                self.flush_connection(key);
+            }
+            VSOCK_OP_SHUTDOWN => {
+                if hdr.flags & (VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND) ==
+                   VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND {
+                    self.reset_connection(key);
+                    return;
+                }
+                let conn = self.connections.get_mut(&key).unwrap();
+                if hdr.flags & VSOCK_SHUTDOWN_RCV != 0 {
+                    conn.read_done = true;
+                }
+                if hdr.flags & VSOCK_SHUTDOWN_SEND != 0 {
+                    let _ = conn.stream.shutdown(Shutdown::Write);
+                }
+            }
+            VSOCK_OP_RST => self.remove_connection(key),
+            VSOCK_OP_CREDIT_REQUEST => self.queue_control(key, VSOCK_OP_CREDIT_UPDATE, 0),
+            // The new credit was recorded above.
+            VSOCK_OP_CREDIT_UPDATE => {}
+            _ => self.reset_connection(key),
+        }
+    }
+
+    fn connect(&mut self, key: ConnKey, hdr: &PacketHeader) {
+        if self.connections.contains_key(&key) {
+            self.reset_connection(key);
+            return;
+        }
+        if self.connections.len() >= MAX_CONNECTIONS {
+            warn!("vsock: refused connection from port {}, too many connections", key.0);
+            self.queue_control(key, VSOCK_OP_RST, 0);
+            return;
+        }
+        let path = self.socket_dir.join(key.1.to_string());
+        // Writes to the host socket must not block the worker on a slow reader.
+        match UnixStream::connect(&path).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+            Ok(stream) => {
+                self.connections.insert(key,
+                                        Connection {
+                                            stream: stream,
+                                            peer_buf_alloc: hdr.buf_alloc,
+                                            peer_fwd_cnt: hdr.fwd_cnt,
+                                            tx_cnt: 0,
+                                            fwd_cnt: 0,
+                                            last_fwd_cnt_sent: 0,
+                                            to_host: VecDeque::new(),
+                                            read_done: false,
+                                            polled: WatchingEvents::empty(),
+                                        });
+                self.queue_control(key, VSOCK_OP_RESPONSE, 0);
+            }
+            Err(e) => {
+                warn!("vsock: failed to connect to {}: {}", path.display(), e);
+                self.queue_control(key, VSOCK_OP_RST, 0);
+            }
+        }
+    }
+
+    // Writes as much of the data queued for the host socket for `key` as the socket takes without
+    // blocking.
+    fn flush_connection(&mut self, key: ConnKey) {
+        let res = match self.connections.get_mut(&key) {
+            Some(conn) => {
+                let mut res = Ok(());
+                while !conn.to_host.is_empty() {
+                    let written = {
+                        let (front, _) = conn.to_host.as_slices();
+                        conn.stream.write(front)
+                    };
+                    match written {
+                        Ok(0) => {
+                            res = Err(io::Error::from(io::ErrorKind::WriteZero));
+                            break;
+                        }
+                        Ok(len) => {
+                            conn.to_host.drain(..len);
+                            conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            res = Err(e);
+                            break;
+                        }
+                    }
+                }
+                res.map(|_| conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) >= CONN_BUF_ALLOC / 2)
+            }
+            None => return,
+        };
+        match res {
+            Ok(true) => self.queue_control(key, VSOCK_OP_CREDIT_UPDATE, 0),
+            Ok(false) => {}
+            Err(e) => {
+                warn!("vsock: failed to write to host socket: {}", e);
+                self.reset_connection(key);
+            }
+        }
+    }
+
+    // Takes all chains the guest has posted on the rx queue so they can be filled as data arrives.
+    fn take_rx_chains(&mut self) {
+        let mut rejects = Vec::new();
+        for avail_desc in self.rx_queue.iter(&self.mem) {
+            let mut chain = RxChain {
+                index: avail_desc.index,
+                descs: Vec::new(),
+            };
+            let mut desc = Some(avail_desc);
+            while let Some(d) = desc {
+                if !d.is_write_only() {
+                    break;
+                }
+                chain.descs.push((d.addr, d.len as usize));
+                desc = d.next_descriptor();
+            }
+            if chain.capacity() < HDR_SIZE {
+                rejects.push(chain.index);
+            } else {
+                self.rx_chains.push_back(chain);
+            }
+        }
+        for index in rejects {
+            self.rx_queue.add_used(&self.mem, index, 0);
+        }
+    }
+
+    // Writes a packet into the next rx chain. Returns false if the guest has no rx buffers posted.
+    fn send_packet(&mut self, hdr: &PacketHeader, payload: &[u8]) -> bool {
+        let chain = match self.rx_chains.pop_front() {
+            Some(c) => c,
+            None => return false,
+        };
+        let hdr_bytes = hdr.to_bytes();
+        let mut written = 0;
+        {
+            let mut data = hdr_bytes.iter().chain(payload.iter());
+            for &(addr, len) in chain.descs.iter() {
+                let buf: Vec<u8> = data.by_ref().take(len).cloned().collect();
+                if buf.is_empty() {
+                    break;
+                }
+                if let Err(e) = self.mem.write_slice_at_addr(&buf, addr) {
+                    error!("vsock: failed to write rx packet: {:?}", e);
+                    break;
+                }
+                written += buf.len();
+            }
+        }
+        self.rx_queue.add_used(&self.mem, chain.index, written as u32);
+        if let Some(conn) = self.connections.get_mut(&(hdr.dst_port, hdr.src_port)) {
+            conn.last_fwd_cnt_sent = hdr.fwd_cnt;
+        }
+        true
+    }
+
+    // Writes queued data to the host sockets that can take it, then sends pending control packets
+    // followed by any data readable from the host sockets, as long as rx buffers are available.
+    // Returns true if any rx buffers were used.
+    fn process_rx(&mut self) -> bool {
+        let mut used = false;
+
+        // A hangup is handled as both, so that the failed read or write drops the connection.
+        let events: Vec<(u64, bool, bool)> =
+            match self.conn_poll_ctx.wait_timeout(Duration::from_secs(0)) {
+                Ok(events) => {
+                    events
+                        .iter()
+                        .map(|e| (e.token(), e.readable() || e.hungup(), e.writable() || e.hungup()))
+                        .collect()
+                }
+                Err(e) => {
+                    error!("vsock: failed polling host sockets: {:?}", e);
+                    Vec::new()
+                }
+            };
+        for &(token, _, writable) in events.iter() {
+            if writable {
+                self.flush_connection(conn_key(token));
+            }
+        }
+
+        while !self.rx_chains.is_empty() {
+            let (key, op, flags) = match self.pending.pop_front() {
+                Some(p) => p,
+                None => break,
+            };
+            let hdr = self.header(key, op, flags, 0);
+            used |= self.send_packet(&hdr, &[]);
+        }
+
+        for &(token, readable, _) in events.iter() {
+            if self.rx_chains.is_empty() {
+                break;
+            }
+            if readable {
+                used |= self.read_connection(conn_key(token));
+            }
+        }
+
+        self.update_polling();
+        used
+    }
+
+    // Reads one packet's worth of data from the host socket for `key` and sends it to the guest.
+    fn read_connection(&mut self, key: ConnKey) -> bool {
+        let capacity = match self.rx_chains.front() {
+            Some(chain) => chain.capacity() - HDR_SIZE,
+            None => return false,
+        };
+        let res = match self.connections.get_mut(&key) {
+            Some(conn) => {
+                let max_len = min(min(capacity, conn.peer_free() as usize), MAX_PKT_PAYLOAD);
+                if max_len == 0 || conn.read_done {
+                    return false;
+                }
+                let mut buf = vec![0u8; max_len];
+                conn.stream.read(&mut buf).map(|len| {
+                    buf.truncate(len);
+                    buf
+                })
+            }
+            None => return false,
+        };
+
+        match res {
+            Ok(ref buf) if buf.is_empty() => {
+                // The host closed its end. Tell the guest no more data will flow in either
+                // direction; the guest answers with a reset that removes the connection.
+                if let Some(conn) = self.connections.get_mut(&key) {
+                    conn.read_done = true;
+                }
+                let hdr = self.header(key,
+                                      VSOCK_OP_SHUTDOWN,
+                                      VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND,
+                                      0);
+                self.send_packet(&hdr, &[])
+            }
+            Ok(buf) => {
+                let hdr = self.header(key, VSOCK_OP_RW, 0, buf.len() as u32);
+                if let Some(conn) = self.connections.get_mut(&key) {
+                    conn.tx_cnt = conn.tx_cnt.wrapping_add(buf.len() as u32);
+                }
+                self.send_packet(&hdr, &buf)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::Interrupted => false,
+            Err(e) => {
+                warn!("vsock: failed to read from host socket: {}", e);
+                self.reset_connection(key);
+                false
+            }
+        }
+    }
+
+    // Only poll host sockets for reading when their data could be delivered right now, so that
+    // level-triggered readiness doesn't spin while the guest is out of rx buffers or credit. They
+    // are polled for writing while data from the guest is queued for them.
+    fn update_polling(&mut self) {
+        let have_rx = !self.rx_chains.is_empty();
+        for (key, conn) in self.connections.iter_mut() {
+            let mut want = WatchingEvents::empty();
+            if have_rx && !conn.read_done && conn.peer_free() > 0 {
+                want = want.set_read();
+            }
+            if !conn.to_host.is_empty() {
+                want = want.set_write();
+            }
+            if want == conn.polled {
+                continue;
+            }
+            if conn.polled != WatchingEvents::empty() {
+                match self.conn_poll_ctx.delete(&conn.stream) {
+                    Ok(()) => conn.polled = WatchingEvents::empty(),
+                    Err(e) => {
+                        error!("vsock: failed to update host socket polling: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            if want != WatchingEvents::empty() {
+                match self.conn_poll_ctx
+                          .add_fd_with_events(&conn.stream, want, conn_token(*key)) {
+                    Ok(()) => conn.polled = want,
+                    Err(e) => error!("vsock: failed to update host socket polling: {:?}", e),
+                }
+            }
+        }
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        let _ = self.interrupt_evt.write(1);
+    }
+
+    fn run(&mut self, mut queue_evts: Vec<EventFd>, kill_evt: EventFd) {
+        let rx_queue_evt = queue_evts.remove(0);
+        let tx_queue_evt = queue_evts.remove(0);
+        let event_queue_evt = queue_evts.remove(0);
+
+        #[derive(PollToken)]
+        enum Token {
+            RxQueue,
+            TxQueue,
+            EventQueue,
+            HostSocket,
+            Kill,
+        }
+
+        let poll_ctx: PollContext<Token> =
+            match PollContext::new()
+                      .and_then(|pc| pc.add(&rx_queue_evt, Token::RxQueue).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&tx_queue_evt, Token::TxQueue).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&event_queue_evt, Token::EventQueue).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&self.conn_poll_ctx, Token::HostSocket).and(Ok(pc)))
+                      .and_then(|pc| pc.add(&kill_evt, Token::Kill).and(Ok(pc))) {
+                Ok(pc) => pc,
+                Err(e) => {
+                    error!("failed creating PollContext: {:?}", e);
+                    return;
+                }
+            };
+
+        'poll: loop {
+            let events = match poll_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {:?}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter_readable() {
+                match event.token() {
+                    Token::RxQueue => {
+                        if let Err(e) = rx_queue_evt.read() {
+                            error!("failed reading rx queue EventFd: {:?}", e);
+                            break 'poll;
+                        }
+                        self.take_rx_chains();
+                    }
+                    Token::TxQueue => {
+                        if let Err(e) = tx_queue_evt.read() {
+                            error!("failed reading tx queue EventFd: {:?}", e);
+                            break 'poll;
+                        }
+                        needs_interrupt |= self.process_tx_queue();
+                    }
+                    // No transport events are ever sent, so buffers on the event queue are left
+                    // with the device.
+                    Token::EventQueue => {
+                        let _ = event_queue_evt.read();
+                    }
+                    Token::HostSocket => {}
+                    Token::Kill => break 'poll,
+                }
+            }
+            needs_interrupt |= self.process_rx();
+            if needs_interrupt {
+                self.signal_used_queue();
+            }
+        }
+    }
+}
+
+/// Virtio device for guest to host stream sockets, implemented without vhost.
+pub struct Vsock {
+    guest_cid: u64,
+    socket_dir: PathBuf,
+    queue_sizes: [u16; NUM_QUEUES],
+    kill_evt: Option<EventFd>,
+}
+
+impl Vsock {
+    /// Create a new virtio-vsock device with the given guest cid that bridges connections to
+    /// the Unix sockets in `socket_dir`. The directory is only accessed once the device is
+    /// activated, so it may be a path that only exists inside the device's jail. The rx and tx
+    /// queues have room for `rx_queue_size` and `tx_queue_size` descriptors.
+    pub fn new(guest_cid: u64, socket_dir: &Path, rx_queue_size: u16, tx_queue_size: u16)
+               -> Vsock {
+        let mut queue_sizes = [QUEUE_SIZE; NUM_QUEUES];
+        queue_sizes[RX_QUEUE] = rx_queue_size;
+        queue_sizes[TX_QUEUE] = tx_queue_size;
+        Vsock {
+            guest_cid: guest_cid,
+            socket_dir: socket_dir.to_path_buf(),
+            queue_sizes: queue_sizes,
+            kill_evt: None,
+        }
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn keep_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        match offset {
+            0 if data.len() == 8 => LittleEndian::write_u64(data, self.guest_cid),
+            0 if data.len() == 4 => {
+                LittleEndian::write_u32(data, (self.guest_cid & 0xffffffff) as u32)
+            }
+            4 if data.len() == 4 => {
+                LittleEndian::write_u32(data, ((self.guest_cid >> 32) & 0xffffffff) as u32)
+            }
+            _ => warn!(
+                "vsock: virtio-vsock received invalid read request of {} bytes at offset {}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+
+    fn activate(&mut self,
+                mem: GuestMemory,
+                interrupt_evt: EventFd,
+                status: Arc<AtomicUsize>,
+                mut queues: Vec<Queue>,
+                queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!("vsock: expected {} queues, got {}", NUM_QUEUES, queues.len());
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to create kill EventFd pair: {:?}", e);
+                    return;
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        let rx_queue = queues.remove(0);
+        let tx_queue = queues.remove(0);
+        let mut worker = match Worker::new(mem,
+                                           self.guest_cid,
+                                           self.socket_dir.clone(),
+                                           rx_queue,
+                                           tx_queue,
+                                           status,
+                                           interrupt_evt) {
+            Ok(w) => w,
+            Err(e) => {
+                error!("vsock: failed to create worker: {:?}", e);
+                return;
+            }
+        };
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_vsock".to_string())
+            .spawn(move || worker.run(queue_evts, kill_evt));
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_vsock worker: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_queue::TestQueue;
+    use std::os::unix::net::UnixListener;
+    use sys_util::TempDir;
+
+    const GUEST_CID: u64 = 3;
+    const GUEST_PORT: u32 = 1025;
+    const HOST_PORT: u32 = 1234;
+
+    fn guest_packet(op: u16, flags: u32, payload: &[u8]) -> Vec<u8> {
+        let hdr = PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: GUEST_PORT,
+            dst_port: HOST_PORT,
+            len: payload.len() as u32,
+            type_: VSOCK_TYPE_STREAM,
+            op: op,
+            flags: flags,
+            buf_alloc: 4096,
+            fwd_cnt: 0,
+        };
+        let mut pkt = hdr.to_bytes().to_vec();
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let hdr = PacketHeader {
+            src_cid: 0x0102030405060708,
+            dst_cid: 2,
+            src_port: 0xdeadbeef,
+            dst_port: 9,
+            len: 100,
+            type_: VSOCK_TYPE_STREAM,
+            op: VSOCK_OP_RW,
+            flags: 3,
+            buf_alloc: 0x10000,
+            fwd_cnt: 42,
+        };
+        let bytes = hdr.to_bytes();
+        assert_eq!(bytes[0], 0x08);
+        assert_eq!(LittleEndian::read_u16(&bytes[30..32]), VSOCK_OP_RW);
+        assert_eq!(PacketHeader::from_bytes(&bytes), hdr);
+    }
+
+    #[test]
+    fn loopback_connection() {
+        let socket_dir = TempDir::new("/tmp/vsock_test").unwrap();
+        let listener =
+            UnixListener::bind(socket_dir.as_path().unwrap().join(HOST_PORT.to_string())).unwrap();
+
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut rx, rx_queue) = TestQueue::new(&mem, 0, QUEUE_SIZE);
+        let (mut tx, tx_queue) = TestQueue::new(&mem, 0x400000, QUEUE_SIZE);
+        let mut worker = Worker::new(mem.clone(),
+                                     GUEST_CID,
+                                     socket_dir.as_path().unwrap().to_path_buf(),
+                                     rx_queue,
+                                     tx_queue,
+                                     Arc::new(AtomicUsize::new(0)),
+                                     EventFd::new().unwrap())
+                .unwrap();
+
+        for i in 0..4 {
+            rx.post(&mem, i, None, 0x1000);
+        }
+        worker.take_rx_chains();
+
+        // The guest connects and the device bridges it to the listening host socket.
+        tx.post(&mem, 0, Some(&guest_packet(VSOCK_OP_REQUEST, 0, &[])), HDR_SIZE as u32);
+        assert!(worker.process_tx_queue());
+        assert!(tx.pop_used(&mem).is_some());
+        let (mut host, _) = listener.accept().unwrap();
+        assert!(worker.process_rx());
+        let resp = rx.pop_used(&mem).unwrap();
+        let hdr = PacketHeader::from_bytes(&resp);
+        assert_eq!(hdr.op, VSOCK_OP_RESPONSE);
+        assert_eq!(hdr.src_port, HOST_PORT);
+        assert_eq!(hdr.dst_port, GUEST_PORT);
+        assert_eq!(hdr.dst_cid, GUEST_CID);
+
+        // Guest to host data.
+        let pkt = guest_packet(VSOCK_OP_RW, 0, b"hello");
+        tx.post(&mem, 1, Some(&pkt), pkt.len() as u32);
+        assert!(worker.process_tx_queue());
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Host to guest data.
+        host.write_all(b"world").unwrap();
+        assert!(worker.process_rx());
+        let data = rx.pop_used(&mem).unwrap();
+        let hdr = PacketHeader::from_bytes(&data);
+        assert_eq!(hdr.op, VSOCK_OP_RW);
+        assert_eq!(hdr.len, 5);
+        assert_eq!(hdr.fwd_cnt, 5);
+        assert_eq!(&data[HDR_SIZE..], b"world");
+
+        // Closing the host end shuts the connection down for the guest.
+        drop(host);
+        assert!(worker.process_rx());
+        let hdr = PacketHeader::from_bytes(&rx.pop_used(&mem).unwrap());
+        assert_eq!(hdr.op, VSOCK_OP_SHUTDOWN);
+        tx.post(&mem, 2, Some(&guest_packet(VSOCK_OP_RST, 0, &[])), HDR_SIZE as u32);
+        worker.process_tx_queue();
+        assert!(worker.connections.is_empty());
+    }
+
+    #[test]
+    fn connect_refused() {
+        let socket_dir = TempDir::new("/tmp/vsock_test").unwrap();
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut rx, rx_queue) = TestQueue::new(&mem, 0, QUEUE_SIZE);
+        let (mut tx, tx_queue) = TestQueue::new(&mem, 0x400000, QUEUE_SIZE);
+        let mut worker = Worker::new(mem.clone(),
+                                     GUEST_CID,
+                                     socket_dir.as_path().unwrap().to_path_buf(),
+                                     rx_queue,
+                                     tx_queue,
+                                     Arc::new(AtomicUsize::new(0)),
+                                     EventFd::new().unwrap())
+                .unwrap();
+
+        rx.post(&mem, 0, None, 0x1000);
+        worker.take_rx_chains();
+        tx.post(&mem, 0, Some(&guest_packet(VSOCK_OP_REQUEST, 0, &[])), HDR_SIZE as u32);
+        worker.process_tx_queue();
+        worker.process_rx();
+        let hdr = PacketHeader::from_bytes(&rx.pop_used(&mem).unwrap());
+        assert_eq!(hdr.op, VSOCK_OP_RST);
+        assert!(worker.connections.is_empty());
+    }
+
+    #[test]
+    fn duplicate_control_packets() {
+        let socket_dir = TempDir::new("/tmp/vsock_test").unwrap();
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (_rx, rx_queue) = TestQueue::new(&mem, 0, QUEUE_SIZE);
+        let (mut tx, tx_queue) = TestQueue::new(&mem, 0x400000, QUEUE_SIZE);
+        let mut worker = Worker::new(mem.clone(),
+                                     GUEST_CID,
+                                     socket_dir.as_path().unwrap().to_path_buf(),
+                                     rx_queue,
+                                     tx_queue,
+                                     Arc::new(AtomicUsize::new(0)),
+                                     EventFd::new().unwrap())
+                .unwrap();
+
+        // Without rx buffers the resets for repeated refused requests wait as one packet.
+        for _ in 0..3 {
+            tx.post(&mem, 0, Some(&guest_packet(VSOCK_OP_REQUEST, 0, &[])), HDR_SIZE as u32);
+            worker.process_tx_queue();
+            tx.pop_used(&mem).unwrap();
+        }
+        assert_eq!(worker.pending.len(), 1);
+    }
+
+    #[test]
+    fn queue_sizes() {
+        let vsock = Vsock::new(GUEST_CID, Path::new("/vsock"), 128, 512);
+        assert_eq!(vsock.queue_max_sizes(), &[128, 512, QUEUE_SIZE]);
+    }
+
+    // Connects the guest to a listener for HOST_PORT and returns the worker, the host end and the
+    // driver side of the rx and tx queues. The rx queue has four buffers posted.
+    fn connected_worker(socket_dir: &TempDir,
+                        mem: &GuestMemory)
+                        -> (Worker, UnixStream, TestQueue, TestQueue) {
+        let listener =
+            UnixListener::bind(socket_dir.as_path().unwrap().join(HOST_PORT.to_string())).unwrap();
+        let (mut rx, rx_queue) = TestQueue::new(mem, 0, QUEUE_SIZE);
+        let (mut tx, tx_queue) = TestQueue::new(mem, 0x400000, QUEUE_SIZE);
+        let mut worker = Worker::new(mem.clone(),
+                                     GUEST_CID,
+                                     socket_dir.as_path().unwrap().to_path_buf(),
+                                     rx_queue,
+                                     tx_queue,
+                                     Arc::new(AtomicUsize::new(0)),
+                                     EventFd::new().unwrap())
+                .unwrap();
+        for i in 0..4 {
+            rx.post(mem, i, None, 0x1000);
+        }
+        worker.take_rx_chains();
+        tx.post(mem, 0, Some(&guest_packet(VSOCK_OP_REQUEST, 0, &[])), HDR_SIZE as u32);
+        worker.process_tx_queue();
+        tx.pop_used(mem).unwrap();
+        let (host, _) = listener.accept().unwrap();
+        worker.process_rx();
+        assert_eq!(PacketHeader::from_bytes(&rx.pop_used(mem).unwrap()).op,
+                   VSOCK_OP_RESPONSE);
+        (worker, host, rx, tx)
+    }
+
+    #[test]
+    fn slow_host_reader() {
+        let socket_dir = TempDir::new("/tmp/vsock_test").unwrap();
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut worker, mut host, _rx, mut tx) = connected_worker(&socket_dir, &mem);
+
+        // The guest sends all of its credit while the host reads nothing. None of this may block.
+        let payload: Vec<u8> = (0..MAX_PKT_PAYLOAD).map(|i| i as u8).collect();
+        let pkt = guest_packet(VSOCK_OP_RW, 0, &payload);
+        let count = CONN_BUF_ALLOC as usize / MAX_PKT_PAYLOAD;
+        for _ in 0..count {
+            tx.post(&mem, 0, Some(&pkt), pkt.len() as u32);
+            assert!(worker.process_tx_queue());
+            tx.pop_used(&mem).unwrap();
+        }
+        worker.process_rx();
+        assert_eq!(worker.connections.len(), 1);
+
+        // What the host socket couldn't take is written as the host reads.
+        host.set_nonblocking(true).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            let mut buf = [0u8; 0x10000];
+            match host.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("failed to read host socket: {}", e),
+            }
+            worker.process_rx();
+            if received.len() == count * MAX_PKT_PAYLOAD {
+                break;
+            }
+        }
+        assert_eq!(received.len(), count * MAX_PKT_PAYLOAD);
+        assert!(received.chunks(MAX_PKT_PAYLOAD).all(|c| c == &payload[..]));
+        assert!(worker.connections[&(GUEST_PORT, HOST_PORT)].to_host.is_empty());
+    }
+
+    #[test]
+    fn oversized_tx_packet() {
+        let socket_dir = TempDir::new("/tmp/vsock_test").unwrap();
+        let mem = GuestMemory::new(&vec![(GuestAddress(0), 0x1000000)]).unwrap();
+        let (mut worker, _host, mut rx, mut tx) = connected_worker(&socket_dir, &mem);
+
+        // The descriptor claims far more than a packet may hold. Only its header is read and the
+        // connection is reset.
+        let pkt = guest_packet(VSOCK_OP_RW, 0, b"hello");
+        tx.post(&mem, 0, Some(&pkt), 0x100000);
+        assert!(worker.process_tx_queue());
+        assert!(tx.pop_used(&mem).is_some());
+        assert!(worker.connections.is_empty());
+        worker.process_rx();
+        assert_eq!(PacketHeader::from_bytes(&rx.pop_used(&mem).unwrap()).op, VSOCK_OP_RST);
+    }
+}
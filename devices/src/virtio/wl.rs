@@ -1459,7 +1459,9 @@ impl VirtioDevice for Wl {
                 interrupt_evt: EventFd,
                 status: Arc<AtomicUsize>,
                 mut queues: Vec<Queue>,
-                queue_evts: Vec<EventFd>) {
+                queue_evts: Vec<EventFd>,
+                _queue_interrupt_evts: Option<Vec<EventFd>>,
+                _max_chains_per_pass: usize) {
         if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
             return;
         }
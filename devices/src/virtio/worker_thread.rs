@@ -0,0 +1,79 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long in milliseconds a device waits for its worker to exit after telling it to.
+pub const WORKER_EXIT_TIMEOUT_MS: u64 = 1000;
+
+/// A device worker thread that can be joined without the risk of waiting forever on a worker that
+/// is stuck, such as one blocked on slow disk I/O.
+pub struct WorkerThread {
+    handle: JoinHandle<()>,
+    exited: Receiver<()>,
+}
+
+impl WorkerThread {
+    /// Spawns a thread with the given name that runs `f`.
+    pub fn spawn<F>(name: &str, f: F) -> io::Result<WorkerThread>
+        where F: FnOnce() + Send + 'static
+    {
+        let (exited_send, exited) = channel();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                       f();
+                       // The receiver is gone if the device stopped waiting.
+                       let _ = exited_send.send(());
+                   })?;
+        Ok(WorkerThread { handle: handle, exited: exited })
+    }
+
+    /// Waits up to `timeout` for the worker to exit and joins it. The worker must already have been
+    /// told to exit. Returns false if it was still running, in which case it is left detached.
+    pub fn join_timeout(self, timeout: Duration) -> bool {
+        match self.exited.recv_timeout(timeout) {
+            // The sender is dropped without sending if the worker panicked.
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                if self.handle.join().is_err() {
+                    error!("device worker thread panicked");
+                }
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn join_exited() {
+        let worker = WorkerThread::spawn("test_worker", || {}).unwrap();
+        assert!(worker.join_timeout(Duration::from_millis(WORKER_EXIT_TIMEOUT_MS)));
+    }
+
+    #[test]
+    fn join_panicked() {
+        let worker = WorkerThread::spawn("test_worker", || panic!("worker failed")).unwrap();
+        assert!(worker.join_timeout(Duration::from_millis(WORKER_EXIT_TIMEOUT_MS)));
+    }
+
+    #[test]
+    fn join_stuck() {
+        let (stop_send, stop_recv) = sync_channel::<()>(0);
+        let worker = WorkerThread::spawn("test_worker", move || {
+                                             let _ = stop_recv.recv();
+                                         })
+                .unwrap();
+        assert!(!worker.join_timeout(Duration::from_millis(10)));
+        drop(stop_send);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sys_util::EventFd;
+
+use BusDevice;
+
+// Register offsets. All registers are 32 bits wide.
+// Control register. Bit 0 enables the watchdog; enabling it starts a new timeout period.
+const WDT_CTRL: u64 = 0x0;
+// Timeout in milliseconds. Writing it while enabled starts a new timeout period.
+const WDT_TIMEOUT: u64 = 0x4;
+// Any write starts a new timeout period. Reads as zero.
+const WDT_PET: u64 = 0x8;
+// Milliseconds left in the current timeout period, or zero if disabled. Read only.
+const WDT_REMAINING: u64 = 0xc;
+
+const WDT_CTRL_ENABLE: u32 = 1 << 0;
+
+/// The size of the register window of a `Watchdog`.
+pub const WATCHDOG_SIZE: u64 = 0x10;
+
+const DEFAULT_TIMEOUT_MS: u32 = 60000;
+
+struct WatchdogState {
+    enabled: bool,
+    timeout_ms: u32,
+    deadline: Instant,
+    exit: bool,
+}
+
+impl WatchdogState {
+    fn pet(&mut self) {
+        self.deadline = Instant::now() + Duration::from_millis(self.timeout_ms as u64);
+    }
+}
+
+type SharedState = Arc<(Mutex<WatchdogState>, Condvar)>;
+
+// Signals `reset_evt` whenever the watchdog is enabled and its deadline passes. The watchdog is
+// disabled after it expires, so the guest has to enable it again after the reset.
+fn run_timer(state: SharedState, reset_evt: EventFd) {
+    let &(ref lock, ref cond) = &*state;
+    let mut s = lock.lock().unwrap();
+    while !s.exit {
+        if !s.enabled {
+            s = cond.wait(s).unwrap();
+            continue;
+        }
+        let now = Instant::now();
+        if now >= s.deadline {
+            s.enabled = false;
+            if let Err(e) = reset_evt.write(1) {
+                error!("failed to trigger watchdog reset event: {:?}", e);
+            }
+            continue;
+        }
+        let wait = s.deadline - now;
+        s = cond.wait_timeout(s, wait).unwrap().0;
+    }
+}
+
+/// A watchdog timer that signals an event, usually the VM's reset event, unless the guest pets it
+/// within a programmable timeout.
+pub struct Watchdog {
+    state: SharedState,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Constructs a disabled watchdog that will signal the given event when it expires.
+    pub fn new(reset_evt: EventFd) -> Watchdog {
+        let state = Arc::new((Mutex::new(WatchdogState {
+                                             enabled: false,
+                                             timeout_ms: DEFAULT_TIMEOUT_MS,
+                                             deadline: Instant::now(),
+                                             exit: false,
+                                         }),
+                              Condvar::new()));
+        let timer_state = state.clone();
+        let timer = thread::Builder::new()
+            .name("crosvm_watchdog".to_owned())
+            .spawn(move || run_timer(timer_state, reset_evt));
+        let timer = match timer {
+            Ok(t) => Some(t),
+            Err(e) => {
+                error!("failed to spawn watchdog timer thread, it will never expire: {:?}", e);
+                None
+            }
+        };
+        Watchdog { state: state, timer: timer }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        {
+            let &(ref lock, ref cond) = &*self.state;
+            lock.lock().unwrap().exit = true;
+            cond.notify_one();
+        }
+        if let Some(timer) = self.timer.take() {
+            if let Err(e) = timer.join() {
+                error!("failed to join watchdog timer thread: {:?}", e);
+            }
+        }
+    }
+}
+
+impl BusDevice for Watchdog {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 {
+            warn!("bad read size: {} for watchdog", data.len());
+            return;
+        }
+
+        let s = self.state.0.lock().unwrap();
+        let val = match offset {
+            WDT_CTRL => if s.enabled { WDT_CTRL_ENABLE } else { 0 },
+            WDT_TIMEOUT => s.timeout_ms,
+            WDT_PET => 0,
+            WDT_REMAINING if s.enabled => {
+                let now = Instant::now();
+                if s.deadline > now {
+                    let left = s.deadline - now;
+                    (left.as_secs() * 1000 + (left.subsec_nanos() / 1_000_000) as u64) as u32
+                } else {
+                    0
+                }
+            }
+            WDT_REMAINING => 0,
+            o => {
+                warn!("watchdog: bad read offset {}", o);
+                0
+            }
+        };
+        LittleEndian::write_u32(data, val);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 {
+            warn!("bad write size: {} for watchdog", data.len());
+            return;
+        }
+
+        let val = LittleEndian::read_u32(data);
+        let &(ref lock, ref cond) = &*self.state;
+        let mut s = lock.lock().unwrap();
+        match offset {
+            WDT_CTRL => {
+                let enable = val & WDT_CTRL_ENABLE != 0;
+                if enable && !s.enabled {
+                    s.pet();
+                }
+                s.enabled = enable;
+            }
+            WDT_TIMEOUT => {
+                s.timeout_ms = val;
+                s.pet();
+            }
+            WDT_PET => s.pet(),
+            o => {
+                warn!("watchdog: bad write offset {}", o);
+                return;
+            }
+        }
+        cond.notify_one();
+    }
+
+    fn reset(&mut self) {
+        let &(ref lock, ref cond) = &*self.state;
+        let mut s = lock.lock().unwrap();
+        s.enabled = false;
+        s.timeout_ms = DEFAULT_TIMEOUT_MS;
+        cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn write_reg(wdt: &mut Watchdog, offset: u64, val: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        wdt.write(offset, &data);
+    }
+
+    fn read_reg(wdt: &mut Watchdog, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        wdt.read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    // Returns how many times `evt` was signaled without blocking when it wasn't.
+    fn count_events(evt: &EventFd) -> u64 {
+        evt.write(1).unwrap();
+        evt.read().unwrap() - 1
+    }
+
+    #[test]
+    fn registers() {
+        let evt = EventFd::new().unwrap();
+        let mut wdt = Watchdog::new(evt.try_clone().unwrap());
+        assert_eq!(read_reg(&mut wdt, WDT_CTRL), 0);
+        assert_eq!(read_reg(&mut wdt, WDT_TIMEOUT), DEFAULT_TIMEOUT_MS);
+        assert_eq!(read_reg(&mut wdt, WDT_REMAINING), 0);
+        write_reg(&mut wdt, WDT_TIMEOUT, 30000);
+        write_reg(&mut wdt, WDT_CTRL, WDT_CTRL_ENABLE);
+        assert_eq!(read_reg(&mut wdt, WDT_CTRL), WDT_CTRL_ENABLE);
+        assert_eq!(read_reg(&mut wdt, WDT_TIMEOUT), 30000);
+        let remaining = read_reg(&mut wdt, WDT_REMAINING);
+        assert!(remaining > 20000 && remaining <= 30000);
+        write_reg(&mut wdt, WDT_CTRL, 0);
+        assert_eq!(read_reg(&mut wdt, WDT_REMAINING), 0);
+        assert_eq!(count_events(&evt), 0);
+    }
+
+    #[test]
+    fn expires() {
+        let evt = EventFd::new().unwrap();
+        let mut wdt = Watchdog::new(evt.try_clone().unwrap());
+        write_reg(&mut wdt, WDT_TIMEOUT, 10);
+        write_reg(&mut wdt, WDT_CTRL, WDT_CTRL_ENABLE);
+        // Blocks until the watchdog expires.
+        assert_eq!(evt.read().unwrap(), 1);
+        // It only fires once and is disabled afterwards.
+        assert_eq!(read_reg(&mut wdt, WDT_CTRL), 0);
+        sleep(Duration::from_millis(50));
+        assert_eq!(count_events(&evt), 0);
+    }
+
+    #[test]
+    fn petting_prevents_reset() {
+        let evt = EventFd::new().unwrap();
+        let mut wdt = Watchdog::new(evt.try_clone().unwrap());
+        write_reg(&mut wdt, WDT_TIMEOUT, 200);
+        write_reg(&mut wdt, WDT_CTRL, WDT_CTRL_ENABLE);
+        for _ in 0..20 {
+            sleep(Duration::from_millis(20));
+            write_reg(&mut wdt, WDT_PET, 1);
+        }
+        assert_eq!(count_events(&evt), 0);
+        write_reg(&mut wdt, WDT_CTRL, 0);
+    }
+}
@@ -15,13 +15,12 @@
 //! # use gpu_buffer::*;
 //! # fn test() -> Result<(), Box<Error>> {
 //! let drm_card = File::open("/dev/dri/card0")?;
-//! let device = Device::new(drm_card).map_err(|_| "failed to create device")?;
+//! let device = Device::new(drm_card)?;
 //! let bo = device
 //!     .create_buffer(1024,
 //!                    512,
 //!                    Format::new(b'X', b'R', b'2', b'4'),
-//!                    Flags::empty().use_scanout(true))
-//!     .map_err(|_| "failed to create buffer")?;
+//!                    Flags::empty().use_scanout(true))?;
 //! assert_eq!(bo.width(), 1024);
 //! assert_eq!(bo.height(), 512);
 //! assert_eq!(bo.format(), Format::new(b'X', b'R', b'2', b'4'));
@@ -38,13 +37,14 @@ pub mod rendernode;
 mod raw;
 
 use std::os::raw::c_void;
-use std::fmt;
+use std::error;
+use std::fmt::{self, Display};
 use std::cmp::min;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::ptr::{copy_nonoverlapping, null_mut};
 use std::rc::Rc;
-use std::result::Result;
+use std::result;
 
 use data_model::VolatileSlice;
 
@@ -52,6 +52,46 @@ use raw::*;
 
 const MAP_FAILED: *mut c_void = (-1isize as *mut _);
 
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The file isn't a device gbm can use.
+    DeviceCreation,
+    /// gbm couldn't create a buffer with the given size, format and flags.
+    BufferCreation,
+    /// The buffer couldn't be mapped, or the mapping didn't fit in the destination.
+    Mapping,
+    /// Exporting a plane failed with the given errno.
+    Export(i32),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::DeviceCreation => "Failed to create gbm device",
+            &Error::BufferCreation => "Failed to create buffer",
+            &Error::Mapping => "Failed to map buffer",
+            &Error::Export(_) => "Failed to export buffer plane",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Export(e) => {
+                write!(f, "GPU Buffer Error: {}: errno {}", error::Error::description(self), e)
+            }
+            _ => write!(f, "GPU Buffer Error: {}", error::Error::description(self)),
+        }
+    }
+}
+
+// Reads the errno left by a failed attempt to export a plane.
+fn export_error() -> Error {
+    Error::Export(sys_util::Error::last().errno())
+}
+
 /// A [fourcc](https://en.wikipedia.org/wiki/FourCC) format identifier.
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Format(u32);
@@ -212,29 +252,35 @@ pub struct Device(Rc<DeviceInner>);
 
 impl Device {
     /// Returns a new `Device` using the given `fd` opened from a device in `/dev/dri/`.
-    pub fn new(fd: File) -> Result<Device, ()> {
+    pub fn new(fd: File) -> Result<Device> {
         // gbm_create_device is safe to call with a valid fd, and we check that a valid one is
         // returned. The FD is not of the appropriate kind (i.e. not a DRM device),
         // gbm_create_device should reject it.
         let gbm = unsafe { gbm_create_device(fd.as_raw_fd()) };
         if gbm.is_null() {
-            Err(())
+            Err(Error::DeviceCreation)
         } else {
             Ok(Device(Rc::new(DeviceInner { _fd: fd, gbm })))
         }
     }
 
+    /// Returns true if buffers with the given format and usage flags can be created.
+    pub fn is_format_supported(&self, format: Format, usage: Flags) -> bool {
+        // This is safe because only a valid gbm_device is used.
+        unsafe { gbm_device_is_format_supported(self.0.gbm, format.0, usage.0) != 0 }
+    }
+
     /// Creates a new buffer with the given metadata.
     pub fn create_buffer(&self,
                          width: u32,
                          height: u32,
                          format: Format,
                          usage: Flags)
-                         -> Result<Buffer, ()> {
+                         -> Result<Buffer> {
         // This is safe because only a valid gbm_device is used and the return value is checked.
         let bo = unsafe { gbm_bo_create(self.0.gbm, width, height, format.0, usage.0) };
         if bo.is_null() {
-            Err(())
+            Err(Error::BufferCreation)
         } else {
             Ok(Buffer(bo, self.clone()))
         }
@@ -293,11 +339,11 @@ impl Buffer {
     }
 
     /// Exports a new dmabuf/prime file descriptor for the given plane.
-    pub fn export_plane_fd(&self, plane: usize) -> Result<File, i32> {
+    pub fn export_plane_fd(&self, plane: usize) -> Result<File> {
         // This is always safe to call with a valid gbm_bo pointer.
         match unsafe { gbm_bo_get_plane_fd(self.0, plane) } {
             fd if fd >= 0 => Ok(unsafe { File::from_raw_fd(fd) }),
-            ret => Err(ret),
+            _ => Err(export_error()),
         }
     }
 
@@ -309,7 +355,7 @@ impl Buffer {
                             height: u32,
                             plane: usize,
                             dst: VolatileSlice)
-                            -> Result<(), ()> {
+                            -> Result<()> {
         let mut stride = 0;
         let mut map_data = null_mut();
         // Safe because only a valid gbm_bo object is used and the return value is checked. Only
@@ -327,7 +373,7 @@ impl Buffer {
                        plane)
         };
         if mapping == MAP_FAILED {
-            return Err(());
+            return Err(Error::Mapping);
         }
 
         let copy_size = (y as u64) * (stride as u64);
@@ -340,7 +386,7 @@ impl Buffer {
             }
             Ok(())
         } else {
-            Err(())
+            Err(Error::Mapping)
         };
 
         // safe because the gbm_bo is assumed to be valid and the map_data is the same one given by
@@ -360,7 +406,7 @@ impl Buffer {
                             height: u32,
                             plane: usize,
                             src: &[u8])
-                            -> Result<(), ()> {
+                            -> Result<()> {
         let mut stride = 0;
         let mut map_data = null_mut();
         // Safe because only a valid gbm_bo object is used and the return value is checked. Only
@@ -378,7 +424,7 @@ impl Buffer {
                        plane)
         };
         if mapping == MAP_FAILED {
-            return Err(());
+            return Err(Error::Mapping);
         }
 
         let copy_size = (height as u64) * (stride as u64);
@@ -406,7 +452,7 @@ impl Buffer {
                                                                     height: u32,
                                                                     plane: usize,
                                                                     sgs: S)
-                                                                    -> Result<(), ()> {
+                                                                    -> Result<()> {
         let mut stride = 0;
         let mut map_data = null_mut();
         // Safe because only a valid gbm_bo object is used and the return value is checked. Only
@@ -424,7 +470,7 @@ impl Buffer {
                        plane)
         };
         if mapping == MAP_FAILED {
-            return Err(());
+            return Err(Error::Mapping);
         }
 
         let mut copy_size = (height as u64) * (stride as u64);
@@ -495,6 +541,17 @@ mod tests {
         Device::new(drm_card).expect("failed to create device with card");
     }
 
+    #[test]
+    #[ignore] // no access to /dev/dri
+    fn format_supported() {
+        let drm_card = File::open("/dev/dri/card0").expect("failed to open card");
+        let device = Device::new(drm_card).expect("failed to create device with card");
+        assert!(device.is_format_supported(Format::new(b'X', b'R', b'2', b'4'),
+                                           Flags::empty().use_linear(true)));
+        assert!(!device.is_format_supported(Format::new(b'B', b'O', b'G', b'U'),
+                                            Flags::empty().use_linear(true)));
+    }
+
     #[test]
     #[ignore] // no access to /dev/dri
     fn create_buffer() {
@@ -527,6 +584,32 @@ mod tests {
         bo.export_plane_fd(0).expect("failed to export plane");
     }
 
+    #[test]
+    fn export_error_errno() {
+        let errno = File::open("/dev/null/missing")
+            .unwrap_err()
+            .raw_os_error()
+            .unwrap();
+        assert_eq!(export_error(), Error::Export(errno));
+    }
+
+    #[test]
+    #[ignore] // no access to /dev/dri
+    fn export_missing_plane() {
+        let drm_card = File::open("/dev/dri/card0").expect("failed to open card");
+        let device = Device::new(drm_card).expect("failed to create device with card");
+        let bo = device
+            .create_buffer(1024,
+                           1024,
+                           Format::new(b'X', b'R', b'2', b'4'),
+                           Flags::empty().use_scanout(true))
+            .expect("failed to create buffer");
+        match bo.export_plane_fd(bo.num_planes()) {
+            Err(Error::Export(errno)) => assert!(errno > 0),
+            _ => panic!("exporting a missing plane didn't fail with an errno"),
+        }
+    }
+
 
     #[test]
     #[ignore] // no access to /dev/dri
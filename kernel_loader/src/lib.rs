@@ -27,6 +27,8 @@ pub enum Error {
     InvalidProgramHeaderSize,
     InvalidProgramHeaderOffset,
     InvalidProgramHeaderAddress,
+    InvalidEntryAddress,
+    ProgramHeaderPastRamEnd,
     ReadElfHeader,
     ReadKernelImage,
     ReadProgramHeader,
@@ -53,6 +55,10 @@ impl error::Error for Error {
                 "Invalid program header offset",
             &Error::InvalidProgramHeaderAddress =>
                 "Invalid Program Header Address",
+            &Error::InvalidEntryAddress =>
+                "Invalid entry point address",
+            &Error::ProgramHeaderPastRamEnd =>
+                "Program header segment extends past the end of RAM",
             &Error::ReadElfHeader =>
                 "Unable to read elf header",
             &Error::ReadKernelImage =>
@@ -75,14 +81,32 @@ impl Display for Error {
     }
 }
 
+/// Where a kernel was placed in guest memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedKernel {
+    /// The address at which the kernel starts executing.
+    pub entry: GuestAddress,
+    /// The first address past the memory used by the kernel.
+    pub end: GuestAddress,
+    /// The raw boot protocol setup header from the image, for formats that carry one.
+    pub setup_header: Option<Vec<u8>>,
+}
+
+/// Returns true if `magic`, the start of a kernel image, identifies an elf file.
+pub fn is_elf(magic: &[u8]) -> bool {
+    magic.starts_with(&elf::ELFMAG[..4])
+}
+
 /// Loads a kernel from a vmlinux elf image to a slice
 ///
+/// Each PT_LOAD segment is loaded at its physical address, offset by `kernel_start`.
+///
 /// # Arguments
 ///
 /// * `guest_mem` - The guest memory region the kernel is written to.
 /// * `kernel_start` - The offset into `guest_mem` at which to load the kernel.
 /// * `kernel_image` - Input vmlinux image.
-pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_start: GuestAddress, kernel_image: &mut F) -> Result<()>
+pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_start: GuestAddress, kernel_image: &mut F) -> Result<LoadedKernel>
     where F: Read + Seek
 {
     let mut ehdr: elf::Elf64_Ehdr = Default::default();
@@ -120,6 +144,7 @@ pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_start: GuestAddress, kerne
     };
 
     // Read in each section pointed to by the program headers.
+    let mut kernel_end = kernel_start;
     for phdr in &phdrs {
         if (phdr.p_type & elf::PT_LOAD) == 0 || phdr.p_filesz == 0 {
             continue;
@@ -130,11 +155,28 @@ pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_start: GuestAddress, kerne
 
         let mem_offset = kernel_start.checked_add(phdr.p_paddr)
             .ok_or(Error::InvalidProgramHeaderAddress)?;
+        // The segment's bss, if any, follows the data read from the image.
+        let segment_end = mem_offset.checked_add(phdr.p_memsz)
+            .ok_or(Error::InvalidProgramHeaderAddress)?;
+        if phdr.p_memsz < phdr.p_filesz || segment_end > guest_mem.end_addr() {
+            return Err(Error::ProgramHeaderPastRamEnd);
+        }
         guest_mem.read_to_memory(mem_offset, kernel_image, phdr.p_filesz as usize)
             .map_err(|_| Error::ReadKernelImage)?;
+
+        if segment_end > kernel_end {
+            kernel_end = segment_end;
+        }
     }
 
-    Ok(())
+    let entry = kernel_start.checked_add(ehdr.e_entry)
+        .ok_or(Error::InvalidEntryAddress)?;
+
+    Ok(LoadedKernel {
+           entry: entry,
+           end: kernel_end,
+           setup_header: None,
+       })
 }
 
 /// Writes the command line string to the given memory slice.
@@ -220,8 +262,89 @@ mod test {
         let gm = create_guest_mem();
         let kernel_addr = GuestAddress(0x0);
         let image = make_elf_bin();
-        assert_eq!(Ok(()),
-                   load_kernel(&gm, kernel_addr, &mut Cursor::new(&image)));
+        let loaded = load_kernel(&gm, kernel_addr, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded.end, GuestAddress(0x40e5));
+    }
+
+    // Builds a little-endian elf64 image with one PT_LOAD segment per `(paddr, data)` pair.
+    fn make_synthetic_elf(entry: u64, segments: &[(u64, &[u8])]) -> Vec<u8> {
+        let ehdr_size = mem::size_of::<elf::Elf64_Ehdr>();
+        let phdr_size = mem::size_of::<elf::Elf64_Phdr>();
+        let mut ehdr: elf::Elf64_Ehdr = Default::default();
+        ehdr.e_ident[..4].copy_from_slice(&elf::ELFMAG[..4]);
+        ehdr.e_ident[elf::EI_CLASS as usize] = elf::ELFCLASS64 as u8;
+        ehdr.e_ident[elf::EI_DATA as usize] = elf::ELFDATA2LSB as u8;
+        ehdr.e_entry = entry;
+        ehdr.e_phoff = ehdr_size as u64;
+        ehdr.e_phentsize = phdr_size as u16;
+        ehdr.e_phnum = segments.len() as u16;
+
+        let mut data_offset = ehdr_size + phdr_size * segments.len();
+        let mut phdrs = Vec::new();
+        for &(paddr, data) in segments {
+            let mut phdr: elf::Elf64_Phdr = Default::default();
+            phdr.p_type = elf::PT_LOAD;
+            phdr.p_offset = data_offset as u64;
+            phdr.p_paddr = paddr;
+            phdr.p_filesz = data.len() as u64;
+            phdr.p_memsz = data.len() as u64;
+            phdrs.push(phdr);
+            data_offset += data.len();
+        }
+
+        // Both headers are plain data, so viewing them as bytes is safe.
+        let mut image = unsafe {
+            std::slice::from_raw_parts(&ehdr as *const _ as *const u8, ehdr_size).to_vec()
+        };
+        for phdr in &phdrs {
+            image.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(phdr as *const _ as *const u8, phdr_size)
+            });
+        }
+        for &(_, data) in segments {
+            image.extend_from_slice(data);
+        }
+        image
+    }
+
+    #[test]
+    fn load_synthetic_elf_segments() {
+        let gm = create_guest_mem();
+        let image = make_synthetic_elf(0x1010, &[(0x1000, b"text"), (0x3000, b"data!")]);
+        let loaded = load_kernel(&gm, GuestAddress(0x0), &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded,
+                   LoadedKernel {
+                       entry: GuestAddress(0x1010),
+                       end: GuestAddress(0x3005),
+                       setup_header: None,
+                   });
+
+        let mut text = [0u8; 4];
+        gm.read_slice_at_addr(&mut text, GuestAddress(0x1000)).unwrap();
+        assert_eq!(&text, b"text");
+        let mut data = [0u8; 5];
+        gm.read_slice_at_addr(&mut data, GuestAddress(0x3000)).unwrap();
+        assert_eq!(&data, b"data!");
+
+        // The same image offset by a load address.
+        let loaded = load_kernel(&gm, GuestAddress(0x2000), &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded.entry, GuestAddress(0x3010));
+        gm.read_slice_at_addr(&mut data, GuestAddress(0x5000)).unwrap();
+        assert_eq!(&data, b"data!");
+    }
+
+    #[test]
+    fn elf_segment_past_ram_end() {
+        let gm = create_guest_mem();
+        let image = make_synthetic_elf(0x1000, &[(MEM_SIZE - 2, b"text")]);
+        assert_eq!(Err(Error::ProgramHeaderPastRamEnd),
+                   load_kernel(&gm, GuestAddress(0x0), &mut Cursor::new(&image)));
+    }
+
+    #[test]
+    fn elf_magic() {
+        assert!(is_elf(&make_synthetic_elf(0, &[])));
+        assert!(!is_elf(b"MZ\0\0"));
     }
 
     #[test]
@@ -131,6 +131,18 @@ impl Kvm {
         }
     }
 
+    /// Gets the most VCPUs a VM can have.
+    pub fn get_max_vcpus(&self) -> u32 {
+        match self.check_extension_int(Cap::MaxVcpus) {
+            0 => self.get_nr_vcpus(), // according to api.txt
+            x if x > 0 => x as u32,
+            _ => {
+                warn!("kernel returned invalid maximum number of VCPUs");
+                self.get_nr_vcpus()
+            },
+        }
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn get_cpuid(&self, kind: u64) -> Result<CpuId> {
         const MAX_KVM_CPUID_ENTRIES: usize = 256;
@@ -401,6 +413,31 @@ impl Vm {
         }
     }
 
+    /// Creates only the local APICs in the kernel, leaving the IOAPIC and PIC to be emulated in
+    /// userspace, by enabling `Cap::SplitIrqchip` with `ioapic_pins` routes reserved for the
+    /// userspace IOAPIC.
+    ///
+    /// This is used instead of `Vm::create_irq_chip` and must be called before any vcpus are
+    /// created. See the documentation on KVM_CAP_SPLIT_IRQCHIP.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn create_split_irq_chip(&self, ioapic_pins: u32) -> Result<()> {
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            flags: 0,
+            args: [0; 4],
+            pad: [0; 64],
+        };
+        cap.args[0] = ioapic_pins as u64;
+        // Safe because we know that our file is a VM fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ENABLE_CAP(), &cap) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     /// Retrieves the state of given interrupt controller by issuing KVM_GET_IRQCHIP ioctl.
     ///
     /// Note that this call can only succeed after a call to `Vm::create_irq_chip`.
@@ -981,6 +1018,33 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Gets the frequency of the VCPU's TSC in kHz.
+    ///
+    /// See the documentation for KVM_GET_TSC_KHZ.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_tsc_khz(&self) -> Result<u32> {
+        // Safe because we know that our file is a VCPU fd and we verify the return result.
+        let ret = unsafe { ioctl(self, KVM_GET_TSC_KHZ()) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(ret as u32)
+    }
+
+    /// Sets the frequency of the VCPU's TSC in kHz. KVM scales the host TSC to match if the host
+    /// supports `Cap::TscControl`.
+    ///
+    /// See the documentation for KVM_SET_TSC_KHZ.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_tsc_khz(&self, khz: u32) -> Result<()> {
+        // Safe because we know that our file is a VCPU fd and we verify the return result.
+        let ret = unsafe { ioctl_with_val(self, KVM_SET_TSC_KHZ(), khz as c_ulong) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
     /// Gets the VCPU debug registers.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_debugregs(&self) -> Result<kvm_debugregs> {
@@ -1007,6 +1071,40 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Sets the guest debugging controls of this VCPU, such as single stepping and hardware
+    /// breakpoints.
+    ///
+    /// See the documentation for KVM_SET_GUEST_DEBUG.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_guest_debug(&self, dbg: &kvm_guest_debug) -> Result<()> {
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), dbg) };
+        if ret != 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Translates a guest virtual address to a guest physical address using this VCPU's current
+    /// page tables.
+    ///
+    /// See the documentation for KVM_TRANSLATE.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn translate(&self, linear_address: u64) -> Result<kvm_translation> {
+        let mut tr = kvm_translation {
+            linear_address: linear_address,
+            ..Default::default()
+        };
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only read and
+        // write the correct amount of memory to our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_TRANSLATE(), &mut tr) };
+        if ret != 0 {
+            return errno_result();
+        }
+        Ok(tr)
+    }
+
     /// Gets the VCPU extended control registers
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_xcrs(&self) -> Result<kvm_xcrs> {
@@ -1245,6 +1343,26 @@ impl AsRawFd for Vcpu {
     }
 }
 
+/// The operations needed to run a VCPU and handle its exits, so that code doing so can also be
+/// used with something other than a `Vcpu`.
+pub trait VcpuRunner {
+    /// Runs the VCPU until it exits, returning the reason. See `Vcpu::run`.
+    fn run(&self) -> Result<VcpuExit>;
+    /// Specifies the set of signals that are blocked while the VCPU runs. See
+    /// `Vcpu::set_signal_mask`.
+    fn set_signal_mask(&self, signals: &[c_int]) -> Result<()>;
+}
+
+impl VcpuRunner for Vcpu {
+    fn run(&self) -> Result<VcpuExit> {
+        Vcpu::run(self)
+    }
+
+    fn set_signal_mask(&self, signals: &[c_int]) -> Result<()> {
+        Vcpu::set_signal_mask(self, signals)
+    }
+}
+
 /// Wrapper for kvm_cpuid2 which has a zero length array at the end.
 /// Hides the zero length array behind a bounds check.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -1287,6 +1405,21 @@ impl CpuId {
         }
     }
 
+    /// Adds `entry` after the current entries. Returns false if there is no room left for it.
+    pub fn push(&mut self, entry: kvm_cpuid_entry2) -> bool {
+        let len = self.mut_entries_slice().len();
+        if len == self.allocated_len {
+            return false;
+        }
+        unsafe {
+            // The entry at `len` is within the allocated array, as checked above.
+            let kvm_cpuid: &mut kvm_cpuid2 = &mut *(self.bytes.as_ptr() as *mut kvm_cpuid2);
+            kvm_cpuid.entries.as_mut_slice(len + 1)[len] = entry;
+            kvm_cpuid.nent += 1;
+        }
+        true
+    }
+
     /// Get a  pointer so it can be passed to the kernel.  Using this pointer is unsafe.
     pub fn as_ptr(&self) -> *const kvm_cpuid2 {
         self.bytes.as_ptr() as *const kvm_cpuid2
@@ -1312,6 +1445,23 @@ mod tests {
         assert_eq!(dirty_log_bitmap_size(page_size * 100), 13);
     }
 
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn cpuid_push() {
+        let mut cpuid = CpuId::new(2);
+        // Start out with one of the two entries in use, like after KVM_GET_SUPPORTED_CPUID.
+        unsafe { (*cpuid.as_mut_ptr()).nent = 1 };
+        let mut entry = kvm_cpuid_entry2::default();
+        entry.function = 0x40000010;
+        entry.eax = 5;
+        assert!(cpuid.push(entry));
+        assert!(!cpuid.push(entry));
+        let entries = cpuid.mut_entries_slice();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].function, 0x40000010);
+        assert_eq!(entries[1].eax, 5);
+    }
+
     #[test]
     fn new() {
         Kvm::new().unwrap();
@@ -1439,6 +1589,21 @@ mod tests {
         vm.set_ioapic_state(&ioapic_state).unwrap();
     }
 
+    #[test]
+    fn split_irq_chip() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = Vm::new(&kvm, gm).unwrap();
+        if !vm.check_extension(Cap::SplitIrqchip) {
+            return;
+        }
+        vm.create_split_irq_chip(24).unwrap();
+        // The IOAPIC and PIC aren't in the kernel, so there is no state to get and a full
+        // irqchip can't be created on top.
+        assert!(vm.get_ioapic_state().is_err());
+        assert!(vm.create_irq_chip().is_err());
+    }
+
     #[test]
     fn pit_handling() {
         let kvm = Kvm::new().unwrap();
@@ -1583,6 +1748,19 @@ mod tests {
         assert_eq!(dregs.dr7, dregs2.dr7);
     }
 
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn tsc_khz() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = Vm::new(&kvm, gm).unwrap();
+        let vcpu = Vcpu::new(0, &kvm, &vm).unwrap();
+        let khz = vcpu.get_tsc_khz().unwrap();
+        assert!(khz > 0);
+        vcpu.set_tsc_khz(khz).unwrap();
+        assert_eq!(vcpu.get_tsc_khz().unwrap(), khz);
+    }
+
     #[test]
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn xcrs() {
@@ -1619,6 +1797,31 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn guest_debug() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = Vm::new(&kvm, gm).unwrap();
+        let vcpu = Vcpu::new(0, &kvm, &vm).unwrap();
+        let mut dbg: kvm_guest_debug = Default::default();
+        dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP;
+        vcpu.set_guest_debug(&dbg).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn translate() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = Vm::new(&kvm, gm).unwrap();
+        let vcpu = Vcpu::new(0, &kvm, &vm).unwrap();
+        // Paging is off after reset, so addresses translate to themselves.
+        let tr = vcpu.translate(0x1234).unwrap();
+        assert_eq!(tr.valid, 1);
+        assert_eq!(tr.physical_address, 0x1234);
+    }
+
     #[test]
     fn mp_state() {
         let kvm = Kvm::new().unwrap();
@@ -31,6 +31,8 @@ pub enum Error {
     CreateTap(SysError),
     /// ioctl failed.
     IoctlError(SysError),
+    /// The file descriptor is not a tap interface with a vnet header.
+    NotTap,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -41,6 +43,7 @@ impl Error {
             &Error::OpenTun(e) => e,
             &Error::CreateTap(e) => e,
             &Error::IoctlError(e) => e,
+            &Error::NotTap => SysError::new(libc::EINVAL),
         }
     }
 }
@@ -166,6 +169,13 @@ pub trait TapT: Read + Write + AsRawFd + Send + Sized {
     /// be used if `vnet_hdr` is false.
     fn new(vnet_hdr: bool) -> Result<Self>;
 
+    /// Adopt an already configured tap interface. The interface must have been created with a
+    /// vnet header. Fails if `fd` is not a tap interface.
+    ///
+    /// This is unsafe because the returned object takes ownership of `fd` and will close it, so
+    /// `fd` must be valid and not owned by anything else.
+    unsafe fn from_raw_fd(fd: RawFd) -> Result<Self>;
+
     /// Get the host-side IP address for the tap interface.
     fn ip_addr(&self) -> Result<net::Ipv4Addr>;
 
@@ -247,6 +257,36 @@ impl TapT for Tap {
            })
     }
 
+    unsafe fn from_raw_fd(fd: RawFd) -> Result<Tap> {
+        // The caller guarantees that fd is valid and owned by us.
+        let tap_file = File::from_raw_fd(fd);
+
+        // ioctl is safe since we call it with a valid fd and check the return value. It fails for
+        // anything other than a tun/tap file descriptor.
+        let mut ifreq: net_sys::ifreq = Default::default();
+        let ret = ioctl_with_mut_ref(&tap_file, net_sys::TUNGETIFF(), &mut ifreq);
+        if ret < 0 {
+            return Err(Error::NotTap);
+        }
+
+        let flags = *ifreq.ifr_ifru.ifru_flags.as_ref() as c_uint;
+        if flags & net_sys::IFF_TAP == 0 || flags & net_sys::IFF_VNET_HDR == 0 {
+            return Err(Error::NotTap);
+        }
+
+        // Match the non-blocking mode used for taps created by `new`.
+        let fl = libc::fcntl(fd, libc::F_GETFL);
+        if fl < 0 || libc::fcntl(fd, libc::F_SETFL, fl | libc::O_NONBLOCK) < 0 {
+            return Err(Error::IoctlError(SysError::last()));
+        }
+
+        // Safe since only the name is accessed, and it's cloned out.
+        Ok(Tap {
+               tap_file: tap_file,
+               if_name: ifreq.ifr_ifrn.ifrn_name.as_ref().clone(),
+           })
+    }
+
     fn ip_addr(&self) -> Result<net::Ipv4Addr> {
         let sock = create_socket()?;
         let mut ifreq = self.get_ifreq();
@@ -451,6 +491,7 @@ impl AsRawFd for Tap {
 
 pub mod fakes {
     use super::*;
+    use std::cell::Cell;
     use std::fs::OpenOptions;
     use std::fs::remove_file;
 
@@ -458,6 +499,14 @@ pub mod fakes {
 
     pub struct FakeTap {
         tap_file: File,
+        addr_configured: Cell<bool>,
+    }
+
+    impl FakeTap {
+        /// Returns true if the IP address or netmask of this tap was set.
+        pub fn addr_configured(&self) -> bool {
+            self.addr_configured.get()
+        }
     }
 
     impl TapT for FakeTap {
@@ -468,7 +517,15 @@ pub mod fakes {
                     .append(true)
                     .create(true)
                     .open(TMP_FILE)
-                    .unwrap()
+                    .unwrap(),
+                addr_configured: Cell::new(false),
+            })
+        }
+
+        unsafe fn from_raw_fd(fd: RawFd) -> Result<FakeTap> {
+            Ok(FakeTap {
+                tap_file: File::from_raw_fd(fd),
+                addr_configured: Cell::new(false),
             })
         }
 
@@ -477,6 +534,7 @@ pub mod fakes {
         }
 
         fn set_ip_addr(&self, _: net::Ipv4Addr) -> Result<()> {
+            self.addr_configured.set(true);
             Ok(())
         }
 
@@ -485,6 +543,7 @@ pub mod fakes {
         }
 
         fn set_netmask(&self, _: net::Ipv4Addr) -> Result<()> {
+            self.addr_configured.set(true);
             Ok(())
         }
 
@@ -546,6 +605,8 @@ pub mod fakes {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
 
     #[test]
     fn parse_mac_address() {
@@ -587,6 +648,16 @@ mod tests {
         tap.set_offload(0).unwrap();
     }
 
+    #[test]
+    fn tap_from_non_tap_fd() {
+        let (sock, _) = UnixStream::pair().unwrap();
+        // The socket's fd is handed over to the tap, which closes it.
+        match unsafe { Tap::from_raw_fd(sock.into_raw_fd()) } {
+            Err(Error::NotTap) => {}
+            _ => panic!("socket should not be accepted as a tap"),
+        }
+    }
+
     #[test]
     fn tap_enable() {
         let tap = Tap::new(true).unwrap();
@@ -0,0 +1,667 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A stub for the gdb remote serial protocol, which lets `gdb` debug the guest over TCP.
+//!
+//! The stub runs on its own thread and carries out gdb's commands by sending `VmRequest`s over a
+//! control socket, like any other client of the VM. All vcpus are paused while gdb has control and
+//! resumed when gdb continues the guest. Registers and memory are accessed through the vcpu that
+//! last stopped, and memory addresses are guest virtual addresses translated with that vcpu's page
+//! tables. Breakpoints use the vcpus' hardware debug registers, so only `MAX_BREAKPOINTS` of them
+//! can be inserted at a time.
+
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+
+use libc::{EFAULT, EINVAL, EIO, ENOSPC};
+
+use sys_util::{self, EventFd, PollContext, PollToken, Scm};
+use vm_control::{VcpuDebugCommand, VcpuRegs, VmRequest, VmResponse, MAX_BREAKPOINTS,
+                 MAX_MEM_ACCESS_LEN};
+
+// Packets larger than this are dropped. It is enough for an `M` packet writing
+// `MAX_MEM_ACCESS_LEN` bytes.
+const MAX_PACKET_LEN: usize = 2 * MAX_MEM_ACCESS_LEN + 64;
+const PAGE_SIZE: u64 = 4096;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// The size of the amd64 register packet that gdb expects without a target description: 17 64-bit
+// registers followed by eflags and six segment selectors of 32 bits each.
+const REGS_PACKET_LEN: usize = 17 * 8 + 7 * 4;
+
+/// Input from gdb, as decoded by `PacketReader`.
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// A packet with a valid checksum, without its framing.
+    Packet(Vec<u8>),
+    /// A packet that was corrupted and should be retransmitted.
+    BadPacket,
+    /// A request to stop the running guest.
+    Interrupt,
+}
+
+enum ReaderState {
+    Idle,
+    Data,
+    Checksum(u8, Option<u8>),
+}
+
+/// Decodes the `$data#cs` framed packets sent by gdb, one byte at a time.
+struct PacketReader {
+    state: ReaderState,
+    data: Vec<u8>,
+}
+
+impl PacketReader {
+    fn new() -> PacketReader {
+        PacketReader {
+            state: ReaderState::Idle,
+            data: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Input> {
+        match self.state {
+            ReaderState::Idle => {
+                match byte {
+                    b'$' => {
+                        self.data.clear();
+                        self.state = ReaderState::Data;
+                    }
+                    0x03 => return Some(Input::Interrupt),
+                    // Acknowledgements of our own packets, which are never retransmitted.
+                    _ => {}
+                }
+            }
+            ReaderState::Data => {
+                if byte == b'#' {
+                    self.state = ReaderState::Checksum(checksum(&self.data), None);
+                } else if self.data.len() < MAX_PACKET_LEN {
+                    self.data.push(byte);
+                } else {
+                    self.state = ReaderState::Idle;
+                    return Some(Input::BadPacket);
+                }
+            }
+            ReaderState::Checksum(sum, None) => {
+                self.state = ReaderState::Checksum(sum, Some(byte));
+            }
+            ReaderState::Checksum(sum, Some(high)) => {
+                self.state = ReaderState::Idle;
+                return match parse_hex(&[high, byte]) {
+                           Some(cs) if cs == sum as u64 => {
+                               Some(Input::Packet(self.data.split_off(0)))
+                           }
+                           _ => Some(Input::BadPacket),
+                       };
+            }
+        }
+        None
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Frames `data` as a packet to send to gdb.
+fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+    packet
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for &c in hex {
+        value = value << 4 | (c as char).to_digit(16)? as u64;
+    }
+    Some(value)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2).map(|b| parse_hex(b).map(|v| v as u8)).collect()
+}
+
+// Splits `a,b` or `a,b:c` style packet arguments.
+fn split_args<'a>(args: &'a [u8], separators: &'a [u8]) -> Vec<&'a [u8]> {
+    args.split(|c| separators.contains(c)).collect()
+}
+
+// The registers in the order of the register packet, with their sizes in bytes.
+fn regs_packet_fields(regs: &mut VcpuRegs) -> [(&mut u64, usize); 18] {
+    [(&mut regs.rax, 8), (&mut regs.rbx, 8), (&mut regs.rcx, 8), (&mut regs.rdx, 8),
+     (&mut regs.rsi, 8), (&mut regs.rdi, 8), (&mut regs.rbp, 8), (&mut regs.rsp, 8),
+     (&mut regs.r8, 8), (&mut regs.r9, 8), (&mut regs.r10, 8), (&mut regs.r11, 8),
+     (&mut regs.r12, 8), (&mut regs.r13, 8), (&mut regs.r14, 8), (&mut regs.r15, 8),
+     (&mut regs.rip, 8), (&mut regs.rflags, 4)]
+}
+
+/// Encodes `regs` as the contents of a reply to a `g` packet.
+fn encode_regs(regs: &VcpuRegs) -> String {
+    let mut regs = *regs;
+    let selectors = [regs.cs.selector, regs.ss.selector, regs.ds.selector, regs.es.selector,
+                     regs.fs.selector, regs.gs.selector];
+    let mut bytes = Vec::with_capacity(REGS_PACKET_LEN);
+    for &mut (ref value, size) in regs_packet_fields(&mut regs).iter_mut() {
+        for i in 0..size {
+            bytes.push((**value >> (8 * i)) as u8);
+        }
+    }
+    for &selector in selectors.iter() {
+        bytes.extend_from_slice(&[selector as u8, (selector >> 8) as u8, 0, 0]);
+    }
+    encode_hex(&bytes)
+}
+
+/// Decodes the contents of a `G` packet over `regs`. Segment selectors are not decoded because they
+/// can't be changed without their descriptors.
+fn decode_regs(hex: &[u8], regs: &mut VcpuRegs) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    if bytes.len() < REGS_PACKET_LEN {
+        return None;
+    }
+    let mut offset = 0;
+    for &mut (ref mut value, size) in regs_packet_fields(regs).iter_mut() {
+        **value = bytes[offset..offset + size]
+            .iter()
+            .rev()
+            .fold(0, |v, &b| v << 8 | b as u64);
+        offset += size;
+    }
+    Some(())
+}
+
+fn error_reply(e: sys_util::Error) -> Vec<u8> {
+    format!("E{:02x}", e.errno() as u8).into_bytes()
+}
+
+#[derive(PollToken)]
+enum Token {
+    Client,
+    VcpuStop,
+}
+
+/// The connection to the VM shared by all gdb sessions.
+struct GdbStub {
+    scm: Scm,
+    control_socket: UnixDatagram,
+    vcpu_count: u32,
+    stopped: Receiver<u32>,
+    stop_evt: EventFd,
+}
+
+/// The state of one connection from gdb.
+struct Session<'a> {
+    stub: &'a mut GdbStub,
+    stream: TcpStream,
+    reader: PacketReader,
+    // The vcpu whose registers and page tables are used.
+    vcpu: u32,
+    breakpoints: Vec<u64>,
+    running: bool,
+}
+
+impl GdbStub {
+    fn request(&mut self, request: VmRequest) -> sys_util::Result<VmResponse> {
+        if let Err(e) = request.send(&mut self.scm, &self.control_socket) {
+            error!("gdb stub failed to send request: {:?}", e);
+            return Err(sys_util::Error::new(EIO));
+        }
+        match VmResponse::recv(&mut self.scm, &self.control_socket) {
+            Ok(VmResponse::Err(e)) => Err(e),
+            Ok(response) => Ok(response),
+            Err(e) => {
+                error!("gdb stub failed to receive response: {:?}", e);
+                Err(sys_util::Error::new(EIO))
+            }
+        }
+    }
+
+    fn debug_all(&mut self, command: VcpuDebugCommand) -> sys_util::Result<()> {
+        for vcpu in 0..self.vcpu_count {
+            self.request(VmRequest::VcpuDebug { vcpu: vcpu, command: command })?;
+        }
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, addrs: &[u64]) -> sys_util::Result<()> {
+        for vcpu in 0..self.vcpu_count {
+            self.request(VmRequest::SetBreakpoints {
+                             vcpu: vcpu,
+                             addrs: addrs.to_vec(),
+                         })?;
+        }
+        Ok(())
+    }
+
+    fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        // gdb expects the guest to be stopped when it attaches.
+        self.debug_all(VcpuDebugCommand::Pause)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        let mut session = Session {
+            stub: self,
+            stream: stream,
+            reader: PacketReader::new(),
+            vcpu: 0,
+            breakpoints: Vec::new(),
+            running: false,
+        };
+        let res = session.run();
+        // Leave the guest running without breakpoints once gdb is gone.
+        let _ = session.stub.set_breakpoints(&[]);
+        let _ = session.stub.debug_all(VcpuDebugCommand::Continue);
+        res
+    }
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        let poll_ctx: PollContext<Token> = PollContext::new()
+            .and_then(|pc| pc.add(&self.stream, Token::Client).and(Ok(pc)))
+            .and_then(|pc| pc.add(&self.stub.stop_evt, Token::VcpuStop).and(Ok(pc)))
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+        loop {
+            let events = poll_ctx
+                .wait()
+                .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+            for event in events.iter_readable() {
+                match event.token() {
+                    Token::Client => {
+                        let mut buf = [0u8; 1024];
+                        let count = self.stream.read(&mut buf)?;
+                        if count == 0 {
+                            return Ok(());
+                        }
+                        for &byte in &buf[..count] {
+                            if !self.handle_input(byte)? {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Token::VcpuStop => {
+                        let _ = self.stub.stop_evt.read();
+                        while let Ok(vcpu) = self.stub.stopped.try_recv() {
+                            if self.running {
+                                self.stop(vcpu, SIGTRAP)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&encode_packet(data))
+    }
+
+    // Pauses all vcpus and tells gdb that the guest stopped at `vcpu` with `signal`.
+    fn stop(&mut self, vcpu: u32, signal: u8) -> io::Result<()> {
+        if let Err(e) = self.stub.debug_all(VcpuDebugCommand::Pause) {
+            error!("gdb stub failed to pause vcpus: {:?}", e);
+        }
+        self.vcpu = vcpu;
+        self.running = false;
+        self.send_packet(format!("S{:02x}", signal).as_bytes())
+    }
+
+    // Returns false once gdb has detached.
+    fn handle_input(&mut self, byte: u8) -> io::Result<bool> {
+        match self.reader.push(byte) {
+            Some(Input::Packet(packet)) => {
+                self.stream.write_all(b"+")?;
+                if packet == b"D" || packet == b"k" {
+                    if packet == b"D" {
+                        self.send_packet(b"OK")?;
+                    }
+                    return Ok(false);
+                }
+                if let Some(reply) = self.handle_packet(&packet) {
+                    self.send_packet(&reply)?;
+                }
+            }
+            Some(Input::BadPacket) => self.stream.write_all(b"-")?,
+            Some(Input::Interrupt) => {
+                if self.running {
+                    let vcpu = self.vcpu;
+                    self.stop(vcpu, SIGINT)?;
+                }
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    // Returns the reply to `packet`, or None if the reply is sent when the guest stops.
+    fn handle_packet(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.is_empty() {
+            return Some(Vec::new());
+        }
+        let args = &packet[1..];
+        let res = match packet[0] {
+            b'?' => Ok(format!("S{:02x}", SIGTRAP).into_bytes()),
+            b'g' => self.read_regs(),
+            b'G' => self.write_regs(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'c' => return self.resume(args, VcpuDebugCommand::Continue),
+            b's' => return self.resume(args, VcpuDebugCommand::Step),
+            b'Z' | b'z' => self.change_breakpoint(packet[0] == b'Z', args),
+            b'H' => Ok(b"OK".to_vec()),
+            b'q' if args.starts_with(b"Supported") => {
+                Ok(format!("PacketSize={:x}", MAX_PACKET_LEN).into_bytes())
+            }
+            b'q' if args == b"Attached" => Ok(b"1".to_vec()),
+            _ => Ok(Vec::new()),
+        };
+        Some(res.unwrap_or_else(error_reply))
+    }
+
+    fn snapshot_regs(&mut self) -> sys_util::Result<VcpuRegs> {
+        match self.stub.request(VmRequest::DumpRegs { vcpu: self.vcpu })? {
+            VmResponse::DumpRegs(regs) => Ok(regs),
+            _ => Err(sys_util::Error::new(EIO)),
+        }
+    }
+
+    fn read_regs(&mut self) -> sys_util::Result<Vec<u8>> {
+        Ok(encode_regs(&self.snapshot_regs()?).into_bytes())
+    }
+
+    fn write_regs(&mut self, args: &[u8]) -> sys_util::Result<Vec<u8>> {
+        let mut regs = self.snapshot_regs()?;
+        decode_regs(args, &mut regs).ok_or(sys_util::Error::new(EINVAL))?;
+        self.stub.request(VmRequest::SetRegs { vcpu: self.vcpu, regs })?;
+        Ok(b"OK".to_vec())
+    }
+
+    fn translate(&mut self, gva: u64) -> sys_util::Result<u64> {
+        match self.stub.request(VmRequest::TranslateAddress { vcpu: self.vcpu, gva })? {
+            VmResponse::TranslateAddress { gpa } => Ok(gpa),
+            _ => Err(sys_util::Error::new(EIO)),
+        }
+    }
+
+    // Calls `f` with the guest physical address and length of each page sized piece of the `len`
+    // bytes at `gva`.
+    fn for_each_page<F>(&mut self, gva: u64, len: usize, mut f: F) -> sys_util::Result<()>
+        where F: FnMut(&mut GdbStub, u64, usize) -> sys_util::Result<()>
+    {
+        let mut done = 0;
+        while done < len {
+            let addr = gva.wrapping_add(done as u64);
+            let chunk = min(len - done, (PAGE_SIZE - addr % PAGE_SIZE) as usize);
+            let gpa = self.translate(addr)?;
+            f(&mut *self.stub, gpa, chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn read_memory(&mut self, args: &[u8]) -> sys_util::Result<Vec<u8>> {
+        let args = split_args(args, b",");
+        let (gva, len) = match (args.get(0).and_then(|a| parse_hex(a)),
+                                args.get(1).and_then(|a| parse_hex(a))) {
+            (Some(gva), Some(len)) => (gva, min(len as usize, MAX_MEM_ACCESS_LEN)),
+            _ => return Err(sys_util::Error::new(EINVAL)),
+        };
+        let mut bytes = Vec::with_capacity(len);
+        let res = self.for_each_page(gva, len, |stub, gpa, chunk| {
+            match stub.request(VmRequest::ReadMem { gpa, len: chunk as u32 })? {
+                VmResponse::ReadMem(data) => {
+                    bytes.extend_from_slice(&data);
+                    Ok(())
+                }
+                _ => Err(sys_util::Error::new(EIO)),
+            }
+        });
+        // gdb accepts a short read of memory that is only partly accessible.
+        match res {
+            Err(_) if !bytes.is_empty() => Ok(encode_hex(&bytes).into_bytes()),
+            Err(e) => Err(e),
+            Ok(()) => Ok(encode_hex(&bytes).into_bytes()),
+        }
+    }
+
+    fn write_memory(&mut self, args: &[u8]) -> sys_util::Result<Vec<u8>> {
+        let args = split_args(args, b",:");
+        let (gva, bytes) = match (args.get(0).and_then(|a| parse_hex(a)),
+                                  args.get(1).and_then(|a| parse_hex(a)),
+                                  args.get(2).and_then(|a| decode_hex(a))) {
+            (Some(gva), Some(len), Some(bytes)) if len as usize == bytes.len() => (gva, bytes),
+            _ => return Err(sys_util::Error::new(EINVAL)),
+        };
+        if bytes.len() > MAX_MEM_ACCESS_LEN {
+            return Err(sys_util::Error::new(EFAULT));
+        }
+        let mut offset = 0;
+        self.for_each_page(gva, bytes.len(), |stub, gpa, chunk| {
+            let data = bytes[offset..offset + chunk].to_vec();
+            offset += chunk;
+            stub.request(VmRequest::WriteMem { gpa: gpa, bytes: data }).map(|_| ())
+        })?;
+        Ok(b"OK".to_vec())
+    }
+
+    fn resume(&mut self, args: &[u8], command: VcpuDebugCommand) -> Option<Vec<u8>> {
+        if let Some(rip) = parse_hex(args) {
+            let res = self.snapshot_regs().and_then(|mut regs| {
+                regs.rip = rip;
+                self.stub.request(VmRequest::SetRegs { vcpu: self.vcpu, regs })
+            });
+            if let Err(e) = res {
+                return Some(error_reply(e));
+            }
+        }
+        // Forget vcpus that stopped while gdb had control so they aren't reported later.
+        while let Ok(_) = self.stub.stopped.try_recv() {}
+        let res = match command {
+            VcpuDebugCommand::Step => {
+                self.stub.request(VmRequest::VcpuDebug { vcpu: self.vcpu, command }).map(|_| ())
+            }
+            _ => self.stub.debug_all(command),
+        };
+        match res {
+            Ok(()) => {
+                self.running = true;
+                None
+            }
+            Err(e) => Some(error_reply(e)),
+        }
+    }
+
+    fn change_breakpoint(&mut self, insert: bool, args: &[u8]) -> sys_util::Result<Vec<u8>> {
+        let args = split_args(args, b",");
+        // Software breakpoints are also set in the debug registers so that guest memory is never
+        // patched.
+        let addr = match (args.get(0).map(|a| &a[..]), args.get(1).and_then(|a| parse_hex(a))) {
+            (Some(b"0"), Some(addr)) | (Some(b"1"), Some(addr)) => addr,
+            _ => return Ok(Vec::new()),
+        };
+        let mut breakpoints = self.breakpoints.clone();
+        if insert {
+            if breakpoints.contains(&addr) {
+                return Ok(b"OK".to_vec());
+            }
+            if breakpoints.len() == MAX_BREAKPOINTS {
+                return Err(sys_util::Error::new(ENOSPC));
+            }
+            breakpoints.push(addr);
+        } else {
+            breakpoints.retain(|&a| a != addr);
+        }
+        self.stub.set_breakpoints(&breakpoints)?;
+        self.breakpoints = breakpoints;
+        Ok(b"OK".to_vec())
+    }
+}
+
+/// Starts a thread serving gdb connections accepted on `listener`, one at a time.
+///
+/// # Arguments
+///
+/// * `listener` - The socket gdb connects to.
+/// * `control_socket` - Sends `VmRequest`s to the VM's control loop.
+/// * `vcpu_count` - The number of vcpus in the VM.
+/// * `stopped` - Receives the index of each vcpu that stops on a breakpoint or single step.
+/// * `stop_evt` - Signaled whenever a vcpu index is sent to `stopped`.
+pub fn start_gdb_stub(listener: TcpListener,
+                      control_socket: UnixDatagram,
+                      vcpu_count: u32,
+                      stopped: Receiver<u32>,
+                      stop_evt: EventFd)
+                      -> io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("crosvm_gdb".to_owned())
+        .spawn(move || {
+            let mut stub = GdbStub {
+                scm: Scm::new(1),
+                control_socket: control_socket,
+                vcpu_count: vcpu_count,
+                stopped: stopped,
+                stop_evt: stop_evt,
+            };
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        info!("gdb connected");
+                        if let Err(e) = stub.serve(stream) {
+                            warn!("gdb connection failed: {}", e);
+                        }
+                        info!("gdb disconnected");
+                    }
+                    Err(e) => {
+                        error!("failed to accept gdb connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(reader: &mut PacketReader, bytes: &[u8]) -> Vec<Input> {
+        bytes.iter().filter_map(|&b| reader.push(b)).collect()
+    }
+
+    #[test]
+    fn packet_checksum() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(encode_packet(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode_packet(b""), b"$#00".to_vec());
+        assert_eq!(encode_packet(b"S05"), b"$S05#b8".to_vec());
+    }
+
+    #[test]
+    fn packet_framing() {
+        let mut reader = PacketReader::new();
+        assert_eq!(read_all(&mut reader, b"+$qAttached#8f+$g#67"),
+                   vec![Input::Packet(b"qAttached".to_vec()), Input::Packet(b"g".to_vec())]);
+        // The checksum is accepted in either case.
+        assert_eq!(read_all(&mut reader, b"$OK#9A"), vec![Input::Packet(b"OK".to_vec())]);
+        assert_eq!(read_all(&mut reader, b"$g#68$g#6z"),
+                   vec![Input::BadPacket, Input::BadPacket]);
+        assert_eq!(read_all(&mut reader, b"\x03$?#3f"),
+                   vec![Input::Interrupt, Input::Packet(b"?".to_vec())]);
+        // A packet can be split across reads.
+        assert!(read_all(&mut reader, b"$m10,").is_empty());
+        assert_eq!(read_all(&mut reader, b"4#2e"), vec![Input::Packet(b"m10,4".to_vec())]);
+    }
+
+    #[test]
+    fn packet_too_long() {
+        let mut reader = PacketReader::new();
+        let mut packet = vec![b'$'];
+        packet.resize(MAX_PACKET_LEN + 2, b'0');
+        assert_eq!(read_all(&mut reader, &packet), vec![Input::BadPacket]);
+        // The rest of the oversized packet is ignored.
+        assert_eq!(read_all(&mut reader, b"00#00$g#67"), vec![Input::Packet(b"g".to_vec())]);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex(b"ffffffff81000000"), Some(0xffffffff81000000));
+        assert_eq!(parse_hex(b"1A"), Some(0x1a));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"1g"), None);
+        assert_eq!(parse_hex(b"11111111111111111"), None);
+        assert_eq!(encode_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(decode_hex(b"00ab10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(decode_hex(b"0"), None);
+    }
+
+    fn fake_regs() -> VcpuRegs {
+        let mut regs = VcpuRegs::default();
+        regs.rax = 0x1122334455667788;
+        regs.rsp = 0xffff880000001000;
+        regs.r15 = 15;
+        regs.rip = 0xffffffff81000000;
+        regs.rflags = 0x246;
+        regs.cs.selector = 0x10;
+        regs.ss.selector = 0x18;
+        regs.gs.selector = 0x2b;
+        regs
+    }
+
+    #[test]
+    fn regs_packet_encoding() {
+        let packet = encode_regs(&fake_regs());
+        assert_eq!(packet.len(), 2 * REGS_PACKET_LEN);
+        // rax is first, in little endian byte order.
+        assert!(packet.starts_with("8877665544332211"));
+        // rsp is the eighth register, after rbp.
+        assert_eq!(&packet[7 * 16..8 * 16], "001000000088ffff");
+        assert_eq!(&packet[15 * 16..16 * 16], "0f00000000000000");
+        assert_eq!(&packet[16 * 16..17 * 16], "00000081ffffffff");
+        assert_eq!(&packet[17 * 16..],
+                   concat!("46020000", "10000000", "18000000", "00000000", "00000000",
+                           "00000000", "2b000000"));
+    }
+
+    #[test]
+    fn regs_packet_decoding() {
+        let packet = encode_regs(&fake_regs());
+        let mut regs = VcpuRegs::default();
+        decode_regs(packet.as_bytes(), &mut regs).unwrap();
+        let mut expected = fake_regs();
+        expected.cs.selector = 0;
+        expected.ss.selector = 0;
+        expected.gs.selector = 0;
+        assert_eq!(regs, expected);
+
+        assert!(decode_regs(&packet.as_bytes()[..packet.len() - 2], &mut regs).is_none());
+        assert!(decode_regs(b"xx", &mut regs).is_none());
+    }
+
+    #[test]
+    fn breakpoint_args() {
+        assert_eq!(split_args(b"0,ffffffff81000000,1", b","),
+                   vec![&b"0"[..], &b"ffffffff81000000"[..], &b"1"[..]]);
+        assert_eq!(split_args(b"1000,2:abcd", b",:"),
+                   vec![&b"1000"[..], &b"2"[..], &b"abcd"[..]]);
+    }
+}
@@ -6,18 +6,24 @@ use std;
 use std::ffi::{CString, CStr};
 use std::fmt;
 use std::error;
-use std::fs::{File, OpenOptions, remove_file};
+use std::env;
+use std::fs::{File, OpenOptions, Permissions, remove_file, set_permissions};
 use std::io::{self, stdin};
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, Barrier};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use libc;
-use libc::c_int;
+use libc::{c_int, pid_t};
 #[cfg(feature = "wl-dmabuf")]
 use libc::EINVAL;
 
@@ -26,19 +32,24 @@ use devices;
 use io_jail::{self, Minijail};
 use kernel_cmdline;
 use kvm::*;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_sys;
 use net_util::Tap;
 use qcow::{self, QcowFile};
 use sys_util::*;
 use sys_util;
 use vhost;
-use vm_control::{VmRequest, GpuMemoryAllocator};
+use vm_control::{VmRequest, VmResponse, DevicePfnAllocator, GpuMemoryAllocator, SharedMemoryAllocator, VcpuControl, VcpuDebugCommand, VcpuRegs, VirtioFeatures};
 #[cfg(feature = "wl-dmabuf")]
 use gpu_buffer;
 
+use gdb;
 use Config;
+use DiskLockMode;
+use GuestConsole;
 use DiskType;
 
-use arch::LinuxArch;
+use arch::{CpuidOverride, LinuxArch};
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use x86_64::X8664arch as Arch;
@@ -47,50 +58,69 @@ use aarch64::AArch64 as Arch;
 
 pub enum Error {
     BalloonDeviceNew(devices::virtio::BalloonError),
+    BindGdbSocket(io::Error),
+    BindGuestMemory(u32, GuestMemoryError),
     BlockDeviceNew(sys_util::Error),
     BlockSignal(sys_util::signal::Error),
     CloneEventFd(sys_util::Error),
     Cmdline(kernel_cmdline::Error),
+    CreateDeviceCounters(sys_util::MmapError),
     CreateEventFd(sys_util::Error),
-    CreateGpuBufferDevice,
+    #[cfg(feature = "wl-dmabuf")]
+    CreateGpuBufferDevice(gpu_buffer::Error),
     CreateGuestMemory(Box<error::Error>),
     CreateIrqChip(Box<error::Error>),
     CreateKvm(sys_util::Error),
+    CreatePivotRoot(sys_util::Error),
     CreatePollContext(sys_util::Error),
     CreateSignalFd(sys_util::SignalFdError),
     CreateSocket(io::Error),
     CreateVcpu(sys_util::Error),
     CreateVm(Box<error::Error>),
     DeviceJail(io_jail::Error),
+    DeviceJailMount(io_jail::Error),
     DevicePivotRoot(io_jail::Error),
     Disk(io::Error),
     DiskImageLock(sys_util::Error),
+    DiskImageLocked(PathBuf),
     FailedCLOEXECCheck,
     FailedToDupFd,
     InvalidFdPath,
+    InvalidPivotRoot(PathBuf, &'static str),
+    LockGuestMemory(GuestMemoryError),
     NetDeviceNew(devices::virtio::NetError),
-    NoVarEmpty,
+    NoVsockSocketDir(PathBuf),
+    OpenConsole(PathBuf, io::Error),
+    OpenInitrd(PathBuf, io::Error),
     OpenKernel(PathBuf, io::Error),
-    OpenGpuBufferDevice,
+    OpenSerialOutput(PathBuf, io::Error),
     PollContextAdd(sys_util::Error),
     QcowDeviceCreate(qcow::Error),
+    Register9p(device_manager::Error),
     RegisterBalloon(device_manager::Error),
     RegisterBlock(device_manager::Error),
+    RegisterConsole(device_manager::Error),
+    RegisterInput(device_manager::Error),
     RegisterNet(device_manager::Error),
     RegisterRng(device_manager::Error),
     RegisterSignalHandler(sys_util::Error),
     RegisterVsock(device_manager::Error),
     RegisterWayland(device_manager::Error),
     RngDeviceNew(devices::virtio::RngError),
+    SeccompPolicyOverrideMissing(String, PathBuf),
     SettingGidMap(io_jail::Error),
     SettingUidMap(io_jail::Error),
     SignalFd(sys_util::SignalFdError),
+    SpawnGdbStub(io::Error),
     SpawnVcpu(io::Error),
+    TooManyVcpus { requested: u32, max: u32 },
     VhostNetDeviceNew(devices::virtio::vhost::Error),
     VhostVsockDeviceNew(devices::virtio::vhost::Error),
     WaylandDeviceNew(sys_util::Error),
     SetupSystemMemory(Box<error::Error>),
+    HighMmioRegion(Box<error::Error>),
     ConfigureVcpu(Box<error::Error>),
+    LoadInitrd(Box<error::Error>),
     LoadKernel(Box<error::Error>),
     SetupIoBus(Box<error::Error>),
     SetupMMIOBus(Box<error::Error>),
@@ -100,45 +130,83 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &Error::BalloonDeviceNew(ref e) => write!(f, "failed to create balloon: {:?}", e),
+            &Error::BindGdbSocket(ref e) => write!(f, "failed to listen for gdb: {}", e),
+            &Error::BindGuestMemory(node, ref e) => {
+                write!(f, "failed to bind guest memory to NUMA node {}: {:?}", node, e)
+            }
             &Error::BlockDeviceNew(ref e) => write!(f, "failed to create block device: {:?}", e),
             &Error::BlockSignal(ref e) => write!(f, "failed to block signal: {:?}", e),
             &Error::CloneEventFd(ref e) => write!(f, "failed to clone eventfd: {:?}", e),
             &Error::Cmdline(ref e) => write!(f, "the given kernel command line was invalid: {}", e),
+            &Error::CreateDeviceCounters(ref e) => {
+                write!(f, "failed to create device statistics counters: {:?}", e)
+            }
             &Error::CreateEventFd(ref e) => write!(f, "failed to create eventfd: {:?}", e),
-            &Error::CreateGpuBufferDevice => write!(f, "failed to create GPU buffer device"),
+            #[cfg(feature = "wl-dmabuf")]
+            &Error::CreateGpuBufferDevice(ref e) => {
+                write!(f, "failed to create GPU buffer device: {}", e)
+            }
             &Error::CreateGuestMemory(ref e) => write!(f, "failed to create guest memory: {:?}", e),
             &Error::CreateIrqChip(ref e) => {
                 write!(f, "failed to create in-kernel IRQ chip: {:?}", e)
             }
             &Error::CreateKvm(ref e) => write!(f, "failed to open /dev/kvm: {:?}", e),
+            &Error::CreatePivotRoot(ref e) => {
+                write!(f, "failed to create a pivot root for jailed devices: {:?}", e)
+            }
             &Error::CreatePollContext(ref e) => write!(f, "failed to create poll context: {:?}", e),
             &Error::CreateSignalFd(ref e) => write!(f, "failed to create signalfd: {:?}", e),
             &Error::CreateSocket(ref e) => write!(f, "failed to create socket: {}", e),
             &Error::CreateVcpu(ref e) => write!(f, "failed to create VCPU: {:?}", e),
             &Error::CreateVm(ref e) => write!(f, "failed to create KVM VM object: {:?}", e),
             &Error::DeviceJail(ref e) => write!(f, "failed to jail device: {}", e),
+            &Error::DeviceJailMount(ref e) => write!(f, "failed to mount in device jail: {}", e),
             &Error::DevicePivotRoot(ref e) => write!(f, "failed to pivot root device: {}", e),
             &Error::Disk(ref e) => write!(f, "failed to load disk image: {}", e),
             &Error::DiskImageLock(ref e) => write!(f, "failed to lock disk image: {:?}", e),
+            &Error::DiskImageLocked(ref p) => {
+                write!(f, "disk image {:?} is locked by another process", p)
+            }
             &Error::FailedCLOEXECCheck => {
                 write!(f, "/proc/self/fd argument failed check for CLOEXEC")
             }
             &Error::FailedToDupFd => write!(f, "failed to dup fd from /proc/self/fd"),
             &Error::InvalidFdPath => write!(f, "failed parsing a /proc/self/fd/*"),
+            &Error::InvalidPivotRoot(ref p, ref reason) => {
+                write!(f, "can't jail devices in {:?}: {}", p, reason)
+            }
+            &Error::LockGuestMemory(ref e) => {
+                write!(f, "failed to lock guest memory, RLIMIT_MEMLOCK may be too small: {:?}", e)
+            }
             &Error::NetDeviceNew(ref e) => write!(f, "failed to set up virtio networking: {:?}", e),
-            &Error::NoVarEmpty => write!(f, "/var/empty doesn't exist, can't jail devices."),
+            &Error::NoVsockSocketDir(ref p) => {
+                write!(f, "virtual socket directory {:?} doesn't exist", p)
+            }
+            &Error::OpenConsole(ref p, ref e) => {
+                write!(f, "failed to open virtio console {:?}: {}", p, e)
+            }
+            &Error::OpenInitrd(ref p, ref e) => {
+                write!(f, "failed to open initrd image {:?}: {}", p, e)
+            }
             &Error::OpenKernel(ref p, ref e) => {
                 write!(f, "failed to open kernel image {:?}: {}", p, e)
             }
-            &Error::OpenGpuBufferDevice => write!(f, "failed to open GPU buffer device"),
+            &Error::OpenSerialOutput(ref p, ref e) => {
+                write!(f, "failed to open serial port output {:?}: {}", p, e)
+            }
             &Error::PollContextAdd(ref e) => write!(f, "failed to add fd to poll context: {:?}", e),
             &Error::QcowDeviceCreate(ref e) => {
                 write!(f, "failed to read qcow formatted file {:?}", e)
             }
+            &Error::Register9p(ref e) => write!(f, "error registering 9p device: {:?}", e),
             &Error::RegisterBalloon(ref e) => {
                 write!(f, "error registering balloon device: {:?}", e)
             },
             &Error::RegisterBlock(ref e) => write!(f, "error registering block device: {:?}", e),
+            &Error::RegisterConsole(ref e) => {
+                write!(f, "error registering console device: {:?}", e)
+            }
+            &Error::RegisterInput(ref e) => write!(f, "error registering input device: {:?}", e),
             &Error::RegisterNet(ref e) => write!(f, "error registering net device: {:?}", e),
             &Error::RegisterRng(ref e) => write!(f, "error registering rng device: {:?}", e),
             &Error::RegisterSignalHandler(ref e) => {
@@ -149,10 +217,17 @@ impl fmt::Display for Error {
             }
             &Error::RegisterWayland(ref e) => write!(f, "error registering wayland device: {}", e),
             &Error::RngDeviceNew(ref e) => write!(f, "failed to set up rng: {:?}", e),
+            &Error::SeccompPolicyOverrideMissing(ref d, ref p) => {
+                write!(f, "seccomp policy {:?} given for the {} device doesn't exist", p, d)
+            }
             &Error::SettingGidMap(ref e) => write!(f, "error setting GID map: {}", e),
             &Error::SettingUidMap(ref e) => write!(f, "error setting UID map: {}", e),
             &Error::SignalFd(ref e) => write!(f, "failed to read signal fd: {:?}", e),
+            &Error::SpawnGdbStub(ref e) => write!(f, "failed to spawn gdb stub thread: {:?}", e),
             &Error::SpawnVcpu(ref e) => write!(f, "failed to spawn VCPU thread: {:?}", e),
+            &Error::TooManyVcpus { requested, max } => {
+                write!(f, "{} VCPUs requested but KVM supports at most {}", requested, max)
+            }
             &Error::VhostNetDeviceNew(ref e) => {
                 write!(f, "failed to set up vhost networking: {:?}", e)
             }
@@ -163,7 +238,11 @@ impl fmt::Display for Error {
                 write!(f, "failed to create wayland device: {:?}", e)
             }
             &Error::SetupSystemMemory(ref e) => write!(f, "error setting up system memory: {}", e),
+            &Error::HighMmioRegion(ref e) => {
+                write!(f, "failed to reserve the high MMIO region: {}", e)
+            }
             &Error::ConfigureVcpu(ref e) => write!(f, "failed to configure vcpu: {}", e),
+            &Error::LoadInitrd(ref e) => write!(f, "failed to load initrd: {}", e),
             &Error::LoadKernel(ref e) => write!(f, "failed to load kernel: {}", e),
             &Error::SetupIoBus(ref e) => write!(f, "failed to setup iobus: {}", e),
             &Error::SetupMMIOBus(ref e) => write!(f, "failed to setup mmio bus: {}", e),
@@ -191,7 +270,159 @@ impl Drop for UnlinkUnixDatagram {
     }
 }
 
-fn create_base_minijail(root: &Path, seccomp_policy: &Path) -> Result<Minijail> {
+// Takes ownership of an FD inherited from the process that started crosvm, returning a close on
+// exec duplicate of it. The original FD is closed.
+fn claim_raw_fd(raw_fd: RawFd) -> Result<RawFd> {
+    unsafe {
+        // The FD is valid and this process owns it because fcntl succeeds on it. Ensure the
+        // returned FD is the only owner by first duping it then closing the original.
+        // Checking that close-on-exec isn't set helps filter out FDs that were opened by
+        // crosvm as all crosvm FDs are close on exec.
+        let flags = libc::fcntl(raw_fd, libc::F_GETFD);
+        if flags < 0 || (flags & libc::FD_CLOEXEC) != 0 {
+            return Err(Error::FailedCLOEXECCheck);
+        }
+
+        let dup_fd = libc::fcntl(raw_fd, libc::F_DUPFD_CLOEXEC, 0) as RawFd;
+        if dup_fd < 0 {
+            return Err(Error::FailedToDupFd);
+        }
+        libc::close(raw_fd);
+        Ok(dup_fd)
+    }
+}
+
+// Number of threads running requests for each disk with async io.
+const ASYNC_DISK_IO_THREADS: usize = 4;
+
+// How long to wait for another process to release a disk image before giving up.
+const DISK_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest pause between attempts to take a disk image lock.
+const DISK_LOCK_MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+// Takes the lock on a disk image requested by `mode`. A conflicting lock held by another process is
+// retried with backoff until `timeout` has passed, after which it is reported separately from other
+// failures.
+fn lock_disk_image(image: &File, path: &Path, mode: DiskLockMode, timeout: Duration) -> Result<()> {
+    let lock_op = match mode {
+        DiskLockMode::Exclusive => FlockOperation::LockExclusive,
+        DiskLockMode::Shared => FlockOperation::LockShared,
+        DiskLockMode::None => return Ok(()),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(10);
+    loop {
+        match flock(image, lock_op, true) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.errno() == libc::EWOULDBLOCK => {}
+            Err(e) => return Err(Error::DiskImageLock(e)),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::DiskImageLocked(path.to_path_buf()));
+        }
+        thread::sleep(std::cmp::min(backoff, deadline - now));
+        backoff = std::cmp::min(backoff * 2, DISK_LOCK_MAX_BACKOFF);
+    }
+}
+
+// The directory jailed devices pivot into: the configured one or, if that doesn't exist, a
+// private empty directory created for this instance of crosvm. The created directory is on the
+// host's filesystem, so each jail mounts an empty tmpfs over it.
+enum PivotRoot {
+    Configured(PathBuf),
+    Created(TempDir),
+}
+
+impl PivotRoot {
+    fn path(&self) -> &Path {
+        match self {
+            &PivotRoot::Configured(ref path) => path,
+            // The path is only taken by TempDir::remove, which is never called.
+            &PivotRoot::Created(ref dir) => dir.as_path().unwrap(),
+        }
+    }
+}
+
+fn create_pivot_root(configured: &Path) -> Result<PivotRoot> {
+    let root = if configured.exists() {
+        PivotRoot::Configured(configured.to_path_buf())
+    } else {
+        warn!("pivot root {:?} doesn't exist, creating a private one", configured);
+        let dir = TempDir::new(env::temp_dir().join("crosvm_pivot_root."))
+            .map_err(Error::CreatePivotRoot)?;
+        // mkdtemp creates the directory with mode 0700, but jailed devices run as other users.
+        set_permissions(dir.as_path().unwrap(), Permissions::from_mode(0o755))
+            .map_err(|e| Error::CreatePivotRoot(sys_util::Error::new(e.raw_os_error()
+                                                                          .unwrap_or(libc::EIO))))?;
+        PivotRoot::Created(dir)
+    };
+    validate_pivot_root(root.path())?;
+    Ok(root)
+}
+
+// Checks that `path` is an empty directory that can't be modified by anyone but its owner, which
+// must be root or the current user.
+fn validate_pivot_root(path: &Path) -> Result<()> {
+    let invalid = |reason| Err(Error::InvalidPivotRoot(path.to_path_buf(), reason));
+    let metadata = match path.metadata() {
+        Ok(m) => m,
+        Err(_) => return invalid("can't read directory metadata"),
+    };
+    if !metadata.is_dir() {
+        return invalid("not a directory");
+    }
+    if metadata.uid() != 0 && metadata.uid() != geteuid() {
+        return invalid("must be owned by root or the current user");
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return invalid("must not be writable by group or others");
+    }
+    match path.read_dir() {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return invalid("must be empty");
+            }
+        }
+        Err(_) => return invalid("can't read directory"),
+    }
+    Ok(())
+}
+
+// The names of the devices that can run in their own jailed process, as given to `jail_device` and
+// `seccomp_policy_path`.
+pub const JAILED_DEVICES: &'static [&'static str] = &["9p",
+                                                      "balloon",
+                                                      "block",
+                                                      "console",
+                                                      "input",
+                                                      "net",
+                                                      "rng",
+                                                      "vhost_net",
+                                                      "vhost_vsock",
+                                                      "vsock",
+                                                      "wl"];
+
+// Returns true if the device named `device` should run in its own jailed process.
+fn jail_device(cfg: &Config, device: &str) -> bool {
+    cfg.multiprocess && !cfg.unjailed_devices.contains(device)
+}
+
+// Returns the seccomp policy for the device named `device`: the override given in the config if
+// any, otherwise `<device>_device.policy` in the policy directory.
+fn seccomp_policy_path(cfg: &Config, device: &str) -> Result<PathBuf> {
+    match cfg.seccomp_policy_overrides.get(device) {
+        Some(path) => {
+            if !path.is_file() {
+                return Err(Error::SeccompPolicyOverrideMissing(device.to_owned(), path.clone()));
+            }
+            Ok(path.clone())
+        }
+        None => Ok(cfg.seccomp_policy_dir.join(format!("{}_device.policy", device))),
+    }
+}
+
+fn create_base_minijail(root: &PivotRoot, seccomp_policy: &Path) -> Result<Minijail> {
     // All child jails run in a new user namespace without any users mapped,
     // they run as nobody unless otherwise configured.
     let mut j = Minijail::new().map_err(|e| Error::DeviceJail(e))?;
@@ -202,8 +433,15 @@ fn create_base_minijail(root: &Path, seccomp_policy: &Path) -> Result<Minijail>
     j.use_caps(0);
     // Create a new mount namespace with an empty root FS.
     j.namespace_vfs();
-    j.enter_pivot_root(root)
+    j.enter_pivot_root(root.path())
         .map_err(|e| Error::DevicePivotRoot(e))?;
+    if let &PivotRoot::Created(_) = root {
+        j.mount_with_data(Path::new("none"), Path::new("/"), "tmpfs",
+                          (libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV |
+                           libc::MS_NOEXEC) as usize,
+                          "mode=0755")
+            .map_err(|e| Error::DevicePivotRoot(e))?;
+    }
     // Run in an empty network namespace.
     j.namespace_net();
     // Apply the block device seccomp policy.
@@ -221,24 +459,38 @@ fn create_base_minijail(root: &Path, seccomp_policy: &Path) -> Result<Minijail>
     Ok(j)
 }
 
+// Returns the window size of `file` if it is a terminal, or a default size for other files.
+fn console_size(file: &File) -> (u16, u16) {
+    // Safe because winsize is plain data and is only written by the kernel.
+    let mut ws: libc::winsize = unsafe { mem::zeroed() };
+    // Safe because the kernel only writes a winsize to our pointer and we check the return value.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::TIOCGWINSZ, &mut ws) };
+    if ret == 0 && ws.ws_col != 0 && ws.ws_row != 0 {
+        (ws.ws_col, ws.ws_row)
+    } else {
+        (devices::virtio::DEFAULT_CONSOLE_COLS, devices::virtio::DEFAULT_CONSOLE_ROWS)
+    }
+}
+
 fn setup_mmio_bus(cfg: &Config,
                   vm: &mut Vm,
                   mem: &GuestMemory,
+                  stdio_serial: Arc<Mutex<devices::Serial>>,
                   cmdline: &mut kernel_cmdline::Cmdline,
                   control_sockets: &mut Vec<UnlinkUnixDatagram>,
-                  balloon_device_socket: UnixDatagram)
+                  balloon_device_socket: UnixDatagram,
+                  input_device_sockets: Vec<UnixDatagram>,
+                  device_counters: &mut Vec<devices::virtio::DeviceCounters>,
+                  acked_features: &mut Vec<devices::virtio::AckedFeatures>,
+                  mmio_devices: &mut Vec<(u64, u32)>,
+                  device_pids: &mut Vec<(pid_t, u32)>,
+                  pivot_root: &PivotRoot)
                   -> Result<devices::Bus> {
-    static DEFAULT_PIVOT_ROOT: &'static str = "/var/empty";
-    let mut device_manager = Arch::get_device_manager(vm, mem.clone()).
+    static DEFAULT_VSOCK_SOCKET_DIR: &'static str = "/run/crosvm/vsock";
+    let mut device_manager = Arch::get_device_manager(vm, mem.clone(), stdio_serial).
         map_err(|e| Error::SetupMMIOBus(e))?;
 
-    // An empty directory for jailed device's pivot root.
-    let empty_root_path = Path::new(DEFAULT_PIVOT_ROOT);
-    if cfg.multiprocess && !empty_root_path.exists() {
-        return Err(Error::NoVarEmpty);
-    }
-
-    for disk in &cfg.disks {
+    for (index, disk) in cfg.disks.iter().enumerate() {
         // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
         let mut raw_image: File = if disk.path.parent() == Some(Path::new("/proc/self/fd")) {
             if !disk.path.is_file() {
@@ -248,24 +500,8 @@ fn setup_mmio_bus(cfg: &Config,
                 .and_then(|fd_osstr| fd_osstr.to_str())
                 .and_then(|fd_str| fd_str.parse::<c_int>().ok())
                 .ok_or(Error::InvalidFdPath)?;
-            unsafe {
-                // The FD is valid and this process owns it because it exists in /proc/self/fd.
-                // Ensure |raw_image| is the only owner by first duping it then closing the
-                // original.
-                // Checking that close-on-exec isn't set helps filter out FDs that were opened by
-                // crosvm as all crosvm FDs are close on exec.
-                let flags = libc::fcntl(raw_fd, libc::F_GETFD);
-                if flags < 0 || (flags & libc::FD_CLOEXEC) != 0 {
-                    return Err(Error::FailedCLOEXECCheck);
-                }
-
-                let dup_fd = libc::fcntl(raw_fd, libc::F_DUPFD_CLOEXEC, 0) as RawFd;
-                if dup_fd < 0 {
-                    return Err(Error::FailedToDupFd);
-                }
-                libc::close(raw_fd);
-                File::from_raw_fd(dup_fd)
-            }
+            // The FD returned by claim_raw_fd is owned only by us.
+            unsafe { File::from_raw_fd(claim_raw_fd(raw_fd)?) }
         } else {
             OpenOptions::new()
                 .read(true)
@@ -273,29 +509,53 @@ fn setup_mmio_bus(cfg: &Config,
                 .open(&disk.path)
                 .map_err(|e| Error::Disk(e))?
         };
-        // Lock the disk image to prevent other crosvm instances from using it.
-        let lock_op = if disk.writable {
-            FlockOperation::LockExclusive
-        } else {
-            FlockOperation::LockShared
-        };
-        flock(&raw_image, lock_op, true).map_err(Error::DiskImageLock)?;
+        // Lock the disk image to prevent conflicting use by other crosvm instances.
+        lock_disk_image(&raw_image, &disk.path, disk.lock, DISK_LOCK_TIMEOUT)?;
 
+        let counters = devices::virtio::DeviceCounters::new(&format!("block{}", index))
+            .map_err(Error::CreateDeviceCounters)?;
         let block_box: Box<devices::virtio::VirtioDevice> = match disk.disk_type {
+            DiskType::FlatFile if disk.sparse => {
+                let sparse_image = devices::virtio::SparseFile::new(raw_image);
+                let mut block = devices::virtio::Block::new(sparse_image, disk.cache_mode)
+                    .map_err(|e| Error::BlockDeviceNew(e))?;
+                block.set_counters(counters.clone());
+                block.set_merge_requests(disk.merge_requests);
+                if let Some(max_transfer) = disk.max_transfer {
+                    block.set_max_transfer(max_transfer);
+                }
+                Box::new(block)
+            }
             DiskType::FlatFile => { // Access as a raw block device.
-                Box::new(devices::virtio::Block::new(raw_image)
-                    .map_err(|e| Error::BlockDeviceNew(e))?)
+                let mut block = devices::virtio::Block::new(raw_image, disk.cache_mode)
+                    .map_err(|e| Error::BlockDeviceNew(e))?;
+                block.set_counters(counters.clone());
+                block.set_merge_requests(disk.merge_requests);
+                if let Some(max_transfer) = disk.max_transfer {
+                    block.set_max_transfer(max_transfer);
+                }
+                if disk.async_io {
+                    block.set_async_io(ASYNC_DISK_IO_THREADS);
+                }
+                Box::new(block)
             }
             DiskType::Qcow => { // Valid qcow header present
                 let qcow_image = QcowFile::from(raw_image)
                     .map_err(|e| Error::QcowDeviceCreate(e))?;
-                Box::new(devices::virtio::Block::new(qcow_image)
-                    .map_err(|e| Error::BlockDeviceNew(e))?)
+                let mut block = devices::virtio::Block::new(qcow_image, disk.cache_mode)
+                    .map_err(|e| Error::BlockDeviceNew(e))?;
+                block.set_counters(counters.clone());
+                block.set_merge_requests(disk.merge_requests);
+                if let Some(max_transfer) = disk.max_transfer {
+                    block.set_max_transfer(max_transfer);
+                }
+                Box::new(block)
             }
         };
-        let jail = if cfg.multiprocess {
-            let policy_path: PathBuf = cfg.seccomp_policy_dir.join("block_device.policy");
-            Some(create_base_minijail(empty_root_path, &policy_path)?)
+        device_counters.push(counters);
+        let jail = if jail_device(cfg, "block") {
+            let policy_path = seccomp_policy_path(cfg, "block")?;
+            Some(create_base_minijail(pivot_root, &policy_path)?)
         }
         else {
             None
@@ -307,9 +567,9 @@ fn setup_mmio_bus(cfg: &Config,
     }
 
     let rng_box = Box::new(devices::virtio::Rng::new().map_err(Error::RngDeviceNew)?);
-    let rng_jail = if cfg.multiprocess {
-        let policy_path: PathBuf = cfg.seccomp_policy_dir.join("rng_device.policy");
-        Some(create_base_minijail(empty_root_path, &policy_path)?)
+    let rng_jail = if jail_device(cfg, "rng") {
+        let policy_path = seccomp_policy_path(cfg, "rng")?;
+        Some(create_base_minijail(pivot_root, &policy_path)?)
     } else {
         None
     };
@@ -317,57 +577,151 @@ fn setup_mmio_bus(cfg: &Config,
         .register_mmio(rng_box, rng_jail, cmdline)
         .map_err(Error::RegisterRng)?;
 
-    let balloon_box = Box::new(devices::virtio::Balloon::new(balloon_device_socket)
+    // Guest memory is backed by regular host pages.
+    let balloon_box = Box::new(devices::virtio::Balloon::new(balloon_device_socket,
+                                                             pagesize() as u64)
                                    .map_err(Error::BalloonDeviceNew)?);
-    let balloon_jail = if cfg.multiprocess {
-        let policy_path: PathBuf = cfg.seccomp_policy_dir.join("balloon_device.policy");
-        Some(create_base_minijail(empty_root_path, &policy_path)?)
+    let balloon_jail = if jail_device(cfg, "balloon") {
+        let policy_path = seccomp_policy_path(cfg, "balloon")?;
+        Some(create_base_minijail(pivot_root, &policy_path)?)
     } else {
         None
     };
     device_manager.register_mmio(balloon_box, balloon_jail, cmdline)
         .map_err(Error::RegisterBalloon)?;
 
-    // We checked above that if the IP is defined, then the netmask is, too.
-    if let Some(host_ip) = cfg.host_ip {
-        if let Some(netmask) = cfg.netmask {
-            if let Some(mac_address) = cfg.mac_address {
-                let net_box: Box<devices::virtio::VirtioDevice> = if cfg.vhost_net {
-                    Box::new(devices::virtio::vhost::Net::<Tap, vhost::Net<Tap>>::new(host_ip,
-                                                                                      netmask,
-                                                                                      mac_address,
-                                                                                      &mem)
-                                       .map_err(|e| Error::VhostNetDeviceNew(e))?)
-                } else {
-                    Box::new(devices::virtio::Net::<Tap>::new(host_ip, netmask, mac_address)
-                                       .map_err(|e| Error::NetDeviceNew(e))?)
-                };
+    if cfg.virtio_console_path.is_some() || !cfg.virtio_console_ports.is_empty() {
+        let mut keep_fds = Vec::new();
+        let mut open_port = |path: &PathBuf| -> Result<(File, File)> {
+            let output = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|e| Error::OpenConsole(path.clone(), e))?;
+            let input = output.try_clone()
+                .map_err(|e| Error::OpenConsole(path.clone(), e))?;
+            keep_fds.push(output.as_raw_fd());
+            keep_fds.push(input.as_raw_fd());
+            Ok((input, output))
+        };
 
-                let jail = if cfg.multiprocess {
-                    let policy_path: PathBuf = if cfg.vhost_net {
-                        cfg.seccomp_policy_dir.join("vhost_net_device.policy")
-                    } else {
-                        cfg.seccomp_policy_dir.join("net_device.policy")
-                    };
+        // Port 0 is always the console, even when only named ports were requested.
+        let mut ports = Vec::new();
+        let (cols, rows) = match cfg.virtio_console_path {
+            Some(ref console_path) => {
+                let (input, output) = open_port(console_path)?;
+                let size = console_size(&output);
+                ports.push(devices::virtio::ConsolePort {
+                               input: Some(Box::new(input)),
+                               output: Some(Box::new(output)),
+                               name: None,
+                           });
+                size
+            }
+            None => {
+                ports.push(devices::virtio::ConsolePort {
+                               input: None,
+                               output: None,
+                               name: None,
+                           });
+                (devices::virtio::DEFAULT_CONSOLE_COLS, devices::virtio::DEFAULT_CONSOLE_ROWS)
+            }
+        };
+        for &(ref name, ref path) in cfg.virtio_console_ports.iter() {
+            let (input, output) = open_port(path)?;
+            ports.push(devices::virtio::ConsolePort {
+                           input: Some(Box::new(input)),
+                           output: Some(Box::new(output)),
+                           name: Some(name.clone()),
+                       });
+        }
+        let console_box = Box::new(devices::virtio::Console::new(ports, keep_fds, cols, rows));
+        let console_jail = if jail_device(cfg, "console") {
+            let policy_path = seccomp_policy_path(cfg, "console")?;
+            Some(create_base_minijail(pivot_root, &policy_path)?)
+        } else {
+            None
+        };
+        device_manager.register_mmio(console_box, console_jail, cmdline)
+            .map_err(Error::RegisterConsole)?;
+    }
 
-                    Some(create_base_minijail(empty_root_path, &policy_path)?)
-                } else {
-                    None
-                };
+    for (config, socket) in cfg.virtio_input_devices.iter().zip(input_device_sockets) {
+        let input_box = Box::new(devices::virtio::Input::new(config.clone(), socket));
+        let input_jail = if jail_device(cfg, "input") {
+            let policy_path = seccomp_policy_path(cfg, "input")?;
+            Some(create_base_minijail(pivot_root, &policy_path)?)
+        } else {
+            None
+        };
+        device_manager.register_mmio(input_box, input_jail, cmdline)
+            .map_err(Error::RegisterInput)?;
+    }
 
-                device_manager
-                    .register_mmio(net_box, jail, cmdline)
-                    .map_err(Error::RegisterNet)?;
-            }
+    // We checked above that if the IP is defined, then the netmask is, too. A tap FD is exclusive
+    // with the IP and netmask.
+    if let Some(mac_address) = cfg.mac_address {
+        // The vhost device moves packets in the kernel, so only the userspace device is counted.
+        let counters = devices::virtio::DeviceCounters::new("net0")
+            .map_err(Error::CreateDeviceCounters)?;
+        let net_box: Option<Box<devices::virtio::VirtioDevice>> = if let Some(tap_fd) = cfg.tap_fd {
+            let tap_fd = claim_raw_fd(tap_fd)?;
+            // claim_raw_fd returns an FD that nothing else owns.
+            Some(if cfg.vhost_net {
+                Box::new(unsafe {
+                    devices::virtio::vhost::Net::<Tap, vhost::Net<Tap>>::from_tap_fd(tap_fd,
+                                                                                     mac_address,
+                                                                                     &mem)
+                }.map_err(|e| Error::VhostNetDeviceNew(e))?)
+            } else {
+                let mut net = unsafe {
+                    devices::virtio::Net::<Tap>::from_tap_fd(tap_fd, mac_address)
+                }.map_err(|e| Error::NetDeviceNew(e))?;
+                net.set_counters(counters.clone());
+                device_counters.push(counters);
+                Box::new(net)
+            })
+        } else if let (Some(host_ip), Some(netmask)) = (cfg.host_ip, cfg.netmask) {
+            Some(if cfg.vhost_net {
+                Box::new(devices::virtio::vhost::Net::<Tap, vhost::Net<Tap>>::new(host_ip,
+                                                                                  netmask,
+                                                                                  mac_address,
+                                                                                  &mem)
+                                   .map_err(|e| Error::VhostNetDeviceNew(e))?)
+            } else {
+                let mut net = devices::virtio::Net::<Tap>::new(host_ip, netmask, mac_address)
+                    .map_err(|e| Error::NetDeviceNew(e))?;
+                net.set_counters(counters.clone());
+                device_counters.push(counters);
+                Box::new(net)
+            })
+        } else {
+            None
+        };
+
+        if let Some(net_box) = net_box {
+            let net_device = if cfg.vhost_net { "vhost_net" } else { "net" };
+            let jail = if jail_device(cfg, net_device) {
+                let policy_path = seccomp_policy_path(cfg, net_device)?;
+
+                Some(create_base_minijail(pivot_root, &policy_path)?)
+            } else {
+                None
+            };
+
+            device_manager
+                .register_mmio(net_box, jail, cmdline)
+                .map_err(Error::RegisterNet)?;
         }
     }
 
     if let Some(wayland_socket_path) = cfg.wayland_socket_path.as_ref() {
         let jailed_wayland_path = Path::new("/wayland-0");
+        let jail_wl = jail_device(cfg, "wl");
 
         let (host_socket, device_socket) = UnixDatagram::pair().map_err(Error::CreateSocket)?;
         control_sockets.push(UnlinkUnixDatagram(host_socket));
-        let wl_box = Box::new(devices::virtio::Wl::new(if cfg.multiprocess {
+        let wl_box = Box::new(devices::virtio::Wl::new(if jail_wl {
                                                            &jailed_wayland_path
                                                        } else {
                                                            wayland_socket_path.as_path()
@@ -375,21 +729,21 @@ fn setup_mmio_bus(cfg: &Config,
                                                        device_socket)
                                       .map_err(Error::WaylandDeviceNew)?);
 
-        let jail = if cfg.multiprocess {
-            let policy_path: PathBuf = cfg.seccomp_policy_dir.join("wl_device.policy");
-            let mut jail = create_base_minijail(empty_root_path, &policy_path)?;
+        let jail = if jail_wl {
+            let policy_path = seccomp_policy_path(cfg, "wl")?;
+            let mut jail = create_base_minijail(pivot_root, &policy_path)?;
 
             // Create a tmpfs in the device's root directory so that we can bind mount the
             // wayland socket into it.  The size=67108864 is size=64*1024*1024 or size=64MB.
             jail.mount_with_data(Path::new("none"), Path::new("/"), "tmpfs",
                                  (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
                                  "size=67108864")
-                .unwrap();
+                .map_err(Error::DeviceJailMount)?;
 
             // Bind mount the wayland socket into jail's root. This is necessary since each
             // new wayland context must open() the socket.
             jail.mount_bind(wayland_socket_path.as_path(), jailed_wayland_path, true)
-                .unwrap();
+                .map_err(Error::DeviceJailMount)?;
 
             // Set the uid/gid for the jailed process, and give a basic id map. This
             // is required for the above bind mount to work.
@@ -424,35 +778,155 @@ fn setup_mmio_bus(cfg: &Config,
             .map_err(Error::RegisterWayland)?;
     }
 
-    if let Some(cid) = cfg.cid {
-        let vsock_box = Box::new(devices::virtio::vhost::Vsock::new(cid, &mem)
-                                     .map_err(Error::VhostVsockDeviceNew)?);
+    if let Some(&(ref shared_dir, ref tag)) = cfg.shared_dir.as_ref() {
+        let jailed_shared_dir = Path::new("/shared");
+        let jail_9p = jail_device(cfg, "9p");
+        let jail = if jail_9p {
+            let policy_path = seccomp_policy_path(cfg, "9p")?;
+            let mut jail = create_base_minijail(pivot_root, &policy_path)?;
 
-        let jail = if cfg.multiprocess {
-            let policy_path: PathBuf = cfg.seccomp_policy_dir.join("vhost_vsock_device.policy");
+            // Bind mount the shared directory into a tmpfs root. The device also checks that the
+            // guest stays under the directory, but the jail keeps the rest of the host out of reach
+            // even if that check is wrong.
+            jail.mount_with_data(Path::new("none"), Path::new("/"), "tmpfs",
+                                 (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                                 "size=67108864")
+                .map_err(Error::DeviceJailMount)?;
+            jail.mount_bind(shared_dir.as_path(), jailed_shared_dir, true)
+                .map_err(Error::DeviceJailMount)?;
+
+            // The files keep the owner they have on the host, so the current user is mapped into
+            // the jail to be able to write to them.
+            let uid = geteuid();
+            let gid = getegid();
+            jail.change_uid(uid);
+            jail.change_gid(gid);
+            jail.uidmap(&format!("{0} {0} 1", uid))
+                .map_err(Error::SettingUidMap)?;
+            jail.gidmap(&format!("{0} {0} 1", gid))
+                .map_err(Error::SettingGidMap)?;
 
-            Some(create_base_minijail(empty_root_path, &policy_path)?)
+            Some(jail)
         } else {
             None
         };
 
+        let p9_box = Box::new(devices::virtio::P9::new(if jail_9p {
+                                                           jailed_shared_dir
+                                                       } else {
+                                                           shared_dir.as_path()
+                                                       },
+                                                       tag));
         device_manager
-            .register_mmio(vsock_box, jail, cmdline)
-            .map_err(Error::RegisterVsock)?;
+            .register_mmio(p9_box, jail, cmdline)
+            .map_err(Error::Register9p)?;
+    }
+
+    if let Some(cid) = cfg.cid {
+        let (rx_queue_size, tx_queue_size) = cfg.vsock_queue_sizes.unwrap_or(
+            (devices::virtio::vhost::VSOCK_DEFAULT_QUEUE_SIZE,
+             devices::virtio::vhost::VSOCK_DEFAULT_QUEUE_SIZE));
+        match devices::virtio::vhost::Vsock::new(cid, &mem, rx_queue_size, tx_queue_size) {
+            Ok(vsock) => {
+                let jail = if jail_device(cfg, "vhost_vsock") {
+                    let policy_path = seccomp_policy_path(cfg, "vhost_vsock")?;
+
+                    Some(create_base_minijail(pivot_root, &policy_path)?)
+                } else {
+                    None
+                };
+
+                device_manager
+                    .register_mmio(Box::new(vsock), jail, cmdline)
+                    .map_err(Error::RegisterVsock)?;
+            }
+            Err(devices::virtio::vhost::Error::VhostOpen(e)) => {
+                warn!("failed to open vhost-vsock, using userspace virtual sockets: {:?}", e);
+                let socket_dir = cfg.vsock_socket_dir
+                    .clone()
+                    .unwrap_or(PathBuf::from(DEFAULT_VSOCK_SOCKET_DIR));
+                if !socket_dir.is_dir() {
+                    return Err(Error::NoVsockSocketDir(socket_dir));
+                }
+                let jailed_socket_dir = Path::new("/vsock");
+
+                let jail_vsock = jail_device(cfg, "vsock");
+                let jail = if jail_vsock {
+                    let policy_path = seccomp_policy_path(cfg, "vsock")?;
+                    let mut jail = create_base_minijail(pivot_root, &policy_path)?;
+
+                    // Bind mount the socket directory into a tmpfs root so the device can connect
+                    // to the host sockets in it.
+                    jail.mount_with_data(Path::new("none"), Path::new("/"), "tmpfs",
+                                         (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as
+                                         usize,
+                                         "size=67108864")
+                        .map_err(Error::DeviceJailMount)?;
+                    jail.mount_bind(socket_dir.as_path(), jailed_socket_dir, true)
+                        .map_err(Error::DeviceJailMount)?;
+
+                    // The bind mount requires the current user to be mapped into the jail.
+                    let uid = geteuid();
+                    let gid = getegid();
+                    jail.change_uid(uid);
+                    jail.change_gid(gid);
+                    jail.uidmap(&format!("{0} {0} 1", uid))
+                        .map_err(Error::SettingUidMap)?;
+                    jail.gidmap(&format!("{0} {0} 1", gid))
+                        .map_err(Error::SettingGidMap)?;
+
+                    Some(jail)
+                } else {
+                    None
+                };
+
+                let vsock_box = Box::new(devices::virtio::Vsock::new(cid,
+                                                                     if jail_vsock {
+                                                                         jailed_socket_dir
+                                                                     } else {
+                                                                         socket_dir.as_path()
+                                                                     },
+                                                                     rx_queue_size,
+                                                                     tx_queue_size));
+
+                device_manager
+                    .register_mmio(vsock_box, jail, cmdline)
+                    .map_err(Error::RegisterVsock)?;
+            }
+            Err(e) => return Err(Error::VhostVsockDeviceNew(e)),
+        }
     }
 
+    *acked_features = device_manager.acked_features;
+    *mmio_devices = device_manager.mmio_devices;
+    *device_pids = device_manager.device_pids;
     Ok(device_manager.bus)
 }
 
 
 fn setup_vcpu(kvm: &Kvm,
               vm: &Vm,
+              kernel_entry: GuestAddress,
               cpu_id: u32,
-              vcpu_count: u32)
+              vcpu_count: u32,
+              x2apic: bool,
+              kvmclock: bool,
+              tsc_khz: Option<u32>,
+              cpuid_overrides: &[CpuidOverride])
               -> Result<Vcpu> {
     let vcpu = Vcpu::new(cpu_id as libc::c_ulong, &kvm, &vm)
         .map_err(Error::CreateVcpu)?;
-    Arch::configure_vcpu(vm.get_memory(), &kvm, &vm, &vcpu, cpu_id as u64, vcpu_count as u64).
+    Arch::configure_vcpu(vm.get_memory(),
+                         kernel_entry,
+                         &kvm,
+                         &vm,
+                         &vcpu,
+                         cpu_id as u64,
+                         vcpu_count as u64,
+                         x2apic,
+                         kvmclock,
+                         tsc_khz,
+                         cpuid_overrides).
         map_err(Error::ConfigureVcpu)?;
     Ok(vcpu)
 }
@@ -468,17 +942,359 @@ fn setup_vcpu_signal_handler() -> Result<()> {
     Ok(())
 }
 
-fn run_vcpu(vcpu: Vcpu,
-            cpu_id: u32,
-            start_barrier: Arc<Barrier>,
-            io_bus: devices::Bus,
-            mmio_bus: devices::Bus,
-            exit_evt: EventFd,
-            kill_signaled: Arc<AtomicBool>) -> Result<JoinHandle<()>> {
+// A request serviced by a vcpu thread between runs, carrying the channel to send the result back on.
+enum VcpuRequest {
+    GetRegs(Sender<sys_util::Result<VcpuRegs>>),
+    SetRegs(VcpuRegs, Sender<sys_util::Result<()>>),
+    Translate(u64, Sender<sys_util::Result<u64>>),
+    Debug(VcpuDebugCommand, Sender<sys_util::Result<()>>),
+    SetBreakpoints(Vec<u64>, Sender<sys_util::Result<()>>),
+}
+
+// Debugging state of a vcpu, owned by its thread.
+#[derive(Default)]
+struct VcpuDebugState {
+    paused: bool,
+    single_step: bool,
+    breakpoints: Vec<u64>,
+}
+
+// Reports which vcpu stopped on a breakpoint or after a single step to the gdb stub.
+struct VcpuStopNotifier {
+    stopped: Sender<u32>,
+    stop_evt: EventFd,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn vcpu_regs(vcpu: &Vcpu) -> sys_util::Result<VcpuRegs> {
+    let regs = vcpu.get_regs()?;
+    let sregs = vcpu.get_sregs()?;
+    Ok(VcpuRegs::from_kvm(&regs, &sregs))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_vcpu_regs(vcpu: &Vcpu, r: VcpuRegs) -> sys_util::Result<()> {
+    let mut regs = vcpu.get_regs()?;
+    regs.rax = r.rax;
+    regs.rbx = r.rbx;
+    regs.rcx = r.rcx;
+    regs.rdx = r.rdx;
+    regs.rsi = r.rsi;
+    regs.rdi = r.rdi;
+    regs.rsp = r.rsp;
+    regs.rbp = r.rbp;
+    regs.r8 = r.r8;
+    regs.r9 = r.r9;
+    regs.r10 = r.r10;
+    regs.r11 = r.r11;
+    regs.r12 = r.r12;
+    regs.r13 = r.r13;
+    regs.r14 = r.r14;
+    regs.r15 = r.r15;
+    regs.rip = r.rip;
+    regs.rflags = r.rflags;
+    vcpu.set_regs(&regs)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn translate_vcpu_address(vcpu: &Vcpu, gva: u64) -> sys_util::Result<u64> {
+    let tr = vcpu.translate(gva)?;
+    if tr.valid == 0 {
+        return Err(sys_util::Error::new(libc::EFAULT));
+    }
+    Ok(tr.physical_address)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_vcpu_guest_debug(vcpu: &Vcpu, state: &VcpuDebugState) -> sys_util::Result<()> {
+    let mut dbg: kvm_sys::kvm_guest_debug = Default::default();
+    if state.single_step || !state.breakpoints.is_empty() {
+        dbg.control = kvm_sys::KVM_GUESTDBG_ENABLE;
+    }
+    if state.single_step {
+        dbg.control |= kvm_sys::KVM_GUESTDBG_SINGLESTEP;
+    }
+    if !state.breakpoints.is_empty() {
+        dbg.control |= kvm_sys::KVM_GUESTDBG_USE_HW_BP;
+        for (i, &addr) in state.breakpoints.iter().enumerate() {
+            dbg.arch.debugreg[i] = addr;
+            // Local enable of an execution breakpoint; the type and length bits stay 0.
+            dbg.arch.debugreg[7] |= 1 << (2 * i);
+        }
+    }
+    vcpu.set_guest_debug(&dbg)
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn vcpu_regs(_vcpu: &Vcpu) -> sys_util::Result<VcpuRegs> {
+    Err(sys_util::Error::new(libc::ENOTSUP))
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn set_vcpu_regs(_vcpu: &Vcpu, _regs: VcpuRegs) -> sys_util::Result<()> {
+    Err(sys_util::Error::new(libc::ENOTSUP))
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn translate_vcpu_address(_vcpu: &Vcpu, _gva: u64) -> sys_util::Result<u64> {
+    Err(sys_util::Error::new(libc::ENOTSUP))
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn set_vcpu_guest_debug(_vcpu: &Vcpu, state: &VcpuDebugState) -> sys_util::Result<()> {
+    if state.single_step || !state.breakpoints.is_empty() {
+        return Err(sys_util::Error::new(libc::ENOTSUP));
+    }
+    Ok(())
+}
+
+// Carries out `request` on the vcpu's thread.
+fn handle_vcpu_request(vcpu: &Vcpu, state: &mut VcpuDebugState, request: VcpuRequest) {
+    match request {
+        VcpuRequest::GetRegs(reply) => {
+            let _ = reply.send(vcpu_regs(vcpu));
+        }
+        VcpuRequest::SetRegs(regs, reply) => {
+            let _ = reply.send(set_vcpu_regs(vcpu, regs));
+        }
+        VcpuRequest::Translate(gva, reply) => {
+            let _ = reply.send(translate_vcpu_address(vcpu, gva));
+        }
+        VcpuRequest::Debug(command, reply) => {
+            state.paused = command == VcpuDebugCommand::Pause;
+            let single_step = command == VcpuDebugCommand::Step;
+            let res = if single_step != state.single_step {
+                state.single_step = single_step;
+                set_vcpu_guest_debug(vcpu, state)
+            } else {
+                Ok(())
+            };
+            let _ = reply.send(res);
+        }
+        VcpuRequest::SetBreakpoints(addrs, reply) => {
+            state.breakpoints = addrs;
+            let _ = reply.send(set_vcpu_guest_debug(vcpu, state));
+        }
+    }
+}
+
+// Carries out requests on vcpus by posting them to a vcpu thread and kicking the vcpu out of the
+// guest so that the thread services them between runs.
+struct VcpuRequester<'a> {
+    vcpu_handles: &'a [JoinHandle<()>],
+    requests: Vec<Sender<VcpuRequest>>,
+}
+
+impl<'a> VcpuRequester<'a> {
+    fn request<T, F>(&self, vcpu: u32, make_request: F) -> sys_util::Result<T>
+        where F: FnOnce(Sender<sys_util::Result<T>>) -> VcpuRequest
+    {
+        // Long enough for a vcpu busy with a slow device access to come back around.
+        const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+        let (handle, requests) = match (self.vcpu_handles.get(vcpu as usize),
+                                        self.requests.get(vcpu as usize)) {
+            (Some(h), Some(r)) => (h, r),
+            _ => return Err(sys_util::Error::new(libc::EINVAL)),
+        };
+        let (reply_send, reply_recv) = channel();
+        requests
+            .send(make_request(reply_send))
+            .map_err(|_| sys_util::Error::new(libc::ESRCH))?;
+        handle.kill(SIGRTMIN() + 0)?;
+        match reply_recv.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(sys_util::Error::new(libc::ETIMEDOUT)),
+            Err(RecvTimeoutError::Disconnected) => Err(sys_util::Error::new(libc::ESRCH)),
+        }
+    }
+}
+
+impl<'a> VcpuControl for VcpuRequester<'a> {
+    fn snapshot_regs(&self, vcpu: u32) -> sys_util::Result<VcpuRegs> {
+        self.request(vcpu, VcpuRequest::GetRegs)
+    }
+
+    fn set_regs(&self, vcpu: u32, regs: VcpuRegs) -> sys_util::Result<()> {
+        self.request(vcpu, |reply| VcpuRequest::SetRegs(regs, reply))
+    }
+
+    fn translate_address(&self, vcpu: u32, gva: u64) -> sys_util::Result<u64> {
+        self.request(vcpu, |reply| VcpuRequest::Translate(gva, reply))
+    }
+
+    fn debug(&self, vcpu: u32, command: VcpuDebugCommand) -> sys_util::Result<()> {
+        self.request(vcpu, |reply| VcpuRequest::Debug(command, reply))
+    }
+
+    fn set_breakpoints(&self, vcpu: u32, addrs: &[u64]) -> sys_util::Result<()> {
+        self.request(vcpu, |reply| VcpuRequest::SetBreakpoints(addrs.to_vec(), reply))
+    }
+}
+
+// The most times in a row that a failed vcpu run is retried before the vcpu gives up.
+const VCPU_RUN_MAX_RETRIES: u32 = 5;
+// The wait before the first retry, which doubles with each retry after it.
+const VCPU_RUN_RETRY_DELAY_MS: u64 = 10;
+
+// Returns how long to wait before running a vcpu again after the run failed with `errno`, having
+// already been retried `retries` times in a row, or `None` if the vcpu should stop. Only errors
+// that come from the host being short on resources for a moment are worth retrying.
+fn vcpu_run_retry_delay(errno: i32, retries: u32) -> Option<Duration> {
+    match errno {
+        libc::ENOMEM | libc::EBUSY if retries < VCPU_RUN_MAX_RETRIES => {
+            Some(Duration::from_millis(VCPU_RUN_RETRY_DELAY_MS << retries))
+        }
+        _ => None,
+    }
+}
+
+// Returns the CPU time the calling thread has used, including time spent running a vcpu in the
+// kernel but not time the vcpu was halted.
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because we give a valid clock and a pointer to a timespec we own, and check the result.
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    if ret != 0 {
+        return Duration::from_secs(0);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+// Caps the CPU time a vcpu thread may use in each window of wall-clock time.
+struct VcpuBudget {
+    budget: Duration,
+    window: Duration,
+    window_start: Instant,
+    used: Duration,
+}
+
+impl VcpuBudget {
+    // Allows `budget` of CPU time in every `window`, starting now.
+    fn new(budget: Duration, window: Duration) -> VcpuBudget {
+        VcpuBudget {
+            budget: budget,
+            window: window,
+            window_start: Instant::now(),
+            used: Duration::from_secs(0),
+        }
+    }
+
+    // Records that the vcpu used `cpu_time` since the last call, as of `now`. Returns how long the
+    // vcpu has to wait before running again if that used up the budget of the current window. A
+    // new window starts after the wait, or when the current one is over. Time used in excess of the
+    // budget is not carried over into the next window.
+    fn account(&mut self, cpu_time: Duration, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= self.window {
+            self.window_start = now;
+            self.used = cpu_time;
+            return None;
+        }
+        self.used += cpu_time;
+        if self.used < self.budget {
+            return None;
+        }
+        let wait = self.window - elapsed;
+        self.window_start = now + wait;
+        self.used = Duration::from_secs(0);
+        Some(wait)
+    }
+}
+
+// Fails if KVM can't create `requested` vcpus and warns if it is more than KVM recommends.
+fn check_vcpu_count(requested: u32, recommended: u32, max: u32) -> Result<()> {
+    if requested > max {
+        return Err(Error::TooManyVcpus {
+            requested: requested,
+            max: max,
+        });
+    }
+    if requested > recommended {
+        warn!("{} VCPUs requested, more than the {} KVM recommends", requested, recommended);
+    }
+    Ok(())
+}
+
+// Returns a bus trace callback that logs each access on the bus called `name`.
+fn bus_tracer(name: &'static str) -> Arc<devices::BusTraceFn> {
+    Arc::new(move |access: &devices::BusAccess| {
+        let device = match access.device_base {
+            Some(base) => format!("device at {:#x}", base),
+            None => "no device".to_owned(),
+        };
+        info!("{} {} {:#x} ({}): {:?}",
+              name,
+              if access.write { "write" } else { "read" },
+              access.addr,
+              device,
+              access.data);
+    })
+}
+
+/// The reason the VM stopped running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitReason {
+    /// A vcpu halted.
+    Halt,
+    /// A vcpu shut down, e.g. after a triple fault.
+    Shutdown,
+    /// The guest asked to be powered off.
+    PowerOff,
+    /// The guest asked to be reset.
+    Reset,
+    /// The guest reported that it crashed.
+    Crash,
+    /// A vcpu could not keep running.
+    VcpuError,
+    /// A device signalled the exit event without giving a reason.
+    Device,
+    /// A child process, such as a sandboxed device, died.
+    ChildDied,
+    /// crosvm received a signal asking it to exit.
+    Signal,
+    /// A control socket asked the VM to exit.
+    ControlRequest,
+    /// Waiting on the control loop's events failed.
+    PollError,
+}
+
+// Maps the type of a KVM_EXIT_SYSTEM_EVENT exit to the reason the VM stops.
+fn system_event_reason(event_type: u32) -> ExitReason {
+    match event_type {
+        kvm_sys::KVM_SYSTEM_EVENT_RESET => ExitReason::Reset,
+        kvm_sys::KVM_SYSTEM_EVENT_CRASH => ExitReason::Crash,
+        _ => ExitReason::PowerOff,
+    }
+}
+
+// Runs `vcpu` on a new thread, dispatching its exits to the buses, until it halts or the VM is
+// shutting down. Requests are carried out by `handle_request` between runs. When the vcpu stops
+// on its own, the reason is sent on `exit_reasons` before `exit_evt` is signalled.
+fn run_vcpu<V>(vcpu: V,
+               cpu_id: u32,
+               start_barrier: Arc<Barrier>,
+               io_bus: devices::Bus,
+               mmio_bus: devices::Bus,
+               exit_evt: EventFd,
+               exit_reasons: Sender<ExitReason>,
+               kill_signaled: Arc<AtomicBool>,
+               requests: Receiver<VcpuRequest>,
+               stop_notifier: Option<VcpuStopNotifier>,
+               mut budget: Option<VcpuBudget>,
+               handle_request: fn(&V, &mut VcpuDebugState, VcpuRequest))
+               -> Result<JoinHandle<()>>
+    where V: VcpuRunner + Send + 'static
+{
     thread::Builder::new()
         .name(format!("crosvm_vcpu{}", cpu_id))
         .spawn(move || {
             let mut sig_ok = true;
+            // Why the vcpu stopped, unless it was told to by the rest of the VM.
+            let mut exit_reason = None;
             match get_blocked_signals() {
                 Ok(mut v) => {
                     v.retain(|&x| x != SIGRTMIN() + 0);
@@ -501,10 +1317,16 @@ fn run_vcpu(vcpu: Vcpu,
 
             start_barrier.wait();
 
-            while sig_ok {
+            let mut debug_state = VcpuDebugState::default();
+            // Consecutive failed runs, and every failed run that was retried.
+            let mut retries = 0;
+            let mut total_retries = 0;
+            let mut cpu_time = thread_cpu_time();
+            'run: while sig_ok {
                 let run_res = vcpu.run();
                 match run_res {
                     Ok(run) => {
+                        retries = 0;
                         match run {
                             VcpuExit::IoIn(addr, data) => {
                                 io_bus.read(addr as u64, data);
@@ -518,20 +1340,48 @@ fn run_vcpu(vcpu: Vcpu,
                             VcpuExit::MmioWrite(addr, data) => {
                                 mmio_bus.write(addr, data);
                             }
-                            VcpuExit::Hlt => break,
-                            VcpuExit::Shutdown => break,
-                            VcpuExit::SystemEvent(_, _) =>
-                                //TODO handle reboot and crash events
-                                kill_signaled.store(true, Ordering::SeqCst),
+                            VcpuExit::Debug => {
+                                // Stay stopped on the breakpoint or step until told to resume.
+                                debug_state.paused = true;
+                                if let Some(ref notifier) = stop_notifier {
+                                    let _ = notifier.stopped.send(cpu_id);
+                                    let _ = notifier.stop_evt.write(1);
+                                }
+                            }
+                            VcpuExit::Hlt => {
+                                exit_reason = Some(ExitReason::Halt);
+                                break;
+                            }
+                            VcpuExit::Shutdown => {
+                                exit_reason = Some(ExitReason::Shutdown);
+                                break;
+                            }
+                            VcpuExit::SystemEvent(event_type, _) => {
+                                exit_reason = Some(system_event_reason(event_type));
+                                kill_signaled.store(true, Ordering::SeqCst);
+                            }
                             r => warn!("unexpected vcpu exit: {:?}", r),
                         }
                     }
                     Err(e) => {
                         match e.errno() {
                             libc::EAGAIN | libc::EINTR => {},
-                            _ => {
-                                error!("vcpu hit unknown error: {:?}", e);
-                                break;
+                            errno => {
+                                match vcpu_run_retry_delay(errno, retries) {
+                                    Some(delay) => {
+                                        warn!("vcpu {} run failed, retrying: {:?}", cpu_id, e);
+                                        retries += 1;
+                                        total_retries += 1;
+                                        thread::sleep(delay);
+                                    }
+                                    None => {
+                                        error!("vcpu hit unknown error after {} retries: {:?}",
+                                               total_retries,
+                                               e);
+                                        exit_reason = Some(ExitReason::VcpuError);
+                                        break;
+                                    }
+                                }
                             }
                         }
                     }
@@ -540,9 +1390,43 @@ fn run_vcpu(vcpu: Vcpu,
                     break;
                 }
 
+                if let Some(ref mut budget) = budget {
+                    let used = thread_cpu_time()
+                        .checked_sub(cpu_time)
+                        .unwrap_or(Duration::from_secs(0));
+                    if let Some(wait) = budget.account(used, Instant::now()) {
+                        thread::sleep(wait);
+                    }
+                    cpu_time = thread_cpu_time();
+                }
+
                 // Try to clear the signal that we use to kick VCPU if it is
                 // pending before attempting to handle pause requests.
                 clear_signal(SIGRTMIN() + 0).expect("failed to clear pending signal");
+
+                // Requests are posted before the kick, so one that raced with clearing the signal
+                // above is still seen here.
+                while let Ok(request) = requests.try_recv() {
+                    handle_request(&vcpu, &mut debug_state, request);
+                }
+                // A paused vcpu only services requests until it is resumed, or until the requests
+                // channel is closed on shutdown.
+                while debug_state.paused {
+                    match requests.recv() {
+                        Ok(request) => handle_request(&vcpu, &mut debug_state, request),
+                        Err(_) => break 'run,
+                    }
+                    if kill_signaled.load(Ordering::SeqCst) {
+                        break 'run;
+                    }
+                }
+            }
+            if !sig_ok {
+                exit_reason = Some(ExitReason::VcpuError);
+            }
+            if let Some(reason) = exit_reason {
+                // The control loop may already be gone if the VM is shutting down anyway.
+                let _ = exit_reasons.send(reason);
             }
             exit_evt
                 .write(1)
@@ -559,26 +1443,43 @@ struct GpuBufferDevice {
 #[cfg(feature = "wl-dmabuf")]
 impl GpuMemoryAllocator for GpuBufferDevice {
     fn allocate(&self, width: u32, height: u32, format: u32) -> sys_util::Result<(File, u32)> {
+        // Linear layout is a requirement as virtio wayland guest expects
+        // this for CPU access to the buffer. Scanout and texturing are
+        // optional as the consumer (wayland compositor) is expected to
+        // fall-back to a less efficient meachnisms for presentation if
+        // neccesary. In practice, linear buffers for commonly used formats
+        // will also support scanout and texturing.
+        let flags = gpu_buffer::Flags::empty().use_linear(true);
+        // The format comes from the guest, so turn away ones the device can't do before asking it
+        // to create a buffer.
+        if !self.device.is_format_supported(gpu_buffer::Format::from(format), flags) {
+            return Err(sys_util::Error::new(libc::ENOTSUP));
+        }
         let buffer = match self.device.create_buffer(
             width,
             height,
             gpu_buffer::Format::from(format),
-            // Linear layout is a requirement as virtio wayland guest expects
-            // this for CPU access to the buffer. Scanout and texturing are
-            // optional as the consumer (wayland compositor) is expected to
-            // fall-back to a less efficient meachnisms for presentation if
-            // neccesary. In practice, linear buffers for commonly used formats
-            // will also support scanout and texturing.
-            gpu_buffer::Flags::empty().use_linear(true)) {
+            flags) {
             Ok(v) => v,
-            Err(_) => return Err(sys_util::Error::new(EINVAL)),
+            Err(e) => {
+                warn!("failed to allocate {}x{} {:?} buffer: {}",
+                      width,
+                      height,
+                      gpu_buffer::Format::from(format),
+                      e);
+                return Err(sys_util::Error::new(EINVAL));
+            }
         };
         // We only support the first plane. Buffers with more planes are not
         // a problem but additional planes will not be registered for access
         // from guest.
         let fd = match buffer.export_plane_fd(0) {
             Ok(v) => v,
-            Err(e) => return Err(sys_util::Error::new(e)),
+            Err(gpu_buffer::Error::Export(errno)) => return Err(sys_util::Error::new(errno)),
+            Err(e) => {
+                warn!("failed to export buffer: {}", e);
+                return Err(sys_util::Error::new(EINVAL));
+            }
         };
 
         Ok((fd, buffer.stride()))
@@ -588,31 +1489,75 @@ impl GpuMemoryAllocator for GpuBufferDevice {
 #[cfg(feature = "wl-dmabuf")]
 fn create_gpu_memory_allocator() -> Result<Option<Box<GpuMemoryAllocator>>> {
     let undesired: &[&str] = &["vgem"];
-    let fd = gpu_buffer::rendernode::open_device(undesired)
-        .map_err(|_| Error::OpenGpuBufferDevice)?;
+    let fd = match gpu_buffer::rendernode::open_device(undesired) {
+        Ok(fd) => fd,
+        Err(_) => {
+            warn!("no render node for DMABuf allocations, falling back to shared memory");
+            return Ok(Some(Box::new(SharedMemoryAllocator)));
+        }
+    };
     let device = gpu_buffer::Device::new(fd)
-        .map_err(|_| Error::CreateGpuBufferDevice)?;
+        .map_err(Error::CreateGpuBufferDevice)?;
     info!("created GPU buffer device for DMABuf allocations");
     Ok(Some(Box::new(GpuBufferDevice { device })))
 }
 
 #[cfg(not(feature = "wl-dmabuf"))]
 fn create_gpu_memory_allocator() -> Result<Option<Box<GpuMemoryAllocator>>> {
-    Ok(None)
+    Ok(Some(Box::new(SharedMemoryAllocator)))
+}
+
+// Reads a pending signal from one of the signalfds of the signals that stop the VM. Returns true
+// if there was one, in which case the VM should shut down.
+fn read_exit_signal(signal_fd: &SignalFd) -> Result<bool> {
+    match signal_fd.read().map_err(Error::SignalFd)? {
+        Some(siginfo) => {
+            info!("received signal {}, shutting down", siginfo.ssi_signo);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Whether the guest can't do without a virtio device of `device_type`. The guest keeps running if
+// the process of a device it can do without dies.
+fn device_is_critical(device_type: u32) -> bool {
+    match device_type {
+        devices::virtio::TYPE_RNG | devices::virtio::TYPE_BALLOON => false,
+        _ => true,
+    }
+}
+
+// Whether the VM has to exit now that child `pid` died. `device_pids` holds the process id and
+// device type of each jailed device. Children that aren't devices are always needed.
+fn child_death_is_fatal(pid: pid_t, device_pids: &[(pid_t, u32)]) -> bool {
+    match device_pids.iter().find(|&&(device_pid, _)| device_pid == pid) {
+        Some(&(_, device_type)) => device_is_critical(device_type),
+        None => true,
+    }
 }
 
 fn run_control(vm: &mut Vm,
                control_sockets: Vec<UnlinkUnixDatagram>,
-               next_dev_pfn: &mut u64,
+               pfn_allocator: &mut DevicePfnAllocator,
                stdio_serial: Arc<Mutex<devices::Serial>>,
                exit_evt: EventFd,
+               exit_reasons: Receiver<ExitReason>,
                sigchld_fd: SignalFd,
+               device_pids: Vec<(pid_t, u32)>,
+               exit_signal_fds: Vec<SignalFd>,
                kill_signaled: Arc<AtomicBool>,
                vcpu_handles: Vec<JoinHandle<()>>,
+               vcpu_requests: Vec<Sender<VcpuRequest>>,
                balloon_host_socket: UnixDatagram,
+               input_host_sockets: Vec<UnixDatagram>,
+               device_counters: Vec<devices::virtio::DeviceCounters>,
+               acked_features: Vec<devices::virtio::AckedFeatures>,
                _irqchip_fd: Option<File>,
-               gpu_memory_allocator: Option<Box<GpuMemoryAllocator>>)
-               -> Result<()> {
+               gpu_memory_allocator: Option<Box<GpuMemoryAllocator>>,
+               io_bus: devices::Bus,
+               mmio_bus: devices::Bus)
+               -> Result<ExitReason> {
     const MAX_VM_FD_RECV: usize = 1;
 
     #[derive(PollToken)]
@@ -620,6 +1565,7 @@ fn run_control(vm: &mut Vm,
         Exit,
         Stdin,
         ChildSignal,
+        ExitSignal { index: usize },
         VmControl { index: usize },
     }
 
@@ -635,31 +1581,73 @@ fn run_control(vm: &mut Vm,
         warn!("failed to add stdin to poll context: {:?}", e);
     }
     poll_ctx.add(&sigchld_fd, Token::ChildSignal).map_err(Error::PollContextAdd)?;
+    for (index, signal_fd) in exit_signal_fds.iter().enumerate() {
+        poll_ctx.add(signal_fd, Token::ExitSignal { index: index }).map_err(Error::PollContextAdd)?;
+    }
     for (index, socket) in control_sockets.iter().enumerate() {
         poll_ctx.add(socket.as_ref(), Token::VmControl{ index }).map_err(Error::PollContextAdd)?;
     }
 
+    // How often to check if the guest made room for more serial input while stdin isn't polled.
+    const STDIN_RETRY_MS: u64 = 20;
+
     let mut scm = Scm::new(MAX_VM_FD_RECV);
+    let vcpu_requester = VcpuRequester {
+        vcpu_handles: &vcpu_handles,
+        requests: vcpu_requests,
+    };
+    // Set while stdin is left unread because the serial input buffer is full.
+    let mut stdin_paused = false;
 
+    let exit_reason;
     'poll: loop {
         let events = {
-            match poll_ctx.wait() {
+            let events = if stdin_paused {
+                poll_ctx.wait_timeout(Duration::from_millis(STDIN_RETRY_MS))
+            } else {
+                poll_ctx.wait()
+            };
+            match events {
                 Ok(v) => v,
                 Err(e) => {
                     error!("failed to poll: {:?}", e);
+                    exit_reason = ExitReason::PollError;
                     break;
                 }
             }
         };
+        if stdin_paused && stdio_serial.lock().unwrap().input_space() > 0 {
+            if let Err(e) = poll_ctx.add(&stdin_handle, Token::Stdin) {
+                warn!("failed to add stdin to poll context: {:?}", e);
+            }
+            stdin_paused = false;
+        }
         for event in events.iter_readable() {
             match event.token() {
                 Token::Exit => {
-                    info!("vcpu requested shutdown");
+                    // Only the first reason matters, the rest are from vcpus stopping after it.
+                    exit_reason = exit_reasons.try_recv().unwrap_or(ExitReason::Device);
+                    if exit_reason == ExitReason::Reset {
+                        // Reset the devices on both buses as a reboot would. The virtio devices
+                        // can't give back the queues their workers are using, so the guest can't be
+                        // booted again in place and the VM still exits.
+                        io_bus.reset_all();
+                        mmio_bus.reset_all();
+                    }
+                    info!("vcpu requested shutdown: {:?}", exit_reason);
                     break 'poll;
                 }
                 Token::Stdin => {
+                    let space = stdio_serial.lock().unwrap().input_space();
+                    if space == 0 {
+                        // The guest isn't keeping up. Leave the input in stdin until it is.
+                        let _ = poll_ctx.delete(&stdin_handle);
+                        stdin_paused = true;
+                        continue;
+                    }
                     let mut out = [0u8; 64];
-                    match stdin_lock.read_raw(&mut out[..]) {
+                    let len = std::cmp::min(out.len(), space);
+                    match stdin_lock.read_raw(&mut out[..len]) {
                         Ok(0) => {
                             // Zero-length read indicates EOF. Remove from pollables.
                             let _ = poll_ctx.delete(&stdin_handle);
@@ -678,37 +1666,91 @@ fn run_control(vm: &mut Vm,
                     }
                 }
                 Token::ChildSignal => {
-                    // Print all available siginfo structs, then exit the loop.
+                    // Print all available siginfo structs.
+                    while let Some(siginfo) = sigchld_fd.read().map_err(Error::SignalFd)? {
+                        error!("child {} died: signo {}, status {}, code {}",
+                               siginfo.ssi_pid,
+                               siginfo.ssi_signo,
+                               siginfo.ssi_status,
+                               siginfo.ssi_code);
+                    }
+                    // Pending SIGCHLDs are coalesced, so a siginfo doesn't name every child that
+                    // died. Reap all of them instead and exit the loop if any of the children
+                    // was needed to keep running the VM.
+                    let mut fatal = false;
                     loop {
-                        let result = sigchld_fd.read().map_err(Error::SignalFd)?;
-                        if let Some(siginfo) = result {
-                            error!("child {} died: signo {}, status {}, code {}",
-                                   siginfo.ssi_pid,
-                                   siginfo.ssi_signo,
-                                   siginfo.ssi_status,
-                                   siginfo.ssi_code);
+                        match reap_child() {
+                            Ok(0) => break,
+                            Ok(pid) => {
+                                if child_death_is_fatal(pid, &device_pids) {
+                                    fatal = true;
+                                } else {
+                                    warn!("continuing without the device run by child {}", pid);
+                                }
+                            }
+                            Err(e) => {
+                                if e.errno() != libc::ECHILD {
+                                    warn!("error while reaping children: {:?}", e);
+                                }
+                                break;
+                            }
                         }
+                    }
+                    if fatal {
+                        exit_reason = ExitReason::ChildDied;
                         break 'poll;
                     }
                 }
+                Token::ExitSignal { index } => {
+                    if let Some(signal_fd) = exit_signal_fds.get(index) {
+                        if read_exit_signal(signal_fd)? {
+                            exit_reason = ExitReason::Signal;
+                            break 'poll;
+                        }
+                    }
+                }
                 Token::VmControl { index } => {
                     if let Some(socket) = control_sockets.get(index as usize) {
                         match VmRequest::recv(&mut scm, socket.as_ref()) {
                             Ok(request) => {
                                 let mut running = true;
-                                let response =
-                                    request.execute(vm,
-                                                    next_dev_pfn,
-                                                    &mut running,
-                                                    &balloon_host_socket,
-                                                    gpu_memory_allocator.as_ref().map(|v| {
-                                                                                          v.as_ref()
-                                                                                      }));
+                                let response = match request {
+                                    VmRequest::DeviceStats => {
+                                        VmResponse::DeviceStats(device_counters
+                                                                    .iter()
+                                                                    .map(|c| c.snapshot())
+                                                                    .collect())
+                                    }
+                                    VmRequest::VirtioFeatures => {
+                                        let features = acked_features
+                                            .iter()
+                                            .map(|f| {
+                                                     VirtioFeatures {
+                                                         device_type: f.device_type(),
+                                                         acked: f.get(),
+                                                     }
+                                                 })
+                                            .collect();
+                                        VmResponse::VirtioFeatures(features)
+                                    }
+                                    _ => {
+                                        request.execute(vm,
+                                                        pfn_allocator,
+                                                        &mut running,
+                                                        &balloon_host_socket,
+                                                        &input_host_sockets,
+                                                        gpu_memory_allocator
+                                                            .as_ref()
+                                                            .map(|v| v.as_ref()),
+                                                        &vcpu_requester)
+                                    }
+                                };
                                 if let Err(e) = response.send(&mut scm, socket.as_ref()) {
                                     error!("failed to send VmResponse: {:?}", e);
                                 }
                                 if !running {
                                     info!("control socket requested exit");
+                                    exit_reason = ExitReason::ControlRequest;
                                     break 'poll;
                                 }
                             }
@@ -729,6 +1771,7 @@ fn run_control(vm: &mut Vm,
                         let _ = poll_ctx.delete(&stdin_handle);
                     },
                     Token::ChildSignal => {},
+                    Token::ExitSignal { .. } => {},
                     Token::VmControl { index } => {
                         if let Some(socket) = control_sockets.get(index as usize) {
                             let _ = poll_ctx.delete(socket.as_ref());
@@ -742,6 +1785,8 @@ fn run_control(vm: &mut Vm,
     // vcpu threads MUST see the kill signaled flag, otherwise they may
     // re-enter the VM.
     kill_signaled.store(true, Ordering::SeqCst);
+    // Closing the request channels wakes up any vcpus paused by a debugger.
+    drop(vcpu_requester);
     for handle in vcpu_handles {
         match handle.kill(SIGRTMIN() + 0) {
             Ok(_) => {
@@ -757,10 +1802,20 @@ fn run_control(vm: &mut Vm,
         .set_canon_mode()
         .expect("failed to restore canonical mode for terminal");
 
-    Ok(())
+    Ok(exit_reason)
 }
 
-pub fn run_config(cfg: Config) -> Result<()> {
+// Size of the guest physical address space that device memory, such as buffers shared with the
+// wayland device, is mapped into.
+const DEVICE_MEMORY_SIZE: u64 = 1 << 30;
+// MiB of guest physical address space reserved for 64-bit PCI BARs unless configured otherwise.
+const DEFAULT_HIGH_MMIO_SIZE_MB: u64 = 4096;
+
+pub fn run_config(cfg: Config) -> Result<ExitReason> {
+    if let Some(level) = cfg.log_level {
+        syslog::set_max_priority(level);
+    }
+
     if cfg.multiprocess {
         // Printing something to the syslog before entering minijail so that libc's syslogger has a
         // chance to open files necessary for its operation, like `/etc/localtime`. After jailing,
@@ -773,6 +1828,12 @@ pub fn run_config(cfg: Config) -> Result<()> {
     // before any jailed devices have been spawned, so that we can catch any of them that fail very
     // quickly.
     let sigchld_fd = SignalFd::new(libc::SIGCHLD).map_err(Error::CreateSignalFd)?;
+    // SIGTERM and SIGINT take the same way out as an exit request, so the terminal is restored
+    // and the vcpus are joined.
+    let mut exit_signal_fds = Vec::new();
+    for &signal in &[libc::SIGTERM, libc::SIGINT] {
+        exit_signal_fds.push(SignalFd::new(signal).map_err(Error::CreateSignalFd)?);
+    }
 
     let mut control_sockets = Vec::new();
     if let Some(ref path) = cfg.socket_path {
@@ -783,39 +1844,161 @@ pub fn run_config(cfg: Config) -> Result<()> {
 
     let kill_signaled = Arc::new(AtomicBool::new(false));
     let exit_evt = EventFd::new().map_err(Error::CreateEventFd)?;
+    let (exit_reason_send, exit_reason_recv) = channel();
 
     let mem_size = cfg.memory.unwrap_or(256) << 20;
     let mem = Arch::setup_memory(mem_size as u64).map_err(|e| Error::CreateGuestMemory(e))?;
+    if !cfg.numa_nodes.is_empty() {
+        let mut regions = Vec::new();
+        let _ = mem.with_regions_mut::<_, ()>(|_, base, size, _| {
+            regions.push((base, size as u64));
+            Ok(())
+        });
+        for (addr, size, node) in arch::numa_node_ranges(&regions, &cfg.numa_nodes) {
+            mem.bind_to_numa_node(addr, size, node)
+                .map_err(|e| Error::BindGuestMemory(node, e))?;
+        }
+    }
+    if cfg.lock_guest_memory {
+        mem.prefault_and_lock().map_err(Error::LockGuestMemory)?;
+    }
     let kvm = Kvm::new().map_err(Error::CreateKvm)?;
-    let mut vm = Arch::create_vm(&kvm, mem.clone()).map_err(|e| Error::CreateVm(e))?;
+    let mut vm = Arch::create_vm(&kvm, mem.clone(), cfg.split_irqchip, !cfg.no_pit).map_err(|e| Error::CreateVm(e))?;
+
+    let mut kernel_image = File::open(cfg.kernel_path.as_path())
+        .map_err(|e| Error::OpenKernel(cfg.kernel_path.clone(), e))?;
+
+    // separate out load_kernel from other setup to get a specific error for
+    // kernel loading
+    let kernel = Arch::load_kernel(&mem, &mut kernel_image)
+        .map_err(|e| Error::LoadKernel(e))?;
+    let initrd = match cfg.initrd_path {
+        Some(ref initrd_path) => {
+            let mut initrd_image = File::open(initrd_path)
+                .map_err(|e| Error::OpenInitrd(initrd_path.clone(), e))?;
+            Some(Arch::load_initrd(&mem, kernel.end, &mut initrd_image)
+                     .map_err(Error::LoadInitrd)?)
+        }
+        None => None,
+    };
 
     let vcpu_count = cfg.vcpu_count.unwrap_or(1);
+    check_vcpu_count(vcpu_count, kvm.get_nr_vcpus(), kvm.get_max_vcpus())?;
     let mut vcpu_handles = Vec::with_capacity(vcpu_count as usize);
     let vcpu_thread_barrier = Arc::new(Barrier::new((vcpu_count + 1) as usize));
     let mut vcpus = Vec::with_capacity(vcpu_count as usize);
     for cpu_id in 0..vcpu_count {
-        let vcpu = setup_vcpu(&kvm, &vm, cpu_id, vcpu_count)?;
+        let vcpu = setup_vcpu(&kvm,
+                              &vm,
+                              kernel.entry,
+                              cpu_id,
+                              vcpu_count,
+                              cfg.x2apic,
+                              // kvmclock always follows the host's time, so a guest that used it
+                              // would ignore the RTC offset.
+                              cfg.rtc_offset == 0,
+                              cfg.tsc_khz,
+                              &cfg.cpuid_overrides)?;
         vcpus.push(vcpu);
     }
 
     let irq_chip = Arch::create_irq_chip(&vm).map_err(|e| Error::CreateIrqChip(e))?;
     let mut cmdline = Arch::get_base_linux_cmdline();
-    let mut next_dev_pfn = Arch::get_base_dev_pfn(mem_size as u64);
-    let (io_bus, stdio_serial) = Arch::setup_io_bus(&mut vm,
-                                                    exit_evt.try_clone().
-                                                    map_err(Error::CloneEventFd)?).
+    if cfg.no_pit {
+        // Without a PIT the IOAPIC timer check at boot would never see the timer tick.
+        cmdline.insert_str("no_timer_check").map_err(Error::Cmdline)?;
+    }
+    let base_dev_pfn = Arch::get_base_dev_pfn(mem_size as u64);
+    let mut pfn_allocator = DevicePfnAllocator::new(base_dev_pfn,
+                                                    DEVICE_MEMORY_SIZE / pagesize() as u64);
+    let high_mmio = match cfg.high_mmio_size.unwrap_or(DEFAULT_HIGH_MMIO_SIZE_MB) {
+        0 => None,
+        size_mb => {
+            let dev_mem_end = base_dev_pfn * pagesize() as u64 + DEVICE_MEMORY_SIZE;
+            // The size was checked to fit in bytes when the argument was parsed.
+            Arch::get_high_mmio_region(dev_mem_end, size_mb * (1 << 20))
+                .map_err(Error::HighMmioRegion)?
+        }
+    };
+    // The guest's RTC starts out at the host's wall-clock time, shifted by the configured offset.
+    let host_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let mut serial_outputs: Vec<(u8, Box<io::Write + Send>)> = Vec::new();
+    for &(num, ref path) in cfg.serial_outputs.iter() {
+        let file = File::create(path).map_err(|e| Error::OpenSerialOutput(path.clone(), e))?;
+        serial_outputs.push((num, Box::new(file)));
+        cmdline.insert_str(&format!("console=ttyS{}", num)).map_err(Error::Cmdline)?;
+    }
+    // The last console given becomes /dev/console.
+    match cfg.console {
+        Some(GuestConsole::Serial(port)) => {
+            if let Some(earlycon) = Arch::serial_earlycon(port) {
+                cmdline.insert("earlycon", &earlycon).map_err(Error::Cmdline)?;
+            }
+            cmdline.insert_str(&format!("console=ttyS{}", port)).map_err(Error::Cmdline)?;
+        }
+        Some(GuestConsole::Virtio) => cmdline.insert_str("console=hvc0").map_err(Error::Cmdline)?,
+        Some(GuestConsole::None) => {}
+        None => cmdline.insert_str("console=ttyS0").map_err(Error::Cmdline)?,
+    }
+    if cfg.discard_serial {
+        serial_outputs.push((0, Box::new(io::sink())));
+    } else if let Some(ref path) = cfg.serial_log_path {
+        let file = File::create(path).map_err(|e| Error::OpenSerialOutput(path.clone(), e))?;
+        serial_outputs.push((0, Box::new(devices::TimestampWriter::new(file))));
+    }
+    let (mut io_bus, stdio_serial) = Arch::setup_io_bus(&mut vm,
+                                                        exit_evt.try_clone().
+                                                        map_err(Error::CloneEventFd)?,
+                                                        host_time + cfg.rtc_offset,
+                                                        serial_outputs,
+                                                        cfg.watchdog).
         map_err(|e| Error::SetupIoBus(e))?;
 
+    // An empty directory for jailed device's pivot root. It must outlive the device processes.
+    let pivot_root = if cfg.multiprocess {
+        create_pivot_root(&cfg.pivot_root)?
+    } else {
+        // Nothing is jailed, so nothing pivots into it.
+        PivotRoot::Configured(cfg.pivot_root.clone())
+    };
+
     let (balloon_host_socket, balloon_device_socket) = UnixDatagram::pair()
         .map_err(Error::CreateSocket)?;
-    let mmio_bus = setup_mmio_bus(&cfg,
-                                  &mut vm,
-                                  &mem,
-                                  &mut cmdline,
-                                  &mut control_sockets,
-                                  balloon_device_socket)?;
-
-    let gpu_memory_allocator = if cfg.wayland_dmabuf {
+    let mut input_host_sockets = Vec::new();
+    let mut input_device_sockets = Vec::new();
+    for _ in 0..cfg.virtio_input_devices.len() {
+        let (host_socket, device_socket) = UnixDatagram::pair().map_err(Error::CreateSocket)?;
+        input_host_sockets.push(host_socket);
+        input_device_sockets.push(device_socket);
+    }
+    let mut device_counters = Vec::new();
+    let mut acked_features = Vec::new();
+    let mut mmio_devices = Vec::new();
+    let mut device_pids = Vec::new();
+    let mut mmio_bus = setup_mmio_bus(&cfg,
+                                      &mut vm,
+                                      &mem,
+                                      stdio_serial.clone(),
+                                      &mut cmdline,
+                                      &mut control_sockets,
+                                      balloon_device_socket,
+                                      input_device_sockets,
+                                      &mut device_counters,
+                                      &mut acked_features,
+                                      &mut mmio_devices,
+                                      &mut device_pids,
+                                      &pivot_root)?;
+    // Both buses are cloned for each vcpu below, so tracing has to be set up before that.
+    if cfg.trace_bus {
+        io_bus.set_trace(Some(bus_tracer("io")));
+        mmio_bus.set_trace(Some(bus_tracer("mmio")));
+    }
+
+    // Without DMABuf support there is no render node to pick, so shared memory is always used.
+    let gpu_memory_allocator = if cfg.wayland_dmabuf || cfg!(not(feature = "wl-dmabuf")) {
         create_gpu_memory_allocator()?
     } else {
         None
@@ -825,38 +2008,508 @@ pub fn run_config(cfg: Config) -> Result<()> {
         cmdline.insert_str(&param).map_err(Error::Cmdline)?;
     }
 
-    let mut kernel_image = File::open(cfg.kernel_path.as_path())
-        .map_err(|e| Error::OpenKernel(cfg.kernel_path.clone(), e))?;
-
-    // separate out load_kernel from other setup to get a specific error for
-    // kernel loading
-    Arch::load_kernel(&mem, &mut kernel_image).map_err(|e| Error::LoadKernel(e))?;
     Arch::setup_system_memory(&mem, mem_size as u64, vcpu_count,
-                              &CString::new(cmdline).unwrap()).
+                              &CString::new(cmdline).unwrap(),
+                              kernel.setup_header.as_ref().map(|h| &h[..]), initrd,
+                              &mmio_devices, high_mmio).
         map_err(|e| Error::SetupSystemMemory(e))?;
 
+    // The gdb stub is a client of the control loop that is also told when vcpus stop.
+    let gdb_stop = match cfg.gdb {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port)).map_err(Error::BindGdbSocket)?;
+            let (host_socket, stub_socket) = UnixDatagram::pair().map_err(Error::CreateSocket)?;
+            control_sockets.push(UnlinkUnixDatagram(host_socket));
+            let (stop_send, stop_recv) = channel();
+            let stop_evt = EventFd::new().map_err(Error::CreateEventFd)?;
+            gdb::start_gdb_stub(listener,
+                                stub_socket,
+                                vcpu_count,
+                                stop_recv,
+                                stop_evt.try_clone().map_err(Error::CloneEventFd)?)
+                .map_err(Error::SpawnGdbStub)?;
+            Some((stop_send, stop_evt))
+        }
+        None => None,
+    };
+
     setup_vcpu_signal_handler()?;
+    let mut vcpu_requests = Vec::with_capacity(vcpu_count as usize);
     for (cpu_id, vcpu) in vcpus.into_iter().enumerate() {
+        let (request_send, request_recv) = channel();
+        vcpu_requests.push(request_send);
+        let stop_notifier = match gdb_stop {
+            Some((ref stopped, ref stop_evt)) => {
+                Some(VcpuStopNotifier {
+                         stopped: stopped.clone(),
+                         stop_evt: stop_evt.try_clone().map_err(Error::CloneEventFd)?,
+                     })
+            }
+            None => None,
+        };
         let handle = run_vcpu(vcpu,
                               cpu_id as u32,
                               vcpu_thread_barrier.clone(),
                               io_bus.clone(),
                               mmio_bus.clone(),
                               exit_evt.try_clone().map_err(Error::CloneEventFd)?,
-                              kill_signaled.clone())?;
+                              exit_reason_send.clone(),
+                              kill_signaled.clone(),
+                              request_recv,
+                              stop_notifier,
+                              cfg.vcpu_budget
+                                  .map(|(budget, window)| VcpuBudget::new(budget, window)),
+                              handle_vcpu_request)?;
         vcpu_handles.push(handle);
     }
     vcpu_thread_barrier.wait();
 
     run_control(&mut vm,
                 control_sockets,
-                &mut next_dev_pfn,
+                &mut pfn_allocator,
                 stdio_serial,
                 exit_evt,
+                exit_reason_recv,
                 sigchld_fd,
+                device_pids,
+                exit_signal_fds,
                 kill_signaled,
                 vcpu_handles,
+                vcpu_requests,
                 balloon_host_socket,
+                input_host_sockets,
+                device_counters,
+                acked_features,
                 irq_chip,
-                gpu_memory_allocator)
+                gpu_memory_allocator,
+                io_bus,
+                mmio_bus)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::UnsafeCell;
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[test]
+    fn vcpu_run_retry() {
+        for &errno in &[libc::ENOMEM, libc::EBUSY] {
+            let delays: Vec<Duration> = (0..VCPU_RUN_MAX_RETRIES)
+                .map(|retries| vcpu_run_retry_delay(errno, retries).unwrap())
+                .collect();
+            assert_eq!(delays[0], Duration::from_millis(VCPU_RUN_RETRY_DELAY_MS));
+            assert!(delays.windows(2).all(|w| w[1] == w[0] * 2));
+            assert_eq!(vcpu_run_retry_delay(errno, VCPU_RUN_MAX_RETRIES), None);
+        }
+        for &errno in &[libc::EFAULT, libc::EINVAL, libc::ENOEXEC, libc::ENXIO] {
+            assert_eq!(vcpu_run_retry_delay(errno, 0), None);
+        }
+    }
+
+    #[test]
+    fn exit_signal() {
+        let signal_fd = SignalFd::new(libc::SIGUSR2).unwrap();
+        assert!(!read_exit_signal(&signal_fd).unwrap_or(true));
+        // The signal is blocked, so it stays pending for the signalfd instead of being handled.
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+        assert!(read_exit_signal(&signal_fd).unwrap_or(false));
+        assert!(!read_exit_signal(&signal_fd).unwrap_or(true));
+    }
+
+    // Error doesn't implement Debug, so it can't be unwrapped.
+    fn policy_path(cfg: &Config, device: &str) -> PathBuf {
+        match seccomp_policy_path(cfg, device) {
+            Ok(p) => p,
+            Err(e) => panic!("failed to get policy path: {}", e),
+        }
+    }
+
+    #[test]
+    fn pivot_root_existing() {
+        let dir = TempDir::new("/tmp/pivot_root_test").unwrap();
+        let path = dir.as_path().unwrap();
+        match create_pivot_root(path) {
+            Ok(PivotRoot::Configured(ref p)) => assert_eq!(p, path),
+            _ => panic!("existing empty directory should be used as is"),
+        }
+    }
+
+    #[test]
+    fn pivot_root_created() {
+        let root = match create_pivot_root(Path::new("/nonexistent/pivot_root")) {
+            Ok(root) => root,
+            Err(e) => panic!("failed to create pivot root: {}", e),
+        };
+        let path = root.path().to_path_buf();
+        match root {
+            PivotRoot::Created(_) => {}
+            _ => panic!("missing pivot root should have been created"),
+        }
+        assert!(path.is_dir());
+        assert_eq!(path.metadata().unwrap().mode() & 0o777, 0o755);
+        drop(root);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pivot_root_validation() {
+        let dir = TempDir::new("/tmp/pivot_root_test").unwrap();
+        let path = dir.as_path().unwrap();
+        // mkdtemp makes a 0700 directory owned by us.
+        assert!(validate_pivot_root(path).is_ok());
+
+        set_permissions(path, Permissions::from_mode(0o777)).unwrap();
+        match validate_pivot_root(path) {
+            Err(Error::InvalidPivotRoot(_, reason)) => assert!(reason.contains("writable")),
+            _ => panic!("world writable pivot root should be rejected"),
+        }
+        set_permissions(path, Permissions::from_mode(0o755)).unwrap();
+
+        File::create(path.join("file")).unwrap();
+        match validate_pivot_root(path) {
+            Err(Error::InvalidPivotRoot(_, reason)) => assert_eq!(reason, "must be empty"),
+            _ => panic!("non-empty pivot root should be rejected"),
+        }
+        remove_file(path.join("file")).unwrap();
+
+        match validate_pivot_root(&path.join("missing")) {
+            Err(Error::InvalidPivotRoot(..)) => {}
+            _ => panic!("missing pivot root should be rejected"),
+        }
+    }
+
+    #[test]
+    fn disk_image_lock_modes() {
+        let dir = TempDir::new("/tmp/disk_lock_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let first = File::open(&path).unwrap();
+        let second = File::open(&path).unwrap();
+        let third = File::open(&path).unwrap();
+
+        let no_wait = Duration::from_millis(0);
+        assert!(lock_disk_image(&first, &path, DiskLockMode::Shared, no_wait).is_ok());
+        assert!(lock_disk_image(&second, &path, DiskLockMode::Shared, no_wait).is_ok());
+        match lock_disk_image(&third, &path, DiskLockMode::Exclusive, no_wait) {
+            Err(Error::DiskImageLocked(ref p)) => assert_eq!(p, &path),
+            _ => panic!("exclusive lock should conflict with shared locks"),
+        }
+        // No lock is taken at all, so it can't conflict.
+        assert!(lock_disk_image(&third, &path, DiskLockMode::None, no_wait).is_ok());
+    }
+
+    #[test]
+    fn disk_image_lock_timeout() {
+        let dir = TempDir::new("/tmp/disk_lock_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let first = File::open(&path).unwrap();
+        let second = File::open(&path).unwrap();
+
+        let timeout = Duration::from_millis(100);
+        assert!(lock_disk_image(&first, &path, DiskLockMode::Exclusive, timeout).is_ok());
+        let start = Instant::now();
+        match lock_disk_image(&second, &path, DiskLockMode::Exclusive, timeout) {
+            Err(Error::DiskImageLocked(ref p)) => assert_eq!(p, &path),
+            _ => panic!("lock held by another handle should time out"),
+        }
+        assert!(start.elapsed() >= timeout);
+
+        // The lock is taken as soon as the other handle lets go of it.
+        let release = thread::spawn(move || {
+                                        thread::sleep(Duration::from_millis(50));
+                                        drop(first);
+                                    });
+        assert!(lock_disk_image(&second, &path, DiskLockMode::Exclusive, DISK_LOCK_TIMEOUT)
+                    .is_ok());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn jail_device_decision() {
+        let mut cfg = Config::default();
+        cfg.multiprocess = true;
+        assert!(jail_device(&cfg, "block"));
+        assert!(jail_device(&cfg, "wl"));
+
+        cfg.unjailed_devices.insert("wl".to_owned());
+        assert!(jail_device(&cfg, "block"));
+        assert!(!jail_device(&cfg, "wl"));
+
+        // Without multiprocess mode nothing is jailed.
+        cfg.multiprocess = false;
+        assert!(!jail_device(&cfg, "block"));
+        assert!(!jail_device(&cfg, "wl"));
+    }
+
+    #[test]
+    fn seccomp_policy_default_path() {
+        let mut cfg = Config::default();
+        cfg.seccomp_policy_dir = PathBuf::from("/policies");
+        assert_eq!(policy_path(&cfg, "block"),
+                   PathBuf::from("/policies/block_device.policy"));
+    }
+
+    #[test]
+    fn seccomp_policy_override_path() {
+        let dir = TempDir::new("/tmp/seccomp_policy_test").unwrap();
+        let policy = dir.as_path().unwrap().join("custom.policy");
+        File::create(&policy).unwrap();
+
+        let mut cfg = Config::default();
+        cfg.seccomp_policy_dir = PathBuf::from("/policies");
+        cfg.seccomp_policy_overrides.insert("block".to_owned(), policy.clone());
+        assert_eq!(policy_path(&cfg, "block"), policy);
+        // Devices without an override still use the policy directory.
+        assert_eq!(policy_path(&cfg, "rng"),
+                   PathBuf::from("/policies/rng_device.policy"));
+    }
+
+    #[test]
+    fn seccomp_policy_override_missing() {
+        let mut cfg = Config::default();
+        cfg.seccomp_policy_overrides.insert("net".to_owned(),
+                                            PathBuf::from("/nonexistent/net.policy"));
+        match seccomp_policy_path(&cfg, "net") {
+            Err(Error::SeccompPolicyOverrideMissing(ref d, _)) => assert_eq!(d, "net"),
+            _ => panic!("missing override policy should be an error"),
+        }
+    }
+
+    // An exit for `ScriptedVcpu` to take.
+    enum ScriptedExit {
+        IoOut(u16, Vec<u8>),
+        IoIn(u16, usize),
+        MmioWrite(u64, Vec<u8>),
+        MmioRead(u64, usize),
+        Hlt,
+        SystemEvent(u32),
+    }
+
+    // A vcpu that takes the exits in its script one after the other and records the data the
+    // buses fill in for reads.
+    struct ScriptedVcpu {
+        script: Arc<Mutex<VecDeque<ScriptedExit>>>,
+        reads: Arc<Mutex<Vec<Vec<u8>>>>,
+        pending_read: Mutex<Option<usize>>,
+        data: UnsafeCell<[u8; 8]>,
+    }
+
+    impl VcpuRunner for ScriptedVcpu {
+        fn run(&self) -> sys_util::Result<VcpuExit> {
+            // Safe because the data of an exit is not used once the vcpu runs again, the same as
+            // with the run structure of a real vcpu.
+            let data = unsafe { &mut *self.data.get() };
+            if let Some(len) = self.pending_read.lock().unwrap().take() {
+                self.reads.lock().unwrap().push(data[..len].to_vec());
+            }
+            let exit = match self.script.lock().unwrap().pop_front() {
+                Some(e) => e,
+                None => panic!("vcpu ran past the end of its script"),
+            };
+            Ok(match exit {
+                   ScriptedExit::IoOut(port, bytes) => {
+                       data[..bytes.len()].copy_from_slice(&bytes);
+                       VcpuExit::IoOut(port, &data[..bytes.len()])
+                   }
+                   ScriptedExit::IoIn(port, len) => {
+                       *self.pending_read.lock().unwrap() = Some(len);
+                       VcpuExit::IoIn(port, &mut data[..len])
+                   }
+                   ScriptedExit::MmioWrite(addr, bytes) => {
+                       data[..bytes.len()].copy_from_slice(&bytes);
+                       VcpuExit::MmioWrite(addr, &data[..bytes.len()])
+                   }
+                   ScriptedExit::MmioRead(addr, len) => {
+                       *self.pending_read.lock().unwrap() = Some(len);
+                       VcpuExit::MmioRead(addr, &mut data[..len])
+                   }
+                   ScriptedExit::Hlt => VcpuExit::Hlt,
+                   ScriptedExit::SystemEvent(event_type) => VcpuExit::SystemEvent(event_type, 0),
+               })
+        }
+
+        fn set_signal_mask(&self, _signals: &[c_int]) -> sys_util::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Records the writes to it and answers reads with the offset of each byte.
+    struct RecordingDevice {
+        writes: Arc<Mutex<Vec<(u64, Vec<u8>)>>>,
+    }
+
+    impl devices::BusDevice for RecordingDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = offset as u8 + i as u8;
+            }
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.writes.lock().unwrap().push((offset, data.to_vec()));
+        }
+    }
+
+    fn ignore_request(_vcpu: &ScriptedVcpu, _state: &mut VcpuDebugState, _request: VcpuRequest) {}
+
+    struct ScriptResult {
+        remaining: usize,
+        reads: Vec<Vec<u8>>,
+        io_writes: Vec<(u64, Vec<u8>)>,
+        mmio_writes: Vec<(u64, Vec<u8>)>,
+        kill_signaled: bool,
+        exit_reason: Option<ExitReason>,
+    }
+
+    // Runs a vcpu through `script` with a device at port 0x3f8 and one at MMIO address 0x1000,
+    // and waits for the vcpu thread to exit.
+    fn run_script(script: Vec<ScriptedExit>) -> ScriptResult {
+        let io_writes = Arc::new(Mutex::new(Vec::new()));
+        let mmio_writes = Arc::new(Mutex::new(Vec::new()));
+        let mut io_bus = devices::Bus::new();
+        io_bus
+            .insert(Arc::new(Mutex::new(RecordingDevice { writes: io_writes.clone() })),
+                    0x3f8,
+                    8)
+            .unwrap();
+        let mut mmio_bus = devices::Bus::new();
+        mmio_bus
+            .insert(Arc::new(Mutex::new(RecordingDevice { writes: mmio_writes.clone() })),
+                    0x1000,
+                    0x100)
+            .unwrap();
+
+        let script = Arc::new(Mutex::new(script.into_iter().collect::<VecDeque<_>>()));
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let vcpu = ScriptedVcpu {
+            script: script.clone(),
+            reads: reads.clone(),
+            pending_read: Mutex::new(None),
+            data: UnsafeCell::new([0; 8]),
+        };
+        let exit_evt = EventFd::new().unwrap();
+        let kill_signaled = Arc::new(AtomicBool::new(false));
+        let (_request_send, request_recv) = channel();
+        let (exit_reason_send, exit_reason_recv) = channel();
+        let handle = match run_vcpu(vcpu,
+                                    0,
+                                    Arc::new(Barrier::new(1)),
+                                    io_bus,
+                                    mmio_bus,
+                                    exit_evt.try_clone().unwrap(),
+                                    exit_reason_send,
+                                    kill_signaled.clone(),
+                                    request_recv,
+                                    None,
+                                    None,
+                                    ignore_request) {
+            Ok(h) => h,
+            Err(e) => panic!("failed to start vcpu thread: {}", e),
+        };
+        handle.join().unwrap();
+        assert_eq!(exit_evt.read().unwrap(), 1);
+
+        let remaining = script.lock().unwrap().len();
+        let reads = reads.lock().unwrap().clone();
+        let io_writes = io_writes.lock().unwrap().clone();
+        let mmio_writes = mmio_writes.lock().unwrap().clone();
+        ScriptResult {
+            remaining: remaining,
+            reads: reads,
+            io_writes: io_writes,
+            mmio_writes: mmio_writes,
+            kill_signaled: kill_signaled.load(Ordering::SeqCst),
+            exit_reason: exit_reason_recv.try_recv().ok(),
+        }
+    }
+
+    #[test]
+    fn vcpu_exit_dispatch() {
+        let result = run_script(vec![ScriptedExit::IoOut(0x3f9, vec![0x41]),
+                                     ScriptedExit::IoIn(0x3fa, 2),
+                                     ScriptedExit::MmioWrite(0x1010, vec![1, 2, 3, 4]),
+                                     ScriptedExit::MmioRead(0x1020, 4),
+                                     ScriptedExit::Hlt,
+                                     ScriptedExit::IoOut(0x3f8, vec![0x42])]);
+        assert_eq!(result.io_writes, vec![(1, vec![0x41])]);
+        assert_eq!(result.mmio_writes, vec![(0x10, vec![1, 2, 3, 4])]);
+        assert_eq!(result.reads, vec![vec![2, 3], vec![0x20, 0x21, 0x22, 0x23]]);
+        // The vcpu stopped at the halt.
+        assert_eq!(result.remaining, 1);
+        assert!(!result.kill_signaled);
+    }
+
+    #[test]
+    fn vcpu_system_event() {
+        let result = run_script(vec![ScriptedExit::IoOut(0x3f8, vec![0x41]),
+                                     ScriptedExit::SystemEvent(kvm_sys::KVM_SYSTEM_EVENT_SHUTDOWN),
+                                     ScriptedExit::IoOut(0x3f8, vec![0x42])]);
+        assert_eq!(result.io_writes, vec![(0, vec![0x41])]);
+        assert_eq!(result.remaining, 1);
+        assert!(result.kill_signaled);
+    }
+
+    #[test]
+    fn vcpu_exit_reasons() {
+        assert_eq!(run_script(vec![ScriptedExit::Hlt]).exit_reason,
+                   Some(ExitReason::Halt));
+        let events = [(kvm_sys::KVM_SYSTEM_EVENT_SHUTDOWN, ExitReason::PowerOff),
+                      (kvm_sys::KVM_SYSTEM_EVENT_RESET, ExitReason::Reset),
+                      (kvm_sys::KVM_SYSTEM_EVENT_CRASH, ExitReason::Crash)];
+        for &(event_type, reason) in events.iter() {
+            let result = run_script(vec![ScriptedExit::SystemEvent(event_type)]);
+            assert_eq!(result.exit_reason, Some(reason));
+        }
+    }
+
+    #[test]
+    fn child_death_policy() {
+        assert!(!device_is_critical(devices::virtio::TYPE_RNG));
+        assert!(!device_is_critical(devices::virtio::TYPE_BALLOON));
+        assert!(device_is_critical(devices::virtio::TYPE_BLOCK));
+        assert!(device_is_critical(devices::virtio::TYPE_NET));
+
+        let device_pids = [(100, devices::virtio::TYPE_RNG), (101, devices::virtio::TYPE_BLOCK)];
+        assert!(!child_death_is_fatal(100, &device_pids));
+        assert!(child_death_is_fatal(101, &device_pids));
+        // A child that isn't a known device.
+        assert!(child_death_is_fatal(102, &device_pids));
+    }
+
+    #[test]
+    fn vcpu_count_limits() {
+        assert!(check_vcpu_count(1, 4, 8).is_ok());
+        assert!(check_vcpu_count(4, 4, 8).is_ok());
+        // Past the recommended count only warns.
+        assert!(check_vcpu_count(8, 4, 8).is_ok());
+        match check_vcpu_count(9, 4, 8) {
+            Err(Error::TooManyVcpus { requested: 9, max: 8 }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("too many vcpus accepted"),
+        }
+    }
+
+    #[test]
+    fn vcpu_budget_accounting() {
+        let ms = Duration::from_millis;
+        let mut budget = VcpuBudget::new(ms(20), ms(100));
+        let start = budget.window_start;
+
+        assert_eq!(budget.account(ms(5), start + ms(10)), None);
+        assert_eq!(budget.account(ms(10), start + ms(30)), None);
+        // That used up the budget, so wait out the rest of the window.
+        assert_eq!(budget.account(ms(5), start + ms(40)), Some(ms(60)));
+        // The next window starts after the wait.
+        assert_eq!(budget.account(ms(15), start + ms(150)), None);
+        assert_eq!(budget.account(ms(5), start + ms(160)), Some(ms(40)));
+
+        // A window that passes without using up the budget starts a new one.
+        assert_eq!(budget.account(ms(10), start + ms(250)), None);
+        assert_eq!(budget.account(ms(5), start + ms(300)), None);
+        assert_eq!(budget.account(ms(14), start + ms(310)), None);
+        assert_eq!(budget.account(ms(1), start + ms(320)), Some(ms(80)));
+    }
 }
@@ -33,51 +33,116 @@ extern crate protobuf;
 extern crate gpu_buffer;
 
 pub mod argument;
+pub mod gdb;
 pub mod linux;
 #[cfg(feature = "plugin")]
 pub mod plugin;
 
+use std::collections::{HashMap, HashSet};
 use std::net;
+use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixDatagram;
 use std::path::PathBuf;
 use std::string::String;
 use std::thread::sleep;
 use std::time::Duration;
 
+use devices::virtio::CacheMode;
 use sys_util::{Scm, getpid, kill_process_group, reap_child, syslog};
 
 use argument::{Argument, set_arguments, print_help};
 use vm_control::VmRequest;
 
 static SECCOMP_POLICY_DIR: &'static str = "/usr/share/policy/crosvm";
+static DEFAULT_PIVOT_ROOT: &'static str = "/var/empty";
+static DEFAULT_SHARED_DIR_TAG: &'static str = "crosvm";
 
 enum DiskType {
     FlatFile,
     Qcow,
 }
 
+/// How a disk image is locked against use by other processes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DiskLockMode {
+    Exclusive,
+    Shared,
+    None,
+}
+
+/// The device the guest kernel uses as its console.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GuestConsole {
+    /// Serial port ttyS`N`, which is also used as the early console.
+    Serial(u8),
+    /// Port 0 of the virtio console, hvc0.
+    Virtio,
+    None,
+}
+
 struct DiskOption {
     path: PathBuf,
     writable: bool,
     disk_type: DiskType,
+    lock: DiskLockMode,
+    sparse: bool,
+    async_io: bool,
+    merge_requests: bool,
+    max_transfer: Option<u32>,
+    cache_mode: CacheMode,
 }
 
 pub struct Config {
     disks: Vec<DiskOption>,
     vcpu_count: Option<u32>,
     memory: Option<usize>,
+    lock_guest_memory: bool,
+    trace_bus: bool,
+    cpuid_overrides: Vec<arch::CpuidOverride>,
+    // CPU time each vcpu may use in every window of wall-clock time.
+    vcpu_budget: Option<(Duration, Duration)>,
+    log_level: Option<syslog::Priority>,
+    // Seconds the guest's RTC runs ahead of the host's wall clock.
+    rtc_offset: i64,
+    // Files that receive the output of ttyS1 through ttyS3, keyed by port number.
+    serial_outputs: Vec<(u8, PathBuf)>,
+    discard_serial: bool,
+    serial_log_path: Option<PathBuf>,
+    // Without a console given, the guest uses ttyS0 but gets no early console.
+    console: Option<GuestConsole>,
+    numa_nodes: Vec<u32>,
     kernel_path: PathBuf,
+    initrd_path: Option<PathBuf>,
     params: Vec<String>,
     host_ip: Option<net::Ipv4Addr>,
     netmask: Option<net::Ipv4Addr>,
     mac_address: Option<net_util::MacAddress>,
+    tap_fd: Option<RawFd>,
     vhost_net: bool,
     wayland_socket_path: Option<PathBuf>,
     wayland_dmabuf: bool,
+    // Host directory shared with the guest over 9P, and the tag the guest mounts it by.
+    shared_dir: Option<(PathBuf, String)>,
+    split_irqchip: bool,
+    no_pit: bool,
+    x2apic: bool,
+    watchdog: bool,
+    tsc_khz: Option<u32>,
+    // MiB of guest physical address space above RAM that is reserved for 64-bit PCI BARs.
+    high_mmio_size: Option<u64>,
     socket_path: Option<PathBuf>,
+    virtio_console_path: Option<PathBuf>,
+    virtio_console_ports: Vec<(String, PathBuf)>,
+    virtio_input_devices: Vec<devices::virtio::InputConfig>,
+    gdb: Option<u16>,
     multiprocess: bool,
+    unjailed_devices: HashSet<String>,
+    pivot_root: PathBuf,
     seccomp_policy_dir: PathBuf,
+    seccomp_policy_overrides: HashMap<String, PathBuf>,
     cid: Option<u64>,
+    vsock_queue_sizes: Option<(u16, u16)>,
+    vsock_socket_dir: Option<PathBuf>,
     plugin: Option<PathBuf>,
     plugin_root: Option<PathBuf>,
 }
@@ -88,18 +153,47 @@ impl Default for Config {
             disks: Vec::new(),
             vcpu_count: None,
             memory: None,
+            lock_guest_memory: false,
+            trace_bus: false,
+            cpuid_overrides: Vec::new(),
+            vcpu_budget: None,
+            log_level: None,
+            rtc_offset: 0,
+            serial_outputs: Vec::new(),
+            discard_serial: false,
+            serial_log_path: None,
+            console: None,
+            numa_nodes: Vec::new(),
             kernel_path: PathBuf::default(),
+            initrd_path: None,
             params: Vec::new(),
             host_ip: None,
             netmask: None,
             mac_address: None,
+            tap_fd: None,
             vhost_net: false,
             wayland_socket_path: None,
             wayland_dmabuf: false,
+            shared_dir: None,
+            split_irqchip: false,
+            no_pit: false,
+            x2apic: false,
+            watchdog: false,
+            tsc_khz: None,
+            high_mmio_size: None,
             socket_path: None,
+            virtio_console_path: None,
+            virtio_console_ports: Vec::new(),
+            virtio_input_devices: Vec::new(),
+            gdb: None,
             multiprocess: !cfg!(feature = "default-no-sandbox"),
+            unjailed_devices: HashSet::new(),
+            pivot_root: PathBuf::from(DEFAULT_PIVOT_ROOT),
             seccomp_policy_dir: PathBuf::from(SECCOMP_POLICY_DIR),
+            seccomp_policy_overrides: HashMap::new(),
             cid: None,
+            vsock_queue_sizes: None,
+            vsock_socket_dir: None,
             plugin: None,
             plugin_root: None,
         }
@@ -134,6 +228,40 @@ fn wait_all_children() -> bool {
     return false;
 }
 
+// Parses a number given in decimal, or in hex with a leading "0x".
+fn parse_u32(s: &str) -> Option<u32> {
+    if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+// Parses a CPUID override in the form LEAF[:SUBLEAF]=EAX,EBX,ECX,EDX.
+fn parse_cpuid_override(s: &str) -> Option<arch::CpuidOverride> {
+    let mut parts = s.splitn(2, '=');
+    let leaf = parts.next()?;
+    let regs = parts.next()?;
+    let mut leaf_parts = leaf.splitn(2, ':');
+    let function = parse_u32(leaf_parts.next()?)?;
+    let index = match leaf_parts.next() {
+        Some(index) => parse_u32(index)?,
+        None => 0,
+    };
+    let regs = regs.split(',').map(parse_u32).collect::<Option<Vec<u32>>>()?;
+    if regs.len() != 4 {
+        return None;
+    }
+    Some(arch::CpuidOverride {
+             function: function,
+             index: index,
+             eax: regs[0],
+             ebx: regs[1],
+             ecx: regs[2],
+             edx: regs[3],
+         })
+}
+
 fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
     match name {
         "" => {
@@ -154,6 +282,19 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 cfg.kernel_path = kernel_path;
             }
         }
+        "initrd" => {
+            if cfg.initrd_path.is_some() {
+                return Err(argument::Error::TooManyArguments("`initrd` already given".to_owned()));
+            }
+            let initrd_path = PathBuf::from(value.unwrap());
+            if !initrd_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "this initrd path does not exist",
+                           });
+            }
+            cfg.initrd_path = Some(initrd_path);
+        }
         "params" => {
             cfg.params.push(value.unwrap().to_owned());
         }
@@ -172,6 +313,17 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                                       }
                                   })?)
         }
+        "tap-fd" => {
+            if cfg.tap_fd.is_some() {
+                return Err(argument::Error::TooManyArguments("`tap-fd` already given".to_owned()));
+            }
+            cfg.tap_fd = Some(value.unwrap().parse().map_err(|_| {
+                argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "this value for `tap-fd` must be an unsigned integer",
+                }
+            })?);
+        }
         "mem" => {
             if cfg.memory.is_some() {
                 return Err(argument::Error::TooManyArguments("`mem` already given".to_owned()));
@@ -188,7 +340,82 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                                   })?)
         }
         "root" | "disk" | "rwdisk" | "qcow" | "rwqcow" => {
-            let disk_path = PathBuf::from(value.unwrap());
+            let mut components = value.unwrap().split(',');
+            // split always returns at least one item.
+            let disk_path = PathBuf::from(components.next().unwrap());
+            let writable = name.starts_with("rw");
+            // Writable disks must not be shared, but read-only images can be used by many VMs.
+            let mut lock = if writable {
+                DiskLockMode::Exclusive
+            } else {
+                DiskLockMode::Shared
+            };
+            let mut sparse = false;
+            let mut async_io = false;
+            let mut merge_requests = false;
+            let mut max_transfer = None;
+            let mut cache_mode = CacheMode::WriteBack;
+            for option in components {
+                match option {
+                    "lock=exclusive" => lock = DiskLockMode::Exclusive,
+                    "lock=shared" => lock = DiskLockMode::Shared,
+                    "lock=none" => lock = DiskLockMode::None,
+                    "sparse" => sparse = true,
+                    "async" => async_io = true,
+                    "merge" => merge_requests = true,
+                    "cache=writeback" => cache_mode = CacheMode::WriteBack,
+                    "cache=writethrough" => cache_mode = CacheMode::WriteThrough,
+                    "cache=none" => cache_mode = CacheMode::None,
+                    _ if option.starts_with("max_transfer=") => {
+                        let bytes = option["max_transfer=".len()..].parse::<u32>().ok();
+                        max_transfer = match bytes {
+                            Some(b) if b > 0 && b % 512 == 0 => Some(b),
+                            _ => {
+                                return Err(argument::Error::InvalidValue {
+                                               value: option.to_owned(),
+                                               expected: "`max_transfer` must be a positive multiple of 512",
+                                           })
+                            }
+                        };
+                    }
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                                       value: option.to_owned(),
+                                       expected: "disk options must be `lock=exclusive`, `lock=shared`, `lock=none`, `sparse`, `async`, `merge`, `max_transfer=BYTES` or `cache=MODE`",
+                                   })
+                    }
+                }
+            }
+            if sparse && (!writable || name.ends_with("qcow")) {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "only writable raw disks can be kept sparse",
+                           });
+            }
+            if async_io && (sparse || name.ends_with("qcow")) {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "only raw disks that aren't sparse can use async io",
+                           });
+            }
+            if cache_mode == CacheMode::None && name.ends_with("qcow") {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "qcow disks can't bypass the host page cache",
+                           });
+            }
+            if async_io && merge_requests {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "requests to a disk with async io can't be merged",
+                           });
+            }
+            if writable && lock == DiskLockMode::Shared {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "a writable disk can not use a shared lock",
+                           });
+            }
             if !disk_path.exists() {
                 return Err(argument::Error::InvalidValue {
                                value: value.unwrap().to_owned(),
@@ -206,12 +433,18 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             cfg.disks
                 .push(DiskOption {
                           path: disk_path,
-                          writable: name.starts_with("rw"),
+                          writable: writable,
                           disk_type: if name.ends_with("qcow") {
                                   DiskType::Qcow
                               } else {
                                   DiskType::FlatFile
                               },
+                          lock: lock,
+                          sparse: sparse,
+                          async_io: async_io,
+                          merge_requests: merge_requests,
+                          max_transfer: max_transfer,
+                          cache_mode: cache_mode,
                       });
         }
         "host_ip" => {
@@ -277,6 +510,32 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         "wayland-dmabuf" => {
             cfg.wayland_dmabuf = true
         }
+        "shared-dir" => {
+            if cfg.shared_dir.is_some() {
+                return Err(argument::Error::TooManyArguments("`shared-dir` already given"
+                                                                 .to_owned()));
+            }
+            let mut components = value.unwrap().split(',');
+            let shared_dir = PathBuf::from(components.next().unwrap());
+            if !shared_dir.is_dir() {
+                return Err(argument::Error::InvalidValue {
+                               value: shared_dir.to_string_lossy().into_owned(),
+                               expected: "the shared directory must be an existing directory",
+                           });
+            }
+            let mut tag = DEFAULT_SHARED_DIR_TAG.to_owned();
+            for option in components {
+                if option.starts_with("tag=") && option.len() > "tag=".len() {
+                    tag = option["tag=".len()..].to_owned();
+                } else {
+                    return Err(argument::Error::InvalidValue {
+                                   value: option.to_owned(),
+                                   expected: "`shared-dir` options must be `tag=TAG`",
+                               });
+                }
+            }
+            cfg.shared_dir = Some((shared_dir, tag));
+        }
         "socket" => {
             if cfg.socket_path.is_some() {
                 return Err(argument::Error::TooManyArguments("`socket` already given".to_owned()));
@@ -293,12 +552,84 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.socket_path = Some(socket_path);
         }
+        "virtio-console" => {
+            if cfg.virtio_console_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`virtio-console` already given".to_owned()));
+            }
+            cfg.virtio_console_path = Some(PathBuf::from(value.unwrap()));
+        }
+        "virtio-console-port" => {
+            let mut components = value.unwrap().splitn(2, '=');
+            let name = components.next().unwrap_or("");
+            let path = components.next().unwrap_or("");
+            if name.is_empty() || path.is_empty() {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "this value for `virtio-console-port` must be NAME=PATH",
+                           });
+            }
+            if cfg.virtio_console_ports.iter().any(|&(ref n, _)| n == name) {
+                return Err(argument::Error::InvalidValue {
+                               value: name.to_owned(),
+                               expected: "virtio console port names must be unique",
+                           });
+            }
+            cfg.virtio_console_ports.push((name.to_owned(), PathBuf::from(path)));
+        }
+        "virtio-input" => {
+            let invalid_value = || argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: "this value for `virtio-input` must be `keyboard`, `mouse` or \
+                           `tablet[:WIDTHxHEIGHT]`",
+            };
+            let mut components = value.unwrap().splitn(2, ':');
+            let config = match (components.next(), components.next()) {
+                (Some("keyboard"), None) => devices::virtio::InputConfig::keyboard(),
+                (Some("mouse"), None) => devices::virtio::InputConfig::mouse(),
+                (Some("tablet"), None) => devices::virtio::InputConfig::tablet(1280, 1024),
+                (Some("tablet"), Some(size)) => {
+                    let mut dims = size.splitn(2, 'x').map(|d| d.parse::<u32>());
+                    match (dims.next(), dims.next()) {
+                        (Some(Ok(width)), Some(Ok(height))) if width > 0 && height > 0 => {
+                            devices::virtio::InputConfig::tablet(width, height)
+                        }
+                        _ => return Err(invalid_value()),
+                    }
+                }
+                _ => return Err(invalid_value()),
+            };
+            cfg.virtio_input_devices.push(config);
+        }
+        "gdb" => {
+            if cfg.gdb.is_some() {
+                return Err(argument::Error::TooManyArguments("`gdb` already given".to_owned()));
+            }
+            cfg.gdb = Some(value.unwrap().parse().map_err(|_| {
+                argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "this value for `gdb` must be a TCP port number",
+                }
+            })?);
+        }
         "multiprocess" => {
             cfg.multiprocess = true;
         }
         "disable-sandbox" => {
             cfg.multiprocess = false;
         }
+        "disable-sandbox-for" => {
+            if !linux::JAILED_DEVICES.contains(&value.unwrap()) {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "`disable-sandbox-for` must name a jailed device",
+                           });
+            }
+            if !cfg.unjailed_devices.insert(value.unwrap().to_owned()) {
+                return Err(argument::Error::TooManyArguments(
+                    format!("`disable-sandbox-for` already given for {}", value.unwrap())));
+            }
+        }
         "cid" => {
             if cfg.cid.is_some() {
                 return Err(argument::Error::TooManyArguments("`cid` alread given".to_owned()));
@@ -310,10 +641,67 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 }
             })?);
         }
+        "vsock-queue-sizes" => {
+            if cfg.vsock_queue_sizes.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`vsock-queue-sizes` already given".to_owned()));
+            }
+            let invalid_value = || argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: "`vsock-queue-sizes` must be two unsigned integers separated by a comma",
+            };
+            let mut sizes = value.unwrap().split(',');
+            let rx = sizes.next().and_then(|s| s.parse().ok()).ok_or_else(&invalid_value)?;
+            let tx = sizes.next().and_then(|s| s.parse().ok()).ok_or_else(&invalid_value)?;
+            if sizes.next().is_some() {
+                return Err(invalid_value());
+            }
+            cfg.vsock_queue_sizes = Some((rx, tx));
+        }
+        "vsock-socket-dir" => {
+            if cfg.vsock_socket_dir.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`vsock-socket-dir` already given".to_owned()));
+            }
+            cfg.vsock_socket_dir = Some(PathBuf::from(value.unwrap()));
+        }
+        "pivot-root" => {
+            let pivot_root = PathBuf::from(value.unwrap());
+            if pivot_root.is_relative() {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "the pivot root must be an absolute path",
+                           });
+            }
+            cfg.pivot_root = pivot_root;
+        }
         "seccomp-policy-dir" => {
             // `value` is Some because we are in this match so it's safe to unwrap.
             cfg.seccomp_policy_dir = PathBuf::from(value.unwrap());
         },
+        "seccomp-policy" => {
+            let mut components = value.unwrap().splitn(2, '=');
+            let device = components.next().unwrap();
+            let path = match components.next() {
+                Some(path) if !device.is_empty() && !path.is_empty() => PathBuf::from(path),
+                _ => {
+                    return Err(argument::Error::InvalidValue {
+                                   value: value.unwrap().to_owned(),
+                                   expected: "`seccomp-policy` must be of the form DEVICE=PATH",
+                               })
+                }
+            };
+            if !linux::JAILED_DEVICES.contains(&device) {
+                return Err(argument::Error::InvalidValue {
+                               value: device.to_owned(),
+                               expected: "`seccomp-policy` must name a device that can be jailed",
+                           });
+            }
+            if cfg.seccomp_policy_overrides.insert(device.to_owned(), path).is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    format!("`seccomp-policy` already given for {}", device)));
+            }
+        }
         "plugin" => {
             if !cfg.kernel_path.as_os_str().is_empty() {
                 return Err(argument::Error::TooManyArguments("`plugin` can not be used with kernel".to_owned()));
@@ -335,6 +723,182 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         "vhost-net" => {
             cfg.vhost_net = true
         },
+        "split-irqchip" => {
+            cfg.split_irqchip = true
+        },
+        "no-pit" => {
+            cfg.no_pit = true
+        },
+        "x2apic" => {
+            cfg.x2apic = true
+        },
+        "watchdog" => {
+            cfg.watchdog = true
+        },
+        "lock-guest-memory" => {
+            cfg.lock_guest_memory = true
+        },
+        "vcpu-budget" => {
+            if cfg.vcpu_budget.is_some() {
+                return Err(argument::Error::TooManyArguments("`vcpu-budget` already given".to_owned()));
+            }
+            let mut parts = value.unwrap().splitn(2, '/').map(|s| s.parse::<u64>());
+            cfg.vcpu_budget = match (parts.next(), parts.next()) {
+                (Some(Ok(budget)), Some(Ok(window))) if budget > 0 && budget < window => {
+                    Some((Duration::from_micros(budget), Duration::from_micros(window)))
+                }
+                _ => {
+                    return Err(argument::Error::InvalidValue {
+                                   value: value.unwrap().to_owned(),
+                                   expected: "`vcpu-budget` must be RUN_US/WINDOW_US with RUN_US less than WINDOW_US",
+                               })
+                }
+            };
+        },
+        "trace-bus" => {
+            cfg.trace_bus = true
+        },
+        "serial" => {
+            let mut components = value.unwrap().splitn(2, '=');
+            let num = components.next().and_then(|n| n.parse::<u8>().ok());
+            let path = components.next().unwrap_or("");
+            let num = match num {
+                Some(num) if num >= 1 && num <= 3 && !path.is_empty() => num,
+                _ => {
+                    return Err(argument::Error::InvalidValue {
+                                   value: value.unwrap().to_owned(),
+                                   expected: "this value for `serial` must be NUM=PATH with NUM from 1 to 3",
+                               })
+                }
+            };
+            if cfg.serial_outputs.iter().any(|&(n, _)| n == num) {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "each serial port may only be given once",
+                           });
+            }
+            cfg.serial_outputs.push((num, PathBuf::from(path)));
+        },
+        "discard-serial" => {
+            if cfg.serial_log_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`discard-serial` can not be used with `serial-log`".to_owned()));
+            }
+            cfg.discard_serial = true
+        },
+        "serial-log" => {
+            if cfg.serial_log_path.is_some() {
+                return Err(argument::Error::TooManyArguments("`serial-log` already given".to_owned()));
+            }
+            if cfg.discard_serial {
+                return Err(argument::Error::TooManyArguments(
+                    "`serial-log` can not be used with `discard-serial`".to_owned()));
+            }
+            cfg.serial_log_path = Some(PathBuf::from(value.unwrap()));
+        },
+        "console" => {
+            if cfg.console.is_some() {
+                return Err(argument::Error::TooManyArguments("`console` already given".to_owned()));
+            }
+            cfg.console = Some(match value.unwrap() {
+                "ttyS0" => GuestConsole::Serial(0),
+                "ttyS1" => GuestConsole::Serial(1),
+                "ttyS2" => GuestConsole::Serial(2),
+                "ttyS3" => GuestConsole::Serial(3),
+                "hvc0" => GuestConsole::Virtio,
+                "none" => GuestConsole::None,
+                _ => {
+                    return Err(argument::Error::InvalidValue {
+                                   value: value.unwrap().to_owned(),
+                                   expected: "`console` must be one of ttyS0-ttyS3, hvc0 or none",
+                               })
+                }
+            });
+        },
+        "rtc-offset" => {
+            cfg.rtc_offset = value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "`rtc-offset` must be a whole number of seconds",
+                })?;
+        },
+        "log-level" => {
+            if cfg.log_level.is_some() {
+                return Err(argument::Error::TooManyArguments("`log-level` already given".to_owned()));
+            }
+            cfg.log_level = Some(value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "`log-level` must be one of error, warning, info or debug",
+                })?);
+        },
+        "cpuid" => {
+            let o = parse_cpuid_override(value.unwrap())
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "`cpuid` must be LEAF[:SUBLEAF]=EAX,EBX,ECX,EDX",
+                })?;
+            if cfg.cpuid_overrides
+                   .iter()
+                   .any(|c| c.function == o.function && c.index == o.index) {
+                return Err(argument::Error::TooManyArguments(
+                    format!("`cpuid` already given for leaf {:#x}:{:#x}", o.function, o.index)));
+            }
+            cfg.cpuid_overrides.push(o);
+        },
+        "numa-nodes" => {
+            if !cfg.numa_nodes.is_empty() {
+                return Err(argument::Error::TooManyArguments(
+                    "`numa-nodes` already given".to_owned()));
+            }
+            cfg.numa_nodes = value.unwrap()
+                .split(',')
+                .map(|s| s.parse())
+                .collect::<std::result::Result<Vec<u32>, _>>()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: "`numa-nodes` must be host NUMA node numbers separated by commas",
+                })?;
+        },
+        "tsc-khz" => {
+            if cfg.tsc_khz.is_some() {
+                return Err(argument::Error::TooManyArguments("`tsc-khz` already given".to_owned()));
+            }
+            cfg.tsc_khz = match value.unwrap().parse() {
+                Ok(0) | Err(_) => {
+                    return Err(argument::Error::InvalidValue {
+                                   value: value.unwrap().to_owned(),
+                                   expected: "this value for `tsc-khz` must be a positive integer",
+                               })
+                }
+                Ok(khz) => Some(khz),
+            };
+        },
+        "high-mmio-size" => {
+            if cfg.high_mmio_size.is_some() {
+                return Err(argument::Error::TooManyArguments("`high-mmio-size` already given".to_owned()));
+            }
+            let size_mb: u64 = value
+                .unwrap()
+                .parse()
+                .map_err(|_| {
+                             argument::Error::InvalidValue {
+                                 value: value.unwrap().to_owned(),
+                                 expected: "this value for `high-mmio-size` needs to be integer",
+                             }
+                         })?;
+            if size_mb.checked_mul(1 << 20).is_none() {
+                return Err(argument::Error::InvalidValue {
+                               value: value.unwrap().to_owned(),
+                               expected: "this value for `high-mmio-size` is too large",
+                           });
+            }
+            cfg.high_mmio_size = Some(size_mb)
+        },
         "help" => return Err(argument::Error::PrintHelp),
         _ => unreachable!(),
     }
@@ -344,7 +908,8 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
 
 fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
     let arguments =
-        &[Argument::positional("KERNEL", "bzImage of kernel to run"),
+        &[Argument::positional("KERNEL", "bzImage or vmlinux ELF of kernel to run"),
+          Argument::short_value('i', "initrd", "PATH", "Initial ramdisk to load with the kernel."),
           Argument::short_value('p',
                                 "params",
                                 "PARAMS",
@@ -358,7 +923,10 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                                 "root",
                                 "PATH",
                                 "Path to a root disk image. Like `--disk` but adds appropriate kernel command line option."),
-          Argument::short_value('d', "disk", "PATH", "Path to a disk image."),
+          Argument::short_value('d',
+                                "disk",
+                                "PATH[,lock=MODE][,sparse][,async][,merge][,max_transfer=BYTES][,cache=CACHE]",
+                                "Path to a disk image. Disk options can follow the path of any disk: MODE is exclusive, shared or none. (default: exclusive for writable disks, shared otherwise) `sparse` deallocates blocks of a writable raw disk that the guest fills with zeros. `async` lets a raw disk work on several requests at once. `merge` combines requests for consecutive sectors into one access to the disk. BYTES is the largest request the guest may make, a multiple of 512. (default: 1MiB) CACHE is writeback, writethrough or none. (default: writeback)"),
           Argument::value("qcow", "PATH", "Path to a qcow2 disk image."),
           Argument::value("rwdisk", "PATH", "Path to a writable disk image."),
           Argument::value("rwqcow", "PATH", "Path to a writable qcow2 disk image."),
@@ -367,24 +935,74 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                           "IP address to assign to host tap interface."),
           Argument::value("netmask", "NETMASK", "Netmask for VM subnet."),
           Argument::value("mac", "MAC", "MAC address for VM."),
+          Argument::value("tap-fd",
+                          "FD",
+                          "File descriptor of an already configured tap interface to use for networking. Requires `mac`; excludes `host_ip` and `netmask`."),
           Argument::value("wayland-sock", "PATH", "Path to the Wayland socket to use."),
           Argument::value("wayland-group",
                           "GROUP",
                           "Name of the group with access to the Wayland socket."),
           #[cfg(feature = "wl-dmabuf")]
           Argument::flag("wayland-dmabuf", "Enable support for DMABufs in Wayland device."),
+          Argument::value("shared-dir",
+                          "PATH[,tag=TAG]",
+                          "Directory to share with the guest over 9P. The guest mounts it by TAG. (default: crosvm)"),
           Argument::short_value('s',
                                 "socket",
                                 "PATH",
                                 "Path to put the control socket. If PATH is a directory, a name will be generated."),
+          Argument::value("virtio-console",
+                          "PATH",
+                          "Terminal, FIFO or file to connect to a virtio console device."),
+          Argument::value("virtio-console-port",
+                          "NAME=PATH",
+                          "Adds a port named NAME to the virtio console, connected to the file at PATH. Can be given more than once."),
+          Argument::value("virtio-input",
+                          "KIND",
+                          "Adds a virtio input device, one of `keyboard`, `mouse` or `tablet[:WIDTHxHEIGHT]`, whose events are injected with `crosvm input`. Can be given more than once."),
+          Argument::value("gdb",
+                          "PORT",
+                          "Listen for a gdb remote debugging connection on localhost TCP port PORT."),
           Argument::short_flag('u', "multiprocess", "Run each device in a child process(default)."),
           Argument::flag("disable-sandbox", "Run all devices in one, non-sandboxed process."),
+          Argument::value("disable-sandbox-for",
+                          "DEVICE",
+                          "Run DEVICE (e.g. block, net, wl) in the main process even in multiprocess mode. Can be given more than once."),
           Argument::value("cid", "CID", "Context ID for virtual sockets"),
+          Argument::value("vsock-queue-sizes",
+                          "RX,TX",
+                          "Number of descriptors in the virtual socket rx and tx queues. (default: 256,256)"),
+          Argument::value("vsock-socket-dir",
+                          "PATH",
+                          "Directory of Unix sockets that guest virtual socket connections are bridged to when vhost-vsock is unavailable. (default: /run/crosvm/vsock)"),
+          Argument::value("pivot-root",
+                          "PATH",
+                          "Empty directory that jailed devices use as their root. A private tmpfs-backed one is created if PATH doesn't exist. (default: /var/empty)"),
           Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files."),
+          Argument::value("seccomp-policy",
+                          "DEVICE=PATH",
+                          "Use the seccomp policy at PATH for DEVICE (e.g. block, net, rng) instead of the one in the policy directory. Can be given more than once."),
           #[cfg(feature = "plugin")]
           Argument::value("plugin", "PATH", "Absolute path to plugin process to run under crosvm."),
           Argument::value("plugin-root", "PATH", "Absolute path to a directory that will become root filesystem for the plugin process."),
           Argument::flag("vhost-net", "Use vhost for networking."),
+          Argument::flag("split-irqchip", "Create only the local APICs in KVM and leave the IOAPIC and PIC to userspace, for experimenting with a userspace IOAPIC. Fails if KVM lacks KVM_CAP_SPLIT_IRQCHIP."),
+          Argument::flag("no-pit", "Don't create the legacy i8254 PIT. Only for guests that use the local APIC timer and don't calibrate against the PIT, e.g. with kvmclock."),
+          Argument::flag("x2apic", "Start each vcpu's local APIC in x2APIC mode. Required for more than 255 VCPUs."),
+          Argument::flag("watchdog", "Add a watchdog timer at I/O ports 0x440-0x44f that resets the guest if it isn't petted within its timeout. The 32-bit registers are control (bit 0 enables) at 0x440, timeout in milliseconds at 0x444, pet at 0x448 and milliseconds remaining at 0x44c. x86 only."),
+          Argument::value("numa-nodes", "NODE[,NODE...]", "Split guest memory evenly between these host NUMA nodes, in order of guest physical address."),
+          Argument::flag("lock-guest-memory", "Allocate all of guest memory at startup and lock it into host memory, so the guest never waits for a page fault. Requires a big enough RLIMIT_MEMLOCK."),
+          Argument::value("cpuid", "LEAF[:SUBLEAF]=EAX,EBX,ECX,EDX", "Report exactly these register values for a CPUID leaf, in decimal or 0x-prefixed hex. May be given more than once."),
+          Argument::value("vcpu-budget", "RUN_US/WINDOW_US", "Let each vcpu use at most RUN_US microseconds of host CPU time in every WINDOW_US microseconds. Unlimited by default."),
+          Argument::flag("trace-bus", "Log every guest access to port IO and MMIO devices. Very verbose, for debugging device emulation."),
+          Argument::value("serial", "NUM=PATH", "Write the output of serial port ttySNUM, for NUM from 1 to 3, to the file at PATH and send kernel messages there too. Input always comes from stdin through ttyS0."),
+          Argument::flag("discard-serial", "Throw away what the guest writes to ttyS0 instead of printing it to stdout. Input from stdin still reaches the guest."),
+          Argument::value("serial-log", "PATH", "Write what the guest sends to ttyS0 to the file at PATH instead of stdout, with each line prefixed by the seconds since the VM started."),
+          Argument::value("console", "DEVICE", "Point the guest kernel's console and early console at DEVICE: ttyS0-ttyS3, hvc0 for the virtio console, or none. Defaults to ttyS0 without an early console."),
+          Argument::value("rtc-offset", "SECONDS", "Start the guest's real-time clock this many seconds ahead of the host's wall clock, or behind it if negative. kvmclock is hidden from the guest while an offset is set, so that it keeps time from the RTC."),
+          Argument::value("log-level", "LEVEL", "Only log messages at least as severe as LEVEL: error, warning, info or debug (default)."),
+          Argument::value("tsc-khz", "KHZ", "Run the guest TSC at KHZ kHz instead of the host's TSC frequency, for a stable clock across hosts."),
+          Argument::value("high-mmio-size", "N", "MiB of guest physical address space above RAM and 4GiB to reserve for 64-bit PCI BARs, 0 for none. (default: 4096)"),
           Argument::short_flag('h', "help", "Print help message.")];
 
     let mut cfg = Config::default();
@@ -392,7 +1010,20 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
         if cfg.kernel_path.as_os_str().is_empty() && cfg.plugin.is_none() {
             return Err(argument::Error::ExpectedArgument("`KERNEL`".to_owned()));
         }
-        if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
+        if cfg.initrd_path.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`initrd` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.gdb.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`gdb` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.tap_fd.is_some() {
+            if cfg.host_ip.is_some() || cfg.netmask.is_some() {
+                return Err(argument::Error::TooManyArguments("`host_ip` and `netmask` can not be used with `tap-fd`".to_owned()));
+            }
+            if cfg.mac_address.is_none() {
+                return Err(argument::Error::ExpectedArgument("`mac` missing from network config".to_owned()));
+            }
+        } else if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
             if cfg.host_ip.is_none() {
                 return Err(argument::Error::ExpectedArgument("`host_ip` missing from network config".to_owned()));
             }
@@ -403,6 +1034,64 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                 return Err(argument::Error::ExpectedArgument("`mac` missing from network config".to_owned()));
             }
         }
+        if cfg.vsock_queue_sizes.is_some() && cfg.cid.is_none() {
+            return Err(argument::Error::ExpectedArgument("`vsock-queue-sizes` requires `cid`".to_owned()));
+        }
+        if cfg.tsc_khz.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`tsc-khz` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.shared_dir.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`shared-dir` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.high_mmio_size.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`high-mmio-size` can not be used with `plugin`".to_owned()));
+        }
+        match cfg.console {
+            Some(GuestConsole::Serial(port)) if port != 0 &&
+                                                !cfg.serial_outputs.iter().any(|&(n, _)| n == port) => {
+                return Err(argument::Error::ExpectedArgument(
+                    format!("`console` ttyS{} needs `serial` {}=PATH for its output", port, port)));
+            }
+            Some(GuestConsole::Virtio) if cfg.virtio_console_path.is_none() &&
+                                          cfg.virtio_console_ports.is_empty() => {
+                return Err(argument::Error::ExpectedArgument(
+                    "`console` hvc0 needs `virtio-console`".to_owned()));
+            }
+            _ => {}
+        }
+        if cfg.console.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`console` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.serial_log_path.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`serial-log` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.discard_serial && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`discard-serial` can not be used with `plugin`".to_owned()));
+        }
+        if !cfg.serial_outputs.is_empty() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`serial` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.rtc_offset != 0 && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`rtc-offset` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.vcpu_budget.is_some() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`vcpu-budget` can not be used with `plugin`".to_owned()));
+        }
+        if !cfg.cpuid_overrides.is_empty() && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`cpuid` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.x2apic && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`x2apic` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.split_irqchip && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`split-irqchip` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.no_pit && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`no-pit` can not be used with `plugin`".to_owned()));
+        }
+        if cfg.watchdog && cfg.plugin.is_some() {
+            return Err(argument::Error::TooManyArguments("`watchdog` can not be used with `plugin`".to_owned()));
+        }
         if cfg.plugin_root.is_some() && cfg.plugin.is_none() {
             return Err(argument::Error::ExpectedArgument("`plugin-root` requires `plugin`".to_owned()));
         }
@@ -425,8 +1114,8 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
         }
         Ok(()) => {
             match linux::run_config(cfg) {
-                Ok(_) => {
-                    info!("crosvm has exited normally");
+                Ok(reason) => {
+                    info!("crosvm has exited normally: {:?}", reason);
                     Ok(())
                 }
                 Err(e) => {
@@ -513,6 +1202,69 @@ fn balloon_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
     return_result
 }
 
+fn input_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut scm = Scm::new(1);
+    if args.len() < 3 {
+        print_help("crosvm input", "DEVICE EVENT... VM_SOCKET", &[]);
+        println!("Injects input events into the virtio input device with index `DEVICE`. Each `EVENT` is TYPE:CODE:VALUE, with the numeric values of `linux/input-event-codes.h`. A SYN_REPORT is added after the last event if it isn't one already.");
+        return Err(());
+    }
+    let device: u32 = match args.next().unwrap().parse() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse input device index");
+            return Err(());
+        }
+    };
+    let mut args: Vec<String> = args.collect();
+    let socket_path = args.pop().unwrap();
+    let mut events = Vec::with_capacity(args.len() + 1);
+    for arg in args.iter() {
+        let fields: Vec<&str> = arg.split(':').collect();
+        if fields.len() != 3 {
+            error!("Input event '{}' isn't TYPE:CODE:VALUE", arg);
+            return Err(());
+        }
+        let event = match (fields[0].parse(), fields[1].parse(), fields[2].parse()) {
+            (Ok(type_), Ok(code), Ok(value)) => {
+                vm_control::InputEvent {
+                    type_: type_,
+                    code: code,
+                    value: value,
+                }
+            }
+            _ => {
+                error!("Failed to parse input event '{}'", arg);
+                return Err(());
+            }
+        };
+        events.push(event);
+    }
+    if events.last().map_or(false, |e| e.type_ != 0) {
+        events.push(vm_control::InputEvent::default());
+    }
+
+    match UnixDatagram::unbound().and_then(|s| {
+                                               s.connect(&socket_path)?;
+                                               Ok(s)
+                                           }) {
+        Ok(s) => {
+            let request = VmRequest::InputEvents { device: device, events: events };
+            if let Err(e) = request.send(&mut scm, &s) {
+                error!("failed to send input request to socket at '{}': {:?}",
+                       socket_path,
+                       e);
+                return Err(());
+            }
+            Ok(())
+        }
+        Err(e) => {
+            error!("failed to connect to socket at '{}': {}", socket_path, e);
+            Err(())
+        }
+    }
+}
+
 fn print_usage() {
     print_help("crosvm", "[stop|run]", &[]);
     println!("Commands:");
@@ -547,6 +1299,9 @@ fn crosvm_main() -> std::result::Result<(), ()> {
         Some("balloon") => {
             balloon_vms(args)
         }
+        Some("input") => {
+            input_vms(args)
+        }
         Some(c) => {
             println!("invalid subcommand: {:?}", c);
             print_usage();
@@ -574,3 +1329,335 @@ fn crosvm_main() -> std::result::Result<(), ()> {
 fn main() {
     std::process::exit(if crosvm_main().is_ok() { 0 } else { 1 });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use sys_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn seccomp_policy_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "seccomp-policy", Some("block=/tmp/block.policy")).is_ok());
+        assert_eq!(cfg.seccomp_policy_overrides.get("block"),
+                   Some(&PathBuf::from("/tmp/block.policy")));
+        assert!(set_argument(&mut cfg, "seccomp-policy", Some("floppy=/tmp/floppy.policy"))
+                    .is_err());
+    }
+
+    #[test]
+    fn disable_sandbox_for_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "disable-sandbox-for", Some("wl")).is_ok());
+        assert!(cfg.unjailed_devices.contains("wl"));
+        assert!(set_argument(&mut cfg, "disable-sandbox-for", Some("wl")).is_err());
+        assert!(set_argument(&mut cfg, "disable-sandbox-for", Some("floppy")).is_err());
+    }
+
+    #[test]
+    fn split_irqchip_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.split_irqchip);
+        assert!(set_argument(&mut cfg, "split-irqchip", None).is_ok());
+        assert!(cfg.split_irqchip);
+    }
+
+    #[test]
+    fn no_pit_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.no_pit);
+        assert!(set_argument(&mut cfg, "no-pit", None).is_ok());
+        assert!(cfg.no_pit);
+    }
+
+    #[test]
+    fn x2apic_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.x2apic);
+        assert!(set_argument(&mut cfg, "x2apic", None).is_ok());
+        assert!(cfg.x2apic);
+    }
+
+    #[test]
+    fn watchdog_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.watchdog);
+        assert!(set_argument(&mut cfg, "watchdog", None).is_ok());
+        assert!(cfg.watchdog);
+    }
+
+    #[test]
+    fn lock_guest_memory_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.lock_guest_memory);
+        assert!(set_argument(&mut cfg, "lock-guest-memory", None).is_ok());
+        assert!(cfg.lock_guest_memory);
+    }
+
+    #[test]
+    fn cpuid_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "cpuid", Some("0x40000010=1,0x2,3,0xffffffff")).is_ok());
+        assert!(set_argument(&mut cfg, "cpuid", Some("7:1=0,0,0,0")).is_ok());
+        assert_eq!(cfg.cpuid_overrides,
+                   vec![arch::CpuidOverride {
+                            function: 0x40000010,
+                            index: 0,
+                            eax: 1,
+                            ebx: 2,
+                            ecx: 3,
+                            edx: 0xffffffff,
+                        },
+                        arch::CpuidOverride {
+                            function: 7,
+                            index: 1,
+                            eax: 0,
+                            ebx: 0,
+                            ecx: 0,
+                            edx: 0,
+                        }]);
+        assert!(set_argument(&mut cfg, "cpuid", Some("7:1=1,1,1,1")).is_err());
+        assert!(set_argument(&mut cfg, "cpuid", Some("1=1,2,3")).is_err());
+        assert!(set_argument(&mut cfg, "cpuid", Some("1")).is_err());
+        assert!(set_argument(&mut cfg, "cpuid", Some("x=1,2,3,4")).is_err());
+    }
+
+    #[test]
+    fn vcpu_budget_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "vcpu-budget", Some("50000/100000")).is_ok());
+        assert_eq!(cfg.vcpu_budget,
+                   Some((Duration::from_millis(50), Duration::from_millis(100))));
+        assert!(set_argument(&mut cfg, "vcpu-budget", Some("1/2")).is_err());
+
+        for bad in &["100000/100000", "0/100", "50000", "a/100", "50/b"] {
+            let mut cfg = Config::default();
+            assert!(set_argument(&mut cfg, "vcpu-budget", Some(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn serial_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "serial", Some("1=/tmp/ttyS1")).is_ok());
+        assert!(set_argument(&mut cfg, "serial", Some("3=/tmp/ttyS3")).is_ok());
+        assert_eq!(cfg.serial_outputs,
+                   vec![(1, PathBuf::from("/tmp/ttyS1")), (3, PathBuf::from("/tmp/ttyS3"))]);
+        assert!(set_argument(&mut cfg, "serial", Some("1=/tmp/other")).is_err());
+
+        for bad in &["0=/tmp/ttyS0", "4=/tmp/ttyS4", "1=", "1", "a=/tmp/ttyS1"] {
+            let mut cfg = Config::default();
+            assert!(set_argument(&mut cfg, "serial", Some(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn discard_serial_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.discard_serial);
+        assert!(set_argument(&mut cfg, "discard-serial", None).is_ok());
+        assert!(cfg.discard_serial);
+    }
+
+    #[test]
+    fn serial_log_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "serial-log", Some("/tmp/console.log")).is_ok());
+        assert_eq!(cfg.serial_log_path, Some(PathBuf::from("/tmp/console.log")));
+        assert!(set_argument(&mut cfg, "serial-log", Some("/tmp/other.log")).is_err());
+        assert!(set_argument(&mut cfg, "discard-serial", None).is_err());
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "discard-serial", None).is_ok());
+        assert!(set_argument(&mut cfg, "serial-log", Some("/tmp/console.log")).is_err());
+    }
+
+    #[test]
+    fn console_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "console", Some("ttyS2")).is_ok());
+        assert_eq!(cfg.console, Some(GuestConsole::Serial(2)));
+        assert!(set_argument(&mut cfg, "console", Some("hvc0")).is_err());
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "console", Some("hvc0")).is_ok());
+        assert_eq!(cfg.console, Some(GuestConsole::Virtio));
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "console", Some("none")).is_ok());
+        assert_eq!(cfg.console, Some(GuestConsole::None));
+
+        for bad in &["ttyS4", "tty0", "serial"] {
+            let mut cfg = Config::default();
+            assert!(set_argument(&mut cfg, "console", Some(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn rtc_offset_argument() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.rtc_offset, 0);
+        assert!(set_argument(&mut cfg, "rtc-offset", Some("-86400")).is_ok());
+        assert_eq!(cfg.rtc_offset, -86400);
+        assert!(set_argument(&mut cfg, "rtc-offset", Some("3600")).is_ok());
+        assert_eq!(cfg.rtc_offset, 3600);
+        assert!(set_argument(&mut cfg, "rtc-offset", Some("1h")).is_err());
+    }
+
+    #[test]
+    fn log_level_argument() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.log_level, None);
+        assert!(set_argument(&mut cfg, "log-level", Some("warning")).is_ok());
+        assert_eq!(cfg.log_level, Some(syslog::Priority::Warning));
+        assert!(set_argument(&mut cfg, "log-level", Some("debug")).is_err());
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "log-level", Some("INFO")).is_ok());
+        assert_eq!(cfg.log_level, Some(syslog::Priority::Info));
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "log-level", Some("verbose")).is_err());
+    }
+
+    #[test]
+    fn trace_bus_argument() {
+        let mut cfg = Config::default();
+        assert!(!cfg.trace_bus);
+        assert!(set_argument(&mut cfg, "trace-bus", None).is_ok());
+        assert!(cfg.trace_bus);
+    }
+
+    #[test]
+    fn numa_nodes_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "numa-nodes", Some("1,0")).is_ok());
+        assert_eq!(cfg.numa_nodes, vec![1, 0]);
+        assert!(set_argument(&mut cfg, "numa-nodes", Some("2")).is_err());
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "numa-nodes", Some("0,x")).is_err());
+    }
+
+    #[test]
+    fn sparse_disk_argument() {
+        let dir = TempDir::new("/tmp/sparse_disk_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "rwdisk", Some(&format!("{},sparse", path))).is_ok());
+        assert!(cfg.disks[0].sparse);
+        assert!(set_argument(&mut cfg, "rwdisk", Some(path)).is_ok());
+        assert!(!cfg.disks[1].sparse);
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},sparse", path))).is_err());
+        assert!(set_argument(&mut cfg, "rwqcow", Some(&format!("{},sparse", path))).is_err());
+    }
+
+    #[test]
+    fn async_disk_argument() {
+        let dir = TempDir::new("/tmp/async_disk_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},async", path))).is_ok());
+        assert!(cfg.disks[0].async_io);
+        assert!(set_argument(&mut cfg, "rwdisk", Some(&format!("{},sparse,async", path)))
+                    .is_err());
+        assert!(set_argument(&mut cfg, "qcow", Some(&format!("{},async", path))).is_err());
+    }
+
+    #[test]
+    fn merge_disk_argument() {
+        let dir = TempDir::new("/tmp/merge_disk_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "qcow", Some(&format!("{},merge", path))).is_ok());
+        assert!(cfg.disks[0].merge_requests);
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},async,merge", path))).is_err());
+    }
+
+    #[test]
+    fn max_transfer_disk_argument() {
+        let dir = TempDir::new("/tmp/max_transfer_disk_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},max_transfer=65536", path)))
+                    .is_ok());
+        assert_eq!(cfg.disks[0].max_transfer, Some(65536));
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},max_transfer=1000", path)))
+                    .is_err());
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},max_transfer=0", path)))
+                    .is_err());
+    }
+
+    #[test]
+    fn cache_disk_argument() {
+        let dir = TempDir::new("/tmp/cache_disk_test").unwrap();
+        let path = dir.as_path().unwrap().join("disk.img");
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "disk", Some(path)).is_ok());
+        assert_eq!(cfg.disks[0].cache_mode, CacheMode::WriteBack);
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},cache=writethrough", path)))
+                    .is_ok());
+        assert_eq!(cfg.disks[1].cache_mode, CacheMode::WriteThrough);
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},cache=none", path))).is_ok());
+        assert_eq!(cfg.disks[2].cache_mode, CacheMode::None);
+        assert!(set_argument(&mut cfg, "qcow", Some(&format!("{},cache=none", path))).is_err());
+        assert!(set_argument(&mut cfg, "disk", Some(&format!("{},cache=fast", path))).is_err());
+    }
+
+    #[test]
+    fn tsc_khz_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "tsc-khz", Some("0")).is_err());
+        assert!(set_argument(&mut cfg, "tsc-khz", Some("fast")).is_err());
+        assert!(set_argument(&mut cfg, "tsc-khz", Some("2000000")).is_ok());
+        assert_eq!(cfg.tsc_khz, Some(2000000));
+        assert!(set_argument(&mut cfg, "tsc-khz", Some("1000000")).is_err());
+    }
+
+    #[test]
+    fn high_mmio_size_argument() {
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "high-mmio-size", Some("big")).is_err());
+        assert!(set_argument(&mut cfg, "high-mmio-size", Some("17592186044416")).is_err());
+        assert!(set_argument(&mut cfg, "high-mmio-size", Some("0")).is_ok());
+        assert_eq!(cfg.high_mmio_size, Some(0));
+        assert!(set_argument(&mut cfg, "high-mmio-size", Some("1024")).is_err());
+    }
+
+    #[test]
+    fn shared_dir_argument() {
+        let dir = TempDir::new("/tmp/shared_dir_test").unwrap();
+        let path = dir.as_path().unwrap();
+        let file_path = path.join("file");
+        File::create(&file_path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "shared-dir", Some(file_path.to_str().unwrap())).is_err());
+        assert!(set_argument(&mut cfg, "shared-dir", Some(&format!("{},ro", path))).is_err());
+        assert!(set_argument(&mut cfg, "shared-dir", Some(&format!("{},tag=", path))).is_err());
+        assert!(set_argument(&mut cfg, "shared-dir", Some(path)).is_ok());
+        assert_eq!(cfg.shared_dir.as_ref().unwrap().1, DEFAULT_SHARED_DIR_TAG);
+
+        let mut cfg = Config::default();
+        assert!(set_argument(&mut cfg, "shared-dir", Some(&format!("{},tag=home", path))).is_ok());
+        assert_eq!(cfg.shared_dir, Some((PathBuf::from(path), "home".to_owned())));
+        assert!(set_argument(&mut cfg, "shared-dir", Some(path)).is_err());
+    }
+}
@@ -27,7 +27,7 @@ use kvm::{Kvm, Vm, Vcpu, VcpuExit, IoeventAddress, NoDatamatch};
 use net_util::{Error as TapError, Tap, TapT};
 use sys_util::{EventFd, MmapError, Killable, SignalFd, SignalFdError, PollContext, PollToken,
                GuestMemory, Result as SysResult, Error as SysError, block_signal, clear_signal,
-               SIGRTMIN, register_signal_handler, geteuid, getegid};
+               SIGRTMIN, register_signal_handler, geteuid, getegid, syslog};
 
 use Config;
 
@@ -418,6 +418,10 @@ enum Token {
 /// Not every field of `cfg` will be used. In particular, most field that pertain to a specific
 /// device are ignored because the plugin is responsible for emulating hardware.
 pub fn run_config(cfg: Config) -> Result<()> {
+    if let Some(level) = cfg.log_level {
+        syslog::set_max_priority(level);
+    }
+
     info!("crosvm starting plugin process");
 
     // Masking signals is inherently dangerous, since this can persist across clones/execs. Do this
@@ -429,7 +433,7 @@ pub fn run_config(cfg: Config) -> Result<()> {
         // An empty directory for jailed plugin pivot root.
         let root_path = match cfg.plugin_root {
             Some(ref dir) => Path::new(dir),
-            None => Path::new("/var/empty"),
+            None => cfg.pivot_root.as_path(),
         };
 
         if root_path.is_relative() {
@@ -6,7 +6,7 @@ use std::mem;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 
-use libc::{read, write, eventfd, dup, c_void};
+use libc::{read, write, eventfd, dup, c_void, poll, pollfd, POLLIN};
 
 use {Result, errno_result};
 
@@ -63,6 +63,30 @@ impl EventFd {
         Ok(buf)
     }
 
+    /// Resets the eventfd's count to zero without blocking and returns what it was.
+    ///
+    /// Any number of writes since the last `read` or `drain` are coalesced into the single count
+    /// returned, so a device worker can handle a burst of queue notifications with one pass over
+    /// the queue. A count of zero means there were no writes. This only avoids blocking if no
+    /// clone of this EventFd reads it concurrently.
+    pub fn drain(&self) -> Result<u64> {
+        let mut pfd = pollfd {
+            fd: self.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        // This is safe because we give a single valid pollfd and a zero timeout, and check the
+        // return value.
+        let ret = unsafe { poll(&mut pfd, 1, 0) };
+        if ret < 0 {
+            return errno_result();
+        }
+        if pfd.revents & POLLIN == 0 {
+            return Ok(0);
+        }
+        self.read()
+    }
+
     /// Clones this EventFd, internally creating a new file descriptor. The new EventFd will share
     /// the same underlying count within the kernel.
     pub fn try_clone(&self) -> Result<EventFd> {
@@ -113,6 +137,18 @@ mod tests {
         assert_eq!(evt.read(), Ok(55));
     }
 
+    #[test]
+    fn drain_coalesces() {
+        let evt = EventFd::new().unwrap();
+        assert_eq!(evt.drain(), Ok(0));
+        evt.write(1).unwrap();
+        evt.write(1).unwrap();
+        evt.write(3).unwrap();
+        assert_eq!(evt.drain(), Ok(5));
+        // The writes were all taken by the single drain, so this doesn't block.
+        assert_eq!(evt.drain(), Ok(0));
+    }
+
     #[test]
     fn clone() {
         let evt = EventFd::new().unwrap();
@@ -4,6 +4,7 @@
 
 //! Track memory regions that are mapped to the guest VM.
 
+use std::cmp::Ordering;
 use std::io::{Read, Write};
 use std::result;
 use std::sync::Arc;
@@ -21,6 +22,7 @@ pub enum Error {
     MemoryAccess(GuestAddress, mmap::Error),
     MemoryMappingFailed(mmap::Error),
     MemoryRegionOverlap,
+    MemoryLockFailed(mmap::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -35,6 +37,8 @@ impl error::Error for Error {
                 "Failed to map guest memory",
             &Error::MemoryRegionOverlap =>
                 "Memory regions overlap",
+            &Error::MemoryLockFailed(_) =>
+                "Failed to lock guest memory",
         }
     }
 }
@@ -58,6 +62,7 @@ fn region_end(region: &MemoryRegion) -> GuestAddress {
 /// Tracks a memory region and where it is mapped in the guest.
 #[derive(Clone)]
 pub struct GuestMemory {
+    // Sorted by `guest_base` and non-overlapping, so a region can be found with a binary search.
     regions: Arc<Vec<MemoryRegion>>,
 }
 
@@ -101,8 +106,7 @@ impl GuestMemory {
     /// ```
     pub fn end_addr(&self) -> GuestAddress {
         self.regions
-            .iter()
-            .max_by_key(|region| region.guest_base)
+            .last()
             .map_or(GuestAddress(0), |region| region_end(region))
     }
 
@@ -140,6 +144,26 @@ impl GuestMemory {
         })
     }
 
+    /// Allocates the host memory backing `count` bytes of guest memory at `addr` from host NUMA
+    /// node `node` only. The range must be inside a single region.
+    pub fn bind_to_numa_node(&self, addr: GuestAddress, count: u64, node: u32) -> Result<()> {
+        self.do_in_region(addr, move |mapping, offset| {
+            mapping
+                .bind_to_numa_node(offset, count as usize, node)
+                .map_err(|e| Error::MemoryAccess(addr, e))
+        })
+    }
+
+    /// Backs all of guest memory with host memory now and locks it there, so the guest never waits
+    /// for the host to fault in or swap in one of its pages.
+    pub fn prefault_and_lock(&self) -> Result<()> {
+        for region in self.regions.iter() {
+            region.mapping.prefault();
+            region.mapping.lock().map_err(Error::MemoryLockFailed)?;
+        }
+        Ok(())
+    }
+
     /// Perform the specified action on each region's addresses.
     pub fn with_regions<F, E>(&self, cb: F) -> result::Result<(), E>
         where F: Fn(usize, GuestAddress, usize, usize) -> result::Result<(), E>
@@ -381,25 +405,35 @@ impl GuestMemory {
     pub fn do_in_region<F, T>(&self, guest_addr: GuestAddress, cb: F) -> Result<T>
         where F: FnOnce(&MemoryMapping, usize) -> Result<T>
     {
-        for region in self.regions.iter() {
-            if guest_addr >= region.guest_base && guest_addr < region_end(region) {
-                return cb(&region.mapping, guest_addr.offset_from(region.guest_base) as usize);
+        match self.find_region(guest_addr) {
+            Some(region) => {
+                cb(&region.mapping, guest_addr.offset_from(region.guest_base) as usize)
             }
+            None => Err(Error::InvalidGuestAddress(guest_addr)),
         }
-        Err(Error::InvalidGuestAddress(guest_addr))
+    }
+
+    // Returns the region containing `guest_addr`, if any.
+    fn find_region(&self, guest_addr: GuestAddress) -> Option<&MemoryRegion> {
+        self.regions
+            .binary_search_by(|region| if guest_addr < region.guest_base {
+                                  Ordering::Greater
+                              } else if guest_addr >= region_end(region) {
+                                  Ordering::Less
+                              } else {
+                                  Ordering::Equal
+                              })
+            .ok()
+            .map(|index| &self.regions[index])
     }
 }
 
 impl VolatileMemory for GuestMemory {
     fn get_slice(&self, offset: u64, count: u64) -> VolatileMemoryResult<VolatileSlice> {
-        for region in self.regions.iter() {
-            if offset >= region.guest_base.0 && offset < region_end(region).0 {
-                return region
-                           .mapping
-                           .get_slice(offset - region.guest_base.0, count);
-            }
+        match self.find_region(GuestAddress(offset)) {
+            Some(region) => region.mapping.get_slice(offset - region.guest_base.0, count),
+            None => Err(VolatileMemoryError::OutOfBounds { addr: offset }),
         }
-        Err(VolatileMemoryError::OutOfBounds { addr: offset })
     }
 }
 
@@ -508,4 +542,29 @@ mod tests {
         let bad_addr = GuestAddress(0x123456);
         assert!(mem.get_host_address(bad_addr).is_err());
     }
+
+    #[test]
+    fn many_regions() {
+        // Regions of one page separated by one page holes.
+        const NUM_REGIONS: u64 = 4096;
+        let ranges: Vec<(GuestAddress, u64)> =
+            (0..NUM_REGIONS).map(|i| (GuestAddress(i * 0x2000), 0x1000)).collect();
+        let mem = GuestMemory::new(&ranges).unwrap();
+        assert_eq!(mem.num_regions(), NUM_REGIONS);
+        assert_eq!(mem.end_addr(), GuestAddress((NUM_REGIONS - 1) * 0x2000 + 0x1000));
+
+        for i in 0..NUM_REGIONS {
+            let base = GuestAddress(i * 0x2000);
+            let last = base.unchecked_add(0xfff);
+            mem.write_obj_at_addr(i as u8, base).unwrap();
+            mem.write_obj_at_addr(!i as u8, last).unwrap();
+            assert_eq!(mem.read_obj_from_addr::<u8>(base).unwrap(), i as u8);
+            assert_eq!(mem.read_obj_from_addr::<u8>(last).unwrap(), !i as u8);
+            assert_eq!(get_mapping(&mem, base).unwrap(), get_mapping(&mem, last).unwrap());
+            // The first and last bytes of the hole after each region aren't mapped.
+            assert!(mem.read_obj_from_addr::<u8>(last.unchecked_add(1)).is_err());
+            assert!(mem.read_obj_from_addr::<u8>(last.unchecked_add(0x1000)).is_err());
+        }
+        assert!(mem.read_obj_from_addr::<u8>(GuestAddress(u64::max_value())).is_err());
+    }
 }
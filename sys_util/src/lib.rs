@@ -111,6 +111,7 @@ pub fn chown(path: &CStr, uid: uid_t, gid: gid_t) -> Result<()> {
 }
 
 /// The operation to perform with `flock`.
+#[derive(Clone, Copy)]
 pub enum FlockOperation {
     LockShared,
     LockExclusive,
@@ -10,9 +10,16 @@ use std::io::{Read, Write};
 use std::ptr::null_mut;
 use std::os::unix::io::AsRawFd;
 
-use libc;
+use libc::{self, c_long, c_ulong, syscall};
 
 use errno;
+use pagesize;
+use syscall_defines::linux::LinuxSyscall::SYS_mbind;
+
+// From the mempolicy.h kernel header.
+const MPOL_BIND: c_long = 2;
+// Nodes that fit in the one word nodemask passed to mbind.
+const MAX_NUMA_NODES: u32 = 64;
 
 use data_model::volatile_memory::*;
 use data_model::DataInit;
@@ -334,6 +341,62 @@ impl MemoryMapping {
         }
     }
 
+    /// Touches every page of the mapping so the host backs all of it with memory now, instead of
+    /// the first time each page is accessed. The contents of the mapping are unchanged.
+    pub fn prefault(&self) {
+        let page_size = pagesize();
+        let mut offset = 0;
+        while offset < self.size {
+            // Safe because the offset is within the mapping and the byte is written back with the
+            // value it already had.
+            unsafe {
+                let p = self.addr.offset(offset as isize);
+                std::ptr::write_volatile(p, std::ptr::read_volatile(p));
+            }
+            offset += page_size;
+        }
+    }
+
+    /// Allocates the pages in `count` bytes at `mem_offset` from host NUMA node `node` only. Pages
+    /// that were already allocated are left where they are, so call this before using the memory.
+    pub fn bind_to_numa_node(&self, mem_offset: usize, count: usize, node: u32) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count))?;
+        if node >= MAX_NUMA_NODES {
+            return Err(Error::SystemCallFailed(errno::Error::new(libc::EINVAL)));
+        }
+        let nodemask: u64 = 1 << node;
+        // This is safe because mbind only changes the placement policy of the range, which was
+        // checked to be inside the mapping, and the kernel only reads the one word of `nodemask`.
+        // The kernel ignores the last of the `maxnode` bits, hence the + 1.
+        let ret = unsafe {
+            syscall(SYS_mbind as c_long,
+                    self.addr as usize + mem_offset,
+                    count,
+                    MPOL_BIND,
+                    &nodemask as *const u64,
+                    MAX_NUMA_NODES as c_ulong + 1,
+                    0)
+        };
+        if ret < 0 {
+            Err(Error::SystemCallFailed(errno::Error::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Locks the mapping into memory so it is never swapped out. Fails if it would exceed the
+    /// RLIMIT_MEMLOCK of the process.
+    pub fn lock(&self) -> Result<()> {
+        // This is safe because mlock doesn't modify the mapping and the return value is checked.
+        let ret = unsafe { libc::mlock(self.addr as *const libc::c_void, self.size) };
+        if ret < 0 {
+            Err(Error::SystemCallFailed(errno::Error::last()))
+        } else {
+            Ok(())
+        }
+    }
+
     unsafe fn as_slice(&self) -> &[u8] {
         // This is safe because we mapped the area at addr ourselves, so this slice will not
         // overflow. However, it is possible to alias.
@@ -413,6 +476,26 @@ mod tests {
         }
     }
 
+    // Number of pages of `m` the host has backed with memory.
+    fn resident_pages(m: &MemoryMapping) -> usize {
+        let mut vec = vec![0u8; (m.size() + pagesize() - 1) / pagesize()];
+        let ret = unsafe {
+            libc::mincore(m.as_ptr() as *mut libc::c_void, m.size(), vec.as_mut_ptr())
+        };
+        assert_eq!(ret, 0);
+        vec.iter().filter(|&v| v & 1 != 0).count()
+    }
+
+    #[test]
+    fn prefault() {
+        let m = MemoryMapping::new(pagesize() * 8).unwrap();
+        m.write_obj(0x55u8, pagesize() * 3).unwrap();
+        m.prefault();
+        assert_eq!(resident_pages(&m), 8);
+        assert_eq!(m.read_obj::<u8>(pagesize() * 3).unwrap(), 0x55);
+        assert_eq!(m.read_obj::<u8>(0).unwrap(), 0);
+    }
+
     #[test]
     fn test_write_past_end() {
         let m = MemoryMapping::new(5).unwrap();
@@ -14,13 +14,34 @@ use std::slice;
 use std::thread;
 use std::time::Duration;
 
-use libc::{c_int, EPOLL_CLOEXEC, EPOLLIN, EPOLLHUP, EPOLL_CTL_ADD, EPOLL_CTL_MOD, EPOLL_CTL_DEL,
+use libc::{c_int, EPOLL_CLOEXEC, EPOLLIN, EPOLLOUT, EPOLLHUP, EPOLL_CTL_ADD, EPOLL_CTL_MOD, EPOLL_CTL_DEL,
            epoll_create1, epoll_ctl, epoll_wait, epoll_event};
 
 use {Result, errno_result};
 
 const POLL_CONTEXT_MAX_EVENTS: usize = 16;
 
+/// The events a `PollContext` watches a file descriptor for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchingEvents(u32);
+
+impl WatchingEvents {
+    /// No events. Hangups and errors are still reported.
+    pub fn empty() -> WatchingEvents {
+        WatchingEvents(0)
+    }
+
+    /// Also watches for the file descriptor becoming readable.
+    pub fn set_read(self) -> WatchingEvents {
+        WatchingEvents(self.0 | EPOLLIN as u32)
+    }
+
+    /// Also watches for the file descriptor becoming writable.
+    pub fn set_write(self) -> WatchingEvents {
+        WatchingEvents(self.0 | EPOLLOUT as u32)
+    }
+}
+
 /// Trait for a token that can be associated with an `fd` in a `PollContext`.
 ///
 /// Simple enums that have no or primitive variant data data can use the `#[derive(PollToken)]`
@@ -113,6 +134,12 @@ impl<'a, T: PollToken> PollEvent<'a, T> {
         self.event.events & (EPOLLIN as u32) != 0
     }
 
+    /// True if the `fd` associated with this token in `PollContext::add_fd_with_events` is
+    /// writable.
+    pub fn writable(&self) -> bool {
+        self.event.events & (EPOLLOUT as u32) != 0
+    }
+
     /// True if the `fd` associated with this token in `PollContext::add` has been hungup on.
     pub fn hungup(&self) -> bool {
         self.event.events & (EPOLLHUP as u32) != 0
@@ -274,8 +301,14 @@ impl<T: PollToken> PollContext<T> {
     /// there were no duplicated file descriptors (i.e. adding the same descriptor with a different
     /// FD number) added to this context, events will not be reported by `wait` anymore.
     pub fn add(&self, fd: &AsRawFd, token: T) -> Result<()> {
+        self.add_fd_with_events(fd, WatchingEvents::empty().set_read(), token)
+    }
+
+    /// Like `add`, but watches the `fd` for the given `events` instead of only for it being
+    /// readable.
+    pub fn add_fd_with_events(&self, fd: &AsRawFd, events: WatchingEvents, token: T) -> Result<()> {
         let mut evt = epoll_event {
-            events: EPOLLIN as u32,
+            events: events.0,
             u64: token.as_raw_token(),
         };
         // Safe because we give a valid epoll FD and FD to watch, as well as a valid epoll_event
@@ -509,6 +542,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn poll_context_writable() {
+        let (s1, _s2) = UnixStream::pair().unwrap();
+        let ctx: PollContext<u32> = PollContext::new().unwrap();
+        ctx.add_fd_with_events(&s1, WatchingEvents::empty().set_write(), 1)
+            .unwrap();
+
+        let events = ctx.wait_timeout(Duration::from_secs(0)).unwrap();
+        let event = events.iter().next().unwrap();
+        assert_eq!(event.token(), 1);
+        assert!(event.writable());
+        assert!(!event.readable());
+    }
+
     #[test]
     #[should_panic]
     fn poll_context_hungup() {
@@ -35,7 +35,7 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::path::PathBuf;
 use std::ptr::null;
-use std::str::from_utf8;
+use std::str::{FromStr, from_utf8};
 use std::sync::{Mutex, MutexGuard, Once, ONCE_INIT};
 
 use libc::{tm, time, time_t, localtime_r, gethostname, openlog, closelog, fcntl, c_char, LOG_NDELAY,
@@ -48,7 +48,7 @@ const SYSLOG_PATH: &'static str = "/dev/log";
 /// The priority (i.e. severity) of a syslog message.
 ///
 /// See syslog man pages for information on their semantics.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Priority {
     Emergency = 0,
     Alert = 1,
@@ -75,6 +75,25 @@ impl fmt::Display for Priority {
     }
 }
 
+impl FromStr for Priority {
+    type Err = ();
+
+    /// Parses a priority from its name, as printed by `Display`, ignoring case.
+    fn from_str(s: &str) -> Result<Priority, ()> {
+        match s.to_uppercase().as_str() {
+            "EMERGENCY" => Ok(Priority::Emergency),
+            "ALERT" => Ok(Priority::Alert),
+            "CRITICAL" => Ok(Priority::Critical),
+            "ERROR" => Ok(Priority::Error),
+            "WARNING" => Ok(Priority::Warning),
+            "NOTICE" => Ok(Priority::Notice),
+            "INFO" => Ok(Priority::Info),
+            "DEBUG" => Ok(Priority::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
 /// The facility of a syslog message.
 ///
 /// See syslog man pages for information on their semantics.
@@ -183,6 +202,7 @@ struct State {
     file: Option<File>,
     hostname: Option<String>,
     proc_name: Option<String>,
+    max_priority: Priority,
 }
 
 impl State {
@@ -194,6 +214,7 @@ impl State {
                file: None,
                hostname: get_hostname().ok(),
                proc_name: get_proc_name(),
+               max_priority: Priority::Debug,
            })
     }
 }
@@ -274,6 +295,17 @@ pub fn set_proc_name<T: Into<String>>(proc_name: T) {
     state.proc_name = Some(proc_name.into());
 }
 
+/// Sets the least severe priority that is still logged. Messages with a less severe priority, such
+/// as `Priority::Debug` when the maximum is `Priority::Info`, are dropped.
+///
+/// The default is `Priority::Debug`, which logs everything.
+///
+/// Does nothing if syslog was never initialized.
+pub fn set_max_priority(pri: Priority) {
+    let mut state = lock!();
+    state.max_priority = pri;
+}
+
 /// Enables or disables echoing log messages to the syslog.
 ///
 /// The default behavior is **enabled**.
@@ -419,6 +451,9 @@ pub fn log(pri: Priority, fac: Facility, file_name: &str, line: u32, args: fmt::
                                         "Sep", "Oct", "Nov", "Dec"];
 
     let mut state = lock!();
+    if pri as u8 > state.max_priority as u8 {
+        return;
+    }
     let mut buf = [0u8; 1024];
     if let Some(ref socket) = state.socket {
         let tm = get_localtime();
@@ -608,4 +643,12 @@ mod tests {
         info!("this is info {}", true);
         debug!("this is debug info {:?}", Some("helpful stuff"));
     }
+
+    #[test]
+    fn parse_priority() {
+        assert_eq!("debug".parse(), Ok(Priority::Debug));
+        assert_eq!("WARNING".parse(), Ok(Priority::Warning));
+        assert_eq!("Error".parse(), Ok(Priority::Error));
+        assert_eq!("verbose".parse::<Priority>(), Err(()));
+    }
 }
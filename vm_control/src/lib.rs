@@ -13,21 +13,28 @@
 extern crate byteorder;
 extern crate data_model;
 extern crate kvm;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern crate kvm_sys;
 extern crate libc;
 extern crate sys_util;
 
+use std::collections::BTreeMap;
+use std::ffi::CStr;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::result;
 
-use libc::{ERANGE, EINVAL, ENODEV};
+use libc::{ERANGE, EFAULT, EINVAL, ENODEV, ENOMEM, SEEK_END, lseek64};
 
-use byteorder::{LittleEndian, WriteBytesExt};
-use data_model::{DataInit, Le32, Le64, VolatileMemory};
-use sys_util::{EventFd, Result, Error as SysError, MmapError, MemoryMapping, Scm, GuestAddress};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use data_model::{DataInit, Le16, Le32, Le64, VolatileMemory};
+use sys_util::{EventFd, Result, Error as SysError, MmapError, MemoryMapping, Scm, GuestAddress,
+               GuestMemory, SharedMemory, pagesize};
 use kvm::{IoeventAddress, Vm};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_sys::{kvm_regs, kvm_segment, kvm_sregs};
 
 #[derive(Debug, PartialEq)]
 /// An error during a request or response transaction.
@@ -43,6 +50,9 @@ pub enum VmControlError {
     BadSize(usize),
     /// There was no associated file descriptor received for a request that expected it.
     ExpectFd,
+    /// The data of a request or response to send is larger than `MAX_MEM_ACCESS_LEN`. The inner
+    /// value is the length of the data.
+    TooLarge(usize),
 }
 
 pub type VmControlResult<T> = result::Result<T, VmControlError>;
@@ -84,14 +94,145 @@ pub enum VmRequest {
     /// Allocate GPU buffer of a given size/format and register the memory into guest address space.
     /// The response variant is `VmResponse::AllocateAndRegisterGpuMemory`
     AllocateAndRegisterGpuMemory { width: u32, height: u32, format: u32 },
+    /// Take a snapshot of the registers of the given vcpu. The response variant is
+    /// `VmResponse::DumpRegs`.
+    DumpRegs { vcpu: u32 },
+    /// Read `len` bytes of guest memory at guest physical address `gpa`. `len` may be at most
+    /// `MAX_MEM_ACCESS_LEN`. The response variant is `VmResponse::ReadMem`.
+    ReadMem { gpa: u64, len: u32 },
+    /// Write `bytes`, at most `MAX_MEM_ACCESS_LEN` of them, to guest memory at guest physical
+    /// address `gpa`.
+    WriteMem { gpa: u64, bytes: Vec<u8> },
+    /// Set the general purpose registers, `rip` and `rflags` of the given vcpu. The segment
+    /// registers in `regs` are ignored.
+    SetRegs { vcpu: u32, regs: VcpuRegs },
+    /// Translate guest virtual address `gva` using the page tables of the given vcpu. The response
+    /// variant is `VmResponse::TranslateAddress`.
+    TranslateAddress { vcpu: u32, gva: u64 },
+    /// Pause, continue or single step the given vcpu.
+    VcpuDebug { vcpu: u32, command: VcpuDebugCommand },
+    /// Replace the hardware breakpoints of the given vcpu with `addrs`, which are guest virtual
+    /// addresses. There may be at most `MAX_BREAKPOINTS` of them.
+    SetBreakpoints { vcpu: u32, addrs: Vec<u64> },
+    /// Deliver `events`, at most `MAX_INPUT_EVENTS` of them, to the guest through the virtio input
+    /// device with index `device`.
+    InputEvents { device: u32, events: Vec<InputEvent> },
+    /// Read the statistics counters of the VM's devices. The response variant is
+    /// `VmResponse::DeviceStats`.
+    DeviceStats,
+    /// Read the feature bits the guest acknowledged for each virtio device. The response variant is
+    /// `VmResponse::VirtioFeatures`.
+    VirtioFeatures,
 }
 
+/// An event in the format of `struct input_event` from `linux/input.h`, less the timestamp, as
+/// delivered by virtio input devices.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// The size of the little endian encoding of an `InputEvent`, which is also the size of a
+/// `struct virtio_input_event`.
+pub const INPUT_EVENT_SIZE: usize = 8;
+
+impl InputEvent {
+    /// Decodes an event from the first `INPUT_EVENT_SIZE` bytes of `bytes`, which must be at least
+    /// that long.
+    pub fn from_bytes(bytes: &[u8]) -> InputEvent {
+        InputEvent {
+            type_: LittleEndian::read_u16(&bytes[0..2]),
+            code: LittleEndian::read_u16(&bytes[2..4]),
+            value: LittleEndian::read_i32(&bytes[4..8]),
+        }
+    }
+
+    /// Encodes this event in the layout of a `struct virtio_input_event`.
+    pub fn to_bytes(&self) -> [u8; INPUT_EVENT_SIZE] {
+        let mut bytes = [0u8; INPUT_EVENT_SIZE];
+        LittleEndian::write_u16(&mut bytes[0..2], self.type_);
+        LittleEndian::write_u16(&mut bytes[2..4], self.code);
+        LittleEndian::write_i32(&mut bytes[4..8], self.value);
+        bytes
+    }
+}
+
+/// How `VmRequest::VcpuDebug` changes the execution of a vcpu.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VcpuDebugCommand {
+    /// Stop running the vcpu until it is continued or stepped.
+    Pause,
+    /// Resume running the vcpu.
+    Continue,
+    /// Resume running the vcpu for a single instruction, after which it pauses again.
+    Step,
+}
+
+/// The largest number of bytes that `VmRequest::ReadMem` and `VmRequest::WriteMem` can access.
+pub const MAX_MEM_ACCESS_LEN: usize = 4096;
+
+/// The largest number of addresses that `VmRequest::SetBreakpoints` can set, which is the number of
+/// x86 debug address registers.
+pub const MAX_BREAKPOINTS: usize = 4;
+
+/// The largest number of events that `VmRequest::InputEvents` can deliver at once.
+pub const MAX_INPUT_EVENTS: usize = 64;
+
+/// The statistics counters of one device, as reported by `VmResponse::DeviceStats`.
+///
+/// "In" counts work toward the guest, such as disk reads and received packets, and "out" counts
+/// work from the guest, such as disk writes and flushes and transmitted packets.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceStats {
+    /// Identifies the device, such as "block0". At most `MAX_DEVICE_NAME_LEN` bytes are sent.
+    pub name: String,
+    pub requests_in: u64,
+    pub bytes_in: u64,
+    pub requests_out: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+}
+
+/// The longest device name `VmResponse::DeviceStats` carries.
+pub const MAX_DEVICE_NAME_LEN: usize = 16;
+
+/// The largest number of devices that `VmResponse::DeviceStats` can report.
+pub const MAX_DEVICE_STATS: usize = 32;
+
+/// The feature bits a guest driver acknowledged for one virtio device, as reported by
+/// `VmResponse::VirtioFeatures`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VirtioFeatures {
+    /// The virtio device type, such as 2 for a block device.
+    pub device_type: u32,
+    pub acked: u64,
+}
+
+/// The largest number of devices that `VmResponse::VirtioFeatures` can report.
+pub const MAX_VIRTIO_FEATURES: usize = 32;
+
 const VM_REQUEST_TYPE_EXIT: u32 = 1;
 const VM_REQUEST_TYPE_REGISTER_MEMORY: u32 = 2;
 const VM_REQUEST_TYPE_UNREGISTER_MEMORY: u32 = 3;
 const VM_REQUEST_TYPE_BALLOON_ADJUST: u32 = 4;
 const VM_REQUEST_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY: u32 = 5;
-const VM_REQUEST_SIZE: usize = 32;
+const VM_REQUEST_TYPE_DUMP_REGS: u32 = 6;
+const VM_REQUEST_TYPE_READ_MEM: u32 = 7;
+const VM_REQUEST_TYPE_WRITE_MEM: u32 = 8;
+const VM_REQUEST_TYPE_SET_REGS: u32 = 9;
+const VM_REQUEST_TYPE_TRANSLATE_ADDRESS: u32 = 10;
+const VM_REQUEST_TYPE_VCPU_DEBUG: u32 = 11;
+const VM_REQUEST_TYPE_SET_BREAKPOINTS: u32 = 12;
+const VM_REQUEST_TYPE_INPUT_EVENTS: u32 = 13;
+const VM_REQUEST_TYPE_DEVICE_STATS: u32 = 14;
+const VM_REQUEST_TYPE_VIRTIO_FEATURES: u32 = 15;
+const VM_REQUEST_SIZE: usize = 56;
+
+const VCPU_DEBUG_COMMAND_PAUSE: u32 = 1;
+const VCPU_DEBUG_COMMAND_CONTINUE: u32 = 2;
+const VCPU_DEBUG_COMMAND_STEP: u32 = 3;
 
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
@@ -103,33 +244,409 @@ struct VmRequestStruct {
     width: Le32,
     height: Le32,
     format: Le32,
+    vcpu: Le32,
+    len: Le32,
+    gpa: Le64,
+    command: Le32,
+    device: Le32,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VmRequestStruct {}
 
-fn register_memory(vm: &mut Vm, next_mem_pfn: &mut u64, fd: &AsRawFd, size: usize) -> Result<(u64, u32)> {
+/// Hands out ranges of guest page frames for device memory, such as the shared memory and dmabufs
+/// the wayland device maps into the guest. Ranges are returned when the memory slot they were
+/// registered in is removed, so the address space gets reused.
+pub struct DevicePfnAllocator {
+    // First page frame that was never handed out.
+    next_pfn: u64,
+    // First page frame past the end of the window.
+    end_pfn: u64,
+    // Ranges below `next_pfn` that were returned, as number of pages keyed by first page frame.
+    free_ranges: BTreeMap<u64, u64>,
+    // Ranges in use, keyed by the memory slot they are registered in.
+    slots: BTreeMap<u32, (u64, u64)>,
+}
+
+impl DevicePfnAllocator {
+    /// Constructs an allocator for the window of `num_pages` page frames starting at `base_pfn`.
+    pub fn new(base_pfn: u64, num_pages: u64) -> DevicePfnAllocator {
+        DevicePfnAllocator {
+            next_pfn: base_pfn,
+            end_pfn: base_pfn + num_pages,
+            free_ranges: BTreeMap::new(),
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the first page frame of `pages` consecutive page frames, or None if there is no
+    /// room left for them in the window.
+    pub fn allocate(&mut self, pages: u64) -> Option<u64> {
+        let found = self.free_ranges
+            .iter()
+            .find(|&(_, &len)| len >= pages)
+            .map(|(&pfn, &len)| (pfn, len));
+        match found {
+            Some((pfn, len)) => {
+                self.free_ranges.remove(&pfn);
+                if len > pages {
+                    self.free_ranges.insert(pfn + pages, len - pages);
+                }
+                Some(pfn)
+            }
+            None => {
+                if self.end_pfn - self.next_pfn < pages {
+                    return None;
+                }
+                let pfn = self.next_pfn;
+                self.next_pfn += pages;
+                Some(pfn)
+            }
+        }
+    }
+
+    /// Returns the `pages` page frames starting at `pfn` to the allocator.
+    pub fn free(&mut self, mut pfn: u64, mut pages: u64) {
+        // Merge with the neighboring free ranges.
+        let next = pfn + pages;
+        if let Some(len) = self.free_ranges.remove(&next) {
+            pages += len;
+        }
+        let prev = self.free_ranges
+            .range(..pfn)
+            .next_back()
+            .map(|(&p, &len)| (p, len));
+        if let Some((prev_pfn, prev_len)) = prev {
+            if prev_pfn + prev_len == pfn {
+                self.free_ranges.remove(&prev_pfn);
+                pfn = prev_pfn;
+                pages += prev_len;
+            }
+        }
+        if pfn + pages == self.next_pfn {
+            self.next_pfn = pfn;
+        } else {
+            self.free_ranges.insert(pfn, pages);
+        }
+    }
+
+    // Records that the range at `pfn` is registered in memory slot `slot`.
+    fn set_slot(&mut self, slot: u32, pfn: u64, pages: u64) {
+        self.slots.insert(slot, (pfn, pages));
+    }
+
+    // True if memory slot `slot` was registered with a range from this allocator.
+    fn has_slot(&self, slot: u32) -> bool {
+        self.slots.contains_key(&slot)
+    }
+
+    // Frees the range registered in memory slot `slot`, if any.
+    fn free_slot(&mut self, slot: u32) {
+        if let Some((pfn, pages)) = self.slots.remove(&slot) {
+            self.free(pfn, pages);
+        }
+    }
+}
+
+fn register_memory(vm: &mut Vm,
+                   pfn_allocator: &mut DevicePfnAllocator,
+                   fd: &AsRawFd,
+                   size: usize)
+                   -> Result<(u64, u32)> {
+    // Safe because lseek doesn't touch memory and the return value is checked.
+    let fd_size = unsafe { lseek64(fd.as_raw_fd(), 0, SEEK_END) };
+    if fd_size < 0 {
+        return Err(SysError::last());
+    }
+    // Pages of the mapping past the end of the file can't be accessed.
+    if size == 0 || size as u64 > fd_size as u64 {
+        return Err(SysError::new(EINVAL));
+    }
     let mmap = match MemoryMapping::from_fd(fd, size) {
         Ok(v) => v,
         Err(MmapError::SystemCallFailed(e)) => return Err(e),
         _ => return Err(SysError::new(EINVAL)),
     };
-    let pfn = *next_mem_pfn;
+    // Leave an unmapped page after each chunk of device memory.
+    let pages = ((size as u64 + 0xfff) >> 12) + 1;
+    let pfn = match pfn_allocator.allocate(pages) {
+        Some(v) => v,
+        None => return Err(SysError::new(ENOMEM)),
+    };
     let slot =
         match vm.add_device_memory(GuestAddress(pfn << 12), mmap, false, false) {
             Ok(v) => v,
-            Err(e) => return Err(e),
+            Err(e) => {
+                pfn_allocator.free(pfn, pages);
+                return Err(e);
+            }
         };
-    // TODO(zachr): Use a smarter allocation strategy. The current strategy is just
-    // bumping this pointer, meaning the remove operation does not free any address
-    // space. Given enough allocations, device memory may run out of address space and
-    // collide with guest memory or MMIO address space. There is currently nothing in
-    // place to limit the amount of address space used by device memory.
-    *next_mem_pfn += (((size + 0x7ff) >> 12) + 1) as u64;
+    pfn_allocator.set_slot(slot, pfn, pages);
 
     Ok((pfn, slot))
 }
 
+/// A segment register, as seen by the vcpu.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuSegment {
+    pub base: u64,
+    pub limit: u32,
+    pub selector: u16,
+    pub type_: u8,
+    pub present: bool,
+    pub dpl: u8,
+    pub db: bool,
+    pub s: bool,
+    pub l: bool,
+    pub g: bool,
+    pub avl: bool,
+}
+
+/// A snapshot of the general purpose and segment registers of a vcpu.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuRegs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: VcpuSegment,
+    pub ds: VcpuSegment,
+    pub es: VcpuSegment,
+    pub fs: VcpuSegment,
+    pub gs: VcpuSegment,
+    pub ss: VcpuSegment,
+    pub tr: VcpuSegment,
+    pub ldt: VcpuSegment,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_segment> for VcpuSegment {
+    fn from(seg: kvm_segment) -> VcpuSegment {
+        VcpuSegment {
+            base: seg.base,
+            limit: seg.limit,
+            selector: seg.selector,
+            type_: seg.type_,
+            present: seg.present != 0,
+            dpl: seg.dpl,
+            db: seg.db != 0,
+            s: seg.s != 0,
+            l: seg.l != 0,
+            g: seg.g != 0,
+            avl: seg.avl != 0,
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl VcpuRegs {
+    /// Collects the registers returned by `Vcpu::get_regs` and `Vcpu::get_sregs`.
+    pub fn from_kvm(regs: &kvm_regs, sregs: &kvm_sregs) -> VcpuRegs {
+        VcpuRegs {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.rflags,
+            cs: sregs.cs.into(),
+            ds: sregs.ds.into(),
+            es: sregs.es.into(),
+            fs: sregs.fs.into(),
+            gs: sregs.gs.into(),
+            ss: sregs.ss.into(),
+            tr: sregs.tr.into(),
+            ldt: sregs.ldt.into(),
+        }
+    }
+}
+
+// The segment attributes are packed into `flags` at the same bit positions as in the upper half of
+// a segment descriptor, less its limit bits: type in 0-3, s in 4, dpl in 5-6, present in 7, avl in
+// 12, l in 13, db in 14 and g in 15.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VcpuSegmentStruct {
+    base: Le64,
+    limit: Le32,
+    selector: Le16,
+    flags: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VcpuSegmentStruct {}
+
+impl From<VcpuSegment> for VcpuSegmentStruct {
+    fn from(seg: VcpuSegment) -> VcpuSegmentStruct {
+        let flags = (seg.type_ as u16 & 0xf) | (seg.s as u16) << 4 | (seg.dpl as u16 & 0x3) << 5 |
+                    (seg.present as u16) << 7 | (seg.avl as u16) << 12 |
+                    (seg.l as u16) << 13 | (seg.db as u16) << 14 |
+                    (seg.g as u16) << 15;
+        VcpuSegmentStruct {
+            base: Le64::from(seg.base),
+            limit: Le32::from(seg.limit),
+            selector: Le16::from(seg.selector),
+            flags: Le16::from(flags),
+        }
+    }
+}
+
+impl From<VcpuSegmentStruct> for VcpuSegment {
+    fn from(seg: VcpuSegmentStruct) -> VcpuSegment {
+        let flags: u16 = seg.flags.into();
+        VcpuSegment {
+            base: seg.base.into(),
+            limit: seg.limit.into(),
+            selector: seg.selector.into(),
+            type_: (flags & 0xf) as u8,
+            s: flags & (1 << 4) != 0,
+            dpl: ((flags >> 5) & 0x3) as u8,
+            present: flags & (1 << 7) != 0,
+            avl: flags & (1 << 12) != 0,
+            l: flags & (1 << 13) != 0,
+            db: flags & (1 << 14) != 0,
+            g: flags & (1 << 15) != 0,
+        }
+    }
+}
+
+const VCPU_REGS_GP_COUNT: usize = 18;
+const VCPU_REGS_SIZE: usize = 272;
+
+// Sent after the `VmResponseStruct` of a `VmResponse::DumpRegs`. The general purpose registers are
+// in the order of the fields of `VcpuRegs`, as are the segments.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VcpuRegsStruct {
+    gp: [Le64; VCPU_REGS_GP_COUNT],
+    segments: [VcpuSegmentStruct; 8],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VcpuRegsStruct {}
+
+impl From<VcpuRegs> for VcpuRegsStruct {
+    fn from(r: VcpuRegs) -> VcpuRegsStruct {
+        let gp = [r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rsp, r.rbp, r.r8, r.r9, r.r10,
+                  r.r11, r.r12, r.r13, r.r14, r.r15, r.rip, r.rflags];
+        let segments = [r.cs, r.ds, r.es, r.fs, r.gs, r.ss, r.tr, r.ldt];
+        let mut regs = VcpuRegsStruct::default();
+        for (dst, &src) in regs.gp.iter_mut().zip(gp.iter()) {
+            *dst = Le64::from(src);
+        }
+        for (dst, &src) in regs.segments.iter_mut().zip(segments.iter()) {
+            *dst = src.into();
+        }
+        regs
+    }
+}
+
+impl From<VcpuRegsStruct> for VcpuRegs {
+    fn from(r: VcpuRegsStruct) -> VcpuRegs {
+        let gp = |i: usize| r.gp[i].to_native();
+        let seg = |i: usize| VcpuSegment::from(r.segments[i]);
+        VcpuRegs {
+            rax: gp(0),
+            rbx: gp(1),
+            rcx: gp(2),
+            rdx: gp(3),
+            rsi: gp(4),
+            rdi: gp(5),
+            rsp: gp(6),
+            rbp: gp(7),
+            r8: gp(8),
+            r9: gp(9),
+            r10: gp(10),
+            r11: gp(11),
+            r12: gp(12),
+            r13: gp(13),
+            r14: gp(14),
+            r15: gp(15),
+            rip: gp(16),
+            rflags: gp(17),
+            cs: seg(0),
+            ds: seg(1),
+            es: seg(2),
+            fs: seg(3),
+            gs: seg(4),
+            ss: seg(5),
+            tr: seg(6),
+            ldt: seg(7),
+        }
+    }
+}
+
+fn check_mem_access(mem: &GuestMemory, gpa: GuestAddress, len: usize) -> Result<()> {
+    if len > MAX_MEM_ACCESS_LEN {
+        return Err(SysError::new(EINVAL));
+    }
+    let last = gpa.checked_add((len as u64).saturating_sub(1))
+        .ok_or(SysError::new(EFAULT))?;
+    if !mem.address_in_range(gpa) || !mem.address_in_range(last) {
+        return Err(SysError::new(EFAULT));
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes of guest memory at `gpa` for `VmRequest::ReadMem`.
+pub fn read_guest_memory(mem: &GuestMemory, gpa: GuestAddress, len: usize) -> Result<Vec<u8>> {
+    check_mem_access(mem, gpa, len)?;
+    let mut bytes = Vec::with_capacity(len);
+    mem.write_from_memory(gpa, &mut bytes, len)
+        .map_err(|_| SysError::new(EFAULT))?;
+    Ok(bytes)
+}
+
+/// Writes `bytes` to guest memory at `gpa` for `VmRequest::WriteMem`.
+pub fn write_guest_memory(mem: &GuestMemory, gpa: GuestAddress, bytes: &[u8]) -> Result<()> {
+    check_mem_access(mem, gpa, bytes.len())?;
+    mem.read_to_memory(gpa, &mut &bytes[..], bytes.len())
+        .map_err(|_| SysError::new(EFAULT))
+}
+
+/// Trait that needs to be implemented in order to service the requests that operate on a single
+/// vcpu, such as `VmRequest::DumpRegs` and `VmRequest::VcpuDebug`. Vcpus run on their own threads,
+/// so implementations need to get the vcpu's thread to carry out the operation.
+pub trait VcpuControl {
+    /// Returns the current registers of the vcpu with index `vcpu`.
+    fn snapshot_regs(&self, vcpu: u32) -> Result<VcpuRegs>;
+    /// Sets the general purpose registers, `rip` and `rflags` of the vcpu with index `vcpu`.
+    fn set_regs(&self, vcpu: u32, regs: VcpuRegs) -> Result<()>;
+    /// Translates `gva` to a guest physical address with the page tables of the vcpu with index
+    /// `vcpu`.
+    fn translate_address(&self, vcpu: u32, gva: u64) -> Result<u64>;
+    /// Pauses, continues or steps the vcpu with index `vcpu`.
+    fn debug(&self, vcpu: u32, command: VcpuDebugCommand) -> Result<()>;
+    /// Replaces the hardware breakpoints of the vcpu with index `vcpu`.
+    fn set_breakpoints(&self, vcpu: u32, addrs: &[u64]) -> Result<()>;
+}
+
 /// Trait that needs to be implemented in order to service GPU memory allocation
 /// requests. Implementations are expected to support some set of buffer sizes and
 /// formats but every possible combination is not required.
@@ -145,22 +662,93 @@ pub trait GpuMemoryAllocator {
     fn allocate(&self, width: u32, height: u32, format: u32) -> Result<(File, u32)>;
 }
 
+fn fourcc(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    a as u32 | (b as u32) << 8 | (c as u32) << 16 | (d as u32) << 24
+}
+
+// Returns the size of a pixel of a packed single plane format, or `None` if `format` isn't one.
+fn packed_bytes_per_pixel(format: u32) -> Option<u32> {
+    if format == fourcc(b'R', b'8', b' ', b' ') {
+        Some(1)
+    } else if format == fourcc(b'R', b'G', b'1', b'6') || format == fourcc(b'G', b'R', b'8', b'8') {
+        Some(2)
+    } else if format == fourcc(b'R', b'G', b'2', b'4') || format == fourcc(b'B', b'G', b'2', b'4') {
+        Some(3)
+    } else if format == fourcc(b'X', b'R', b'2', b'4') || format == fourcc(b'A', b'R', b'2', b'4') ||
+              format == fourcc(b'X', b'B', b'2', b'4') ||
+              format == fourcc(b'A', b'B', b'2', b'4') {
+        Some(4)
+    } else {
+        None
+    }
+}
+
+/// A `GpuMemoryAllocator` backed by shared memory, for hosts without a render node. Only packed
+/// single plane formats are supported, and the buffers are only good for CPU access.
+pub struct SharedMemoryAllocator;
+
+impl GpuMemoryAllocator for SharedMemoryAllocator {
+    fn allocate(&self, width: u32, height: u32, format: u32) -> Result<(File, u32)> {
+        let bytes_per_pixel = packed_bytes_per_pixel(format).ok_or(SysError::new(EINVAL))?;
+        let stride = width
+            .checked_mul(bytes_per_pixel)
+            .ok_or(SysError::new(EINVAL))?;
+        let size = stride as u64 * height as u64;
+        if size == 0 {
+            return Err(SysError::new(EINVAL));
+        }
+        // The buffer gets registered as guest memory, which is made of whole pages.
+        let page_mask = pagesize() as u64 - 1;
+        let size = (size + page_mask) & !page_mask;
+
+        let name = CStr::from_bytes_with_nul(b"crosvm_gpu_buffer\0").unwrap();
+        let mut shm = SharedMemory::new(Some(name))?;
+        shm.set_size(size)?;
+        Ok((shm.into(), stride))
+    }
+}
+
 impl VmRequest {
     /// Receive a `VmRequest` from the given socket.
     ///
     /// A `VmResponse` should be sent out over the given socket before another request is received.
     pub fn recv(scm: &mut Scm, s: &UnixDatagram) -> VmControlResult<VmRequest> {
         assert_eq!(VM_REQUEST_SIZE, std::mem::size_of::<VmRequestStruct>());
-        let mut buf = [0; VM_REQUEST_SIZE];
+        assert_eq!(VCPU_REGS_SIZE, std::mem::size_of::<VcpuRegsStruct>());
+        // Large enough for the biggest request, which is `WriteMem`.
+        assert!(MAX_INPUT_EVENTS * INPUT_EVENT_SIZE <= MAX_MEM_ACCESS_LEN);
+        let mut buf = [0; VM_REQUEST_SIZE + MAX_MEM_ACCESS_LEN];
         let mut fds = Vec::new();
-        let read = scm.recv(s, &mut [&mut buf], &mut fds)
+        let read = scm.recv(s, &mut [&mut buf[..]], &mut fds)
             .map_err(|e| VmControlError::Recv(e))?;
-        if read != VM_REQUEST_SIZE {
+        if read < VM_REQUEST_SIZE {
             return Err(VmControlError::BadSize(read));
         }
         // The unwrap() will never fail because it's referencing a buf statically sized to be large
         // enough for a VmRequestStruct.
-        let req: VmRequestStruct = buf.as_mut().get_ref(0).unwrap().load();
+        let req: VmRequestStruct = (&mut buf[..]).get_ref(0).unwrap().load();
+        let expected_size = match req.type_.into() {
+            VM_REQUEST_TYPE_WRITE_MEM => VM_REQUEST_SIZE + req.len.to_native() as usize,
+            VM_REQUEST_TYPE_SET_REGS => VM_REQUEST_SIZE + VCPU_REGS_SIZE,
+            VM_REQUEST_TYPE_SET_BREAKPOINTS => {
+                let count = req.len.to_native() as usize;
+                if count > MAX_BREAKPOINTS {
+                    return Err(VmControlError::TooLarge(count));
+                }
+                VM_REQUEST_SIZE + count * 8
+            }
+            VM_REQUEST_TYPE_INPUT_EVENTS => {
+                let count = req.len.to_native() as usize;
+                if count > MAX_INPUT_EVENTS {
+                    return Err(VmControlError::TooLarge(count));
+                }
+                VM_REQUEST_SIZE + count * INPUT_EVENT_SIZE
+            }
+            _ => VM_REQUEST_SIZE,
+        };
+        if read != expected_size {
+            return Err(VmControlError::BadSize(read));
+        }
 
         match req.type_.into() {
             VM_REQUEST_TYPE_EXIT => Ok(VmRequest::Exit),
@@ -179,6 +767,68 @@ impl VmRequest {
                                                              format: req.format.to_native()
                     })
             },
+            VM_REQUEST_TYPE_DUMP_REGS => Ok(VmRequest::DumpRegs { vcpu: req.vcpu.into() }),
+            VM_REQUEST_TYPE_READ_MEM => {
+                Ok(VmRequest::ReadMem {
+                       gpa: req.gpa.into(),
+                       len: req.len.into(),
+                   })
+            }
+            VM_REQUEST_TYPE_WRITE_MEM => {
+                Ok(VmRequest::WriteMem {
+                       gpa: req.gpa.into(),
+                       bytes: buf[VM_REQUEST_SIZE..read].to_vec(),
+                   })
+            }
+            VM_REQUEST_TYPE_SET_REGS => {
+                // The unwrap() will never fail because `read` was checked to include the registers.
+                let regs: VcpuRegsStruct =
+                    (&mut buf[..]).get_ref(VM_REQUEST_SIZE as u64).unwrap().load();
+                Ok(VmRequest::SetRegs {
+                       vcpu: req.vcpu.into(),
+                       regs: regs.into(),
+                   })
+            }
+            VM_REQUEST_TYPE_TRANSLATE_ADDRESS => {
+                Ok(VmRequest::TranslateAddress {
+                       vcpu: req.vcpu.into(),
+                       gva: req.gpa.into(),
+                   })
+            }
+            VM_REQUEST_TYPE_VCPU_DEBUG => {
+                let command = match req.command.into() {
+                    VCPU_DEBUG_COMMAND_PAUSE => VcpuDebugCommand::Pause,
+                    VCPU_DEBUG_COMMAND_CONTINUE => VcpuDebugCommand::Continue,
+                    VCPU_DEBUG_COMMAND_STEP => VcpuDebugCommand::Step,
+                    _ => return Err(VmControlError::InvalidType),
+                };
+                Ok(VmRequest::VcpuDebug {
+                       vcpu: req.vcpu.into(),
+                       command: command,
+                   })
+            }
+            VM_REQUEST_TYPE_SET_BREAKPOINTS => {
+                let addrs = buf[VM_REQUEST_SIZE..read]
+                    .chunks(8)
+                    .map(|addr| LittleEndian::read_u64(addr))
+                    .collect();
+                Ok(VmRequest::SetBreakpoints {
+                       vcpu: req.vcpu.into(),
+                       addrs: addrs,
+                   })
+            }
+            VM_REQUEST_TYPE_INPUT_EVENTS => {
+                let events = buf[VM_REQUEST_SIZE..read]
+                    .chunks(INPUT_EVENT_SIZE)
+                    .map(InputEvent::from_bytes)
+                    .collect();
+                Ok(VmRequest::InputEvents {
+                       device: req.device.into(),
+                       events: events,
+                   })
+            }
+            VM_REQUEST_TYPE_DEVICE_STATS => Ok(VmRequest::DeviceStats),
+            VM_REQUEST_TYPE_VIRTIO_FEATURES => Ok(VmRequest::VirtioFeatures),
             _ => Err(VmControlError::InvalidType),
         }
     }
@@ -192,6 +842,10 @@ impl VmRequest {
         let mut req = VmRequestStruct::default();
         let mut fd_buf = [0; 1];
         let mut fd_len = 0;
+        let mut regs_buf = [0; VCPU_REGS_SIZE];
+        let mut addrs_buf = [0; MAX_BREAKPOINTS * 8];
+        let mut events_buf = Vec::new();
+        let mut data: &[u8] = &[];
         match self {
             &VmRequest::Exit => req.type_ = Le32::from(VM_REQUEST_TYPE_EXIT),
             &VmRequest::RegisterMemory(ref fd, size) => {
@@ -214,11 +868,79 @@ impl VmRequest {
                 req.height = Le32::from(height as u32);
                 req.format = Le32::from(format as u32);
             },
+            &VmRequest::DumpRegs { vcpu } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_DUMP_REGS);
+                req.vcpu = Le32::from(vcpu);
+            }
+            &VmRequest::ReadMem { gpa, len } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_READ_MEM);
+                req.gpa = Le64::from(gpa);
+                req.len = Le32::from(len);
+            }
+            &VmRequest::WriteMem { gpa, ref bytes } => {
+                if bytes.len() > MAX_MEM_ACCESS_LEN {
+                    return Err(VmControlError::TooLarge(bytes.len()));
+                }
+                req.type_ = Le32::from(VM_REQUEST_TYPE_WRITE_MEM);
+                req.gpa = Le64::from(gpa);
+                req.len = Le32::from(bytes.len() as u32);
+                data = bytes;
+            }
+            &VmRequest::SetRegs { vcpu, regs } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_SET_REGS);
+                req.vcpu = Le32::from(vcpu);
+                (&mut regs_buf[..]).get_ref(0).unwrap().store(VcpuRegsStruct::from(regs));
+                data = &regs_buf[..];
+            }
+            &VmRequest::TranslateAddress { vcpu, gva } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_TRANSLATE_ADDRESS);
+                req.vcpu = Le32::from(vcpu);
+                req.gpa = Le64::from(gva);
+            }
+            &VmRequest::VcpuDebug { vcpu, command } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_VCPU_DEBUG);
+                req.vcpu = Le32::from(vcpu);
+                req.command = Le32::from(match command {
+                                             VcpuDebugCommand::Pause => VCPU_DEBUG_COMMAND_PAUSE,
+                                             VcpuDebugCommand::Continue => {
+                                                 VCPU_DEBUG_COMMAND_CONTINUE
+                                             }
+                                             VcpuDebugCommand::Step => VCPU_DEBUG_COMMAND_STEP,
+                                         });
+            }
+            &VmRequest::SetBreakpoints { vcpu, ref addrs } => {
+                if addrs.len() > MAX_BREAKPOINTS {
+                    return Err(VmControlError::TooLarge(addrs.len()));
+                }
+                req.type_ = Le32::from(VM_REQUEST_TYPE_SET_BREAKPOINTS);
+                req.vcpu = Le32::from(vcpu);
+                req.len = Le32::from(addrs.len() as u32);
+                for (dst, &addr) in addrs_buf.chunks_mut(8).zip(addrs.iter()) {
+                    LittleEndian::write_u64(dst, addr);
+                }
+                data = &addrs_buf[..addrs.len() * 8];
+            }
+            &VmRequest::InputEvents { device, ref events } => {
+                if events.len() > MAX_INPUT_EVENTS {
+                    return Err(VmControlError::TooLarge(events.len()));
+                }
+                req.type_ = Le32::from(VM_REQUEST_TYPE_INPUT_EVENTS);
+                req.device = Le32::from(device);
+                req.len = Le32::from(events.len() as u32);
+                for event in events {
+                    events_buf.extend_from_slice(&event.to_bytes());
+                }
+                data = &events_buf[..];
+            }
+            &VmRequest::DeviceStats => req.type_ = Le32::from(VM_REQUEST_TYPE_DEVICE_STATS),
+            &VmRequest::VirtioFeatures => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_VIRTIO_FEATURES)
+            }
             _ => return Err(VmControlError::InvalidType),
         }
         let mut buf = [0; VM_REQUEST_SIZE];
         buf.as_mut().get_ref(0).unwrap().store(req);
-        scm.send(s, &[buf.as_ref()], &fd_buf[..fd_len])
+        scm.send(s, &[buf.as_ref(), data], &fd_buf[..fd_len])
             .map_err(|e| VmControlError::Send(e))?;
         Ok(())
     }
@@ -227,16 +949,20 @@ impl VmRequest {
     ///
     /// # Arguments
     /// * `vm` - The `Vm` to perform the request on.
-    /// * `next_mem_pfn` - In/out argument for the page frame number to put the next chunk of device
-    /// memory into.
+    /// * `pfn_allocator` - Allocates the page frames to put device memory into.
     /// * `running` - Out argument that is set to false if the request was to stop running the VM.
+    /// * `input_host_sockets` - Sockets to forward the events of `InputEvents` requests to, indexed
+    /// by input device.
+    /// * `vcpu_control` - Carries out requests that operate on a single vcpu.
     ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmResponse` with the intended purpose of sending the response back over the  socket that
     /// received this `VmRequest`.
-    pub fn execute(&self, vm: &mut Vm, next_mem_pfn: &mut u64, running: &mut bool,
+    pub fn execute(&self, vm: &mut Vm, pfn_allocator: &mut DevicePfnAllocator, running: &mut bool,
                    balloon_host_socket: &UnixDatagram,
-                   gpu_memory_allocator: Option<&GpuMemoryAllocator>) -> VmResponse {
+                   input_host_sockets: &[UnixDatagram],
+                   gpu_memory_allocator: Option<&GpuMemoryAllocator>,
+                   vcpu_control: &VcpuControl) -> VmResponse {
         *running = true;
         match self {
             &VmRequest::Exit => {
@@ -256,14 +982,21 @@ impl VmRequest {
                 }
             }
             &VmRequest::RegisterMemory(ref fd, size) => {
-                match register_memory(vm, next_mem_pfn, fd, size) {
+                match register_memory(vm, pfn_allocator, fd, size) {
                     Ok((pfn, slot)) => VmResponse::RegisterMemory { pfn, slot },
                     Err(e) => VmResponse::Err(e),
                 }
             }
             &VmRequest::UnregisterMemory(slot) => {
+                // Only slots registered by a `VmRequest` may be removed this way.
+                if !pfn_allocator.has_slot(slot) {
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
                 match vm.remove_device_memory(slot) {
-                    Ok(_) => VmResponse::Ok,
+                    Ok(_) => {
+                        pfn_allocator.free_slot(slot);
+                        VmResponse::Ok
+                    }
                     Err(e) => VmResponse::Err(e),
                 }
             }
@@ -291,7 +1024,7 @@ impl VmRequest {
                     Ok(v) => v,
                     Err(e) => return VmResponse::Err(SysError::from(e)),
                 };
-                match register_memory(vm, next_mem_pfn, &fd, size as usize) {
+                match register_memory(vm, pfn_allocator, &fd, size as usize) {
                     Ok((pfn, slot)) => VmResponse::AllocateAndRegisterGpuMemory {
                         fd: MaybeOwnedFd::Owned(fd),
                         pfn,
@@ -300,6 +1033,68 @@ impl VmRequest {
                     Err(e) => VmResponse::Err(e),
                 }
             }
+            &VmRequest::DumpRegs { vcpu } => {
+                match vcpu_control.snapshot_regs(vcpu) {
+                    Ok(regs) => VmResponse::DumpRegs(regs),
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::ReadMem { gpa, len } => {
+                match read_guest_memory(vm.get_memory(), GuestAddress(gpa), len as usize) {
+                    Ok(bytes) => VmResponse::ReadMem(bytes),
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::WriteMem { gpa, ref bytes } => {
+                match write_guest_memory(vm.get_memory(), GuestAddress(gpa), bytes) {
+                    Ok(()) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::SetRegs { vcpu, regs } => {
+                match vcpu_control.set_regs(vcpu, regs) {
+                    Ok(()) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::TranslateAddress { vcpu, gva } => {
+                match vcpu_control.translate_address(vcpu, gva) {
+                    Ok(gpa) => VmResponse::TranslateAddress { gpa: gpa },
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::VcpuDebug { vcpu, command } => {
+                match vcpu_control.debug(vcpu, command) {
+                    Ok(()) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::SetBreakpoints { vcpu, ref addrs } => {
+                if addrs.len() > MAX_BREAKPOINTS {
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                match vcpu_control.set_breakpoints(vcpu, addrs) {
+                    Ok(()) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::InputEvents { device, ref events } => {
+                let socket = match input_host_sockets.get(device as usize) {
+                    Some(s) => s,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                let mut buf = Vec::with_capacity(events.len() * INPUT_EVENT_SIZE);
+                for event in events {
+                    buf.extend_from_slice(&event.to_bytes());
+                }
+                match socket.send(&buf) {
+                    Ok(_) => VmResponse::Ok,
+                    Err(_) => VmResponse::Err(SysError::last()),
+                }
+            }
+            // The counters belong to the devices, so the owner of the devices answers this.
+            &VmRequest::DeviceStats => VmResponse::Err(SysError::new(ENODEV)),
+            &VmRequest::VirtioFeatures => VmResponse::Err(SysError::new(ENODEV)),
         }
     }
 }
@@ -318,13 +1113,29 @@ pub enum VmResponse {
     /// The request to allocate and register GPU memory into guest address space was successfully
     /// done at page frame number `pfn` and memory slot number `slot` for buffer with `stride`.
     AllocateAndRegisterGpuMemory { fd: MaybeOwnedFd, pfn: u64, slot: u32, stride: u32 },
+    /// The registers of the vcpu requested by `VmRequest::DumpRegs`.
+    DumpRegs(VcpuRegs),
+    /// The guest memory requested by `VmRequest::ReadMem`.
+    ReadMem(Vec<u8>),
+    /// The guest physical address that the address of `VmRequest::TranslateAddress` maps to.
+    TranslateAddress { gpa: u64 },
+    /// The statistics counters of each device, requested by `VmRequest::DeviceStats`.
+    DeviceStats(Vec<DeviceStats>),
+    /// The acknowledged feature bits of each virtio device, requested by
+    /// `VmRequest::VirtioFeatures`.
+    VirtioFeatures(Vec<VirtioFeatures>),
 }
 
 const VM_RESPONSE_TYPE_OK: u32 = 1;
 const VM_RESPONSE_TYPE_ERR: u32 = 2;
 const VM_RESPONSE_TYPE_REGISTER_MEMORY: u32 = 3;
 const VM_RESPONSE_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY: u32 = 4;
-const VM_RESPONSE_SIZE: usize = 24;
+const VM_RESPONSE_TYPE_DUMP_REGS: u32 = 5;
+const VM_RESPONSE_TYPE_READ_MEM: u32 = 6;
+const VM_RESPONSE_TYPE_TRANSLATE_ADDRESS: u32 = 7;
+const VM_RESPONSE_TYPE_DEVICE_STATS: u32 = 8;
+const VM_RESPONSE_TYPE_VIRTIO_FEATURES: u32 = 9;
+const VM_RESPONSE_SIZE: usize = 32;
 
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
@@ -334,24 +1145,134 @@ struct VmResponseStruct {
     pfn: Le64,
     slot: Le32,
     stride: Le32,
+    addr: Le64,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VmResponseStruct {}
 
+const DEVICE_STATS_SIZE: usize = 56;
+
+// Sent for each device after the `VmResponseStruct` of a `VmResponse::DeviceStats`. The name is
+// padded with zeros.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DeviceStatsStruct {
+    name: [u8; MAX_DEVICE_NAME_LEN],
+    requests_in: Le64,
+    bytes_in: Le64,
+    requests_out: Le64,
+    bytes_out: Le64,
+    errors: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for DeviceStatsStruct {}
+
+impl<'a> From<&'a DeviceStats> for DeviceStatsStruct {
+    fn from(stats: &DeviceStats) -> DeviceStatsStruct {
+        let mut name = [0u8; MAX_DEVICE_NAME_LEN];
+        let len = std::cmp::min(stats.name.len(), MAX_DEVICE_NAME_LEN);
+        name[..len].copy_from_slice(&stats.name.as_bytes()[..len]);
+        DeviceStatsStruct {
+            name: name,
+            requests_in: Le64::from(stats.requests_in),
+            bytes_in: Le64::from(stats.bytes_in),
+            requests_out: Le64::from(stats.requests_out),
+            bytes_out: Le64::from(stats.bytes_out),
+            errors: Le64::from(stats.errors),
+        }
+    }
+}
+
+impl From<DeviceStatsStruct> for DeviceStats {
+    fn from(stats: DeviceStatsStruct) -> DeviceStats {
+        let len = stats.name.iter().position(|&b| b == 0).unwrap_or(MAX_DEVICE_NAME_LEN);
+        DeviceStats {
+            name: String::from_utf8_lossy(&stats.name[..len]).into_owned(),
+            requests_in: stats.requests_in.into(),
+            bytes_in: stats.bytes_in.into(),
+            requests_out: stats.requests_out.into(),
+            bytes_out: stats.bytes_out.into(),
+            errors: stats.errors.into(),
+        }
+    }
+}
+
+const VIRTIO_FEATURES_SIZE: usize = 16;
+
+// Sent for each device after the `VmResponseStruct` of a `VmResponse::VirtioFeatures`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioFeaturesStruct {
+    device_type: Le32,
+    padding: Le32,
+    acked: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VirtioFeaturesStruct {}
+
+impl<'a> From<&'a VirtioFeatures> for VirtioFeaturesStruct {
+    fn from(features: &VirtioFeatures) -> VirtioFeaturesStruct {
+        VirtioFeaturesStruct {
+            device_type: Le32::from(features.device_type),
+            padding: Le32::from(0),
+            acked: Le64::from(features.acked),
+        }
+    }
+}
+
+impl From<VirtioFeaturesStruct> for VirtioFeatures {
+    fn from(features: VirtioFeaturesStruct) -> VirtioFeatures {
+        VirtioFeatures {
+            device_type: features.device_type.into(),
+            acked: features.acked.into(),
+        }
+    }
+}
+
 impl VmResponse {
     /// Receive a `VmResponse` from the given socket.
     ///
     /// This should be called after the sending a `VmRequest` before sending another request.
     pub fn recv(scm: &mut Scm, s: &UnixDatagram) -> VmControlResult<VmResponse> {
-        let mut buf = [0; VM_RESPONSE_SIZE];
+        assert_eq!(VCPU_REGS_SIZE, std::mem::size_of::<VcpuRegsStruct>());
+        assert_eq!(DEVICE_STATS_SIZE, std::mem::size_of::<DeviceStatsStruct>());
+        assert!(MAX_DEVICE_STATS * DEVICE_STATS_SIZE <= MAX_MEM_ACCESS_LEN);
+        assert_eq!(VIRTIO_FEATURES_SIZE, std::mem::size_of::<VirtioFeaturesStruct>());
+        assert!(MAX_VIRTIO_FEATURES * VIRTIO_FEATURES_SIZE <= MAX_MEM_ACCESS_LEN);
+        // Large enough for the biggest response, which is `ReadMem`.
+        let mut buf = [0; VM_RESPONSE_SIZE + MAX_MEM_ACCESS_LEN];
         let mut fds = Vec::new();
-        let read = scm.recv(s, &mut [&mut buf], &mut fds)
+        let read = scm.recv(s, &mut [&mut buf[..]], &mut fds)
             .map_err(|e| VmControlError::Recv(e))?;
-        if read != VM_RESPONSE_SIZE {
+        if read < VM_RESPONSE_SIZE {
+            return Err(VmControlError::BadSize(read));
+        }
+        let resp: VmResponseStruct = (&mut buf[..]).get_ref(0).unwrap().load();
+        let expected_size = match resp.type_.into() {
+            VM_RESPONSE_TYPE_DUMP_REGS => VM_RESPONSE_SIZE + VCPU_REGS_SIZE,
+            VM_RESPONSE_TYPE_READ_MEM => read,
+            VM_RESPONSE_TYPE_DEVICE_STATS => {
+                let count = resp.slot.to_native() as usize;
+                if count > MAX_DEVICE_STATS {
+                    return Err(VmControlError::TooLarge(count));
+                }
+                VM_RESPONSE_SIZE + count * DEVICE_STATS_SIZE
+            }
+            VM_RESPONSE_TYPE_VIRTIO_FEATURES => {
+                let count = resp.slot.to_native() as usize;
+                if count > MAX_VIRTIO_FEATURES {
+                    return Err(VmControlError::TooLarge(count));
+                }
+                VM_RESPONSE_SIZE + count * VIRTIO_FEATURES_SIZE
+            }
+            _ => VM_RESPONSE_SIZE,
+        };
+        if read != expected_size {
             return Err(VmControlError::BadSize(read));
         }
-        let resp: VmResponseStruct = buf.as_mut().get_ref(0).unwrap().load();
 
         match resp.type_.into() {
             VM_RESPONSE_TYPE_OK => Ok(VmResponse::Ok),
@@ -373,6 +1294,44 @@ impl VmResponse {
                        stride: resp.stride.into()
                   })
             }
+            VM_RESPONSE_TYPE_DUMP_REGS => {
+                // The unwrap() will never fail because `read` was checked to include the registers.
+                let regs: VcpuRegsStruct =
+                    (&mut buf[..]).get_ref(VM_RESPONSE_SIZE as u64).unwrap().load();
+                Ok(VmResponse::DumpRegs(regs.into()))
+            }
+            VM_RESPONSE_TYPE_READ_MEM => {
+                Ok(VmResponse::ReadMem(buf[VM_RESPONSE_SIZE..read].to_vec()))
+            }
+            VM_RESPONSE_TYPE_TRANSLATE_ADDRESS => {
+                Ok(VmResponse::TranslateAddress { gpa: resp.addr.into() })
+            }
+            VM_RESPONSE_TYPE_DEVICE_STATS => {
+                let count = resp.slot.to_native() as usize;
+                let mut stats = Vec::with_capacity(count);
+                for i in 0..count {
+                    let offset = VM_RESPONSE_SIZE + i * DEVICE_STATS_SIZE;
+                    // The unwrap() will never fail because `read` was checked to include every
+                    // device.
+                    let entry: DeviceStatsStruct =
+                        (&mut buf[..]).get_ref(offset as u64).unwrap().load();
+                    stats.push(entry.into());
+                }
+                Ok(VmResponse::DeviceStats(stats))
+            }
+            VM_RESPONSE_TYPE_VIRTIO_FEATURES => {
+                let count = resp.slot.to_native() as usize;
+                let mut features = Vec::with_capacity(count);
+                for i in 0..count {
+                    let offset = VM_RESPONSE_SIZE + i * VIRTIO_FEATURES_SIZE;
+                    // The unwrap() will never fail because `read` was checked to include every
+                    // device.
+                    let entry: VirtioFeaturesStruct =
+                        (&mut buf[..]).get_ref(offset as u64).unwrap().load();
+                    features.push(entry.into());
+                }
+                Ok(VmResponse::VirtioFeatures(features))
+            }
             _ => Err(VmControlError::InvalidType),
         }
     }
@@ -385,6 +1344,10 @@ impl VmResponse {
         let mut resp = VmResponseStruct::default();
         let mut fd_buf = [0; 1];
         let mut fd_len = 0;
+        let mut regs_buf = [0; VCPU_REGS_SIZE];
+        let mut stats_buf = [0; MAX_DEVICE_STATS * DEVICE_STATS_SIZE];
+        let mut features_buf = [0; MAX_VIRTIO_FEATURES * VIRTIO_FEATURES_SIZE];
+        let mut data: &[u8] = &[];
         match self {
             &VmResponse::Ok => resp.type_ = Le32::from(VM_RESPONSE_TYPE_OK),
             &VmResponse::Err(e) => {
@@ -404,10 +1367,56 @@ impl VmResponse {
                 resp.slot = Le32::from(slot);
                 resp.stride = Le32::from(stride);
             }
+            &VmResponse::DumpRegs(regs) => {
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_DUMP_REGS);
+                (&mut regs_buf[..]).get_ref(0).unwrap().store(VcpuRegsStruct::from(regs));
+                data = &regs_buf[..];
+            }
+            &VmResponse::ReadMem(ref bytes) => {
+                if bytes.len() > MAX_MEM_ACCESS_LEN {
+                    return Err(VmControlError::TooLarge(bytes.len()));
+                }
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_READ_MEM);
+                data = bytes;
+            }
+            &VmResponse::TranslateAddress { gpa } => {
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_TRANSLATE_ADDRESS);
+                resp.addr = Le64::from(gpa);
+            }
+            &VmResponse::DeviceStats(ref stats) => {
+                if stats.len() > MAX_DEVICE_STATS {
+                    return Err(VmControlError::TooLarge(stats.len()));
+                }
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_DEVICE_STATS);
+                resp.slot = Le32::from(stats.len() as u32);
+                for (i, entry) in stats.iter().enumerate() {
+                    let offset = (i * DEVICE_STATS_SIZE) as u64;
+                    (&mut stats_buf[..])
+                        .get_ref(offset)
+                        .unwrap()
+                        .store(DeviceStatsStruct::from(entry));
+                }
+                data = &stats_buf[..stats.len() * DEVICE_STATS_SIZE];
+            }
+            &VmResponse::VirtioFeatures(ref features) => {
+                if features.len() > MAX_VIRTIO_FEATURES {
+                    return Err(VmControlError::TooLarge(features.len()));
+                }
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_VIRTIO_FEATURES);
+                resp.slot = Le32::from(features.len() as u32);
+                for (i, entry) in features.iter().enumerate() {
+                    let offset = (i * VIRTIO_FEATURES_SIZE) as u64;
+                    (&mut features_buf[..])
+                        .get_ref(offset)
+                        .unwrap()
+                        .store(VirtioFeaturesStruct::from(entry));
+                }
+                data = &features_buf[..features.len() * VIRTIO_FEATURES_SIZE];
+            }
         }
         let mut buf = [0; VM_RESPONSE_SIZE];
         buf.as_mut().get_ref(0).unwrap().store(resp);
-        scm.send(s, &[buf.as_ref()], &fd_buf[..fd_len])
+        scm.send(s, &[buf.as_ref(), data], &fd_buf[..fd_len])
             .map_err(|e| VmControlError::Send(e))?;
         Ok(())
     }
@@ -420,9 +1429,30 @@ mod tests {
 
     use std::net::Shutdown;
 
+    use kvm::Kvm;
     use sys_util::kernel_has_memfd;
     use sys_util::SharedMemory;
 
+    struct NoVcpus;
+
+    impl VcpuControl for NoVcpus {
+        fn snapshot_regs(&self, _vcpu: u32) -> Result<VcpuRegs> {
+            Err(SysError::new(ENODEV))
+        }
+        fn set_regs(&self, _vcpu: u32, _regs: VcpuRegs) -> Result<()> {
+            Err(SysError::new(ENODEV))
+        }
+        fn translate_address(&self, _vcpu: u32, _gva: u64) -> Result<u64> {
+            Err(SysError::new(ENODEV))
+        }
+        fn debug(&self, _vcpu: u32, _command: VcpuDebugCommand) -> Result<()> {
+            Err(SysError::new(ENODEV))
+        }
+        fn set_breakpoints(&self, _vcpu: u32, _addrs: &[u64]) -> Result<()> {
+            Err(SysError::new(ENODEV))
+        }
+    }
+
     #[test]
     fn request_exit() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
@@ -575,6 +1605,403 @@ mod tests {
         }
     }
 
+    #[test]
+    fn request_dump_regs() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::DumpRegs { vcpu: 3 }.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::DumpRegs { vcpu } => assert_eq!(vcpu, 3),
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    fn fake_regs() -> VcpuRegs {
+        let segment = |selector: u16| {
+            VcpuSegment {
+                base: 0x1000 * selector as u64,
+                limit: 0xfffff,
+                selector: selector,
+                type_: 0xb,
+                present: true,
+                dpl: 3,
+                db: false,
+                s: true,
+                l: true,
+                g: true,
+                avl: false,
+            }
+        };
+        VcpuRegs {
+            rax: 1,
+            rbx: 2,
+            rcx: 3,
+            rdx: 4,
+            rsi: 5,
+            rdi: 6,
+            rsp: 7,
+            rbp: 8,
+            r8: 9,
+            r9: 10,
+            r10: 11,
+            r11: 12,
+            r12: 13,
+            r13: 14,
+            r14: 15,
+            r15: 16,
+            rip: 0xffffffff81000000,
+            rflags: 0x202,
+            cs: segment(0x10),
+            ds: segment(0x18),
+            es: segment(0x18),
+            fs: segment(0),
+            gs: segment(0),
+            ss: segment(0x18),
+            tr: segment(0x20),
+            ldt: VcpuSegment::default(),
+        }
+    }
+
+    #[test]
+    fn vcpu_regs_format() {
+        let mut buf = [0u8; VCPU_REGS_SIZE];
+        (&mut buf[..]).get_ref(0).unwrap().store(VcpuRegsStruct::from(fake_regs()));
+        // rax is first, rip and rflags end the general purpose registers.
+        assert_eq!(&buf[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[128..136], &[0, 0, 0, 0x81, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(&buf[136..144], &[2, 2, 0, 0, 0, 0, 0, 0]);
+        // cs: base, limit, selector then flags with type 0xb, s, dpl 3, present, l and g.
+        assert_eq!(&buf[144..160],
+                   &[0, 0, 1, 0, 0, 0, 0, 0, 0xff, 0xff, 0x0f, 0, 0x10, 0, 0xfb, 0xa0]);
+        // ldt is last.
+        assert_eq!(&buf[256..272], &[0; 16]);
+
+        let regs: VcpuRegsStruct = (&mut buf[..]).get_ref(0).unwrap().load();
+        assert_eq!(VcpuRegs::from(regs), fake_regs());
+    }
+
+    #[test]
+    fn resp_dump_regs() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmResponse::DumpRegs(fake_regs()).send(&mut scm, &s1).unwrap();
+        match VmResponse::recv(&mut scm, &s2).unwrap() {
+            VmResponse::DumpRegs(regs) => assert_eq!(regs, fake_regs()),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn resp_dump_regs_truncated() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let mut bad_response = [0; VM_RESPONSE_SIZE];
+        bad_response[0] = VM_RESPONSE_TYPE_DUMP_REGS as u8;
+        scm.send(&s2, &[bad_response.as_ref()], &[]).unwrap();
+        match VmResponse::recv(&mut scm, &s1) {
+            Err(e) => assert_eq!(e, VmControlError::BadSize(VM_RESPONSE_SIZE)),
+            _ => panic!("recv wrong response"),
+        }
+    }
+
+    #[test]
+    fn request_read_mem() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::ReadMem { gpa: 0x1234_5678_9000, len: 16 }.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::ReadMem { gpa, len } => {
+                assert_eq!(gpa, 0x1234_5678_9000);
+                assert_eq!(len, 16);
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_write_mem() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::WriteMem { gpa: 0x1000, bytes: vec![1, 2, 3] }.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::WriteMem { gpa, bytes } => {
+                assert_eq!(gpa, 0x1000);
+                assert_eq!(bytes, vec![1, 2, 3]);
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+
+        let too_large = VmRequest::WriteMem { gpa: 0, bytes: vec![0; MAX_MEM_ACCESS_LEN + 1] };
+        assert_eq!(too_large.send(&mut scm, &s1),
+                   Err(VmControlError::TooLarge(MAX_MEM_ACCESS_LEN + 1)));
+    }
+
+    #[test]
+    fn request_set_regs() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::SetRegs { vcpu: 1, regs: fake_regs() }.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::SetRegs { vcpu, regs } => {
+                assert_eq!(vcpu, 1);
+                assert_eq!(regs, fake_regs());
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_translate_address() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::TranslateAddress { vcpu: 2, gva: 0xffffffff81000000 }
+            .send(&mut scm, &s1)
+            .unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::TranslateAddress { vcpu, gva } => {
+                assert_eq!(vcpu, 2);
+                assert_eq!(gva, 0xffffffff81000000);
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_vcpu_debug() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        for &command in &[VcpuDebugCommand::Pause, VcpuDebugCommand::Continue,
+                          VcpuDebugCommand::Step] {
+            VmRequest::VcpuDebug { vcpu: 1, command }.send(&mut scm, &s1).unwrap();
+            match VmRequest::recv(&mut scm, &s2).unwrap() {
+                VmRequest::VcpuDebug { vcpu, command: c } => {
+                    assert_eq!(vcpu, 1);
+                    assert_eq!(c, command);
+                }
+                _ => panic!("recv wrong request variant"),
+            }
+        }
+    }
+
+    #[test]
+    fn request_set_breakpoints() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let addrs = vec![0x1000, 0xffffffff81000000];
+        VmRequest::SetBreakpoints { vcpu: 0, addrs: addrs.clone() }.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::SetBreakpoints { vcpu, addrs: a } => {
+                assert_eq!(vcpu, 0);
+                assert_eq!(a, addrs);
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+
+        let too_many = VmRequest::SetBreakpoints { vcpu: 0, addrs: vec![0; MAX_BREAKPOINTS + 1] };
+        assert_eq!(too_many.send(&mut scm, &s1),
+                   Err(VmControlError::TooLarge(MAX_BREAKPOINTS + 1)));
+    }
+
+    #[test]
+    fn request_input_events() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let events = vec![InputEvent { type_: 1, code: 30, value: 1 },
+                          InputEvent { type_: 3, code: 0, value: -5 }];
+        VmRequest::InputEvents { device: 2, events: events.clone() }
+            .send(&mut scm, &s1)
+            .unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::InputEvents { device, events: e } => {
+                assert_eq!(device, 2);
+                assert_eq!(e, events);
+            }
+            _ => panic!("recv wrong request variant"),
+        }
+
+        let too_many = VmRequest::InputEvents {
+            device: 0,
+            events: vec![InputEvent::default(); MAX_INPUT_EVENTS + 1],
+        };
+        assert_eq!(too_many.send(&mut scm, &s1),
+                   Err(VmControlError::TooLarge(MAX_INPUT_EVENTS + 1)));
+    }
+
+    #[test]
+    fn input_event_format() {
+        let event = InputEvent { type_: 3, code: 1, value: -2 };
+        let bytes = event.to_bytes();
+        assert_eq!(bytes, [3, 0, 1, 0, 0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(InputEvent::from_bytes(&bytes), event);
+    }
+
+    #[test]
+    fn guest_memory_access() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+        write_guest_memory(&mem, GuestAddress(0x1ffc), &[1, 2, 3, 4]).unwrap();
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x1ffc), 4).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x1ffe), 2).unwrap(), vec![3, 4]);
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x1000), MAX_MEM_ACCESS_LEN)
+                       .unwrap()
+                       .len(),
+                   MAX_MEM_ACCESS_LEN);
+    }
+
+    #[test]
+    fn guest_memory_access_out_of_range() {
+        let mem = GuestMemory::new(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+        let efault = Err(SysError::new(EFAULT));
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x800), 4), efault);
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x1ffe), 4), efault);
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0xffff_ffff_ffff_fffe), 4), efault);
+        assert_eq!(write_guest_memory(&mem, GuestAddress(0x2000), &[1]), Err(SysError::new(EFAULT)));
+        assert_eq!(write_guest_memory(&mem, GuestAddress(0x1fff), &[1, 2]),
+                   Err(SysError::new(EFAULT)));
+        assert_eq!(read_guest_memory(&mem, GuestAddress(0x1000), MAX_MEM_ACCESS_LEN + 1),
+                   Err(SysError::new(EINVAL)));
+    }
+
+    #[test]
+    fn resp_read_mem() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let memory = vec![0xa5; MAX_MEM_ACCESS_LEN];
+        VmResponse::ReadMem(memory.clone()).send(&mut scm, &s1).unwrap();
+        match VmResponse::recv(&mut scm, &s2).unwrap() {
+            VmResponse::ReadMem(bytes) => assert_eq!(bytes, memory),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn resp_translate_address() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmResponse::TranslateAddress { gpa: 0x1234000 }.send(&mut scm, &s1).unwrap();
+        match VmResponse::recv(&mut scm, &s2).unwrap() {
+            VmResponse::TranslateAddress { gpa } => assert_eq!(gpa, 0x1234000),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn shared_memory_allocator() {
+        let xr24 = fourcc(b'X', b'R', b'2', b'4');
+        let (mut file, stride) = SharedMemoryAllocator.allocate(100, 30, xr24).unwrap();
+        assert_eq!(stride, 400);
+        let size = file.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(size % pagesize() as u64, 0);
+        assert!(size >= 400 * 30);
+        // The buffer has to be mappable to be registered with the VM.
+        MemoryMapping::from_fd(&file, size as usize).unwrap();
+
+        let (_, stride) = SharedMemoryAllocator
+            .allocate(7, 5, fourcc(b'R', b'G', b'1', b'6'))
+            .unwrap();
+        assert_eq!(stride, 14);
+    }
+
+    #[test]
+    fn shared_memory_allocator_rejects() {
+        let xr24 = fourcc(b'X', b'R', b'2', b'4');
+        // Multi-planar formats aren't supported.
+        let nv12 = fourcc(b'N', b'V', b'1', b'2');
+        assert_eq!(SharedMemoryAllocator.allocate(64, 64, nv12).unwrap_err(),
+                   SysError::new(EINVAL));
+        assert_eq!(SharedMemoryAllocator.allocate(0, 64, xr24).unwrap_err(),
+                   SysError::new(EINVAL));
+        assert_eq!(SharedMemoryAllocator
+                       .allocate(u32::max_value(), 1, xr24)
+                       .unwrap_err(),
+                   SysError::new(EINVAL));
+    }
+
+    #[test]
+    fn request_device_stats() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::DeviceStats.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::DeviceStats => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn resp_device_stats() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let stats = vec![DeviceStats {
+                             name: "block0".to_owned(),
+                             requests_in: 3,
+                             bytes_in: 1536,
+                             requests_out: 1,
+                             bytes_out: 512,
+                             errors: 2,
+                         },
+                         DeviceStats {
+                             name: "net0".to_owned(),
+                             ..Default::default()
+                         }];
+        VmResponse::DeviceStats(stats.clone()).send(&mut scm, &s1).unwrap();
+        match VmResponse::recv(&mut scm, &s2).unwrap() {
+            VmResponse::DeviceStats(recv_stats) => assert_eq!(recv_stats, stats),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn resp_device_stats_too_many() {
+        let (s1, _) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let stats = vec![DeviceStats::default(); MAX_DEVICE_STATS + 1];
+        match VmResponse::DeviceStats(stats).send(&mut scm, &s1) {
+            Err(VmControlError::TooLarge(n)) => assert_eq!(n, MAX_DEVICE_STATS + 1),
+            _ => panic!("too many device stats were sent"),
+        }
+    }
+
+    #[test]
+    fn request_virtio_features() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        VmRequest::VirtioFeatures.send(&mut scm, &s1).unwrap();
+        match VmRequest::recv(&mut scm, &s2).unwrap() {
+            VmRequest::VirtioFeatures => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn resp_virtio_features() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let features = vec![VirtioFeatures {
+                                device_type: 2,
+                                acked: 1 << 32 | 0x240,
+                            },
+                            VirtioFeatures {
+                                device_type: 1,
+                                acked: 0,
+                            }];
+        VmResponse::VirtioFeatures(features.clone()).send(&mut scm, &s1).unwrap();
+        match VmResponse::recv(&mut scm, &s2).unwrap() {
+            VmResponse::VirtioFeatures(recv_features) => assert_eq!(recv_features, features),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn resp_virtio_features_too_many() {
+        let (s1, _) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut scm = Scm::new(1);
+        let features = vec![VirtioFeatures::default(); MAX_VIRTIO_FEATURES + 1];
+        match VmResponse::VirtioFeatures(features).send(&mut scm, &s1) {
+            Err(VmControlError::TooLarge(n)) => assert_eq!(n, MAX_VIRTIO_FEATURES + 1),
+            _ => panic!("too many virtio features were sent"),
+        }
+    }
+
     #[test]
     fn resp_no_data() {
         let (s1, _) = UnixDatagram::pair().expect("failed to create socket pair");
@@ -642,4 +2069,101 @@ mod tests {
             _ => panic!("recv wrong response variant"),
         }
     }
+
+    #[test]
+    fn device_pfn_allocator() {
+        let mut allocator = DevicePfnAllocator::new(0x100, 0x20);
+        assert_eq!(allocator.allocate(4), Some(0x100));
+        assert_eq!(allocator.allocate(2), Some(0x104));
+        assert_eq!(allocator.allocate(4), Some(0x106));
+
+        allocator.set_slot(1, 0x100, 4);
+        allocator.set_slot(2, 0x104, 2);
+        allocator.free_slot(1);
+        // Freeing a slot twice does nothing.
+        allocator.free_slot(1);
+        // Reuses the freed range, splitting it if it is too big.
+        assert_eq!(allocator.allocate(3), Some(0x100));
+        assert_eq!(allocator.allocate(2), Some(0x10a));
+        assert_eq!(allocator.allocate(1), Some(0x103));
+
+        // Freed ranges are merged with their neighbors.
+        allocator.free(0x100, 3);
+        allocator.free_slot(2);
+        allocator.free(0x103, 1);
+        assert_eq!(allocator.allocate(6), Some(0x100));
+
+        // A range freed at the end is handed out again, with the free ranges before it.
+        allocator.free(0x106, 4);
+        allocator.free(0x10a, 2);
+        assert_eq!(allocator.allocate(8), Some(0x106));
+    }
+
+    #[test]
+    fn device_pfn_allocator_window() {
+        let mut allocator = DevicePfnAllocator::new(0x100, 0x10);
+        assert_eq!(allocator.allocate(0x11), None);
+        // Mapping and unmapping over and over never runs out of room.
+        for i in 0..0x100 {
+            let a = allocator.allocate(0x8).unwrap();
+            let b = allocator.allocate(0x4 + i % 4).unwrap();
+            allocator.set_slot(0, a, 0x8);
+            allocator.set_slot(1, b, 0x4 + i % 4);
+            allocator.free_slot(0);
+            allocator.free_slot(1);
+        }
+        assert_eq!(allocator.allocate(0x10), Some(0x100));
+        assert_eq!(allocator.allocate(1), None);
+    }
+
+    #[test]
+    fn execute_register_unregister_memory() {
+        if !kernel_has_memfd() { return; }
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = Vm::new(&kvm, gm).unwrap();
+        let mut allocator = DevicePfnAllocator::new(0x20, 0x100);
+        let (balloon_socket, _) = UnixDatagram::pair().unwrap();
+        let mut running = true;
+        let mut execute = |vm: &mut Vm, allocator: &mut DevicePfnAllocator, request: VmRequest| {
+            request.execute(vm, allocator, &mut running, &balloon_socket, &[], None, &NoVcpus)
+        };
+
+        let mut shm = SharedMemory::new(None).unwrap();
+        shm.set_size(0x2000).unwrap();
+        let register = |size| VmRequest::RegisterMemory(MaybeOwnedFd::Borrowed(shm.as_raw_fd()),
+                                                        size);
+        let slot = match execute(&mut vm, &mut allocator, register(0x2000)) {
+            VmResponse::RegisterMemory { pfn, slot } => {
+                assert_eq!(pfn, 0x20);
+                slot
+            }
+            _ => panic!("unexpected response to RegisterMemory"),
+        };
+        // Bigger than the memfd.
+        match execute(&mut vm, &mut allocator, register(0x3000)) {
+            VmResponse::Err(e) => assert_eq!(e.errno(), EINVAL),
+            _ => panic!("registered memory past the end of the file"),
+        }
+
+        // Guest RAM isn't device memory.
+        match execute(&mut vm, &mut allocator, VmRequest::UnregisterMemory(0)) {
+            VmResponse::Err(e) => assert_eq!(e.errno(), EINVAL),
+            _ => panic!("unregistered guest memory"),
+        }
+        match execute(&mut vm, &mut allocator, VmRequest::UnregisterMemory(slot)) {
+            VmResponse::Ok => {}
+            _ => panic!("unexpected response to UnregisterMemory"),
+        }
+        match execute(&mut vm, &mut allocator, VmRequest::UnregisterMemory(slot)) {
+            VmResponse::Err(e) => assert_eq!(e.errno(), EINVAL),
+            _ => panic!("unregistered memory twice"),
+        }
+
+        // The page frames of the unregistered memory are reused.
+        match execute(&mut vm, &mut allocator, register(0x1000)) {
+            VmResponse::RegisterMemory { pfn, .. } => assert_eq!(pfn, 0x20),
+            _ => panic!("unexpected response to RegisterMemory"),
+        }
+    }
 }
@@ -0,0 +1,282 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::error::{self, Error as AcpiError};
+use std::fmt::{self, Display};
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sys_util::{GuestAddress, GuestMemory};
+
+#[derive(Debug)]
+pub enum Error {
+    /// There was too little guest memory to store the ACPI tables.
+    NotEnoughMemory,
+    /// Failure to write an ACPI table.
+    WriteTable,
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::NotEnoughMemory =>
+                "There was too little guest memory to store the ACPI tables",
+            &Error::WriteTable => "Failure to write an ACPI table",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ACPI Error: {}", Error::description(self))
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// The guest finds the RSDP by scanning the BIOS area from 0xe0000 to 0xfffff for its signature.
+// The e820 map leaves this area out of RAM, so the tables that follow it are safe too.
+const RSDP_ADDR: u64 = 0xe0000;
+const ACPI_AREA_END: u64 = 0x100000;
+// Tables start on 16 byte boundaries.
+const TABLE_ALIGNMENT: u64 = 16;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_SIZE: usize = 20;
+const OEM_ID: &[u8; 6] = b"CROSVM";
+const OEM_TABLE_ID: &[u8; 8] = b"CROSVM  ";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: &[u8; 4] = b"CRVM";
+const CREATOR_REVISION: u32 = 1;
+const SDT_HEADER_SIZE: usize = 36;
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+// FADT of ACPI 5.0, the first revision with hardware reduced ACPI. The guest then doesn't expect
+// any of the fixed hardware, such as the PM timer or the SCI interrupt.
+const FADT_REVISION: u8 = 5;
+const FADT_SIZE: usize = 268;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_MINOR_VERSION_OFFSET: usize = 131;
+const FADT_X_DSDT_OFFSET: usize = 140;
+const FADT_POWER_BUTTON: u32 = 1 << 4;
+const FADT_SLEEP_BUTTON: u32 = 1 << 5;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+const MADT_REVISION: u8 = 3;
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1;
+// These match the MP table.
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee00000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec00000;
+
+fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    (!sum).wrapping_add(1)
+}
+
+// A system description table: the common header followed by the table's own fields.
+struct Sdt {
+    data: Vec<u8>,
+}
+
+impl Sdt {
+    fn new(signature: &[u8; 4], revision: u8, size: usize) -> Sdt {
+        let mut data = vec![0u8; size];
+        data[0..4].copy_from_slice(signature);
+        data[8] = revision;
+        data[10..16].copy_from_slice(OEM_ID);
+        data[16..24].copy_from_slice(OEM_TABLE_ID);
+        LittleEndian::write_u32(&mut data[24..28], OEM_REVISION);
+        data[28..32].copy_from_slice(CREATOR_ID);
+        LittleEndian::write_u32(&mut data[32..36], CREATOR_REVISION);
+        Sdt { data: data }
+    }
+
+    fn write_u8(&mut self, offset: usize, val: u8) {
+        self.data[offset] = val;
+    }
+
+    fn write_u32(&mut self, offset: usize, val: u32) {
+        LittleEndian::write_u32(&mut self.data[offset..offset + 4], val);
+    }
+
+    fn write_u64(&mut self, offset: usize, val: u64) {
+        LittleEndian::write_u64(&mut self.data[offset..offset + 8], val);
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Fills in the length and checksum and returns the bytes of the table.
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.data.len() as u32;
+        LittleEndian::write_u32(&mut self.data[4..8], len);
+        self.data[SDT_CHECKSUM_OFFSET] = 0;
+        self.data[SDT_CHECKSUM_OFFSET] = checksum(&self.data);
+        self.data
+    }
+}
+
+fn rsdp(rsdt_addr: u64) -> Vec<u8> {
+    let mut data = vec![0u8; RSDP_SIZE];
+    data[0..8].copy_from_slice(RSDP_SIGNATURE);
+    data[9..15].copy_from_slice(OEM_ID);
+    // Revision 0 is ACPI 1.0, which only points to the RSDT.
+    data[15] = 0;
+    LittleEndian::write_u32(&mut data[16..20], rsdt_addr as u32);
+    data[8] = checksum(&data);
+    data
+}
+
+fn rsdt(tables: &[u64]) -> Vec<u8> {
+    let mut rsdt = Sdt::new(b"RSDT", 1, SDT_HEADER_SIZE);
+    for &addr in tables {
+        let mut entry = [0u8; 4];
+        LittleEndian::write_u32(&mut entry, addr as u32);
+        rsdt.append(&entry);
+    }
+    rsdt.finish()
+}
+
+// An empty DSDT. There are no devices to describe, but guests expect the FADT to point to one.
+fn dsdt() -> Vec<u8> {
+    Sdt::new(b"DSDT", 2, SDT_HEADER_SIZE).finish()
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut fadt = Sdt::new(b"FACP", FADT_REVISION, FADT_SIZE);
+    fadt.write_u32(FADT_DSDT_OFFSET, dsdt_addr as u32);
+    fadt.write_u64(FADT_X_DSDT_OFFSET, dsdt_addr);
+    fadt.write_u32(FADT_FLAGS_OFFSET,
+                   FADT_POWER_BUTTON | FADT_SLEEP_BUTTON | FADT_HW_REDUCED_ACPI);
+    fadt.write_u8(FADT_MINOR_VERSION_OFFSET, 0);
+    fadt.finish()
+}
+
+fn madt(num_cpus: u8) -> Vec<u8> {
+    let mut madt = Sdt::new(b"APIC", MADT_REVISION, SDT_HEADER_SIZE + 8);
+    madt.write_u32(SDT_HEADER_SIZE, APIC_DEFAULT_PHYS_BASE);
+    madt.write_u32(SDT_HEADER_SIZE + 4, MADT_PCAT_COMPAT);
+    for cpu_id in 0..num_cpus {
+        let mut entry = [0u8; 8];
+        entry[0] = MADT_LOCAL_APIC;
+        entry[1] = entry.len() as u8;
+        entry[2] = cpu_id; // ACPI processor id
+        entry[3] = cpu_id; // APIC id
+        LittleEndian::write_u32(&mut entry[4..8], MADT_LOCAL_APIC_ENABLED);
+        madt.append(&entry);
+    }
+    let mut entry = [0u8; 12];
+    entry[0] = MADT_IO_APIC;
+    entry[1] = entry.len() as u8;
+    entry[2] = num_cpus + 1; // IOAPIC id, as in the MP table.
+    LittleEndian::write_u32(&mut entry[4..8], IO_APIC_DEFAULT_PHYS_BASE);
+    // The IOAPIC pins are GSIs 0 to 23, so the ISA interrupts need no overrides.
+    LittleEndian::write_u32(&mut entry[8..12], 0);
+    madt.append(&entry);
+    madt.finish()
+}
+
+/// Writes the RSDP, RSDT, FADT, DSDT and MADT describing `num_cpus` local APICs and the IOAPIC
+/// into the BIOS area of guest memory.
+pub fn setup_acpi_tables(mem: &GuestMemory, num_cpus: u8) -> Result<()> {
+    let align = |addr: u64| (addr + TABLE_ALIGNMENT - 1) & !(TABLE_ALIGNMENT - 1);
+
+    // Each table's address has to be known before the tables that point to it are built, so they
+    // are laid out back to front from the RSDP: RSDT, FADT, DSDT, MADT.
+    let rsdt_addr = align(RSDP_ADDR + RSDP_SIZE as u64);
+    let rsdt_size = (SDT_HEADER_SIZE + 2 * 4) as u64;
+    let fadt_addr = align(rsdt_addr + rsdt_size);
+    let dsdt_addr = align(fadt_addr + FADT_SIZE as u64);
+    let madt_addr = align(dsdt_addr + SDT_HEADER_SIZE as u64);
+
+    let tables = [(RSDP_ADDR, rsdp(rsdt_addr)),
+                  (rsdt_addr, rsdt(&[fadt_addr, madt_addr])),
+                  (fadt_addr, fadt(dsdt_addr)),
+                  (dsdt_addr, dsdt()),
+                  (madt_addr, madt(num_cpus))];
+    for &(addr, ref table) in tables.iter() {
+        if addr + table.len() as u64 > ACPI_AREA_END {
+            return Err(Error::NotEnoughMemory);
+        }
+        mem.checked_offset(GuestAddress(addr), table.len() as u64)
+            .ok_or(Error::NotEnoughMemory)?;
+        mem.write_slice_at_addr(table, GuestAddress(addr))
+            .map_err(|_| Error::WriteTable)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_table(mem: &GuestMemory, addr: u64) -> Vec<u8> {
+        let mut header = [0u8; SDT_HEADER_SIZE];
+        mem.read_slice_at_addr(&mut header, GuestAddress(addr)).unwrap();
+        let mut table = vec![0u8; LittleEndian::read_u32(&header[4..8]) as usize];
+        mem.read_slice_at_addr(&mut table, GuestAddress(addr)).unwrap();
+        table
+    }
+
+    fn sum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    #[test]
+    fn tables() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x100000)]).unwrap();
+        setup_acpi_tables(&mem, 4).unwrap();
+
+        let mut rsdp = [0u8; RSDP_SIZE];
+        mem.read_slice_at_addr(&mut rsdp, GuestAddress(RSDP_ADDR)).unwrap();
+        assert_eq!(&rsdp[0..8], RSDP_SIGNATURE);
+        assert_eq!(sum(&rsdp), 0);
+
+        let rsdt = read_table(&mem, LittleEndian::read_u32(&rsdp[16..20]) as u64);
+        assert_eq!(&rsdt[0..4], b"RSDT");
+        assert_eq!(sum(&rsdt), 0);
+        assert_eq!(rsdt.len(), SDT_HEADER_SIZE + 8);
+
+        let fadt = read_table(&mem, LittleEndian::read_u32(&rsdt[36..40]) as u64);
+        assert_eq!(&fadt[0..4], b"FACP");
+        assert_eq!(sum(&fadt), 0);
+        assert_eq!(fadt.len(), FADT_SIZE);
+
+        let dsdt_addr = LittleEndian::read_u32(&fadt[FADT_DSDT_OFFSET..]) as u64;
+        assert_eq!(LittleEndian::read_u64(&fadt[FADT_X_DSDT_OFFSET..]), dsdt_addr);
+        let dsdt = read_table(&mem, dsdt_addr);
+        assert_eq!(&dsdt[0..4], b"DSDT");
+        assert_eq!(sum(&dsdt), 0);
+
+        let madt = read_table(&mem, LittleEndian::read_u32(&rsdt[40..44]) as u64);
+        assert_eq!(&madt[0..4], b"APIC");
+        assert_eq!(sum(&madt), 0);
+        // Four local APICs and an IOAPIC.
+        assert_eq!(madt.len(), SDT_HEADER_SIZE + 8 + 4 * 8 + 12);
+        let ioapic = &madt[SDT_HEADER_SIZE + 8 + 4 * 8..];
+        assert_eq!(ioapic[0], MADT_IO_APIC);
+        assert_eq!(ioapic[2], 5);
+    }
+
+    #[test]
+    fn not_enough_memory() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0xe0010)]).unwrap();
+        match setup_acpi_tables(&mem, 1) {
+            Err(Error::NotEnoughMemory) => {}
+            _ => panic!("ACPI tables written past the end of memory"),
+        }
+    }
+
+    #[test]
+    fn max_cpus() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x100000)]).unwrap();
+        setup_acpi_tables(&mem, 254).unwrap();
+    }
+}
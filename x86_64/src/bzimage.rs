@@ -0,0 +1,208 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Loader for bzImage kernels using the 64-bit linux boot protocol described in
+// Documentation/x86/boot.txt of the kernel source.
+
+use std::cmp::{max, min};
+use std::error::{self, Error as BzImageError};
+use std::fmt::{self, Display};
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::result;
+
+use bootparam::setup_header;
+use data_model::DataInit;
+use kernel_loader::LoadedKernel;
+use sys_util::{self, GuestAddress, GuestMemory};
+
+// The setup header is at the same offset in the image as in the zero page.
+const SETUP_HEADER_OFFSET: u64 = 0x1f1;
+// The setup header ends at this offset plus the second byte of its jump instruction.
+const SETUP_HEADER_END_BASE: u64 = 0x202;
+const SECTOR_SIZE: u64 = 512;
+const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
+const KERNEL_HDR_MAGIC: u32 = 0x53726448;
+// The first boot protocol version with xloadflags.
+const BOOT_PROTOCOL_XLOADFLAGS: u16 = 0x020c;
+// Set in xloadflags if the kernel has a 64-bit entry point.
+const XLF_KERNEL_64: u16 = 1 << 0;
+// The 64-bit entry point's offset from the start of the protected mode kernel.
+const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidMagicNumber,
+    InvalidSetupSects,
+    KernelPastRamEnd,
+    No64BitEntry,
+    ReadKernelImage,
+    ReadSetupHeader,
+    SeekKernelStart,
+    SeekSetupHeader,
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::InvalidMagicNumber => "Invalid bzImage magic number",
+            &Error::InvalidSetupSects => "Setup sectors extend past the end of the image",
+            &Error::KernelPastRamEnd => "The kernel extends past the end of RAM",
+            &Error::No64BitEntry => "The kernel has no 64-bit entry point",
+            &Error::ReadKernelImage => "Unable to read kernel image",
+            &Error::ReadSetupHeader => "Unable to read setup header",
+            &Error::SeekKernelStart => "Unable to seek to kernel start",
+            &Error::SeekSetupHeader => "Unable to seek to setup header",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bzImage Loader Error: {}", Error::description(self))
+    }
+}
+
+/// Loads the protected mode part of a bzImage kernel at `kernel_start`.
+///
+/// The returned end of the kernel leaves room for the kernel to decompress itself in place.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory region the kernel is written to.
+/// * `kernel_start` - The address in `guest_mem` at which to load the kernel.
+/// * `kernel_image` - Input bzImage.
+pub fn load_bzimage<F>(guest_mem: &GuestMemory,
+                       kernel_start: GuestAddress,
+                       kernel_image: &mut F)
+                       -> Result<LoadedKernel>
+    where F: Read + Seek
+{
+    let mut hdr: setup_header = Default::default();
+    kernel_image.seek(SeekFrom::Start(SETUP_HEADER_OFFSET))
+        .map_err(|_| Error::SeekSetupHeader)?;
+    unsafe {
+        // read_struct is safe when reading a POD struct.  It can be used and dropped without issue.
+        sys_util::read_struct(kernel_image, &mut hdr).map_err(|_| Error::ReadSetupHeader)?;
+    }
+
+    if hdr.boot_flag != KERNEL_BOOT_FLAG_MAGIC || hdr.header != KERNEL_HDR_MAGIC {
+        return Err(Error::InvalidMagicNumber);
+    }
+    if hdr.version < BOOT_PROTOCOL_XLOADFLAGS || hdr.xloadflags & XLF_KERNEL_64 == 0 {
+        return Err(Error::No64BitEntry);
+    }
+
+    // A setup_sects of 0 means 4 for historical reasons. The boot sector is not counted.
+    let setup_sects = if hdr.setup_sects == 0 {
+        4
+    } else {
+        hdr.setup_sects as u64
+    };
+    let kernel_offset = (setup_sects + 1) * SECTOR_SIZE;
+    let image_size = kernel_image.seek(SeekFrom::End(0))
+        .map_err(|_| Error::SeekKernelStart)?;
+    let kernel_size = image_size.checked_sub(kernel_offset)
+        .ok_or(Error::InvalidSetupSects)?;
+
+    let kernel_end = kernel_start.checked_add(max(kernel_size, hdr.init_size as u64))
+        .ok_or(Error::KernelPastRamEnd)?;
+    if kernel_end > guest_mem.end_addr() {
+        return Err(Error::KernelPastRamEnd);
+    }
+
+    // Only the part of the header the kernel actually has is handed back in the zero page, the
+    // rest of boot_params is left for the loader to fill in.
+    let hdr_end = SETUP_HEADER_END_BASE + (hdr.jump >> 8) as u64;
+    let hdr_len = min((hdr_end - SETUP_HEADER_OFFSET) as usize, mem::size_of::<setup_header>());
+
+    kernel_image.seek(SeekFrom::Start(kernel_offset))
+        .map_err(|_| Error::SeekKernelStart)?;
+    guest_mem.read_to_memory(kernel_start, kernel_image, kernel_size as usize)
+        .map_err(|_| Error::ReadKernelImage)?;
+
+    Ok(LoadedKernel {
+           entry: kernel_start.unchecked_add(KERNEL_64BIT_ENTRY_OFFSET),
+           end: kernel_end,
+           setup_header: Some(hdr.as_slice()[..hdr_len].to_vec()),
+       })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MEM_SIZE: u64 = 0x10000;
+
+    // Builds a bzImage with one setup sector followed by `kernel`.
+    fn make_bzimage(version: u16, xloadflags: u16, init_size: u32, kernel: &[u8]) -> Vec<u8> {
+        let mut hdr: setup_header = Default::default();
+        // A short jump past the end of the whole setup header.
+        hdr.jump = 0x66eb;
+        hdr.setup_sects = 1;
+        hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
+        hdr.header = KERNEL_HDR_MAGIC;
+        hdr.version = version;
+        hdr.xloadflags = xloadflags;
+        hdr.init_size = init_size;
+
+        let mut image = vec![0u8; SETUP_HEADER_OFFSET as usize];
+        // setup_header is plain data, so viewing it as bytes is safe.
+        image.extend_from_slice(unsafe {
+            ::std::slice::from_raw_parts(&hdr as *const _ as *const u8,
+                                         mem::size_of::<setup_header>())
+        });
+        image.resize(2 * SECTOR_SIZE as usize, 0);
+        image.extend_from_slice(kernel);
+        image
+    }
+
+    #[test]
+    fn load_kernel() {
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let image = make_bzimage(BOOT_PROTOCOL_XLOADFLAGS, XLF_KERNEL_64, 0x4000, b"kernel");
+        let loaded = load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded,
+                   LoadedKernel {
+                       entry: GuestAddress(0x1200),
+                       end: GuestAddress(0x5000),
+                       setup_header: Some(image[SETUP_HEADER_OFFSET as usize..]
+                                              [..mem::size_of::<setup_header>()]
+                                              .to_vec()),
+                   });
+        let mut kernel = [0u8; 6];
+        gm.read_slice_at_addr(&mut kernel, GuestAddress(0x1000)).unwrap();
+        assert_eq!(&kernel, b"kernel");
+    }
+
+    #[test]
+    fn short_setup_header() {
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut image = make_bzimage(BOOT_PROTOCOL_XLOADFLAGS, XLF_KERNEL_64, 0, b"kernel");
+        image[0x201] = 0x44;
+        let loaded = load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded.setup_header,
+                   Some(image[SETUP_HEADER_OFFSET as usize..0x246].to_vec()));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let image = make_bzimage(0x020b, XLF_KERNEL_64, 0, b"kernel");
+        assert_eq!(Err(Error::No64BitEntry),
+                   load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)));
+        let image = make_bzimage(BOOT_PROTOCOL_XLOADFLAGS, 0, 0, b"kernel");
+        assert_eq!(Err(Error::No64BitEntry),
+                   load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)));
+        let image = make_bzimage(BOOT_PROTOCOL_XLOADFLAGS, XLF_KERNEL_64, MEM_SIZE as u32, b"");
+        assert_eq!(Err(Error::KernelPastRamEnd),
+                   load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)));
+        let mut image = make_bzimage(BOOT_PROTOCOL_XLOADFLAGS, XLF_KERNEL_64, 0, b"");
+        image[0x1fe] = 0;
+        assert_eq!(Err(Error::InvalidMagicNumber),
+                   load_bzimage(&gm, GuestAddress(0x1000), &mut Cursor::new(&image)));
+    }
+}
@@ -6,7 +6,9 @@ use std::result;
 use std::fmt::{self, Display};
 use std::error::{self, Error as CpuidError};
 
+use arch::CpuidOverride;
 use kvm;
+use kvm_sys::{kvm_cpuid_entry2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
 use sys_util;
 
 // Query the CPU vendor.  ebx/ecx/edx pack an ASCII string into these 3 regs
@@ -16,10 +18,19 @@ const VENDOR_EBX_VAL: u32 = 0x736f7263;  // sorc
 const VENDOR_ECX_VAL: u32 = 0x4d567473;  // MVts
 const VENDOR_EDX_VAL: u32 = 0x65426d76;  // eBmv
 
+// The hypervisor leaves KVM paravirtual features are advertised in.
+const KVM_CPUID_SIGNATURE: u32 = 0x40000000;
+const KVM_CPUID_FEATURES: u32 = 0x40000001;
+// "KVMKVMKVM\0\0\0", which guests look for before using KVM paravirtual features.
+const KVM_SIGNATURE_EBX_VAL: u32 = 0x4b4d564b;  // KVMK
+const KVM_SIGNATURE_ECX_VAL: u32 = 0x564b4d56;  // VMKV
+const KVM_SIGNATURE_EDX_VAL: u32 = 0x0000004d;  // M
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     GetSupportedCpusFailed(sys_util::Error),
     SetSupportedCpusFailed(sys_util::Error),
+    TooManyOverrides,
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -30,6 +41,8 @@ impl error::Error for Error {
                 "GetSupportedCpus ioctl failed",
             &Error::SetSupportedCpusFailed(_) =>
                 "SetSupportedCpus ioctl failed",
+            &Error::TooManyOverrides =>
+                "No room for another CPUID entry for an override",
         }
     }
 }
@@ -46,10 +59,21 @@ const EBX_CLFLUSH_SIZE_SHIFT: u32 = 8; // Bytes flushed when executing CLFLUSH.
 const EBX_CPU_COUNT_SHIFT: u32 = 16; // Index of this CPU.
 const EBX_CPUID_SHIFT: u32 = 24; // Index of this CPU.
 const ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
+const ECX_X2APIC_SHIFT: u32 = 21; // The local APIC supports x2APIC mode.
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
 
-fn filter_cpuid(cpu_id: u64, cpu_count: u64, kvm_cpuid: &mut kvm::CpuId) -> Result<()> {
+// KVM paravirtual feature bits in eax of KVM_CPUID_FEATURES.
+const KVM_FEATURE_CLOCKSOURCE_SHIFT: u32 = 0; // kvmclock at the original MSRs.
+const KVM_FEATURE_CLOCKSOURCE2_SHIFT: u32 = 3; // kvmclock at MSR_KVM_SYSTEM_TIME_NEW.
+const KVM_FEATURE_CLOCKSOURCE_STABLE_SHIFT: u32 = 24; // The kvmclock is stable across vcpus.
+
+fn filter_cpuid(cpu_id: u64,
+                cpu_count: u64,
+                x2apic: bool,
+                kvmclock: bool,
+                kvm_cpuid: &mut kvm::CpuId)
+                -> Result<()> {
     let entries = kvm_cpuid.mut_entries_slice();
 
     for entry in entries.iter_mut() {
@@ -65,6 +89,9 @@ fn filter_cpuid(cpu_id: u64, cpu_count: u64, kvm_cpuid: &mut kvm::CpuId) -> Resu
                 if entry.index == 0 {
                     entry.ecx |= 1 << ECX_HYPERVISOR_SHIFT;
                 }
+                if x2apic {
+                    entry.ecx |= 1 << ECX_X2APIC_SHIFT;
+                }
                 entry.ebx = (cpu_id << EBX_CPUID_SHIFT) as u32 |
                             (EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT);
                 if cpu_count > 1 {
@@ -76,6 +103,25 @@ fn filter_cpuid(cpu_id: u64, cpu_count: u64, kvm_cpuid: &mut kvm::CpuId) -> Resu
                 // Clear X86 EPB feature.  No frequency selection in the hypervisor.
                 entry.ecx &= !(1 << ECX_EPB_SHIFT);
             }
+            KVM_CPUID_SIGNATURE => {
+                entry.eax = KVM_CPUID_FEATURES;
+                entry.ebx = KVM_SIGNATURE_EBX_VAL;
+                entry.ecx = KVM_SIGNATURE_ECX_VAL;
+                entry.edx = KVM_SIGNATURE_EDX_VAL;
+            }
+            KVM_CPUID_FEATURES => {
+                let kvmclock_bits = (1 << KVM_FEATURE_CLOCKSOURCE_SHIFT) |
+                                    (1 << KVM_FEATURE_CLOCKSOURCE2_SHIFT) |
+                                    (1 << KVM_FEATURE_CLOCKSOURCE_STABLE_SHIFT);
+                if kvmclock {
+                    // Advertise kvmclock so the guest doesn't fall back to a drifting clocksource.
+                    entry.eax |= kvmclock_bits;
+                } else {
+                    // The guest prefers kvmclock's wall-clock time over the RTC's, so hiding it
+                    // is the only way for the guest to keep a time other than the host's.
+                    entry.eax &= !kvmclock_bits;
+                }
+            }
             _ => (),
         }
     }
@@ -83,6 +129,43 @@ fn filter_cpuid(cpu_id: u64, cpu_count: u64, kvm_cpuid: &mut kvm::CpuId) -> Resu
     Ok(())
 }
 
+// Sets the registers of each overridden leaf, adding entries for leaves KVM didn't report.
+fn apply_overrides(overrides: &[CpuidOverride], kvm_cpuid: &mut kvm::CpuId) -> Result<()> {
+    for o in overrides {
+        let found = match kvm_cpuid
+                  .mut_entries_slice()
+                  .iter_mut()
+                  .find(|e| e.function == o.function && e.index == o.index) {
+            Some(entry) => {
+                entry.eax = o.eax;
+                entry.ebx = o.ebx;
+                entry.ecx = o.ecx;
+                entry.edx = o.edx;
+                true
+            }
+            None => false,
+        };
+        if found {
+            continue;
+        }
+        let entry = kvm_cpuid_entry2 {
+            function: o.function,
+            index: o.index,
+            // Without this, KVM reports the entry for every subleaf of the leaf.
+            flags: if o.index != 0 { KVM_CPUID_FLAG_SIGNIFCANT_INDEX } else { 0 },
+            eax: o.eax,
+            ebx: o.ebx,
+            ecx: o.ecx,
+            edx: o.edx,
+            ..Default::default()
+        };
+        if !kvm_cpuid.push(entry) {
+            return Err(Error::TooManyOverrides);
+        }
+    }
+    Ok(())
+}
+
 /// Sets up the cpuid entries for the given vcpu.  Can fail if there are too many CPUs specified or
 /// if an ioctl returns an error.
 ///
@@ -92,11 +175,22 @@ fn filter_cpuid(cpu_id: u64, cpu_count: u64, kvm_cpuid: &mut kvm::CpuId) -> Resu
 /// * `vcpu` - `Vcpu` for setting CPU ID.
 /// * `cpu_id` - The index of the CPU `vcpu` is for.
 /// * `nrcpus` - The number of vcpus being used by this VM.
-pub fn setup_cpuid(kvm: &kvm::Kvm, vcpu: &kvm::Vcpu, cpu_id: u64, nrcpus: u64) -> Result<()> {
+/// * `x2apic` - Advertise x2APIC support to the guest.
+/// * `kvmclock` - Advertise the kvmclock paravirtual clock to the guest.
+/// * `overrides` - Leaves to report exactly these values for, applied after everything else.
+pub fn setup_cpuid(kvm: &kvm::Kvm,
+                   vcpu: &kvm::Vcpu,
+                   cpu_id: u64,
+                   nrcpus: u64,
+                   x2apic: bool,
+                   kvmclock: bool,
+                   overrides: &[CpuidOverride])
+                   -> Result<()> {
     let mut kvm_cpuid = kvm.get_supported_cpuid()
         .map_err(Error::GetSupportedCpusFailed)?;
 
-    filter_cpuid(cpu_id, nrcpus, &mut kvm_cpuid)?;
+    filter_cpuid(cpu_id, nrcpus, x2apic, kvmclock, &mut kvm_cpuid)?;
+    apply_overrides(overrides, &mut kvm_cpuid)?;
 
     vcpu.set_cpuid2(&kvm_cpuid)
         .map_err(Error::SetSupportedCpusFailed)
@@ -117,7 +211,7 @@ mod tests {
             entries[1].ecx = 0x10;
             entries[1].edx = 0;
         }
-        assert_eq!(Ok(()), filter_cpuid(1, 2, &mut cpuid));
+        assert_eq!(Ok(()), filter_cpuid(1, 2, false, true, &mut cpuid));
         {
             let entries = cpuid.mut_entries_slice();
             assert_eq!(entries[0].function, 0);
@@ -130,6 +224,91 @@ mod tests {
                        (entries[1].ebx >> EBX_CLFLUSH_SIZE_SHIFT) & 0x000000ff);
             assert_ne!(0, entries[1].ecx & (1 << ECX_HYPERVISOR_SHIFT));
             assert_ne!(0, entries[1].edx & (1 << EDX_HTT_SHIFT));
+            assert_eq!(0, entries[1].ecx & (1 << ECX_X2APIC_SHIFT));
         }
     }
+
+    #[test]
+    fn x2apic() {
+        let mut cpuid = kvm::CpuId::new(1);
+        cpuid.mut_entries_slice()[0].function = 1;
+        assert_eq!(Ok(()), filter_cpuid(0, 1, true, true, &mut cpuid));
+        assert_ne!(0, cpuid.mut_entries_slice()[0].ecx & (1 << ECX_X2APIC_SHIFT));
+    }
+
+    #[test]
+    fn kvm_paravirt_leaves() {
+        let mut cpuid = kvm::CpuId::new(2);
+        {
+            let entries = cpuid.mut_entries_slice();
+            entries[0].function = KVM_CPUID_SIGNATURE;
+            entries[1].function = KVM_CPUID_FEATURES;
+        }
+        assert_eq!(Ok(()), filter_cpuid(0, 1, false, true, &mut cpuid));
+        let entries = cpuid.mut_entries_slice();
+        assert_eq!(entries[0].eax, KVM_CPUID_FEATURES);
+        assert_eq!(entries[0].ebx, KVM_SIGNATURE_EBX_VAL);
+        assert_eq!(entries[0].ecx, KVM_SIGNATURE_ECX_VAL);
+        assert_eq!(entries[0].edx, KVM_SIGNATURE_EDX_VAL);
+        assert_ne!(0, entries[1].eax & (1 << KVM_FEATURE_CLOCKSOURCE2_SHIFT));
+        assert_ne!(0, entries[1].eax & (1 << KVM_FEATURE_CLOCKSOURCE_STABLE_SHIFT));
+    }
+
+    #[test]
+    fn kvmclock_hidden() {
+        let mut cpuid = kvm::CpuId::new(1);
+        {
+            let entry = &mut cpuid.mut_entries_slice()[0];
+            entry.function = KVM_CPUID_FEATURES;
+            // KVM reports kvmclock as supported.
+            entry.eax = (1 << KVM_FEATURE_CLOCKSOURCE_SHIFT) |
+                        (1 << KVM_FEATURE_CLOCKSOURCE2_SHIFT);
+        }
+        assert_eq!(Ok(()), filter_cpuid(0, 1, false, false, &mut cpuid));
+        assert_eq!(0, cpuid.mut_entries_slice()[0].eax);
+    }
+
+    #[test]
+    fn overrides() {
+        let mut cpuid = kvm::CpuId::new(3);
+        {
+            let entries = cpuid.mut_entries_slice();
+            entries[0].function = 0;
+            entries[1].function = 1;
+        }
+        // Leave room for one more entry.
+        unsafe { (*cpuid.as_mut_ptr()).nent = 2 };
+        assert_eq!(Ok(()), filter_cpuid(0, 1, false, true, &mut cpuid));
+
+        let vendor = CpuidOverride {
+            function: 0,
+            index: 0,
+            eax: 0xd,
+            ebx: 1,
+            ecx: 2,
+            edx: 3,
+        };
+        let extra = CpuidOverride {
+            function: 7,
+            index: 1,
+            eax: 4,
+            ebx: 5,
+            ecx: 6,
+            edx: 7,
+        };
+        assert_eq!(Ok(()), apply_overrides(&[vendor, extra], &mut cpuid));
+        {
+            let entries = cpuid.mut_entries_slice();
+            assert_eq!(entries.len(), 3);
+            assert_eq!((entries[0].eax, entries[0].ebx, entries[0].ecx, entries[0].edx),
+                       (0xd, 1, 2, 3));
+            assert_eq!((entries[2].function, entries[2].index, entries[2].flags),
+                       (7, 1, KVM_CPUID_FLAG_SIGNIFCANT_INDEX));
+            assert_eq!((entries[2].eax, entries[2].ebx, entries[2].ecx, entries[2].edx),
+                       (4, 5, 6, 7));
+        }
+
+        let another = CpuidOverride { function: 0x40000010, ..vendor };
+        assert_eq!(Err(Error::TooManyOverrides), apply_overrides(&[another], &mut cpuid));
+    }
 }
@@ -34,6 +34,7 @@ impl Clone for bootparam::boot_params {
 }
 // boot_params is just a series of ints, it is safe to initialize it.
 unsafe impl data_model::DataInit for bootparam::boot_params {}
+unsafe impl data_model::DataInit for bootparam::setup_header {}
 
 #[allow(dead_code)]
 #[allow(non_upper_case_globals)]
@@ -52,12 +53,17 @@ unsafe impl data_model::DataInit for mpspec::mpc_table {}
 unsafe impl data_model::DataInit for mpspec::mpc_lintsrc {}
 unsafe impl data_model::DataInit for mpspec::mpf_intel {}
 
+#[cfg(feature = "acpi")]
+mod acpi;
+mod bzimage;
 mod cpuid;
 mod gdt;
 mod interrupts;
 mod mptable;
 mod regs;
 
+use std::arch::x86_64::__cpuid;
+use std::cmp;
 use std::mem;
 use std::result;
 use std::error::{self, Error as X86Error};
@@ -65,10 +71,12 @@ use std::fmt::{self, Display};
 use std::fs::File;
 use std::ffi::CStr;
 use std::sync::{Arc, Mutex};
-use std::io::stdout;
+use std::io::{self, Read, Seek, SeekFrom, stdout};
 
-use bootparam::boot_params;
-use bootparam::E820_RAM;
+use bootparam::{boot_params, setup_header};
+use bootparam::{E820_RAM, E820_RESERVED};
+use arch::{CpuidOverride, LoadedKernel};
+use data_model::DataInit;
 use sys_util::{EventFd, GuestAddress, GuestMemory};
 use kvm::*;
 
@@ -80,18 +88,40 @@ pub enum Error {
     CloneEventFd(sys_util::Error),
     /// Unable to make an EventFd
     CreateEventFd(sys_util::Error),
-    /// The kernel extends past the end of RAM
+    /// The kernel entry point is past the end of RAM
     KernelOffsetPastEnd,
     /// Error registering an IrqFd
     RegisterIrqfd(sys_util::Error),
     LoadCmdline(kernel_loader::Error),
     LoadKernel(kernel_loader::Error),
+    /// Unable to read the kernel image's magic number
+    ReadKernelMagic,
+    /// Unable to get the size of the initrd image
+    InitrdMetadata,
+    /// Error copying the initrd into guest memory
+    LoadInitrd,
+    /// The initrd doesn't fit in the 32-bit RAM
+    InitrdPastRamEnd,
+    /// The initrd would overlap the kernel
+    InitrdOverlapsKernel,
     /// Error writing the zero page of guest memory.
     ZeroPageSetup,
     /// The zero page extends past the end of guest_mem.
     ZeroPagePastRamEnd,
     /// Invalid e820 setup params.
     E820Configuration,
+    /// KVM can't split the interrupt controllers between the kernel and userspace.
+    SplitIrqChipUnsupported,
+    /// More vcpus were requested than xAPIC IDs can address without x2APIC.
+    X2ApicRequired,
+    /// Setting the TSC frequency of a vcpu failed.
+    SetTscKhz(sys_util::Error),
+    /// The window for 64-bit PCI BARs doesn't fit in the guest physical address space.
+    HighMmioPastAddressSpace,
+    /// The window for 64-bit PCI BARs overlaps guest RAM.
+    HighMmioOverlapsRam,
+    /// The kernel's setup header is the wrong size.
+    InvalidSetupHeader,
 }
 
 impl error::Error for Error {
@@ -101,15 +131,29 @@ impl error::Error for Error {
             &Error::CloneEventFd(_) => "Unable to clone an EventFd",
             &Error::CreateEventFd(_) => "Unable to make an EventFd",
             &Error::KernelOffsetPastEnd =>
-                "The kernel extends past the end of RAM",
+                "The kernel entry point is past the end of RAM",
             &Error::RegisterIrqfd(_) => "Error registering an IrqFd",
             &Error::LoadCmdline(_) => "Error Loading command line",
             &Error::LoadKernel(_) => "Error Loading Kernel",
+            &Error::ReadKernelMagic => "Unable to read the kernel image's magic number",
+            &Error::InitrdMetadata => "Unable to get the size of the initrd image",
+            &Error::LoadInitrd => "Error loading initrd",
+            &Error::InitrdPastRamEnd =>
+                "The initrd doesn't fit in RAM below 4GB",
+            &Error::InitrdOverlapsKernel => "The initrd would overlap the kernel",
             &Error::ZeroPageSetup =>
                 "Error writing the zero page of guest memory",
             &Error::ZeroPagePastRamEnd =>
                 "The zero page extends past the end of guest_mem",
             &Error::E820Configuration => "Invalid e820 setup params",
+            &Error::SplitIrqChipUnsupported =>
+                "KVM doesn't support a split irqchip (KVM_CAP_SPLIT_IRQCHIP)",
+            &Error::X2ApicRequired => "More than 255 vcpus require x2APIC",
+            &Error::SetTscKhz(_) => "Error setting the TSC frequency",
+            &Error::HighMmioPastAddressSpace =>
+                "The high MMIO window doesn't fit in the guest physical address space",
+            &Error::HighMmioOverlapsRam => "The high MMIO window overlaps guest RAM",
+            &Error::InvalidSetupHeader => "The kernel setup header is too long",
         }
     }
 }
@@ -126,18 +170,59 @@ pub type Result<T> = result::Result<T, Box<std::error::Error>>;
 const BOOT_STACK_POINTER: u64 = 0x8000;
 const MEM_32BIT_GAP_SIZE: u64 = (768 << 20);
 const FIRST_ADDR_PAST_32BITS: u64 = (1 << 32);
-const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+// The window for 64-bit PCI BARs starts on a boundary of this size.
+const HIGH_MMIO_ALIGNMENT: u64 = (1 << 30);
+// The physical address width assumed for CPUs that don't report theirs.
+const DEFAULT_PHYS_ADDR_BITS: u32 = 36;
 const ZERO_PAGE_OFFSET: u64 = 0x7000;
 
 const KERNEL_START_OFFSET: u64 = 0x200000;
 const CMDLINE_OFFSET: u64 = 0x20000;
 const CMDLINE_MAX_SIZE: u64 = KERNEL_START_OFFSET - CMDLINE_OFFSET;
+// The most vcpus that can be addressed by the 8 bit xAPIC IDs, which reserve 0xff for broadcast.
+const MAX_XAPIC_CPUS: u64 = 255;
+// The number of pins on the IOAPIC, whose routes are reserved when it's emulated in userspace.
+const NUM_IOAPIC_PINS: u32 = 24;
+// The IO port base and interrupt line of ttyS0 through ttyS3.
+const SERIAL_PORTS: [(u64, u32); 4] = [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+// The IO port base of the watchdog's registers: control at +0x0, the timeout in milliseconds at
+// +0x4, pet at +0x8 and the milliseconds remaining at +0xc.
+const WATCHDOG_PORT: u64 = 0x440;
+
+// Puts ttyS0 through ttyS3 on `io_bus`. Each port writes to its entry in `outputs`, keyed by port
+// number, or nowhere if it has none. Returns ttyS0, which is the only port that gets input.
+fn add_serial_ports(io_bus: &mut devices::Bus,
+                    irq_routes: &mut arch::IrqRoutes,
+                    outputs: Vec<(u8, Box<io::Write + Send>)>)
+                    -> Result<Arc<Mutex<devices::Serial>>> {
+    let mut sinks: Vec<Option<Box<io::Write + Send>>> = SERIAL_PORTS.iter().map(|_| None).collect();
+    for (num, out) in outputs {
+        if let Some(sink) = sinks.get_mut(num as usize) {
+            *sink = Some(out);
+        }
+    }
+
+    let mut serials = Vec::new();
+    for (&(base, irq), sink) in SERIAL_PORTS.iter().zip(sinks) {
+        let evt = irq_routes.get_evt(irq).map_err(Error::CreateEventFd)?;
+        let serial = Arc::new(Mutex::new(match sink {
+            Some(out) => devices::Serial::new_out(evt, out),
+            None => devices::Serial::new_sink(evt),
+        }));
+        io_bus.insert(serial.clone(), base, 0x8).unwrap();
+        serials.push(serial);
+    }
+    Ok(serials.swap_remove(0))
+}
 
 fn configure_system(guest_mem: &GuestMemory,
                     kernel_addr: GuestAddress,
                     cmdline_addr: GuestAddress,
                     cmdline_size: usize,
-                    num_cpus: u8)
+                    num_cpus: u8,
+                    setup_header: Option<setup_header>,
+                    initrd: Option<(GuestAddress, usize)>,
+                    high_mmio: Option<(GuestAddress, u64)>)
                     -> Result<()> {
     const EBDA_START: u64 = 0x0009fc00;
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
@@ -149,29 +234,62 @@ fn configure_system(guest_mem: &GuestMemory,
 
     // Note that this puts the mptable at 0x0 in guest physical memory.
     mptable::setup_mptable(guest_mem, num_cpus)?;
+    #[cfg(feature = "acpi")]
+    acpi::setup_acpi_tables(guest_mem, num_cpus)?;
 
     let mut params: boot_params = Default::default();
 
+    // The kernel expects to find the rest of its own setup header, such as init_size, in the
+    // zero page.
+    if let Some(hdr) = setup_header {
+        params.hdr = hdr;
+    }
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
     params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
     params.hdr.header = KERNEL_HDR_MAGIC;
     params.hdr.cmd_line_ptr = cmdline_addr.offset() as u32;
     params.hdr.cmdline_size = cmdline_size as u32;
     params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
+    if let Some((initrd_addr, initrd_size)) = initrd {
+        params.hdr.ramdisk_image = initrd_addr.offset() as u32;
+        params.hdr.ramdisk_size = initrd_size as u32;
+    }
 
     add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
 
     let mem_end = guest_mem.end_addr();
-    if mem_end < end_32bit_gap_start {
-        add_e820_entry(&mut params,
-                       kernel_addr.offset() as u64,
-                       mem_end.offset_from(kernel_addr) as u64,
-                       E820_RAM)?;
+    let low_mem_end = if mem_end < end_32bit_gap_start {
+        mem_end
     } else {
-        add_e820_entry(&mut params,
-                       kernel_addr.offset() as u64,
-                       end_32bit_gap_start.offset_from(kernel_addr) as u64,
-                       E820_RAM)?;
+        end_32bit_gap_start
+    };
+    match initrd {
+        Some((initrd_addr, initrd_size)) => {
+            // Split the low RAM around the initrd so that it is reserved.
+            let initrd_end = GuestAddress(initrd_addr.offset() + initrd_size as u64);
+            add_e820_entry(&mut params,
+                           kernel_addr.offset() as u64,
+                           initrd_addr.offset_from(kernel_addr) as u64,
+                           E820_RAM)?;
+            add_e820_entry(&mut params,
+                           initrd_addr.offset() as u64,
+                           initrd_size as u64,
+                           E820_RESERVED)?;
+            if initrd_end < low_mem_end {
+                add_e820_entry(&mut params,
+                               initrd_end.offset() as u64,
+                               low_mem_end.offset_from(initrd_end) as u64,
+                               E820_RAM)?;
+            }
+        }
+        None => {
+            add_e820_entry(&mut params,
+                           kernel_addr.offset() as u64,
+                           low_mem_end.offset_from(kernel_addr) as u64,
+                           E820_RAM)?;
+        }
+    }
+    if mem_end > end_32bit_gap_start {
         if mem_end > first_addr_past_32bits {
             add_e820_entry(&mut params,
                            first_addr_past_32bits.offset() as u64,
@@ -179,6 +297,13 @@ fn configure_system(guest_mem: &GuestMemory,
                            E820_RAM)?;
         }
     }
+    if let Some((high_mmio_start, high_mmio_size)) = high_mmio {
+        if high_mmio_start < first_addr_past_32bits || high_mmio_start < mem_end {
+            return Err(Box::new(Error::HighMmioOverlapsRam));
+        }
+        // Keeps the guest from using the window for anything but the BARs placed in it.
+        add_e820_entry(&mut params, high_mmio_start.offset(), high_mmio_size, E820_RESERVED)?;
+    }
 
     let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
     guest_mem.checked_offset(zero_page_addr, mem::size_of::<boot_params>() as u64)
@@ -204,6 +329,59 @@ fn add_e820_entry(params: &mut boot_params, addr: u64, size: u64, mem_type: u32)
     Ok(())
 }
 
+/// Returns the page aligned address at which an initrd of `initrd_size` bytes is loaded: as high as
+/// possible in the RAM below the 32-bit gap, which ends at `mem_end` for small VMs.
+/// Fails if the initrd doesn't fit in that RAM or would overlap the kernel ending at `kernel_end`.
+fn initrd_addr(mem_end: GuestAddress,
+               kernel_end: GuestAddress,
+               initrd_size: u64)
+               -> result::Result<GuestAddress, Error> {
+    let end_32bit_gap_start = GuestAddress(FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE);
+    let low_mem_end = if mem_end < end_32bit_gap_start {
+        mem_end
+    } else {
+        end_32bit_gap_start
+    };
+    let page_mask = !(sys_util::pagesize() as u64 - 1);
+    let addr = low_mem_end.offset()
+        .checked_sub(initrd_size)
+        .ok_or(Error::InitrdPastRamEnd)? & page_mask;
+    if addr < kernel_end.offset() {
+        return Err(Error::InitrdOverlapsKernel);
+    }
+    Ok(GuestAddress(addr))
+}
+
+// Returns the number of physical address bits of the host CPU, which KVM reports to the guest as
+// its own.
+fn phys_addr_bits() -> u32 {
+    // Safe because cpuid only reads processor identification, and leaf 0x80000008 is only read
+    // if the CPU reports having it.
+    unsafe {
+        if __cpuid(0x8000_0000).eax < 0x8000_0008 {
+            return DEFAULT_PHYS_ADDR_BITS;
+        }
+        __cpuid(0x8000_0008).eax & 0xff
+    }
+}
+
+/// Returns the window of `size` bytes that 64-bit PCI BARs are placed in. It is above the 32-bit
+/// address space and past `mem_end`, the end of guest RAM and of any device memory after it, and
+/// must end within the `phys_bits` wide guest physical address space.
+fn high_mmio_region(mem_end: u64,
+                    size: u64,
+                    phys_bits: u32)
+                    -> result::Result<(GuestAddress, u64), Error> {
+    let start = cmp::max(mem_end, FIRST_ADDR_PAST_32BITS)
+        .checked_add(HIGH_MMIO_ALIGNMENT - 1)
+        .ok_or(Error::HighMmioPastAddressSpace)? & !(HIGH_MMIO_ALIGNMENT - 1);
+    let end = start.checked_add(size).ok_or(Error::HighMmioPastAddressSpace)?;
+    if phys_bits < 64 && end > 1u64 << phys_bits {
+        return Err(Error::HighMmioPastAddressSpace);
+    }
+    Ok((GuestAddress(start), size))
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platfrom.
 /// For x86_64 all addresses are valid from the start of the kenel except a
@@ -227,16 +405,39 @@ fn arch_memory_regions(size: u64) -> Vec<(GuestAddress, u64)> {
 }
 
 impl arch::LinuxArch for X8664arch {
-    /// Loads the kernel from an open file.
+    /// Loads the kernel from an open file. An elf vmlinux is loaded at the physical addresses of
+    /// its segments and anything else is expected to be a bzImage.
     ///
     /// # Arguments
     ///
     /// * `mem` - The memory to be used by the guest.
     /// * `kernel_image` - the File object for the specified kernel.
-    fn load_kernel(mem: &GuestMemory, mut kernel_image: &mut File) -> Result<()> {
-        kernel_loader::load_kernel(mem, GuestAddress(KERNEL_START_OFFSET),
-                                   &mut kernel_image)?;
-        Ok(())
+    fn load_kernel(mem: &GuestMemory, mut kernel_image: &mut File) -> Result<LoadedKernel> {
+        let mut magic = [0u8; 4];
+        kernel_image.seek(SeekFrom::Start(0)).map_err(|_| Error::ReadKernelMagic)?;
+        kernel_image.read_exact(&mut magic).map_err(|_| Error::ReadKernelMagic)?;
+        let kernel = if kernel_loader::is_elf(&magic) {
+            kernel_loader::load_kernel(mem, GuestAddress(0), &mut kernel_image)?
+        } else {
+            bzimage::load_bzimage(mem, GuestAddress(KERNEL_START_OFFSET), &mut kernel_image)?
+        };
+        Ok(kernel)
+    }
+
+    /// Loads the initrd at the top of the RAM below 4GB.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory to be used by the guest.
+    /// * `kernel_end` - The address following the loaded kernel.
+    /// * `initrd_image` - the File object for the specified initrd.
+    fn load_initrd(mem: &GuestMemory, kernel_end: GuestAddress, initrd_image: &mut File)
+                   -> Result<(GuestAddress, usize)> {
+        let initrd_size = initrd_image.metadata().map_err(|_| Error::InitrdMetadata)?.len();
+        let addr = initrd_addr(mem.end_addr(), kernel_end, initrd_size)?;
+        mem.read_to_memory(addr, initrd_image, initrd_size as usize)
+            .map_err(|_| Error::LoadInitrd)?;
+        Ok((addr, initrd_size as usize))
     }
 
     /// Configures the system memory space should be called once per vm before
@@ -247,12 +448,32 @@ impl arch::LinuxArch for X8664arch {
     /// * `mem` - The memory to be used by the guest.
     /// * `vcpu_count` - Number of virtual CPUs the guest will have.
     /// * `cmdline` - the kernel commandline
+    /// * `setup_header` - the setup header of a bzImage kernel, handed back in the zero page
+    /// * `initrd` - the address and size of the initrd, if one was loaded
+    /// * `mmio_devices` - unused, the kernel command line already describes the devices
     fn setup_system_memory(mem: &GuestMemory, _mem_size: u64,
-                           vcpu_count: u32, cmdline: &CStr) -> Result<()> {
+                           vcpu_count: u32, cmdline: &CStr,
+                           setup_header: Option<&[u8]>,
+                           initrd: Option<(GuestAddress, usize)>,
+                           _mmio_devices: &[(u64, u32)],
+                           high_mmio: Option<(GuestAddress, u64)>) -> Result<()> {
+        let setup_header = match setup_header {
+            Some(bytes) => {
+                // Older kernels have shorter headers, the fields they lack are left zeroed.
+                let mut hdr: setup_header = Default::default();
+                if bytes.len() > hdr.as_slice().len() {
+                    return Err(Box::new(Error::InvalidSetupHeader));
+                }
+                hdr.as_mut_slice()[..bytes.len()].copy_from_slice(bytes);
+                Some(hdr)
+            }
+            None => None,
+        };
         kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)?;
         configure_system(mem, GuestAddress(KERNEL_START_OFFSET),
                          GuestAddress(CMDLINE_OFFSET),
-                         cmdline.to_bytes().len() + 1, vcpu_count as u8)?;
+                         cmdline.to_bytes().len() + 1, vcpu_count as u8, setup_header, initrd,
+                         high_mmio)?;
         Ok(())
     }
 
@@ -262,12 +483,26 @@ impl arch::LinuxArch for X8664arch {
     ///
     /// * `kvm` - The opened /dev/kvm object.
     /// * `mem` - The memory to be used by the guest.
-    fn create_vm(kvm: &Kvm, mem: GuestMemory) -> Result<Vm> {
+    /// * `split_irqchip` - Leave the IOAPIC, PIC and PIT to userspace.
+    /// * `pit` - Create the in-kernel i8254 PIT along with the full in-kernel irqchip.
+    fn create_vm(kvm: &Kvm, mem: GuestMemory, split_irqchip: bool, pit: bool) -> Result<Vm> {
         let vm = Vm::new(&kvm, mem)?;
         let tss_addr = GuestAddress(0xfffbd000);
         vm.set_tss_addr(tss_addr).expect("set tss addr failed");
-        vm.create_pit().expect("create pit failed");
-        vm.create_irq_chip()?;
+        if split_irqchip {
+            if !vm.check_extension(Cap::SplitIrqchip) {
+                return Err(Box::new(Error::SplitIrqChipUnsupported));
+            }
+            // The in-kernel PIT raises its interrupt through the in-kernel PIC, so it is left out
+            // along with the IOAPIC and PIC.
+            vm.create_split_irq_chip(NUM_IOAPIC_PINS)?;
+        } else {
+            // The PIT is wired to the in-kernel PIC and IOAPIC, which have to exist first.
+            vm.create_irq_chip()?;
+            if pit {
+                vm.create_pit().expect("create pit failed");
+            }
+        }
         Ok(vm)
     }
 
@@ -300,28 +535,43 @@ impl arch::LinuxArch for X8664arch {
     ///
     /// * `mem_size` - the size in bytes of physical ram for the guest
     fn get_base_dev_pfn(mem_size: u64) -> u64 {
-        // Put device memory at nearest 2MB boundary after physical memory
+        // Put device memory at the nearest 2MB boundary after physical memory, but never below
+        // 4G where it would run into the MMIO devices and the rest of the 32-bit gap.
         const MB: u64 = 1024 * 1024;
         let mem_size_round_2mb = (mem_size + 2*MB - 1) / (2*MB) * (2*MB);
-        mem_size_round_2mb / sys_util::pagesize() as u64
+        cmp::max(mem_size_round_2mb, FIRST_ADDR_PAST_32BITS) / sys_util::pagesize() as u64
+    }
+
+    fn get_high_mmio_region(mem_end: u64, size: u64) -> Result<Option<(GuestAddress, u64)>> {
+        Ok(Some(high_mmio_region(mem_end, size, phys_addr_bits())?))
     }
 
     /// This returns a minimal kernel command for this architecture
     fn get_base_linux_cmdline() -> kernel_cmdline::Cmdline {
         let mut cmdline = kernel_cmdline::Cmdline::new(CMDLINE_MAX_SIZE as usize);
-        cmdline.insert_str("console=ttyS0 noacpi reboot=k panic=1 pci=off").
-            unwrap();
+        let base = if cfg!(feature = "acpi") {
+            "reboot=k panic=1 pci=off"
+        } else {
+            "noacpi reboot=k panic=1 pci=off"
+        };
+        cmdline.insert_str(base).unwrap();
         cmdline
     }
 
+    fn serial_earlycon(port: u8) -> Option<String> {
+        SERIAL_PORTS
+            .get(port as usize)
+            .map(|&(base, _)| format!("uart8250,io,{:#x}", base))
+    }
+
     /// This creates and returns a device_manager object for this vm.
     ///
     /// # Arguments
     ///
     /// * `vm` - the vm object
     /// * `mem` - A copy of the GuestMemory object for this VM.
-    fn get_device_manager(vm: &mut Vm, mem: GuestMemory) ->
-        Result<device_manager::DeviceManager> {
+    fn get_device_manager(vm: &mut Vm, mem: GuestMemory, _serial: Arc<Mutex<devices::Serial>>)
+                          -> Result<device_manager::DeviceManager> {
         const MMIO_BASE: u64 = 0xd0000000;
         const MMIO_LEN: u64 = 0x1000;
         const IRQ_BASE: u32 = 5;
@@ -335,41 +585,32 @@ impl arch::LinuxArch for X8664arch {
     ///
     /// * - `vm` the vm object
     /// * - `exit_evt` - the event fd object which should receive exit events
-    fn setup_io_bus(vm: &mut Vm, exit_evt: EventFd)
+    /// * - `rtc_base_time` - the time, in seconds since the Unix epoch, the guest's real-time clock
+    ///     starts at
+    /// * - `serial_outputs` - where the output of ttyS0 through ttyS3 goes, keyed by port number.
+    ///     ttyS0 writes to stdout unless it is given here.
+    /// * - `watchdog` - Add a watchdog timer at `WATCHDOG_PORT`.
+    fn setup_io_bus(vm: &mut Vm,
+                    exit_evt: EventFd,
+                    rtc_base_time: i64,
+                    serial_outputs: Vec<(u8, Box<io::Write + Send>)>,
+                    watchdog: bool)
                     -> Result<(devices::Bus, Arc<Mutex<devices::Serial>>)> {
         struct NoDevice;
         impl devices::BusDevice for NoDevice {}
 
         let mut io_bus = devices::Bus::new();
+        // Nothing drives the data lines for an unclaimed port, so they read as all ones.
+        io_bus.set_unmapped_read_value(Some(0xff));
 
-        let com_evt_1_3 = EventFd::new().map_err(|e| Error::CreateEventFd(e))?;
-        let com_evt_2_4 = EventFd::new().map_err(|e| Error::CreateEventFd(e))?;
-        let stdio_serial =
-            Arc::new(Mutex::new(
-                devices::Serial::new_out(com_evt_1_3.try_clone().
-                                         map_err(|e| Error::CloneEventFd(e))?,
-                                         Box::new(stdout()))));
+        let mut irq_routes = arch::IrqRoutes::new();
+        let mut outputs = serial_outputs;
+        if !outputs.iter().any(|&(num, _)| num == 0) {
+            outputs.push((0, Box::new(stdout())));
+        }
+        let stdio_serial = add_serial_ports(&mut io_bus, &mut irq_routes, outputs)?;
         let nul_device = Arc::new(Mutex::new(NoDevice));
-        io_bus.insert(stdio_serial.clone(), 0x3f8, 0x8).unwrap();
-        io_bus.insert(Arc::new(Mutex::new(
-            devices::Serial::new_sink(com_evt_2_4.try_clone().
-                                      map_err(|e| Error::CloneEventFd(e))?))),
-                      0x2f8,
-                      0x8)
-            .unwrap();
-        io_bus.insert(Arc::new(Mutex::new(
-            devices::Serial::new_sink(com_evt_1_3.try_clone().
-                                      map_err(|e| Error::CloneEventFd(e))?))),
-                      0x3e8,
-                      0x8)
-            .unwrap();
-        io_bus.insert(Arc::new(Mutex::new(
-            devices::Serial::new_sink(com_evt_2_4.try_clone().
-                                      map_err(|e| Error::CloneEventFd(e))?))),
-                      0x2e8,
-                      0x8)
-            .unwrap();
-        io_bus.insert(Arc::new(Mutex::new(devices::Cmos::new())), 0x70, 0x2)
+        io_bus.insert(Arc::new(Mutex::new(devices::Cmos::new(rtc_base_time))), 0x70, 0x2)
             .unwrap();
         io_bus.insert(Arc::new(Mutex::new(
             devices::I8042Device::new(exit_evt.try_clone().
@@ -377,13 +618,20 @@ impl arch::LinuxArch for X8664arch {
                       0x061,
                       0x4)
             .unwrap();
+        if watchdog {
+            io_bus.insert(Arc::new(Mutex::new(
+                devices::Watchdog::new(exit_evt.try_clone().
+                                       map_err(|e| Error::CloneEventFd(e))?))),
+                          WATCHDOG_PORT,
+                          devices::WATCHDOG_SIZE)
+                .unwrap();
+        }
         io_bus.insert(nul_device.clone(), 0x040, 0x8).unwrap(); // ignore pit
         io_bus.insert(nul_device.clone(), 0x0ed, 0x1).unwrap(); // most likely this one does nothing
         io_bus.insert(nul_device.clone(), 0x0f0, 0x2).unwrap(); // ignore fpu
         io_bus.insert(nul_device.clone(), 0xcf8, 0x8).unwrap(); // ignore pci
 
-        vm.register_irqfd(&com_evt_1_3, 4).map_err(Error::RegisterIrqfd)?;
-        vm.register_irqfd(&com_evt_2_4, 3).map_err(Error::RegisterIrqfd)?;
+        irq_routes.register(vm).map_err(Error::RegisterIrqfd)?;
 
         Ok((io_bus, stdio_serial))
     }
@@ -393,27 +641,36 @@ impl arch::LinuxArch for X8664arch {
     /// # Arguments
     ///
     /// * `guest_mem` - The memory to be used by the guest.
-    /// * `kernel_load_offset` - Offset in bytes from `guest_mem` at which the
-    ///                          kernel starts.
+    /// * `kernel_entry` - The address of the kernel's entry point.
     /// * `kvm` - The /dev/kvm object that created vcpu.
     /// * `vm` - The VM object associated with this VCPU.
     /// * `vcpu` - The VCPU object to configure.
     /// * `cpu_id` - The id of the given `vcpu`.
     /// * `num_cpus` - Number of virtual CPUs the guest will have.
     fn configure_vcpu(guest_mem: &GuestMemory,
+                      kernel_entry: GuestAddress,
                       kvm: &Kvm,
                       _vm: &Vm,
                       vcpu: &Vcpu,
                       cpu_id: u64,
-                      num_cpus: u64)
+                      num_cpus: u64,
+                      x2apic: bool,
+                      kvmclock: bool,
+                      tsc_khz: Option<u32>,
+                      cpuid_overrides: &[CpuidOverride])
                       -> Result<()> {
-        let kernel_load_addr = GuestAddress(KERNEL_START_OFFSET);
-        cpuid::setup_cpuid(kvm, vcpu, cpu_id, num_cpus)?;
-        regs::setup_msrs(vcpu)?;
-        let kernel_end = guest_mem.checked_offset(kernel_load_addr, KERNEL_64BIT_ENTRY_OFFSET)
+        if num_cpus > MAX_XAPIC_CPUS && !x2apic {
+            return Err(Box::new(Error::X2ApicRequired));
+        }
+        cpuid::setup_cpuid(kvm, vcpu, cpu_id, num_cpus, x2apic, kvmclock, cpuid_overrides)?;
+        regs::setup_msrs(vcpu, cpu_id, x2apic)?;
+        if let Some(khz) = tsc_khz {
+            vcpu.set_tsc_khz(khz).map_err(Error::SetTscKhz)?;
+        }
+        let kernel_entry = guest_mem.checked_offset(kernel_entry, 0)
             .ok_or(Error::KernelOffsetPastEnd)?;
         regs::setup_regs(vcpu,
-                         (kernel_end).offset() as u64,
+                         kernel_entry.offset() as u64,
                          BOOT_STACK_POINTER as u64,
                          ZERO_PAGE_OFFSET as u64)?;
         regs::setup_fpu(vcpu)?;
@@ -426,6 +683,19 @@ impl arch::LinuxArch for X8664arch {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use arch::LinuxArch;
+    use devices::fakes::SharedBuffer;
+
+    #[test]
+    #[ignore] // no access to /dev/kvm
+    fn create_vm_pit() {
+        let kvm = Kvm::new().unwrap();
+        let mem = X8664arch::setup_memory(1 << 24).unwrap();
+        let vm = X8664arch::create_vm(&kvm, mem.clone(), false, true).unwrap();
+        assert!(vm.get_pit_state().is_ok());
+        let vm = X8664arch::create_vm(&kvm, mem, false, false).unwrap();
+        assert!(vm.get_pit_state().is_err());
+    }
 
     #[test]
     fn regions_lt_4gb() {
@@ -442,4 +712,194 @@ mod tests {
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(1u64 << 32), regions[1].0);
     }
+
+    #[test]
+    fn high_mmio_disjoint_from_ram() {
+        let sizes = [1u64 << 29,
+                     FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE + 0x1000,
+                     1u64 << 32,
+                     (1u64 << 32) + 0x8000,
+                     1u64 << 36];
+        for &mem_size in sizes.iter() {
+            let (start, size) = high_mmio_region(mem_size, 1u64 << 32, 40).unwrap();
+            assert!(start.offset() >= FIRST_ADDR_PAST_32BITS);
+            assert_eq!(start.offset() % HIGH_MMIO_ALIGNMENT, 0);
+            for &(ram_start, ram_size) in arch_memory_regions(mem_size).iter() {
+                assert!(ram_start.offset() + ram_size <= start.offset() ||
+                        start.offset() + size <= ram_start.offset());
+            }
+        }
+        match high_mmio_region(u64::max_value() - 0x1000, 0x1000, 64) {
+            Err(Error::HighMmioPastAddressSpace) => {}
+            _ => panic!("window past the end of the address space should fail"),
+        }
+        // A 39 bit guest can't reach a 512GiB window above 4GiB.
+        assert!(high_mmio_region(1u64 << 30, 1u64 << 39, 40).is_ok());
+        match high_mmio_region(1u64 << 30, 1u64 << 39, 39) {
+            Err(Error::HighMmioPastAddressSpace) => {}
+            _ => panic!("window past the physical address width should fail"),
+        }
+    }
+
+    #[test]
+    fn device_memory_disjoint_from_ram_and_gap() {
+        let sizes = [1u64 << 28,
+                     FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE - 0x1000,
+                     (1u64 << 32) + 0x8000,
+                     1u64 << 36];
+        for &mem_size in sizes.iter() {
+            let start = X8664arch::get_base_dev_pfn(mem_size) * sys_util::pagesize() as u64;
+            assert!(start >= FIRST_ADDR_PAST_32BITS);
+            for &(ram_start, ram_size) in arch_memory_regions(mem_size).iter() {
+                assert!(ram_start.offset() + ram_size <= start);
+            }
+        }
+    }
+
+    #[test]
+    fn high_mmio_boot_params() {
+        let mem_size = 1u64 << 29;
+        let mem = GuestMemory::new(&arch_memory_regions(mem_size)).unwrap();
+        let high_mmio = high_mmio_region(mem_size, 1u64 << 30, 40).unwrap();
+        configure_system(&mem,
+                         GuestAddress(KERNEL_START_OFFSET),
+                         GuestAddress(CMDLINE_OFFSET),
+                         1,
+                         1,
+                         None,
+                         None,
+                         Some(high_mmio))
+            .unwrap();
+        let params: boot_params = mem.read_obj_from_addr(GuestAddress(ZERO_PAGE_OFFSET)).unwrap();
+        let last = params.e820_map[params.e820_entries as usize - 1];
+        assert_eq!((last.addr, last.size, last.type_),
+                   (FIRST_ADDR_PAST_32BITS, 1u64 << 30, E820_RESERVED));
+
+        // A window below the end of RAM is rejected.
+        assert!(configure_system(&mem,
+                                 GuestAddress(KERNEL_START_OFFSET),
+                                 GuestAddress(CMDLINE_OFFSET),
+                                 1,
+                                 1,
+                                 None,
+                                 None,
+                                 Some((GuestAddress(mem_size - 0x1000), 0x2000)))
+                        .is_err());
+    }
+
+    #[test]
+    fn setup_header_boot_params() {
+        let mem = GuestMemory::new(&arch_memory_regions(1u64 << 29)).unwrap();
+        let mut hdr: setup_header = Default::default();
+        hdr.init_size = 0x123000;
+        hdr.type_of_loader = 0x12;
+        configure_system(&mem,
+                         GuestAddress(KERNEL_START_OFFSET),
+                         GuestAddress(CMDLINE_OFFSET),
+                         1,
+                         1,
+                         Some(hdr),
+                         None,
+                         None)
+            .unwrap();
+        let params: boot_params = mem.read_obj_from_addr(GuestAddress(ZERO_PAGE_OFFSET)).unwrap();
+        assert_eq!({ params.hdr.init_size }, 0x123000);
+        assert_eq!({ params.hdr.type_of_loader }, 0xff);
+        assert_eq!({ params.hdr.cmd_line_ptr }, CMDLINE_OFFSET as u32);
+    }
+
+    #[test]
+    fn initrd_placement() {
+        let kernel_end = GuestAddress(KERNEL_START_OFFSET + 0x100000);
+        // Small VMs put the initrd at the end of RAM, page aligned.
+        assert_eq!(initrd_addr(GuestAddress(1u64 << 29), kernel_end, 0x1800).unwrap(),
+                   GuestAddress((1u64 << 29) - 0x2000));
+        // Large VMs put it below the 32-bit gap.
+        assert_eq!(initrd_addr(GuestAddress(1u64 << 33), kernel_end, 0x1000).unwrap(),
+                   GuestAddress(FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE - 0x1000));
+        match initrd_addr(GuestAddress(1u64 << 29), kernel_end, 1u64 << 30) {
+            Err(Error::InitrdPastRamEnd) => {}
+            _ => panic!("initrd larger than RAM should fail"),
+        }
+        match initrd_addr(GuestAddress(1u64 << 29), kernel_end, (1u64 << 29) - 0x200000) {
+            Err(Error::InitrdOverlapsKernel) => {}
+            _ => panic!("initrd overlapping the kernel should fail"),
+        }
+    }
+
+    #[test]
+    fn initrd_boot_params() {
+        let mem_size = 1u64 << 29;
+        let mem = GuestMemory::new(&arch_memory_regions(mem_size)).unwrap();
+        let initrd_start = GuestAddress(mem_size - 0x3000);
+        configure_system(&mem,
+                         GuestAddress(KERNEL_START_OFFSET),
+                         GuestAddress(CMDLINE_OFFSET),
+                         1,
+                         1,
+                         None,
+                         Some((initrd_start, 0x2000)),
+                         None)
+            .unwrap();
+        let params: boot_params = mem.read_obj_from_addr(GuestAddress(ZERO_PAGE_OFFSET)).unwrap();
+        assert_eq!({ params.hdr.ramdisk_image }, initrd_start.offset() as u32);
+        assert_eq!({ params.hdr.ramdisk_size }, 0x2000);
+
+        let e820: Vec<(u64, u64, u32)> = params.e820_map[..params.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(e820,
+                   vec![(0, 0x9fc00, E820_RAM),
+                        (KERNEL_START_OFFSET,
+                         initrd_start.offset() as u64 - KERNEL_START_OFFSET,
+                         E820_RAM),
+                        (initrd_start.offset() as u64, 0x2000, E820_RESERVED),
+                        (mem_size - 0x1000, 0x1000, E820_RAM)]);
+    }
+
+    #[test]
+    fn serial_port_outputs() {
+        let mut io_bus = devices::Bus::new();
+        let mut irq_routes = arch::IrqRoutes::new();
+        let ttys1 = SharedBuffer::new();
+        let ttys3 = SharedBuffer::new();
+        add_serial_ports(&mut io_bus,
+                         &mut irq_routes,
+                         vec![(1, Box::new(ttys1.clone())), (3, Box::new(ttys3.clone()))])
+            .unwrap();
+
+        assert!(io_bus.write(0x2f8, b"a"));
+        assert!(io_bus.write(0x2e8, b"b"));
+        assert!(io_bus.write(0x2e8, b"c"));
+        // Ports without an output swallow what is written to them.
+        assert!(io_bus.write(0x3f8, b"d"));
+        assert!(io_bus.write(0x3e8, b"e"));
+        assert_eq!(ttys1.contents(), b"a".to_vec());
+        assert_eq!(ttys3.contents(), b"bc".to_vec());
+        assert_eq!(irq_routes.irqs(), vec![3, 4]);
+    }
+
+    #[test]
+    fn serial_earlycon_matches_port() {
+        let outputs: Vec<SharedBuffer> = (0..4).map(|_| SharedBuffer::new()).collect();
+        let mut io_bus = devices::Bus::new();
+        add_serial_ports(&mut io_bus,
+                         &mut arch::IrqRoutes::new(),
+                         outputs
+                             .iter()
+                             .enumerate()
+                             .map(|(i, out)| (i as u8, Box::new(out.clone()) as Box<io::Write + Send>))
+                             .collect())
+            .unwrap();
+
+        for port in 0..4u8 {
+            let earlycon = X8664arch::serial_earlycon(port).unwrap();
+            assert!(earlycon.starts_with("uart8250,io,0x"), "bad earlycon {}", earlycon);
+            let base = u64::from_str_radix(&earlycon["uart8250,io,0x".len()..], 16).unwrap();
+            assert!(io_bus.write(base, &[b'0' + port]));
+            assert_eq!(outputs[port as usize].contents(), vec![b'0' + port]);
+        }
+        assert_eq!(X8664arch::serial_earlycon(4), None);
+    }
 }
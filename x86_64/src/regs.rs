@@ -75,7 +75,26 @@ impl Display for Error {
     }
 }
 
-fn create_msr_entries() -> Vec<kvm_msr_entry> {
+// Switches the local APIC to x2APIC mode when set in MSR_IA32_APICBASE.
+const MSR_IA32_APICBASE_EXTD: u64 = 1 << 10;
+// The architectural physical address of the local APIC registers.
+const APIC_DEFAULT_BASE: u64 = 0xfee00000;
+// The kvmclock MSRs advertised by KVM_FEATURE_CLOCKSOURCE2, from `asm/kvm_para.h`.
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b564d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b564d01;
+
+fn apic_base(cpu_id: u64, x2apic: bool) -> u64 {
+    let mut base = APIC_DEFAULT_BASE | ::msr_index::MSR_IA32_APICBASE_ENABLE as u64;
+    if cpu_id == 0 {
+        base |= ::msr_index::MSR_IA32_APICBASE_BSP as u64;
+    }
+    if x2apic {
+        base |= MSR_IA32_APICBASE_EXTD;
+    }
+    base
+}
+
+fn create_msr_entries(cpu_id: u64, x2apic: bool) -> Vec<kvm_msr_entry> {
     let mut entries = Vec::<kvm_msr_entry>::new();
 
     entries.push(kvm_msr_entry {
@@ -130,6 +149,23 @@ fn create_msr_entries() -> Vec<kvm_msr_entry> {
                      data: ::msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64,
                      ..Default::default()
                  });
+    // kvmclock starts disabled and the guest enables it by writing the address of its time
+    // structures, so that no stale clock state is left from an earlier boot.
+    entries.push(kvm_msr_entry {
+                     index: MSR_KVM_WALL_CLOCK_NEW,
+                     data: 0x0,
+                     ..Default::default()
+                 });
+    entries.push(kvm_msr_entry {
+                     index: MSR_KVM_SYSTEM_TIME_NEW,
+                     data: 0x0,
+                     ..Default::default()
+                 });
+    entries.push(kvm_msr_entry {
+                     index: ::msr_index::MSR_IA32_APICBASE,
+                     data: apic_base(cpu_id, x2apic),
+                     ..Default::default()
+                 });
 
     entries
 }
@@ -139,8 +175,10 @@ fn create_msr_entries() -> Vec<kvm_msr_entry> {
 /// # Arguments
 ///
 /// * `vcpu` - Structure for the vcpu that holds the vcpu fd.
-pub fn setup_msrs(vcpu: &kvm::Vcpu) -> Result<()> {
-    let entry_vec = create_msr_entries();
+/// * `cpu_id` - The id of the vcpu. Only vcpu 0 is the bootstrap processor.
+/// * `x2apic` - Start the local APIC in x2APIC mode. The x2APIC CPUID bit must already be set.
+pub fn setup_msrs(vcpu: &kvm::Vcpu, cpu_id: u64, x2apic: bool) -> Result<()> {
+    let entry_vec = create_msr_entries(cpu_id, x2apic);
     let vec_size_bytes = mem::size_of::<kvm_msrs>() +
                          (entry_vec.len() * mem::size_of::<kvm_msr_entry>());
     let vec: Vec<u8> = Vec::with_capacity(vec_size_bytes);
@@ -328,6 +366,28 @@ mod tests {
         gm.read_obj_from_addr(read_addr).unwrap()
     }
 
+    fn find_apic_base(entries: &[kvm_msr_entry]) -> u64 {
+        entries
+            .iter()
+            .find(|e| e.index == ::msr_index::MSR_IA32_APICBASE)
+            .unwrap()
+            .data
+    }
+
+    #[test]
+    fn msr_apic_base() {
+        let bsp = find_apic_base(&create_msr_entries(0, false));
+        assert_eq!(bsp & ::msr_index::MSR_IA32_APICBASE_BASE as u64, APIC_DEFAULT_BASE);
+        assert_ne!(bsp & ::msr_index::MSR_IA32_APICBASE_ENABLE as u64, 0);
+        assert_ne!(bsp & ::msr_index::MSR_IA32_APICBASE_BSP as u64, 0);
+        assert_eq!(bsp & MSR_IA32_APICBASE_EXTD, 0);
+
+        let ap = find_apic_base(&create_msr_entries(1, true));
+        assert_ne!(ap & ::msr_index::MSR_IA32_APICBASE_ENABLE as u64, 0);
+        assert_eq!(ap & ::msr_index::MSR_IA32_APICBASE_BSP as u64, 0);
+        assert_ne!(ap & MSR_IA32_APICBASE_EXTD, 0);
+    }
+
     #[test]
     fn segments_and_sregs() {
         let mut sregs: kvm_sregs = Default::default();